@@ -2,16 +2,28 @@ use anyhow::{anyhow, Context, Result};
 use std::{fmt::Display, future::Future, io::Write, pin::Pin};
 use wasmtime::{Caller, Memory, Val};
 
+mod linker;
+pub use linker::*;
+
 const ALLOCATOR_FUNCTION_NAME: &str = "lunatic_alloc";
 const FREEING_FUNCTION_NAME: &str = "lunatic_free";
 
 // Get exported memory
+//
+// Host functions take 32-bit pointers, so the exported memory can't be a 64-bit memory. Modules
+// using 64-bit memories need to export an additional 32-bit memory as `memory`.
 pub fn get_memory<T>(caller: &mut Caller<T>) -> Result<Memory> {
-    caller
+    let memory = caller
         .get_export("memory")
         .or_trap("No export `memory` found")?
         .into_memory()
-        .or_trap("Export `memory` is not a memory")
+        .or_trap("Export `memory` is not a memory")?;
+    if memory.ty(&caller).is_64() {
+        return Err(anyhow!(
+            "Export `memory` is a 64-bit memory, host functions only support 32-bit memories"
+        ));
+    }
+    Ok(memory)
 }
 
 // Call guest to allocate a Vec of size `size`
@@ -0,0 +1,192 @@
+use std::{future::Future, pin::Pin, time::Instant};
+
+use anyhow::Result;
+use wasmtime::{Caller, Linker, WasmRet, WasmTy};
+
+/// Name of the histogram recording the duration of host function calls.
+pub const HOST_CALL_DURATION: &str = "lunatic.host_call.duration";
+
+/// Describes the metrics recorded by [`InstrumentedLinker`].
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    metrics::describe_histogram!(
+        HOST_CALL_DURATION,
+        metrics::Unit::Seconds,
+        "Duration of host function calls, labelled with `namespace::function`"
+    );
+}
+
+/// Wraps a [`Linker`] so that every host function registered through it records its duration.
+///
+/// The registration methods mirror the ones of the linker, so APIs can shadow the linker with
+/// this wrapper at the top of their `register` function and keep the rest unchanged. Durations
+/// are recorded in the [`HOST_CALL_DURATION`] histogram with a `function` label of the form
+/// `namespace::function`. Without the `metrics` feature nothing is recorded.
+///
+/// The duration of async host functions includes the time they spend waiting, e.g. for a
+/// message to arrive.
+pub struct InstrumentedLinker<'a, T> {
+    linker: &'a mut Linker<T>,
+}
+
+impl<'a, T> InstrumentedLinker<'a, T> {
+    pub fn new(linker: &'a mut Linker<T>) -> Self {
+        Self { linker }
+    }
+
+    /// Defines a synchronous host function, see [`Linker::func_wrap`].
+    pub fn func_wrap<Params, Results>(
+        &mut self,
+        module: &str,
+        name: &str,
+        func: impl InstrumentedFunc<T, Params, Results>,
+    ) -> Result<&mut Self> {
+        func.define(self.linker, module, name)?;
+        Ok(self)
+    }
+}
+
+/// Implemented for the same host function signatures as [`wasmtime::IntoFunc`].
+pub trait InstrumentedFunc<T, Params, Results> {
+    fn define(self, linker: &mut Linker<T>, module: &str, name: &str) -> Result<()>;
+}
+
+macro_rules! instrumented_func {
+    ($($args:ident)*) => {
+        #[allow(non_snake_case)]
+        impl<T, F, $($args,)* R> InstrumentedFunc<T, (Caller<'_, T>, $($args,)*), R> for F
+        where
+            F: Fn(Caller<'_, T>, $($args),*) -> R + Send + Sync + 'static,
+            $($args: WasmTy,)*
+            R: WasmRet,
+        {
+            fn define(self, linker: &mut Linker<T>, module: &str, name: &str) -> Result<()> {
+                let timer = CallTimer::new(module, name);
+                linker.func_wrap(module, name, move |caller: Caller<'_, T>, $($args: $args),*| {
+                    let start = Instant::now();
+                    let result = self(caller, $($args),*);
+                    timer.record(start);
+                    result
+                })?;
+                Ok(())
+            }
+        }
+
+        // Functions without a caller are marked with `()` in place of it, so the implementations
+        // can't overlap
+        #[allow(non_snake_case)]
+        impl<T, F, $($args,)* R> InstrumentedFunc<T, ((), $($args,)*), R> for F
+        where
+            F: Fn($($args),*) -> R + Send + Sync + 'static,
+            $($args: WasmTy,)*
+            R: WasmRet,
+        {
+            fn define(self, linker: &mut Linker<T>, module: &str, name: &str) -> Result<()> {
+                let timer = CallTimer::new(module, name);
+                linker.func_wrap(module, name, move |$($args: $args),*| {
+                    let start = Instant::now();
+                    let result = self($($args),*);
+                    timer.record(start);
+                    result
+                })?;
+                Ok(())
+            }
+        }
+    };
+}
+
+instrumented_func!();
+instrumented_func!(A1);
+instrumented_func!(A1 A2);
+instrumented_func!(A1 A2 A3);
+instrumented_func!(A1 A2 A3 A4);
+instrumented_func!(A1 A2 A3 A4 A5);
+instrumented_func!(A1 A2 A3 A4 A5 A6);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12 A13);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12 A13 A14);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12 A13 A14 A15);
+instrumented_func!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12 A13 A14 A15 A16);
+
+macro_rules! func_wrap_async {
+    ($method:ident $($args:ident)*) => {
+        /// Defines an async host function, see the method of the same name on [`Linker`].
+        #[allow(non_snake_case, clippy::type_complexity)]
+        pub fn $method<$($args,)* R>(
+            &mut self,
+            module: &str,
+            name: &str,
+            func: impl for<'c> Fn(Caller<'c, T>, $($args),*) -> Box<dyn Future<Output = R> + Send + 'c>
+                + Send
+                + Sync
+                + 'static,
+        ) -> Result<&mut Self>
+        where
+            $($args: WasmTy,)*
+            R: WasmRet + 'static,
+        {
+            let timer = CallTimer::new(module, name);
+            self.linker.$method(module, name, move |caller: Caller<'_, T>, $($args: $args),*| {
+                timed(func(caller, $($args),*), timer.clone())
+            })?;
+            Ok(self)
+        }
+    };
+}
+
+impl<'a, T> InstrumentedLinker<'a, T> {
+    func_wrap_async!(func_wrap1_async A1);
+    func_wrap_async!(func_wrap2_async A1 A2);
+    func_wrap_async!(func_wrap3_async A1 A2 A3);
+    func_wrap_async!(func_wrap4_async A1 A2 A3 A4);
+    func_wrap_async!(func_wrap5_async A1 A2 A3 A4 A5);
+    func_wrap_async!(func_wrap6_async A1 A2 A3 A4 A5 A6);
+    func_wrap_async!(func_wrap7_async A1 A2 A3 A4 A5 A6 A7);
+    func_wrap_async!(func_wrap8_async A1 A2 A3 A4 A5 A6 A7 A8);
+    func_wrap_async!(func_wrap9_async A1 A2 A3 A4 A5 A6 A7 A8 A9);
+    func_wrap_async!(func_wrap10_async A1 A2 A3 A4 A5 A6 A7 A8 A9 A10);
+    func_wrap_async!(func_wrap11_async A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11);
+}
+
+// Records the duration of the host function call once the future completes
+fn timed<'a, R: 'a>(
+    call: Box<dyn Future<Output = R> + Send + 'a>,
+    timer: CallTimer,
+) -> Box<dyn Future<Output = R> + Send + 'a> {
+    Box::new(async move {
+        let start = Instant::now();
+        let result = Pin::from(call).await;
+        timer.record(start);
+        result
+    })
+}
+
+#[derive(Clone)]
+struct CallTimer {
+    #[cfg(feature = "metrics")]
+    histogram: metrics::Histogram,
+}
+
+impl CallTimer {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn new(module: &str, name: &str) -> Self {
+        Self {
+            #[cfg(feature = "metrics")]
+            histogram: metrics::register_histogram!(
+                HOST_CALL_DURATION,
+                "function" => format!("{module}::{name}")
+            ),
+        }
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn record(&self, start: Instant) {
+        #[cfg(feature = "metrics")]
+        self.histogram.record(start.elapsed());
+    }
+}
@@ -244,10 +244,8 @@ where
             .map_err(|e| ApiError::log_internal("Error getting cs in registration auth", e))?;
 
         let (registration_id, reg) = cs
-            .registrations
-            .iter()
-            .find(|r| r.node_name == node_name && r.authentication_token == token)
-            .map(|r| (*r.key(), r.value().clone()))
+            .authenticate(node_name, token)
+            .map_err(|e| ApiError::log_internal("Error loading registration", e))?
             .ok_or(ApiError::NotAuthenticated)?;
         let node_auth = NodeAuth {
             registration_id: registration_id as i64,
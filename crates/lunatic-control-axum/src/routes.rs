@@ -1,5 +1,7 @@
 use std::{collections::HashMap, sync::Arc};
 
+use chrono::{Datelike, Duration, Utc};
+
 use asn1_rs::ToDer;
 use axum::{
     body::Bytes,
@@ -17,16 +19,12 @@ use crate::{
     server::ControlServer,
 };
 
-pub async fn register(
-    control: Extension<Arc<ControlServer>>,
-    HostExtractor(host): HostExtractor,
-    JsonExtractor(reg): JsonExtractor<Register>,
-) -> ApiResponse<Registration> {
-    log::info!("Registration for node name {}", reg.node_name);
-
-    let control = control.as_ref();
+// How long node certificates are valid, nodes renew them when a third of this is left
+const NODE_CERT_VALIDITY_DAYS: i64 = 30;
 
-    let mut sign_request = CertificateSigningRequest::from_pem(&reg.csr_pem).map_err(|e| {
+// Signs the certificate of a node, valid from the day before to account for clock differences
+fn sign_node_certificate(control: &ControlServer, csr_pem: &str) -> Result<String, ApiError> {
+    let mut sign_request = CertificateSigningRequest::from_pem(csr_pem).map_err(|e| {
         ApiError::custom(
             "sign_error",
             format!("Certificate Signing Request invalid pem format: {}", e),
@@ -46,16 +44,35 @@ pub async fn register(
             .to_der_vec()
             .map_err(|e| ApiError::log_internal("Error serializing allowed envs to der", e))?,
         ));
-    let cert_pem = sign_request
+    let day = |date: chrono::DateTime<Utc>| {
+        rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+    };
+    let now = Utc::now();
+    sign_request.params.not_before = day(now - Duration::days(1));
+    sign_request.params.not_after = day(now + Duration::days(NODE_CERT_VALIDITY_DAYS));
+    sign_request
         .serialize_pem_with_signer(&control.ca_cert)
-        .map_err(|e| ApiError::custom("sign_error", e.to_string()))?;
+        .map_err(|e| ApiError::custom("sign_error", e.to_string()))
+}
+
+pub async fn register(
+    control: Extension<Arc<ControlServer>>,
+    HostExtractor(host): HostExtractor,
+    JsonExtractor(reg): JsonExtractor<Register>,
+) -> ApiResponse<Registration> {
+    log::info!("Registration for node name {}", reg.node_name);
+
+    let control = control.as_ref();
+    let cert_pem = sign_node_certificate(control, &reg.csr_pem)?;
 
     let mut authentication_token = [0u8; 32];
     getrandom::getrandom(&mut authentication_token)
         .map_err(|e| ApiError::log_internal("Error generating random token for registration", e))?;
     let authentication_token = base64_url::encode(&authentication_token);
 
-    control.register(&reg, &cert_pem, &authentication_token);
+    control
+        .register(&reg, &cert_pem, &authentication_token)
+        .map_err(|e| ApiError::log_internal("Error storing registration", e))?;
 
     ok(Registration {
         node_name: reg.node_name,
@@ -70,6 +87,11 @@ pub async fn register(
             get_module: format!("http://{host}/module/{{id}}"),
             add_module: format!("http://{host}/module"),
             get_nodes: format!("http://{host}/nodes"),
+            registry: Some(format!("http://{host}/registry")),
+            get_module_hash: Some(format!("http://{host}/module/{{id}}/hash")),
+            node_tags: Some(format!("http://{host}/node/tags")),
+            node_stats: Some(format!("http://{host}/node/stats")),
+            renew_certificate: Some(format!("http://{host}/node/certificate")),
         },
         envs: Vec::new(),
         is_privileged: true,
@@ -83,7 +105,9 @@ pub async fn node_stopped(
     log::info!("Node {} stopped", node_auth.node_name);
 
     let control = control.as_ref();
-    control.stop_node(node_auth.registration_id as u64);
+    control
+        .stop_node(node_auth.registration_id as u64)
+        .map_err(|e| ApiError::log_internal("Error stopping node", e))?;
 
     ok(())
 }
@@ -94,9 +118,13 @@ pub async fn node_started(
     Json(data): Json<NodeStart>,
 ) -> ApiResponse<NodeStarted> {
     let control = control.as_ref();
-    control.stop_node(node_auth.registration_id as u64);
+    control
+        .stop_node(node_auth.registration_id as u64)
+        .map_err(|e| ApiError::log_internal("Error stopping node", e))?;
 
-    let (node_id, _node_address) = control.start_node(node_auth.registration_id as u64, data);
+    let (node_id, _node_address) = control
+        .start_node(node_auth.registration_id as u64, data)
+        .map_err(|e| ApiError::log_internal("Error starting node", e))?;
 
     log::info!("Node {} started with id {}", node_auth.node_name, node_id);
 
@@ -107,6 +135,53 @@ pub async fn node_started(
     })
 }
 
+pub async fn set_node_tag(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    JsonExtractor(tag): JsonExtractor<NodeTag>,
+) -> ApiResponse<()> {
+    log::info!(
+        "Node {} set tag {}={}",
+        node_auth.node_name,
+        tag.key,
+        tag.value
+    );
+
+    control
+        .set_node_tag(node_auth.registration_id as u64, tag)
+        .map_err(|e| ApiError::log_internal("Error storing node tag", e))?;
+    ok(())
+}
+
+pub async fn renew_certificate(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    JsonExtractor(renew): JsonExtractor<RenewCertificate>,
+) -> ApiResponse<RenewedCertificate> {
+    log::info!("Renewing certificate of node {}", node_auth.node_name);
+
+    let control = control.as_ref();
+    let cert_pem = sign_node_certificate(control, &renew.csr_pem)?;
+    control
+        .renew_certificate(node_auth.registration_id as u64, &renew.csr_pem, &cert_pem)
+        .map_err(|e| ApiError::log_internal("Error storing renewed certificate", e))?;
+
+    ok(RenewedCertificate {
+        cert_pem_chain: vec![cert_pem],
+    })
+}
+
+pub async fn set_node_stats(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    JsonExtractor(stats): JsonExtractor<NodeStats>,
+) -> ApiResponse<()> {
+    control
+        .set_node_stats(node_auth.registration_id as u64, stats)
+        .map_err(|e| ApiError::log_internal("Error storing node stats", e))?;
+    ok(())
+}
+
 pub async fn list_nodes(
     _node_auth: NodeAuth,
     Query(query): Query<HashMap<String, String>>,
@@ -136,6 +211,9 @@ pub async fn list_nodes(
                     id: *n.key(),
                     address: n.node_address.parse().unwrap(),
                     name: r.node_name.to_string(),
+                    codecs: n.codecs.clone(),
+                    processes: n.processes,
+                    attributes: n.attributes.clone(),
                 })
         })
         .collect();
@@ -151,7 +229,9 @@ pub async fn add_module(
     log::info!("Node {} add_module", node_auth.node_name);
 
     let control = control.as_ref();
-    let module_id = control.add_module(body.to_vec());
+    let module_id = control
+        .add_module(body.to_vec())
+        .map_err(|e| ApiError::log_internal("Error storing module", e))?;
     ok(ModuleId { module_id })
 }
 
@@ -163,23 +243,98 @@ pub async fn get_module(
     log::info!("Node {} get_module {}", node_auth.node_name, id);
 
     let bytes = control
-        .modules
-        .iter()
-        .find(|m| m.key() == &id)
-        .map(|m| m.value().clone())
+        .get_module(id)
+        .map_err(|e| ApiError::log_internal("Error loading module", e))?
         .ok_or_else(|| ApiError::custom_code("error_reading_bytes"))?;
 
     ok(ModuleBytes { bytes })
 }
 
+pub async fn get_module_hash(
+    _node_auth: NodeAuth,
+    PathExtractor(id): PathExtractor<u64>,
+    control: Extension<Arc<ControlServer>>,
+) -> ApiResponse<ModuleHash> {
+    let hash = control
+        .module_hash(id)
+        .map_err(|e| ApiError::log_internal("Error loading module hash", e))?
+        .ok_or_else(|| ApiError::custom_code("module_not_found"))?;
+
+    ok(ModuleHash { hash })
+}
+
+pub async fn registry_list(
+    _node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+) -> ApiResponse<RegistryList> {
+    let entries = control
+        .registry
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+    ok(RegistryList { entries })
+}
+
+pub async fn registry_lookup(
+    _node_auth: NodeAuth,
+    Query(query): Query<HashMap<String, String>>,
+    control: Extension<Arc<ControlServer>>,
+) -> ApiResponse<RegistryLookup> {
+    let name = query
+        .get("name")
+        .ok_or_else(|| ApiError::InvalidQueryArg("Missing `name`".into()))?;
+    let entry = control
+        .registry
+        .get(name)
+        .map(|entry| entry.value().clone());
+    ok(RegistryLookup { entry })
+}
+
+pub async fn registry_put(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    JsonExtractor(put): JsonExtractor<RegistryPut>,
+) -> ApiResponse<RegistryEntry> {
+    log::info!("Node {} registry_put {}", node_auth.node_name, put.name);
+
+    let entry = control
+        .registry_put(put)
+        .map_err(|e| ApiError::log_internal("Error storing global name", e))?;
+    ok(entry)
+}
+
+pub async fn registry_remove(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    JsonExtractor(remove): JsonExtractor<RegistryRemove>,
+) -> ApiResponse<()> {
+    log::info!(
+        "Node {} registry_remove {}",
+        node_auth.node_name,
+        remove.name
+    );
+
+    control
+        .registry_remove(remove)
+        .map_err(|e| ApiError::log_internal("Error removing global name", e))?;
+    ok(())
+}
+
 pub fn init_routes() -> Router {
     Router::new()
         .route("/", post(register))
         .route("/stopped", post(node_stopped))
         .route("/started", post(node_started))
         .route("/nodes", get(list_nodes))
+        .route("/node/tags", post(set_node_tag))
+        .route("/node/stats", post(set_node_stats))
+        .route("/node/certificate", post(renew_certificate))
         .route("/module", post(add_module))
         .route("/module/:id", get(get_module))
+        .route("/module/:id/hash", get(get_module_hash))
+        .route("/registry", get(registry_list).post(registry_put))
+        .route("/registry/lookup", get(registry_lookup))
+        .route("/registry/remove", post(registry_remove))
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(50 * 1024 * 1024)) // 50 mb
 }
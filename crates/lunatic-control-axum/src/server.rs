@@ -1,31 +1,49 @@
+mod store;
+
 use std::{
     collections::HashMap,
+    hash::Hash,
     net::{SocketAddr, TcpListener},
+    path::Path,
     sync::{
         atomic::{self, AtomicU64},
         Arc,
     },
+    time::Duration,
 };
 
 use anyhow::Result;
 use axum::{Extension, Router};
 use chrono::{DateTime, Utc};
-use dashmap::DashMap;
-use lunatic_control::api::{NodeStart, Register};
+use dashmap::{mapref::entry::Entry, DashMap};
+use lunatic_control::api::{
+    NodeStart, NodeStats, NodeTag, Register, RegistryEntry, RegistryPut, RegistryRemove,
+};
+use lunatic_distributed::distributed::module_cache::content_hash;
 use rcgen::Certificate;
 use uuid::Uuid;
 
 use crate::routes;
 
+pub use self::store::ControlServerStore;
+
+// How often replicas pick up the changes made by the other replicas
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct ControlServer {
     pub ca_cert: Certificate,
     pub quic_client: lunatic_distributed::quic::Client,
     pub registrations: DashMap<u64, Registered>,
     pub nodes: DashMap<u64, NodeDetails>,
     pub modules: DashMap<u64, Vec<u8>>,
+    pub module_hashes: DashMap<u64, String>,
+    pub registry: DashMap<String, RegistryEntry>,
     next_registration_id: AtomicU64,
     next_node_id: AtomicU64,
     next_module_id: AtomicU64,
+    next_registry_version: AtomicU64,
+    // The maps above only cache the database if there is one
+    store: Option<ControlServerStore>,
 }
 
 #[derive(Clone)]
@@ -43,6 +61,9 @@ pub struct NodeDetails {
     pub stopped_at: Option<DateTime<Utc>>,
     pub node_address: String,
     pub attributes: HashMap<String, String>,
+    pub codecs: Vec<String>,
+    // Number of processes running on the node as last reported
+    pub processes: Option<u64>,
 }
 
 impl ControlServer {
@@ -53,27 +74,101 @@ impl ControlServer {
             registrations: DashMap::new(),
             nodes: DashMap::new(),
             modules: DashMap::new(),
+            module_hashes: DashMap::new(),
+            registry: DashMap::new(),
             next_registration_id: AtomicU64::new(1),
             next_node_id: AtomicU64::new(1),
             next_module_id: AtomicU64::new(1),
+            next_registry_version: AtomicU64::new(1),
+            store: None,
         }
     }
 
-    pub fn register(&self, reg: &Register, cert_pem: &str, authentication_token: &str) {
-        let id = self
-            .next_registration_id
-            .fetch_add(1, atomic::Ordering::Relaxed);
+    /// Creates a control server that keeps its state in `store`, so that it survives restarts.
+    ///
+    /// Multiple control servers using the same database can run behind one URL.
+    pub fn with_store(
+        ca_cert: Certificate,
+        quic_client: lunatic_distributed::quic::Client,
+        store: ControlServerStore,
+    ) -> Result<Self> {
+        let control = Self {
+            store: Some(store),
+            ..Self::new(ca_cert, quic_client)
+        };
+        control.reload()?;
+        Ok(control)
+    }
+
+    /// Replaces the cached state with the one in the database, including changes made by other
+    /// replicas. Modules are only loaded when requested.
+    pub fn reload(&self) -> Result<()> {
+        if let Some(store) = &self.store {
+            replace(&self.registrations, store.load_registrations()?);
+            replace(&self.nodes, store.load_nodes()?);
+            replace(&self.registry, store.load_registry()?);
+        }
+        Ok(())
+    }
+
+    pub fn register(
+        &self,
+        reg: &Register,
+        cert_pem: &str,
+        authentication_token: &str,
+    ) -> Result<()> {
         let registered = Registered {
             node_name: reg.node_name,
             csr_pem: reg.csr_pem.clone(),
             cert_pem: cert_pem.to_owned(),
             authentication_token: authentication_token.to_owned(),
         };
+        let id = match &self.store {
+            Some(store) => store.add_registration(&registered)?,
+            None => self
+                .next_registration_id
+                .fetch_add(1, atomic::Ordering::Relaxed),
+        };
         self.registrations.insert(id, registered);
+        Ok(())
     }
 
-    pub fn start_node(&self, registration_id: u64, data: NodeStart) -> (u64, String) {
-        let id = self.next_node_id.fetch_add(1, atomic::Ordering::Relaxed);
+    /// Replaces the certificate of a registration with a renewed one.
+    pub fn renew_certificate(&self, reg_id: u64, csr_pem: &str, cert_pem: &str) -> Result<()> {
+        if let Some(store) = &self.store {
+            store.set_registration_certificate(reg_id, csr_pem, cert_pem)?;
+        }
+        if let Some(mut registered) = self.registrations.get_mut(&reg_id) {
+            registered.csr_pem = csr_pem.to_owned();
+            registered.cert_pem = cert_pem.to_owned();
+        }
+        Ok(())
+    }
+
+    /// Finds the registration of a node, which may have registered with another replica.
+    pub fn authenticate(
+        &self,
+        node_name: Uuid,
+        authentication_token: &str,
+    ) -> Result<Option<(u64, Registered)>> {
+        let registration = self
+            .registrations
+            .iter()
+            .find(|r| r.node_name == node_name && r.authentication_token == authentication_token)
+            .map(|r| (*r.key(), r.value().clone()));
+        match (registration, &self.store) {
+            (None, Some(store)) => {
+                let registration = store.find_registration(node_name, authentication_token)?;
+                if let Some((id, registered)) = &registration {
+                    self.registrations.insert(*id, registered.clone());
+                }
+                Ok(registration)
+            }
+            (registration, _) => Ok(registration),
+        }
+    }
+
+    pub fn start_node(&self, registration_id: u64, data: NodeStart) -> Result<(u64, String)> {
         let details = NodeDetails {
             registration_id,
             status: 0,
@@ -81,45 +176,208 @@ impl ControlServer {
             stopped_at: None,
             node_address: data.node_address.to_string(),
             attributes: data.attributes,
+            codecs: data.codecs,
+            processes: None,
+        };
+        let id = match &self.store {
+            Some(store) => store.add_node(&details)?,
+            None => self.next_node_id.fetch_add(1, atomic::Ordering::Relaxed),
         };
         self.nodes.insert(id, details);
-        (id, data.node_address.to_string())
+        Ok((id, data.node_address.to_string()))
     }
 
-    pub fn stop_node(&self, reg_id: u64) {
+    pub fn stop_node(&self, reg_id: u64) -> Result<()> {
+        let stopped_at = Utc::now();
+        if let Some(store) = &self.store {
+            store.stop_node(reg_id, stopped_at)?;
+        }
         if let Some(mut node) = self.nodes.get_mut(&reg_id) {
             node.status = 2;
-            node.stopped_at = Some(Utc::now());
+            node.stopped_at = Some(stopped_at);
+        }
+        // Processes on stopped nodes can't be reached anymore
+        self.registry.retain(|_, entry| {
+            self.nodes
+                .get(&entry.node_id)
+                .map(|node| node.status < 2)
+                .unwrap_or(false)
+        });
+        Ok(())
+    }
+
+    /// Sets the attribute `tag.key` of the running nodes started with the registration.
+    pub fn set_node_tag(&self, reg_id: u64, tag: NodeTag) -> Result<()> {
+        for mut node in self.nodes.iter_mut() {
+            if node.registration_id != reg_id || node.status >= 2 {
+                continue;
+            }
+            node.attributes.insert(tag.key.clone(), tag.value.clone());
+            if let Some(store) = &self.store {
+                store.set_node_attributes(*node.key(), &node.attributes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the load of the running nodes started with the registration.
+    pub fn set_node_stats(&self, reg_id: u64, stats: NodeStats) -> Result<()> {
+        for mut node in self.nodes.iter_mut() {
+            if node.registration_id != reg_id || node.status >= 2 {
+                continue;
+            }
+            node.processes = Some(stats.processes);
+            if let Some(store) = &self.store {
+                store.set_node_processes(*node.key(), stats.processes)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn add_module(&self, bytes: Vec<u8>) -> u64 {
-        let id = self.next_module_id.fetch_add(1, atomic::Ordering::Relaxed);
+    /// Adds a module and returns its ID. Adding the same module again returns the same ID.
+    pub fn add_module(&self, bytes: Vec<u8>) -> Result<u64> {
+        let hash = content_hash(&bytes);
+        let id = match &self.store {
+            Some(store) => store.add_module(&hash, &bytes)?,
+            None => {
+                let existing = self
+                    .module_hashes
+                    .iter()
+                    .find(|entry| *entry.value() == hash)
+                    .map(|entry| *entry.key());
+                match existing {
+                    Some(id) => return Ok(id),
+                    None => self.next_module_id.fetch_add(1, atomic::Ordering::Relaxed),
+                }
+            }
+        };
         self.modules.insert(id, bytes);
-        id
+        self.module_hashes.insert(id, hash);
+        Ok(id)
+    }
+
+    /// Returns the content hash of a module, which nodes use to look it up in their cache.
+    pub fn module_hash(&self, id: u64) -> Result<Option<String>> {
+        if let Some(hash) = self.module_hashes.get(&id) {
+            return Ok(Some(hash.clone()));
+        }
+        match &self.store {
+            Some(store) => {
+                let hash = store.module_hash(id)?;
+                if let Some(hash) = &hash {
+                    self.module_hashes.insert(id, hash.clone());
+                }
+                Ok(hash)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the module, which may have been added through another replica.
+    pub fn get_module(&self, id: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(module) = self.modules.get(&id) {
+            return Ok(Some(module.clone()));
+        }
+        match &self.store {
+            Some(store) => {
+                let module = store.module(id)?;
+                if let Some(module) = &module {
+                    self.modules.insert(id, module.clone());
+                }
+                Ok(module)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Registers a process under a global name and returns the registration that won.
+    ///
+    /// If `if_version` doesn't match the current registration, the name was changed by another
+    /// node while the registering one couldn't reach the control server. The registration the
+    /// control server already knows about is kept in that case.
+    pub fn registry_put(&self, put: RegistryPut) -> Result<RegistryEntry> {
+        if let Some(store) = &self.store {
+            let entry = store.registry_put(put)?;
+            self.registry.insert(entry.name.clone(), entry.clone());
+            return Ok(entry);
+        }
+        let version = self
+            .next_registry_version
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        let entry = RegistryEntry {
+            name: put.name.clone(),
+            node_id: put.node_id,
+            process_id: put.process_id,
+            version,
+        };
+        match self.registry.entry(put.name) {
+            Entry::Occupied(mut current) => {
+                if matches!(put.if_version, Some(v) if v != current.get().version) {
+                    return Ok(current.get().clone());
+                }
+                current.insert(entry.clone());
+                Ok(entry)
+            }
+            Entry::Vacant(vacant) => Ok(vacant.insert(entry).clone()),
+        }
+    }
+
+    pub fn registry_remove(&self, remove: RegistryRemove) -> Result<()> {
+        if let Some(store) = &self.store {
+            store.registry_remove(&remove)?;
+        }
+        self.registry.remove_if(&remove.name, |_, entry| {
+            remove.if_version.is_none_or(|v| v == entry.version)
+        });
+        Ok(())
     }
 }
 
-fn prepare_app() -> Result<Router> {
+fn replace<K: Eq + Hash, V>(map: &DashMap<K, V>, entries: HashMap<K, V>) {
+    map.retain(|key, _| entries.contains_key(key));
+    for (key, value) in entries {
+        map.insert(key, value);
+    }
+}
+
+async fn reload_task(control: Arc<ControlServer>) {
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        if let Err(error) = control.reload() {
+            log::error!("Failed to reload the control server state: {error:?}");
+        }
+    }
+}
+
+fn prepare_app(database: Option<&Path>) -> Result<Router> {
     let ca_cert_str = lunatic_distributed::distributed::server::test_root_cert();
     let ca_cert = lunatic_distributed::control::cert::test_root_cert()?;
     let (ctrl_cert, ctrl_pk) =
         lunatic_distributed::control::cert::default_server_certificates(&ca_cert)?;
     let quic_client =
         lunatic_distributed::quic::new_quic_client(&ca_cert_str, &ctrl_cert, &ctrl_pk)?;
-    let control = Arc::new(ControlServer::new(ca_cert, quic_client));
+    let control = match database {
+        Some(path) => {
+            let store = ControlServerStore::open(path)?;
+            let control = Arc::new(ControlServer::with_store(ca_cert, quic_client, store)?);
+            tokio::spawn(reload_task(control.clone()));
+            control
+        }
+        None => Arc::new(ControlServer::new(ca_cert, quic_client)),
+    };
     let app = Router::new()
         .nest("/", routes::init_routes())
         .layer(Extension(control));
     Ok(app)
 }
 
-pub async fn control_server(http_socket: SocketAddr) -> Result<()> {
-    control_server_from_tcp(TcpListener::bind(http_socket)?).await
+/// Runs the control server, keeping its state in the SQLite `database` if one is given.
+pub async fn control_server(http_socket: SocketAddr, database: Option<&Path>) -> Result<()> {
+    control_server_from_tcp(TcpListener::bind(http_socket)?, database).await
 }
 
-pub async fn control_server_from_tcp(listener: TcpListener) -> Result<()> {
-    let app = prepare_app()?;
+pub async fn control_server_from_tcp(listener: TcpListener, database: Option<&Path>) -> Result<()> {
+    let app = prepare_app(database)?;
 
     axum::Server::from_tcp(listener)?
         .serve(app.into_make_service())
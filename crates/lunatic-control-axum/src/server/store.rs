@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use lunatic_control::api::{RegistryEntry, RegistryPut, RegistryRemove};
+use sqlite::{Connection, State, Statement, Value};
+use uuid::Uuid;
+
+use super::{NodeDetails, Registered};
+
+// Other replicas using the same database can lock it while writing
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+/// SQLite database the control server keeps its state in.
+///
+/// IDs and registry versions are handed out by the database, so multiple control servers can
+/// share one database file and run as replicas behind the same URL.
+pub struct ControlServerStore {
+    conn: Mutex<Connection>,
+}
+
+impl ControlServerStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+        conn.execute("PRAGMA journal_mode = WAL")?;
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS registrations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_name TEXT NOT NULL,
+                csr_pem TEXT NOT NULL,
+                cert_pem TEXT NOT NULL,
+                auth_token TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS nodes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                registration_id INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                stopped_at TEXT,
+                node_address TEXT NOT NULL,
+                attributes TEXT NOT NULL,
+                codecs TEXT NOT NULL,
+                processes INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS modules (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash TEXT NOT NULL UNIQUE,
+                module BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS registry (
+                name TEXT PRIMARY KEY,
+                node_id INTEGER NOT NULL,
+                process_id INTEGER NOT NULL,
+                version INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS counters (
+                name TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );
+            INSERT OR IGNORE INTO counters (name, value) VALUES ('registry_version', 0);
+            "#,
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("Control server database lock poisoned"))
+    }
+
+    pub fn load_registrations(&self) -> Result<HashMap<u64, Registered>> {
+        let conn = self.lock()?;
+        query(
+            &conn,
+            "SELECT id, node_name, csr_pem, cert_pem, auth_token FROM registrations",
+            &[],
+            read_registration,
+        )
+        .map(|rows| rows.into_iter().collect())
+    }
+
+    /// Finds a registration that may have been added by another replica.
+    pub fn find_registration(
+        &self,
+        node_name: Uuid,
+        auth_token: &str,
+    ) -> Result<Option<(u64, Registered)>> {
+        let conn = self.lock()?;
+        let rows = query(
+            &conn,
+            r#"
+            SELECT id, node_name, csr_pem, cert_pem, auth_token FROM registrations
+            WHERE node_name = ? AND auth_token = ?
+            "#,
+            &[node_name.to_string().into(), auth_token.into()],
+            read_registration,
+        )?;
+        Ok(rows.into_iter().next())
+    }
+
+    pub fn add_registration(&self, registered: &Registered) -> Result<u64> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            r#"
+            INSERT INTO registrations (node_name, csr_pem, cert_pem, auth_token)
+            VALUES (?, ?, ?, ?)
+            "#,
+            &[
+                registered.node_name.to_string().into(),
+                registered.csr_pem.as_str().into(),
+                registered.cert_pem.as_str().into(),
+                registered.authentication_token.as_str().into(),
+            ],
+        )?;
+        last_insert_id(&conn)
+    }
+
+    pub fn set_registration_certificate(
+        &self,
+        id: u64,
+        csr_pem: &str,
+        cert_pem: &str,
+    ) -> Result<()> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            "UPDATE registrations SET csr_pem = ?, cert_pem = ? WHERE id = ?",
+            &[csr_pem.into(), cert_pem.into(), (id as i64).into()],
+        )
+    }
+
+    pub fn load_nodes(&self) -> Result<HashMap<u64, NodeDetails>> {
+        let conn = self.lock()?;
+        query(
+            &conn,
+            r#"
+            SELECT id, registration_id, status, created_at, stopped_at, node_address, attributes,
+                codecs, processes
+            FROM nodes
+            "#,
+            &[],
+            |row| {
+                let stopped_at: Option<String> = row.read(4)?;
+                let attributes: String = row.read(6)?;
+                let codecs: String = row.read(7)?;
+                let processes: Option<i64> = row.read(8)?;
+                let node = NodeDetails {
+                    registration_id: row.read::<i64, _>(1)? as u64,
+                    status: row.read::<i64, _>(2)? as i16,
+                    created_at: parse_datetime(&row.read::<String, _>(3)?)?,
+                    stopped_at: stopped_at.as_deref().map(parse_datetime).transpose()?,
+                    node_address: row.read(5)?,
+                    attributes: serde_json::from_str(&attributes)?,
+                    codecs: serde_json::from_str(&codecs)?,
+                    processes: processes.map(|processes| processes as u64),
+                };
+                Ok((row.read::<i64, _>(0)? as u64, node))
+            },
+        )
+        .map(|rows| rows.into_iter().collect())
+    }
+
+    pub fn add_node(&self, node: &NodeDetails) -> Result<u64> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            r#"
+            INSERT INTO nodes (
+                registration_id, status, created_at, stopped_at, node_address, attributes, codecs
+            ) VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+            &[
+                (node.registration_id as i64).into(),
+                (node.status as i64).into(),
+                node.created_at.to_rfc3339().into(),
+                node.stopped_at
+                    .map(|stopped_at| stopped_at.to_rfc3339().into())
+                    .unwrap_or(Value::Null),
+                node.node_address.as_str().into(),
+                serde_json::to_string(&node.attributes)?.into(),
+                serde_json::to_string(&node.codecs)?.into(),
+            ],
+        )?;
+        last_insert_id(&conn)
+    }
+
+    pub fn set_node_attributes(&self, id: u64, attributes: &HashMap<String, String>) -> Result<()> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            "UPDATE nodes SET attributes = ? WHERE id = ?",
+            &[
+                serde_json::to_string(attributes)?.into(),
+                (id as i64).into(),
+            ],
+        )
+    }
+
+    pub fn set_node_processes(&self, id: u64, processes: u64) -> Result<()> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            "UPDATE nodes SET processes = ? WHERE id = ?",
+            &[(processes as i64).into(), (id as i64).into()],
+        )
+    }
+
+    /// Marks the node as stopped and removes the global names of all stopped nodes.
+    pub fn stop_node(&self, id: u64, stopped_at: DateTime<Utc>) -> Result<()> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            "UPDATE nodes SET status = 2, stopped_at = ? WHERE id = ?",
+            &[stopped_at.to_rfc3339().into(), (id as i64).into()],
+        )?;
+        execute(
+            &conn,
+            "DELETE FROM registry WHERE node_id NOT IN (SELECT id FROM nodes WHERE status < 2)",
+            &[],
+        )
+    }
+
+    pub fn module(&self, id: u64) -> Result<Option<Vec<u8>>> {
+        let conn = self.lock()?;
+        let rows = query(
+            &conn,
+            "SELECT module FROM modules WHERE id = ?",
+            &[(id as i64).into()],
+            |row| Ok(row.read::<Vec<u8>, _>(0)?),
+        )?;
+        Ok(rows.into_iter().next())
+    }
+
+    pub fn module_hash(&self, id: u64) -> Result<Option<String>> {
+        let conn = self.lock()?;
+        let rows = query(
+            &conn,
+            "SELECT hash FROM modules WHERE id = ?",
+            &[(id as i64).into()],
+            |row| Ok(row.read::<String, _>(0)?),
+        )?;
+        Ok(rows.into_iter().next())
+    }
+
+    /// Adds a module and returns its ID. A module with the same `hash` keeps the ID it already
+    /// has, also if it was added by another replica.
+    pub fn add_module(&self, hash: &str, module: &[u8]) -> Result<u64> {
+        let conn = self.lock()?;
+        execute(
+            &conn,
+            "INSERT INTO modules (hash, module) VALUES (?, ?) ON CONFLICT(hash) DO NOTHING",
+            &[hash.into(), module.into()],
+        )?;
+        query(
+            &conn,
+            "SELECT id FROM modules WHERE hash = ?",
+            &[hash.into()],
+            |row| Ok(row.read::<i64, _>(0)? as u64),
+        )?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Missing module {hash}"))
+    }
+
+    pub fn load_registry(&self) -> Result<HashMap<String, RegistryEntry>> {
+        let conn = self.lock()?;
+        query(
+            &conn,
+            "SELECT name, node_id, process_id, version FROM registry",
+            &[],
+            |row| {
+                let entry = read_registry_entry(row)?;
+                Ok((entry.name.clone(), entry))
+            },
+        )
+        .map(|rows| rows.into_iter().collect())
+    }
+
+    /// Same as `ControlServer::registry_put`, checking `if_version` and writing the new
+    /// registration in one transaction.
+    pub fn registry_put(&self, put: RegistryPut) -> Result<RegistryEntry> {
+        let conn = self.lock()?;
+        transaction(&conn, || {
+            let current = query(
+                &conn,
+                "SELECT name, node_id, process_id, version FROM registry WHERE name = ?",
+                &[put.name.as_str().into()],
+                read_registry_entry,
+            )?;
+            if let Some(current) = current.into_iter().next() {
+                if matches!(put.if_version, Some(v) if v != current.version) {
+                    return Ok(current);
+                }
+            }
+            execute(
+                &conn,
+                "UPDATE counters SET value = value + 1 WHERE name = 'registry_version'",
+                &[],
+            )?;
+            let version = query(
+                &conn,
+                "SELECT value FROM counters WHERE name = 'registry_version'",
+                &[],
+                |row| Ok(row.read::<i64, _>(0)? as u64),
+            )?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Missing registry version counter"))?;
+            let entry = RegistryEntry {
+                name: put.name.clone(),
+                node_id: put.node_id,
+                process_id: put.process_id,
+                version,
+            };
+            execute(
+                &conn,
+                r#"
+                INSERT INTO registry (name, node_id, process_id, version) VALUES (?, ?, ?, ?)
+                ON CONFLICT(name) DO UPDATE SET
+                    node_id = excluded.node_id,
+                    process_id = excluded.process_id,
+                    version = excluded.version
+                "#,
+                &[
+                    entry.name.as_str().into(),
+                    (entry.node_id as i64).into(),
+                    (entry.process_id as i64).into(),
+                    (entry.version as i64).into(),
+                ],
+            )?;
+            Ok(entry)
+        })
+    }
+
+    pub fn registry_remove(&self, remove: &RegistryRemove) -> Result<()> {
+        let conn = self.lock()?;
+        let if_version = remove
+            .if_version
+            .map(|v| Value::Integer(v as i64))
+            .unwrap_or(Value::Null);
+        execute(
+            &conn,
+            "DELETE FROM registry WHERE name = ? AND (?2 IS NULL OR version = ?2)",
+            &[remove.name.as_str().into(), if_version],
+        )
+    }
+}
+
+fn execute(conn: &Connection, sql: &str, params: &[Value]) -> Result<()> {
+    query(conn, sql, params, |_| Ok(())).map(|_| ())
+}
+
+fn query<T>(
+    conn: &Connection,
+    sql: &str,
+    params: &[Value],
+    mut read: impl FnMut(&Statement) -> Result<T>,
+) -> Result<Vec<T>> {
+    let mut statement = conn.prepare(sql)?;
+    statement.bind(params)?;
+    let mut rows = Vec::new();
+    while let State::Row = statement.next()? {
+        rows.push(read(&statement)?);
+    }
+    Ok(rows)
+}
+
+fn last_insert_id(conn: &Connection) -> Result<u64> {
+    query(conn, "SELECT last_insert_rowid()", &[], |row| {
+        Ok(row.read::<i64, _>(0)? as u64)
+    })?
+    .into_iter()
+    .next()
+    .ok_or_else(|| anyhow!("Missing inserted row id"))
+}
+
+// Runs `f` in a write transaction, so that other replicas can't change the rows in between
+fn transaction<T>(conn: &Connection, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    conn.execute("BEGIN IMMEDIATE")?;
+    match f() {
+        Ok(result) => {
+            conn.execute("COMMIT")?;
+            Ok(result)
+        }
+        Err(error) => {
+            conn.execute("ROLLBACK")?;
+            Err(error)
+        }
+    }
+}
+
+fn read_registration(row: &Statement) -> Result<(u64, Registered)> {
+    let registered = Registered {
+        node_name: row.read::<String, _>(1)?.parse()?,
+        csr_pem: row.read(2)?,
+        cert_pem: row.read(3)?,
+        authentication_token: row.read(4)?,
+    };
+    Ok((row.read::<i64, _>(0)? as u64, registered))
+}
+
+fn read_registry_entry(row: &Statement) -> Result<RegistryEntry> {
+    Ok(RegistryEntry {
+        name: row.read(0)?,
+        node_id: row.read::<i64, _>(1)? as u64,
+        process_id: row.read::<i64, _>(2)? as u64,
+        version: row.read::<i64, _>(3)? as u64,
+    })
+}
+
+fn parse_datetime(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
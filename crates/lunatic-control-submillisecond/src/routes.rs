@@ -53,6 +53,11 @@ pub fn register(
             get_module: format!("http://{host}/module/{{id}}"),
             add_module: format!("http://{host}/module"),
             get_nodes: format!("http://{host}/nodes"),
+            registry: None,
+            get_module_hash: None,
+            node_tags: None,
+            node_stats: None,
+            renew_certificate: None,
         },
         envs: Vec::new(),
         is_privileged: true,
@@ -116,6 +121,10 @@ pub fn list_nodes(
                     id: k,
                     address: n.node_address.parse().unwrap(),
                     name: r.node_name.to_string(),
+                    // Codecs aren't stored, nodes don't compress messages to each other
+                    codecs: Vec::new(),
+                    processes: None,
+                    attributes: n.attributes.clone(),
                 })
         })
         .collect();
@@ -30,12 +30,57 @@ pub struct ControlUrls {
     pub get_module: String,
     pub add_module: String,
     pub get_nodes: String,
+    /// Base URL of the global process registry, `None` if the control server doesn't keep one.
+    #[serde(default)]
+    pub registry: Option<String>,
+    /// URL returning the content hash of a module, `None` if the control server doesn't support
+    /// it. Nodes use the hash to look up modules in their on-disk cache.
+    #[serde(default)]
+    pub get_module_hash: Option<String>,
+    /// URL nodes post their changed tags to, `None` if the control server doesn't support
+    /// changing them after the node started.
+    #[serde(default)]
+    pub node_tags: Option<String>,
+    /// URL nodes periodically post their `NodeStats` to, `None` if the control server doesn't
+    /// keep them.
+    #[serde(default)]
+    pub node_stats: Option<String>,
+    /// URL nodes post a `RenewCertificate` to before their certificate expires, `None` if the
+    /// control server issues certificates that don't expire.
+    #[serde(default)]
+    pub renew_certificate: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NodeStart {
     pub node_address: SocketAddr,
     pub attributes: HashMap<String, String>,
+    #[serde(default)]
+    pub codecs: Vec<String>,
+}
+
+/// Sets the attribute `key` of the node sending the request to `value`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeTag {
+    pub key: String,
+    pub value: String,
+}
+
+/// Load of the node sending the request, used to place processes spawned without a node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub processes: u64,
+}
+
+/// Asks to sign a new certificate for the node sending the request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RenewCertificate {
+    pub csr_pem: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RenewedCertificate {
+    pub cert_pem_chain: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -54,6 +99,11 @@ pub struct ModuleBytes {
     pub bytes: Vec<u8>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleHash {
+    pub hash: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AddModule {
     pub bytes: Vec<u8>,
@@ -63,3 +113,41 @@ pub struct AddModule {
 pub struct ModuleId {
     pub module_id: u64,
 }
+
+/// A process registered under a `global::` name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistryEntry {
+    pub name: String,
+    pub node_id: u64,
+    pub process_id: u64,
+    /// Assigned by the control server on every registration, never reused.
+    pub version: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistryPut {
+    pub name: String,
+    pub node_id: u64,
+    pub process_id: u64,
+    /// Only replace the current registration if it still has this version (`0` if the name must
+    /// be free). Nodes use it to replay registrations made while the control server was
+    /// unreachable, so that they don't override registrations made from the rest of the cluster.
+    pub if_version: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistryRemove {
+    pub name: String,
+    /// Only remove the registration if it still has this version.
+    pub if_version: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistryLookup {
+    pub entry: Option<RegistryEntry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegistryList {
+    pub entries: Vec<RegistryEntry>,
+}
@@ -1,6 +1,6 @@
 pub mod api;
 
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
@@ -9,4 +9,13 @@ pub struct NodeInfo {
     pub id: u64,
     pub address: SocketAddr,
     pub name: String,
+    /// Codecs the node can decompress message chunks with.
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// Number of processes running on the node as last reported, `None` if unknown.
+    #[serde(default)]
+    pub processes: Option<u64>,
+    /// Tags of the node, as set with `--tag` or changed at runtime.
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
 }
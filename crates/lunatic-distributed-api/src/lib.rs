@@ -1,13 +1,19 @@
-use std::{future::Future, sync::Arc, time::Duration};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
 use asn1_rs::ToDer;
-use lunatic_common_api::{get_memory, write_to_guest_vec, IntoTrap};
+use lunatic_common_api::{get_memory, write_to_guest_vec, InstrumentedLinker, IntoTrap};
 use lunatic_distributed::{
+    congestion::NodeStatus,
+    control::Placement,
     distributed::{
         self,
-        client::{EnvironmentId, NodeId, ProcessId, SendParams, SpawnParams},
-        message::{ClientError, Spawn, Val},
+        client::{
+            Client, EnvironmentId, LinkParams, MessageId, MonitorParams, NodeId, ProcessId,
+            ProcessParams, RemoteLinkKind, SendParams, SpawnParams, StreamParams, RESPONSE_TIMEOUT,
+        },
+        message::{ClientError, ResponseContent, Spawn, Val},
+        RemoteProcess,
     },
     CertAttrs, DistributedCtx, SUBJECT_DIR_ATTRS,
 };
@@ -15,9 +21,11 @@ use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
     env::Environment,
     message::{DataMessage, Message},
+    DeathReason, Process, Signal, WasmProcess,
 };
 use lunatic_process_api::ProcessCtx;
 use rcgen::{Certificate, CertificateParams, CertificateSigningRequest, CustomExtension, KeyPair};
+use serde::Serialize;
 use tokio::time::timeout;
 use wasmtime::{Caller, Linker, ResourceLimiter};
 
@@ -28,12 +36,37 @@ where
     E: Environment + 'static,
     for<'a> &'a T: Send,
 {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::distributed", "nodes_count", nodes_count)?;
     linker.func_wrap("lunatic::distributed", "get_nodes", get_nodes)?;
     linker.func_wrap("lunatic::distributed", "node_id", node_id)?;
     linker.func_wrap("lunatic::distributed", "module_id", module_id)?;
+    linker.func_wrap("lunatic::distributed", "node_status", node_status)?;
+    linker.func_wrap("lunatic::distributed", "node_info", node_info)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "subscribe_node_changes",
+        subscribe_node_changes,
+    )?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "unsubscribe_node_changes",
+        unsubscribe_node_changes,
+    )?;
     linker.func_wrap8_async("lunatic::distributed", "spawn", spawn)?;
+    linker.func_wrap10_async("lunatic::distributed", "spawn_auto", spawn_auto)?;
+    linker.func_wrap3_async("lunatic::distributed", "push_module", push_module)?;
     linker.func_wrap2_async("lunatic::distributed", "send", send)?;
+    linker.func_wrap3_async("lunatic::distributed", "send_confirmed", send_confirmed)?;
+    linker.func_wrap3_async("lunatic::distributed", "link", link)?;
+    linker.func_wrap2_async("lunatic::distributed", "unlink", unlink)?;
+    linker.func_wrap2_async("lunatic::distributed", "monitor", monitor)?;
+    linker.func_wrap2_async("lunatic::distributed", "demonitor", demonitor)?;
+    linker.func_wrap2_async("lunatic::distributed", "kill", kill)?;
+    linker.func_wrap2_async("lunatic::distributed", "exists", exists)?;
+    linker.func_wrap("lunatic::distributed", "stream_open", stream_open)?;
+    linker.func_wrap4_async("lunatic::distributed", "stream_write", stream_write)?;
+    linker.func_wrap2_async("lunatic::distributed", "stream_close", stream_close)?;
     linker.func_wrap4_async(
         "lunatic::distributed",
         "send_receive_skip_search",
@@ -44,6 +77,7 @@ where
         "exec_lookup_nodes",
         exec_lookup_nodes,
     )?;
+    linker.func_wrap5_async("lunatic::distributed", "set_node_tag", set_node_tag)?;
     linker.func_wrap(
         "lunatic::distributed",
         "copy_lookup_nodes_results",
@@ -153,6 +187,66 @@ where
     })
 }
 
+// Sets the tag **key** of the current node to **value**, so that lookup node queries from all
+// nodes see the new value. This allows routing processes based on changing signals like the
+// current load or a maintenance mode.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_ptr**
+//
+// Traps:
+// * If the process doesn't have permissions to change the tags of the node.
+// * If the key or value is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn set_node_tag<T, E>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        if !caller.data().can_set_node_tags() {
+            return Err(anyhow!(
+                "Process doesn't have permissions to change the tags of the node"
+            ));
+        }
+        let memory = get_memory(&mut caller)?;
+        let key = memory
+            .data(&caller)
+            .get(key_ptr as usize..(key_ptr + key_len) as usize)
+            .or_trap("lunatic::distributed::set_node_tag::key_ptr")?;
+        let key = std::str::from_utf8(key)
+            .or_trap("lunatic::distributed::set_node_tag::key_utf8")?
+            .to_owned();
+        let value = memory
+            .data(&caller)
+            .get(value_ptr as usize..(value_ptr + value_len) as usize)
+            .or_trap("lunatic::distributed::set_node_tag::value_ptr")?;
+        let value = std::str::from_utf8(value)
+            .or_trap("lunatic::distributed::set_node_tag::value_utf8")?
+            .to_owned();
+        let control = caller.data().distributed()?.control.clone();
+        match control.set_node_tag(&key, &value).await {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::set_node_tag::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
 // Copies node ids to guest memory from the lookup node query result, returns number of node ids copied.
 //
 // Traps:
@@ -366,6 +460,7 @@ where
 // * 0      on success - The ID of the newly created process is written to `id_ptr`
 // * 1      If node does not exist
 // * 2      If module does not exist
+// * 3      If this or the target node doesn't have access to the environment
 // * 9027   If node connection error occurred
 //
 // Traps:
@@ -390,106 +485,293 @@ where
     for<'a> &'a T: Send,
 {
     Box::new(async move {
-        if !caller.data().can_spawn() {
-            return Err(anyhow!(
-                "Process doesn't have permissions to spawn sub-processes"
-            ));
-        }
+        let (process_or_error_id, ret) = spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            (func_str_ptr, func_str_len),
+            (params_ptr, params_len),
+        )
+        .await?;
+
         let memory = get_memory(&mut caller)?;
-        let func_str = memory
-            .data(&caller)
-            .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
-            .or_trap("lunatic::distributed::spawn::func_str")?;
+        memory
+            .write(
+                &mut caller,
+                id_ptr as usize,
+                &process_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::spawn::write_id")?;
 
-        let function =
-            std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
+        Ok(ret)
+    })
+}
 
-        let params = memory
+// Same as `spawn`, but the host picks the node to spawn on. Only nodes with tags matching the
+// query are considered, the query has the same format as in `exec_lookup_nodes`.
+//
+// The `strategy` decides which of the matching nodes is used:
+// * 0 - round-robin, each matching node in turn
+// * 1 - least processes, the node with the fewest running processes as last reported by the
+//       nodes, taking turns between nodes with the same number
+// * 2 - random
+//
+// Returns:
+// * 0      on success - The ID of the node followed by the ID of the newly created process are
+//                       written to `id_ptr`
+// * 1      If no node matches the query, or the nodes couldn't be looked up
+// * 2      If module does not exist
+// * 3      If this or the target node doesn't have access to the environment
+// * 9027   If node connection error occurred
+//
+// On errors the error ID is written to `id_ptr`.
+//
+// Traps:
+// * If the strategy is not one of the above.
+// * If the query or function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_auto<T, E>(
+    mut caller: Caller<T>,
+    query_ptr: u32,
+    query_len: u32,
+    strategy: u32,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let placement = match strategy {
+            0 => Placement::RoundRobin,
+            1 => Placement::LeastProcesses,
+            2 => Placement::Random,
+            _ => {
+                return Err(anyhow!(
+                    "lunatic::distributed::spawn_auto: Unknown strategy"
+                ))
+            }
+        };
+        let memory = get_memory(&mut caller)?;
+        let query = memory
             .data(&caller)
-            .get(params_ptr as usize..(params_ptr + params_len) as usize)
-            .or_trap("lunatic::distributed::spawn::params")?;
-        let params = params
-            .chunks_exact(17)
-            .map(|chunk| {
-                let value = u128::from_le_bytes(chunk[1..].try_into()?);
-                let result = match chunk[0] {
-                    0x7F => Val::I32(value as i32),
-                    0x7E => Val::I64(value as i64),
-                    0x7B => Val::V128(value),
-                    _ => return Err(anyhow!("Unsupported type ID")),
-                };
-                Ok(result)
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let state = caller.data();
+            .get(query_ptr as usize..(query_ptr + query_len) as usize)
+            .or_trap("lunatic::distributed::spawn_auto::query_ptr")?;
+        let query = std::str::from_utf8(query)
+            .or_trap("lunatic::distributed::spawn_auto::query_str_utf8")?;
 
-        let config = match config_id {
-            -1 => state.config().clone(),
-            config_id => Arc::new(
-                caller
-                    .data()
-                    .config_resources()
-                    .get(config_id as u64)
-                    .or_trap("lunatic::distributed::spawn: Config ID doesn't exist")?
-                    .clone(),
-            ),
+        let control = caller.data().distributed()?.control.clone();
+        let placed = control
+            .place(query, placement)
+            .await
+            .and_then(|node| node.ok_or_else(|| anyhow!("No node matches the query '{query}'.")));
+        let node_id = match placed {
+            Ok(node_id) => node_id,
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::spawn_auto::write_id")?;
+                return Ok(1);
+            }
         };
-        let config: Vec<u8> =
-            rmp_serde::to_vec(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
 
-        log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
+        let (process_or_error_id, ret) = spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            (func_str_ptr, func_str_len),
+            (params_ptr, params_len),
+        )
+        .await?;
 
-        let self_node_id = state.distributed()?.node_id();
-        let spawn_params = SpawnParams {
-            env: EnvironmentId(state.environment_id()),
-            src: ProcessId(state.id()),
-            node: NodeId(node_id),
-            spawn: Spawn {
-                response_node_id: self_node_id,
-                environment_id: state.environment_id(),
-                function: function.to_string(),
-                module_id,
-                params,
-                config,
-            },
+        let ids = if ret == 0 {
+            [node_id.to_le_bytes(), process_or_error_id.to_le_bytes()].concat()
+        } else {
+            process_or_error_id.to_le_bytes().to_vec()
         };
-        let node_client = state.distributed()?.node_client.clone();
-        let spawn_response = node_client
-            .spawn(spawn_params)
-            .await
-            .map(|message_id| node_client.await_response(message_id))?
-            .await?;
-        let (process_or_error_id, ret) = match spawn_response {
-            distributed::message::ResponseContent::Spawned(process_id) => Ok((process_id, 0)),
-            distributed::message::ResponseContent::Error(error) => {
-                let (code, message): (u32, String) = match error {
-                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
-                    ClientError::Connection(cause) => Ok((9027, cause)),
-                    ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
-                    ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
-                    ClientError::ProcessNotFound => Err(anyhow!("unreachable")),
-                }?;
-                Ok((
-                    caller
-                        .data_mut()
-                        .error_resources_mut()
-                        .add(anyhow!(message)),
-                    code,
-                ))
-            }
-            _ => Err(anyhow!("unreachable")),
-        }?;
+        memory
+            .write(&mut caller, id_ptr as usize, &ids)
+            .or_trap("lunatic::distributed::spawn_auto::write_id")?;
 
+        Ok(ret)
+    })
+}
+
+// Spawns the process on the node and returns the ID of the process or of the error, together
+// with the return code of `spawn`.
+async fn spawn_on_node<T, E>(
+    caller: &mut Caller<'_, T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    (func_str_ptr, func_str_len): (u32, u32),
+    (params_ptr, params_len): (u32, u32),
+) -> Result<(u64, u32)>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    if !caller.data().can_spawn() {
+        return Err(anyhow!(
+            "Process doesn't have permissions to spawn sub-processes"
+        ));
+    }
+    let memory = get_memory(caller)?;
+    let func_str = memory
+        .data(&*caller)
+        .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
+        .or_trap("lunatic::distributed::spawn::func_str")?;
+
+    let function =
+        std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
+
+    let params = memory
+        .data(&*caller)
+        .get(params_ptr as usize..(params_ptr + params_len) as usize)
+        .or_trap("lunatic::distributed::spawn::params")?;
+    let params = params
+        .chunks_exact(17)
+        .map(|chunk| {
+            let value = u128::from_le_bytes(chunk[1..].try_into()?);
+            let result = match chunk[0] {
+                0x7F => Val::I32(value as i32),
+                0x7E => Val::I64(value as i64),
+                0x7B => Val::V128(value),
+                _ => return Err(anyhow!("Unsupported type ID")),
+            };
+            Ok(result)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let state = caller.data();
+
+    let config = match config_id {
+        -1 => state.config().clone(),
+        config_id => Arc::new(
+            caller
+                .data()
+                .config_resources()
+                .get(config_id as u64)
+                .or_trap("lunatic::distributed::spawn: Config ID doesn't exist")?
+                .clone(),
+        ),
+    };
+    let config: Vec<u8> =
+        rmp_serde::to_vec(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
+
+    log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
+
+    let self_node_id = state.distributed()?.node_id();
+    let spawn_params = SpawnParams {
+        env: EnvironmentId(state.environment_id()),
+        src: ProcessId(state.id()),
+        node: NodeId(node_id),
+        spawn: Spawn {
+            response_node_id: self_node_id,
+            environment_id: state.environment_id(),
+            function: function.to_string(),
+            module_id,
+            params,
+            config,
+        },
+    };
+    let node_client = state.distributed()?.node_client.clone();
+    let spawn_response = node_client
+        .spawn(spawn_params)
+        .await
+        .map(|message_id| node_client.await_response(message_id))?
+        .await?;
+    match spawn_response {
+        distributed::message::ResponseContent::Spawned(process_id) => Ok((process_id, 0)),
+        distributed::message::ResponseContent::Error(error) => {
+            let (code, message): (u32, String) = match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::Connection(cause) => Ok((9027, cause)),
+                ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
+                ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
+                ClientError::ProcessNotFound => Err(anyhow!("unreachable")),
+                ClientError::Unauthorized(cause) => Ok((3, cause)),
+            }?;
+            Ok((
+                caller
+                    .data_mut()
+                    .error_resources_mut()
+                    .add(anyhow!(message)),
+                code,
+            ))
+        }
+        _ => Err(anyhow!("unreachable")),
+    }
+}
+
+// Uploads the module `module_data` to the control server, so that processes can be spawned from
+// it on any node by passing its ID to `spawn`.
+//
+// Pushing the same module again returns the same ID. Nodes download the module only once and
+// keep it in their module cache.
+//
+// Returns:
+// * 0 on success - The module ID is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**
+//
+// Traps:
+// * If the process doesn't have permissions to spawn sub-processes.
+// * If the node is not part of a cluster.
+// * If any memory outside the guest heap space is referenced.
+fn push_module<T, E>(
+    mut caller: Caller<T>,
+    module_data_ptr: u32,
+    module_data_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        if !caller.data().can_spawn() {
+            return Err(anyhow!(
+                "Process doesn't have permissions to spawn sub-processes"
+            ));
+        }
+        let memory = get_memory(&mut caller)?;
+        let module = memory
+            .data(&caller)
+            .get(module_data_ptr as usize..(module_data_ptr + module_data_len) as usize)
+            .or_trap("lunatic::distributed::push_module::module_data")?
+            .to_vec();
+        let control = caller.data().distributed()?.control.clone();
+        let (module_or_error_id, result) = match control.add_module(module).await {
+            Ok(module) => (
+                module
+                    .id
+                    .or_trap("lunatic::distributed::push_module::module_id")?,
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
         memory
             .write(
                 &mut caller,
                 id_ptr as usize,
-                &process_or_error_id.to_le_bytes(),
+                &module_or_error_id.to_le_bytes(),
             )
-            .or_trap("lunatic::distributed::spawn::write_id")?;
-
-        Ok(ret)
+            .or_trap("lunatic::distributed::push_module::write_id")?;
+        Ok(result)
     })
 }
 
@@ -527,6 +809,7 @@ where
             tag,
             buffer,
             resources,
+            headers,
             ..
         }) = message
         {
@@ -542,6 +825,7 @@ where
                 dest: ProcessId(process_id),
                 tag,
                 data: buffer,
+                headers,
             };
             match state.distributed()?.node_client.send(send_params).await {
                 Ok(_) => Ok(0),
@@ -553,6 +837,539 @@ where
     })
 }
 
+// Sends the message in scratch area to a process running on a node with id `node_id` and waits
+// for the node to confirm that the message was put into the mailbox of the process.
+//
+// If timeout is specified (value different from u64::MAX), the function will return on timeout
+// expiration with value 9027. It also returns 9027 if the node doesn't confirm the message within
+// the response timeout of the node connection. The message could still be delivered in these
+// cases, so guests retrying the send get at-least-once delivery.
+//
+// Returns:
+// * 0      If message was delivered
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If this or the target node doesn't have access to the environment
+// * 9027   If call timed out.
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn send_confirmed<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::send_confirmed::no_message")?;
+
+        if let Message::Data(DataMessage {
+            tag,
+            buffer,
+            resources,
+            headers,
+            ..
+        }) = message
+        {
+            if !resources.is_empty() {
+                return Err(anyhow!("Cannot send resources to remote nodes."));
+            }
+
+            let state = caller.data();
+            let send_params = SendParams {
+                env: EnvironmentId(state.environment_id()),
+                src: ProcessId(state.id()),
+                node: NodeId(node_id),
+                dest: ProcessId(process_id),
+                tag,
+                data: buffer,
+                headers,
+            };
+            let node_client = state.distributed()?.node_client.clone();
+            let message_id = match node_client.send_confirmed(send_params).await {
+                Ok(message_id) => message_id,
+                Err(_) => return Ok(2),
+            };
+            let _forget = ForgetResponse(&node_client, message_id);
+            let response = node_client.await_response(message_id);
+            let response = match timeout_duration {
+                // Without timeout
+                u64::MAX => response.await,
+                // With timeout
+                t => match timeout(Duration::from_millis(t), response).await {
+                    Ok(response) => response,
+                    Err(_) => return Ok(9027),
+                },
+            };
+            match response? {
+                ResponseContent::Sent => Ok(0),
+                ResponseContent::Error(ClientError::ProcessNotFound) => Ok(1),
+                ResponseContent::Error(ClientError::NodeNotFound) => Ok(2),
+                ResponseContent::Error(ClientError::Unauthorized(_)) => Ok(3),
+                ResponseContent::Error(ClientError::Connection(_)) => Ok(9027),
+                ResponseContent::Error(ClientError::Unexpected(cause))
+                    if cause == RESPONSE_TIMEOUT =>
+                {
+                    Ok(9027)
+                }
+                ResponseContent::Error(error) => Err(anyhow!("{error:?}")),
+                response => Err(anyhow!("Unexpected response {response:?}")),
+            }
+        } else {
+            Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    })
+}
+
+// Stops waiting for the response when dropped, also if the waiting process is killed
+struct ForgetResponse<'a>(&'a Client, MessageId);
+
+impl Drop for ForgetResponse<'_> {
+    fn drop(&mut self) {
+        self.0.forget_response(self.1);
+    }
+}
+
+// Links the current process with the process **process_id** running on the node **node_id**.
+//
+// Same as a local link, if one of the processes dies the other one receives a `LinkDied` signal
+// with the **tag**. If the remote process doesn't exist, or its node leaves the cluster, the
+// link dies with the "no process" reason.
+fn link<T, E>(
+    caller: Caller<T>,
+    tag: i64,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let tag = match tag {
+            0 => None,
+            tag => Some(tag),
+        };
+        let state = caller.data();
+        let signal_mailbox = state.signal_mailbox().0.clone();
+        let this_process: Arc<dyn Process> =
+            Arc::new(WasmProcess::new(state.id(), signal_mailbox.clone()));
+        let node_client = state.distributed()?.node_client.clone();
+        let (env, node, remote_id) = (
+            EnvironmentId(state.environment_id()),
+            NodeId(node_id),
+            ProcessId(process_id),
+        );
+
+        // Track the link before sending the request, so that the node leaving is not missed
+        node_client.add_remote_link(
+            node,
+            RemoteLinkKind::Link,
+            env,
+            this_process.clone(),
+            remote_id,
+            tag,
+        );
+        let remote = RemoteProcess::new(node_client.clone(), env, node, remote_id);
+        signal_mailbox
+            .send(Signal::Link(tag, Arc::new(remote)))
+            .expect("The Link signal is sent to itself and the receiver must exist at this point");
+
+        let link_params = LinkParams {
+            env,
+            src: ProcessId(this_process.id()),
+            node,
+            dest: remote_id,
+            tag,
+        };
+        let linked = match node_client.link(link_params).await {
+            Ok(message_id) => matches!(
+                node_client.await_response(message_id).await,
+                Ok(ResponseContent::Linked)
+            ),
+            Err(_) => false,
+        };
+        if !linked {
+            node_client.remove_remote_link(
+                node,
+                RemoteLinkKind::Link,
+                env,
+                ProcessId(this_process.id()),
+                remote_id,
+            );
+            this_process.send(Signal::LinkDied(
+                Some(node_id),
+                process_id,
+                tag,
+                DeathReason::NoProcess,
+                None,
+            ));
+        }
+        Ok(())
+    })
+}
+
+// Unlinks the current process from the process **process_id** running on the node **node_id**.
+// This is not an atomic operation.
+fn unlink<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        state
+            .signal_mailbox()
+            .0
+            .send(Signal::UnLink {
+                node_id: Some(node_id),
+                process_id,
+            })
+            .expect("The signal is sent to itself and the receiver must exist at this point");
+
+        let node_client = state.distributed()?.node_client.clone();
+        let link_params = LinkParams {
+            env: EnvironmentId(state.environment_id()),
+            src: ProcessId(state.id()),
+            node: NodeId(node_id),
+            dest: ProcessId(process_id),
+            tag: None,
+        };
+        node_client.remove_remote_link(
+            link_params.node,
+            RemoteLinkKind::Link,
+            link_params.env,
+            link_params.src,
+            link_params.dest,
+        );
+        if let Err(error) = node_client.unlink(link_params).await {
+            log::debug!("Failed to unlink process {process_id} on node {node_id}: {error}");
+        }
+        Ok(())
+    })
+}
+
+// Starts monitoring the process **process_id** running on the node **node_id**.
+//
+// Once the monitored process dies, a `ProcessDied` message is put into the mailbox of the current
+// process. If the remote process doesn't exist, or its node leaves the cluster, the message is
+// delivered with the "no process" reason.
+fn monitor<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let this_process: Arc<dyn Process> = Arc::new(WasmProcess::new(
+            state.id(),
+            state.signal_mailbox().0.clone(),
+        ));
+        let node_client = state.distributed()?.node_client.clone();
+        let monitor_params = MonitorParams {
+            env: EnvironmentId(state.environment_id()),
+            src: ProcessId(state.id()),
+            node: NodeId(node_id),
+            dest: ProcessId(process_id),
+        };
+
+        node_client.add_remote_link(
+            monitor_params.node,
+            RemoteLinkKind::Monitor,
+            monitor_params.env,
+            this_process.clone(),
+            monitor_params.dest,
+            None,
+        );
+        let (env, node, src, dest) = (
+            monitor_params.env,
+            monitor_params.node,
+            monitor_params.src,
+            monitor_params.dest,
+        );
+        let monitoring = match node_client.monitor(monitor_params).await {
+            Ok(message_id) => matches!(
+                node_client.await_response(message_id).await,
+                Ok(ResponseContent::Monitoring)
+            ),
+            Err(_) => false,
+        };
+        if !monitoring {
+            node_client.remove_remote_link(node, RemoteLinkKind::Monitor, env, src, dest);
+            this_process.send(Signal::ProcessDied(process_id, DeathReason::NoProcess));
+        }
+        Ok(())
+    })
+}
+
+// Stops monitoring the process **process_id** running on the node **node_id**. This is not an
+// atomic operation, a `ProcessDied` message could already be in the mailbox.
+fn demonitor<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let monitor_params = MonitorParams {
+            env: EnvironmentId(state.environment_id()),
+            src: ProcessId(state.id()),
+            node: NodeId(node_id),
+            dest: ProcessId(process_id),
+        };
+        node_client.remove_remote_link(
+            monitor_params.node,
+            RemoteLinkKind::Monitor,
+            monitor_params.env,
+            monitor_params.src,
+            monitor_params.dest,
+        );
+        if let Err(error) = node_client.stop_monitoring(monitor_params).await {
+            log::debug!(
+                "Failed to stop monitoring process {process_id} on node {node_id}: {error}"
+            );
+        }
+        Ok(())
+    })
+}
+
+// Kills the process **process_id** running on the node **node_id**.
+//
+// Returns:
+// * 0      If the kill signal was delivered
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If this or the target node doesn't have access to the environment
+// * 9027   If the node disconnected before confirming the kill
+fn kill<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let params = ProcessParams {
+            env: EnvironmentId(state.environment_id()),
+            src: ProcessId(state.id()),
+            node: NodeId(node_id),
+            dest: ProcessId(process_id),
+        };
+        let message_id = match node_client.kill(params).await {
+            Ok(message_id) => message_id,
+            Err(_) => return Ok(2),
+        };
+        match node_client.await_response(message_id).await? {
+            ResponseContent::Killed => Ok(0),
+            ResponseContent::Error(ClientError::ProcessNotFound) => Ok(1),
+            ResponseContent::Error(ClientError::NodeNotFound) => Ok(2),
+            ResponseContent::Error(ClientError::Unauthorized(_)) => Ok(3),
+            ResponseContent::Error(ClientError::Connection(_)) => Ok(9027),
+            ResponseContent::Error(error) => Err(anyhow!("{error:?}")),
+            response => Err(anyhow!("Unexpected response {response:?}")),
+        }
+    })
+}
+
+// Checks if the process **process_id** is running on the node **node_id**.
+//
+// Returns:
+// * 0      If the process doesn't exist
+// * 1      If the process exists
+// * 2      If node_id does not exist
+// * 3      If this or the target node doesn't have access to the environment
+// * 9027   If the node disconnected before answering
+fn exists<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let params = ProcessParams {
+            env: EnvironmentId(state.environment_id()),
+            src: ProcessId(state.id()),
+            node: NodeId(node_id),
+            dest: ProcessId(process_id),
+        };
+        let message_id = match node_client.exists(params).await {
+            Ok(message_id) => message_id,
+            Err(_) => return Ok(2),
+        };
+        match node_client.await_response(message_id).await? {
+            ResponseContent::Exists(exists) => Ok(exists as u32),
+            ResponseContent::Error(ClientError::NodeNotFound) => Ok(2),
+            ResponseContent::Error(ClientError::Unauthorized(_)) => Ok(3),
+            ResponseContent::Error(ClientError::Connection(_)) => Ok(9027),
+            ResponseContent::Error(error) => Err(anyhow!("{error:?}")),
+            response => Err(anyhow!("Unexpected response {response:?}")),
+        }
+    })
+}
+
+// Opens a byte stream to the process **process_id** running on the node **node_id** and returns
+// the ID of the stream.
+//
+// Large payloads can be written to the stream in chunks, instead of sending them as one message
+// that needs to fit into memory on both nodes. Every chunk arrives as a message with the **tag**
+// (0 for no tag) and the `lunatic.stream.id` header containing the stream ID as little endian
+// u64. The last message of the stream is empty and has the `lunatic.stream.end` header.
+//
+// Traps:
+// * If the node is not part of a cluster.
+fn stream_open<T, E>(caller: Caller<T>, node_id: u64, process_id: u64, tag: i64) -> Result<u64>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let state = caller.data();
+    let params = StreamParams {
+        env: EnvironmentId(state.environment_id()),
+        src: ProcessId(state.id()),
+        node: NodeId(node_id),
+        dest: ProcessId(process_id),
+        tag: match tag {
+            0 => None,
+            tag => Some(tag),
+        },
+    };
+    Ok(state.distributed()?.node_client.stream_open(params))
+}
+
+// Writes the next chunk to the stream **stream_id**.
+//
+// Only a few chunks can be on their way before the receiving process reads them from its
+// mailbox, after that writing waits for the reader to catch up. If timeout is specified (value
+// different from u64::MAX), the function will return on timeout expiration with value 9027 and
+// the chunk is not written.
+//
+// Returns:
+// * 0      If the chunk was written
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If this or the target node doesn't have access to the environment
+// * 9027   If call timed out or the node is unreachable.
+//
+// Traps:
+// * If the stream doesn't exist or was closed.
+// * If any memory outside the guest heap space is referenced.
+fn stream_write<T, E>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    data_ptr: u32,
+    data_len: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let data = memory
+            .data(&caller)
+            .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
+            .or_trap("lunatic::distributed::stream_write")?
+            .to_vec();
+        let state = caller.data();
+        let (env, src) = (EnvironmentId(state.environment_id()), ProcessId(state.id()));
+        let timeout = match timeout_duration {
+            u64::MAX => None,
+            t => Some(Duration::from_millis(t)),
+        };
+        let node_client = state.distributed()?.node_client.clone();
+        let response = node_client
+            .stream_write(env, src, stream_id, data, timeout)
+            .await?;
+        stream_result(response)
+    })
+}
+
+// Closes the stream **stream_id** and waits until the receiving process read all chunks.
+//
+// If timeout is specified (value different from u64::MAX), the function will return on timeout
+// expiration with value 9027. In that case the stream stays open and closing can be retried.
+//
+// Returns the same values as `stream_write`.
+//
+// Traps:
+// * If the stream doesn't exist.
+fn stream_close<T, E>(
+    caller: Caller<T>,
+    stream_id: u64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let (env, src) = (EnvironmentId(state.environment_id()), ProcessId(state.id()));
+        let timeout = match timeout_duration {
+            u64::MAX => None,
+            t => Some(Duration::from_millis(t)),
+        };
+        let node_client = state.distributed()?.node_client.clone();
+        let response = node_client
+            .stream_close(env, src, stream_id, timeout)
+            .await?;
+        stream_result(response)
+    })
+}
+
+fn stream_result(response: Option<ResponseContent>) -> Result<u32> {
+    match response {
+        Some(ResponseContent::Sent) => Ok(0),
+        Some(ResponseContent::Error(ClientError::ProcessNotFound)) => Ok(1),
+        Some(ResponseContent::Error(ClientError::NodeNotFound)) => Ok(2),
+        Some(ResponseContent::Error(ClientError::Unauthorized(_))) => Ok(3),
+        Some(ResponseContent::Error(ClientError::Connection(_))) | None => Ok(9027),
+        Some(ResponseContent::Error(error)) => Err(anyhow!("{error:?}")),
+        Some(response) => Err(anyhow!("Unexpected response {response:?}")),
+    }
+}
+
 // Sends the message to a process on a node with id `node_id` and waits for a reply,
 // but doesn't look through existing messages in the mailbox queue while waiting.
 // This is an optimization that only makes sense with tagged messages.
@@ -597,6 +1414,7 @@ where
             tag,
             buffer,
             resources,
+            headers,
             ..
         }) = message
         {
@@ -612,6 +1430,7 @@ where
                 dest: ProcessId(process_id),
                 tag,
                 data: buffer,
+                headers,
             };
             let code = match state.distributed()?.node_client.send(send_params).await {
                 Ok(_) => Ok(0),
@@ -630,7 +1449,11 @@ where
                 // With timeout
                 t => timeout(Duration::from_millis(t), pop_skip_search).await,
             } {
-                // Put the message into the scratch area
+                // Put the message into the scratch area, taking over its trace context
+                if let Message::Data(data) = &message {
+                    let context = data.trace_context().map(|context| context.to_vec());
+                    caller.data_mut().set_trace_context(context);
+                }
                 caller.data_mut().message_scratch_area().replace(message);
                 Ok(0)
             } else {
@@ -656,6 +1479,127 @@ where
         .unwrap_or(0)
 }
 
+// Returns the state of the connection to the node **node_id**.
+//
+// After failing to connect multiple times in a row, the node is considered unreachable and
+// requests to it fail right away, while reconnecting continues in the background with
+// exponential backoff.
+//
+// Returns:
+// * 0 If the node is connected
+// * 1 If the node is being connected or reconnected
+// * 2 If the node is unreachable
+// * 3 If nothing was sent to the node yet, or the node is not part of a cluster
+fn node_status<T, E>(caller: Caller<T>, node_id: u64) -> u32
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let status = caller
+        .data()
+        .distributed()
+        .ok()
+        .and_then(|d| d.node_client.node_status(NodeId(node_id)));
+    status_code(status)
+}
+
+fn status_code(status: Option<NodeStatus>) -> u32 {
+    match status {
+        Some(NodeStatus::Connected) => 0,
+        Some(NodeStatus::Connecting) => 1,
+        Some(NodeStatus::Unreachable) => 2,
+        None => 3,
+    }
+}
+
+// Information about a node, as serialized by `node_info`
+#[derive(Serialize)]
+struct NodeDetails {
+    name: String,
+    address: String,
+    tags: HashMap<String, String>,
+    status: u32,
+}
+
+// Writes the bincode serialized name, address, tags and connection status of the node
+// **node_id** to **ptr**, if the serialized info fits into **len** bytes. The status has the same
+// values as returned by `node_status`, the current node is always connected.
+//
+// Returns:
+// * The size of the serialized info, nothing is written if it's bigger than **len**
+// * -1 If the node is not known, or the current node is not part of a cluster
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn node_info<T, E>(mut caller: Caller<T>, node_id: u64, ptr: u32, len: u32) -> Result<i32>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let distributed = match caller.data().distributed() {
+        Ok(distributed) => distributed,
+        Err(_) => return Ok(-1),
+    };
+    let node = match distributed.control.node_info(node_id) {
+        Some(node) => node,
+        None => return Ok(-1),
+    };
+    let status = if node_id == distributed.node_id() {
+        Some(NodeStatus::Connected)
+    } else {
+        distributed.node_client.node_status(NodeId(node_id))
+    };
+    let details = NodeDetails {
+        name: node.name,
+        address: node.address.to_string(),
+        tags: node.attributes,
+        status: status_code(status),
+    };
+    let data = bincode::serialize(&details).or_trap("lunatic::distributed::node_info")?;
+    if data.len() <= len as usize {
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, ptr as usize, &data)
+            .or_trap("lunatic::distributed::node_info")?;
+    }
+    Ok(data.len() as i32)
+}
+
+// Sends a message with **tag** to the calling process every time a node joins or leaves the
+// cluster. The message contains the byte 0 if the node joined or 1 if it left, followed by the
+// node ID as little endian u64. Node changes are noticed when the list of nodes is refreshed,
+// every few seconds.
+//
+// Subscribing again replaces the tag. Outside of a cluster no messages are sent.
+fn subscribe_node_changes<T, E>(caller: Caller<T>, tag: i64)
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let state = caller.data();
+    if let Ok(distributed) = state.distributed() {
+        if let Some(process) = state.environment().get_process(state.id()) {
+            distributed
+                .control
+                .subscribe_node_changes(state.environment_id(), process, tag);
+        }
+    }
+}
+
+// Stops the messages sent after `subscribe_node_changes`.
+fn unsubscribe_node_changes<T, E>(caller: Caller<T>)
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let state = caller.data();
+    if let Ok(distributed) = state.distributed() {
+        distributed
+            .control
+            .unsubscribe_node_changes(state.environment_id(), state.id());
+    }
+}
+
 // Returns id of the module that the current process is spawned from
 fn module_id<T, E>(caller: Caller<T>) -> u64
 where
@@ -0,0 +1,92 @@
+use std::{fmt::Display, io::Read, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+/// Set in the `chunk_size` header field if the chunk is compressed. The codec is written as a
+/// single byte after the header then.
+///
+/// Chunks are only compressed if the receiving node advertised the codec, so nodes that don't
+/// know about compression never see the flag.
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Chunks can't be bigger than this, because the highest bit of the size is the compression flag.
+pub const MAX_CHUNK_SIZE: usize = (COMPRESSED_FLAG - 1) as usize;
+
+const ZSTD_LEVEL: i32 = 1;
+
+/// Compression of message chunks sent to other nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Codec {
+    #[default]
+    None = 0,
+    Zstd = 1,
+}
+
+impl Codec {
+    /// Codecs this node can decompress, advertised to the other nodes through the control server.
+    pub const SUPPORTED: [Codec; 1] = [Codec::Zstd];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            _ => Err(anyhow!("Unknown codec {value}")),
+        }
+    }
+
+    /// Returns the compressed data, or `None` if it didn't get smaller.
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Codec::None => return None,
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+        };
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+
+    /// Decompresses a chunk that can't be bigger than `max_size` bytes.
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::with_capacity(data.len());
+        match self {
+            Codec::None => decompressed.extend_from_slice(data),
+            Codec::Zstd => {
+                zstd::stream::read::Decoder::new(data)?
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+            }
+        };
+        if decompressed.len() > max_size {
+            return Err(anyhow!("Decompressed chunk is bigger than the message"));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(anyhow!("Unknown codec '{s}', expected 'none' or 'zstd'")),
+        }
+    }
+}
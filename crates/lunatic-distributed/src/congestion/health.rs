@@ -0,0 +1,158 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use lunatic_process::events::{self, Event};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Consecutive failed connection attempts after which the node is considered unreachable
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// State of the connection to another node.
+///
+/// Works as a circuit breaker: once connecting failed 5 times in a row the node is
+/// `Unreachable` and requests to it fail right away, instead of waiting for a response
+/// that can't arrive. Reconnecting continues in the background and closes the circuit again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NodeStatus {
+    Connected = 0,
+    Connecting = 1,
+    Unreachable = 2,
+}
+
+/// Connection health of a node, shared between its node connection manager and the client.
+#[derive(Debug)]
+pub struct NodeHealth {
+    status: AtomicU8,
+    failures: AtomicU32,
+    connections: AtomicU64,
+    // Last measured round trip time in microseconds, `0` if never measured
+    rtt_micros: AtomicU64,
+}
+
+impl Default for NodeHealth {
+    fn default() -> Self {
+        Self {
+            status: AtomicU8::new(NodeStatus::Connecting as u8),
+            failures: AtomicU32::new(0),
+            connections: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl NodeHealth {
+    pub fn status(&self) -> NodeStatus {
+        match self.status.load(Ordering::Relaxed) {
+            0 => NodeStatus::Connected,
+            1 => NodeStatus::Connecting,
+            _ => NodeStatus::Unreachable,
+        }
+    }
+
+    /// Number of times the connection was established again after it was lost.
+    pub fn reconnects(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    /// Last measured round trip time to the node.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    pub(crate) fn connected(&self, node_id: u64) {
+        events::record(None, Event::NodeConnected { node_id });
+        self.failures.store(0, Ordering::Relaxed);
+        let previous = self
+            .status
+            .swap(NodeStatus::Connected as u8, Ordering::Relaxed);
+        if previous == NodeStatus::Unreachable as u8 {
+            log::info!("Node {node_id} is reachable again");
+        }
+        if self.connections.fetch_add(1, Ordering::Relaxed) > 0 {
+            log::debug!("Reconnected to node {node_id}");
+            #[cfg(feature = "metrics")]
+            metrics::increment_counter!(
+                "lunatic.distributed.node.reconnects",
+                "node_id" => node_id.to_string()
+            );
+        }
+    }
+
+    pub(crate) fn disconnected(&self, node_id: u64) {
+        events::record(None, Event::NodeDisconnected { node_id });
+        self.status
+            .store(NodeStatus::Connecting as u8, Ordering::Relaxed);
+        log::debug!("Connection to node {node_id} lost, reconnecting");
+    }
+
+    /// Records a failed connection attempt and returns how long to wait before the next one.
+    pub(crate) fn failed(&self, node_id: u64) -> Duration {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures == FAILURE_THRESHOLD {
+            log::warn!("Node {node_id} is unreachable after {failures} connection attempts");
+            self.status
+                .store(NodeStatus::Unreachable as u8, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!(
+            "lunatic.distributed.node.connection_failures",
+            "node_id" => node_id.to_string()
+        );
+        backoff(failures)
+    }
+
+    pub(crate) fn record_rtt(&self, node_id: u64, rtt: Duration) {
+        self.rtt_micros
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+        log::trace!("node={node_id} rtt={rtt:?}");
+        #[cfg(feature = "metrics")]
+        metrics::histogram!(
+            "lunatic.distributed.node.rtt",
+            rtt.as_secs_f64(),
+            "node_id" => node_id.to_string()
+        );
+    }
+}
+
+// Exponential backoff with "equal jitter": half of the delay is fixed and half is random, so that
+// nodes losing the same peer at the same time don't all reconnect at once
+fn backoff(failures: u32) -> Duration {
+    let exponential = INITIAL_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF);
+    let half = exponential / 2;
+    let random = RandomState::new().build_hasher().finish();
+    half + half.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_histogram, Unit};
+
+    describe_counter!(
+        "lunatic.distributed.node.reconnects",
+        Unit::Count,
+        "Number of times a lost connection to a node was established again since startup"
+    );
+
+    describe_counter!(
+        "lunatic.distributed.node.connection_failures",
+        Unit::Count,
+        "Number of failed attempts to connect to a node since startup"
+    );
+
+    describe_histogram!(
+        "lunatic.distributed.node.rtt",
+        Unit::Seconds,
+        "Round trip time of the connection to a node"
+    );
+}
@@ -14,6 +14,17 @@
 ///
 /// Stream task manages quic stream and writes multiple message chunks.
 ///
+/// If the connection to a node fails, the node connection manager reconnects with exponential
+/// backoff and tracks the health of the connection in `NodeHealth`.
+///
+/// When the certificate of the node is rotated, the node connection manager finishes the streams
+/// of the current connection, waiting until the other node received all chunks written to them,
+/// and connects again with the new certificate.
+///
+/// The queue of each node connection manager is bounded. If it's full, the worker keeps the
+/// message in progress and moves on to other processes. When no chunk can be queued the worker
+/// sleeps until a process sends a new message or a node connection manager takes a chunk.
+///
 /// Topology illustration:
 ///
 ///  -----       -----
@@ -39,16 +50,29 @@
 /// |  S  | ... |  S  | - Stream tasks
 ///  -----       -----
 ///
+mod codec;
+mod health;
+
+pub use codec::{Codec, COMPRESSED_FLAG, MAX_CHUNK_SIZE};
+#[cfg(feature = "metrics")]
+pub use health::describe_metrics;
+pub use health::{NodeHealth, NodeStatus};
+
 use std::{
     collections::VecDeque,
     sync::{atomic, Arc},
+    time::Duration,
 };
 
 use anyhow::Result;
 use lunatic_control::NodeInfo;
 use tokio::sync::{
-    mpsc::{self, error::TryRecvError, Receiver, Sender},
-    RwLock,
+    mpsc::{
+        self,
+        error::{TryRecvError, TrySendError},
+        Receiver, Sender,
+    },
+    Notify, RwLock,
 };
 
 use crate::{
@@ -65,13 +89,51 @@ pub struct MessageChunk {
     data: bytes::Bytes,
 }
 
-// TODO: move to configuration
-const CHUNK_SIZE: usize = 1024;
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+pub const DEFAULT_STREAMS: usize = 10;
+pub const DEFAULT_NODE_QUEUE_DEPTH: usize = 1024;
+pub const DEFAULT_PROCESS_QUEUE_DEPTH: usize = 10_000;
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+// How often the round trip time of node connections is recorded
+const RTT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Limits of how messages to other nodes are chunked and queued.
+#[derive(Debug, Clone, Copy)]
+pub struct CongestionConfig {
+    /// Maximum size of a message chunk in bytes. Bigger chunks speed up large messages, smaller
+    /// ones interleave the messages of competing processes more evenly.
+    pub chunk_size: usize,
+    /// Number of QUIC streams opened to each node.
+    pub streams: usize,
+    /// Number of chunks waiting for a node connection before no more chunks are queued for it.
+    pub node_queue_depth: usize,
+    /// Number of messages a process can queue for other nodes before sending waits.
+    pub process_queue_depth: usize,
+    /// Codec used to compress chunks, if the receiving node supports it.
+    pub compression: Codec,
+    /// Messages smaller than this are never compressed.
+    pub compression_threshold: usize,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: DEFAULT_STREAMS,
+            node_queue_depth: DEFAULT_NODE_QUEUE_DEPTH,
+            process_queue_depth: DEFAULT_PROCESS_QUEUE_DEPTH,
+            compression: Codec::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
 
 pub async fn congestion_control_worker(state: distributed::Client) -> ! {
-    state.inner.has_messages.notified().await;
+    let chunk_size = state.inner.config.chunk_size;
     log::trace!("starting congestion control worker");
     loop {
+        // Set if a chunk was queued or a new message picked up during this pass
+        let mut progress = false;
         for env in state.inner.buf_rx.iter() {
             let mut disconected = vec![];
             for pid in env.iter() {
@@ -80,11 +142,11 @@ pub async fn congestion_control_worker(state: distributed::Client) -> ! {
                     // Chunk data using offset
                     let offset = msg_ctx.offset.load(atomic::Ordering::Relaxed);
                     let chunk_id = msg_ctx.chunk_id.load(atomic::Ordering::Relaxed);
-                    let (data, finished) = if msg_ctx.data.len() <= offset + CHUNK_SIZE {
+                    let (data, finished) = if msg_ctx.data.len() <= offset + chunk_size {
                         // Chunk will be finished after this write
                         (msg_ctx.data.slice(offset..), true)
                     } else {
-                        (msg_ctx.data.slice(offset..offset + CHUNK_SIZE), false)
+                        (msg_ctx.data.slice(offset..offset + chunk_size), false)
                     };
                     // Create chunk
                     let chunk = MessageChunk {
@@ -105,25 +167,28 @@ pub async fn congestion_control_worker(state: distributed::Client) -> ! {
                                 // Move to next chunk
                                 msg_ctx
                                     .offset
-                                    .store(offset + CHUNK_SIZE, atomic::Ordering::Relaxed);
+                                    .store(offset + chunk_size, atomic::Ordering::Relaxed);
                                 msg_ctx
                                     .chunk_id
                                     .store(chunk_id + 1, atomic::Ordering::Relaxed);
+                                progress = true;
                                 finished
                             }
-                            Err(e) => {
+                            // Retry once the node connection manager takes chunks from the queue
+                            Err(TrySendError::Full(_)) => false,
+                            Err(TrySendError::Closed(_)) => {
                                 log::warn!(
-                                    "Cannot send next chunk from pid={} to node={} dest_pid={}, reason: {e}",
+                                    "Cannot send next chunk from pid={} to node={} dest_pid={}, connection closed",
                                     msg_ctx.src.0,
                                     msg_ctx.node.0,
                                     msg_ctx.dest.0,
                                 );
-                                finished
+                                true
                             }
                         }
                     } else {
                         log::error!("Connection to node={} does not exist", msg_ctx.node.0);
-                        false
+                        true
                     }
                 } else {
                     true
@@ -142,6 +207,7 @@ pub async fn congestion_control_worker(state: distributed::Client) -> ! {
                                 .inner
                                 .in_progress
                                 .insert((new_msg_ctx.env, new_msg_ctx.src), new_msg_ctx);
+                            progress = true;
                         }
                         // No new messages
                         Err(TryRecvError::Empty) => (),
@@ -156,18 +222,23 @@ pub async fn congestion_control_worker(state: distributed::Client) -> ! {
             for pid in disconected {
                 env.remove(&pid);
             }
-            // wait to be woken up by next message
-            if state.inner.in_progress.is_empty() {
-                state.inner.has_messages.notified().await;
-            }
+        }
+        // Nothing can be sent right now, wait to be woken up by a new message or by a node
+        // connection manager with free space in its queue. Wake-ups that happened during the
+        // pass are stored as a permit, so this returns immediately if there were any.
+        if !progress {
+            state.inner.has_messages.notified().await;
         }
     }
 }
 
 type StreamBuffer = Arc<RwLock<VecDeque<MessageChunk>>>;
 
+#[derive(Clone, Copy)]
 enum StreamAction {
     Message,
+    // Stop after everything written to the stream was received by the other node
+    Finish,
     Die,
 }
 
@@ -176,12 +247,27 @@ pub struct NodeConnectionManager {
     pub node_info: NodeInfo,
     pub client: quic::Client,
     pub message_chunks: Receiver<MessageChunk>,
+    // Wakes up the congestion control worker once there is space in the queue again
+    pub worker: Arc<Notify>,
+    pub compression: Codec,
+    pub compression_threshold: usize,
+    pub health: Arc<NodeHealth>,
 }
 
 pub async fn node_connection_manager(mut manager: NodeConnectionManager) -> Result<()> {
     let node_info = manager.node_info;
+    // Only compress if the other node can decompress
+    let codec = if node_info
+        .codecs
+        .iter()
+        .any(|codec| codec == manager.compression.name())
+    {
+        manager.compression
+    } else {
+        Codec::None
+    };
     log::trace!(
-        "congestion::node_connection_manager::started node={} address={}",
+        "congestion::node_connection_manager::started node={} address={} codec={codec}",
         node_info.id,
         node_info.address
     );
@@ -193,16 +279,30 @@ pub async fn node_connection_manager(mut manager: NodeConnectionManager) -> Resu
     // Setup stream dead waker
     let (dead_stream_notifier, mut dead_stream_waker) = mpsc::channel::<()>(1);
 
+    let health = manager.health;
+    let mut rotations = manager.client.rotations();
+    let mut connected = false;
+    // Set if the last connection was closed to use a new certificate
+    let mut rotated = false;
     loop {
-        // Setup conn or fail
+        if connected && !rotated {
+            health.disconnected(node_info.id);
+        }
+        // Setup conn or retry after a backoff
         let conn = match manager
             .client
-            .try_connect(node_info.address, &node_info.name, 3)
+            ._connect(node_info.address, &node_info.name)
             .await
         {
             Ok(conn) => conn,
             Err(e) => {
-                log::error!("congestion::node_connection_manager Connection failed: {e}");
+                let backoff = health.failed(node_info.id);
+                log::error!(
+                    "congestion::node_connection_manager Connection to node={} failed, retrying in {backoff:?}: {e}",
+                    node_info.id
+                );
+                connected = false;
+                tokio::time::sleep(backoff).await;
                 continue;
             }
         };
@@ -220,7 +320,7 @@ pub async fn node_connection_manager(mut manager: NodeConnectionManager) -> Resu
                 Ok(stream) => stream,
                 Err(e) => {
                     log::error!("congestion::node_connection_manager Stream open failed: {e}");
-                    continue;
+                    break;
                 }
             };
             let (send, recv) = mpsc::channel::<StreamAction>(1000);
@@ -230,13 +330,37 @@ pub async fn node_connection_manager(mut manager: NodeConnectionManager) -> Resu
                 action: recv,
                 manager_notifier: dead_stream_notifier.clone(),
                 buffer: buffer.clone(),
+                codec,
+                compression_threshold: manager.compression_threshold,
             })));
         }
+        connected = stream_wakers.len() == manager.streams;
+        if connected && rotated {
+            log::debug!(
+                "Connection to node {} uses the new certificate",
+                node_info.id
+            );
+        } else if connected {
+            health.connected(node_info.id);
+        } else {
+            // Every stream is needed to keep the order of messages, connect again
+            for stream in stream_wakers {
+                stream.try_send(StreamAction::Die).ok();
+            }
+            for task in stream_tasks {
+                task.await.ok();
+            }
+            tokio::time::sleep(health.failed(node_info.id)).await;
+            continue;
+        }
+        rotated = false;
+        let mut rtt_interval = tokio::time::interval(RTT_INTERVAL);
         // Working chunk passing loop
         'forward_chunks: loop {
             tokio::select! {
                 Some(chunk) = manager.message_chunks.recv() => {
                     log::trace!("congestion::node_connection_manager::msg_id {}", chunk.message_id);
+                    manager.worker.notify_one();
                     let src = chunk.src.0;
                     let dest = chunk.dest.0;
                     // Determine stream index by source and destination process_id
@@ -250,11 +374,24 @@ pub async fn node_connection_manager(mut manager: NodeConnectionManager) -> Resu
                 _ = dead_stream_waker.recv() => {
                     break 'forward_chunks;
                 },
+                _ = rtt_interval.tick() => {
+                    health.record_rtt(node_info.id, conn.rtt());
+                },
+                Ok(()) = rotations.changed() => {
+                    rotated = true;
+                    break 'forward_chunks;
+                },
             };
         }
-        // Try to wake up all remaining streams
+        // Try to wake up all remaining streams, after a rotation they first write the chunks
+        // already in their buffers, so that no message is split between two connections
+        let action = if rotated {
+            StreamAction::Finish
+        } else {
+            StreamAction::Die
+        };
         for stream in stream_wakers {
-            stream.try_send(StreamAction::Die).ok();
+            stream.send(action).await.ok();
         }
         // Clean up tasks
         for task in stream_tasks {
@@ -268,11 +405,19 @@ struct StreamTask {
     action: Receiver<StreamAction>,
     manager_notifier: Sender<()>,
     buffer: StreamBuffer,
+    codec: Codec,
+    compression_threshold: usize,
 }
 
 async fn stream_task(mut state: StreamTask) {
     log::trace!("congestion::stream_task::start {}", state.quic_stream.id());
-    while let Some(StreamAction::Message) = state.action.recv().await {
+    while let Some(action) = state.action.recv().await {
+        // Chunks still in the buffer are written before finishing
+        let finish = match action {
+            StreamAction::Message => false,
+            StreamAction::Finish => true,
+            StreamAction::Die => break,
+        };
         let mut buffer = state.buffer.write().await;
         let mut chunks = Vec::new();
         while let Some(chunk) = buffer.pop_back() {
@@ -281,13 +426,27 @@ async fn stream_task(mut state: StreamTask) {
         let mut data: Vec<bytes::Bytes> = chunks
             .iter()
             .flat_map(|c| {
+                let compressed = if c.message_size as usize >= state.compression_threshold {
+                    state.codec.compress(&c.data)
+                } else {
+                    None
+                };
                 let mut buf = Vec::new();
                 buf.extend(c.message_id.to_le_bytes().as_ref());
                 buf.extend(c.message_size.to_le_bytes().as_ref());
                 buf.extend(c.chunk_id.to_le_bytes().as_ref());
-                buf.extend((c.data.len() as u32).to_le_bytes().as_ref());
-                // buf.extend(&c.data);
-                vec![bytes::Bytes::from(buf), c.data.clone()]
+                match compressed {
+                    Some(compressed) => {
+                        let chunk_size = compressed.len() as u32 | COMPRESSED_FLAG;
+                        buf.extend(chunk_size.to_le_bytes().as_ref());
+                        buf.push(state.codec as u8);
+                        vec![bytes::Bytes::from(buf), bytes::Bytes::from(compressed)]
+                    }
+                    None => {
+                        buf.extend((c.data.len() as u32).to_le_bytes().as_ref());
+                        vec![bytes::Bytes::from(buf), c.data.clone()]
+                    }
+                }
             })
             .collect();
         // Try to send data
@@ -303,5 +462,10 @@ async fn stream_task(mut state: StreamTask) {
                 break;
             }
         };
+        if finish {
+            drop(buffer);
+            state.quic_stream.finish().await.ok();
+            break;
+        }
     }
 }
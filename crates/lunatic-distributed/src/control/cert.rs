@@ -2,6 +2,9 @@ use std::path::Path;
 
 use anyhow::Result;
 use rcgen::*;
+use x509_parser::oid_registry::asn1_rs::ToDer;
+
+use crate::{CertAttrs, SUBJECT_DIR_ATTRS};
 
 pub static TEST_ROOT_CERT: &str = r#"""
 -----BEGIN CERTIFICATE-----
@@ -60,3 +63,32 @@ pub fn default_server_certificates(root_cert: &Certificate) -> Result<(String, S
     let key_pem = ctrl_cert.serialize_private_key_pem();
     Ok((cert_pem, key_pem))
 }
+
+/// Creates the certificate of a node that doesn't register with a control server, signed by
+/// `root_cert`. The node is allowed to use all environments.
+///
+/// Returns the certificate and private key as PEM.
+pub fn local_node_certificate(
+    node_name: &str,
+    root_cert: &Certificate,
+) -> Result<(String, String)> {
+    let mut params = CertificateParams::new(vec![node_name.to_string()]);
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, "Lunatic Inc.");
+    params.distinguished_name.push(DnType::CommonName, "Node");
+    let attrs = serde_json::to_string(&CertAttrs {
+        allowed_envs: vec![],
+        is_privileged: true,
+    })?;
+    params
+        .custom_extensions
+        .push(CustomExtension::from_oid_content(
+            &SUBJECT_DIR_ATTRS,
+            attrs.to_der_vec()?,
+        ));
+    let node_cert = Certificate::from_params(params)?;
+    let cert_pem = node_cert.serialize_pem_with_signer(root_cert)?;
+    let key_pem = node_cert.serialize_private_key_pem();
+    Ok((cert_pem, key_pem))
+}
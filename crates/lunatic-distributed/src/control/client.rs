@@ -2,15 +2,31 @@ use anyhow::{anyhow, Context, Result};
 use dashmap::DashMap;
 use lunatic_control::api::*;
 use lunatic_control::NodeInfo;
-use lunatic_process::runtimes::RawWasm;
+use lunatic_process::{
+    message::{DataMessage, Message},
+    runtimes::RawWasm,
+    Process, Signal,
+};
 use reqwest::{Client as HttpClient, Url};
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap,
+    },
+    hash::{BuildHasher, Hash, Hasher},
     net::SocketAddr,
+    path::PathBuf,
     sync::{atomic, atomic::AtomicU64, Arc, RwLock},
     time::Duration,
 };
+use tokio::sync::broadcast;
+
+use super::{mdns::Mdns, peers::StaticPeers};
+use crate::congestion::Codec;
+
+// Nodes with their attributes
+type LocalNodes = Vec<(NodeInfo, HashMap<String, String>)>;
 
 #[derive(Clone)]
 pub struct Client {
@@ -18,7 +34,9 @@ pub struct Client {
 }
 
 pub struct InnerClient {
-    reg: Registration,
+    // `None` if the nodes are discovered without a control server
+    reg: Option<Registration>,
+    discovery: Discovery,
     node_id: u64,
     http_client: HttpClient,
     next_message_id: AtomicU64,
@@ -26,6 +44,56 @@ pub struct InnerClient {
     node_queries: DashMap<u64, Vec<u64>>,
     nodes: DashMap<u64, NodeInfo>,
     node_ids: RwLock<Vec<u64>>,
+    // Notified with the ID of every node that disappears from the nodes list
+    nodes_left: broadcast::Sender<u64>,
+    // Last known state of the global registry, used while the control server is unreachable
+    registry: DashMap<String, RegistryEntry>,
+    // Registry changes that couldn't be sent to the control server yet
+    registry_pending: DashMap<String, RegistryChange>,
+    // Modules added on this node while there is no control server to upload them to
+    modules: DashMap<u64, Vec<u8>>,
+    // Round-robin position of every node query used for placing processes
+    placements: DashMap<String, u64>,
+    // Processes notified when nodes join or leave, keyed by environment and process ID, with the
+    // tag of the messages
+    node_subscribers: DashMap<(u64, u64), (Arc<dyn Process>, i64)>,
+}
+
+/// First byte of the message sent to subscribers when a node joins the cluster.
+pub const NODE_JOINED: u8 = 0;
+/// First byte of the message sent to subscribers when a node leaves the cluster.
+pub const NODE_LEFT: u8 = 1;
+
+// Where the list of nodes comes from
+enum Discovery {
+    Control,
+    Static(PathBuf),
+    Mdns(Mdns),
+}
+
+/// How a node is picked for a process spawned without a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// Each matching node in turn.
+    RoundRobin,
+    /// The matching node with the fewest processes as last reported, taking turns between nodes
+    /// with the same number. Nodes that didn't report yet are treated as idle.
+    LeastProcesses,
+    /// A random matching node.
+    Random,
+}
+
+// `if_version` is the version of the registration this node saw before changing it
+#[derive(Clone, Copy)]
+enum RegistryChange {
+    Put {
+        node_id: u64,
+        process_id: u64,
+        if_version: u64,
+    },
+    Remove {
+        if_version: u64,
+    },
 }
 
 impl Client {
@@ -41,13 +109,42 @@ impl Client {
             NodeStart {
                 node_address,
                 attributes,
+                codecs: Codec::SUPPORTED
+                    .iter()
+                    .map(|codec| codec.name().to_owned())
+                    .collect(),
             },
         )
         .await?;
+        Self::with_discovery(node_id, http_client, Some(reg), Discovery::Control).await
+    }
+
+    /// Creates a client that reads the nodes from a static peers file instead of asking a
+    /// control server. This node is the entry listening on `node_address`.
+    pub async fn new_static(peers_file: PathBuf, node_address: SocketAddr) -> Result<Self> {
+        let node_id = StaticPeers::read(&peers_file)?.find(node_address)?.id;
+        let discovery = Discovery::Static(peers_file);
+        Self::with_discovery(node_id, HttpClient::new(), None, discovery).await
+    }
+
+    /// Creates a client that discovers the nodes on the local network with mDNS instead of
+    /// asking a control server.
+    pub async fn new_mdns(node: NodeInfo, attributes: HashMap<String, String>) -> Result<Self> {
+        let node_id = node.id;
+        let discovery = Discovery::Mdns(Mdns::start(node, attributes).await?);
+        Self::with_discovery(node_id, HttpClient::new(), None, discovery).await
+    }
 
+    async fn with_discovery(
+        node_id: u64,
+        http_client: HttpClient,
+        reg: Option<Registration>,
+        discovery: Discovery,
+    ) -> Result<Self> {
         let client = Client {
             inner: Arc::new(InnerClient {
                 reg,
+                discovery,
                 node_id,
                 http_client,
                 next_message_id: AtomicU64::new(1),
@@ -55,6 +152,12 @@ impl Client {
                 next_query_id: AtomicU64::new(1),
                 nodes: Default::default(),
                 node_ids: Default::default(),
+                nodes_left: broadcast::channel(64).0,
+                registry: DashMap::new(),
+                registry_pending: DashMap::new(),
+                modules: DashMap::new(),
+                placements: DashMap::new(),
+                node_subscribers: DashMap::new(),
             }),
         };
 
@@ -74,10 +177,17 @@ impl Client {
         Self::send_registration(http_client, control_url, reg).await
     }
 
-    pub fn reg(&self) -> Registration {
+    pub fn reg(&self) -> Option<Registration> {
         self.inner.reg.clone()
     }
 
+    fn registration(&self) -> Result<&Registration> {
+        self.inner
+            .reg
+            .as_ref()
+            .ok_or_else(|| anyhow!("The node doesn't use a control server"))
+    }
+
     pub fn node_id(&self) -> u64 {
         self.inner.node_id
     }
@@ -141,6 +251,7 @@ impl Client {
     }
 
     pub async fn get<T: DeserializeOwned>(&self, url: &str, query: Option<&str>) -> Result<T> {
+        let reg = self.registration()?;
         let mut url: Url = url.parse()?;
         url.set_query(query);
 
@@ -148,10 +259,10 @@ impl Client {
             .inner
             .http_client
             .get(url.clone())
-            .bearer_auth(&self.inner.reg.authentication_token)
+            .bearer_auth(&reg.authentication_token)
             .header(
                 "x-lunatic-node-name",
-                &self.inner.reg.node_name.hyphenated().to_string(),
+                &reg.node_name.hyphenated().to_string(),
             )
             .send()
             .await
@@ -166,6 +277,7 @@ impl Client {
     }
 
     pub async fn post<T: Serialize, R: DeserializeOwned>(&self, url: &str, data: T) -> Result<R> {
+        let reg = self.registration()?;
         let url: Url = url.parse()?;
 
         let resp: R = self
@@ -173,10 +285,10 @@ impl Client {
             .http_client
             .post(url.clone())
             .json(&data)
-            .bearer_auth(&self.inner.reg.authentication_token)
+            .bearer_auth(&reg.authentication_token)
             .header(
                 "x-lunatic-node-name",
-                &self.inner.reg.node_name.hyphenated().to_string(),
+                &reg.node_name.hyphenated().to_string(),
             )
             .send()
             .await
@@ -191,6 +303,7 @@ impl Client {
     }
 
     pub async fn upload<R: DeserializeOwned>(&self, url: &str, body: Vec<u8>) -> Result<R> {
+        let reg = self.registration()?;
         let url: Url = url.parse()?;
 
         let resp: R = self
@@ -198,10 +311,10 @@ impl Client {
             .http_client
             .post(url.clone())
             .body(body)
-            .bearer_auth(&self.inner.reg.authentication_token)
+            .bearer_auth(&reg.authentication_token)
             .header(
                 "x-lunatic-node-name",
-                &self.inner.reg.node_name.hyphenated().to_string(),
+                &reg.node_name.hyphenated().to_string(),
             )
             .send()
             .await
@@ -216,24 +329,162 @@ impl Client {
         Ok(resp)
     }
 
+    // Nodes known without asking the control server, with their attributes
+    fn local_nodes(&self) -> Result<Option<LocalNodes>> {
+        match &self.inner.discovery {
+            Discovery::Control => Ok(None),
+            Discovery::Static(path) => {
+                let peers = StaticPeers::read(path)?;
+                let nodes = peers
+                    .nodes
+                    .into_iter()
+                    .map(|peer| (peer.node_info(), peer.attributes))
+                    .collect();
+                Ok(Some(nodes))
+            }
+            Discovery::Mdns(mdns) => Ok(Some(mdns.nodes())),
+        }
+    }
+
     pub async fn refresh_nodes(&self) -> Result<()> {
-        let resp: NodesList = self.get(&self.inner.reg.urls.nodes, None).await?;
+        let nodes: Vec<NodeInfo> = match self.local_nodes()? {
+            Some(nodes) => nodes.into_iter().map(|(node, _)| node).collect(),
+            None => {
+                let resp: NodesList = self.get(&self.registration()?.urls.nodes, None).await?;
+                resp.nodes
+            }
+        };
         let mut node_ids = vec![];
-        for node in resp.nodes {
+        for node in nodes {
             let id = node.id;
             node_ids.push(id);
-            if !self.inner.nodes.contains_key(&id) {
-                self.inner.nodes.insert(id, node);
+            // Replaces the previous info, the tags and load of a node change over time
+            self.inner.nodes.insert(id, node);
+        }
+        let (joined_nodes, left_nodes) = match self.inner.node_ids.write() {
+            Ok(mut self_node_ids) => {
+                let joined_nodes: Vec<u64> = node_ids
+                    .iter()
+                    .filter(|id| !self_node_ids.contains(id))
+                    .copied()
+                    .collect();
+                let left_nodes: Vec<u64> = self_node_ids
+                    .iter()
+                    .filter(|id| !node_ids.contains(id))
+                    .copied()
+                    .collect();
+                *self_node_ids = node_ids;
+                (joined_nodes, left_nodes)
             }
+            Err(_) => (vec![], vec![]),
+        };
+        for node_id in joined_nodes {
+            self.notify_node_subscribers(NODE_JOINED, node_id);
         }
-        if let Ok(mut self_node_ids) = self.inner.node_ids.write() {
-            *self_node_ids = node_ids;
+        for node_id in left_nodes {
+            log::info!("Node {node_id} left");
+            self.inner
+                .registry
+                .retain(|_, entry| entry.node_id != node_id);
+            self.inner.nodes_left.send(node_id).ok();
+            self.notify_node_subscribers(NODE_LEFT, node_id);
         }
         Ok(())
     }
 
+    /// Sends a message with `tag` to the process every time a node joins or leaves the cluster.
+    ///
+    /// The message contains `NODE_JOINED` or `NODE_LEFT` followed by the node ID as little endian
+    /// u64. Subscribing again replaces the tag.
+    pub fn subscribe_node_changes(&self, environment_id: u64, process: Arc<dyn Process>, tag: i64) {
+        self.inner
+            .node_subscribers
+            .insert((environment_id, process.id()), (process, tag));
+    }
+
+    pub fn unsubscribe_node_changes(&self, environment_id: u64, process_id: u64) {
+        self.inner
+            .node_subscribers
+            .remove(&(environment_id, process_id));
+    }
+
+    // Subscribers that finished are removed
+    fn notify_node_subscribers(&self, change: u8, node_id: u64) {
+        self.inner.node_subscribers.retain(|_, (process, tag)| {
+            let mut buffer = vec![change];
+            buffer.extend(node_id.to_le_bytes());
+            let message = DataMessage::new_from_vec(Some(*tag), buffer);
+            process.try_send(Signal::Message(Message::Data(message)))
+        });
+    }
+
+    /// Returns a receiver that gets the ID of every node that leaves the cluster.
+    pub fn subscribe_nodes_left(&self) -> broadcast::Receiver<u64> {
+        self.inner.nodes_left.subscribe()
+    }
+
     pub async fn notify_node_stopped(&self) -> Result<()> {
-        self.post(&self.inner.reg.urls.node_stopped, ()).await?;
+        match &self.inner.discovery {
+            Discovery::Control => {
+                self.post::<_, ()>(&self.registration()?.urls.node_stopped, ())
+                    .await?;
+            }
+            Discovery::Static(_) => {}
+            Discovery::Mdns(mdns) => mdns.goodbye().await?,
+        }
+        Ok(())
+    }
+
+    /// Sets the attribute `key` of this node, so that `lookup_nodes` on all nodes sees the new
+    /// value.
+    pub async fn set_node_tag(&self, key: &str, value: &str) -> Result<()> {
+        match &self.inner.discovery {
+            Discovery::Control => {
+                let url = self
+                    .registration()?
+                    .urls
+                    .node_tags
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("The control server doesn't support changing tags"))?;
+                let tag = NodeTag {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                };
+                self.post::<_, ()>(url, tag).await
+            }
+            Discovery::Static(path) => Err(anyhow!(
+                "Tags of nodes are defined in the static peers file '{}'",
+                path.display()
+            )),
+            Discovery::Mdns(mdns) => mdns.set_attribute(key.to_owned(), value.to_owned()).await,
+        }
+    }
+
+    /// Asks the control server to sign a new certificate for this node, before the current one
+    /// expires. Returns `None` if the control server issues certificates that don't expire.
+    pub async fn renew_certificate(&self, csr_pem: String) -> Result<Option<Vec<String>>> {
+        match self.registration()?.urls.renew_certificate.as_deref() {
+            Some(url) => {
+                let renewed: RenewedCertificate =
+                    self.post(url, RenewCertificate { csr_pem }).await?;
+                Ok(Some(renewed.cert_pem_chain))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reports the load of this node, so that other nodes can take it into account when placing
+    /// processes.
+    pub async fn report_stats(&self, stats: NodeStats) -> Result<()> {
+        match &self.inner.discovery {
+            Discovery::Control => {
+                if let Some(url) = self.registration()?.urls.node_stats.as_deref() {
+                    self.post::<_, ()>(url, stats).await?;
+                }
+            }
+            Discovery::Static(_) => {}
+            Discovery::Mdns(mdns) => mdns.set_processes(stats.processes),
+        }
         Ok(())
     }
 
@@ -245,17 +496,69 @@ impl Client {
         self.inner.node_ids.read().unwrap().clone()
     }
 
+    // Returns the nodes with attributes matching the query
+    async fn find_nodes(&self, query: &str) -> Result<Vec<NodeInfo>> {
+        match self.local_nodes()? {
+            Some(nodes) => {
+                // The query has the same `key=value&...` format the control server filters with
+                let url = Url::parse(&format!("lunatic://nodes?{query}"))?;
+                let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                Ok(nodes
+                    .into_iter()
+                    .filter(|(_, attributes)| {
+                        query.iter().all(|(k, v)| attributes.get(k) == Some(v))
+                    })
+                    .map(|(node, _)| node)
+                    .collect())
+            }
+            None => {
+                let resp: NodesList = self
+                    .get(&self.registration()?.urls.get_nodes, Some(query))
+                    .await?;
+                Ok(resp.nodes)
+            }
+        }
+    }
+
     pub async fn lookup_nodes(&self, query: &str) -> Result<(u64, usize)> {
-        let resp: NodesList = self
-            .get(&self.inner.reg.urls.get_nodes, Some(query))
-            .await?;
-        let nodes: Vec<u64> = resp.nodes.into_iter().map(move |v| v.id).collect();
+        let nodes: Vec<u64> = self
+            .find_nodes(query)
+            .await?
+            .into_iter()
+            .map(|node| node.id)
+            .collect();
         let nodes_count = nodes.len();
         let query_id = self.next_query_id();
         self.inner.node_queries.insert(query_id, nodes);
         Ok((query_id, nodes_count))
     }
 
+    /// Picks a node matching the query to spawn a process on, `None` if no node matches.
+    ///
+    /// The node statistics are fetched with every call, so they are at most as old as the last
+    /// report of each node.
+    pub async fn place(&self, query: &str, placement: Placement) -> Result<Option<u64>> {
+        let mut nodes = self.find_nodes(query).await?;
+        if placement == Placement::LeastProcesses {
+            let least = nodes.iter().map(|node| node.processes.unwrap_or(0)).min();
+            nodes.retain(|node| Some(node.processes.unwrap_or(0)) == least);
+        }
+        if nodes.is_empty() {
+            return Ok(None);
+        }
+        // Sorted, so that taking turns doesn't depend on the order the nodes are listed in
+        nodes.sort_by_key(|node| node.id);
+        let index = match placement {
+            Placement::RoundRobin | Placement::LeastProcesses => {
+                let mut turn = self.inner.placements.entry(query.to_owned()).or_insert(0);
+                *turn = turn.wrapping_add(1);
+                *turn
+            }
+            Placement::Random => RandomState::new().build_hasher().finish(),
+        };
+        Ok(Some(nodes[(index % nodes.len() as u64) as usize].id))
+    }
+
     pub fn query_result(&self, query_id: &u64) -> Option<(u64, Vec<u64>)> {
         self.inner.node_queries.remove(query_id)
     }
@@ -266,9 +569,11 @@ impl Client {
 
     pub async fn get_module(&self, module_id: u64, environment_id: u64) -> Result<Vec<u8>> {
         log::info!("Get module {module_id}");
+        if let Some(module) = self.local_module(module_id) {
+            return Ok(module);
+        }
         let url = self
-            .inner
-            .reg
+            .registration()?
             .urls
             .get_module
             .replace("{id}", &module_id.to_string());
@@ -277,16 +582,225 @@ impl Client {
         Ok(resp.bytes)
     }
 
+    /// Returns the content hash of a module, `None` if the control server doesn't provide it.
+    pub async fn module_hash(&self, module_id: u64) -> Result<Option<String>> {
+        let url = match self.registration()?.urls.get_module_hash {
+            Some(ref url) => url.replace("{id}", &module_id.to_string()),
+            None => return Ok(None),
+        };
+        let resp: ModuleHash = self.get(&url, None).await?;
+        Ok(Some(resp.hash))
+    }
+
     pub async fn add_module(&self, module: Vec<u8>) -> Result<RawWasm> {
-        let url = &self.inner.reg.urls.add_module;
-        let resp: ModuleId = self.upload(url, module.clone()).await?;
-        Ok(RawWasm::new(Some(resp.module_id), module))
+        let module_id = match self.inner.reg {
+            Some(ref reg) => {
+                let resp: ModuleId = self.upload(&reg.urls.add_module, module.clone()).await?;
+                resp.module_id
+            }
+            None => {
+                // Other nodes fetch the module from this node, so a content hash is unique enough
+                let mut hasher = DefaultHasher::new();
+                module.hash(&mut hasher);
+                let module_id = hasher.finish();
+                self.store_module(module_id, module.clone());
+                module_id
+            }
+        };
+        Ok(RawWasm::new(Some(module_id), module))
+    }
+
+    /// Returns a module added on this node or fetched from another node without a control server.
+    pub fn local_module(&self, module_id: u64) -> Option<Vec<u8>> {
+        self.inner
+            .modules
+            .get(&module_id)
+            .map(|module| module.clone())
+    }
+
+    /// Keeps a module fetched from another node, so that without a control server this node can
+    /// serve it to other nodes too.
+    pub fn store_module(&self, module_id: u64, module: Vec<u8>) {
+        if self.inner.reg.is_none() {
+            self.inner.modules.insert(module_id, module);
+        }
+    }
+
+    fn registry_url(&self) -> Result<&str> {
+        self.registration()?
+            .urls
+            .registry
+            .as_deref()
+            .ok_or_else(|| anyhow!("The control server doesn't support global names"))
+    }
+
+    // Version of the registration under `name` as last seen by this node, `0` if the name is free.
+    fn seen_version(&self, name: &str) -> u64 {
+        self.inner
+            .registry
+            .get(name)
+            .map(|entry| entry.version)
+            .unwrap_or(0)
+    }
+
+    /// Registers a process under a global name.
+    ///
+    /// If the control server can't be reached, the registration is only visible on this node and
+    /// sent to the control server once it's reachable again. If another node registered the same
+    /// name in the meantime, its registration wins.
+    pub async fn registry_put(&self, name: &str, node_id: u64, process_id: u64) -> Result<()> {
+        let url = self.registry_url()?;
+        let put = RegistryPut {
+            name: name.to_owned(),
+            node_id,
+            process_id,
+            if_version: None,
+        };
+        match self.post::<_, RegistryEntry>(url, put).await {
+            Ok(entry) => {
+                self.inner.registry_pending.remove(name);
+                self.inner.registry.insert(name.to_owned(), entry);
+            }
+            Err(error) => {
+                log::warn!("Global registration of '{name}' delayed: {error}");
+                let version = self.seen_version(name);
+                // The cached entry keeps the version seen before, so that changes replayed later
+                // are checked against it
+                self.inner.registry.insert(
+                    name.to_owned(),
+                    RegistryEntry {
+                        name: name.to_owned(),
+                        node_id,
+                        process_id,
+                        version,
+                    },
+                );
+                self.inner.registry_pending.insert(
+                    name.to_owned(),
+                    RegistryChange::Put {
+                        node_id,
+                        process_id,
+                        if_version: version,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the `(node_id, process_id)` registered under a global name.
+    ///
+    /// Falls back to the last known state of the registry if the control server is unreachable.
+    pub async fn registry_get(&self, name: &str) -> Result<Option<(u64, u64)>> {
+        let mut url: Url = self.registry_url()?.parse()?;
+        url.set_path(&format!("{}/lookup", url.path().trim_end_matches('/')));
+        url.query_pairs_mut().append_pair("name", name);
+        if !self.inner.registry_pending.contains_key(name) {
+            match self.get::<RegistryLookup>(url.as_str(), url.query()).await {
+                Ok(RegistryLookup { entry: Some(entry) }) => {
+                    self.inner.registry.insert(name.to_owned(), entry);
+                }
+                Ok(RegistryLookup { entry: None }) => {
+                    self.inner.registry.remove(name);
+                }
+                Err(error) => log::warn!("Global lookup of '{name}' uses cached entry: {error}"),
+            }
+        }
+        Ok(self
+            .inner
+            .registry
+            .get(name)
+            .map(|entry| (entry.node_id, entry.process_id)))
+    }
+
+    /// Removes the process registered under a global name.
+    pub async fn registry_remove(&self, name: &str) -> Result<()> {
+        let url = format!("{}/remove", self.registry_url()?.trim_end_matches('/'));
+        let remove = RegistryRemove {
+            name: name.to_owned(),
+            if_version: None,
+        };
+        if let Err(error) = self.post::<_, ()>(&url, remove).await {
+            log::warn!("Global removal of '{name}' delayed: {error}");
+            let if_version = self.seen_version(name);
+            self.inner
+                .registry_pending
+                .insert(name.to_owned(), RegistryChange::Remove { if_version });
+        } else {
+            self.inner.registry_pending.remove(name);
+        }
+        self.inner.registry.remove(name);
+        Ok(())
+    }
+
+    /// Sends the registry changes made while the control server was unreachable and refreshes
+    /// the local copy of the registry.
+    ///
+    /// Changes are only applied if the name wasn't changed by another node in the meantime, so
+    /// after a net-split the registrations made from the side that could reach the control
+    /// server win.
+    pub async fn sync_registry(&self) -> Result<()> {
+        let url = match self
+            .inner
+            .reg
+            .as_ref()
+            .and_then(|reg| reg.urls.registry.as_deref())
+        {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        let names: Vec<String> = self
+            .inner
+            .registry_pending
+            .iter()
+            .map(|change| change.key().clone())
+            .collect();
+        for name in names {
+            let change = match self.inner.registry_pending.get(&name) {
+                Some(change) => *change,
+                None => continue,
+            };
+            let sent = match change {
+                RegistryChange::Put {
+                    node_id,
+                    process_id,
+                    if_version,
+                } => {
+                    let put = RegistryPut {
+                        name: name.clone(),
+                        node_id,
+                        process_id,
+                        if_version: Some(if_version),
+                    };
+                    self.post::<_, RegistryEntry>(url, put).await.map(|_| ())
+                }
+                RegistryChange::Remove { if_version } => {
+                    let remove = RegistryRemove {
+                        name: name.clone(),
+                        if_version: Some(if_version),
+                    };
+                    let url = format!("{}/remove", url.trim_end_matches('/'));
+                    self.post::<_, ()>(&url, remove).await
+                }
+            };
+            sent?;
+            self.inner.registry_pending.remove(&name);
+        }
+        let list: RegistryList = self.get(url, None).await?;
+        self.inner.registry.clear();
+        for entry in list.entries {
+            self.inner.registry.insert(entry.name.clone(), entry);
+        }
+        Ok(())
     }
 }
 
 async fn refresh_nodes_task(client: Client) -> Result<()> {
     loop {
         client.refresh_nodes().await.ok();
+        if let Err(error) = client.sync_registry().await {
+            log::debug!("Failed to sync the global registry: {error}");
+        }
         tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use lunatic_control::NodeInfo;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use trust_dns_proto::{
+    op::{Message, MessageType, Query},
+    rr::{
+        rdata::{PTR, TXT},
+        Name, RData, Record, RecordType,
+    },
+};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_lunatic._udp.local.";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+// Nodes that didn't announce themselves for 3 intervals are considered gone
+const TTL: u32 = 15;
+
+/// Discovers the nodes on the local network with multicast DNS.
+///
+/// Every node announces itself as an instance of the `_lunatic._udp.local` service. The TXT
+/// record of the instance carries the node id, address, codecs, number of processes and
+/// attributes.
+#[derive(Clone)]
+pub struct Mdns {
+    inner: Arc<MdnsInner>,
+}
+
+struct MdnsInner {
+    socket: UdpSocket,
+    node: NodeInfo,
+    attributes: RwLock<HashMap<String, String>>,
+    processes: RwLock<Option<u64>>,
+    // Other nodes with their attributes and when their announcement expires
+    peers: DashMap<u64, (NodeInfo, HashMap<String, String>, Instant)>,
+}
+
+impl Mdns {
+    /// Joins the mDNS multicast group and starts announcing `node`.
+    pub async fn start(node: NodeInfo, attributes: HashMap<String, String>) -> Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Other mDNS responders on the host use the same port
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+        socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+
+        let mdns = Mdns {
+            inner: Arc::new(MdnsInner {
+                socket,
+                node,
+                attributes: RwLock::new(attributes),
+                processes: RwLock::new(None),
+                peers: DashMap::new(),
+            }),
+        };
+        tokio::spawn(listen_task(mdns.clone()));
+        tokio::spawn(announce_task(mdns.clone()));
+        // Ask the nodes that are already running to announce themselves
+        let mut query = Message::new();
+        query
+            .set_message_type(MessageType::Query)
+            .add_query(Query::query(Name::from_ascii(SERVICE)?, RecordType::PTR));
+        mdns.send(&query).await?;
+        Ok(mdns)
+    }
+
+    /// Returns this node and all nodes that announced themselves recently, with their attributes.
+    pub fn nodes(&self) -> Vec<(NodeInfo, HashMap<String, String>)> {
+        let now = Instant::now();
+        self.inner.peers.retain(|_, (_, _, expires)| *expires > now);
+        let mut node = self.inner.node.clone();
+        node.processes = *self.inner.processes.read().unwrap();
+        let attributes = self.inner.attributes.read().unwrap().clone();
+        node.attributes = attributes.clone();
+        let mut nodes = vec![(node, attributes)];
+        nodes.extend(
+            self.inner
+                .peers
+                .iter()
+                .filter(|peer| *peer.key() != self.inner.node.id)
+                .map(|peer| (peer.0.clone(), peer.1.clone())),
+        );
+        nodes
+    }
+
+    /// Sets an attribute of this node and announces the change right away.
+    pub async fn set_attribute(&self, key: String, value: String) -> Result<()> {
+        self.inner.attributes.write().unwrap().insert(key, value);
+        self.send(&self.announcement(TTL)?).await
+    }
+
+    /// Sets the number of processes running on this node, sent with the next announcement.
+    pub fn set_processes(&self, processes: u64) {
+        *self.inner.processes.write().unwrap() = Some(processes);
+    }
+
+    /// Tells the other nodes that this node is leaving.
+    pub async fn goodbye(&self) -> Result<()> {
+        self.send(&self.announcement(0)?).await
+    }
+
+    fn announcement(&self, ttl: u32) -> Result<Message> {
+        let node = &self.inner.node;
+        let service = Name::from_ascii(SERVICE)?;
+        let instance = Name::from_ascii(&node.name)?.append_domain(&service)?;
+        let mut txt = vec![
+            format!("id={}", node.id),
+            format!("address={}", node.address),
+            format!("codecs={}", node.codecs.join(",")),
+        ];
+        if let Some(processes) = *self.inner.processes.read().unwrap() {
+            txt.push(format!("processes={processes}"));
+        }
+        txt.extend(
+            self.inner
+                .attributes
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| format!("attr.{key}={value}")),
+        );
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true)
+            .add_answer(Record::from_rdata(
+                service,
+                ttl,
+                RData::PTR(PTR(instance.clone())),
+            ))
+            .add_answer(Record::from_rdata(instance, ttl, RData::TXT(TXT::new(txt))));
+        Ok(message)
+    }
+
+    async fn send(&self, message: &Message) -> Result<()> {
+        self.inner
+            .socket
+            .send_to(&message.to_vec()?, (MDNS_ADDR, MDNS_PORT))
+            .await?;
+        Ok(())
+    }
+
+    fn handle_response(&self, message: &Message, source: SocketAddr) {
+        for record in message.answers() {
+            let txt = match record.data() {
+                Some(RData::TXT(txt)) if record.name().to_ascii().ends_with(SERVICE) => txt,
+                _ => continue,
+            };
+            match parse_txt(txt, record.name(), source) {
+                Ok((node, attributes)) => {
+                    if record.ttl() == 0 {
+                        log::info!("Node {} left", node.id);
+                        self.inner.peers.remove(&node.id);
+                    } else {
+                        let expires = Instant::now() + Duration::from_secs(record.ttl() as u64);
+                        self.inner
+                            .peers
+                            .insert(node.id, (node, attributes, expires));
+                    }
+                }
+                Err(error) => log::debug!("Invalid mDNS announcement from {source}: {error}"),
+            }
+        }
+    }
+}
+
+// Reads the node from the TXT record of its service instance
+fn parse_txt(
+    txt: &TXT,
+    instance: &Name,
+    source: SocketAddr,
+) -> Result<(NodeInfo, HashMap<String, String>)> {
+    let name = instance
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("Missing instance name"))?;
+    let mut id = None;
+    let mut address = None;
+    let mut codecs = Vec::new();
+    let mut processes = None;
+    let mut attributes = HashMap::new();
+    for entry in txt.iter() {
+        let entry = std::str::from_utf8(entry)?;
+        match entry.split_once('=') {
+            Some(("id", value)) => id = Some(value.parse()?),
+            Some(("address", value)) => {
+                let mut value: SocketAddr = value.parse()?;
+                // Nodes listening on all interfaces are reachable where the announcement came from
+                if value.ip().is_unspecified() {
+                    value.set_ip(source.ip());
+                }
+                address = Some(value);
+            }
+            Some(("codecs", value)) => {
+                codecs = value
+                    .split(',')
+                    .filter(|codec| !codec.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            Some(("processes", value)) => processes = Some(value.parse()?),
+            Some((key, value)) => {
+                if let Some(key) = key.strip_prefix("attr.") {
+                    attributes.insert(key.to_owned(), value.to_owned());
+                }
+            }
+            None => {}
+        }
+    }
+    let node = NodeInfo {
+        id: id.ok_or_else(|| anyhow!("Missing node id"))?,
+        address: address.ok_or_else(|| anyhow!("Missing node address"))?,
+        name: std::str::from_utf8(name)?.to_owned(),
+        codecs,
+        processes,
+        attributes: attributes.clone(),
+    };
+    Ok((node, attributes))
+}
+
+async fn listen_task(mdns: Mdns) {
+    let mut buffer = vec![0u8; 9000];
+    loop {
+        let (len, source) = match mdns.inner.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(error) => {
+                log::error!("mDNS socket failed: {error}");
+                return;
+            }
+        };
+        let message = match Message::from_vec(&buffer[..len]) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        match message.message_type() {
+            MessageType::Response => mdns.handle_response(&message, source),
+            MessageType::Query => {
+                let asks_for_nodes = message
+                    .queries()
+                    .iter()
+                    .any(|query| query.name().to_ascii() == SERVICE);
+                if asks_for_nodes {
+                    if let Ok(announcement) = mdns.announcement(TTL) {
+                        mdns.send(&announcement).await.ok();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn announce_task(mdns: Mdns) {
+    loop {
+        match mdns.announcement(TTL) {
+            Ok(announcement) => {
+                if let Err(error) = mdns.send(&announcement).await {
+                    log::warn!("Failed to send mDNS announcement: {error}");
+                }
+            }
+            Err(error) => {
+                log::error!("Invalid mDNS announcement: {error}");
+                return;
+            }
+        }
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+    }
+}
@@ -1,5 +1,7 @@
 pub mod client;
 //pub mod server;
 pub mod cert;
+pub mod mdns;
+pub mod peers;
 
-pub use client::Client;
+pub use client::{Client, Placement};
@@ -0,0 +1,82 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use lunatic_control::NodeInfo;
+use serde::Deserialize;
+
+/// A static list of all nodes in the cluster, read from a TOML file:
+///
+/// ```toml
+/// [[node]]
+/// id = 1
+/// address = "10.0.0.1:3030"
+///
+/// [[node]]
+/// id = 2
+/// address = "10.0.0.2:3030"
+/// name = "edge-2"
+/// attributes = { region = "eu" }
+/// codecs = ["zstd"]
+/// ```
+///
+/// All nodes can share the same file, each node finds itself by its address. The name is used
+/// to verify the certificate of the node and defaults to `node-<id>`. `codecs` lists the codecs
+/// the node can decompress messages with.
+#[derive(Debug, Deserialize)]
+pub struct StaticPeers {
+    #[serde(default, rename = "node")]
+    pub nodes: Vec<StaticPeer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StaticPeer {
+    pub id: u64,
+    pub address: SocketAddr,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(default)]
+    pub codecs: Vec<String>,
+}
+
+impl StaticPeers {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read peers file '{}'", path.display()))?;
+        let peers: StaticPeers = toml::from_str(&file)
+            .with_context(|| format!("Failed to parse peers file '{}'", path.display()))?;
+        for (i, peer) in peers.nodes.iter().enumerate() {
+            if peers.nodes[..i].iter().any(|other| other.id == peer.id) {
+                return Err(anyhow!("Node id {} is used more than once", peer.id));
+            }
+        }
+        Ok(peers)
+    }
+
+    /// Returns the entry of the node listening on `address`.
+    pub fn find(&self, address: SocketAddr) -> Result<&StaticPeer> {
+        self.nodes
+            .iter()
+            .find(|peer| peer.address == address)
+            .ok_or_else(|| anyhow!("No node with address {address} in the peers file"))
+    }
+}
+
+impl StaticPeer {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("node-{}", self.id))
+    }
+
+    pub fn node_info(&self) -> NodeInfo {
+        NodeInfo {
+            id: self.id,
+            address: self.address,
+            name: self.name(),
+            codecs: self.codecs.clone(),
+            processes: None,
+            attributes: self.attributes.clone(),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     sync::{
         atomic::{self, AtomicU64, AtomicUsize},
         Arc,
@@ -10,15 +11,20 @@ use anyhow::{anyhow, Result};
 use async_cell::sync::AsyncCell;
 use bytes::Bytes;
 use dashmap::DashMap;
+use lunatic_process::{message::MessageHeaders, DeathReason, Process, Signal};
 use tokio::sync::{
+    broadcast,
     mpsc::{Receiver, Sender},
-    Notify, RwLock,
+    Mutex, Notify, RwLock,
 };
 
 use crate::{
-    congestion::{self, node_connection_manager, MessageChunk, NodeConnectionManager},
+    congestion::{
+        self, node_connection_manager, CongestionConfig, MessageChunk, NodeConnectionManager,
+        NodeHealth, NodeStatus,
+    },
     control,
-    distributed::message::{Request, ResponseContent, Spawn},
+    distributed::message::{ClientError, Request, ResponseContent, Spawn},
     quic,
 };
 
@@ -43,6 +49,7 @@ pub struct SendParams {
     pub dest: ProcessId,
     pub tag: Option<i64>,
     pub data: Vec<u8>,
+    pub headers: MessageHeaders,
 }
 
 pub struct SpawnParams {
@@ -52,6 +59,50 @@ pub struct SpawnParams {
     pub spawn: Spawn,
 }
 
+pub struct LinkParams {
+    pub env: EnvironmentId,
+    pub src: ProcessId,
+    pub node: NodeId,
+    pub dest: ProcessId,
+    pub tag: Option<i64>,
+}
+
+pub struct MonitorParams {
+    pub env: EnvironmentId,
+    pub src: ProcessId,
+    pub node: NodeId,
+    pub dest: ProcessId,
+}
+
+// Targets the remote process `dest` on behalf of the local process `src`.
+pub struct ProcessParams {
+    pub env: EnvironmentId,
+    pub src: ProcessId,
+    pub node: NodeId,
+    pub dest: ProcessId,
+}
+
+// Opens a stream from the local process `src` to the remote process `dest`, every chunk is
+// delivered as a message with `tag`.
+pub struct StreamParams {
+    pub env: EnvironmentId,
+    pub src: ProcessId,
+    pub node: NodeId,
+    pub dest: ProcessId,
+    pub tag: Option<i64>,
+}
+
+// Notifies `dest` that the process `src` died.
+pub struct DeathParams {
+    pub env: EnvironmentId,
+    pub src: ProcessId,
+    pub node: NodeId,
+    pub dest: ProcessId,
+    pub tag: Option<i64>,
+    pub reason: DeathReason,
+    pub kill_reason: Option<Vec<u8>>,
+}
+
 pub struct ResponseParams {
     pub node_id: NodeId,
     pub response: Response,
@@ -71,7 +122,37 @@ pub struct MessageCtx {
 // Receiving part of the message queue
 type BufRx = RwLock<Receiver<MessageCtx>>;
 
-type IncomingResponse = (AsyncCell<ResponseContent>, Instant);
+// The response cell and when the request was sent. Requests sent without an `Instant` are not
+// timed out by `process_responses`, the waiting side handles the timeout itself.
+type IncomingResponse = (AsyncCell<ResponseContent>, Option<Instant>);
+
+/// Cause of the `ClientError::Unexpected` error that requests time out with if no response
+/// arrives.
+pub const RESPONSE_TIMEOUT: &str = "Response timeout.";
+
+/// How a local process is tied to a process on another node.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum RemoteLinkKind {
+    Link,
+    Monitor,
+}
+
+// Node of the remote process, kind, environment, local process and remote process
+type RemoteLinkKey = (NodeId, RemoteLinkKind, EnvironmentId, ProcessId, ProcessId);
+
+// Number of chunks of a stream that can be unacknowledged before writing waits
+const STREAM_WINDOW: usize = 4;
+
+// Environment and process owning the stream, and the stream ID
+type StreamKey = (EnvironmentId, ProcessId, u64);
+
+pub struct OutgoingStream {
+    params: StreamParams,
+    // Chunks sent to the remote node that were not acknowledged yet, oldest first
+    pending: VecDeque<MessageId>,
+    // Set once the end of the stream was sent
+    ended: bool,
+}
 
 #[derive(Clone)]
 pub struct Client {
@@ -82,6 +163,7 @@ pub struct Client {
 pub struct Inner {
     control_client: control::Client,
     node_client: quic::Client,
+    pub config: CongestionConfig,
     pub next_message_id: AtomicU64,
     // Across Environments and ProcessId's track message queues
     pub buf_rx: DashMap<EnvironmentId, DashMap<ProcessId, BufRx>>,
@@ -90,31 +172,50 @@ pub struct Inner {
     // Holds the message while its being chunked
     pub in_progress: DashMap<(EnvironmentId, ProcessId), MessageCtx>,
     pub nodes_queues: DashMap<NodeId, Sender<MessageChunk>>,
+    pub nodes_health: DashMap<NodeId, Arc<NodeHealth>>,
     pub responses: DashMap<MessageId, Arc<IncomingResponse>>,
     pub response_tx: Sender<(MessageId, ResponseContent)>,
     pub has_messages: Arc<Notify>,
+    // Local processes that need to be notified if a remote process' node leaves, with link tags
+    pub remote_links: DashMap<RemoteLinkKey, (Arc<dyn Process>, Option<i64>)>,
+    pub next_stream_id: AtomicU64,
+    pub streams: DashMap<StreamKey, Arc<Mutex<OutgoingStream>>>,
 }
 
 impl Client {
-    pub fn new(node_id: u64, control_client: control::Client, node_client: quic::Client) -> Self {
+    pub fn new(
+        node_id: u64,
+        control_client: control::Client,
+        node_client: quic::Client,
+        config: CongestionConfig,
+    ) -> Self {
         let (send, recv) = tokio::sync::mpsc::channel(1000);
         let client = Self {
             node_id: NodeId(node_id),
             inner: Arc::new(Inner {
                 control_client,
                 node_client,
+                config,
                 next_message_id: AtomicU64::new(1),
                 buf_rx: DashMap::new(),
                 buf_tx: DashMap::new(),
                 in_progress: DashMap::new(),
                 nodes_queues: DashMap::new(),
+                nodes_health: DashMap::new(),
                 responses: DashMap::new(),
                 response_tx: send,
                 has_messages: Arc::new(Notify::new()),
+                remote_links: DashMap::new(),
+                next_stream_id: AtomicU64::new(1),
+                streams: DashMap::new(),
             }),
         };
+        #[cfg(feature = "metrics")]
+        congestion::describe_metrics();
+        let nodes_left = client.inner.control_client.subscribe_nodes_left();
         tokio::spawn(congestion::congestion_control_worker(client.clone()));
         tokio::spawn(process_responses(client.clone(), recv));
+        tokio::spawn(notify_nodes_left(client.clone(), nodes_left));
         client
     }
 
@@ -133,12 +234,14 @@ impl Client {
         node: NodeId,
         dest: ProcessId,
         data: Bytes,
+        response: Option<IncomingResponse>,
     ) -> Result<MessageId> {
         // Lazy initialize process message buffers
         let tx = match self.inner.buf_tx.get(&(env, src)) {
             Some(tx) => tx,
             None => {
-                let (send, recv) = tokio::sync::mpsc::channel(1_000_000);
+                let (send, recv) =
+                    tokio::sync::mpsc::channel(self.inner.config.process_queue_depth);
                 match self.inner.buf_rx.get(&env) {
                     Some(env_queue) => {
                         env_queue.insert(src, RwLock::new(recv));
@@ -164,16 +267,44 @@ impl Client {
                 .control_client
                 .node_info(node.0)
                 .ok_or_else(|| anyhow!("Node does not exist"))?;
-            let (send, recv) = tokio::sync::mpsc::channel(1_000_000);
+            let (send, recv) = tokio::sync::mpsc::channel(self.inner.config.node_queue_depth);
+            let health = Arc::new(NodeHealth::default());
             tokio::spawn(node_connection_manager(NodeConnectionManager {
-                streams: 10,
+                streams: self.inner.config.streams,
                 node_info,
                 client: self.inner.node_client.clone(),
                 message_chunks: recv,
+                worker: self.inner.has_messages.clone(),
+                compression: self.inner.config.compression,
+                compression_threshold: self.inner.config.compression_threshold,
+                health: health.clone(),
             }));
+            self.inner.nodes_health.insert(node, health);
             self.inner.nodes_queues.insert(node, send);
         }
         let message_id = self.next_message_id();
+        // Fail fast while the node is unreachable instead of queueing messages for it
+        if self.node_status(node) == Some(NodeStatus::Unreachable) {
+            log::debug!(
+                "Dropped message {} to unreachable node {}",
+                message_id.0,
+                node.0
+            );
+            if let Some((cell, _)) = response {
+                cell.set(ResponseContent::Error(ClientError::Connection(format!(
+                    "Node {} is unreachable",
+                    node.0
+                ))));
+                self.inner
+                    .responses
+                    .insert(message_id, Arc::new((cell, None)));
+            }
+            return Ok(message_id);
+        }
+        // Register the response before sending, so that a fast reply is not missed
+        if let Some(response) = response {
+            self.inner.responses.insert(message_id, Arc::new(response));
+        }
         match tx
             .send(MessageCtx {
                 message_id,
@@ -194,18 +325,47 @@ impl Client {
         Ok(message_id)
     }
 
+    /// Returns the state of the connection to `node`, `None` if this node never sent anything to
+    /// it.
+    pub fn node_status(&self, node: NodeId) -> Option<NodeStatus> {
+        self.inner
+            .nodes_health
+            .get(&node)
+            .map(|health| health.status())
+    }
+
     pub fn remove_process_resources(&self, env: EnvironmentId, process_id: ProcessId) {
         self.inner.buf_tx.remove(&(env, process_id));
+        self.inner
+            .streams
+            .retain(|(stream_env, src, _), _| *stream_env != env || *src != process_id);
     }
 
     // Send distributed message
     pub async fn send(&self, params: SendParams) -> Result<MessageId> {
+        self.send_message(params, None).await
+    }
+
+    // Send distributed message and wait for the receiving node to confirm that it was put into
+    // the mailbox of the destination process, use `await_response` to get the confirmation. The
+    // confirmation times out like the responses to other requests.
+    pub async fn send_confirmed(&self, params: SendParams) -> Result<MessageId> {
+        self.send_message(params, Some((AsyncCell::new(), Some(Instant::now()))))
+            .await
+    }
+
+    async fn send_message(
+        &self,
+        params: SendParams,
+        response: Option<IncomingResponse>,
+    ) -> Result<MessageId> {
         let message = Request::Message {
             node_id: self.node_id.0,
             environment_id: params.env.0,
             process_id: params.dest.0,
             tag: params.tag,
             data: params.data,
+            headers: params.headers,
         };
         let data = match rmp_serde::to_vec(&message) {
             Ok(data) => data,
@@ -217,6 +377,7 @@ impl Client {
             params.node,
             params.dest,
             data.into(),
+            response,
         )
         .await
     }
@@ -228,19 +389,404 @@ impl Client {
             Ok(data) => data,
             Err(_) => unreachable!("lunatic::distributed::client::spawn serialize_message"),
         };
+        self.new_message(
+            params.env,
+            params.src,
+            params.node,
+            ProcessId(0),
+            data.into(),
+            Some((AsyncCell::new(), Some(Instant::now()))),
+        )
+        .await
+    }
+
+    // Send a request to another node, expecting a response if `awaits_response` is set
+    async fn request(
+        &self,
+        env: EnvironmentId,
+        src: ProcessId,
+        node: NodeId,
+        dest: ProcessId,
+        request: Request,
+        awaits_response: bool,
+    ) -> Result<MessageId> {
+        let data = match rmp_serde::to_vec(&request) {
+            Ok(data) => data,
+            Err(_) => unreachable!("lunatic::distributed::client::request serialize_message"),
+        };
+        let response = awaits_response.then(|| (AsyncCell::new(), Some(Instant::now())));
+        self.new_message(env, src, node, dest, data.into(), response)
+            .await
+    }
+
+    // Link the local process `src` with the remote process `dest`, the response is `Linked` if
+    // the remote process exists
+    pub async fn link(&self, params: LinkParams) -> Result<MessageId> {
+        let request = Request::Link {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            linked_process_id: params.src.0,
+            tag: params.tag,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            true,
+        )
+        .await
+    }
+
+    pub async fn unlink(&self, params: LinkParams) -> Result<MessageId> {
+        let request = Request::Unlink {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            linked_process_id: params.src.0,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            false,
+        )
+        .await
+    }
+
+    // Notify the remote process `dest` that the linked process `src` died
+    pub async fn link_died(&self, params: DeathParams) -> Result<MessageId> {
+        let request = Request::LinkDied {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            dead_process_id: params.src.0,
+            tag: params.tag,
+            reason: params.reason,
+            kill_reason: params.kill_reason,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            false,
+        )
+        .await
+    }
+
+    // Start monitoring the remote process `dest`, the response is `Monitoring` if the remote
+    // process exists
+    pub async fn monitor(&self, params: MonitorParams) -> Result<MessageId> {
+        let request = Request::Monitor {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            monitoring_process_id: params.src.0,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            true,
+        )
+        .await
+    }
+
+    pub async fn stop_monitoring(&self, params: MonitorParams) -> Result<MessageId> {
+        let request = Request::StopMonitoring {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            monitoring_process_id: params.src.0,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            false,
+        )
+        .await
+    }
+
+    // Notify the remote process `dest` that the monitored process `src` died
+    pub async fn process_died(&self, params: DeathParams) -> Result<MessageId> {
+        let request = Request::ProcessDied {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            dead_process_id: params.src.0,
+            reason: params.reason,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            false,
+        )
+        .await
+    }
+
+    // Kill the remote process `dest`, the response is `Killed` if the remote process exists
+    pub async fn kill(&self, params: ProcessParams) -> Result<MessageId> {
+        let request = Request::Kill {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            killing_process_id: params.src.0,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            true,
+        )
+        .await
+    }
+
+    // Check if the remote process `dest` exists, the response is `Exists`
+    pub async fn exists(&self, params: ProcessParams) -> Result<MessageId> {
+        let request = Request::Exists {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+        };
+        self.request(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            request,
+            true,
+        )
+        .await
+    }
+
+    /// Opens a stream to a remote process and returns its ID.
+    ///
+    /// Nothing is sent until the first chunk is written, so a missing process is only reported
+    /// by `stream_write`.
+    pub fn stream_open(&self, params: StreamParams) -> u64 {
+        let stream_id = self
+            .inner
+            .next_stream_id
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        let key = (params.env, params.src, stream_id);
+        let stream = OutgoingStream {
+            params,
+            pending: VecDeque::new(),
+            ended: false,
+        };
+        self.inner.streams.insert(key, Arc::new(Mutex::new(stream)));
+        stream_id
+    }
+
+    /// Sends the next chunk of the stream `stream_id`, owned by the process `src`.
+    ///
+    /// The receiving node acknowledges chunks once the receiving process took them out of its
+    /// mailbox. If too many chunks are unacknowledged, this waits for the oldest one, so that a
+    /// fast writer can't flood a slow reader.
+    ///
+    /// Returns `Sent` if the chunk was queued, the error of an earlier chunk, or `None` if
+    /// waiting for an acknowledgement timed out. The chunk is not sent in the last two cases.
+    pub async fn stream_write(
+        &self,
+        env: EnvironmentId,
+        src: ProcessId,
+        stream_id: u64,
+        data: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ResponseContent>> {
+        let stream = self.stream(env, src, stream_id)?;
+        let mut stream = stream.lock().await;
+        if stream.ended {
+            return Err(anyhow!("Stream {stream_id} is closed"));
+        }
+        match self
+            .stream_drain(&mut stream, STREAM_WINDOW - 1, timeout)
+            .await?
+        {
+            Some(ResponseContent::Sent) => (),
+            response => return Ok(response),
+        }
+        let message_id = self
+            .stream_chunk(&stream.params, stream_id, data, false)
+            .await?;
+        stream.pending.push_back(message_id);
+        Ok(Some(ResponseContent::Sent))
+    }
+
+    /// Ends the stream `stream_id` and waits until the receiving node acknowledged all chunks.
+    ///
+    /// Returns the same as `stream_write`. The stream is removed, unless waiting timed out and
+    /// closing needs to be retried.
+    pub async fn stream_close(
+        &self,
+        env: EnvironmentId,
+        src: ProcessId,
+        stream_id: u64,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ResponseContent>> {
+        let stream = self.stream(env, src, stream_id)?;
+        let mut stream = stream.lock().await;
+        if !stream.ended {
+            let message_id = self
+                .stream_chunk(&stream.params, stream_id, Vec::new(), true)
+                .await?;
+            stream.pending.push_back(message_id);
+            stream.ended = true;
+        }
+        let response = self.stream_drain(&mut stream, 0, timeout).await?;
+        match response {
+            // Closing can be retried
+            None => return Ok(None),
+            Some(ResponseContent::Sent) => (),
+            Some(_) => {
+                for message_id in stream.pending.drain(..) {
+                    self.forget_response(message_id);
+                }
+            }
+        }
+        self.inner.streams.remove(&(env, src, stream_id));
+        Ok(response)
+    }
+
+    fn stream(
+        &self,
+        env: EnvironmentId,
+        src: ProcessId,
+        stream_id: u64,
+    ) -> Result<Arc<Mutex<OutgoingStream>>> {
+        self.inner
+            .streams
+            .get(&(env, src, stream_id))
+            .map(|stream| stream.clone())
+            .ok_or_else(|| anyhow!("Stream {stream_id} doesn't exist"))
+    }
+
+    // Waits until at most `max_pending` chunks are unacknowledged
+    async fn stream_drain(
+        &self,
+        stream: &mut OutgoingStream,
+        max_pending: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ResponseContent>> {
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        while stream.pending.len() > max_pending {
+            let oldest = stream.pending[0];
+            let response = self.await_response(oldest);
+            let response = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, response).await {
+                    Ok(response) => response?,
+                    Err(_) => return Ok(None),
+                },
+                None => response.await?,
+            };
+            stream.pending.pop_front();
+            if !matches!(response, ResponseContent::Sent) {
+                return Ok(Some(response));
+            }
+        }
+        Ok(Some(ResponseContent::Sent))
+    }
+
+    async fn stream_chunk(
+        &self,
+        params: &StreamParams,
+        stream_id: u64,
+        data: Vec<u8>,
+        end: bool,
+    ) -> Result<MessageId> {
+        let request = Request::StreamChunk {
+            node_id: self.node_id.0,
+            environment_id: params.env.0,
+            process_id: params.dest.0,
+            stream_id,
+            tag: params.tag,
+            data,
+            end,
+        };
+        let data = match rmp_serde::to_vec(&request) {
+            Ok(data) => data,
+            Err(_) => unreachable!("lunatic::distributed::client::stream serialize_message"),
+        };
+        // Slow readers delay the acknowledgement, so it's not timed out
+        self.new_message(
+            params.env,
+            params.src,
+            params.node,
+            params.dest,
+            data.into(),
+            Some((AsyncCell::new(), None)),
+        )
+        .await
+    }
+
+    // Fetch the bytes of a module from the node that spawned it
+    pub async fn fetch_module(
+        &self,
+        env: EnvironmentId,
+        node: NodeId,
+        module_id: u64,
+    ) -> Result<Vec<u8>> {
+        let request = Request::GetModule {
+            node_id: self.node_id.0,
+            environment_id: env.0,
+            module_id,
+        };
         let message_id = self
-            .new_message(
-                params.env,
-                params.src,
-                params.node,
-                ProcessId(0),
-                data.into(),
-            )
+            .request(env, ProcessId(0), node, ProcessId(0), request, true)
             .await?;
+        match self.await_response(message_id).await? {
+            ResponseContent::Module(module) => Ok(module),
+            response => Err(anyhow!(
+                "Failed to fetch module {module_id} from node {}: {response:?}",
+                node.0
+            )),
+        }
+    }
+
+    /// Remembers that the local process is tied to a process on `node`, so that it receives a
+    /// `NoProcess` death notification if the node leaves.
+    pub fn add_remote_link(
+        &self,
+        node: NodeId,
+        kind: RemoteLinkKind,
+        env: EnvironmentId,
+        local: Arc<dyn Process>,
+        remote: ProcessId,
+        tag: Option<i64>,
+    ) {
+        let local_id = ProcessId(local.id());
         self.inner
-            .responses
-            .insert(message_id, Arc::new((AsyncCell::new(), Instant::now())));
-        Ok(message_id)
+            .remote_links
+            .insert((node, kind, env, local_id, remote), (local, tag));
+    }
+
+    pub fn remove_remote_link(
+        &self,
+        node: NodeId,
+        kind: RemoteLinkKind,
+        env: EnvironmentId,
+        local: ProcessId,
+        remote: ProcessId,
+    ) {
+        self.inner
+            .remote_links
+            .remove(&(node, kind, env, local, remote));
     }
 
     // Send distributed response message
@@ -256,6 +802,7 @@ impl Client {
             params.node_id,
             ProcessId(0),
             data.into(),
+            None,
         )
         .await
     }
@@ -270,17 +817,58 @@ impl Client {
     }
 
     pub async fn await_response(&self, message_id: MessageId) -> Result<ResponseContent> {
-        let response = self
+        // Don't hold the map entry while waiting, `process_responses` needs to remove entries
+        let cell = self
             .inner
             .responses
             .get(&message_id)
-            .ok_or_else(|| anyhow!("message does not exist"))?
-            .0
-            .take()
-            .await;
+            .map(|response| response.clone())
+            .ok_or_else(|| anyhow!("message does not exist"))?;
+        let response = cell.0.take().await;
         self.inner.responses.remove(&message_id);
         Ok(response)
     }
+
+    /// Stops waiting for the response to `message_id`, a response arriving later is dropped.
+    pub fn forget_response(&self, message_id: MessageId) {
+        self.inner.responses.remove(&message_id);
+    }
+}
+
+// Deliver `NoProcess` deaths to local processes tied to processes on nodes that left
+async fn notify_nodes_left(client: Client, mut nodes_left: broadcast::Receiver<u64>) {
+    loop {
+        let node = match nodes_left.recv().await {
+            Ok(node) => NodeId(node),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("lunatic::distributed::client missed {skipped} nodes leaving");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let keys: Vec<RemoteLinkKey> = client
+            .inner
+            .remote_links
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|key| key.0 == node)
+            .collect();
+        for key in keys {
+            if let Some(((_, kind, _, _, remote), (local, tag))) =
+                client.inner.remote_links.remove(&key)
+            {
+                let signal = match kind {
+                    RemoteLinkKind::Link => {
+                        Signal::LinkDied(Some(node.0), remote.0, tag, DeathReason::NoProcess, None)
+                    }
+                    RemoteLinkKind::Monitor => {
+                        Signal::ProcessDied(remote.0, DeathReason::NoProcess)
+                    }
+                };
+                local.send(signal);
+            }
+        }
+    }
 }
 
 pub async fn process_responses(
@@ -298,20 +886,22 @@ pub async fn process_responses(
             }
            },
            _ = tokio::time::sleep(TIMEOUT) => {
-            for entry in client.inner.responses.iter() {
+            client.inner.responses.retain(|_, entry| {
+                let timed_out = entry.1.is_some_and(|sent| sent.elapsed() > TIMEOUT);
                 // Clean up timeouts
-                if entry.0.is_set() && entry.1.elapsed() > TIMEOUT {
-                    client.inner.responses.remove(entry.key());
+                if entry.0.is_set() && timed_out {
+                    return false;
                 }
                 // Set timeout response
-                if !entry.0.is_set() && entry.1.elapsed() > TIMEOUT {
+                if !entry.0.is_set() && timed_out {
                     entry.0.set(ResponseContent::Error(
                         crate::distributed::message::ClientError::Unexpected(
-                            "Response timeout.".to_string(),
+                            RESPONSE_TIMEOUT.to_string(),
                         ),
                     ));
                 }
-            }
+                true
+            });
            }
         };
     }
@@ -1,6 +1,13 @@
 use bytes::Bytes;
+use lunatic_process::{message::MessageHeaders, DeathReason};
 use serde::{Deserialize, Serialize};
 
+/// Header of messages carrying a chunk of a stream, the value is the stream ID as little endian
+/// `u64`.
+pub const STREAM_ID_HEADER: &[u8] = b"lunatic.stream.id";
+/// Header of the last message of a stream, it carries no data.
+pub const STREAM_END_HEADER: &[u8] = b"lunatic.stream.end";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
     Spawn(Spawn),
@@ -10,6 +17,84 @@ pub enum Request {
         process_id: u64,
         tag: Option<i64>,
         data: Vec<u8>,
+        #[serde(default)]
+        headers: MessageHeaders,
+    },
+    // Links `process_id` with the process `linked_process_id` running on node `node_id`.
+    Link {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        linked_process_id: u64,
+        tag: Option<i64>,
+    },
+    Unlink {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        linked_process_id: u64,
+    },
+    // Notifies `process_id` that the linked process `dead_process_id` on node `node_id` died.
+    LinkDied {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        dead_process_id: u64,
+        tag: Option<i64>,
+        reason: DeathReason,
+        kill_reason: Option<Vec<u8>>,
+    },
+    // The process `monitoring_process_id` on node `node_id` starts monitoring `process_id`.
+    Monitor {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        monitoring_process_id: u64,
+    },
+    StopMonitoring {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        monitoring_process_id: u64,
+    },
+    // Notifies `process_id` that the monitored process `dead_process_id` on node `node_id` died.
+    ProcessDied {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        dead_process_id: u64,
+        reason: DeathReason,
+    },
+    // Asks for the bytes of a module spawned from node `node_id`, used when there is no control
+    // server to get them from.
+    GetModule {
+        node_id: u64,
+        environment_id: u64,
+        module_id: u64,
+    },
+    // Kills `process_id`, sent by the process `killing_process_id` on node `node_id`.
+    Kill {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        killing_process_id: u64,
+    },
+    // Checks if `process_id` is still running.
+    Exists {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+    },
+    // Next chunk of the stream `stream_id` from node `node_id` to `process_id`. The chunk is
+    // acknowledged once the process caught up with reading its mailbox.
+    StreamChunk {
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        stream_id: u64,
+        tag: Option<i64>,
+        data: Vec<u8>,
+        end: bool,
     },
     Response(Response),
 }
@@ -19,9 +104,82 @@ impl Request {
         match self {
             Request::Spawn(_) => "Spawn",
             Request::Message { .. } => "Message",
+            Request::Link { .. } => "Link",
+            Request::Unlink { .. } => "Unlink",
+            Request::LinkDied { .. } => "LinkDied",
+            Request::Monitor { .. } => "Monitor",
+            Request::StopMonitoring { .. } => "StopMonitoring",
+            Request::ProcessDied { .. } => "ProcessDied",
+            Request::GetModule { .. } => "GetModule",
+            Request::Kill { .. } => "Kill",
+            Request::Exists { .. } => "Exists",
+            Request::StreamChunk { .. } => "StreamChunk",
             Request::Response(_) => "Response",
         }
     }
+
+    /// Returns the node that sent the request and the environment it targets.
+    pub fn origin(&self) -> Option<(u64, u64)> {
+        match self {
+            Request::Spawn(spawn) => Some((spawn.response_node_id, spawn.environment_id)),
+            Request::Message {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::Link {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::Unlink {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::LinkDied {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::Monitor {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::StopMonitoring {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::ProcessDied {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::GetModule {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::Kill {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::Exists {
+                node_id,
+                environment_id,
+                ..
+            }
+            | Request::StreamChunk {
+                node_id,
+                environment_id,
+                ..
+            } => Some((*node_id, *environment_id)),
+            Request::Response(_) => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -41,6 +199,8 @@ pub enum ClientError {
     NodeNotFound,
     ModuleNotFound,
     ProcessNotFound,
+    // The sending or the receiving node doesn't have access to the environment
+    Unauthorized(String),
 }
 
 impl Default for ClientError {
@@ -60,6 +220,10 @@ pub enum ResponseContent {
     Spawned(u64),
     Sent,
     Linked,
+    Monitoring,
+    Module(Vec<u8>),
+    Killed,
+    Exists(bool),
     Error(ClientError),
 }
 
@@ -69,6 +233,10 @@ impl Response {
             ResponseContent::Spawned(_) => "Spawned",
             ResponseContent::Sent => "Sent",
             ResponseContent::Linked => "Linked",
+            ResponseContent::Monitoring => "Monitoring",
+            ResponseContent::Module(_) => "Module",
+            ResponseContent::Killed => "Killed",
+            ResponseContent::Exists(_) => "Exists",
             ResponseContent::Error(_) => "Error",
         }
     }
@@ -99,3 +267,102 @@ pub fn pack_response(msg_id: u64, resp: Response) -> [Bytes; 2] {
     let bytes: Bytes = data.into();
     [size, bytes]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Requests are sent between nodes encoded with MessagePack
+    fn round_trip(request: Request) {
+        let data = rmp_serde::to_vec(&request).unwrap();
+        let decoded: Request = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{request:?}"));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip(Request::Link {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            linked_process_id: 4,
+            tag: Some(-5),
+        });
+        round_trip(Request::Unlink {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            linked_process_id: 4,
+        });
+        round_trip(Request::LinkDied {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            dead_process_id: 4,
+            tag: None,
+            reason: DeathReason::Exit(6),
+            kill_reason: Some(b"reason".to_vec()),
+        });
+        round_trip(Request::Monitor {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            monitoring_process_id: 4,
+        });
+        round_trip(Request::StopMonitoring {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            monitoring_process_id: 4,
+        });
+        round_trip(Request::ProcessDied {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            dead_process_id: 4,
+            reason: DeathReason::NoProcess,
+        });
+        round_trip(Request::GetModule {
+            node_id: 1,
+            environment_id: 2,
+            module_id: 3,
+        });
+        round_trip(Request::Kill {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            killing_process_id: 4,
+        });
+        round_trip(Request::Exists {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+        });
+        round_trip(Request::StreamChunk {
+            node_id: 1,
+            environment_id: 2,
+            process_id: 3,
+            stream_id: 4,
+            tag: Some(5),
+            data: vec![6; 16],
+            end: true,
+        });
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for content in [
+            ResponseContent::Linked,
+            ResponseContent::Monitoring,
+            ResponseContent::Module(vec![0, 97, 115, 109]),
+            ResponseContent::Killed,
+            ResponseContent::Exists(true),
+            ResponseContent::Error(ClientError::Unauthorized("environment 2".to_string())),
+        ] {
+            round_trip(Request::Response(Response {
+                message_id: 1,
+                content,
+            }));
+        }
+    }
+}
@@ -1,5 +1,9 @@
 pub mod client;
 pub mod message;
+pub mod module_cache;
+pub mod remote;
 pub mod server;
 
 pub use client::Client;
+pub use module_cache::ModuleCache;
+pub use remote::RemoteProcess;
@@ -0,0 +1,73 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Returns the hex encoded SHA-256 hash of a module, used as its key in the cache.
+pub fn content_hash(module: &[u8]) -> String {
+    Sha256::digest(module)
+        .iter()
+        .fold(String::with_capacity(64), |mut hash, byte| {
+            let _ = write!(hash, "{byte:02x}");
+            hash
+        })
+}
+
+/// Modules fetched from the control server, kept on disk so that they are only downloaded once
+/// per node, also across restarts.
+#[derive(Clone, Debug)]
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create module cache '{}'", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{hash}.wasm"))
+    }
+
+    /// Returns the cached module with the content `hash`.
+    ///
+    /// Files that don't match their hash anymore are ignored, so that a corrupted cache entry is
+    /// downloaded again. Reading and hashing happens on the blocking thread pool.
+    pub async fn get(&self, hash: &str) -> Option<Vec<u8>> {
+        let path = self.path(hash);
+        let hash = hash.to_string();
+        tokio::task::spawn_blocking(move || {
+            let module = std::fs::read(path).ok()?;
+            if content_hash(&module) == hash {
+                Some(module)
+            } else {
+                log::warn!("Ignoring corrupted module {hash} in the module cache");
+                None
+            }
+        })
+        .await
+        .ok()?
+    }
+
+    /// Stores the module with the content `hash`, writing happens on the blocking thread pool.
+    pub async fn put(&self, hash: &str, module: Vec<u8>) -> Result<()> {
+        // Write to a temporary file first, so that other nodes sharing the cache never read a
+        // partially written module
+        let tmp = self
+            .dir
+            .join(format!("{hash}.{}.tmp", uuid::Uuid::new_v4().simple()));
+        let path = self.path(hash);
+        tokio::task::spawn_blocking(move || {
+            std::fs::write(&tmp, module)?;
+            std::fs::rename(&tmp, path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
@@ -0,0 +1,81 @@
+use lunatic_process::{Process, Signal};
+
+use super::client::{Client, DeathParams, EnvironmentId, NodeId, ProcessId, RemoteLinkKind};
+
+/// A process running on another node.
+///
+/// It can be put into the links and monitors of local processes. Death notifications sent to it
+/// are forwarded to the node, all other signals are dropped.
+pub struct RemoteProcess {
+    client: Client,
+    env: EnvironmentId,
+    node: NodeId,
+    id: ProcessId,
+}
+
+impl RemoteProcess {
+    pub fn new(client: Client, env: EnvironmentId, node: NodeId, id: ProcessId) -> Self {
+        Self {
+            client,
+            env,
+            node,
+            id,
+        }
+    }
+}
+
+impl Process for RemoteProcess {
+    fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    fn node_id(&self) -> Option<u64> {
+        Some(self.node.0)
+    }
+
+    fn send(&self, signal: Signal) {
+        let client = self.client.clone();
+        let (env, node, dest) = (self.env, self.node, self.id);
+        match signal {
+            // Only local processes notify remote ones, so the dead process is on this node
+            Signal::LinkDied(_, id, tag, reason, kill_reason) => {
+                client.remove_remote_link(node, RemoteLinkKind::Link, env, ProcessId(id), dest);
+                tokio::spawn(async move {
+                    let params = DeathParams {
+                        env,
+                        src: ProcessId(id),
+                        node,
+                        dest,
+                        tag,
+                        reason,
+                        kill_reason,
+                    };
+                    if let Err(error) = client.link_died(params).await {
+                        log::debug!("Failed to forward LinkDied to node {}: {error}", node.0);
+                    }
+                });
+            }
+            Signal::ProcessDied(id, reason) => {
+                tokio::spawn(async move {
+                    let params = DeathParams {
+                        env,
+                        src: ProcessId(id),
+                        node,
+                        dest,
+                        tag: None,
+                        reason,
+                        kill_reason: None,
+                    };
+                    if let Err(error) = client.process_died(params).await {
+                        log::debug!("Failed to forward ProcessDied to node {}: {error}", node.0);
+                    }
+                });
+            }
+            signal => log::debug!(
+                "Signal {signal:?} can't be sent to process {} on node {}",
+                dest.0,
+                node.0
+            ),
+        }
+    }
+}
@@ -1,13 +1,13 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
 
 use lunatic_process::{
     env::{Environment, Environments},
-    message::{DataMessage, Message},
+    message::{DataMessage, Message, MessageHeaders},
     runtimes::{wasmtime::WasmtimeRuntime, Modules, RawWasm},
     state::ProcessState,
-    Signal,
+    Process, Signal,
 };
 use rcgen::*;
 use wasmtime::ResourceLimiter;
@@ -19,10 +19,17 @@ use crate::{
 };
 
 use super::{
-    client::{Client, NodeId, ResponseParams},
-    message::{ClientError, ResponseContent, Spawn},
+    client::{Client, EnvironmentId, NodeId, ProcessId, RemoteLinkKind, ResponseParams},
+    message::{ClientError, ResponseContent, Spawn, STREAM_END_HEADER, STREAM_ID_HEADER},
+    module_cache::ModuleCache,
+    remote::RemoteProcess,
 };
 
+// Stream chunks are acknowledged once fewer messages than this wait in the mailbox of the
+// receiving process, or it's below its configured watermark
+const STREAM_MAILBOX_LIMIT: usize = 16;
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct ServerCtx<T, E: Environment> {
     pub envs: Arc<dyn Environments<Env = E>>,
     pub modules: Modules<T>,
@@ -30,6 +37,7 @@ pub struct ServerCtx<T, E: Environment> {
     pub runtime: WasmtimeRuntime,
     pub node_client: Client,
     pub allowed_envs: Option<HashSet<u64>>,
+    pub module_cache: Option<ModuleCache>,
 }
 
 impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
@@ -41,6 +49,7 @@ impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
             runtime: self.runtime.clone(),
             node_client: self.node_client.clone(),
             allowed_envs: self.allowed_envs.clone(),
+            module_cache: self.module_cache.clone(),
         }
     }
 }
@@ -64,18 +73,12 @@ pub fn gen_node_cert(node_name: &str) -> Result<Certificate> {
         .map_err(|_| anyhow!("Error while generating node certificate."))
 }
 
-pub async fn node_server<T, E>(
-    ctx: ServerCtx<T, E>,
-    socket: SocketAddr,
-    ca_cert: String,
-    certs: Vec<String>,
-    key: String,
-) -> Result<()>
+/// Handles the connections of other nodes to `quic_server`, created with `quic::new_quic_server`.
+pub async fn node_server<T, E>(ctx: ServerCtx<T, E>, mut quic_server: quinn::Endpoint) -> Result<()>
 where
     T: ProcessState + ResourceLimiter + DistributedCtx<E> + Send + Sync + 'static,
     E: Environment + 'static,
 {
-    let mut quic_server = quic::new_quic_server(socket, certs, &key, &ca_cert)?;
     if let Err(e) = quic::handle_node_server(&mut quic_server, ctx.clone()).await {
         log::error!("Node server stopped {e}")
     };
@@ -106,49 +109,36 @@ where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + Sync + 'static,
     E: Environment + 'static,
 {
-    let env_id = match &msg {
-        Request::Spawn(spawn) => Some((spawn.response_node_id, spawn.environment_id)),
-        Request::Message {
-            node_id,
-            environment_id,
-            process_id: _,
-            tag: _,
-            data: _,
-        } => Some((*node_id, *environment_id)),
-        Request::Response(_) => None,
-    };
-    if let Some((node_id, env_id)) = env_id {
-        if let Some(ref allowed_envs) = node_permissions.0 {
-            if !allowed_envs.contains(&env_id) {
-                ctx.node_client
-                    .send_response(ResponseParams {
-                        node_id: NodeId(node_id),
-                        response: Response {
-                            message_id: msg_id,
-                            content: ResponseContent::Error(ClientError::Unexpected(format!(
-                    "The node sending the request does not have access to the environment {env_id}"
-                ))),
-                        },
-                    })
-                    .await?;
-                return Ok(());
-            }
-        }
-        if let Some(ref allowed_envs) = ctx.allowed_envs {
-            if !allowed_envs.contains(&env_id) {
-                ctx.node_client
-                    .send_response(ResponseParams {
-                        node_id: NodeId(node_id),
-                        response: Response {
-                            message_id: msg_id,
-                            content: ResponseContent::Error(ClientError::Unexpected(format!(
-                                "This node does not have access to environment {env_id}"
-                            ))),
-                        },
-                    })
-                    .await?;
-                return Ok(());
-            }
+    // Every request except responses targets an environment, which both the sending node's
+    // certificate and this node must have access to
+    if let Some((node_id, env_id)) = msg.origin() {
+        let denied = if !node_permissions.allows(env_id) {
+            Some(format!(
+                "The node sending the request does not have access to the environment {env_id}"
+            ))
+        } else if !ctx
+            .allowed_envs
+            .as_ref()
+            .is_none_or(|allowed_envs| allowed_envs.contains(&env_id))
+        {
+            Some(format!(
+                "This node does not have access to environment {env_id}"
+            ))
+        } else {
+            None
+        };
+        if let Some(reason) = denied {
+            log::warn!("Rejected {} from node {node_id}: {reason}", msg.kind());
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: NodeId(node_id),
+                    response: Response {
+                        message_id: msg_id,
+                        content: ResponseContent::Error(ClientError::Unauthorized(reason)),
+                    },
+                })
+                .await?;
+            return Ok(());
         }
     }
     match msg {
@@ -206,9 +196,19 @@ where
             process_id,
             tag,
             data,
+            headers,
         } => {
             log::trace!("distributed::server process Message");
-            match handle_process_message(ctx.clone(), environment_id, process_id, tag, data).await {
+            match handle_process_message(
+                ctx.clone(),
+                environment_id,
+                process_id,
+                tag,
+                data,
+                headers,
+            )
+            .await
+            {
                 Ok(_) => {
                     ctx.node_client
                         .send_response(ResponseParams {
@@ -233,6 +233,267 @@ where
                 }
             }
         }
+        Request::Link {
+            node_id,
+            environment_id,
+            process_id,
+            linked_process_id,
+            tag,
+        } => {
+            log::trace!("distributed::server process Link");
+            let (env, node, linked) = (
+                EnvironmentId(environment_id),
+                NodeId(node_id),
+                ProcessId(linked_process_id),
+            );
+            let remote = RemoteProcess::new(ctx.node_client.clone(), env, node, linked);
+            let process = get_process(&ctx, environment_id, process_id)
+                .await
+                .filter(|process| process.try_send(Signal::Link(tag, Arc::new(remote))));
+            let content = match process {
+                Some(process) => {
+                    ctx.node_client.add_remote_link(
+                        node,
+                        RemoteLinkKind::Link,
+                        env,
+                        process,
+                        linked,
+                        tag,
+                    );
+                    ResponseContent::Linked
+                }
+                None => ResponseContent::Error(ClientError::ProcessNotFound),
+            };
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: node,
+                    response: Response {
+                        message_id: msg_id,
+                        content,
+                    },
+                })
+                .await?;
+        }
+        Request::Unlink {
+            node_id,
+            environment_id,
+            process_id,
+            linked_process_id,
+        } => {
+            log::trace!("distributed::server process Unlink");
+            ctx.node_client.remove_remote_link(
+                NodeId(node_id),
+                RemoteLinkKind::Link,
+                EnvironmentId(environment_id),
+                ProcessId(process_id),
+                ProcessId(linked_process_id),
+            );
+            if let Some(process) = get_process(&ctx, environment_id, process_id).await {
+                process.send(Signal::UnLink {
+                    node_id: Some(node_id),
+                    process_id: linked_process_id,
+                });
+            }
+        }
+        Request::LinkDied {
+            node_id,
+            environment_id,
+            process_id,
+            dead_process_id,
+            tag,
+            reason,
+            kill_reason,
+        } => {
+            log::trace!("distributed::server process LinkDied");
+            ctx.node_client.remove_remote_link(
+                NodeId(node_id),
+                RemoteLinkKind::Link,
+                EnvironmentId(environment_id),
+                ProcessId(process_id),
+                ProcessId(dead_process_id),
+            );
+            if let Some(process) = get_process(&ctx, environment_id, process_id).await {
+                process.send(Signal::LinkDied(
+                    Some(node_id),
+                    dead_process_id,
+                    tag,
+                    reason,
+                    kill_reason,
+                ));
+            }
+        }
+        Request::Monitor {
+            node_id,
+            environment_id,
+            process_id,
+            monitoring_process_id,
+        } => {
+            log::trace!("distributed::server process Monitor");
+            let remote = RemoteProcess::new(
+                ctx.node_client.clone(),
+                EnvironmentId(environment_id),
+                NodeId(node_id),
+                ProcessId(monitoring_process_id),
+            );
+            let process = get_process(&ctx, environment_id, process_id)
+                .await
+                .filter(|process| process.try_send(Signal::Monitor(Arc::new(remote))));
+            let content = match process {
+                Some(_) => ResponseContent::Monitoring,
+                None => ResponseContent::Error(ClientError::ProcessNotFound),
+            };
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: NodeId(node_id),
+                    response: Response {
+                        message_id: msg_id,
+                        content,
+                    },
+                })
+                .await?;
+        }
+        Request::StopMonitoring {
+            node_id,
+            environment_id,
+            process_id,
+            monitoring_process_id,
+        } => {
+            log::trace!("distributed::server process StopMonitoring");
+            if let Some(process) = get_process(&ctx, environment_id, process_id).await {
+                process.send(Signal::StopMonitoring {
+                    node_id: Some(node_id),
+                    process_id: monitoring_process_id,
+                });
+            }
+        }
+        Request::ProcessDied {
+            node_id,
+            environment_id,
+            process_id,
+            dead_process_id,
+            reason,
+        } => {
+            log::trace!("distributed::server process ProcessDied");
+            ctx.node_client.remove_remote_link(
+                NodeId(node_id),
+                RemoteLinkKind::Monitor,
+                EnvironmentId(environment_id),
+                ProcessId(process_id),
+                ProcessId(dead_process_id),
+            );
+            if let Some(process) = get_process(&ctx, environment_id, process_id).await {
+                process.send(Signal::ProcessDied(dead_process_id, reason));
+            }
+        }
+        Request::GetModule {
+            node_id, module_id, ..
+        } => {
+            log::trace!("distributed::server process GetModule");
+            let content = match ctx.distributed.control.local_module(module_id) {
+                Some(module) => ResponseContent::Module(module),
+                None => ResponseContent::Error(ClientError::ModuleNotFound),
+            };
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: NodeId(node_id),
+                    response: Response {
+                        message_id: msg_id,
+                        content,
+                    },
+                })
+                .await?;
+        }
+        Request::Kill {
+            node_id,
+            environment_id,
+            process_id,
+            killing_process_id,
+        } => {
+            log::trace!("distributed::server process Kill");
+            let content = match get_process(&ctx, environment_id, process_id).await {
+                Some(process) => {
+                    log::debug!(
+                        "Process {killing_process_id} on node {node_id} killed process {process_id}"
+                    );
+                    process.send(Signal::Kill(None));
+                    ResponseContent::Killed
+                }
+                None => ResponseContent::Error(ClientError::ProcessNotFound),
+            };
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: NodeId(node_id),
+                    response: Response {
+                        message_id: msg_id,
+                        content,
+                    },
+                })
+                .await?;
+        }
+        Request::Exists {
+            node_id,
+            environment_id,
+            process_id,
+        } => {
+            log::trace!("distributed::server process Exists");
+            let exists = get_process(&ctx, environment_id, process_id)
+                .await
+                .is_some();
+            ctx.node_client
+                .send_response(ResponseParams {
+                    node_id: NodeId(node_id),
+                    response: Response {
+                        message_id: msg_id,
+                        content: ResponseContent::Exists(exists),
+                    },
+                })
+                .await?;
+        }
+        Request::StreamChunk {
+            node_id,
+            environment_id,
+            process_id,
+            stream_id,
+            tag,
+            data,
+            end,
+        } => {
+            log::trace!("distributed::server process StreamChunk");
+            let mut message = DataMessage::new_from_vec(tag, data);
+            message.set_header(STREAM_ID_HEADER.to_vec(), stream_id.to_le_bytes().to_vec());
+            if end {
+                message.set_header(STREAM_END_HEADER.to_vec(), Vec::new());
+            }
+            let env = ctx.envs.get(environment_id).await;
+            let process = env
+                .as_ref()
+                .and_then(|env| env.get_process(process_id))
+                .filter(|process| process.try_send(Signal::Message(Message::Data(message))));
+            let response = move |content| ResponseParams {
+                node_id: NodeId(node_id),
+                response: Response {
+                    message_id: msg_id,
+                    content,
+                },
+            };
+            match (env, process) {
+                (Some(env), Some(process)) => {
+                    // Waiting for the reader must not hold up other requests from the node
+                    let node_client = ctx.node_client.clone();
+                    tokio::spawn(async move {
+                        wait_for_reader(env, process).await;
+                        node_client
+                            .send_response(response(ResponseContent::Sent))
+                            .await
+                            .ok();
+                    });
+                }
+                _ => {
+                    let content = ResponseContent::Error(ClientError::ProcessNotFound);
+                    ctx.node_client.send_response(response(content)).await?;
+                }
+            }
+        }
         Request::Response(response) => {
             log::trace!("distributed::server process Response");
             ctx.node_client.recv_response(response).await;
@@ -247,12 +508,12 @@ where
     E: Environment + 'static,
 {
     let Spawn {
+        response_node_id,
         environment_id,
         module_id,
         function,
         params,
         config,
-        ..
     } = spawn;
     let config: T::Config = rmp_serde::from_slice(&config[..])?;
     let config = Arc::new(config);
@@ -260,17 +521,13 @@ where
     let module = match ctx.modules.get(module_id) {
         Some(module) => module,
         None => {
-            if let Ok(bytes) = ctx
-                .distributed
-                .control
-                .get_module(module_id, environment_id)
-                .await
+            let bytes = match module_bytes(&ctx, environment_id, module_id, response_node_id).await
             {
-                let wasm = RawWasm::new(Some(module_id), bytes);
-                ctx.modules.compile(ctx.runtime.clone(), wasm).await??
-            } else {
-                return Ok(Err(ClientError::ModuleNotFound));
-            }
+                Some(bytes) => bytes,
+                None => return Ok(Err(ClientError::ModuleNotFound)),
+            };
+            let wasm = RawWasm::new(Some(module_id), bytes);
+            ctx.modules.compile(ctx.runtime.clone(), wasm).await??
         }
     };
 
@@ -300,26 +557,98 @@ where
     Ok(Ok(proc.id()))
 }
 
+// Looks up the bytes of a module that isn't compiled on this node yet
+async fn module_bytes<T, E>(
+    ctx: &ServerCtx<T, E>,
+    environment_id: u64,
+    module_id: u64,
+    response_node_id: u64,
+) -> Option<Vec<u8>>
+where
+    T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + Sync + 'static,
+    E: Environment + 'static,
+{
+    let control = &ctx.distributed.control;
+    let hash = match ctx.module_cache {
+        Some(_) => control.module_hash(module_id).await.unwrap_or(None),
+        None => None,
+    };
+    if let (Some(cache), Some(hash)) = (&ctx.module_cache, &hash) {
+        if let Some(bytes) = cache.get(hash).await {
+            return Some(bytes);
+        }
+    }
+    let bytes = match control.get_module(module_id, environment_id).await {
+        Ok(bytes) => bytes,
+        // Without a control server the module is fetched from the spawning node
+        Err(_) if control.reg().is_none() && response_node_id != 0 => {
+            let bytes = ctx
+                .node_client
+                .fetch_module(
+                    EnvironmentId(environment_id),
+                    NodeId(response_node_id),
+                    module_id,
+                )
+                .await
+                .ok()?;
+            control.store_module(module_id, bytes.clone());
+            bytes
+        }
+        Err(_) => return None,
+    };
+    if let (Some(cache), Some(hash)) = (&ctx.module_cache, &hash) {
+        if let Err(error) = cache.put(hash, bytes.clone()).await {
+            log::warn!("Failed to cache module {module_id}: {error}");
+        }
+    }
+    Some(bytes)
+}
+
 async fn handle_process_message<T, E>(
     ctx: ServerCtx<T, E>,
     environment_id: u64,
     process_id: u64,
     tag: Option<i64>,
     data: Vec<u8>,
+    headers: MessageHeaders,
 ) -> std::result::Result<(), ClientError>
 where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
     E: Environment,
 {
-    let env = ctx.envs.get(environment_id).await;
-    if let Some(env) = env {
-        if let Some(proc) = env.get_process(process_id) {
-            proc.send(Signal::Message(Message::Data(DataMessage::new_from_vec(
-                tag, data,
-            ))));
-        } else {
-            return Err(ClientError::ProcessNotFound);
+    let proc = get_process(&ctx, environment_id, process_id)
+        .await
+        .ok_or(ClientError::ProcessNotFound)?;
+    let mut message = DataMessage::new_from_vec(tag, data);
+    message.headers = headers;
+    // The process could have finished after it was looked up
+    if proc.try_send(Signal::Message(Message::Data(message))) {
+        Ok(())
+    } else {
+        Err(ClientError::ProcessNotFound)
+    }
+}
+
+// Waits until the process took enough messages out of its mailbox, or finished
+async fn wait_for_reader<E: Environment>(env: Arc<E>, process: Arc<dyn Process>) {
+    while let Some((len, reached_watermark)) = process.mailbox_pressure() {
+        if !reached_watermark && len < STREAM_MAILBOX_LIMIT {
+            break;
+        }
+        if env.get_process(process.id()).is_none() {
+            break;
         }
+        tokio::time::sleep(STREAM_POLL_INTERVAL).await;
     }
-    Ok(())
+}
+
+async fn get_process<T, E>(
+    ctx: &ServerCtx<T, E>,
+    environment_id: u64,
+    process_id: u64,
+) -> Option<Arc<dyn Process>>
+where
+    E: Environment,
+{
+    ctx.envs.get(environment_id).await?.get_process(process_id)
 }
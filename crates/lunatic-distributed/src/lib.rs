@@ -25,6 +25,7 @@ pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
     fn module_id(&self) -> u64;
     fn environment_id(&self) -> u64;
     fn can_spawn(&self) -> bool;
+    fn can_set_node_tags(&self) -> bool;
 }
 
 #[derive(Clone)]
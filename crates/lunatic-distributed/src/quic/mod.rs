@@ -1,2 +1,3 @@
 mod quin;
 pub use quin::*;
+pub use quinn::Endpoint;
@@ -1,4 +1,9 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
@@ -7,10 +12,12 @@ use lunatic_process::{env::Environment, state::ProcessState};
 use quinn::{ClientConfig, Connecting, Connection, ConnectionError, Endpoint, ServerConfig};
 use rustls::server::AllowAnyAuthenticatedClient;
 use rustls_pemfile::Item;
+use tokio::sync::watch;
 use wasmtime::ResourceLimiter;
 use x509_parser::{der_parser::oid, oid_registry::asn1_rs::Utf8String, prelude::FromDer};
 
 use crate::{
+    congestion::{Codec, COMPRESSED_FLAG},
     distributed::{self},
     CertAttrs, DistributedCtx,
 };
@@ -18,11 +25,30 @@ use crate::{
 #[derive(Clone)]
 pub struct Client {
     inner: Endpoint,
+    // Used for new connections, replaced when the certificate is rotated
+    config: Arc<RwLock<ClientConfig>>,
+    // Counts the certificate rotations, so that open connections can move to the new certificate
+    rotations: Arc<watch::Sender<u64>>,
 }
 
 impl Client {
     pub async fn _connect(&self, addr: SocketAddr, name: &str) -> Result<quinn::Connection> {
-        Ok(self.inner.connect(addr, name)?.await?)
+        let config = self.config.read().unwrap().clone();
+        Ok(self.inner.connect_with(config, addr, name)?.await?)
+    }
+
+    /// Uses the new certificate for all connections opened from now on and notifies the
+    /// subscribers of `rotations`.
+    pub fn set_certificate(&self, ca_cert: &str, cert: &str, key: &str) -> Result<()> {
+        *self.config.write().unwrap() = client_config(ca_cert, cert, key)?;
+        self.rotations.send_modify(|rotations| *rotations += 1);
+        Ok(())
+    }
+
+    /// Changes every time the certificate is rotated. Connections opened before keep working
+    /// with the old certificate until it expires.
+    pub fn rotations(&self) -> watch::Receiver<u64> {
+        self.rotations.subscribe()
     }
 
     pub async fn try_connect(
@@ -56,7 +82,7 @@ fn get_cert_attrs(conn: &Connection) -> Result<CertAttrs> {
     if peer_identity.len() != 1 {
         return Err(anyhow!("More than one identity certificate detected."));
     }
-    let cert = peer_identity.get(0).unwrap();
+    let cert = peer_identity.first().unwrap();
     let (_rem, x509) = x509_parser::certificate::X509Certificate::from_der(&cert.0)?;
     let oid = oid!(2.5.29 .9);
     let ext = x509
@@ -66,7 +92,34 @@ fn get_cert_attrs(conn: &Connection) -> Result<CertAttrs> {
     Ok(serde_json::from_str(&value.string())?)
 }
 
+/// Returns the time range in which the PEM encoded certificate is valid.
+pub fn certificate_validity(cert: &str) -> Result<(SystemTime, SystemTime)> {
+    let mut cert = cert.as_bytes();
+    let cert = match rustls_pemfile::read_one(&mut cert)? {
+        Some(Item::X509Certificate(cert)) => Ok(cert),
+        _ => Err(anyhow!("Not a valid certificate")),
+    }?;
+    let (_rem, x509) = x509_parser::certificate::X509Certificate::from_der(&cert)?;
+    let validity = x509.validity();
+    let time =
+        |timestamp: i64| SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64);
+    Ok((
+        time(validity.not_before.timestamp()),
+        time(validity.not_after.timestamp()),
+    ))
+}
+
 pub fn new_quic_client(ca_cert: &str, cert: &str, key: &str) -> Result<Client> {
+    let client_config = client_config(ca_cert, cert, key)?;
+    let endpoint = Endpoint::client("[::]:0".parse().unwrap())?;
+    Ok(Client {
+        inner: endpoint,
+        config: Arc::new(RwLock::new(client_config)),
+        rotations: Arc::new(watch::channel(0).0),
+    })
+}
+
+fn client_config(ca_cert: &str, cert: &str, key: &str) -> Result<ClientConfig> {
     let mut ca_cert = ca_cert.as_bytes();
     let ca_cert = rustls_pemfile::read_one(&mut ca_cert)?.unwrap();
     let ca_cert = match ca_cert {
@@ -95,10 +148,7 @@ pub fn new_quic_client(ca_cert: &str, cert: &str, key: &str) -> Result<Client> {
         .with_root_certificates(roots)
         .with_client_auth_cert(cert, pk)?;
 
-    let client_config = ClientConfig::new(Arc::new(client_crypto));
-    let mut endpoint = Endpoint::client("[::]:0".parse().unwrap())?;
-    endpoint.set_default_client_config(client_config);
-    Ok(Client { inner: endpoint })
+    Ok(ClientConfig::new(Arc::new(client_crypto)))
 }
 
 pub fn new_quic_server(
@@ -107,6 +157,22 @@ pub fn new_quic_server(
     key: &str,
     ca_cert: &str,
 ) -> Result<Endpoint> {
+    let server_config = server_config(certs, key, ca_cert)?;
+    Ok(quinn::Endpoint::server(server_config, addr)?)
+}
+
+/// Accepts new connections with the new certificate, connections accepted before are kept.
+pub fn set_server_certificate(
+    quic_server: &Endpoint,
+    certs: Vec<String>,
+    key: &str,
+    ca_cert: &str,
+) -> Result<()> {
+    quic_server.set_server_config(Some(server_config(certs, key, ca_cert)?));
+    Ok(())
+}
+
+fn server_config(certs: Vec<String>, key: &str, ca_cert: &str) -> Result<ServerConfig> {
     let mut ca_cert = ca_cert.as_bytes();
     let ca_cert = rustls_pemfile::read_one(&mut ca_cert)?.unwrap();
     let ca_cert = match ca_cert {
@@ -145,8 +211,7 @@ pub fn new_quic_server(
     Arc::get_mut(&mut server_config.transport)
         .unwrap()
         .keep_alive_interval(Some(Duration::from_millis(100)));
-
-    Ok(quinn::Endpoint::server(server_config, addr)?)
+    Ok(server_config)
 }
 
 pub async fn handle_node_server<T, E>(
@@ -163,9 +228,17 @@ where
     Err(anyhow!("Node server exited"))
 }
 
+/// Environments a connected node may use, read from the attributes of its certificate. `None`
+/// if the node is privileged and can use all environments.
 pub struct NodeEnvPermission(pub Option<HashSet<u64>>);
 
 impl NodeEnvPermission {
+    pub fn allows(&self, env_id: u64) -> bool {
+        self.0
+            .as_ref()
+            .is_none_or(|allowed_envs| allowed_envs.contains(&env_id))
+    }
+
     fn new(cert_attrs: CertAttrs) -> Self {
         let some_set: Option<HashSet<u64>> = if cert_attrs.is_privileged {
             None
@@ -277,12 +350,28 @@ async fn read_next_stream_chunk(recv: &mut quinn::RecvStream) -> Result<Chunk> {
     let message_id = u64::from_le_bytes(message_id);
     let message_size = u32::from_le_bytes(message_size) as usize;
     let chunk_id = u64::from_le_bytes(chunk_id);
-    let chunk_size = u32::from_le_bytes(chunk_size) as usize;
+    let chunk_size = u32::from_le_bytes(chunk_size);
+    let codec = if chunk_size & COMPRESSED_FLAG != 0 {
+        let mut codec = [0u8; 1];
+        recv.read_exact(&mut codec)
+            .await
+            .map_err(|e| anyhow!("{e} failed to read header codec"))?;
+        Codec::from_u8(codec[0])?
+    } else {
+        Codec::None
+    };
+    let chunk_size = (chunk_size & !COMPRESSED_FLAG) as usize;
     // Read chunk data
     let mut data = vec![0u8; chunk_size];
     recv.read_exact(&mut data)
         .await
         .map_err(|e| anyhow!("{e} failed to read message body"))?;
+    let data = match codec {
+        Codec::None => data,
+        codec => codec
+            .decompress(&data, message_size)
+            .map_err(|e| anyhow!("{e} failed to decompress message body"))?,
+    };
     log::trace!("read message_id={message_id} chunk_id={chunk_id}");
     Ok(Chunk {
         message_id,
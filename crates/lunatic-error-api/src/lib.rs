@@ -1,6 +1,6 @@
 use anyhow::Result;
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use wasmtime::{Caller, Linker};
 
 pub type ErrorResource = HashMapId<anyhow::Error>;
@@ -12,6 +12,7 @@ pub trait ErrorCtx {
 
 // Register the error APIs to the linker
 pub fn register<T: ErrorCtx + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::error", "string_size", string_size)?;
     linker.func_wrap("lunatic::error", "to_string", to_string)?;
     linker.func_wrap("lunatic::error", "drop", drop)?;
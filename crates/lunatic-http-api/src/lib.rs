@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hash_map_id::HashMapId;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_error_api::ErrorCtx;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION, TRANSFER_ENCODING, WWW_AUTHENTICATE,
+};
+use reqwest::{redirect, Client, Method, Response, StatusCode, Url};
+use tokio::sync::Mutex;
+use tokio::time::{timeout_at, Instant};
+use wasmtime::{Caller, Linker};
+
+// Same limit as the default redirect policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+// Clients pinned to addresses are dropped once there are more, DNS changes create new ones
+const MAX_PINNED_CLIENTS: usize = 256;
+
+/// A response to a HTTP request. The status and headers are available as soon as the response
+/// arrives, the body is streamed from the connection while it's read.
+pub struct HttpResponse {
+    status: u16,
+    // Headers serialized as `name: value\r\n` lines.
+    headers: Vec<u8>,
+    // The response and the part of the last received chunk that didn't fit into the guest buffer.
+    body: Mutex<(Response, Bytes)>,
+    // The timeout of the request also applies to reading the body
+    deadline: Option<Instant>,
+}
+
+impl HttpResponse {
+    fn new(response: Response, deadline: Option<Instant>) -> Self {
+        let mut headers = Vec::new();
+        for (name, value) in response.headers() {
+            headers.extend_from_slice(name.as_str().as_bytes());
+            headers.extend_from_slice(b": ");
+            headers.extend_from_slice(value.as_bytes());
+            headers.extend_from_slice(b"\r\n");
+        }
+        HttpResponse {
+            status: response.status().as_u16(),
+            headers,
+            body: Mutex::new((response, Bytes::new())),
+            deadline,
+        }
+    }
+}
+
+pub type HttpResponseResources = HashMapId<Arc<HttpResponse>>;
+
+pub trait HttpCtx {
+    fn http_response_resources(&self) -> &HttpResponseResources;
+    fn http_response_resources_mut(&mut self) -> &mut HttpResponseResources;
+    // Checks the network policy of the process, the same as for other connections.
+    fn can_access_network(&self, host: &str, port: Option<u16>) -> Result<(), String>;
+    // Checks an address the host name was resolved to against the network policy.
+    fn can_access_address(&self, host: &str, ip: IpAddr, port: u16) -> Result<(), String>;
+}
+
+// Redirects are followed by `request`, so that the network policy is checked for each of them.
+fn client_builder() -> reqwest::ClientBuilder {
+    Client::builder().redirect(redirect::Policy::none())
+}
+
+// Returns the client for requests to the host that may only connect to the addresses.
+//
+// The client resolves the host itself, so each set of addresses allowed by the network policy
+// gets its own client that can't connect anywhere else. Processes share the clients, so that
+// connections to the same host are pooled and reused.
+fn client(host: &str, addresses: Option<&[IpAddr]>) -> Result<Client> {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    type PinnedClients = std::sync::Mutex<HashMap<(String, Vec<IpAddr>), Client>>;
+    static PINNED_CLIENTS: OnceLock<PinnedClients> = OnceLock::new();
+
+    let addresses = match addresses {
+        Some(addresses) => addresses,
+        // IP addresses don't need to be resolved
+        None => {
+            if let Some(client) = CLIENT.get() {
+                return Ok(client.clone());
+            }
+            let client = client_builder().build()?;
+            return Ok(CLIENT.get_or_init(|| client).clone());
+        }
+    };
+    let key = (host.to_string(), addresses.to_vec());
+    let mut clients = PINNED_CLIENTS.get_or_init(Default::default).lock().unwrap();
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    if clients.len() >= MAX_PINNED_CLIENTS {
+        clients.clear();
+    }
+    // The port is ignored, the client uses the port of the URL
+    let addrs: Vec<_> = addresses.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+    let client = client_builder().resolve_to_addrs(host, &addrs).build()?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+// Checks the URL against the network policy of the process and returns the client to send the
+// request with. Host names are resolved here and only the addresses allowed by the policy are
+// used for the connection.
+//
+// Takes the caller mutably, so that the future stays `Send` without requiring `T: Sync`.
+async fn checked_client<T: HttpCtx>(caller: &mut Caller<'_, T>, url: &Url) -> Result<Client> {
+    let host = url.host_str().unwrap_or_default();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("URL '{url}' has no port"))?;
+    caller
+        .data()
+        .can_access_network(host, Some(port))
+        .map_err(|error| anyhow!(error))?;
+    if host.parse::<IpAddr>().is_ok() {
+        return client(host, None);
+    }
+
+    let mut addresses: Vec<IpAddr> = tokio::net::lookup_host((host, port))
+        .await?
+        .map(|addr| addr.ip())
+        .filter(|ip| caller.data().can_access_address(host, *ip, port).is_ok())
+        .collect();
+    if addresses.is_empty() {
+        return Err(anyhow!(
+            "Network access to '{host}:{port}' denied for all resolved addresses"
+        ));
+    }
+    addresses.sort();
+    addresses.dedup();
+    client(host, Some(&addresses))
+}
+
+// Changes the request to follow the redirect in the response, the same way reqwest does it.
+//
+// Returns the URL of the redirect, or `None` if the response is not a redirect.
+fn follow_redirect(
+    response: &Response,
+    method: &mut Method,
+    headers: &mut HeaderMap,
+    body: &mut Bytes,
+) -> Result<Option<Url>> {
+    match response.status() {
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => {
+            *body = Bytes::new();
+            for header in [
+                TRANSFER_ENCODING,
+                CONTENT_ENCODING,
+                CONTENT_TYPE,
+                CONTENT_LENGTH,
+            ] {
+                headers.remove(header);
+            }
+            if *method != Method::GET && *method != Method::HEAD {
+                *method = Method::GET;
+            }
+        }
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => {}
+        _ => return Ok(None),
+    }
+    let location = match response.headers().get(LOCATION) {
+        Some(location) => location.to_str()?,
+        None => return Ok(None),
+    };
+    let next = response.url().join(location)?;
+    let previous = response.url();
+    if next.host_str() != previous.host_str()
+        || next.port_or_known_default() != previous.port_or_known_default()
+    {
+        for header in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, WWW_AUTHENTICATE] {
+            headers.remove(header);
+        }
+        headers.remove("cookie2");
+    }
+    Ok(Some(next))
+}
+
+// Register the HTTP APIs to the linker
+pub fn register<T: HttpCtx + ErrorCtx + Send + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap10_async("lunatic::http", "request", request)?;
+    linker.func_wrap("lunatic::http", "response_status", response_status)?;
+    linker.func_wrap("lunatic::http", "response_headers", response_headers)?;
+    linker.func_wrap4_async("lunatic::http", "response_read", response_read)?;
+    linker.func_wrap("lunatic::http", "drop_response", drop_response)?;
+    Ok(())
+}
+
+// Parses headers in the `name: value\r\n` format.
+fn parse_headers(headers: &[u8]) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for line in headers.split(|&byte| byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let colon = line
+            .iter()
+            .position(|&byte| byte == b':')
+            .ok_or_else(|| anyhow!("Header is missing a `:` separator"))?;
+        let name = HeaderName::from_bytes(&line[..colon])?;
+        let value = &line[colon + 1..];
+        let value_start = value
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(value.len());
+        let value = HeaderValue::from_bytes(&value[value_start..])?;
+        header_map.append(name, value);
+    }
+    Ok(header_map)
+}
+
+// Sends a HTTP request and waits for the response headers. The body of the response can be read
+// with `response_read`.
+//
+// **headers_ptr** points to the request headers in the `name: value\r\n` format. HTTP/2 is used
+// if the server supports it, connections are pooled between all processes. Up to 10 redirects
+// are followed. The host of the URL and of each redirect, as well as the addresses they resolve
+// to, must be allowed by the network policy of the process.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027. The timeout also applies to reading the body of the response.
+//
+// Returns:
+// * 0 on success - The ID of the response is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the method or URL is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn request<T: HttpCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    method_str_ptr: u32,
+    method_str_len: u32,
+    url_str_ptr: u32,
+    url_str_len: u32,
+    headers_ptr: u32,
+    headers_len: u32,
+    body_ptr: u32,
+    body_len: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let memory_slice = memory.data(&caller);
+        let method = memory_slice
+            .get(method_str_ptr as usize..(method_str_ptr + method_str_len) as usize)
+            .or_trap("lunatic::http::request")?;
+        let method = std::str::from_utf8(method).or_trap("lunatic::http::request")?;
+        let url = memory_slice
+            .get(url_str_ptr as usize..(url_str_ptr + url_str_len) as usize)
+            .or_trap("lunatic::http::request")?;
+        let url = std::str::from_utf8(url).or_trap("lunatic::http::request")?;
+        let headers = memory_slice
+            .get(headers_ptr as usize..(headers_ptr + headers_len) as usize)
+            .or_trap("lunatic::http::request")?;
+        let body = memory_slice
+            .get(body_ptr as usize..(body_ptr + body_len) as usize)
+            .or_trap("lunatic::http::request")?
+            .to_vec();
+
+        let request = Method::from_str(method)
+            .map_err(anyhow::Error::from)
+            .and_then(|method| Ok((method, Url::parse(url)?, parse_headers(headers)?)));
+        let deadline = match timeout_duration {
+            u64::MAX => None,
+            t => Some(Instant::now() + Duration::from_millis(t)),
+        };
+
+        let caller_mut = &mut caller;
+        let send = async move {
+            let (mut method, mut url, mut headers) = request?;
+            let mut body = Bytes::from(body);
+            for _ in 0..=MAX_REDIRECTS {
+                let response = checked_client(caller_mut, &url)
+                    .await?
+                    .request(method.clone(), url)
+                    .headers(headers.clone())
+                    .body(body.clone())
+                    .send()
+                    .await?;
+                match follow_redirect(&response, &mut method, &mut headers, &mut body)? {
+                    Some(next) => url = next,
+                    None => return Ok(response),
+                }
+            }
+            Err(anyhow!("Too many redirects"))
+        };
+        let response = match deadline {
+            // Without timeout
+            None => send.await,
+            // With timeout
+            Some(deadline) => match timeout_at(deadline, send).await {
+                Ok(response) => response,
+                // Call timed out
+                Err(_) => return Ok(9027),
+            },
+        };
+
+        let (response_or_error_id, result) = match response {
+            Ok(response) => (
+                caller
+                    .data_mut()
+                    .http_response_resources_mut()
+                    .add(Arc::new(HttpResponse::new(response, deadline))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &response_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::http::request")?;
+        Ok(result)
+    })
+}
+
+// Returns the status code of the response.
+//
+// Traps:
+// * If the response ID doesn't exist.
+fn response_status<T: HttpCtx>(caller: Caller<T>, response_id: u64) -> Result<u32> {
+    let response = caller
+        .data()
+        .http_response_resources()
+        .get(response_id)
+        .or_trap("lunatic::http::response_status")?;
+    Ok(response.status as u32)
+}
+
+// Writes the headers of the response in the `name: value\r\n` format to **buf_ptr**. If the buffer
+// is too small, only **buf_len** bytes are written.
+//
+// Returns the size of all headers in bytes.
+//
+// Traps:
+// * If the response ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn response_headers<T: HttpCtx>(
+    mut caller: Caller<T>,
+    response_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u32> {
+    let response = caller
+        .data()
+        .http_response_resources()
+        .get(response_id)
+        .or_trap("lunatic::http::response_headers")?
+        .clone();
+    let len = response.headers.len().min(buf_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, buf_ptr as usize, &response.headers[..len])
+        .or_trap("lunatic::http::response_headers")?;
+    Ok(response.headers.len() as u32)
+}
+
+// Reads the next part of the response body into the buffer. Once the whole body was read, 0
+// bytes are returned.
+//
+// Returns:
+// * 0 on success - The number of bytes read is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+// * 9027 if the timeout of the request expired
+//
+// Traps:
+// * If the response ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn response_read<T: HttpCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    response_id: u64,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let response = caller
+            .data()
+            .http_response_resources()
+            .get(response_id)
+            .or_trap("lunatic::http::response_read")?
+            .clone();
+        let response_deadline = response.deadline;
+        let mut body = response.body.lock().await;
+        let (response, remaining) = &mut *body;
+
+        let chunk = if remaining.is_empty() {
+            let chunk = response.chunk();
+            match response_deadline {
+                None => chunk.await,
+                Some(deadline) => match timeout_at(deadline, chunk).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(9027),
+                },
+            }
+            .map(Option::unwrap_or_default)
+        } else {
+            Ok(std::mem::take(remaining))
+        };
+
+        let (opaque, return_) = match chunk {
+            Ok(mut chunk) => {
+                let len = chunk.len().min(buffer_len as usize);
+                *remaining = chunk.split_off(len);
+                let memory = get_memory(&mut caller)?;
+                memory
+                    .write(&mut caller, buffer_ptr as usize, &chunk)
+                    .or_trap("lunatic::http::response_read")?;
+                (len as u64, 0)
+            }
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::http::response_read")?;
+        Ok(return_)
+    })
+}
+
+// Drops the response resource, closing the connection if the body was not read completely.
+//
+// Traps:
+// * If the response ID doesn't exist.
+fn drop_response<T: HttpCtx>(mut caller: Caller<T>, response_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .http_response_resources_mut()
+        .remove(response_id)
+        .or_trap("lunatic::http::drop_response")?;
+    Ok(())
+}
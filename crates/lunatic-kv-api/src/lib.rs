@@ -0,0 +1,273 @@
+pub mod store;
+
+use std::future::Future;
+
+use anyhow::Result;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_process::state::ProcessState;
+use lunatic_process_api::ProcessCtx;
+use wasmtime::{Caller, Linker};
+
+use crate::store::{KvStore, QuotaExceeded};
+
+/// The compare-and-swap only succeeds if there is no entry with the key.
+pub const CAS_EXPECT_MISSING: u32 = 1;
+/// The compare-and-swap deletes the entry instead of storing a new value.
+pub const CAS_DELETE: u32 = 2;
+
+// Register the key-value store APIs to the linker
+pub fn register<T>(linker: &mut Linker<T>) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync + 'static,
+{
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap4_async("lunatic::kv", "get", get)?;
+    linker.func_wrap4_async("lunatic::kv", "put", put)?;
+    linker.func_wrap2_async("lunatic::kv", "delete", delete)?;
+    linker.func_wrap7_async("lunatic::kv", "cas", cas)?;
+    linker.func_wrap7_async("lunatic::kv", "scan", scan)?;
+    Ok(())
+}
+
+// Runs the operation on the store of the process' environment. Persistent stores write to disk,
+// so their operations run on the blocking thread pool.
+async fn with_store<R, F>(environment_id: u64, f: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce(&KvStore) -> R + Send + 'static,
+{
+    if store::is_persistent() {
+        tokio::task::spawn_blocking(move || Ok(f(&*store::get(environment_id)?)))
+            .await
+            .or_trap("lunatic::kv")?
+    } else {
+        Ok(f(&*store::get(environment_id)?))
+    }
+}
+
+fn read_guest(caller: &mut Caller<'_, impl Send>, ptr: u32, len: u32) -> Result<Vec<u8>> {
+    let memory = get_memory(caller)?;
+    let data = memory
+        .data(&caller)
+        .get(ptr as usize..(ptr as usize + len as usize))
+        .or_trap("lunatic::kv")?;
+    Ok(data.to_vec())
+}
+
+// Copies the value stored under **key** in the environment's store to **value_ptr**.
+//
+// At most **value_len** bytes are copied. Calling it with a **value_len** of 0 can be used to
+// query the size of the value first.
+//
+// Returns:
+// * The size of the stored value in bytes.
+// * -1 if nothing is stored under **key**.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn get<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Box<dyn Future<Output = Result<i64>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync,
+{
+    Box::new(async move {
+        let environment_id = caller.data().environment().id();
+        let key = read_guest(&mut caller, key_ptr, key_len)?;
+        let value = match with_store(environment_id, move |store| store.get(&key)).await? {
+            Some(value) => value,
+            None => return Ok(-1),
+        };
+        let copy_len = value.len().min(value_len as usize);
+        let memory = get_memory(&mut caller)?;
+        memory
+            .data_mut(&mut caller)
+            .get_mut(value_ptr as usize..(value_ptr as usize + copy_len))
+            .or_trap("lunatic::kv::get")?
+            .copy_from_slice(&value[..copy_len]);
+        Ok(value.len() as i64)
+    })
+}
+
+// Stores the value under **key** in the environment's store, replacing the previous value.
+//
+// Returns:
+// * 0 on success.
+// * 1 if the value couldn't be persisted.
+// * 2 if the store would grow past its quota.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn put<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync,
+{
+    Box::new(async move {
+        let environment_id = caller.data().environment().id();
+        let key = read_guest(&mut caller, key_ptr, key_len)?;
+        let value = read_guest(&mut caller, value_ptr, value_len)?;
+        match with_store(environment_id, move |store| store.put(&key, &value)).await? {
+            Ok(()) => Ok(0),
+            Err(error) if error.is::<QuotaExceeded>() => Ok(2),
+            Err(error) => {
+                log::warn!("lunatic::kv::put failed: {error}");
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Removes the value stored under **key** from the environment's store.
+//
+// Returns:
+// * 0 if the value was removed.
+// * 1 if nothing is stored under **key**.
+// * 2 if the removal couldn't be persisted.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn delete<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync,
+{
+    Box::new(async move {
+        let environment_id = caller.data().environment().id();
+        let key = read_guest(&mut caller, key_ptr, key_len)?;
+        match with_store(environment_id, move |store| store.delete(&key)).await? {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(1),
+            Err(error) => {
+                log::warn!("lunatic::kv::delete failed: {error}");
+                Ok(2)
+            }
+        }
+    })
+}
+
+// Atomically replaces the value stored under **key** with the value at **new_ptr**, if the
+// current value equals the one at **expected_ptr**.
+//
+// The **flags** change what is compared and stored:
+// * `CAS_EXPECT_MISSING` (1): there must be no value stored under **key**, the expected value is
+//   ignored.
+// * `CAS_DELETE` (2): the value is removed instead, the new value is ignored.
+//
+// Returns:
+// * 0 if the value was replaced.
+// * 1 if the current value didn't match.
+// * 2 if the change couldn't be persisted.
+// * 3 if the store would grow past its quota.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn cas<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    expected_ptr: u32,
+    expected_len: u32,
+    new_ptr: u32,
+    new_len: u32,
+    flags: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync,
+{
+    Box::new(async move {
+        let environment_id = caller.data().environment().id();
+        let key = read_guest(&mut caller, key_ptr, key_len)?;
+        let expected = match flags & CAS_EXPECT_MISSING {
+            0 => Some(read_guest(&mut caller, expected_ptr, expected_len)?),
+            _ => None,
+        };
+        let new = match flags & CAS_DELETE {
+            0 => Some(read_guest(&mut caller, new_ptr, new_len)?),
+            _ => None,
+        };
+        let swapped = with_store(environment_id, move |store| {
+            store.compare_and_swap(&key, expected.as_deref(), new.as_deref())
+        })
+        .await?;
+        match swapped {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(1),
+            Err(error) if error.is::<QuotaExceeded>() => Ok(3),
+            Err(error) => {
+                log::warn!("lunatic::kv::cas failed: {error}");
+                Ok(2)
+            }
+        }
+    })
+}
+
+// Looks up to **limit** entries of the environment's store in the order of their keys, starting
+// with the key at **start_ptr** and ending before the key at **end_ptr**. An **end_len** of 0
+// scans until the last entry, an end that is not after the start returns no entries.
+//
+// The entries are encoded one after the other, each as the length of the key (u32 little endian),
+// the key, the length of the value (u32 little endian) and the value. At most **buf_len** bytes
+// are copied to **buf_ptr**, if the buffer is too small the scan can be repeated with a bigger one.
+//
+// Returns:
+// * The size of the encoded entries in bytes.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn scan<T>(
+    mut caller: Caller<T>,
+    start_ptr: u32,
+    start_len: u32,
+    end_ptr: u32,
+    end_len: u32,
+    limit: u32,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send + Sync,
+{
+    Box::new(async move {
+        let environment_id = caller.data().environment().id();
+        let start = read_guest(&mut caller, start_ptr, start_len)?;
+        let end = match end_len {
+            0 => None,
+            _ => Some(read_guest(&mut caller, end_ptr, end_len)?),
+        };
+        let entries = with_store(environment_id, move |store| {
+            store.scan(&start, end.as_deref(), limit as usize)
+        })
+        .await?;
+
+        let mut encoded = Vec::new();
+        for (key, value) in entries {
+            encoded.extend((key.len() as u32).to_le_bytes());
+            encoded.extend(key);
+            encoded.extend((value.len() as u32).to_le_bytes());
+            encoded.extend(value);
+        }
+        let copy_len = encoded.len().min(buf_len as usize);
+        let memory = get_memory(&mut caller)?;
+        memory
+            .data_mut(&mut caller)
+            .get_mut(buf_ptr as usize..(buf_ptr as usize + copy_len))
+            .or_trap("lunatic::kv::scan")?
+            .copy_from_slice(&encoded[..copy_len]);
+        Ok(encoded.len() as u64)
+    })
+}
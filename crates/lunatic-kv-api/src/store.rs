@@ -0,0 +1,348 @@
+//! Key-value stores shared by all processes of an environment.
+//!
+//! Stores live in memory. Once persistence is configured with [`configure`], each store also
+//! writes through to the sqlite database `<dir>/<environment_id>.sqlite` and is loaded from it the
+//! first time it's used, so the entries survive crashes and restarts of the runtime.
+//!
+//! A [`Quota`] set with [`set_quota`] limits how many entries and bytes each store can hold.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use sqlite::{Connection, State};
+
+static DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+static STORES: OnceLock<Mutex<HashMap<u64, Arc<KvStore>>>> = OnceLock::new();
+static QUOTA: OnceLock<Quota> = OnceLock::new();
+
+/// Limits of a single store. `None` leaves a limit unrestricted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    /// Maximum number of entries.
+    pub max_entries: Option<usize>,
+    /// Maximum size of all keys and values in bytes.
+    pub max_bytes: Option<u64>,
+}
+
+/// Error returned by writes that would grow a store past its [`Quota`].
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key-value store quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Persists the stores of all environments in the directory, creating it if it doesn't exist.
+///
+/// Can only be called once and before the first store is used.
+pub fn configure(dir: impl Into<PathBuf>) -> Result<()> {
+    let dir = dir.into();
+    std::fs::create_dir_all(&dir).with_context(|| {
+        format!(
+            "Failed to create key-value store directory '{}'",
+            dir.display()
+        )
+    })?;
+    DIRECTORY
+        .set(dir)
+        .map_err(|_| anyhow!("Key-value store persistence is already configured"))
+}
+
+/// Limits the stores of all environments to the quota.
+///
+/// Can only be called once and before the first store is used.
+pub fn set_quota(quota: Quota) -> Result<()> {
+    QUOTA
+        .set(quota)
+        .map_err(|_| anyhow!("Key-value store quota is already configured"))
+}
+
+/// Returns true if the stores write to disk.
+pub fn is_persistent() -> bool {
+    DIRECTORY.get().is_some()
+}
+
+/// Returns the store of the environment, opening it on first use.
+pub fn get(environment_id: u64) -> Result<Arc<KvStore>> {
+    let mut stores = STORES.get_or_init(Default::default).lock().unwrap();
+    if let Some(store) = stores.get(&environment_id) {
+        return Ok(store.clone());
+    }
+    let store = match DIRECTORY.get() {
+        Some(dir) => KvStore::open(&dir.join(format!("{environment_id}.sqlite")))?,
+        None => KvStore::default(),
+    };
+    let store = Arc::new(store.with_quota(QUOTA.get().copied().unwrap_or_default()));
+    stores.insert(environment_id, store.clone());
+    Ok(store)
+}
+
+/// Entries ordered by key, optionally backed by a sqlite database.
+#[derive(Default)]
+pub struct KvStore {
+    entries: RwLock<Entries>,
+    db: Option<Mutex<Connection>>,
+    quota: Quota,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Size of all keys and values in bytes
+    size: u64,
+}
+
+impl Entries {
+    // Fails if replacing the entry of the key with `value` would grow the store past the quota.
+    // Changes that shrink a store which is already over its quota, e.g. because the quota was
+    // lowered after the entries were persisted, are still allowed.
+    fn check_quota(&self, quota: &Quota, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let old = self.map.get(key).map(|old| (key.len() + old.len()) as u64);
+        let new = value.map(|value| (key.len() + value.len()) as u64);
+        if let Some(max_entries) = quota.max_entries {
+            if old.is_none() && new.is_some() && self.map.len() >= max_entries {
+                return Err(QuotaExceeded.into());
+            }
+        }
+        if let Some(max_bytes) = quota.max_bytes {
+            let size = self.size - old.unwrap_or(0) + new.unwrap_or(0);
+            if size > max_bytes && size > self.size {
+                return Err(QuotaExceeded.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.size += (key.len() + value.len()) as u64;
+        if let Some(old) = self.map.insert(key.to_vec(), value.to_vec()) {
+            self.size -= (key.len() + old.len()) as u64;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(old) = self.map.remove(key) {
+            self.size -= (key.len() + old.len()) as u64;
+        }
+    }
+}
+
+impl KvStore {
+    /// Opens the database at `path` and loads all entries from it.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sqlite::open(path)
+            .with_context(|| format!("Failed to open key-value store '{}'", path.display()))?;
+        db.execute("CREATE TABLE IF NOT EXISTS kv (key BLOB PRIMARY KEY, value BLOB NOT NULL)")?;
+        let mut entries = Entries::default();
+        let mut statement = db.prepare("SELECT key, value FROM kv")?;
+        while let State::Row = statement.next()? {
+            entries.insert(
+                &statement.read::<Vec<u8>, usize>(0)?,
+                &statement.read::<Vec<u8>, usize>(1)?,
+            );
+        }
+        drop(statement);
+        Ok(Self {
+            entries: RwLock::new(entries),
+            db: Some(Mutex::new(db)),
+            quota: Quota::default(),
+        })
+    }
+
+    /// Rejects writes that would grow the store past the quota with [`QuotaExceeded`].
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.read().unwrap().map.get(key).cloned()
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        entries.check_quota(&self.quota, key, Some(value))?;
+        self.persist(key, Some(value))?;
+        entries.insert(key, value);
+        Ok(())
+    }
+
+    /// Returns false if there was no entry with the key.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        if !entries.map.contains_key(key) {
+            return Ok(false);
+        }
+        self.persist(key, None)?;
+        entries.remove(key);
+        Ok(true)
+    }
+
+    /// Replaces the value of the key with `new` if the current value is `expected`. A value of
+    /// `None` stands for a missing entry, so entries can be created and deleted the same way.
+    ///
+    /// Returns false if the current value didn't match, the quota is only checked if it matched.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        if entries.map.get(key).map(Vec::as_slice) != expected {
+            return Ok(false);
+        }
+        entries.check_quota(&self.quota, key, new)?;
+        self.persist(key, new)?;
+        match new {
+            Some(new) => entries.insert(key, new),
+            None => entries.remove(key),
+        }
+        Ok(true)
+    }
+
+    /// Returns up to `limit` entries with keys from `start` up to, but excluding, `end`.
+    ///
+    /// The range is empty if `end` is not after `start`.
+    pub fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let end = match end {
+            // `BTreeMap::range` panics on inverted ranges
+            Some(end) if end <= start => return Vec::new(),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.entries
+            .read()
+            .unwrap()
+            .map
+            .range::<[u8], _>((Bound::Included(start), end))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    // Writes the change to the database before it's applied in memory, so that entries are never
+    // visible before they are stored
+    fn persist(&self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let db = match &self.db {
+            Some(db) => db.lock().unwrap(),
+            None => return Ok(()),
+        };
+        let mut statement = match value {
+            Some(value) => {
+                let mut statement =
+                    db.prepare("INSERT OR REPLACE INTO kv (key, value) VALUES (?, ?)")?;
+                statement.bind((2, value))?;
+                statement
+            }
+            None => db.prepare("DELETE FROM kv WHERE key = ?")?,
+        };
+        statement.bind((1, key))?;
+        while let State::Row = statement.next()? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_and_swap_creates_and_deletes_entries() {
+        let store = KvStore::default();
+        assert!(store.compare_and_swap(b"a", None, Some(&b"1"[..])).unwrap());
+        assert!(!store.compare_and_swap(b"a", None, Some(&b"2"[..])).unwrap());
+        assert!(store.compare_and_swap(b"a", Some(&b"1"[..]), None).unwrap());
+        assert_eq!(store.get(b"a"), None);
+    }
+
+    #[test]
+    fn scan_with_end_before_start_is_empty() {
+        let store = KvStore::default();
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"2").unwrap();
+        assert!(store.scan(b"b", Some(&b"a"[..]), 10).is_empty());
+        assert!(store.scan(b"a", Some(&b"a"[..]), 10).is_empty());
+        assert_eq!(store.scan(b"a", Some(&b"b"[..]), 10).len(), 1);
+    }
+
+    #[test]
+    fn writes_past_the_quota_are_rejected() {
+        let store = KvStore::default().with_quota(Quota {
+            max_entries: Some(2),
+            max_bytes: Some(8),
+        });
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"2").unwrap();
+        let error = store.put(b"c", b"3").unwrap_err();
+        assert!(error.is::<QuotaExceeded>());
+        // Replacing an entry doesn't count against the entry limit, only against the size
+        store.put(b"a", b"1234").unwrap();
+        assert!(store
+            .put(b"a", b"12345678")
+            .unwrap_err()
+            .is::<QuotaExceeded>());
+        assert!(store
+            .compare_and_swap(b"b", Some(&b"2"[..]), Some(&b"12345"[..]))
+            .unwrap_err()
+            .is::<QuotaExceeded>());
+        assert_eq!(store.get(b"b"), Some(b"2".to_vec()));
+        // Deleting frees up space again
+        store.delete(b"a").unwrap();
+        store.put(b"c", b"123").unwrap();
+    }
+
+    #[test]
+    fn stores_over_the_quota_can_shrink() {
+        let path =
+            std::env::temp_dir().join(format!("lunatic-kv-quota-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = KvStore::open(&path).unwrap();
+        store.put(b"a", b"1234").unwrap();
+        store.put(b"b", b"1234").unwrap();
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap().with_quota(Quota {
+            max_entries: Some(1),
+            max_bytes: Some(4),
+        });
+        assert!(store.put(b"c", b"1").unwrap_err().is::<QuotaExceeded>());
+        assert!(store.put(b"a", b"12345").unwrap_err().is::<QuotaExceeded>());
+        store.put(b"a", b"12").unwrap();
+        assert!(store.delete(b"b").unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn entries_are_loaded_from_disk() {
+        let path = std::env::temp_dir().join(format!("lunatic-kv-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = KvStore::open(&path).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"2").unwrap();
+        store.put(b"c", b"3").unwrap();
+        store.delete(b"c").unwrap();
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(
+            store.scan(b"", None, 10),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(store.scan(b"b", Some(&b"c"[..]), 10).len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use log::Level;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_process::{config::ProcessConfig, state::ProcessState};
+use lunatic_process_api::ProcessCtx;
+use wasmtime::{Caller, Linker};
+
+// Target of records logged without one
+const DEFAULT_TARGET: &str = "lunatic::guest";
+
+// Register the log APIs to the linker
+pub fn register<T: ProcessState + ProcessCtx<T> + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap("lunatic::log", "trace", trace)?;
+    linker.func_wrap("lunatic::log", "debug", debug)?;
+    linker.func_wrap("lunatic::log", "info", info)?;
+    linker.func_wrap("lunatic::log", "warn", warn)?;
+    linker.func_wrap("lunatic::log", "error", error)?;
+    Ok(())
+}
+
+// Passes a record to the host logger, with the process and environment ID of the calling process
+// prepended to the message. The target can be used to filter guest records with `RUST_LOG`, like
+// the module path of host records. If it's empty, `lunatic::guest` is used.
+//
+// Records more verbose than the max log level of the process configuration are dropped.
+//
+// Traps:
+// * If the target or message is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn log_record<T: ProcessState + ProcessCtx<T>>(
+    caller: &mut Caller<T>,
+    level: Level,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    let state = caller.data();
+    if !state.config().get_max_log_level().allows(level) {
+        return Ok(());
+    }
+    let process_id = state.id();
+    let environment_id = state.environment().id();
+
+    let memory = get_memory(caller)?;
+    let memory = memory.data(&caller);
+    let target = memory
+        .get(target_str_ptr as usize..(target_str_ptr + target_str_len) as usize)
+        .or_trap("lunatic::log::target")?;
+    let target = std::str::from_utf8(target).or_trap("lunatic::log::target_utf8")?;
+    let target = if target.is_empty() {
+        DEFAULT_TARGET
+    } else {
+        target
+    };
+    let msg = memory
+        .get(msg_str_ptr as usize..(msg_str_ptr + msg_str_len) as usize)
+        .or_trap("lunatic::log::msg")?;
+    let msg = std::str::from_utf8(msg).or_trap("lunatic::log::msg_utf8")?;
+
+    log::log!(
+        target: target,
+        level,
+        "[process_id={process_id} environment_id={environment_id}] {msg}"
+    );
+    Ok(())
+}
+
+fn trace<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    log_record(
+        &mut caller,
+        Level::Trace,
+        target_str_ptr,
+        target_str_len,
+        msg_str_ptr,
+        msg_str_len,
+    )
+}
+
+fn debug<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    log_record(
+        &mut caller,
+        Level::Debug,
+        target_str_ptr,
+        target_str_len,
+        msg_str_ptr,
+        msg_str_len,
+    )
+}
+
+fn info<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    log_record(
+        &mut caller,
+        Level::Info,
+        target_str_ptr,
+        target_str_len,
+        msg_str_ptr,
+        msg_str_len,
+    )
+}
+
+fn warn<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    log_record(
+        &mut caller,
+        Level::Warn,
+        target_str_ptr,
+        target_str_len,
+        msg_str_ptr,
+        msg_str_len,
+    )
+}
+
+fn error<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    msg_str_ptr: u32,
+    msg_str_len: u32,
+) -> Result<()> {
+    log_record(
+        &mut caller,
+        Level::Error,
+        target_str_ptr,
+        target_str_len,
+        msg_str_ptr,
+        msg_str_len,
+    )
+}
@@ -2,31 +2,61 @@ use std::{
     convert::TryInto,
     future::Future,
     io::{Read, Write},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, Result};
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_distributed::{
+    distributed::client::{EnvironmentId, NodeId, ProcessId, SendParams},
+    DistributedCtx,
+};
 use lunatic_networking_api::NetworkingCtx;
 use lunatic_process_api::ProcessCtx;
-use tokio::time::{timeout, Duration};
+use lunatic_quic_api::QuicCtx;
+use lunatic_wasi_api::LunaticWasiCtx;
+use lunatic_websocket_api::WebSocketCtx;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
 use wasmtime::{Caller, Linker};
 
 use lunatic_process::{
-    message::{DataMessage, Message},
+    clock,
+    config::ProcessConfig,
+    env::Environment,
+    message::{DataMessage, Message, TRACE_CONTEXT_HEADER},
     state::ProcessState,
-    Signal,
+    Process, Signal,
 };
 
 // Register the mailbox APIs to the linker
-pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static>(
+pub fn register<
+    T: ProcessState
+        + ProcessCtx<T>
+        + DistributedCtx<E>
+        + NetworkingCtx
+        + WebSocketCtx
+        + QuicCtx
+        + LunaticWasiCtx
+        + Send
+        + Sync
+        + 'static,
+    E: Environment + 'static,
+>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::message", "create_data", create_data)?;
     linker.func_wrap("lunatic::message", "write_data", write_data)?;
     linker.func_wrap("lunatic::message", "read_data", read_data)?;
     linker.func_wrap("lunatic::message", "seek_data", seek_data)?;
     linker.func_wrap("lunatic::message", "get_tag", get_tag)?;
+    linker.func_wrap("lunatic::message", "set_header", set_header)?;
+    linker.func_wrap("lunatic::message", "get_header", get_header)?;
     linker.func_wrap("lunatic::message", "get_process_id", get_process_id)?;
+    linker.func_wrap("lunatic::message", "get_link_died_pid", get_link_died_pid)?;
+    linker.func_wrap("lunatic::message", "get_death_reason", get_death_reason)?;
+    linker.func_wrap("lunatic::message", "get_exit_code", get_exit_code)?;
+    linker.func_wrap("lunatic::message", "get_kill_reason", get_kill_reason)?;
     linker.func_wrap("lunatic::message", "data_size", data_size)?;
     linker.func_wrap("lunatic::message", "push_module", push_module)?;
     linker.func_wrap("lunatic::message", "take_module", take_module)?;
@@ -34,25 +64,95 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
     linker.func_wrap("lunatic::message", "take_tcp_stream", take_tcp_stream)?;
     linker.func_wrap("lunatic::message", "push_tls_stream", push_tls_stream)?;
     linker.func_wrap("lunatic::message", "take_tls_stream", take_tls_stream)?;
+    linker.func_wrap("lunatic::message", "push_tcp_listener", push_tcp_listener)?;
+    linker.func_wrap("lunatic::message", "take_tcp_listener", take_tcp_listener)?;
+    linker.func_wrap("lunatic::message", "push_tls_listener", push_tls_listener)?;
+    linker.func_wrap("lunatic::message", "take_tls_listener", take_tls_listener)?;
     linker.func_wrap("lunatic::message", "send", send)?;
+    linker.func_wrap("lunatic::message", "send_many", send_many)?;
+    linker.func_wrap2_async("lunatic::message", "send_to", send_to)?;
+    linker.func_wrap2_async("lunatic::message", "send_to_name", send_to_name)?;
+    linker.func_wrap(
+        "lunatic::message",
+        "send_with_backpressure",
+        send_with_backpressure,
+    )?;
+    linker.func_wrap("lunatic::message", "create_data_slot", create_data_slot)?;
+    linker.func_wrap("lunatic::message", "drop_data_slot", drop_data_slot)?;
+    linker.func_wrap("lunatic::message", "slot_create_data", slot_create_data)?;
+    linker.func_wrap("lunatic::message", "slot_write_data", slot_write_data)?;
+    linker.func_wrap("lunatic::message", "slot_send", slot_send)?;
     linker.func_wrap3_async(
         "lunatic::message",
         "send_receive_skip_search",
         send_receive_skip_search,
     )?;
+    linker.func_wrap5_async("lunatic::message", "send_receive_many", send_receive_many)?;
     linker.func_wrap3_async("lunatic::message", "receive", receive)?;
+    linker.func_wrap4_async("lunatic::message", "receive_until", receive_until)?;
+    linker.func_wrap3_async("lunatic::message", "receive_range", receive_range)?;
+    linker.func_wrap3_async("lunatic::message", "peek", peek)?;
+    linker.func_wrap("lunatic::message", "discard", discard)?;
     linker.func_wrap("lunatic::message", "push_udp_socket", push_udp_socket)?;
     linker.func_wrap("lunatic::message", "take_udp_socket", take_udp_socket)?;
+    linker.func_wrap("lunatic::message", "push_websocket", push_websocket)?;
+    linker.func_wrap("lunatic::message", "take_websocket", take_websocket)?;
+    linker.func_wrap(
+        "lunatic::message",
+        "push_quic_connection",
+        push_quic_connection,
+    )?;
+    linker.func_wrap(
+        "lunatic::message",
+        "take_quic_connection",
+        take_quic_connection,
+    )?;
+    linker.func_wrap(
+        "lunatic::message",
+        "push_quic_send_stream",
+        push_quic_send_stream,
+    )?;
+    linker.func_wrap(
+        "lunatic::message",
+        "take_quic_send_stream",
+        take_quic_send_stream,
+    )?;
+    linker.func_wrap(
+        "lunatic::message",
+        "push_quic_recv_stream",
+        push_quic_recv_stream,
+    )?;
+    linker.func_wrap(
+        "lunatic::message",
+        "take_quic_recv_stream",
+        take_quic_recv_stream,
+    )?;
+    linker.func_wrap("lunatic::message", "push_stdin_pipe", push_stdin_pipe)?;
+    linker.func_wrap("lunatic::message", "take_stdin_pipe", take_stdin_pipe)?;
+    #[cfg(unix)]
+    linker.func_wrap("lunatic::message", "push_unix_stream", push_unix_stream)?;
+    #[cfg(unix)]
+    linker.func_wrap("lunatic::message", "take_unix_stream", take_unix_stream)?;
+
+    linker.func_wrap("lunatic::pubsub", "subscribe", subscribe)?;
+    linker.func_wrap("lunatic::pubsub", "unsubscribe", unsubscribe)?;
+    linker.func_wrap("lunatic::pubsub", "publish", publish)?;
 
     Ok(())
 }
 
-// There are two kinds of messages a lunatic process can receive:
+// There are four kinds of messages a lunatic process can receive:
 //
 // 1. **Data message** that contains a buffer of raw `u8` data and host side resources.
 // 2. **LinkDied message**, representing a `LinkDied` signal that was turned into a message. The
 //    process can control if when a link dies the process should die too, or just receive a
-//    `LinkDied` message notifying it about the link's death.
+//    `LinkDied` message notifying it about the link's death. It contains the ID of the dead
+//    process and the reason of death. If the linked process was killed with `kill_with_reason`,
+//    the message also carries the kill reason.
+// 3. **ProcessDied message**, received if a monitored process dies. It contains the ID of the
+//    dead process and the reason of death.
+// 4. **Shutdown message**, representing a `Shutdown` signal that was turned into a message. This
+//    only happens if the process opted into trapping shutdowns.
 //
 // All messages have a `tag` allowing for selective receives. If there are already messages in the
 // receiving queue, they will be first searched for a specific tag and the first match returned.
@@ -128,17 +228,48 @@ fn create_data<T: ProcessState + ProcessCtx<T>>(
     tag: i64,
     buffer_capacity: u64,
 ) {
-    let tag = match tag {
-        0 => None,
-        tag => Some(tag),
-    };
-    let message = DataMessage::new(tag, buffer_capacity as usize);
+    let message = new_data_message(caller.data(), tag, buffer_capacity);
     caller
         .data_mut()
         .message_scratch_area()
         .replace(Message::Data(message));
 }
 
+// Creates a data message carrying the trace context of the process as header.
+//
+// The buffer capacity is only a hint, it's clamped to the maximum message size of the process or,
+// without one, to its maximum memory. This keeps guests from reserving huge host allocations up
+// front.
+fn new_data_message<T: ProcessState + ProcessCtx<T>>(
+    state: &T,
+    tag: i64,
+    buffer_capacity: u64,
+) -> DataMessage {
+    let tag = match tag {
+        0 => None,
+        tag => Some(tag),
+    };
+    let max_capacity = state
+        .config()
+        .get_max_message_size()
+        .unwrap_or_else(|| state.config().get_max_memory());
+    let buffer_capacity = (buffer_capacity as usize).min(max_capacity);
+    let mut message = DataMessage::new(tag, buffer_capacity);
+    if let Some(context) = state.trace_context() {
+        message.set_header(TRACE_CONTEXT_HEADER.to_vec(), context.to_vec());
+    }
+    message
+}
+
+// Puts a received message into the scratch area. A data message also replaces the trace context
+// of the process with its own.
+fn receive_message<T: ProcessState + ProcessCtx<T>>(state: &mut T, message: Message) {
+    if let Message::Data(data) = &message {
+        state.set_trace_context(data.trace_context().map(|context| context.to_vec()));
+    }
+    state.message_scratch_area().replace(message);
+}
+
 // Writes some data into the message buffer and returns how much data is written in bytes.
 //
 // Traps:
@@ -160,13 +291,17 @@ fn write_data<T: ProcessState + ProcessCtx<T>>(
         .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
         .or_trap("lunatic::message::write_data")?;
     let bytes = match &mut message {
-        Message::Data(data) => data.write(buffer).or_trap("lunatic::message::write_data")?,
-        Message::LinkDied(_) => {
+        Message::Data(data) => {
+            check_message_size(caller.data(), data, buffer.len())?;
+            data.write(buffer).or_trap("lunatic::message::write_data")?
+        }
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     // Put message back after writing to it.
     caller.data_mut().message_scratch_area().replace(message);
@@ -174,6 +309,19 @@ fn write_data<T: ProcessState + ProcessCtx<T>>(
     Ok(bytes as u32)
 }
 
+// Fails if writing **len** more bytes to the message would exceed the maximum message size of the
+// process.
+fn check_message_size<T: ProcessState>(state: &T, message: &DataMessage, len: usize) -> Result<()> {
+    if let Some(max_message_size) = state.config().get_max_message_size() {
+        if message.size() + len > max_message_size {
+            return Err(anyhow!(
+                "Message size limit of {max_message_size} bytes exceeded"
+            ));
+        }
+    }
+    Ok(())
+}
+
 // Reads some data from the message buffer and returns how much data is read in bytes.
 //
 // Traps:
@@ -196,12 +344,13 @@ fn read_data<T: ProcessState + ProcessCtx<T>>(
         .or_trap("lunatic::message::read_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.read(buffer).or_trap("lunatic::message::read_data")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     // Put message back after reading from it.
     caller.data_mut().message_scratch_area().replace(message);
@@ -223,16 +372,108 @@ fn seek_data<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, index: u64)
         .or_trap("lunatic::message::seek_data")?;
     match &mut message {
         Message::Data(data) => data.seek(index as usize),
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(())
 }
 
+// Sets the header **key** of the message in the scratch area to **value**, replacing any previous
+// value.
+//
+// Headers are small key/value pairs travelling with the message, next to the data buffer. They
+// are also forwarded when sending the message to another node.
+//
+// Returns:
+// * 0 on success
+// * 1 if the combined size of all headers would exceed 4 KiB.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+// * If it's called without a data message being inside of the scratch area.
+fn set_header<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::message::set_header")?;
+    let value = memory_slice
+        .get(value_ptr as usize..(value_ptr as usize + value_len as usize))
+        .or_trap("lunatic::message::set_header")?;
+    let message = state
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::set_header")?;
+    match message {
+        Message::Data(data) => Ok(!data.set_header(key.to_vec(), value.to_vec()) as u32),
+        Message::LinkDied(..) => Err(anyhow!("Unexpected `Message::LinkDied` in scratch area")),
+        Message::ProcessDied(..) => {
+            Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    }
+}
+
+// Copies the value of the header **key** of the message in the scratch area to **value_ptr**.
+//
+// At most **value_len** bytes are copied. Calling it with a **value_len** of 0 can be used to
+// query the size of the value first.
+//
+// Returns:
+// * The size of the header value in bytes.
+// * -1 if the message has no header **key**.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+// * If it's called without a data message being inside of the scratch area.
+fn get_header<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<i64> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::message::get_header")?;
+    let message = state
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_header")?;
+    let value = match message {
+        Message::Data(data) => match data.header(key) {
+            Some(value) => value,
+            None => return Ok(-1),
+        },
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    let copy_len = value.len().min(value_len as usize);
+    memory_slice
+        .get_mut(value_ptr as usize..(value_ptr as usize + copy_len))
+        .or_trap("lunatic::message::get_header")?
+        .copy_from_slice(&value[..copy_len]);
+    Ok(value.len() as i64)
+}
+
 // Returns the message tag or 0 if no tag was set.
 //
 // Traps:
@@ -246,7 +487,8 @@ fn get_tag<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<i64
     Ok(message.tag().unwrap_or(0))
 }
 
-// Returns the process id if the message is a process died signal, or 0 if any other message type.
+// Returns the process id if the message is a link died or process died signal, or 0 if any other
+// message type.
 //
 // Traps:
 // * If it's called without a message being inside of the scratch area.
@@ -259,6 +501,105 @@ fn get_process_id<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Res
     Ok(message.process_id().unwrap_or(0))
 }
 
+// Returns the ID of the dead process if the message is a link died signal.
+//
+// Traps:
+// * If it's called without a link died message being inside of the scratch area.
+fn get_link_died_pid<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_link_died_pid")?;
+    match message {
+        Message::LinkDied(_, process_id, ..) => Ok(*process_id),
+        _ => Err(anyhow!(
+            "lunatic::message::get_link_died_pid: not a link died message"
+        )),
+    }
+}
+
+// Returns the reason of death if the message is a link died or process died signal.
+//
+// Returns:
+// * 0 if the process finished normally.
+// * 1 if the process failed or was killed.
+// * 2 if the process didn't exist when the link or monitor was set up.
+// * 3 if the process called `proc_exit` with a non-zero exit code.
+//
+// Traps:
+// * If it's called without a link died or process died message being inside of the scratch area.
+fn get_death_reason<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u32> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_death_reason")?;
+    let reason = message
+        .death_reason()
+        .or_trap("lunatic::message::get_death_reason: not a link died or process died message")?;
+    Ok(reason.as_u32())
+}
+
+// Returns the exit code of a process that called `proc_exit` with a non-zero exit code.
+//
+// Traps:
+// * If it's called without a link died or process died message being inside of the scratch area.
+// * If the process didn't die by calling `proc_exit`.
+fn get_exit_code<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<i32> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_exit_code")?;
+    let reason = message
+        .death_reason()
+        .or_trap("lunatic::message::get_exit_code: not a link died or process died message")?;
+    reason
+        .exit_code()
+        .or_trap("lunatic::message::get_exit_code: process didn't exit with a code")
+}
+
+// Copies the kill reason of a link died message to **reason_ptr**.
+//
+// At most **reason_len** bytes are copied. Calling it with a **reason_len** of 0 can be used to
+// query the size of the reason first.
+//
+// Returns:
+// * The size of the reason in bytes.
+// * -1 if the linked process was not killed with a reason.
+//
+// Traps:
+// * If it's called without a link died message being inside of the scratch area.
+// * If any memory outside the guest heap space is referenced.
+fn get_kill_reason<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    reason_ptr: u32,
+    reason_len: u32,
+) -> Result<i64> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let message = state
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_kill_reason")?;
+    if !matches!(message, Message::LinkDied(..)) {
+        return Err(anyhow!(
+            "lunatic::message::get_kill_reason: not a link died message"
+        ));
+    }
+    let reason = match message.kill_reason() {
+        Some(reason) => reason,
+        None => return Ok(-1),
+    };
+    let copy_len = reason.len().min(reason_len as usize);
+    memory_slice
+        .get_mut(reason_ptr as usize..(reason_ptr as usize + copy_len))
+        .or_trap("lunatic::message::get_kill_reason")?
+        .copy_from_slice(&reason[..copy_len]);
+    Ok(reason.len() as i64)
+}
+
 // Returns the size in bytes of the message buffer.
 //
 // Traps:
@@ -271,12 +612,13 @@ fn data_size<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u
         .or_trap("lunatic::message::data_size")?;
     let bytes = match message {
         Message::Data(data) => data.size(),
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
 
     Ok(bytes as u64)
@@ -305,12 +647,13 @@ fn push_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
         .or_trap("lunatic::message::push_module")?;
     let index = match message {
         Message::Data(data) => data.add_resource(module) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -334,12 +677,13 @@ fn take_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
         Message::Data(data) => data
             .take_module(index as usize)
             .or_trap("lunatic::message::take_module")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().module_resources_mut().add(module))
 }
@@ -366,12 +710,13 @@ fn push_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .or_trap("lunatic::message::push_tcp_stream")?;
     let index = match message {
         Message::Data(data) => data.add_resource(stream) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -395,12 +740,13 @@ fn take_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         Message::Data(data) => data
             .take_tcp_stream(index as usize)
             .or_trap("lunatic::message::take_tcp_stream")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().tcp_stream_resources_mut().add(tcp_stream))
 }
@@ -428,12 +774,13 @@ fn push_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .or_trap("lunatic::message::push_tls_stream")?;
     let index = match message {
         Message::Data(data) => data.add_resource(stream) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -457,20 +804,161 @@ fn take_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         Message::Data(data) => data
             .take_tls_stream(index as usize)
             .or_trap("lunatic::message::take_tls_stream")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().tls_stream_resources_mut().add(tls_stream))
 }
 
+// move listeners
+
+// Adds a tcp listener resource to the message that is currently in the scratch area and
+// returns the new location of it. This will remove the listener from the current process'
+// resources.
+//
+// To share a listener between multiple processes accepting connections on it, clone it with
+// `lunatic::networking::clone_tcp_listener` and push each clone into a message for one process.
+//
+// Traps:
+// * If TCP listener ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_tcp_listener<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    listener_id: u64,
+) -> Result<u64> {
+    let listener = caller
+        .data_mut()
+        .tcp_listener_resources_mut()
+        .remove(listener_id)
+        .or_trap("lunatic::message::push_tcp_listener")?;
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_tcp_listener")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(listener) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the tcp listener from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a tcp listener).
+// * If no data message is in the scratch area.
+fn take_tcp_listener<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_tcp_listener")?;
+    let listener = match message {
+        Message::Data(data) => data
+            .take_tcp_listener(index as usize)
+            .or_trap("lunatic::message::take_tcp_listener")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller.data_mut().tcp_listener_resources_mut().add(listener))
+}
+
+// Adds a tls listener resource to the message that is currently in the scratch area and
+// returns the new location of it. This will remove the listener from the current process'
+// resources.
+//
+// To share a listener between multiple processes accepting connections on it, clone it with
+// `lunatic::networking::clone_tls_listener` and push each clone into a message for one process.
+//
+// Traps:
+// * If TLS listener ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_tls_listener<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    listener_id: u64,
+) -> Result<u64> {
+    let listener = caller
+        .data_mut()
+        .tls_listener_resources_mut()
+        .remove(listener_id)
+        .or_trap("lunatic::message::push_tls_listener")?;
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_tls_listener")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(listener) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the tls listener from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a tls listener).
+// * If no data message is in the scratch area.
+fn take_tls_listener<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_tls_listener")?;
+    let listener = match message {
+        Message::Data(data) => data
+            .take_tls_listener(index as usize)
+            .or_trap("lunatic::message::take_tls_listener")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller.data_mut().tls_listener_resources_mut().add(listener))
+}
+
 // Sends the message to a process.
 //
 // There are no guarantees that the message will be received.
 //
+// Returns:
+// * 0 if the message was sent.
+// * 1 if the message exceeds the maximum message size of the receiving process and was dropped.
+//
 // Traps:
 // * If the process ID doesn't exist.
 // * If it's called before creating the next message.
@@ -482,168 +970,1102 @@ fn send<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u64)
         .or_trap("lunatic::message::send::no_message")?;
 
     if let Some(process) = caller.data_mut().environment().get_process(process_id) {
+        if exceeds_max_message_size(process.as_ref(), &message) {
+            return Ok(1);
+        }
         process.send(Signal::Message(message));
     }
 
     Ok(0)
 }
 
-// Sends the message to a process and waits for a reply, but doesn't look through existing
-// messages in the mailbox queue while waiting. This is an optimization that only makes sense
-// with tagged messages. In a request/reply scenario we can tag the request message with an
-// unique tag and just wait on it specifically.
-//
-// This operation needs to be an atomic host function, if we jumped back into the guest we could
-// miss out on the incoming message before `receive` is called.
+// Returns true if the data message is bigger than the maximum message size of the receiving
+// process. The mailbox would drop it on arrival, so senders are told about it instead.
+fn exceeds_max_message_size(process: &dyn Process, message: &Message) -> bool {
+    match (message, process.max_message_size()) {
+        (Message::Data(data), Some(max_message_size)) => data.size() > max_message_size,
+        _ => false,
+    }
+}
+
+// Looks up the processes in the environment, IDs of processes that don't exist are skipped.
+fn existing_processes<T: ProcessState + ProcessCtx<T>>(
+    state: &T,
+    process_ids: &[u64],
+) -> Vec<Arc<dyn Process>> {
+    let environment = state.environment();
+    process_ids
+        .iter()
+        .filter_map(|process_id| environment.get_process(*process_id))
+        .collect()
+}
+
+// Returns true if any of the processes would drop the message because of its size.
+fn exceeds_any_max_message_size(processes: &[Arc<dyn Process>], message: &Message) -> bool {
+    processes
+        .iter()
+        .any(|process| exceeds_max_message_size(process.as_ref(), message))
+}
+
+// Sends the message to a process on any node, the node ID is the one returned by
+// `lunatic::distributed::node_id`. Processes on the current node are sent to directly, the
+// others through the distributed node.
 //
-// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
-// expiration with value 9027.
+// There are no guarantees that the message will be received.
 //
 // Returns:
-// * 0    if message arrived.
-// * 9027 if call timed out.
+// * 0 if the message was sent.
+// * 1 if the process doesn't exist, only checked for processes on the current node.
+// * 2 if the node doesn't exist.
+// * 3 if the message exceeds the maximum message size of the receiving process, only checked
+//     for processes on the current node.
 //
 // Traps:
-// * If the process ID doesn't exist.
-// * If it's called with wrong data in the scratch area.
-fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
+// * If the message contains resources and the process is on another node.
+// * If it's called before creating the next message.
+fn send_to<T, E>(
     mut caller: Caller<T>,
+    node_id: u64,
     process_id: u64,
-    wait_on_tag: i64,
-    timeout_duration: u64,
-) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + DistributedCtx<E> + Send + Sync,
+    E: Environment,
+{
     Box::new(async move {
         let message = caller
             .data_mut()
             .message_scratch_area()
             .take()
-            .or_trap("lunatic::message::send_receive_skip_search")?;
-
-        if let Some(process) = caller.data_mut().environment().get_process(process_id) {
-            process.send(Signal::Message(message));
-        }
-
-        let tags = [wait_on_tag];
-        let pop_skip_search_tag = caller.data_mut().mailbox().pop_skip_search(Some(&tags));
-        if let Ok(message) = match timeout_duration {
-            // Without timeout
-            u64::MAX => Ok(pop_skip_search_tag.await),
-            // With timeout
-            t => timeout(Duration::from_millis(t), pop_skip_search_tag).await,
-        } {
-            // Put the message into the scratch area
-            caller.data_mut().message_scratch_area().replace(message);
-            Ok(0)
-        } else {
-            Ok(9027)
-        }
+            .or_trap("lunatic::message::send_to::no_message")?;
+        route(&mut caller, message, node_id, process_id).await
     })
 }
 
-// Takes the next message out of the queue or blocks until the next message is received if queue
-// is empty.
-//
-// If **tag_len** is a value greater than 0 it will block until a message is received matching any
-// of the supplied tags. **tag_ptr** points to an array containing i64 value encoded as little
-// endian values.
-//
-// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
-// expiration with value 9027.
+// Sends the message to the process registered under the name, on the current or another node.
+// Names starting with `global::` are looked up in the whole cluster, like with
+// `lunatic::registry::get`.
 //
-// Once the message is received, functions like `lunatic::message::read_data()` can be used to
-// extract data out of it.
+// There are no guarantees that the message will be received.
 //
 // Returns:
-// * 0    if it's a data message.
-// * 1    if it's a link died signal.
-// * 2    if it's a process died signal.
+// * 0 if the message was sent.
+// * 1 if no process is registered under the name, or the process on the current node doesn't
+//     exist anymore.
+// * 2 if the node of the process doesn't exist.
+// * 3 if the message exceeds the maximum message size of the receiving process, only checked
+//     for processes on the current node.
+//
+// Traps:
+// * If the name is not a valid UTF-8 string.
+// * If the message contains resources and the process is on another node.
+// * If it's called before creating the next message.
+// * If any memory outside the guest heap space is referenced.
+fn send_to_name<T, E>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + DistributedCtx<E> + Send + Sync,
+    E: Environment,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send_to_name::no_message")?;
+        let memory = get_memory(&mut caller)?;
+        let name = memory
+            .data(&caller)
+            .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+            .or_trap("lunatic::message::send_to_name")?;
+        let name = std::str::from_utf8(name).or_trap("lunatic::message::send_to_name")?;
+        match lunatic_registry_api::lookup(caller.data(), name).await? {
+            Some((node_id, process_id)) => route(&mut caller, message, node_id, process_id).await,
+            None => Ok(1),
+        }
+    })
+}
+
+// Sends the message through the local environment or the distributed node, depending on where
+// the process runs. Without a distributed node only node ID 0 is local.
+async fn route<T, E>(
+    caller: &mut Caller<'_, T>,
+    message: Message,
+    node_id: u64,
+    process_id: u64,
+) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T> + DistributedCtx<E> + Send + Sync,
+    E: Environment,
+{
+    let state = caller.data();
+    let distributed = state.distributed().ok();
+    let local_node_id = distributed
+        .map(|distributed| distributed.node_id())
+        .unwrap_or(0);
+    if node_id == local_node_id {
+        return match state.environment().get_process(process_id) {
+            Some(process) if exceeds_max_message_size(process.as_ref(), &message) => Ok(3),
+            Some(process) => {
+                process.send(Signal::Message(message));
+                Ok(0)
+            }
+            None => Ok(1),
+        };
+    }
+    let distributed = match distributed {
+        Some(distributed) => distributed,
+        None => return Ok(2),
+    };
+
+    let DataMessage {
+        tag,
+        buffer,
+        resources,
+        headers,
+        ..
+    } = match message {
+        Message::Data(data) => data,
+        _ => return Err(anyhow!("Only Message::Data can be sent across nodes.")),
+    };
+    if !resources.is_empty() {
+        return Err(anyhow!("Cannot send resources to remote nodes."));
+    }
+    let send_params = SendParams {
+        env: EnvironmentId(state.environment_id()),
+        src: ProcessId(state.id()),
+        node: NodeId(node_id),
+        dest: ProcessId(process_id),
+        tag,
+        data: buffer,
+        headers,
+    };
+    // Fails only if the node isn't known
+    match distributed.node_client.send(send_params).await {
+        Ok(_) => Ok(0),
+        Err(_) => Ok(2),
+    }
+}
+
+// Sends the message to a process and reports the pressure on its mailbox, so that producers can
+// slow down.
+//
+// The number of messages waiting in the mailbox of the receiving process is written to
+// **len_ptr** as a little endian u64 value. Messages that are still on their way to the mailbox,
+// including the one just sent, are not part of this number.
+//
+// Returns:
+// * 0 if the mailbox is below the watermark of the receiving process, or no watermark is set.
+// * 1 if the mailbox reached the watermark of the receiving process.
+// * 2 if the process doesn't exist.
+// * 3 if the message exceeds the maximum message size of the receiving process and was dropped.
+//
+// Traps:
+// * If **len_ptr** is outside the memory.
+// * If it's called before creating the next message.
+fn send_with_backpressure<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    len_ptr: u32,
+) -> Result<u32> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::send_with_backpressure::no_message")?;
+
+    let (len, result) = match caller.data().environment().get_process(process_id) {
+        Some(process) if exceeds_max_message_size(process.as_ref(), &message) => (0, 3),
+        Some(process) => {
+            process.send(Signal::Message(message));
+            match process.mailbox_pressure() {
+                Some((len, reached)) => (len as u64, reached as u32),
+                None => (0, 0),
+            }
+        }
+        None => (0, 2),
+    };
+
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, len_ptr as usize, &len.to_le_bytes())
+        .or_trap("lunatic::message::send_with_backpressure")?;
+    Ok(result)
+}
+
+// Sends the message to multiple processes.
+//
+// **process_ids_ptr** points to an array of **process_ids_len** process IDs encoded as little
+// endian u64 values. The message is copied on the host for each process. Messages with
+// resources attached can't be copied and stay in the scratch area. IDs of processes that don't
+// exist are ignored.
+//
+// There are no guarantees that the message will be received.
+//
+// Returns:
+// * 0 on success
+// * 1 if resources are attached to the message.
+// * 2 if the message is bigger than the maximum message size of one of the processes. The message
+//     isn't sent to any of them and stays in the scratch area.
+//
+// Traps:
+// * If **process_ids_ptr + (process_ids_len * 8)** is outside the memory.
+// * If it's called before creating the next message.
+fn send_many<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    process_ids_ptr: u32,
+    process_ids_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let process_ids: Vec<u64> = memory
+        .data(&caller)
+        .get(process_ids_ptr as usize..(process_ids_ptr + process_ids_len * 8) as usize)
+        .or_trap("lunatic::message::send_many")?
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("works")))
+        .collect();
+    let has_resources = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::send_many::no_message")?
+        .has_resources();
+    if has_resources {
+        return Ok(1);
+    }
+    let processes = existing_processes(caller.data(), &process_ids);
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::send_many::no_message")?;
+    if exceeds_any_max_message_size(&processes, &message) {
+        caller.data_mut().message_scratch_area().replace(message);
+        return Ok(2);
+    }
+
+    for process in processes {
+        process.send(Signal::Message(message.clone()));
+    }
+
+    Ok(0)
+}
+
+// Besides the scratch area, a process can create additional data slots to build multiple messages
+// at the same time (e.g. while streaming). The scratch area is slot 0 and all other slots are
+// created with `lunatic::message::create_data_slot`.
+
+// Returns the message stored in a data slot. Slot 0 is the scratch area.
+fn message_slot<T: ProcessState + ProcessCtx<T>>(
+    state: &mut T,
+    slot_id: u64,
+) -> Option<&mut Option<Message>> {
+    match slot_id {
+        0 => Some(state.message_scratch_area()),
+        // Ids of the additional slots are offset by one, to keep 0 for the scratch area.
+        slot_id => state.message_slots().get_mut(slot_id - 1),
+    }
+}
+
+// Creates a new empty data slot and returns its ID.
+fn create_data_slot<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u64 {
+    caller.data_mut().message_slots().add(None) + 1
+}
+
+// Drops a data slot and the message inside of it.
+//
+// Returns:
+// * 0 on success
+// * 1 if the slot doesn't exist or is the scratch area (slot 0).
+fn drop_data_slot<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, slot_id: u64) -> u32 {
+    if slot_id == 0 {
+        return 1;
+    }
+    match caller.data_mut().message_slots().remove(slot_id - 1) {
+        Some(_) => 0,
+        None => 1,
+    }
+}
+
+// Same as `lunatic::message::create_data`, but creates the message inside of a data slot.
+//
+// Traps:
+// * If the slot doesn't exist.
+fn slot_create_data<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    slot_id: u64,
+    tag: i64,
+    buffer_capacity: u64,
+) -> Result<()> {
+    let message = new_data_message(caller.data(), tag, buffer_capacity);
+    message_slot(caller.data_mut(), slot_id)
+        .or_trap("lunatic::message::slot_create_data")?
+        .replace(Message::Data(message));
+    Ok(())
+}
+
+// Same as `lunatic::message::write_data`, but writes to the message inside of a data slot.
+//
+// Traps:
+// * If the slot doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+// * If it's called without a data message being inside of the slot.
+fn slot_write_data<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    slot_id: u64,
+    data_ptr: u32,
+    data_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let mut message = message_slot(caller.data_mut(), slot_id)
+        .or_trap("lunatic::message::slot_write_data")?
+        .take()
+        .or_trap("lunatic::message::slot_write_data")?;
+    let buffer = memory
+        .data(&caller)
+        .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
+        .or_trap("lunatic::message::slot_write_data")?;
+    let bytes = match &mut message {
+        Message::Data(data) => {
+            check_message_size(caller.data(), data, buffer.len())?;
+            data.write(buffer)
+                .or_trap("lunatic::message::slot_write_data")?
+        }
+        Message::LinkDied(..) => return Err(anyhow!("Unexpected `Message::LinkDied` in slot")),
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in slot"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in slot")),
+    };
+    // Put message back after writing to it.
+    message_slot(caller.data_mut(), slot_id)
+        .expect("slot exists")
+        .replace(message);
+
+    Ok(bytes as u32)
+}
+
+// Same as `lunatic::message::send`, but sends the message inside of a data slot. The slot stays
+// available for the next message.
+//
+// Returns:
+// * 0 if the message was sent.
+// * 1 if the message exceeds the maximum message size of the receiving process and was dropped.
+//
+// Traps:
+// * If the slot doesn't exist.
+// * If it's called before creating the next message in the slot.
+fn slot_send<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    slot_id: u64,
+    process_id: u64,
+) -> Result<u32> {
+    let message = message_slot(caller.data_mut(), slot_id)
+        .or_trap("lunatic::message::slot_send")?
+        .take()
+        .or_trap("lunatic::message::slot_send::no_message")?;
+
+    if let Some(process) = caller.data_mut().environment().get_process(process_id) {
+        if exceeds_max_message_size(process.as_ref(), &message) {
+            return Ok(1);
+        }
+        process.send(Signal::Message(message));
+    }
+
+    Ok(0)
+}
+
+// Sends the message to a process and waits for a reply, but doesn't look through existing
+// messages in the mailbox queue while waiting. This is an optimization that only makes sense
+// with tagged messages. In a request/reply scenario we can tag the request message with an
+// unique tag and just wait on it specifically.
+//
+// This operation needs to be an atomic host function, if we jumped back into the guest we could
+// miss out on the incoming message before `receive` is called.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if message arrived.
+// * 1    if the message exceeds the maximum message size of the receiving process and was
+//          dropped, without waiting for a reply.
+// * 9027 if call timed out.
+//
+// Traps:
+// * If the process ID doesn't exist.
+// * If it's called with wrong data in the scratch area.
+fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    wait_on_tag: i64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send_receive_skip_search")?;
+
+        if let Some(process) = caller.data_mut().environment().get_process(process_id) {
+            if exceeds_max_message_size(process.as_ref(), &message) {
+                return Ok(1);
+            }
+            process.send(Signal::Message(message));
+        }
+
+        let tags = [wait_on_tag];
+        let pop_skip_search_tag = caller.data_mut().mailbox().pop_skip_search(Some(&tags));
+        if let Ok(message) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(pop_skip_search_tag.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), pop_skip_search_tag).await,
+        } {
+            receive_message(caller.data_mut(), message);
+            Ok(0)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Sends the message to multiple processes and waits for their replies. Like with
+// `send_receive_skip_search`, the existing messages in the mailbox queue are not searched for
+// replies.
+//
+// **process_ids_ptr** points to an array of **len** process IDs and **tags_ptr** to an array of
+// **len** tags, both encoded as little endian 64 bit values. The process at a given position is
+// expected to reply with the tag at the same position.
+//
+// Modes:
+// * 0 - Waits for the first reply and puts it into the scratch area.
+// * 1 - Waits for the replies of all processes. The replies are put at the front of the mailbox
+//       queue in order of arrival, so that they can be taken out with `receive`.
+//
+// Replies arriving after the call returned end up in the mailbox queue like any other message.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will stop waiting on
+// replies once it expires.
+//
+// Returns the number of received replies. Messages with resources attached can't be copied to
+// multiple processes, they stay in the scratch area and `u32::MAX` is returned instead. If the
+// message is bigger than the maximum message size of one of the processes, it isn't sent to any of
+// them, stays in the scratch area and `u32::MAX - 1` is returned.
+//
+// Traps:
+// * If the mode is unknown.
+// * If **process_ids_ptr + (len * 8)** or **tags_ptr + (len * 8)** is outside the memory.
+// * If it's called before creating the next message.
+fn send_receive_many<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    process_ids_ptr: u32,
+    tags_ptr: u32,
+    len: u32,
+    mode: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let wait_on_all = match mode {
+            0 => false,
+            1 => true,
+            mode => {
+                return Err(anyhow!(
+                    "lunatic::message::send_receive_many: Unknown mode {mode}"
+                ))
+            }
+        };
+        let memory = get_memory(&mut caller)?;
+        let (process_ids, mut tags) = {
+            let read_u64s = |ptr: u32| {
+                memory
+                    .data(&caller)
+                    .get(ptr as usize..(ptr + len * 8) as usize)
+                    .map(|buffer| {
+                        buffer
+                            .chunks_exact(8)
+                            .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("works")))
+                            .collect::<Vec<u64>>()
+                    })
+            };
+            let process_ids =
+                read_u64s(process_ids_ptr).or_trap("lunatic::message::send_receive_many")?;
+            let tags: Vec<i64> = read_u64s(tags_ptr)
+                .or_trap("lunatic::message::send_receive_many")?
+                .into_iter()
+                .map(|tag| tag as i64)
+                .collect();
+            (process_ids, tags)
+        };
+        let has_resources = caller
+            .data_mut()
+            .message_scratch_area()
+            .as_ref()
+            .or_trap("lunatic::message::send_receive_many::no_message")?
+            .has_resources();
+        if has_resources {
+            return Ok(u32::MAX);
+        }
+        let processes = existing_processes(caller.data(), &process_ids);
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send_receive_many::no_message")?;
+        if exceeds_any_max_message_size(&processes, &message) {
+            caller.data_mut().message_scratch_area().replace(message);
+            return Ok(u32::MAX - 1);
+        }
+
+        for process in processes {
+            process.send(Signal::Message(message.clone()));
+        }
+
+        // Without timeout, or if the timeout can't be represented, wait forever
+        let deadline = match timeout_duration {
+            u64::MAX => None,
+            t => Instant::now().checked_add(Duration::from_millis(t)),
+        };
+        let mailbox = caller.data_mut().mailbox().clone();
+        let mut replies = Vec::new();
+        while !tags.is_empty() {
+            // Replies can't be in the queue before the first wait, afterwards they could have
+            // arrived while processing the previous reply.
+            let pop = async {
+                if replies.is_empty() {
+                    mailbox.pop_skip_search(Some(&tags)).await
+                } else {
+                    mailbox.pop(Some(&tags)).await
+                }
+            };
+            let reply = match deadline {
+                Some(deadline) => match timeout_at(deadline, pop).await {
+                    Ok(reply) => reply,
+                    Err(_) => break,
+                },
+                None => pop.await,
+            };
+            if let Some(index) = tags.iter().position(|tag| Some(*tag) == reply.tag()) {
+                tags.swap_remove(index);
+            }
+            replies.push(reply);
+            if !wait_on_all {
+                break;
+            }
+        }
+
+        let received = replies.len() as u32;
+        if wait_on_all {
+            for reply in replies.into_iter().rev() {
+                mailbox.push_front(reply);
+            }
+        } else if let Some(reply) = replies.pop() {
+            receive_message(caller.data_mut(), reply);
+        }
+        Ok(received)
+    })
+}
+
+// Reads **tag_len** tags encoded as little endian i64 values from the guest memory, or returns
+// `None` if **tag_len** is 0.
+fn read_tags<T: ProcessState + ProcessCtx<T>>(
+    caller: &mut Caller<T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    trap: &str,
+) -> Result<Option<Vec<i64>>> {
+    if tag_len == 0 {
+        return Ok(None);
+    }
+    let memory = get_memory(caller)?;
+    let buffer = memory
+        .data(&caller)
+        .get(tag_ptr as usize..(tag_ptr + tag_len * 8) as usize)
+        .or_trap(trap)?;
+
+    // Gether all tags
+    let tags: Vec<i64> = buffer
+        .chunks_exact(8)
+        .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("works")))
+        .collect();
+    Ok(Some(tags))
+}
+
+// Takes the next message out of the queue or blocks until the next message is received if queue
+// is empty.
+//
+// If **tag_len** is a value greater than 0 it will block until a message is received matching any
+// of the supplied tags. **tag_ptr** points to an array containing i64 value encoded as little
+// endian values.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Once the message is received, functions like `lunatic::message::read_data()` can be used to
+// extract data out of it.
+//
+// Returns:
+// * 0    if it's a data message.
+// * 1    if it's a link died signal.
+// * 2    if it's a process died signal.
+// * 3    if it's a shutdown signal.
+// * 9027 if call timed out.
+//
+// Traps:
+// * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
+fn receive<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let tags = read_tags(&mut caller, tag_ptr, tag_len, "lunatic::message::receive")?;
+
+        let pop = caller.data_mut().mailbox().pop(tags.as_deref());
+        if let Ok(message) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(pop.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), pop).await,
+        } {
+            let result = match message {
+                Message::Data(_) => 0,
+                Message::LinkDied(..) => 1,
+                Message::ProcessDied(..) => 2,
+                Message::Shutdown => 3,
+            };
+            receive_message(caller.data_mut(), message);
+            Ok(result)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Same as `receive`, but waits until an absolute **deadline** instead of a relative timeout.
+//
+// The deadline is a point in time of the monotonic clock returned by
+// `lunatic::timer::now_monotonic()`. If the deadline is `u64::MAX` the function will wait
+// without a deadline.
+//
+// The time left until the deadline in milliseconds is written to **remaining_ptr** as a little
+// endian u64 value, so that the guest can keep waiting with the rest of its budget after an
+// unexpected message. It's 0 if the deadline was reached and `u64::MAX` without a deadline.
+//
+// Returns:
+// * 0    if it's a data message.
+// * 1    if it's a link died signal.
+// * 2    if it's a process died signal.
+// * 3    if it's a shutdown signal.
+// * 9027 if the deadline was reached.
+//
+// Traps:
+// * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
+// * If **remaining_ptr + 8** is outside the memory
+fn receive_until<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    deadline: u64,
+    remaining_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let tags = read_tags(
+            &mut caller,
+            tag_ptr,
+            tag_len,
+            "lunatic::message::receive_until",
+        )?;
+
+        let pop = caller.data_mut().mailbox().pop(tags.as_deref());
+        // Deadlines that can't be represented are too far in the future to ever be reached
+        let deadline = match deadline {
+            u64::MAX => None,
+            deadline => clock::instant_from_monotonic_ms(deadline),
+        };
+        let received = match deadline {
+            // Without deadline
+            None => Ok(pop.await),
+            // With deadline
+            Some(deadline) => timeout_at(deadline.into(), pop).await,
+        };
+        let remaining = match deadline {
+            None => u64::MAX,
+            Some(deadline) => deadline
+                .saturating_duration_since(std::time::Instant::now())
+                .as_millis() as u64,
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                remaining_ptr as usize,
+                &remaining.to_le_bytes(),
+            )
+            .or_trap("lunatic::message::receive_until")?;
+        if let Ok(message) = received {
+            let result = match message {
+                Message::Data(_) => 0,
+                Message::LinkDied(..) => 1,
+                Message::ProcessDied(..) => 2,
+                Message::Shutdown => 3,
+            };
+            receive_message(caller.data_mut(), message);
+            Ok(result)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Takes the first message with a tag between **min_tag** and **max_tag** (inclusive) out of the
+// queue or blocks until such a message is received.
+//
+// This allows guest libraries to encode additional data (e.g. request IDs) in the lower bits of
+// tags and still receive a whole class of messages. Messages without a tag are never matched.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if it's a data message.
+// * 1    if it's a link died signal.
+// * 2    if it's a process died signal.
+// * 3    if it's a shutdown signal.
+// * 9027 if call timed out.
+//
+// Traps:
+// * If **min_tag** is greater than **max_tag**, no message could ever match.
+fn receive_range<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    min_tag: i64,
+    max_tag: i64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        if min_tag > max_tag {
+            return Err(anyhow!(
+                "lunatic::message::receive_range: min tag {min_tag} is greater than max tag {max_tag}"
+            ));
+        }
+        let pop = caller.data_mut().mailbox().pop_range(min_tag, max_tag);
+        if let Ok(message) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(pop.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), pop).await,
+        } {
+            let result = match message {
+                Message::Data(_) => 0,
+                Message::LinkDied(..) => 1,
+                Message::ProcessDied(..) => 2,
+                Message::Shutdown => 3,
+            };
+            receive_message(caller.data_mut(), message);
+            Ok(result)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Behaves like `receive`, but leaves the message in the mailbox.
+//
+// The matching message is moved to the front of the queue and a copy of it is put into the
+// scratch area, so that its data can be inspected with `lunatic::message::read_data()`. A
+// following `receive` without tags will return the same message, or it can be dropped with
+// `lunatic::message::discard()`.
+//
+// Only the data of the message is copied, resources can only be taken out after the message is
+// received.
+//
+// Returns:
+// * 0    if it's a data message.
+// * 1    if it's a link died signal.
+// * 2    if it's a process died signal.
+// * 3    if it's a shutdown signal.
 // * 9027 if call timed out.
 //
 // Traps:
-// * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
-fn receive<T: ProcessState + ProcessCtx<T> + Send>(
+// * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
+fn peek<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let tags = read_tags(&mut caller, tag_ptr, tag_len, "lunatic::message::peek")?;
+
+        let peek = caller.data_mut().mailbox().peek(tags.as_deref());
+        if let Ok(message) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(peek.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), peek).await,
+        } {
+            let result = match message {
+                Message::Data(_) => 0,
+                Message::LinkDied(..) => 1,
+                Message::ProcessDied(..) => 2,
+                Message::Shutdown => 3,
+            };
+            // Put the copy of the message data into the scratch area
+            caller.data_mut().message_scratch_area().replace(message);
+            Ok(result)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Drops the message at the front of the mailbox, usually a message previously returned by
+// `lunatic::message::peek()`.
+//
+// Returns:
+// * 0 if a message was dropped.
+// * 1 if the mailbox is empty.
+fn discard<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u32 {
+    if caller.data_mut().mailbox().discard() {
+        0
+    } else {
+        1
+    }
+}
+
+// Adds a udp socket resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the socket from the current process' resources.
+//
+// Traps:
+// * If UDP socket ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    socket_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let socket = data
+        .udp_resources_mut()
+        .remove(socket_id)
+        .or_trap("lunatic::message::push_udp_socket")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_udp_socket")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(socket) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the udp socket from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a udp socket).
+// * If no data message is in the scratch area.
+fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_udp_socket")?;
+    let udp_socket = match message {
+        Message::Data(data) => data
+            .take_udp_socket(index as usize)
+            .or_trap("lunatic::message::take_udp_socket")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller.data_mut().udp_resources_mut().add(udp_socket))
+}
+
+// Adds a WebSocket resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the WebSocket from the current process' resources.
+//
+// Traps:
+// * If WebSocket ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_websocket<T: ProcessState + ProcessCtx<T> + WebSocketCtx>(
+    mut caller: Caller<T>,
+    websocket_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let websocket = data
+        .websocket_resources_mut()
+        .remove(websocket_id)
+        .or_trap("lunatic::message::push_websocket")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_websocket")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(websocket) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the WebSocket from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a WebSocket).
+// * If no data message is in the scratch area.
+fn take_websocket<T: ProcessState + ProcessCtx<T> + WebSocketCtx>(
     mut caller: Caller<T>,
-    tag_ptr: u32,
-    tag_len: u32,
-    timeout_duration: u64,
-) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
-    Box::new(async move {
-        let tags = if tag_len > 0 {
-            let memory = get_memory(&mut caller)?;
-            let buffer = memory
-                .data(&caller)
-                .get(tag_ptr as usize..(tag_ptr + tag_len * 8) as usize)
-                .or_trap("lunatic::message::receive")?;
-
-            // Gether all tags
-            let tags: Vec<i64> = buffer
-                .chunks_exact(8)
-                .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("works")))
-                .collect();
-            Some(tags)
-        } else {
-            None
-        };
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_websocket")?;
+    let websocket = match message {
+        Message::Data(data) => data
+            .take_websocket(index as usize)
+            .or_trap("lunatic::message::take_websocket")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller.data_mut().websocket_resources_mut().add(websocket))
+}
 
-        let pop = caller.data_mut().mailbox().pop(tags.as_deref());
-        if let Ok(message) = match timeout_duration {
-            // Without timeout
-            u64::MAX => Ok(pop.await),
-            // With timeout
-            t => timeout(Duration::from_millis(t), pop).await,
-        } {
-            let result = match message {
-                Message::Data(_) => 0,
-                Message::LinkDied(_) => 1,
-                Message::ProcessDied(_) => 2,
-            };
-            // Put the message into the scratch area
-            caller.data_mut().message_scratch_area().replace(message);
-            Ok(result)
-        } else {
-            Ok(9027)
+// Adds a QUIC connection resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the QUIC connection from the current process' resources.
+//
+// Traps:
+// * If QUIC connection ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_quic_connection<T: ProcessState + ProcessCtx<T> + QuicCtx>(
+    mut caller: Caller<T>,
+    quic_connection_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let quic_connection = data
+        .quic_connection_resources_mut()
+        .remove(quic_connection_id)
+        .or_trap("lunatic::message::push_quic_connection")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_quic_connection")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(quic_connection) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-    })
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
 }
 
-// Adds a udp socket resource to the message that is currently in the scratch area and returns
-// the new location of it. This will remove the socket from the current process' resources.
+// Takes the QUIC connection from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
 //
 // Traps:
-// * If UDP socket ID doesn't exist
+// * If index ID doesn't exist or matches the wrong resource (not a QUIC connection).
 // * If no data message is in the scratch area.
-fn push_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+fn take_quic_connection<T: ProcessState + ProcessCtx<T> + QuicCtx>(
     mut caller: Caller<T>,
-    socket_id: u64,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_quic_connection")?;
+    let quic_connection = match message {
+        Message::Data(data) => data
+            .take_quic_connection(index as usize)
+            .or_trap("lunatic::message::take_quic_connection")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller
+        .data_mut()
+        .quic_connection_resources_mut()
+        .add(quic_connection))
+}
+
+// Adds a QUIC send stream resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the QUIC send stream from the current process' resources.
+//
+// Traps:
+// * If QUIC send stream ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_quic_send_stream<T: ProcessState + ProcessCtx<T> + QuicCtx>(
+    mut caller: Caller<T>,
+    quic_send_stream_id: u64,
 ) -> Result<u64> {
     let data = caller.data_mut();
-    let socket = data
-        .udp_resources_mut()
-        .remove(socket_id)
-        .or_trap("lunatic::message::push_udp_socket")?;
+    let quic_send_stream = data
+        .quic_send_stream_resources_mut()
+        .remove(quic_send_stream_id)
+        .or_trap("lunatic::message::push_quic_send_stream")?;
     let message = data
         .message_scratch_area()
         .as_mut()
-        .or_trap("lunatic::message::push_udp_socket")?;
+        .or_trap("lunatic::message::push_quic_send_stream")?;
     let index = match message {
-        Message::Data(data) => data.add_resource(socket) as u64,
-        Message::LinkDied(_) => {
+        Message::Data(data) => data.add_resource(quic_send_stream) as u64,
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
 
-// Takes the udp socket from the message that is currently in the scratch area by index, puts
+// Takes the QUIC send stream from the message that is currently in the scratch area by index, puts
 // it into the process' resources and returns the resource ID.
 //
 // Traps:
-// * If index ID doesn't exist or matches the wrong resource (not a udp socket).
+// * If index ID doesn't exist or matches the wrong resource (not a QUIC send stream).
 // * If no data message is in the scratch area.
-fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+fn take_quic_send_stream<T: ProcessState + ProcessCtx<T> + QuicCtx>(
     mut caller: Caller<T>,
     index: u64,
 ) -> Result<u64> {
@@ -651,17 +2073,320 @@ fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .data_mut()
         .message_scratch_area()
         .as_mut()
-        .or_trap("lunatic::message::take_udp_socket")?;
-    let udp_socket = match message {
+        .or_trap("lunatic::message::take_quic_send_stream")?;
+    let quic_send_stream = match message {
         Message::Data(data) => data
-            .take_udp_socket(index as usize)
-            .or_trap("lunatic::message::take_udp_socket")?,
-        Message::LinkDied(_) => {
+            .take_quic_send_stream(index as usize)
+            .or_trap("lunatic::message::take_quic_send_stream")?,
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
-        Message::ProcessDied(_) => {
+        Message::ProcessDied(..) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
-    Ok(caller.data_mut().udp_resources_mut().add(udp_socket))
+    Ok(caller
+        .data_mut()
+        .quic_send_stream_resources_mut()
+        .add(quic_send_stream))
+}
+
+// Adds a QUIC receive stream resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the QUIC receive stream from the current process' resources.
+//
+// Traps:
+// * If QUIC receive stream ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_quic_recv_stream<T: ProcessState + ProcessCtx<T> + QuicCtx>(
+    mut caller: Caller<T>,
+    quic_recv_stream_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let quic_recv_stream = data
+        .quic_recv_stream_resources_mut()
+        .remove(quic_recv_stream_id)
+        .or_trap("lunatic::message::push_quic_recv_stream")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_quic_recv_stream")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(quic_recv_stream) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the QUIC receive stream from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a QUIC receive stream).
+// * If no data message is in the scratch area.
+fn take_quic_recv_stream<T: ProcessState + ProcessCtx<T> + QuicCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_quic_recv_stream")?;
+    let quic_recv_stream = match message {
+        Message::Data(data) => data
+            .take_quic_recv_stream(index as usize)
+            .or_trap("lunatic::message::take_quic_recv_stream")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller
+        .data_mut()
+        .quic_recv_stream_resources_mut()
+        .add(quic_recv_stream))
+}
+
+// Adds a stdin pipe resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the stdin pipe from the current process' resources.
+//
+// Traps:
+// * If stdin pipe ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_stdin_pipe<T: ProcessState + ProcessCtx<T> + LunaticWasiCtx>(
+    mut caller: Caller<T>,
+    stdin_pipe_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let stdin_pipe = data
+        .stdin_pipe_resources_mut()
+        .remove(stdin_pipe_id)
+        .or_trap("lunatic::message::push_stdin_pipe")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_stdin_pipe")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(stdin_pipe) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the stdin pipe from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a stdin pipe).
+// * If no data message is in the scratch area.
+fn take_stdin_pipe<T: ProcessState + ProcessCtx<T> + LunaticWasiCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_stdin_pipe")?;
+    let stdin_pipe = match message {
+        Message::Data(data) => data
+            .take_stdin_pipe(index as usize)
+            .or_trap("lunatic::message::take_stdin_pipe")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller.data_mut().stdin_pipe_resources_mut().add(stdin_pipe))
+}
+
+// Adds a unix stream resource to the message that is currently in the scratch area and returns
+// the new location of it. This will remove the unix stream from the current process' resources.
+//
+// Traps:
+// * If Unix stream ID doesn't exist
+// * If no data message is in the scratch area.
+#[cfg(unix)]
+fn push_unix_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+) -> Result<u64> {
+    let data = caller.data_mut();
+    let stream = data
+        .unix_stream_resources_mut()
+        .remove(stream_id)
+        .or_trap("lunatic::message::push_unix_stream")?;
+    let message = data
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_unix_stream")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(stream) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the unix stream from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a unix stream).
+// * If no data message is in the scratch area.
+#[cfg(unix)]
+fn take_unix_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_unix_stream")?;
+    let unix_stream = match message {
+        Message::Data(data) => data
+            .take_unix_stream(index as usize)
+            .or_trap("lunatic::message::take_unix_stream")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(..) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(caller
+        .data_mut()
+        .unix_stream_resources_mut()
+        .add(unix_stream))
+}
+
+// Topics allow publishing messages to all processes in the environment that subscribed to them,
+// without the publisher knowing the subscribers. A process is unsubscribed from all topics once
+// it dies.
+
+// Reads the topic name from the guest memory.
+fn get_topic<T: ProcessState + ProcessCtx<T>>(
+    caller: &mut Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+    trap: &str,
+) -> Result<String> {
+    let memory = get_memory(caller)?;
+    let topic = memory
+        .data(&caller)
+        .get(topic_ptr as usize..(topic_ptr + topic_len) as usize)
+        .or_trap(trap)?;
+    let topic = std::str::from_utf8(topic).or_trap(trap)?;
+    Ok(topic.to_string())
+}
+
+// Subscribes the current process to the topic.
+//
+// Traps:
+// * If the topic is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn subscribe<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<()> {
+    let topic = get_topic(
+        &mut caller,
+        topic_ptr,
+        topic_len,
+        "lunatic::pubsub::subscribe",
+    )?;
+    let process_id = caller.data().id();
+    caller.data().environment().subscribe(&topic, process_id);
+    Ok(())
+}
+
+// Unsubscribes the current process from the topic.
+//
+// Returns:
+// * 0 on success
+// * 1 if the process was not subscribed to the topic.
+//
+// Traps:
+// * If the topic is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn unsubscribe<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<u32> {
+    let topic = get_topic(
+        &mut caller,
+        topic_ptr,
+        topic_len,
+        "lunatic::pubsub::unsubscribe",
+    )?;
+    let process_id = caller.data().id();
+    let unsubscribed = caller.data().environment().unsubscribe(&topic, process_id);
+    Ok(!unsubscribed as u32)
+}
+
+// Sends the message from the scratch area to all subscribers of the topic and returns the number
+// of subscribers.
+//
+// Each subscriber receives a copy of the message buffer. Messages with resources attached can't
+// be copied and stay in the scratch area, `u64::MAX` is returned for them instead.
+//
+// Traps:
+// * If the topic is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+// * If it's called before creating the next message.
+fn publish<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<u64> {
+    let topic = get_topic(
+        &mut caller,
+        topic_ptr,
+        topic_len,
+        "lunatic::pubsub::publish",
+    )?;
+    let has_resources = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::pubsub::publish::no_message")?
+        .has_resources();
+    if has_resources {
+        return Ok(u64::MAX);
+    }
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::pubsub::publish::no_message")?;
+    let subscribers = caller.data().environment().publish(&topic, message);
+    Ok(subscribers as u64)
 }
@@ -1,16 +1,22 @@
 use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_process::{
+    config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
+    state::ProcessState,
+};
 use metrics::{counter, decrement_gauge, gauge, histogram, increment_counter, increment_gauge};
 use wasmtime::{Caller, Linker};
 
 /// Links the [Metrics](https://crates.io/crates/metrics) APIs
-pub fn register<T: 'static>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+pub fn register<T: ProcessState + 'static>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::metrics", "counter", counter)?;
     linker.func_wrap("lunatic::metrics", "increment_counter", increment_counter)?;
     linker.func_wrap("lunatic::metrics", "gauge", gauge)?;
     linker.func_wrap("lunatic::metrics", "increment_gauge", increment_gauge)?;
     linker.func_wrap("lunatic::metrics", "decrement_gauge", decrement_gauge)?;
     linker.func_wrap("lunatic::metrics", "histogram", histogram)?;
+    linker.func_wrap("lunatic::metrics", "self_stats", self_stats)?;
     Ok(())
 }
 
@@ -159,3 +165,38 @@ fn histogram<T>(
     histogram!(name, value);
     Ok(())
 }
+
+/// Writes the resource usage of the calling process to **stats_ptr**, so that it can adapt to the
+/// load without an external metrics scraper.
+///
+/// The stats are written as 4 little endian u64 values:
+/// [memory pages | fuel consumed since the last yield | mailbox length | message bytes received]
+///
+/// The process yields to the scheduler each time it consumed the fuel per yield of its
+/// configuration. The message bytes received are the total size of all data messages delivered
+/// to the mailbox.
+///
+/// Traps:
+/// * If any memory outside the guest heap space is referenced.
+fn self_stats<T: ProcessState>(mut caller: Caller<'_, T>, stats_ptr: u32) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let fuel_per_yield = caller
+        .data()
+        .config()
+        .get_fuel_per_yield()
+        .unwrap_or(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+    let fuel_consumed = caller.fuel_consumed().unwrap_or_default() % fuel_per_yield.max(1);
+    let process_stats = caller.data().stats();
+    let mailbox_len = process_stats.snapshot().mailbox_len;
+    let bytes_received = process_stats.bytes_received();
+
+    let mut stats = [0; 32];
+    stats[0..8].copy_from_slice(&memory.size(&caller).to_le_bytes());
+    stats[8..16].copy_from_slice(&fuel_consumed.to_le_bytes());
+    stats[16..24].copy_from_slice(&mailbox_len.to_le_bytes());
+    stats[24..32].copy_from_slice(&bytes_received.to_le_bytes());
+    memory
+        .write(&mut caller, stats_ptr as usize, &stats)
+        .or_trap("lunatic::metrics::self_stats")?;
+    Ok(())
+}
@@ -1,16 +1,21 @@
+use std::collections::VecDeque;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::time::Duration;
 use std::vec::IntoIter;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio::time::timeout;
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::TokioAsyncResolver;
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
-use crate::NetworkingCtx;
+use crate::{check_network_access, NetworkingCtx};
 
 pub struct DnsIterator {
     iter: IntoIter<SocketAddr>,
@@ -30,10 +35,20 @@ impl Iterator for DnsIterator {
     }
 }
 
+/// Records returned by a lookup with a custom resolver, serialized in the format expected by the
+/// guest.
+pub struct DnsRecords {
+    records: VecDeque<Vec<u8>>,
+}
+
+// Number of entries kept in the cache of each resolver. Entries expire with the record's TTL.
+const RESOLVER_CACHE_SIZE: usize = 1024;
+
 // Register DNS networking APIs to the linker
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap4_async("lunatic::networking", "resolve", resolve)?;
     linker.func_wrap(
         "lunatic::networking",
@@ -41,6 +56,19 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
         drop_dns_iterator,
     )?;
     linker.func_wrap("lunatic::networking", "resolve_next", resolve_next)?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "create_dns_resolver",
+        create_dns_resolver,
+    )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "drop_dns_resolver",
+        drop_dns_resolver,
+    )?;
+    linker.func_wrap6_async("lunatic::networking", "resolve_with", resolve_with)?;
+    linker.func_wrap4_async("lunatic::networking", "dns_record_next", dns_record_next)?;
+    linker.func_wrap("lunatic::networking", "drop_dns_records", drop_dns_records)?;
     Ok(())
 }
 
@@ -67,16 +95,21 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
     Box::new(async move {
         let memory = get_memory(&mut caller)?;
-        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
-
-        let buffer = memory_slice
+        let buffer = memory
+            .data(&caller)
             .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
             .or_trap("lunatic::network::resolve")?;
         let name = std::str::from_utf8(buffer)
-            .or_trap("lunatic::network::resolve::not_valid_utf8_string")?;
+            .or_trap("lunatic::network::resolve::not_valid_utf8_string")?
+            .to_string();
+        let (host, port) = split_host_port(&name);
+        if !check_network_access(&mut caller, &memory, host, port, id_u64_ptr)? {
+            return Ok(1);
+        }
+        let state = caller.data_mut();
 
         // Check for timeout during lookup
-        let lookup_host = tokio::net::lookup_host(name);
+        let lookup_host = tokio::net::lookup_host(&name);
         let (iter_or_error_id, result) = if let Ok(result) = match timeout_duration {
             // Without timeout
             u64::MAX => Ok(lookup_host.await),
@@ -85,11 +118,13 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
         } {
             match result {
                 Ok(sockets) => {
-                    // This is a bug in clippy, this collect is not needless
-                    #[allow(clippy::needless_collect)]
-                    let id = state.dns_resources_mut().add(DnsIterator::new(
-                        sockets.collect::<Vec<SocketAddr>>().into_iter(),
-                    ));
+                    let sockets = sockets.collect::<Vec<SocketAddr>>();
+                    for socket in sockets.iter() {
+                        state.add_resolved_host(socket.ip(), host);
+                    }
+                    let id = state
+                        .dns_resources_mut()
+                        .add(DnsIterator::new(sockets.into_iter()));
                     (id, 0)
                 }
                 Err(error) => {
@@ -113,6 +148,23 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
+// Splits a `host:port` name into the host and port. IPv6 addresses can be enclosed in brackets
+// (`[::1]:80`).
+pub(crate) fn split_host_port(name: &str) -> (&str, Option<u16>) {
+    let (host, port) = match name.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) if !host.contains(':') || host.starts_with('[') => (host, Some(port)),
+            _ => (name, None),
+        },
+        None => (name, None),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    (host, port)
+}
+
 // Drops the DNS iterator resource..
 //
 // Traps:
@@ -200,3 +252,276 @@ fn resolve_next<T: NetworkingCtx>(
         None => Ok(1),
     }
 }
+
+// Creates a new DNS resolver that caches the results of lookups, respecting the TTL of the
+// records.
+//
+// **servers_str_ptr** points to a comma separated list of upstream name servers (e.g.
+// `1.1.1.1:53,8.8.8.8:53`), which are queried over UDP and TCP. If the list is empty, the name
+// servers of the host system are used.
+//
+// Returns:
+// * 0 on success - The ID of the resolver is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the list of servers is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn create_dns_resolver<T: NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    servers_str_ptr: u32,
+    servers_str_len: u32,
+    id_u64_ptr: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let servers = memory
+        .data(&caller)
+        .get(servers_str_ptr as usize..(servers_str_ptr + servers_str_len) as usize)
+        .or_trap("lunatic::networking::create_dns_resolver")?;
+    let servers =
+        std::str::from_utf8(servers).or_trap("lunatic::networking::create_dns_resolver")?;
+
+    let resolver = if servers.trim().is_empty() {
+        read_system_conf().map_err(anyhow::Error::from)
+    } else {
+        servers
+            .split(',')
+            .map(|server| server.trim().parse::<SocketAddr>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(anyhow::Error::from)
+            .and_then(|servers| {
+                // Queries are sent to the upstream servers, so they must be accessible
+                for server in servers.iter() {
+                    caller
+                        .data()
+                        .can_access_network(&server.ip().to_string(), Some(server.port()))
+                        .map_err(|error| anyhow!(error))?;
+                }
+                Ok(servers)
+            })
+            .map(|servers| {
+                let mut config = ResolverConfig::new();
+                for server in servers {
+                    config.add_name_server(NameServerConfig::new(server, Protocol::Udp));
+                    config.add_name_server(NameServerConfig::new(server, Protocol::Tcp));
+                }
+                (config, ResolverOpts::default())
+            })
+    }
+    .map(|(config, mut options)| {
+        options.cache_size = RESOLVER_CACHE_SIZE;
+        TokioAsyncResolver::tokio(config, options)
+    });
+
+    let (resolver_or_error_id, result) = match resolver {
+        Ok(resolver) => (
+            caller.data_mut().dns_resolver_resources_mut().add(resolver),
+            0,
+        ),
+        Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+    };
+    memory
+        .write(
+            &mut caller,
+            id_u64_ptr as usize,
+            &resolver_or_error_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::networking::create_dns_resolver")?;
+    Ok(result)
+}
+
+// Drops the DNS resolver resource, together with its cache.
+//
+// Traps:
+// * If the DNS resolver ID doesn't exist.
+fn drop_dns_resolver<T: NetworkingCtx>(mut caller: Caller<T>, resolver_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .dns_resolver_resources_mut()
+        .remove(resolver_id)
+        .or_trap("lunatic::networking::drop_dns_resolver")?;
+    Ok(())
+}
+
+// Looks up the records of **record_type** for the name, using the resolver created with
+// `create_dns_resolver`. Supported record types are A (1), AAAA (28), TXT (16) and SRV (33). The
+// records can be read with `dns_record_next`, each one is serialized as:
+// * A    - 4 bytes of the IPv4 address.
+// * AAAA - 16 bytes of the IPv6 address.
+// * TXT  - The character strings of the record, concatenated.
+// * SRV  - Priority, weight and port as little endian u16 values, followed by the target name.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0 on success - The ID of the records is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the DNS resolver ID doesn't exist.
+// * If the record type is not supported.
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn resolve_with<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    resolver_id: u64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    record_type: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let record_type = match record_type {
+            1 => RecordType::A,
+            16 => RecordType::TXT,
+            28 => RecordType::AAAA,
+            33 => RecordType::SRV,
+            _ => {
+                return Err(anyhow!(
+                    "lunatic::networking::resolve_with: unsupported record type"
+                ))
+            }
+        };
+        let memory = get_memory(&mut caller)?;
+        let name = memory
+            .data(&caller)
+            .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+            .or_trap("lunatic::networking::resolve_with")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::networking::resolve_with::not_valid_utf8_string")?
+            .to_string();
+        if !check_network_access(&mut caller, &memory, &name, None, id_u64_ptr)? {
+            return Ok(1);
+        }
+        let resolver = caller
+            .data()
+            .dns_resolver_resources()
+            .get(resolver_id)
+            .or_trap("lunatic::networking::resolve_with")?
+            .clone();
+
+        let lookup = resolver.lookup(name.as_str(), record_type);
+        let (records_or_error_id, result) = if let Ok(result) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(lookup.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), lookup).await,
+        } {
+            match result {
+                Ok(lookup) => {
+                    for record in lookup.iter() {
+                        match record {
+                            RData::A(a) => caller.data_mut().add_resolved_host(a.0.into(), &name),
+                            RData::AAAA(aaaa) => {
+                                caller.data_mut().add_resolved_host(aaaa.0.into(), &name)
+                            }
+                            _ => (),
+                        }
+                    }
+                    let records = lookup.iter().filter_map(serialize_record).collect();
+                    let id = caller
+                        .data_mut()
+                        .dns_record_resources_mut()
+                        .add(DnsRecords { records });
+                    (id, 0)
+                }
+                Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+            }
+        } else {
+            // Call timed out
+            return Ok(9027);
+        };
+
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &records_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::resolve_with")?;
+        Ok(result)
+    })
+}
+
+// Serializes a record in the format described on `resolve_with`. Records of other types can show
+// up in the answer (e.g. CNAME) and are skipped.
+fn serialize_record(record: &RData) -> Option<Vec<u8>> {
+    match record {
+        RData::A(a) => Some(a.0.octets().to_vec()),
+        RData::AAAA(aaaa) => Some(aaaa.0.octets().to_vec()),
+        RData::TXT(txt) => Some(txt.txt_data().concat()),
+        RData::SRV(srv) => {
+            let mut data = Vec::new();
+            data.extend_from_slice(&srv.priority().to_le_bytes());
+            data.extend_from_slice(&srv.weight().to_le_bytes());
+            data.extend_from_slice(&srv.port().to_le_bytes());
+            data.extend_from_slice(srv.target().to_utf8().as_bytes());
+            Some(data)
+        }
+        _ => None,
+    }
+}
+
+// Writes the next record to the buffer and removes it from the records.
+//
+// Returns:
+// * 0 on success  - The size of the record is written to **size_u64_ptr**
+// * 1 if there are no more records
+// * 2 if the buffer is too small - The size of the record is written to **size_u64_ptr** and
+//                                  the record is kept, so that it can be read with a bigger
+//                                  buffer.
+//
+// Traps:
+// * If the DNS records ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn dns_record_next<T: NetworkingCtx + Send>(
+    mut caller: Caller<T>,
+    records_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+    size_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let records = caller
+            .data_mut()
+            .dns_record_resources_mut()
+            .get_mut(records_id)
+            .or_trap("lunatic::networking::dns_record_next")?;
+        let record = match records.records.front() {
+            Some(record) if record.len() > buf_len as usize => Err(record.len()),
+            Some(_) => Ok(records.records.pop_front().expect("front exists")),
+            None => return Ok(1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        let (size, result) = match record {
+            Ok(record) => {
+                memory
+                    .write(&mut caller, buf_ptr as usize, &record)
+                    .or_trap("lunatic::networking::dns_record_next")?;
+                (record.len() as u64, 0)
+            }
+            Err(size) => (size as u64, 2),
+        };
+        memory
+            .write(&mut caller, size_u64_ptr as usize, &size.to_le_bytes())
+            .or_trap("lunatic::networking::dns_record_next")?;
+        Ok(result)
+    })
+}
+
+// Drops the DNS records resource.
+//
+// Traps:
+// * If the DNS records ID doesn't exist.
+fn drop_dns_records<T: NetworkingCtx>(mut caller: Caller<T>, records_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .dns_record_resources_mut()
+        .remove(records_id)
+        .or_trap("lunatic::networking::drop_dns_records")?;
+    Ok(())
+}
@@ -1,11 +1,20 @@
 mod dns;
+mod poll;
+mod proxy;
 mod tcp;
 mod tls_tcp;
 mod udp;
+#[cfg(unix)]
+mod unix;
 
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::sync::Arc;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use anyhow::Result;
@@ -14,17 +23,23 @@ use lunatic_error_api::ErrorCtx;
 use tokio::io::{split, ReadHalf, WriteHalf};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
 use anyhow::anyhow;
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
 use tokio_rustls::TlsStream;
+use trust_dns_resolver::TokioAsyncResolver;
+use wasi_common::{WasiCtx, WasiFile};
 use wasmtime::Memory;
 use wasmtime::{Caller, Linker};
 
 use lunatic_common_api::IntoTrap;
 
-pub use dns::DnsIterator;
+pub use dns::{DnsIterator, DnsRecords};
+pub use tls_tcp::{load_cert_chain, load_private_key};
 
 pub struct TcpConnection {
     pub reader: Mutex<OwnedReadHalf>,
@@ -44,16 +59,87 @@ pub struct TlsConnection {
     pub read_timeout: Mutex<Option<Duration>>,
     pub write_timeout: Mutex<Option<Duration>>,
     pub peek_timeout: Mutex<Option<Duration>>,
+    // The underlying TCP stream can't be reached anymore after splitting, the addresses are
+    // captured when the connection is created.
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
 }
 
 pub struct TlsListener {
     pub listener: TcpListener,
-    pub certs: Certificate,
-    pub keys: PrivateKey,
+    pub certs: Arc<TlsCertResolver>,
+    pub config: Arc<ServerConfig>,
+}
+
+/// Picks the server certificate based on the SNI hostname sent by the client, falling back to
+/// the default certificate if the hostname is missing or unknown.
+///
+/// Certificates can be replaced while the listener is in use, new handshakes will pick up the
+/// changes.
+pub struct TlsCertResolver {
+    default: RwLock<Arc<CertifiedKey>>,
+    sni: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl TlsCertResolver {
+    pub fn new(certs: Vec<Certificate>, key: &PrivateKey) -> Result<Self> {
+        Ok(TlsCertResolver {
+            default: RwLock::new(certified_key(certs, key)?),
+            sni: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Replaces the certificate used for `hostname`, or the default one if `hostname` is `None`.
+    pub fn set(
+        &self,
+        hostname: Option<&str>,
+        certs: Vec<Certificate>,
+        key: &PrivateKey,
+    ) -> Result<()> {
+        let certified_key = certified_key(certs, key)?;
+        match hostname {
+            Some(hostname) => {
+                self.sni
+                    .write()
+                    .unwrap()
+                    .insert(hostname.to_lowercase(), certified_key);
+            }
+            None => *self.default.write().unwrap() = certified_key,
+        }
+        Ok(())
+    }
+
+    /// Removes the certificate used for `hostname`, returns `false` if there was none.
+    pub fn remove(&self, hostname: &str) -> bool {
+        self.sni
+            .write()
+            .unwrap()
+            .remove(&hostname.to_lowercase())
+            .is_some()
+    }
+}
+
+impl ResolvesServerCert for TlsCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(hostname) = client_hello.server_name() {
+            if let Some(certified_key) = self.sni.read().unwrap().get(hostname) {
+                return Some(certified_key.clone());
+            }
+        }
+        Some(self.default.read().unwrap().clone())
+    }
+}
+
+fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> Result<Arc<CertifiedKey>> {
+    let key = any_supported_type(key)?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
 }
 
 impl TlsConnection {
     pub fn new(sock: TlsStream<TcpStream>) -> TlsConnection {
+        let (tcp_stream, _) = sock.get_ref();
+        let peer_addr = tcp_stream.peer_addr().ok();
+        let local_addr = tcp_stream.local_addr().ok();
         let (read_half, write_half) = split(sock);
         TlsConnection {
             reader: Mutex::new(read_half),
@@ -63,6 +149,8 @@ impl TlsConnection {
             read_timeout: Mutex::new(None),
             write_timeout: Mutex::new(None),
             peek_timeout: Mutex::new(None),
+            peer_addr,
+            local_addr,
         }
     }
 }
@@ -80,26 +168,138 @@ impl TcpConnection {
     }
 }
 
-pub type TcpListenerResources = HashMapId<TcpListener>;
-pub type TlsListenerResources = HashMapId<TlsListener>;
+/// A TCP connection that is established in the background, started with `tcp_connect_start`.
+pub struct TcpConnecting {
+    state: Mutex<ConnectState>,
+}
+
+enum ConnectState {
+    Connecting(JoinHandle<io::Result<TcpStream>>),
+    Done(io::Result<TcpStream>),
+    Taken,
+}
+
+impl TcpConnecting {
+    pub fn new(addr: SocketAddr) -> Self {
+        TcpConnecting {
+            state: Mutex::new(ConnectState::Connecting(tokio::spawn(TcpStream::connect(
+                addr,
+            )))),
+        }
+    }
+
+    // Waits until the connection is established or fails.
+    async fn wait(&self) {
+        let mut state = self.state.lock().await;
+        if let ConnectState::Connecting(handle) = &mut *state {
+            let result = handle
+                .await
+                .unwrap_or_else(|error| Err(io::Error::other(error)));
+            *state = ConnectState::Done(result);
+        }
+    }
+
+    // Waits for the connection and takes it.
+    async fn take(&self) -> io::Result<TcpStream> {
+        self.wait().await;
+        let mut state = self.state.lock().await;
+        match std::mem::replace(&mut *state, ConnectState::Taken) {
+            ConnectState::Done(result) => result,
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Connection was already taken",
+            )),
+        }
+    }
+}
+
+impl Drop for TcpConnecting {
+    fn drop(&mut self) {
+        if let ConnectState::Connecting(handle) = self.state.get_mut() {
+            handle.abort();
+        }
+    }
+}
+
+/// A Unix domain socket connection, split into halves so that reads and writes can happen
+/// concurrently from cloned resources.
+#[cfg(unix)]
+pub struct UnixConnection {
+    pub reader: Mutex<tokio::net::unix::OwnedReadHalf>,
+    pub writer: Mutex<tokio::net::unix::OwnedWriteHalf>,
+    pub read_timeout: Mutex<Option<Duration>>,
+    pub write_timeout: Mutex<Option<Duration>>,
+}
+
+#[cfg(unix)]
+impl UnixConnection {
+    pub fn new(stream: tokio::net::UnixStream) -> Self {
+        let (read_half, write_half) = stream.into_split();
+        UnixConnection {
+            reader: Mutex::new(read_half),
+            writer: Mutex::new(write_half),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        }
+    }
+}
+
+pub type TcpListenerResources = HashMapId<Arc<TcpListener>>;
+pub type TlsListenerResources = HashMapId<Arc<TlsListener>>;
 pub type TcpStreamResources = HashMapId<Arc<TcpConnection>>;
 pub type TlsStreamResources = HashMapId<Arc<TlsConnection>>;
+pub type TcpConnectingResources = HashMapId<Arc<TcpConnecting>>;
+#[cfg(unix)]
+pub type UnixListenerResources = HashMapId<Arc<tokio::net::UnixListener>>;
+#[cfg(unix)]
+pub type UnixStreamResources = HashMapId<Arc<UnixConnection>>;
 pub type UdpResources = HashMapId<Arc<UdpSocket>>;
 pub type DnsResources = HashMapId<DnsIterator>;
+pub type DnsResolverResources = HashMapId<TokioAsyncResolver>;
+pub type DnsRecordResources = HashMapId<DnsRecords>;
 
 pub trait NetworkingCtx {
     fn tcp_listener_resources(&self) -> &TcpListenerResources;
     fn tcp_listener_resources_mut(&mut self) -> &mut TcpListenerResources;
     fn tcp_stream_resources(&self) -> &TcpStreamResources;
     fn tcp_stream_resources_mut(&mut self) -> &mut TcpStreamResources;
+    fn tcp_connecting_resources(&self) -> &TcpConnectingResources;
+    fn tcp_connecting_resources_mut(&mut self) -> &mut TcpConnectingResources;
     fn tls_listener_resources(&self) -> &TlsListenerResources;
     fn tls_listener_resources_mut(&mut self) -> &mut TlsListenerResources;
     fn tls_stream_resources(&self) -> &TlsStreamResources;
     fn tls_stream_resources_mut(&mut self) -> &mut TlsStreamResources;
+    #[cfg(unix)]
+    fn unix_listener_resources(&self) -> &UnixListenerResources;
+    #[cfg(unix)]
+    fn unix_listener_resources_mut(&mut self) -> &mut UnixListenerResources;
+    #[cfg(unix)]
+    fn unix_stream_resources(&self) -> &UnixStreamResources;
+    #[cfg(unix)]
+    fn unix_stream_resources_mut(&mut self) -> &mut UnixStreamResources;
     fn udp_resources(&self) -> &UdpResources;
     fn udp_resources_mut(&mut self) -> &mut UdpResources;
     fn dns_resources(&self) -> &DnsResources;
     fn dns_resources_mut(&mut self) -> &mut DnsResources;
+    fn dns_resolver_resources(&self) -> &DnsResolverResources;
+    fn dns_resolver_resources_mut(&mut self) -> &mut DnsResolverResources;
+    fn dns_record_resources(&self) -> &DnsRecordResources;
+    fn dns_record_resources_mut(&mut self) -> &mut DnsRecordResources;
+    // Unix sockets are created and opened by the host and need to pass the same filesystem
+    // permission check as other file accesses of the process.
+    fn can_access_fs_location(&self, path: &Path) -> Result<(), String>;
+    // Checks the network policy of the process. The host is a name or an IP address and the port
+    // is `None` for DNS lookups.
+    fn can_access_network(&self, host: &str, port: Option<u16>) -> Result<(), String>;
+    // Remembers that the address was resolved from the host name, so that connecting to the
+    // address is checked against the rules for the name.
+    fn add_resolved_host(&mut self, addr: IpAddr, host: &str);
+    // Resolves once a message is waiting in the mailbox, without taking it out. Used by `poll`.
+    fn wait_for_message(&self) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+    // Adds the file to the WASI descriptor table of the process. Used by `wasi_poll_fd`.
+    fn push_wasi_file(&mut self, file: Box<dyn WasiFile>) -> Result<u32>;
+    // The WASI context of the process. `tcp_send_file` reads from its file descriptors.
+    fn wasi_ctx(&self) -> &WasiCtx;
 }
 
 // Register the networking APIs to the linker
@@ -108,12 +308,39 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
 ) -> Result<()> {
     dns::register(linker)?;
     tcp::register(linker)?;
+    poll::register(linker)?;
+    proxy::register(linker)?;
     tls_tcp::register(linker)?;
     udp::register(linker)?;
+    #[cfg(unix)]
+    unix::register(linker)?;
     Ok(())
 }
 
-fn socket_address<T: NetworkingCtx>(
+/// Checks if the process is allowed to access the host. If it isn't, the error ID is written to
+/// **error_id_ptr** and `false` is returned.
+pub fn check_network_access<T: NetworkingCtx + ErrorCtx>(
+    caller: &mut Caller<T>,
+    memory: &Memory,
+    host: &str,
+    port: Option<u16>,
+    error_id_ptr: u32,
+) -> Result<bool> {
+    match caller.data().can_access_network(host, port) {
+        Ok(()) => Ok(true),
+        Err(error) => {
+            let error_id = caller.data_mut().error_resources_mut().add(anyhow!(error));
+            memory
+                .write(caller, error_id_ptr as usize, &error_id.to_le_bytes())
+                .or_trap("lunatic::networking::check_network_access")?;
+            Ok(false)
+        }
+    }
+}
+
+/// Reads a socket address passed by the guest as **addr_type** (4 or 6), the IP address at
+/// **addr_u8_ptr**, port, flow info and scope ID.
+pub fn socket_address<T: NetworkingCtx>(
     caller: &Caller<T>,
     memory: &Memory,
     addr_type: u32,
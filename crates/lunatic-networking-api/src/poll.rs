@@ -0,0 +1,296 @@
+use std::any::Any;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use wasi_common::file::FileType;
+use wasi_common::WasiFile;
+use wasmtime::{Caller, Linker};
+
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+
+use crate::{NetworkingCtx, TcpConnection};
+
+// Interest flags of a poll entry
+const READABLE: u16 = 1;
+const WRITABLE: u16 = 2;
+
+// Resource types that can be polled
+const MAILBOX: u32 = 0;
+const TCP_STREAM: u32 = 1;
+const UDP_SOCKET: u32 = 2;
+const TCP_CONNECTING: u32 = 3;
+#[cfg(unix)]
+const UNIX_STREAM: u32 = 4;
+
+// Size of a poll entry in guest memory
+const ENTRY_SIZE: u32 = 16;
+
+type Readiness = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Register the poll API to the linker
+pub fn register<T: NetworkingCtx + Send + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap3_async("lunatic::networking", "poll", poll)?;
+    linker.func_wrap("lunatic::networking", "wasi_poll_fd", wasi_poll_fd)?;
+    Ok(())
+}
+
+// Waits until at least one of the resources is ready, so that a single process can multiplex
+// many sockets and its mailbox.
+//
+// **entries_ptr** points to an array of **entries_len** entries with the following structure:
+// [0..8 bytes = resource ID as u64; 8..12 bytes = resource type as u32;
+//  12..14 bytes = interest as u16; 14..16 bytes = ready as u16]
+//
+// The resource types are:
+// * 0 - Mailbox of the process (the resource ID is ignored), readable once a message arrives.
+// * 1 - TCP stream
+// * 2 - UDP socket
+// * 3 - Pending TCP connection from `tcp_connect_start`, writable once the connection finished.
+// * 4 - Unix domain socket stream
+//
+// The interest is a combination of the flags 1 (readable) and 2 (writable). Once the function
+// returns, the flags that are ready are written to the ready field of each entry. A socket with a
+// pending error is also reported as ready, the next read or write returns the error. Readiness
+// can be spurious, so reads and writes should use a timeout.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0 if at least one of the entries is ready
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If a resource type is not supported.
+// * If any of the resource IDs doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn poll<T: NetworkingCtx + Send>(
+    mut caller: Caller<T>,
+    entries_ptr: u32,
+    entries_len: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let entries = memory
+            .data(&caller)
+            .get(entries_ptr as usize..(entries_ptr + entries_len * ENTRY_SIZE) as usize)
+            .or_trap("lunatic::networking::poll")?
+            .to_vec();
+
+        // Each interest of each entry is polled separately
+        let mut waiting = Vec::new();
+        for (index, entry) in entries.chunks_exact(ENTRY_SIZE as usize).enumerate() {
+            let id = u64::from_le_bytes(entry[0..8].try_into().expect("exactly 8 bytes"));
+            let type_ = u32::from_le_bytes(entry[8..12].try_into().expect("exactly 4 bytes"));
+            let interest = u16::from_le_bytes(entry[12..14].try_into().expect("exactly 2 bytes"));
+            for flag in [READABLE, WRITABLE] {
+                if interest & flag != 0 {
+                    let readiness = readiness(caller.data(), id, type_, flag)
+                        .or_trap("lunatic::networking::poll")?;
+                    waiting.push((index, flag, readiness, false));
+                }
+            }
+        }
+
+        // All futures are polled on each wake-up, so that every entry that is ready at the same
+        // time is reported.
+        let ready = poll_fn(|cx| {
+            let mut any_ready = false;
+            for (_, _, readiness, ready) in waiting.iter_mut() {
+                if !*ready && readiness.as_mut().poll(cx).is_ready() {
+                    *ready = true;
+                }
+                any_ready |= *ready;
+            }
+            match any_ready {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        });
+        let timed_out = match timeout_duration {
+            // Without timeout
+            u64::MAX => {
+                ready.await;
+                false
+            }
+            // With timeout
+            t => timeout(Duration::from_millis(t), ready).await.is_err(),
+        };
+        if timed_out {
+            return Ok(9027);
+        }
+
+        let mut ready_flags = vec![0u16; entries.len() / ENTRY_SIZE as usize];
+        for (index, flag, _, ready) in waiting {
+            if ready {
+                ready_flags[index] |= flag;
+            }
+        }
+        for (index, flags) in ready_flags.into_iter().enumerate() {
+            let ready_ptr = entries_ptr as usize + index * ENTRY_SIZE as usize + 14;
+            memory
+                .write(&mut caller, ready_ptr, &flags.to_le_bytes())
+                .or_trap("lunatic::networking::poll")?;
+        }
+        Ok(0)
+    })
+}
+
+// Returns a future that resolves once the resource is ready for the interest.
+fn readiness<T: NetworkingCtx>(state: &T, id: u64, type_: u32, interest: u16) -> Result<Readiness> {
+    let readiness: Readiness = match (type_, interest) {
+        (MAILBOX, READABLE) => state.wait_for_message(),
+        // The mailbox is never writable
+        (MAILBOX, _) => Box::pin(std::future::pending()),
+        (TCP_STREAM, READABLE) => {
+            let stream = state
+                .tcp_stream_resources()
+                .get(id)
+                .or_trap("TCP stream")?
+                .clone();
+            Box::pin(async move {
+                let _ = stream.reader.lock().await.readable().await;
+            })
+        }
+        (TCP_STREAM, _) => {
+            let stream = state
+                .tcp_stream_resources()
+                .get(id)
+                .or_trap("TCP stream")?
+                .clone();
+            Box::pin(async move {
+                let _ = stream.writer.lock().await.writable().await;
+            })
+        }
+        (UDP_SOCKET, READABLE) => {
+            let socket = state.udp_resources().get(id).or_trap("UDP socket")?.clone();
+            Box::pin(async move {
+                let _ = socket.readable().await;
+            })
+        }
+        (UDP_SOCKET, _) => {
+            let socket = state.udp_resources().get(id).or_trap("UDP socket")?.clone();
+            Box::pin(async move {
+                let _ = socket.writable().await;
+            })
+        }
+        // A pending connection is never readable
+        (TCP_CONNECTING, READABLE) => Box::pin(std::future::pending()),
+        (TCP_CONNECTING, _) => {
+            let connecting = state
+                .tcp_connecting_resources()
+                .get(id)
+                .or_trap("pending TCP connection")?
+                .clone();
+            Box::pin(async move { connecting.wait().await })
+        }
+        #[cfg(unix)]
+        (UNIX_STREAM, READABLE) => {
+            let stream = state
+                .unix_stream_resources()
+                .get(id)
+                .or_trap("Unix stream")?
+                .clone();
+            Box::pin(async move {
+                let _ = stream.reader.lock().await.readable().await;
+            })
+        }
+        #[cfg(unix)]
+        (UNIX_STREAM, _) => {
+            let stream = state
+                .unix_stream_resources()
+                .get(id)
+                .or_trap("Unix stream")?
+                .clone();
+            Box::pin(async move {
+                let _ = stream.writer.lock().await.writable().await;
+            })
+        }
+        _ => return Err(anyhow!("Unsupported resource type {type_}")),
+    };
+    Ok(readiness)
+}
+
+// Opens a WASI file descriptor for a TCP stream or UDP socket, so that WASI code can wait on it
+// with `poll_oneoff`.
+//
+// The descriptor can only be polled and closed, reading and writing still happens through the
+// lunatic networking API. The resource types are the same as for `poll`, but only 1 (TCP stream)
+// and 2 (UDP socket) are supported.
+//
+// Returns:
+// * The WASI file descriptor.
+//
+// Traps:
+// * If the resource type is not supported.
+// * If the resource ID doesn't exist.
+// * If the WASI descriptor table is full.
+fn wasi_poll_fd<T: NetworkingCtx>(mut caller: Caller<T>, type_: u32, id: u64) -> Result<u32> {
+    let state = caller.data();
+    let socket = match type_ {
+        TCP_STREAM => PollShim::Tcp(
+            state
+                .tcp_stream_resources()
+                .get(id)
+                .or_trap("lunatic::networking::wasi_poll_fd: TCP stream")?
+                .clone(),
+        ),
+        UDP_SOCKET => PollShim::Udp(
+            state
+                .udp_resources()
+                .get(id)
+                .or_trap("lunatic::networking::wasi_poll_fd: UDP socket")?
+                .clone(),
+        ),
+        _ => {
+            return Err(anyhow!(
+                "lunatic::networking::wasi_poll_fd: unsupported resource type {type_}"
+            ))
+        }
+    };
+    caller
+        .data_mut()
+        .push_wasi_file(Box::new(socket))
+        .or_trap("lunatic::networking::wasi_poll_fd")
+}
+
+// Exposes the readiness of a lunatic socket to WASI's `poll_oneoff`.
+enum PollShim {
+    Tcp(Arc<TcpConnection>),
+    Udp(Arc<UdpSocket>),
+}
+
+#[wiggle::async_trait]
+impl WasiFile for PollShim {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn get_filetype(&self) -> Result<FileType, wasi_common::Error> {
+        match self {
+            PollShim::Tcp(_) => Ok(FileType::SocketStream),
+            PollShim::Udp(_) => Ok(FileType::SocketDgram),
+        }
+    }
+    async fn readable(&self) -> Result<(), wasi_common::Error> {
+        match self {
+            PollShim::Tcp(stream) => stream.reader.lock().await.readable().await?,
+            PollShim::Udp(socket) => socket.readable().await?,
+        }
+        Ok(())
+    }
+    async fn writable(&self) -> Result<(), wasi_common::Error> {
+        match self {
+            PollShim::Tcp(stream) => stream.writer.lock().await.writable().await?,
+            PollShim::Udp(socket) => socket.writable().await?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::{rustls, TlsStream};
+use wasmtime::{Caller, Linker, Memory};
+
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_error_api::ErrorCtx;
+
+use crate::dns::split_host_port;
+use crate::tls_tcp::tls_connector;
+use crate::{check_network_access, NetworkingCtx, TcpConnection, TlsConnection};
+
+// Supported proxy protocols
+const SOCKS5: u32 = 0;
+const HTTP_CONNECT: u32 = 1;
+
+// Upper limit for the response headers of a HTTP proxy
+const MAX_HTTP_RESPONSE_SIZE: usize = 8 * 1024;
+
+// Register proxy networking APIs to the linker
+pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
+    linker: &mut Linker<T>,
+) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap9_async(
+        "lunatic::networking",
+        "tcp_connect_via_proxy",
+        tcp_connect_via_proxy,
+    )?;
+    linker.func_wrap11_async(
+        "lunatic::networking",
+        "tls_connect_via_proxy",
+        tls_connect_via_proxy,
+    )?;
+    Ok(())
+}
+
+// Connection through a proxy, as requested by the guest.
+struct ProxyRequest {
+    protocol: u32,
+    proxy: String,
+    host: String,
+    port: u16,
+    // Name of the credentials, the secret itself is only known to the host
+    auth: String,
+}
+
+impl ProxyRequest {
+    #[allow(clippy::too_many_arguments)]
+    fn read<T>(
+        caller: &Caller<T>,
+        memory: &Memory,
+        protocol: u32,
+        proxy_str_ptr: u32,
+        proxy_str_len: u32,
+        target_str_ptr: u32,
+        target_str_len: u32,
+        auth_str_ptr: u32,
+        auth_str_len: u32,
+    ) -> Result<Self> {
+        if protocol != SOCKS5 && protocol != HTTP_CONNECT {
+            return Err(anyhow!("Unsupported proxy protocol {protocol}"));
+        }
+        let string = |ptr: u32, len: u32| -> Result<String> {
+            let bytes = memory
+                .data(caller)
+                .get(ptr as usize..(ptr + len) as usize)
+                .ok_or_else(|| anyhow!("String is outside the guest memory"))?;
+            Ok(std::str::from_utf8(bytes)?.to_string())
+        };
+        let proxy = string(proxy_str_ptr, proxy_str_len)?;
+        let target = string(target_str_ptr, target_str_len)?;
+        let auth = string(auth_str_ptr, auth_str_len)?;
+        let (host, port) = match split_host_port(&target) {
+            (host, Some(port)) => (host.to_string(), port),
+            (_, None) => return Err(anyhow!("Target '{target}' is missing a port")),
+        };
+        Ok(ProxyRequest {
+            protocol,
+            proxy,
+            host,
+            port,
+            auth,
+        })
+    }
+
+    // Both the proxy and the target need to be allowed by the network policy of the process.
+    fn check_network_access<T: NetworkingCtx + ErrorCtx>(
+        &self,
+        caller: &mut Caller<T>,
+        memory: &Memory,
+        error_id_ptr: u32,
+    ) -> Result<bool> {
+        let (proxy_host, proxy_port) = split_host_port(&self.proxy);
+        Ok(
+            check_network_access(caller, memory, proxy_host, proxy_port, error_id_ptr)?
+                && check_network_access(caller, memory, &self.host, Some(self.port), error_id_ptr)?,
+        )
+    }
+
+    // Connects to the proxy and asks it to open a tunnel to the target.
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let credentials = proxy_credentials(&self.auth)?;
+        let mut stream = TcpStream::connect(&self.proxy).await?;
+        match self.protocol {
+            SOCKS5 => socks5_handshake(&mut stream, &self.host, self.port, credentials).await?,
+            _ => http_connect_handshake(&mut stream, &self.host, self.port, credentials).await?,
+        }
+        Ok(stream)
+    }
+}
+
+// Looks up the credentials with the given name in the `LUNATIC_PROXY_AUTH_<NAME>` environment
+// variable of the host, in the `user:password` format. An empty name means no authentication.
+fn proxy_credentials(name: &str) -> io::Result<Option<(String, String)>> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    let variable = format!("LUNATIC_PROXY_AUTH_{name}");
+    let credentials = std::env::var(&variable)
+        .map_err(|_| proxy_error(format!("Proxy credentials `{variable}` are not set")))?;
+    match credentials.split_once(':') {
+        Some((user, password)) => Ok(Some((user.to_string(), password.to_string()))),
+        None => Err(proxy_error(format!(
+            "Proxy credentials `{variable}` are not in the `user:password` format"
+        ))),
+    }
+}
+
+fn proxy_error<S: Into<String>>(message: S) -> io::Error {
+    io::Error::other(message.into())
+}
+
+// Performs the SOCKS5 handshake (RFC 1928) with username/password authentication (RFC 1929).
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> io::Result<()> {
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(proxy_error(
+            "SOCKS5 proxy rejected the authentication method",
+        ));
+    }
+
+    if let Some((user, password)) = credentials {
+        if user.len() > 255 || password.len() > 255 {
+            return Err(proxy_error("SOCKS5 proxy credentials are too long"));
+        }
+        let mut request = vec![1, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(proxy_error("SOCKS5 proxy authentication failed"));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(proxy_error("Target host name is too long"));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(proxy_error("Invalid SOCKS5 proxy reply"));
+    }
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "SOCKS5 proxy failed to connect to the target (reply {})",
+            reply[1]
+        )));
+    }
+    // Skip the bound address and port
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(proxy_error("Invalid SOCKS5 proxy reply")),
+    };
+    let mut bound_addr = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+    Ok(())
+}
+
+// Opens a tunnel with a HTTP `CONNECT` request.
+async fn http_connect_handshake(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> io::Result<()> {
+    let authority = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, password)) = credentials {
+        let token = STANDARD.encode(format!("{user}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read one byte at a time, so that no data from the tunnel is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE_SIZE {
+            return Err(proxy_error("HTTP proxy response is too large"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status = response
+        .split(|&byte| byte == b' ')
+        .nth(1)
+        .and_then(|status| std::str::from_utf8(status).ok())
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| proxy_error("Invalid HTTP proxy response"))?;
+    if !(200..300).contains(&status) {
+        return Err(proxy_error(format!(
+            "HTTP proxy refused to connect to the target (status {status})"
+        )));
+    }
+    Ok(())
+}
+
+// Connects to the target through a proxy. **protocol** is 0 for SOCKS5 and 1 for HTTP `CONNECT`
+// proxies.
+//
+// The proxy and the target are strings in the `host:port` format. **auth_str_ptr** points to the
+// name of the credentials for the proxy, an empty name means no authentication. The credentials
+// are read on the host from the `LUNATIC_PROXY_AUTH_<NAME>` environment variable in the
+// `user:password` format, so that they are never exposed to the guest.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0 on success - The ID of the newly created TCP stream is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the protocol is not supported.
+// * If any of the strings is not a valid utf8 string or the target is missing a port.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn tcp_connect_via_proxy<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    protocol: u32,
+    proxy_str_ptr: u32,
+    proxy_str_len: u32,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    auth_str_ptr: u32,
+    auth_str_len: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let request = ProxyRequest::read(
+            &caller,
+            &memory,
+            protocol,
+            proxy_str_ptr,
+            proxy_str_len,
+            target_str_ptr,
+            target_str_len,
+            auth_str_ptr,
+            auth_str_len,
+        )
+        .or_trap("lunatic::networking::tcp_connect_via_proxy")?;
+        if !request.check_network_access(&mut caller, &memory, id_u64_ptr)? {
+            return Ok(1);
+        }
+
+        let connect = request.connect();
+        let result = match timeout_duration {
+            // Without timeout
+            u64::MAX => connect.await,
+            // With timeout
+            t => match timeout(Duration::from_millis(t), connect).await {
+                Ok(result) => result,
+                // Call timed out
+                Err(_) => return Ok(9027),
+            },
+        };
+
+        let (stream_or_error_id, result) = match result {
+            Ok(stream) => (
+                caller
+                    .data_mut()
+                    .tcp_stream_resources_mut()
+                    .add(Arc::new(TcpConnection::new(stream))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::tcp_connect_via_proxy")?;
+        Ok(result)
+    })
+}
+
+// Same as `tcp_connect_via_proxy`, but performs a TLS handshake with the target through the
+// tunnel. The certificates are passed the same way as to `tls_connect`.
+//
+// Returns:
+// * 0 on success - The ID of the newly created TLS stream is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the protocol is not supported.
+// * If any of the strings is not a valid utf8 string or the target is missing a port.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn tls_connect_via_proxy<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    protocol: u32,
+    proxy_str_ptr: u32,
+    proxy_str_len: u32,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    auth_str_ptr: u32,
+    auth_str_len: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+    certs_array_ptr: u32,
+    certs_array_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let request = ProxyRequest::read(
+            &caller,
+            &memory,
+            protocol,
+            proxy_str_ptr,
+            proxy_str_len,
+            target_str_ptr,
+            target_str_len,
+            auth_str_ptr,
+            auth_str_len,
+        )
+        .or_trap("lunatic::networking::tls_connect_via_proxy")?;
+        let connector = tls_connector(&caller, &memory, certs_array_ptr, certs_array_len)?;
+        if !request.check_network_access(&mut caller, &memory, id_u64_ptr)? {
+            return Ok(1);
+        }
+
+        let connect = async {
+            let stream = request.connect().await?;
+            let domain = rustls::ServerName::try_from(request.host.as_str())
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+            connector.connect(domain, stream).await
+        };
+        let result = match timeout_duration {
+            // Without timeout
+            u64::MAX => connect.await,
+            // With timeout
+            t => match timeout(Duration::from_millis(t), connect).await {
+                Ok(result) => result,
+                // Call timed out
+                Err(_) => return Ok(9027),
+            },
+        };
+
+        let (stream_or_error_id, result) = match result {
+            Ok(stream) => (
+                caller
+                    .data_mut()
+                    .tls_stream_resources_mut()
+                    .add(Arc::new(TlsConnection::new(TlsStream::Client(stream)))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::tls_connect_via_proxy")?;
+        Ok(result)
+    })
+}
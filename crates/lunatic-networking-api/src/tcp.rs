@@ -1,37 +1,83 @@
 use std::convert::TryInto;
 use std::future::Future;
-use std::io::IoSlice;
+use std::io::{self, IoSlice};
+use std::net::{Shutdown, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
-use tokio::time::timeout;
+use anyhow::{anyhow, Result};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::time::{error::Elapsed, timeout};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpSocket, TcpStream},
 };
+use wasi_common::snapshots::preview_1::{types::Fd, wasi_snapshot_preview1::WasiSnapshotPreview1};
+use wasi_common::WasiCtx;
 use wasmtime::{Caller, Linker};
+use wiggle::{wasmtime::WasmtimeGuestMemory, GuestPtr};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 use crate::dns::DnsIterator;
-use crate::{socket_address, NetworkingCtx, TcpConnection};
+
+// Size of the chunks `tcp_send_file` reads from the file
+const SEND_FILE_CHUNK_SIZE: usize = 64 * 1024;
+// Size of a WASI `iovec`, a pointer and a length
+const IOVEC_SIZE: usize = 8;
+use crate::{check_network_access, socket_address, NetworkingCtx, TcpConnecting, TcpConnection};
 
 // Register TCP networking APIs to the linker
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap6_async("lunatic::networking", "tcp_bind", tcp_bind)?;
+    linker.func_wrap8_async(
+        "lunatic::networking",
+        "tcp_bind_with_options",
+        tcp_bind_with_options,
+    )?;
     linker.func_wrap(
         "lunatic::networking",
         "drop_tcp_listener",
         drop_tcp_listener,
     )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "clone_tcp_listener",
+        clone_tcp_listener,
+    )?;
     linker.func_wrap("lunatic::networking", "tcp_local_addr", tcp_local_addr)?;
     linker.func_wrap3_async("lunatic::networking", "tcp_accept", tcp_accept)?;
+    linker.func_wrap4_async(
+        "lunatic::networking",
+        "tcp_accept_with_timeout",
+        tcp_accept_with_timeout,
+    )?;
     linker.func_wrap7_async("lunatic::networking", "tcp_connect", tcp_connect)?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "tcp_connect_start",
+        tcp_connect_start,
+    )?;
+    linker.func_wrap2_async(
+        "lunatic::networking",
+        "tcp_connect_finish",
+        tcp_connect_finish,
+    )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "drop_tcp_connecting",
+        drop_tcp_connecting,
+    )?;
     linker.func_wrap2_async("lunatic::networking", "tcp_peer_addr", tcp_peer_addr)?;
+    linker.func_wrap2_async(
+        "lunatic::networking",
+        "tcp_stream_local_addr",
+        tcp_stream_local_addr,
+    )?;
     linker.func_wrap("lunatic::networking", "drop_tcp_stream", drop_tcp_stream)?;
     linker.func_wrap("lunatic::networking", "clone_tcp_stream", clone_tcp_stream)?;
     linker.func_wrap4_async(
@@ -56,6 +102,25 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     )?;
     linker.func_wrap1_async("lunatic::networking", "get_peek_timeout", get_peek_timeout)?;
     linker.func_wrap2_async("lunatic::networking", "tcp_flush", tcp_flush)?;
+    linker.func_wrap5_async("lunatic::networking", "tcp_send_file", tcp_send_file)?;
+    linker.func_wrap3_async("lunatic::networking", "tcp_shutdown", tcp_shutdown)?;
+    linker.func_wrap3_async("lunatic::networking", "tcp_set_nodelay", tcp_set_nodelay)?;
+    linker.func_wrap4_async(
+        "lunatic::networking",
+        "tcp_set_keepalive",
+        tcp_set_keepalive,
+    )?;
+    linker.func_wrap3_async("lunatic::networking", "tcp_set_linger", tcp_set_linger)?;
+    linker.func_wrap3_async(
+        "lunatic::networking",
+        "tcp_set_send_buffer_size",
+        tcp_set_send_buffer_size,
+    )?;
+    linker.func_wrap3_async(
+        "lunatic::networking",
+        "tcp_set_recv_buffer_size",
+        tcp_set_recv_buffer_size,
+    )?;
     Ok(())
 }
 
@@ -93,7 +158,10 @@ fn tcp_bind<T: NetworkingCtx + ErrorCtx + Send>(
         )?;
         let (tcp_listener_or_error_id, result) = match TcpListener::bind(socket_addr).await {
             Ok(listener) => (
-                caller.data_mut().tcp_listener_resources_mut().add(listener),
+                caller
+                    .data_mut()
+                    .tcp_listener_resources_mut()
+                    .add(Arc::new(listener)),
                 0,
             ),
             Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
@@ -110,6 +178,97 @@ fn tcp_bind<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
+// Listener options for `tcp_bind_with_options`
+const REUSEADDR: u32 = 1;
+const REUSEPORT: u32 = 2;
+const V6ONLY: u32 = 4;
+
+// Same as `tcp_bind`, but allows configuring the listener socket before binding.
+//
+// **backlog** is the maximum number of pending connections. **flags** is a combination of:
+// * 1 - Set `SO_REUSEADDR`, so that the address can be bound again while old connections are in
+//       the `TIME_WAIT` state.
+// * 2 - Set `SO_REUSEPORT`, so that multiple listeners can bind the same address and incoming
+//       connections are balanced between them. Only supported on Unix platforms.
+// * 4 - Set `IPV6_V6ONLY`, so that an IPv6 listener doesn't accept IPv4 connections.
+//
+// Returns:
+// * 0 on success - The ID of the newly created TCP listener is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If **addr_type** is neither 4 or 6.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn tcp_bind_with_options<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    addr_type: u32,
+    addr_u8_ptr: u32,
+    port: u32,
+    flow_info: u32,
+    scope_id: u32,
+    backlog: u32,
+    flags: u32,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let socket_addr = socket_address(
+            &caller,
+            &memory,
+            addr_type,
+            addr_u8_ptr,
+            port,
+            flow_info,
+            scope_id,
+        )?;
+
+        let listener = (|| {
+            let socket = match socket_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if flags & REUSEADDR != 0 {
+                socket.set_reuseaddr(true)?;
+            }
+            if flags & REUSEPORT != 0 {
+                #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+                socket.set_reuseport(true)?;
+                #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SO_REUSEPORT is not supported on this platform",
+                ));
+            }
+            if flags & V6ONLY != 0 {
+                SockRef::from(&socket).set_only_v6(true)?;
+            }
+            socket.bind(socket_addr)?;
+            socket.listen(backlog)
+        })();
+
+        let (tcp_listener_or_error_id, result) = match listener {
+            Ok(listener) => (
+                caller
+                    .data_mut()
+                    .tcp_listener_resources_mut()
+                    .add(Arc::new(listener)),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &tcp_listener_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::tcp_bind_with_options")?;
+
+        Ok(result)
+    })
+}
+
 // Drops the TCP listener resource.
 //
 // Traps:
@@ -123,6 +282,27 @@ fn drop_tcp_listener<T: NetworkingCtx>(mut caller: Caller<T>, tcp_listener_id: u
     Ok(())
 }
 
+// Clones a TCP listener returning the ID of the clone.
+//
+// Clones accept connections on the same socket. Sending clones to multiple processes lets them
+// share the listener, e.g. in a pool of workers.
+//
+// Traps:
+// * If the TCP listener ID doesn't exist.
+fn clone_tcp_listener<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    tcp_listener_id: u64,
+) -> Result<u64> {
+    let listener = caller
+        .data()
+        .tcp_listener_resources()
+        .get(tcp_listener_id)
+        .or_trap("lunatic::networking::clone_tcp_listener")?
+        .clone();
+    let id = caller.data_mut().tcp_listener_resources_mut().add(listener);
+    Ok(id)
+}
+
 // Returns the local address that this listener is bound to as an DNS iterator with just one
 // element.
 // * 0 on success - The local address that this listener is bound to is returned as an DNS
@@ -176,8 +356,39 @@ fn tcp_local_addr<T: NetworkingCtx + ErrorCtx>(
 // * If the tcp listener ID doesn't exist.
 // * If any memory outside the guest heap space is referenced.
 fn tcp_accept<T: NetworkingCtx + ErrorCtx + Send>(
+    caller: Caller<T>,
+    listener_id: u64,
+    id_u64_ptr: u32,
+    socket_addr_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    tcp_accept_with_timeout(
+        caller,
+        listener_id,
+        u64::MAX,
+        id_u64_ptr,
+        socket_addr_id_ptr,
+    )
+}
+
+// Same as `tcp_accept`, but gives up waiting for a connection after the timeout.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0 on success - The ID of the newly created TCP stream is written to **id_u64_ptr** and the
+//                  peer address is returned as an DNS iterator with just one element and written
+//                  to **peer_addr_dns_iter_id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the tcp listener ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_accept_with_timeout<T: NetworkingCtx + ErrorCtx + Send>(
     mut caller: Caller<T>,
     listener_id: u64,
+    timeout_duration: u64,
     id_u64_ptr: u32,
     socket_addr_id_ptr: u32,
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
@@ -186,9 +397,22 @@ fn tcp_accept<T: NetworkingCtx + ErrorCtx + Send>(
             .data()
             .tcp_listener_resources()
             .get(listener_id)
-            .or_trap("lunatic::network::tcp_accept")?;
+            .or_trap("lunatic::network::tcp_accept")?
+            .clone();
+
+        let accept = tcp_listener.accept();
+        let accepted = match timeout_duration {
+            // Without timeout
+            u64::MAX => accept.await,
+            // With timeout
+            t => match timeout(Duration::from_millis(t), accept).await {
+                Ok(accepted) => accepted,
+                // Call timed out
+                Err(_) => return Ok(9027),
+            },
+        };
 
-        let (tcp_stream_or_error_id, peer_addr_iter, result) = match tcp_listener.accept().await {
+        let (tcp_stream_or_error_id, peer_addr_iter, result) = match accepted {
             Ok((stream, socket_addr)) => {
                 let stream_id = caller
                     .data_mut()
@@ -259,6 +483,11 @@ fn tcp_connect<T: NetworkingCtx + ErrorCtx + Send>(
             flow_info,
             scope_id,
         )?;
+        let ip = socket_addr.ip().to_string();
+        let port = Some(socket_addr.port());
+        if !check_network_access(&mut caller, &memory, &ip, port, id_u64_ptr)? {
+            return Ok(1);
+        }
 
         let connect = TcpStream::connect(socket_addr);
         if let Ok(result) = match timeout_duration {
@@ -293,6 +522,115 @@ fn tcp_connect<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
+// Starts connecting to the address in the background and returns right away.
+//
+// The pending connection becomes writable for `poll` once the connection is established or
+// failed. `tcp_connect_finish` turns it into a TCP stream.
+//
+// Returns:
+// * 0 on success - The ID of the pending connection is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If **addr_type** is neither 4 or 6.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_connect_start<T: NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    addr_type: u32,
+    addr_u8_ptr: u32,
+    port: u32,
+    flow_info: u32,
+    scope_id: u32,
+    id_u64_ptr: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let socket_addr = socket_address(
+        &caller,
+        &memory,
+        addr_type,
+        addr_u8_ptr,
+        port,
+        flow_info,
+        scope_id,
+    )?;
+    let ip = socket_addr.ip().to_string();
+    let port = Some(socket_addr.port());
+    if !check_network_access(&mut caller, &memory, &ip, port, id_u64_ptr)? {
+        return Ok(1);
+    }
+
+    let connecting_id = caller
+        .data_mut()
+        .tcp_connecting_resources_mut()
+        .add(Arc::new(TcpConnecting::new(socket_addr)));
+    memory
+        .write(
+            &mut caller,
+            id_u64_ptr as usize,
+            &connecting_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::networking::tcp_connect_start")?;
+    Ok(0)
+}
+
+// Waits for the pending connection and turns it into a TCP stream. The pending connection
+// resource is consumed.
+//
+// Returns:
+// * 0 on success - The ID of the newly created TCP stream is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the pending connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_connect_finish<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connecting_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connecting = caller
+            .data_mut()
+            .tcp_connecting_resources_mut()
+            .remove(connecting_id)
+            .or_trap("lunatic::networking::tcp_connect_finish")?;
+
+        let (stream_or_error_id, result) = match connecting.take().await {
+            Ok(stream) => (
+                caller
+                    .data_mut()
+                    .tcp_stream_resources_mut()
+                    .add(Arc::new(TcpConnection::new(stream))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::tcp_connect_finish")?;
+        Ok(result)
+    })
+}
+
+// Drops the pending connection resource, aborting the connection attempt.
+//
+// Traps:
+// * If the pending connection ID doesn't exist.
+fn drop_tcp_connecting<T: NetworkingCtx>(mut caller: Caller<T>, connecting_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .tcp_connecting_resources_mut()
+        .remove(connecting_id)
+        .or_trap("lunatic::networking::drop_tcp_connecting")?;
+    Ok(())
+}
+
 // Drops the TCP stream resource..
 //
 // Traps:
@@ -367,6 +705,52 @@ fn tcp_peer_addr<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
+// Returns the local address of this tcp stream, bound to a DNS iterator with just one element.
+//
+// * 0 on success - The local address of the stream, returned as a DNS iterator with just one
+//                  element and written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**.
+//
+// Traps:
+// * If the tcp stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_stream_local_addr<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    tcp_stream_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let tcp_stream = caller
+            .data()
+            .tcp_stream_resources()
+            .get(tcp_stream_id)
+            .or_trap("lunatic::network::tcp_stream_local_addr: stream ID doesn't exist")?
+            .clone();
+        let local_addr = tcp_stream.writer.lock().await.local_addr();
+        let (dns_iter_or_error_id, result) = match local_addr {
+            Ok(socket_addr) => {
+                let dns_iter_id = caller
+                    .data_mut()
+                    .dns_resources_mut()
+                    .add(DnsIterator::new(vec![socket_addr].into_iter()));
+                (dns_iter_id, 0)
+            }
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &dns_iter_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::network::tcp_stream_local_addr")?;
+
+        Ok(result)
+    })
+}
+
 // Gathers data from the vector buffers and writes them to the stream. **ciovec_array_ptr** points
 // to an array of (ciovec_ptr, ciovec_len) pairs where each pair represents a buffer to be written.
 //
@@ -751,3 +1135,339 @@ fn tcp_flush<T: NetworkingCtx + ErrorCtx + Send>(
         Ok(result)
     })
 }
+
+// Copies **len** bytes of the file opened as the WASI file descriptor **fd**, starting at
+// **offset**, to the TCP stream. The data is copied entirely on the host, without passing through
+// the guest memory. If **len** is `u64::MAX` everything until the end of the file is sent.
+//
+// The file is read the same way as with WASI's `fd_pread`, so the descriptor needs the rights to
+// read and seek. The position of the descriptor doesn't change. The write timeout of the stream
+// applies to the whole transfer.
+//
+// Returns:
+// * 0 on success - The number of bytes sent is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_send_file<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    fd: u32,
+    offset: u64,
+    len: u64,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .tcp_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::tcp_send_file")?
+            .clone();
+
+        let mut wasi = caller.data().wasi_ctx().clone();
+        let send_file = async {
+            let write_timeout = stream.write_timeout.lock().await;
+            let mut writer = stream.writer.lock().await;
+            let copy = async {
+                let mut buffer = vec![0; IOVEC_SIZE + SEND_FILE_CHUNK_SIZE];
+                let mut sent = 0;
+                while sent < len {
+                    let chunk = (len - sent).min(SEND_FILE_CHUNK_SIZE as u64) as u32;
+                    let position = offset.saturating_add(sent);
+                    let read = read_wasi_file(&mut wasi, fd, position, &mut buffer, chunk).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    writer
+                        .write_all(&buffer[IOVEC_SIZE..IOVEC_SIZE + read as usize])
+                        .await?;
+                    sent += read as u64;
+                }
+                Ok::<_, anyhow::Error>(sent)
+            };
+            match *write_timeout {
+                Some(write_timeout) => timeout(write_timeout, copy).await?,
+                None => copy.await,
+            }
+        };
+
+        let (opaque, return_) = match send_file.await {
+            Ok(sent) => (sent, 0),
+            Err(error) if error.is::<Elapsed>() => return Ok(9027),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::networking::tcp_send_file")?;
+        Ok(return_)
+    })
+}
+
+// Reads up to **len** bytes at **offset** of the WASI file descriptor into **buffer**, right after
+// the `iovec` pointing to them. Going through `fd_pread` applies the rights of the descriptor and
+// the restrictions of its preopened directory, the same as if the process read the file itself.
+async fn read_wasi_file(
+    wasi: &mut WasiCtx,
+    fd: u32,
+    offset: u64,
+    buffer: &mut [u8],
+    len: u32,
+) -> Result<u32> {
+    buffer[..4].copy_from_slice(&(IOVEC_SIZE as u32).to_le_bytes());
+    buffer[4..IOVEC_SIZE].copy_from_slice(&len.to_le_bytes());
+    let memory = WasmtimeGuestMemory::new(buffer);
+    let iovs = GuestPtr::new(&memory, (0, 1));
+    Ok(wasi.fd_pread(Fd::from(fd), &iovs, offset).await?)
+}
+
+// Runs **f** on the socket of the TCP stream and writes the error ID to **error_id_ptr** if it
+// fails.
+//
+// The socket is shared between both halves of the stream, **f** is called while holding the
+// writer lock.
+async fn with_socket<T, F>(
+    caller: &mut Caller<'_, T>,
+    stream_id: u64,
+    error_id_ptr: u32,
+    trap: &str,
+    f: F,
+) -> Result<u32>
+where
+    T: NetworkingCtx + ErrorCtx + Send,
+    F: FnOnce(SockRef) -> io::Result<()>,
+{
+    let stream = caller
+        .data()
+        .tcp_stream_resources()
+        .get(stream_id)
+        .or_trap(trap)?
+        .clone();
+    let writer = stream.writer.lock().await;
+    let result = f(SockRef::from(writer.as_ref()));
+    drop(writer);
+
+    let (error_id, result) = match result {
+        Ok(()) => (0, 0),
+        Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+    };
+
+    let memory = get_memory(caller)?;
+    memory
+        .write(caller, error_id_ptr as usize, &error_id.to_le_bytes())
+        .or_trap(trap)?;
+    Ok(result)
+}
+
+// Shuts down the read (**how** = 0), write (**how** = 1) or both halves (**how** = 2) of the
+// stream. After shutting down the write half, the peer will read an end of file, but data can
+// still be read from the stream.
+//
+// The shutdown affects all clones of the stream.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If **how** is not 0, 1 or 2.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_shutdown<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    how: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let how = match how {
+            0 => Shutdown::Read,
+            1 => Shutdown::Write,
+            2 => Shutdown::Both,
+            _ => {
+                return Err(anyhow!(
+                    "lunatic::networking::tcp_shutdown: invalid `how` value"
+                ))
+            }
+        };
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_shutdown",
+            |socket| socket.shutdown(how),
+        )
+        .await
+    })
+}
+
+// Enables (**nodelay** != 0) or disables the `TCP_NODELAY` option on the stream. With the
+// option enabled, Nagle's algorithm is disabled and small writes are sent out immediately.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_set_nodelay<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    nodelay: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_set_nodelay",
+            |socket| socket.set_nodelay(nodelay != 0),
+        )
+        .await
+    })
+}
+
+// Enables TCP keepalive on the stream. The first probe is sent after the connection was idle for
+// **interval** milliseconds, and is repeated every **interval** milliseconds up to **retries**
+// times before the connection is dropped. If **interval** is `u64::MAX` keepalive is disabled.
+//
+// The probe interval is ignored on platforms that don't support configuring it, the same is
+// true for the number of retries.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_set_keepalive<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    interval: u64,
+    retries: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_set_keepalive",
+            |socket| {
+                // a way to disable keepalive
+                if interval == u64::MAX {
+                    return socket.set_keepalive(false);
+                }
+                let interval = Duration::from_millis(interval);
+                let keepalive = TcpKeepalive::new().with_time(interval);
+                #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+                let keepalive = keepalive.with_interval(interval);
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                let keepalive = keepalive.with_retries(retries);
+                #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+                let _ = retries;
+                socket.set_tcp_keepalive(&keepalive)
+            },
+        )
+        .await
+    })
+}
+
+// Sets the `SO_LINGER` option on the stream to **linger** milliseconds. If **linger** is
+// `u64::MAX` the option is disabled and closing the stream returns immediately.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_set_linger<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    linger: u64,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_set_linger",
+            |socket| {
+                // a way to disable linger
+                let linger = match linger {
+                    u64::MAX => None,
+                    linger => Some(Duration::from_millis(linger)),
+                };
+                socket.set_linger(linger)
+            },
+        )
+        .await
+    })
+}
+
+// Sets the size of the send buffer (`SO_SNDBUF`) of the stream to **size** bytes. The operating
+// system may adjust the value.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_set_send_buffer_size<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    size: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_set_send_buffer_size",
+            |socket| socket.set_send_buffer_size(size as usize),
+        )
+        .await
+    })
+}
+
+// Sets the size of the receive buffer (`SO_RCVBUF`) of the stream to **size** bytes. The
+// operating system may adjust the value.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tcp_set_recv_buffer_size<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    size: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        with_socket(
+            &mut caller,
+            stream_id,
+            error_id_ptr,
+            "lunatic::networking::tcp_set_recv_buffer_size",
+            |socket| socket.set_recv_buffer_size(size as usize),
+        )
+        .await
+    })
+}
@@ -1,6 +1,7 @@
 use std::convert::TryInto;
 use std::future::Future;
 use std::io::{self, IoSlice};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -10,14 +11,17 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
-use wasmtime::{Caller, Linker};
+use wasmtime::{Caller, Linker, Memory};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 use webpki::TrustAnchor;
 
 use crate::dns::DnsIterator;
-use crate::{socket_address, NetworkingCtx, TlsConnection, TlsListener};
+use crate::{
+    check_network_access, socket_address, NetworkingCtx, TlsCertResolver, TlsConnection,
+    TlsListener,
+};
 use tokio_rustls::rustls::{self, OwnedTrustAnchor};
 use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
 
@@ -25,13 +29,35 @@ use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap10_async("lunatic::networking", "tls_bind", tls_bind)?;
     linker.func_wrap(
         "lunatic::networking",
         "drop_tls_listener",
         drop_tls_listener,
     )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "clone_tls_listener",
+        clone_tls_listener,
+    )?;
     linker.func_wrap("lunatic::networking", "tls_local_addr", tls_local_addr)?;
+    linker.func_wrap("lunatic::networking", "tls_peer_addr", tls_peer_addr)?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "tls_stream_local_addr",
+        tls_stream_local_addr,
+    )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "tls_listener_reload_certs",
+        tls_listener_reload_certs,
+    )?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "tls_listener_remove_sni_cert",
+        tls_listener_remove_sni_cert,
+    )?;
     linker.func_wrap3_async("lunatic::networking", "tls_accept", tls_accept)?;
     linker.func_wrap7_async("lunatic::networking", "tls_connect", tls_connect)?;
     linker.func_wrap("lunatic::networking", "drop_tls_stream", drop_tls_stream)?;
@@ -63,6 +89,7 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
         get_tls_write_timeout,
     )?;
     linker.func_wrap2_async("lunatic::networking", "tls_flush", tls_flush)?;
+    linker.func_wrap2_async("lunatic::networking", "tls_shutdown", tls_shutdown)?;
     Ok(())
 }
 
@@ -109,6 +136,96 @@ fn tls_local_addr<T: NetworkingCtx + ErrorCtx>(
     Ok(result)
 }
 
+// Returns the remote address this tls stream is connected to, bound to a DNS iterator with just
+// one element.
+//
+// * 0 on success - The peer address of the stream, returned as a DNS iterator with just one
+//                  element and written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**.
+//
+// Traps:
+// * If the tls stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tls_peer_addr<T: NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    tls_stream_id: u64,
+    id_u64_ptr: u32,
+) -> Result<u32> {
+    let peer_addr = caller
+        .data()
+        .tls_stream_resources()
+        .get(tls_stream_id)
+        .or_trap("lunatic::network::tls_peer_addr: stream ID doesn't exist")?
+        .peer_addr;
+    stream_addr_result(
+        &mut caller,
+        peer_addr,
+        id_u64_ptr,
+        "lunatic::network::tls_peer_addr",
+    )
+}
+
+// Returns the local address of this tls stream, bound to a DNS iterator with just one element.
+//
+// * 0 on success - The local address of the stream, returned as a DNS iterator with just one
+//                  element and written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**.
+//
+// Traps:
+// * If the tls stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tls_stream_local_addr<T: NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    tls_stream_id: u64,
+    id_u64_ptr: u32,
+) -> Result<u32> {
+    let local_addr = caller
+        .data()
+        .tls_stream_resources()
+        .get(tls_stream_id)
+        .or_trap("lunatic::network::tls_stream_local_addr: stream ID doesn't exist")?
+        .local_addr;
+    stream_addr_result(
+        &mut caller,
+        local_addr,
+        id_u64_ptr,
+        "lunatic::network::tls_stream_local_addr",
+    )
+}
+
+// Writes the address as a DNS iterator, or an error if the address could not be determined when
+// the connection was established.
+fn stream_addr_result<T: NetworkingCtx + ErrorCtx>(
+    caller: &mut Caller<T>,
+    addr: Option<SocketAddr>,
+    id_u64_ptr: u32,
+    trap: &str,
+) -> Result<u32> {
+    let (dns_iter_or_error_id, result) = match addr {
+        Some(socket_addr) => {
+            let dns_iter_id = caller
+                .data_mut()
+                .dns_resources_mut()
+                .add(DnsIterator::new(vec![socket_addr].into_iter()));
+            (dns_iter_id, 0)
+        }
+        None => {
+            let error = io::Error::from(io::ErrorKind::NotConnected);
+            (caller.data_mut().error_resources_mut().add(error.into()), 1)
+        }
+    };
+
+    let memory = get_memory(caller)?;
+    memory
+        .write(
+            caller,
+            id_u64_ptr as usize,
+            &dns_iter_or_error_id.to_le_bytes(),
+        )
+        .or_trap(trap)?;
+    Ok(result)
+}
+
 // Creates a new TLS listener, which will be bound to the specified address. The returned listener
 // is ready for accepting connections.
 //
@@ -150,8 +267,16 @@ fn tls_bind<T: NetworkingCtx + ErrorCtx + Send>(
             .to_vec();
         let keys = load_private_key(&keys)
             .or_trap("lunatic::networking::tls_bind::failed to unpack the keys")?;
-        let certs = load_certs(&certs)
+        let certs = load_cert_chain(&certs)
             .or_trap("lunatic::networking::tls_bind::failed to unpack the certs")?;
+        let resolver = Arc::new(
+            TlsCertResolver::new(certs, &keys)
+                .or_trap("lunatic::networking::tls_bind::unsupported private key")?,
+        );
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
         let socket_addr = socket_address(
             &caller,
             &memory,
@@ -166,11 +291,11 @@ fn tls_bind<T: NetworkingCtx + ErrorCtx + Send>(
                 caller
                     .data_mut()
                     .tls_listener_resources_mut()
-                    .add(TlsListener {
+                    .add(Arc::new(TlsListener {
                         listener,
-                        keys,
-                        certs,
-                    }),
+                        certs: resolver,
+                        config: Arc::new(config),
+                    })),
                 0,
             ),
             Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
@@ -200,6 +325,27 @@ fn drop_tls_listener<T: NetworkingCtx>(mut caller: Caller<T>, tls_listener_id: u
     Ok(())
 }
 
+// Clones a TLS listener returning the ID of the clone.
+//
+// Clones accept connections on the same socket. Sending clones to multiple processes lets them
+// share the listener, e.g. in a pool of workers.
+//
+// Traps:
+// * If the TLS listener ID doesn't exist.
+fn clone_tls_listener<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    tls_listener_id: u64,
+) -> Result<u64> {
+    let listener = caller
+        .data()
+        .tls_listener_resources()
+        .get(tls_listener_id)
+        .or_trap("lunatic::networking::clone_tls_listener")?
+        .clone();
+    let id = caller.data_mut().tls_listener_resources_mut().add(listener);
+    Ok(id)
+}
+
 // Returns:
 // * 0 on success - The ID of the newly created TLS stream is written to **id_u64_ptr** and the
 //                  peer address is returned as an DNS iterator with just one element and written
@@ -220,20 +366,13 @@ fn tls_accept<T: NetworkingCtx + ErrorCtx + Send>(
             .data()
             .tls_listener_resources()
             .get(listener_id)
-            .or_trap("lunatic::network::tls_accept")?;
-        let keys = tls_listener.keys.clone();
-        let certs = tls_listener.certs.clone();
+            .or_trap("lunatic::network::tls_accept")?
+            .clone();
 
         let (tls_stream_or_error_id, peer_addr_iter, result) =
             match tls_listener.listener.accept().await {
                 Ok((stream, socket_addr)) => {
-                    let config = rustls::ServerConfig::builder()
-                        .with_safe_defaults()
-                        .with_no_client_auth()
-                        .with_single_cert(vec![certs], keys)
-                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
-                        .or_trap("lunatic::network::tls_accept server_config")?;
-                    let acceptor = TlsAcceptor::from(Arc::new(config));
+                    let acceptor = TlsAcceptor::from(tls_listener.config.clone());
                     let stream = acceptor
                         .accept(stream)
                         .await
@@ -274,17 +413,14 @@ fn tls_accept<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
-// Load private key from file.
-fn load_private_key(file: &[u8]) -> io::Result<rustls::PrivateKey> {
+/// Load private key from file.
+pub fn load_private_key(file: &[u8]) -> io::Result<rustls::PrivateKey> {
     let mut reader = io::BufReader::new(file);
 
     // Load and return a single private key.
     let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
     if keys.len() != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "expected a single private key",
-        ));
+        return Err(io::Error::other("expected a single private key"));
     }
 
     Ok(rustls::PrivateKey(keys[0].clone()))
@@ -294,15 +430,146 @@ fn load_certs(file: &[u8]) -> io::Result<rustls::Certificate> {
     let mut reader = io::BufReader::new(file);
     let certs = rustls_pemfile::certs(&mut reader)?;
     if certs.len() != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "expected a single private key",
-        ));
+        return Err(io::Error::other("expected a single private key"));
     }
 
     Ok(rustls::Certificate(certs[0].clone()))
 }
 
+/// Load a certificate chain from file.
+pub fn load_cert_chain(file: &[u8]) -> io::Result<Vec<rustls::Certificate>> {
+    let mut reader = io::BufReader::new(file);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::other("expected at least one certificate"));
+    }
+
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+// Reads the SNI hostname from guest memory. An empty hostname refers to the default certificate.
+fn sni_hostname<T>(
+    caller: &mut Caller<T>,
+    hostname_str_ptr: u32,
+    hostname_str_len: u32,
+    trap: &str,
+) -> Result<Option<String>> {
+    if hostname_str_len == 0 {
+        return Ok(None);
+    }
+    let memory = get_memory(caller)?;
+    let hostname = memory
+        .data(&caller)
+        .get(hostname_str_ptr as usize..(hostname_str_ptr + hostname_str_len) as usize)
+        .or_trap(trap)?;
+    let hostname = std::str::from_utf8(hostname).or_trap(trap)?;
+    Ok(Some(hostname.to_string()))
+}
+
+// Replaces the certificate chain and private key that the TLS listener presents to clients
+// requesting **hostname** through SNI. If the hostname is not known yet, it is added. If
+// **hostname_str_len** is 0 the default certificate, used when no hostname matches, is replaced.
+//
+// Connections that are already established keep using the old certificate, the listening socket
+// is not affected.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the TLS listener ID doesn't exist.
+// * If the hostname is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn tls_listener_reload_certs<T: NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    tls_listener_id: u64,
+    hostname_str_ptr: u32,
+    hostname_str_len: u32,
+    certs_array_ptr: u32,
+    certs_array_len: u32,
+    keys_array_ptr: u32,
+    keys_array_len: u32,
+    error_id_ptr: u32,
+) -> Result<u32> {
+    let resolver = caller
+        .data()
+        .tls_listener_resources()
+        .get(tls_listener_id)
+        .or_trap("lunatic::networking::tls_listener_reload_certs")?
+        .certs
+        .clone();
+    let hostname = sni_hostname(
+        &mut caller,
+        hostname_str_ptr,
+        hostname_str_len,
+        "lunatic::networking::tls_listener_reload_certs",
+    )?;
+
+    let memory = get_memory(&mut caller)?;
+    let certs = memory
+        .data(&caller)
+        .get(certs_array_ptr as usize..(certs_array_ptr + certs_array_len) as usize)
+        .or_trap("lunatic::networking::tls_listener_reload_certs")?;
+    let keys = memory
+        .data(&caller)
+        .get(keys_array_ptr as usize..(keys_array_ptr + keys_array_len) as usize)
+        .or_trap("lunatic::networking::tls_listener_reload_certs")?;
+
+    // Invalid certificates are reported as errors, a long running server should not crash
+    // because a renewed certificate is broken.
+    let reload = load_cert_chain(certs)
+        .map_err(anyhow::Error::from)
+        .and_then(|certs| {
+            let keys = load_private_key(keys)?;
+            resolver.set(hostname.as_deref(), certs, &keys)
+        });
+    let (error_id, result) = match reload {
+        Ok(()) => (0, 0),
+        Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+    };
+
+    memory
+        .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+        .or_trap("lunatic::networking::tls_listener_reload_certs")?;
+    Ok(result)
+}
+
+// Removes the certificate that the TLS listener presents to clients requesting **hostname**
+// through SNI. These clients will receive the default certificate from now on.
+//
+// Returns:
+// * 0 on success
+// * 1 if no certificate was registered for the hostname
+//
+// Traps:
+// * If the TLS listener ID doesn't exist.
+// * If the hostname is empty or not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn tls_listener_remove_sni_cert<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    tls_listener_id: u64,
+    hostname_str_ptr: u32,
+    hostname_str_len: u32,
+) -> Result<u32> {
+    let resolver = caller
+        .data()
+        .tls_listener_resources()
+        .get(tls_listener_id)
+        .or_trap("lunatic::networking::tls_listener_remove_sni_cert")?
+        .certs
+        .clone();
+    let hostname = sni_hostname(
+        &mut caller,
+        hostname_str_ptr,
+        hostname_str_len,
+        "lunatic::networking::tls_listener_remove_sni_cert",
+    )?
+    .or_trap("lunatic::networking::tls_listener_remove_sni_cert: empty hostname")?;
+    Ok(if resolver.remove(&hostname) { 0 } else { 1 })
+}
+
 // If timeout is specified (value different from `u64::MAX`), the function will return on timeout
 // expiration with value 9027.
 // If cert_array_len is 0 it is treated as if there's no cert and the default certs are added
@@ -337,73 +604,12 @@ fn tls_connect<T: NetworkingCtx + ErrorCtx + Send>(
                 .to_vec(),
         )
         .or_trap("lunatic::network::tls_connect::tls_connect_socket_addr")?;
-
-        // if cerst_array_len is 0 this means there are no custom certs
-        let cafile = if certs_array_len == 0 {
-            None
-        } else {
-            let certs_list = memory
-                .data(&caller)
-                .get(certs_array_ptr as usize..(certs_array_ptr + certs_array_len * 8) as usize)
-                .or_trap("lunatic::networking::tls_connect")?
-                .to_vec();
-
-            let vec_slices: Result<Vec<_>> = certs_list
-                .chunks_exact(8)
-                .map(|ciovec| {
-                    let ciovec_ptr = u32::from_le_bytes(
-                        ciovec[0..4]
-                            .try_into()
-                            .or_trap("lunatic::networking::tls_connect::read_ciovec_ptr")?,
-                    ) as usize;
-                    let ciovec_len = u32::from_le_bytes(
-                        ciovec[4..8]
-                            .try_into()
-                            .or_trap("lunatic::networking::tls_connect::read_ciovec_len")?,
-                    ) as usize;
-                    let slice = memory
-                        .data(&caller)
-                        .get(ciovec_ptr..(ciovec_ptr + ciovec_len))
-                        .or_trap("lunatic::networking::tls_connect")?;
-                    Ok(slice.to_vec())
-                })
-                .collect();
-            Some(vec_slices)
-        };
-
-        let mut root_cert_store = rustls::RootCertStore::empty();
-        if let Some(Ok(pem_list)) = cafile {
-            let trust_anchors = pem_list
-                .iter()
-                .map(|pem| {
-                    let certs =
-                        load_certs(pem).or_trap("lunatic::networking::tls_connect::load_certs")?;
-                    let ta = TrustAnchor::try_from_cert_der(&certs.0[..])
-                        .or_trap("lunatic::networking::tls_connect::load_cert DER")?;
-                    Ok(OwnedTrustAnchor::from_subject_spki_name_constraints(
-                        ta.subject,
-                        ta.spki,
-                        ta.name_constraints,
-                    ))
-                })
-                .filter_map(|r: Result<OwnedTrustAnchor>| r.ok());
-            root_cert_store.add_trust_anchors(trust_anchors);
-        } else {
-            root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                    ta.subject,
-                    ta.spki,
-                    ta.name_constraints,
-                )
-            }));
+        let host_port = Some(port as u16);
+        if !check_network_access(&mut caller, &memory, &socket_addr, host_port, id_u64_ptr)? {
+            return Ok(1);
         }
 
-        let config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth(); // i guess this was previously the default?
-
-        let connector = TlsConnector::from(Arc::new(config));
+        let connector = tls_connector(&caller, &memory, certs_array_ptr, certs_array_len)?;
         let connect = TcpStream::connect((&socket_addr[..], port as u16));
         if let Ok(result) = match timeout_duration {
             // Without timeout
@@ -447,6 +653,82 @@ fn tls_connect<T: NetworkingCtx + ErrorCtx + Send>(
     })
 }
 
+// Creates a TLS connector that trusts the PEM encoded certificates from the **certs_array_ptr**
+// ciovec array, or the default root certificates if **certs_array_len** is 0.
+pub(crate) fn tls_connector<T>(
+    caller: &Caller<T>,
+    memory: &Memory,
+    certs_array_ptr: u32,
+    certs_array_len: u32,
+) -> Result<TlsConnector> {
+    // if cerst_array_len is 0 this means there are no custom certs
+    let cafile = if certs_array_len == 0 {
+        None
+    } else {
+        let certs_list = memory
+            .data(caller)
+            .get(certs_array_ptr as usize..(certs_array_ptr + certs_array_len * 8) as usize)
+            .or_trap("lunatic::networking::tls_connect")?
+            .to_vec();
+
+        let vec_slices: Result<Vec<_>> = certs_list
+            .chunks_exact(8)
+            .map(|ciovec| {
+                let ciovec_ptr = u32::from_le_bytes(
+                    ciovec[0..4]
+                        .try_into()
+                        .or_trap("lunatic::networking::tls_connect::read_ciovec_ptr")?,
+                ) as usize;
+                let ciovec_len = u32::from_le_bytes(
+                    ciovec[4..8]
+                        .try_into()
+                        .or_trap("lunatic::networking::tls_connect::read_ciovec_len")?,
+                ) as usize;
+                let slice = memory
+                    .data(caller)
+                    .get(ciovec_ptr..(ciovec_ptr + ciovec_len))
+                    .or_trap("lunatic::networking::tls_connect")?;
+                Ok(slice.to_vec())
+            })
+            .collect();
+        Some(vec_slices)
+    };
+
+    let mut root_cert_store = rustls::RootCertStore::empty();
+    if let Some(Ok(pem_list)) = cafile {
+        let trust_anchors = pem_list
+            .iter()
+            .map(|pem| {
+                let certs =
+                    load_certs(pem).or_trap("lunatic::networking::tls_connect::load_certs")?;
+                let ta = TrustAnchor::try_from_cert_der(&certs.0[..])
+                    .or_trap("lunatic::networking::tls_connect::load_cert DER")?;
+                Ok(OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                ))
+            })
+            .filter_map(|r: Result<OwnedTrustAnchor>| r.ok());
+        root_cert_store.add_trust_anchors(trust_anchors);
+    } else {
+        root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    }
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth(); // i guess this was previously the default?
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
 // Drops the TLS stream resource..
 //
 // Traps:
@@ -755,3 +1037,43 @@ fn tls_flush<T: NetworkingCtx + ErrorCtx + Send>(
         Ok(result)
     })
 }
+
+// Sends the TLS `close_notify` alert and shuts down the write half of the underlying TCP stream.
+// Data sent by the peer can still be read from the stream afterwards.
+//
+// The shutdown affects all clones of the stream.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn tls_shutdown<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .tls_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::tls_shutdown")?
+            .clone();
+
+        let mut stream = stream.writer.lock().await;
+
+        let (error_id, result) = match stream.shutdown().await {
+            Ok(()) => (0, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+            .or_trap("lunatic::networking::tls_shutdown")?;
+        Ok(result)
+    })
+}
@@ -9,14 +9,15 @@ use tokio::time::timeout;
 use wasmtime::{Caller, Linker};
 
 use crate::dns::DnsIterator;
-use crate::{socket_address, NetworkingCtx};
-use lunatic_common_api::{get_memory, IntoTrap};
+use crate::{check_network_access, socket_address, NetworkingCtx};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 // Register UDP networking APIs to the linker
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap6_async("lunatic::networking", "udp_bind", udp_bind)?;
     linker.func_wrap("lunatic::networking", "udp_local_addr", udp_local_addr)?;
     linker.func_wrap("lunatic::networking", "udp_peer_addr", udp_peer_addr)?;
@@ -261,6 +262,11 @@ fn udp_connect<T: NetworkingCtx + ErrorCtx + Send>(
             flow_info,
             scope_id,
         )?;
+        let ip = socket_addr.ip().to_string();
+        let port = Some(socket_addr.port());
+        if !check_network_access(&mut caller, &memory, &ip, port, id_u64_ptr)? {
+            return Ok(1);
+        }
         let socket = caller
             .data_mut()
             .udp_resources_mut()
@@ -418,6 +424,11 @@ fn udp_send_to<T: NetworkingCtx + ErrorCtx + Send>(
             flow_info,
             scope_id,
         )?;
+        let ip = socket_addr.ip().to_string();
+        let port = Some(socket_addr.port());
+        if !check_network_access(&mut caller, &memory, &ip, port, opaque_ptr)? {
+            return Ok(1);
+        }
         let buffer = memory
             .data(&caller)
             .get(buffer_ptr as usize..(buffer_ptr + buffer_len) as usize)
@@ -0,0 +1,550 @@
+use std::convert::TryInto;
+use std::future::Future;
+use std::io::IoSlice;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::time::timeout;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use wasmtime::{Caller, Linker};
+
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_error_api::ErrorCtx;
+
+use crate::{NetworkingCtx, UnixConnection};
+
+// Register Unix domain socket APIs to the linker
+pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
+    linker: &mut Linker<T>,
+) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap3_async("lunatic::networking", "unix_bind", unix_bind)?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "drop_unix_listener",
+        drop_unix_listener,
+    )?;
+    linker.func_wrap2_async("lunatic::networking", "unix_accept", unix_accept)?;
+    linker.func_wrap4_async("lunatic::networking", "unix_connect", unix_connect)?;
+    linker.func_wrap("lunatic::networking", "drop_unix_stream", drop_unix_stream)?;
+    linker.func_wrap(
+        "lunatic::networking",
+        "clone_unix_stream",
+        clone_unix_stream,
+    )?;
+    linker.func_wrap4_async(
+        "lunatic::networking",
+        "unix_write_vectored",
+        unix_write_vectored,
+    )?;
+    linker.func_wrap4_async("lunatic::networking", "unix_read", unix_read)?;
+    linker.func_wrap2_async(
+        "lunatic::networking",
+        "set_unix_read_timeout",
+        set_unix_read_timeout,
+    )?;
+    linker.func_wrap2_async(
+        "lunatic::networking",
+        "set_unix_write_timeout",
+        set_unix_write_timeout,
+    )?;
+    linker.func_wrap1_async(
+        "lunatic::networking",
+        "get_unix_read_timeout",
+        get_unix_read_timeout,
+    )?;
+    linker.func_wrap1_async(
+        "lunatic::networking",
+        "get_unix_write_timeout",
+        get_unix_write_timeout,
+    )?;
+    linker.func_wrap2_async("lunatic::networking", "unix_flush", unix_flush)?;
+    Ok(())
+}
+
+fn socket_path<T>(caller: &mut Caller<T>, path_str_ptr: u32, path_str_len: u32) -> Result<String> {
+    let memory = get_memory(caller)?;
+    let path = memory
+        .data(&caller)
+        .get(path_str_ptr as usize..(path_str_ptr + path_str_len) as usize)
+        .or_trap("lunatic::networking::unix_*")?;
+    let path = std::str::from_utf8(path).or_trap("lunatic::networking::unix_*")?;
+    Ok(path.to_string())
+}
+
+// Creates a new Unix domain socket listener, which will be bound to the path specified by
+// **path_str_ptr** and **path_str_len**. The returned listener is ready for accepting
+// connections.
+//
+// The socket is created by the host, so the process needs to have access to the path.
+//
+// Returns:
+// * 0 on success - The ID of the newly created Unix listener is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the path is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn unix_bind<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    path_str_ptr: u32,
+    path_str_len: u32,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let path = socket_path(&mut caller, path_str_ptr, path_str_len)?;
+        let bind = caller
+            .data()
+            .can_access_fs_location(Path::new(&path))
+            .map_err(|error| anyhow!(error).context(format!("Failed to access '{path}'")))
+            .and_then(|()| Ok(UnixListener::bind(&path)?));
+        let (unix_listener_or_error_id, result) = match bind {
+            Ok(listener) => (
+                caller
+                    .data_mut()
+                    .unix_listener_resources_mut()
+                    .add(Arc::new(listener)),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &unix_listener_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::unix_bind")?;
+        Ok(result)
+    })
+}
+
+// Drops the Unix listener resource.
+//
+// Traps:
+// * If the Unix listener ID doesn't exist.
+fn drop_unix_listener<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    unix_listener_id: u64,
+) -> Result<()> {
+    caller
+        .data_mut()
+        .unix_listener_resources_mut()
+        .remove(unix_listener_id)
+        .or_trap("lunatic::networking::drop_unix_listener")?;
+    Ok(())
+}
+
+// Returns:
+// * 0 on success - The ID of the newly created Unix stream is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the Unix listener ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn unix_accept<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    listener_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let unix_listener = caller
+            .data()
+            .unix_listener_resources()
+            .get(listener_id)
+            .or_trap("lunatic::network::unix_accept")?
+            .clone();
+
+        let (unix_stream_or_error_id, result) = match unix_listener.accept().await {
+            Ok((stream, _)) => (
+                caller
+                    .data_mut()
+                    .unix_stream_resources_mut()
+                    .add(Arc::new(UnixConnection::new(stream))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &unix_stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::unix_accept")?;
+        Ok(result)
+    })
+}
+
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// The process needs to have access to the path of the socket.
+//
+// Returns:
+// * 0 on success - The ID of the newly created Unix stream is written to **id_u64_ptr**.
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the path is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn unix_connect<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    path_str_ptr: u32,
+    path_str_len: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let path = socket_path(&mut caller, path_str_ptr, path_str_len)?;
+
+        let access = caller.data().can_access_fs_location(Path::new(&path));
+        let connect = async {
+            access.map_err(|error| anyhow!(error).context(format!("Failed to access '{path}'")))?;
+            Ok::<_, anyhow::Error>(UnixStream::connect(&path).await?)
+        };
+        if let Ok(result) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(connect.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), connect).await,
+        } {
+            let (stream_or_error_id, result) = match result {
+                Ok(stream) => (
+                    caller
+                        .data_mut()
+                        .unix_stream_resources_mut()
+                        .add(Arc::new(UnixConnection::new(stream))),
+                    0,
+                ),
+                Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+            };
+
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(
+                    &mut caller,
+                    id_u64_ptr as usize,
+                    &stream_or_error_id.to_le_bytes(),
+                )
+                .or_trap("lunatic::networking::unix_connect")?;
+            Ok(result)
+        } else {
+            // Call timed out
+            Ok(9027)
+        }
+    })
+}
+
+// Drops the Unix stream resource.
+//
+// Traps:
+// * If the Unix stream ID doesn't exist.
+fn drop_unix_stream<T: NetworkingCtx>(mut caller: Caller<T>, unix_stream_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .unix_stream_resources_mut()
+        .remove(unix_stream_id)
+        .or_trap("lunatic::networking::drop_unix_stream")?;
+    Ok(())
+}
+
+// Clones a Unix stream returning the ID of the clone.
+//
+// Traps:
+// * If the stream ID doesn't exist.
+fn clone_unix_stream<T: NetworkingCtx>(mut caller: Caller<T>, unix_stream_id: u64) -> Result<u64> {
+    let stream = caller
+        .data()
+        .unix_stream_resources()
+        .get(unix_stream_id)
+        .or_trap("lunatic::networking::clone_unix_stream")?
+        .clone();
+    let id = caller.data_mut().unix_stream_resources_mut().add(stream);
+    Ok(id)
+}
+
+// Gathers data from the vector buffers and writes them to the stream. **ciovec_array_ptr** points
+// to an array of (ciovec_ptr, ciovec_len) pairs where each pair represents a buffer to be written.
+//
+// Returns:
+// * 0 on success - The number of bytes written is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn unix_write_vectored<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    ciovec_array_ptr: u32,
+    ciovec_array_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let buffer = memory
+            .data(&caller)
+            .get(ciovec_array_ptr as usize..(ciovec_array_ptr + ciovec_array_len * 8) as usize)
+            .or_trap("lunatic::networking::unix_write_vectored")?;
+
+        // Ciovecs consist of 32bit ptr + 32bit len = 8 bytes.
+        let vec_slices: Result<Vec<_>> = buffer
+            .chunks_exact(8)
+            .map(|ciovec| {
+                let ciovec_ptr =
+                    u32::from_le_bytes(ciovec[0..4].try_into().expect("works")) as usize;
+                let ciovec_len =
+                    u32::from_le_bytes(ciovec[4..8].try_into().expect("works")) as usize;
+                let slice = memory
+                    .data(&caller)
+                    .get(ciovec_ptr..(ciovec_ptr + ciovec_len))
+                    .or_trap("lunatic::networking::unix_write_vectored")?;
+                Ok(IoSlice::new(slice))
+            })
+            .collect();
+        let vec_slices = vec_slices?;
+
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::unix_write_vectored")?
+            .clone();
+
+        let write_timeout = stream.write_timeout.lock().await;
+        let mut stream = stream.writer.lock().await;
+
+        if let Ok(write_result) = match *write_timeout {
+            Some(write_timeout) => {
+                timeout(write_timeout, stream.write_vectored(vec_slices.as_slice())).await
+            }
+            None => Ok(stream.write_vectored(vec_slices.as_slice()).await),
+        } {
+            let (opaque, return_) = match write_result {
+                Ok(bytes) => (bytes as u64, 0),
+                Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+            };
+
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+                .or_trap("lunatic::networking::unix_write_vectored")?;
+            Ok(return_)
+        } else {
+            // Call timed out
+            Ok(9027)
+        }
+    })
+}
+
+// Reads data from the Unix stream and writes it to the buffer.
+//
+// If no data was read within the specified timeout duration the value 9027 is returned
+//
+// Returns:
+// * 0 on success - The number of bytes read is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn unix_read<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::unix_read")?
+            .clone();
+        let read_timeout = stream.read_timeout.lock().await;
+        let mut stream = stream.reader.lock().await;
+
+        let memory = get_memory(&mut caller)?;
+        let buffer = memory
+            .data_mut(&mut caller)
+            .get_mut(buffer_ptr as usize..(buffer_ptr + buffer_len) as usize)
+            .or_trap("lunatic::networking::unix_read")?;
+
+        if let Ok(read_result) = match *read_timeout {
+            Some(read_timeout) => timeout(read_timeout, stream.read(buffer)).await,
+            None => Ok(stream.read(buffer).await),
+        } {
+            let (opaque, return_) = match read_result {
+                Ok(bytes) => (bytes as u64, 0),
+                Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+            };
+
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+                .or_trap("lunatic::networking::unix_read")?;
+            Ok(return_)
+        } else {
+            // Call timed out
+            Ok(9027)
+        }
+    })
+}
+
+// Sets the new value for write timeout for the **UnixStream**
+//
+// Returns:
+// * 0 on success
+//
+// Traps:
+// * If the stream ID doesn't exist.
+fn set_unix_write_timeout<T: NetworkingCtx + ErrorCtx + Send>(
+    caller: Caller<T>,
+    stream_id: u64,
+    duration: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::set_unix_write_timeout")?
+            .clone();
+        let mut timeout = stream.write_timeout.lock().await;
+        // a way to disable the timeout
+        if duration == u64::MAX {
+            *timeout = None;
+        } else {
+            *timeout = Some(Duration::from_millis(duration));
+        }
+        Ok(())
+    })
+}
+
+// Gets the value for write timeout for the **UnixStream**
+//
+// Returns:
+// * value of write timeout duration in milliseconds
+//
+// Traps:
+// * If the stream ID doesn't exist.
+fn get_unix_write_timeout<T: NetworkingCtx + ErrorCtx + Send>(
+    caller: Caller<T>,
+    stream_id: u64,
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::get_unix_write_timeout")?
+            .clone();
+        let timeout = stream.write_timeout.lock().await;
+        // a way to disable the timeout
+        Ok(timeout.map_or(u64::MAX, |t| t.as_millis() as u64))
+    })
+}
+
+// Sets the new value for read timeout for the **UnixStream**
+//
+// Returns:
+// * 0 on success
+//
+// Traps:
+// * If the stream ID doesn't exist.
+fn set_unix_read_timeout<T: NetworkingCtx + ErrorCtx + Send>(
+    caller: Caller<T>,
+    stream_id: u64,
+    duration: u64,
+) -> Box<dyn Future<Output = Result<()>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::set_unix_read_timeout")?
+            .clone();
+        let mut timeout = stream.read_timeout.lock().await;
+        // a way to disable the timeout
+        if duration == u64::MAX {
+            *timeout = None;
+        } else {
+            *timeout = Some(Duration::from_millis(duration));
+        }
+        Ok(())
+    })
+}
+
+// Gets the value for read timeout for the **UnixStream**
+//
+// Returns:
+// * value of read timeout duration in milliseconds
+//
+// Traps:
+// * If the stream ID doesn't exist.
+fn get_unix_read_timeout<T: NetworkingCtx + ErrorCtx + Send>(
+    caller: Caller<T>,
+    stream_id: u64,
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::get_unix_read_timeout")?
+            .clone();
+        let timeout = stream.read_timeout.lock().await;
+        // a way to disable the timeout
+        Ok(timeout.map_or(u64::MAX, |t| t.as_millis() as u64))
+    })
+}
+
+// Flushes this output stream, ensuring that all intermediately buffered contents reach their
+// destination.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn unix_flush<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .unix_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::network::unix_flush")?
+            .clone();
+
+        let mut stream = stream.writer.lock().await;
+
+        let (error_id, result) = match stream.flush().await {
+            Ok(()) => (0, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+            .or_trap("lunatic::networking::unix_flush")?;
+        Ok(result)
+    })
+}
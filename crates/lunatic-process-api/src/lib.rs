@@ -1,7 +1,10 @@
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     future::Future,
     io::Write,
+    net::IpAddr,
+    ops::RangeInclusive,
     path::Path,
     sync::Arc,
     time::{Duration, Instant},
@@ -9,23 +12,88 @@ use std::{
 
 use anyhow::{anyhow, Result};
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
 use lunatic_distributed::DistributedCtx;
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
-    config::ProcessConfig,
-    env::Environment,
+    checkpoint::Checkpoint,
+    config::{LogLevel, MailboxOverflowPolicy, ProcessConfig},
+    env::{Environment, ProcessCounterGuard},
     mailbox::MessageMailbox,
-    message::Message,
+    message::{Message, MAX_HEADERS_SIZE},
     runtimes::{wasmtime::WasmtimeCompiledModule, RawWasm},
     state::ProcessState,
-    DeathReason, Process, Signal, WasmProcess,
+    DeathReason, Hibernate, Process, Signal, WasmProcess,
 };
 use lunatic_wasi_api::LunaticWasiCtx;
-use wasmtime::{Caller, Linker, ResourceLimiter, Val};
+use wasmtime::{Caller, ExternType, Linker, ResourceLimiter, Val};
 
 pub type ProcessResources = HashMapId<Arc<dyn Process>>;
 pub type ModuleResources<S> = HashMapId<Arc<WasmtimeCompiledModule<S>>>;
+// The current version number and module of each named module, shared by all processes of a
+// process tree on this node
+pub type ModuleVersions<S> =
+    Arc<std::sync::RwLock<HashMap<String, (u64, Arc<WasmtimeCompiledModule<S>>)>>>;
+pub type MessageSlots = HashMapId<Option<Message>>;
+
+// Maximum size of a key in the process-local storage (1 KiB).
+const MAX_LOCAL_KEY_SIZE: usize = 1024;
+// Maximum size of a value in the process-local storage (64 KiB).
+const MAX_LOCAL_VALUE_SIZE: usize = 64 * 1024;
+// Maximum size of all keys and values in the process-local storage (1 MiB).
+const MAX_LOCAL_STORAGE_SIZE: usize = 1024 * 1024;
+// Maximum number of entries in the process-local storage.
+const MAX_LOCAL_ENTRIES: usize = 1024;
+// Maximum size of the reason sent with `kill_with_reason` (1 KiB).
+const MAX_KILL_REASON_SIZE: usize = 1024;
+
+/// Process-local key/value storage, limited in the size of its entries.
+#[derive(Debug, Default)]
+pub struct LocalStorage {
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    // Combined size of all keys and values
+    size: usize,
+}
+
+impl LocalStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Stores **value** under **key**, replacing any previous value.
+    ///
+    /// Returns `false` without changing the storage if the entry doesn't fit into the limits.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        if key.len() > MAX_LOCAL_KEY_SIZE || value.len() > MAX_LOCAL_VALUE_SIZE {
+            return false;
+        }
+        let (size, entries) = match self.entries.get(key) {
+            Some(previous) => (self.size - previous.len() + value.len(), self.entries.len()),
+            None => (self.size + key.len() + value.len(), self.entries.len() + 1),
+        };
+        if size > MAX_LOCAL_STORAGE_SIZE || entries > MAX_LOCAL_ENTRIES {
+            return false;
+        }
+        self.size = size;
+        self.entries.insert(key.to_vec(), value.to_vec());
+        true
+    }
+
+    /// Returns `false` if nothing is stored under **key**.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        match self.entries.remove(key) {
+            Some(value) => {
+                self.size -= key.len() + value.len();
+                true
+            }
+            None => false,
+        }
+    }
+}
 
 pub trait ProcessConfigCtx {
     fn can_compile_modules(&self) -> bool;
@@ -34,15 +102,43 @@ pub trait ProcessConfigCtx {
     fn set_can_create_configs(&mut self, can: bool);
     fn can_spawn_processes(&self) -> bool;
     fn set_can_spawn_processes(&mut self, can: bool);
+    fn can_use_nn(&self) -> bool;
+    fn set_can_use_nn(&mut self, can: bool);
+    fn can_set_node_tags(&self) -> bool;
+    fn set_can_set_node_tags(&mut self, can: bool);
+    fn process_counter_id(&self) -> Option<u64>;
+    fn set_process_counter_id(&mut self, counter_id: Option<u64>);
     fn can_access_fs_location(&self, path: &Path) -> Result<(), String>;
+    fn allow_network(&mut self, target: &str, ports: RangeInclusive<u16>) -> Result<(), String>;
+    fn deny_network(&mut self, target: &str, ports: RangeInclusive<u16>) -> Result<(), String>;
+    fn can_access_network(
+        &self,
+        host: Option<&str>,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+    ) -> Result<(), String>;
+    fn allow_sqlite(&mut self, path_glob: &str, max_db_size: Option<u64>);
+    /// Returns the size quota of the database at `path`, if the process is allowed to open it.
+    ///
+    /// Without any rules added by `allow_sqlite`, every database is allowed without a quota and
+    /// only the filesystem access (`can_access_fs_location`) restricts which ones can be opened.
+    fn can_open_sqlite(&self, path: &Path) -> Result<Option<u64>, String>;
 }
 
 pub trait ProcessCtx<S: ProcessState> {
     fn mailbox(&mut self) -> &mut MessageMailbox;
     fn message_scratch_area(&mut self) -> &mut Option<Message>;
+    fn message_slots(&mut self) -> &mut MessageSlots;
     fn module_resources(&self) -> &ModuleResources<S>;
     fn module_resources_mut(&mut self) -> &mut ModuleResources<S>;
+    fn module_versions(&self) -> &ModuleVersions<S>;
     fn environment(&self) -> Arc<dyn Environment>;
+    fn process_counters(&self) -> &ProcessCounterGuard;
+    fn set_process_counters(&mut self, counters: ProcessCounterGuard);
+    fn local_storage(&self) -> &LocalStorage;
+    fn local_storage_mut(&mut self) -> &mut LocalStorage;
+    fn trace_context(&self) -> Option<&[u8]>;
+    fn set_trace_context(&mut self, context: Option<Vec<u8>>);
 }
 
 // Register the process APIs to the linker
@@ -61,8 +157,11 @@ where
     T::Config: ProcessConfigCtx,
     E: Environment + 'static,
 {
+    let mut linker = InstrumentedLinker::new(linker);
     #[cfg(feature = "metrics")]
     lunatic_process::describe_metrics();
+    #[cfg(feature = "metrics")]
+    lunatic_common_api::describe_metrics();
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -94,6 +193,8 @@ where
 
     linker.func_wrap("lunatic::process", "compile_module", compile_module)?;
     linker.func_wrap("lunatic::process", "drop_module", drop_module)?;
+    linker.func_wrap("lunatic::process", "set_module_version", set_module_version)?;
+    linker.func_wrap("lunatic::process", "get_module_version", get_module_version)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -138,6 +239,96 @@ where
         "config_get_max_fuel",
         config_get_max_fuel,
     )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_fuel_per_yield",
+        config_set_fuel_per_yield,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_fuel_per_yield",
+        config_get_fuel_per_yield,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_lifetime_ms",
+        config_set_max_lifetime_ms,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_lifetime_ms",
+        config_get_max_lifetime_ms,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_processes",
+        config_set_max_processes,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_processes",
+        config_get_max_processes,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_mailbox_size",
+        config_set_max_mailbox_size,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_mailbox_size",
+        config_get_max_mailbox_size,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_mailbox_overflow_policy",
+        config_set_mailbox_overflow_policy,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_mailbox_overflow_policy",
+        config_get_mailbox_overflow_policy,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_mailbox_watermark",
+        config_set_mailbox_watermark,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_mailbox_watermark",
+        config_get_mailbox_watermark,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_log_level",
+        config_set_max_log_level,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_log_level",
+        config_get_max_log_level,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_crash_dumps",
+        config_set_crash_dumps,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_crash_dumps",
+        config_get_crash_dumps,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_max_message_size",
+        config_set_max_message_size,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_max_message_size",
+        config_get_max_message_size,
+    )?;
     linker.func_wrap(
         "lunatic::process",
         "config_can_compile_modules",
@@ -168,20 +359,78 @@ where
         "config_set_can_spawn_processes",
         config_set_can_spawn_processes,
     )?;
+    linker.func_wrap("lunatic::process", "config_can_use_nn", config_can_use_nn)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_can_use_nn",
+        config_set_can_use_nn,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_can_set_node_tags",
+        config_can_set_node_tags,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_can_set_node_tags",
+        config_set_can_set_node_tags,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_allow_network",
+        config_allow_network,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_deny_network",
+        config_deny_network,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_allow_sqlite",
+        config_allow_sqlite,
+    )?;
 
     linker.func_wrap8_async("lunatic::process", "spawn", spawn)?;
+    linker.func_wrap8_async("lunatic::process", "spawn_with_message", spawn_with_message)?;
+    linker.func_wrap8_async("lunatic::process", "spawn_link_await", spawn_link_await)?;
+    linker.func_wrap4_async("lunatic::process", "spawn_start", spawn_start)?;
+    linker.func_wrap6_async("lunatic::process", "restore", restore)?;
     linker.func_wrap11_async("lunatic::process", "get_or_spawn", get_or_spawn)?;
+    linker.func_wrap11_async("lunatic::process", "spawn_named", get_or_spawn)?;
     linker.func_wrap1_async("lunatic::process", "sleep_ms", sleep_ms)?;
     linker.func_wrap("lunatic::process", "die_when_link_dies", die_when_link_dies)?;
+    linker.func_wrap("lunatic::process", "trap_shutdown", trap_shutdown)?;
 
     linker.func_wrap("lunatic::process", "process_id", process_id)?;
     linker.func_wrap("lunatic::process", "environment_id", environment_id)?;
     linker.func_wrap("lunatic::process", "link", link)?;
+    linker.func_wrap("lunatic::process", "link_and_send", link_and_send)?;
     linker.func_wrap("lunatic::process", "unlink", unlink)?;
     linker.func_wrap("lunatic::process", "monitor", monitor)?;
-    linker.func_wrap("lunatic::process", "stop_monitoring", stop_monitoring)?;
+    linker.func_wrap("lunatic::process", "demonitor", demonitor)?;
+    // Kept for backwards compatibility, `demonitor` should be used instead.
+    linker.func_wrap("lunatic::process", "stop_monitoring", demonitor)?;
     linker.func_wrap("lunatic::process", "kill", kill)?;
+    linker.func_wrap("lunatic::process", "kill_with_reason", kill_with_reason)?;
+    linker.func_wrap("lunatic::process", "shutdown", shutdown)?;
+    linker.func_wrap("lunatic::process", "suspend", suspend)?;
+    linker.func_wrap("lunatic::process", "hibernate", hibernate)?;
+    linker.func_wrap5_async("lunatic::process", "checkpoint", checkpoint)?;
+    linker.func_wrap("lunatic::process", "resume", resume)?;
     linker.func_wrap("lunatic::process", "exists", exists)?;
+    linker.func_wrap("lunatic::process", "stats", stats)?;
+    linker.func_wrap5_async("lunatic::process", "list", list)?;
+    linker.func_wrap("lunatic::process", "group_create", group_create)?;
+    linker.func_wrap("lunatic::process", "group_join", group_join)?;
+    linker.func_wrap("lunatic::process", "group_leave", group_leave)?;
+    linker.func_wrap("lunatic::process", "group_kill", group_kill)?;
+    linker.func_wrap("lunatic::process", "group_send", group_send)?;
+    linker.func_wrap("lunatic::process", "local_set", local_set)?;
+    linker.func_wrap("lunatic::process", "local_get", local_get)?;
+    linker.func_wrap("lunatic::process", "local_delete", local_delete)?;
+    linker.func_wrap("lunatic::process", "set_trace_context", set_trace_context)?;
+    linker.func_wrap("lunatic::process", "get_trace_context", get_trace_context)?;
     Ok(())
 }
 
@@ -269,6 +518,103 @@ fn drop_module<T: ProcessState + ProcessCtx<T>>(
     Ok(())
 }
 
+// Registers the module **module_id** as the next version of the module with the name at
+// **name_str_ptr**.
+//
+// Running processes keep the module they were spawned from. Processes that look up the module by
+// name with `get_module_version` from now on get the new version, so that supervisors can upgrade
+// their children without downtime by restarting them one by one.
+//
+// Versions are only visible to the processes spawned from the same root process on this node.
+//
+// TODO: Register versions with the control server, so that they are shared across the cluster and
+//       can be pushed from outside the app (`lunatic deploy --hot`).
+//
+// Returns:
+// * The version number of the module, starting with 1 for the first version of a name.
+// * -1 in case the process doesn't have permission to compile modules.
+//
+// Traps:
+// * If the module ID doesn't exist.
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn set_module_version<T>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    module_id: u64,
+) -> Result<i64>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    if !caller.data().config().can_compile_modules() {
+        return Ok(-1);
+    }
+    let memory = get_memory(&mut caller)?;
+    let name = memory
+        .data(&caller)
+        .get(name_str_ptr as usize..(name_str_ptr as usize + name_str_len as usize))
+        .or_trap("lunatic::process::set_module_version")?;
+    let name = std::str::from_utf8(name)
+        .or_trap("lunatic::process::set_module_version")?
+        .to_string();
+    let module = caller
+        .data()
+        .module_resources()
+        .get(module_id)
+        .or_trap("lunatic::process::set_module_version: Module ID doesn't exist")?
+        .clone();
+    let mut versions = caller.data().module_versions().write().unwrap();
+    let version = versions.get(&name).map_or(1, |(version, _)| version + 1);
+    versions.insert(name, (version, module));
+    Ok(version as i64)
+}
+
+// Looks up the current version of the module with the name at **name_str_ptr** and adds it to the
+// module resources.
+//
+// Returns:
+// * The version number of the module, the ID of the module is written to **id_ptr**.
+// * -1 if no module was registered under the name.
+//
+// Traps:
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn get_module_version<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    id_ptr: u32,
+) -> Result<i64> {
+    let memory = get_memory(&mut caller)?;
+    let name = memory
+        .data(&caller)
+        .get(name_str_ptr as usize..(name_str_ptr as usize + name_str_len as usize))
+        .or_trap("lunatic::process::get_module_version")?;
+    let name = std::str::from_utf8(name).or_trap("lunatic::process::get_module_version")?;
+    let current = caller
+        .data()
+        .module_versions()
+        .read()
+        .unwrap()
+        .get(name)
+        .cloned();
+    let (version, module) = match current {
+        Some(current) => current,
+        None => return Ok(-1),
+    };
+
+    #[cfg(feature = "metrics")]
+    metrics::increment_gauge!("lunatic.process.modules.active", 1.0);
+
+    let module_id = caller.data_mut().module_resources_mut().add(module);
+    memory
+        .write(&mut caller, id_ptr as usize, &module_id.to_le_bytes())
+        .or_trap("lunatic::process::get_module_version")?;
+    Ok(version as i64)
+}
+
 // Create a new configuration with all permissions denied.
 //
 // There is no memory or fuel limit set on the newly created configuration.
@@ -397,138 +743,837 @@ fn config_get_max_fuel<T: ProcessState + ProcessCtx<T>>(
     }
 }
 
-// Returns 1 if processes spawned from this configuration can compile Wasm modules, otherwise 0.
+// Sets the amount of fuel (instructions) a process spawned from this configuration can consume
+// before yielding back to the scheduler. After yielding the process continues running, only the
+// fuel limit (`config_set_max_fuel`) can stop it.
+//
+// A value of 0 sets the default of 100k instructions.
 //
 // Traps:
 // * If the config ID doesn't exist.
-fn config_can_compile_modules<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
-where
-    T: ProcessState + ProcessCtx<T>,
-    T::Config: ProcessConfigCtx,
-{
-    let can = caller
+fn config_set_fuel_per_yield<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    fuel_per_yield: u64,
+) -> Result<()> {
+    let fuel_per_yield = match fuel_per_yield {
+        0 => None,
+        fuel_per_yield => Some(fuel_per_yield),
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_fuel_per_yield: Config ID doesn't exist")?
+        .set_fuel_per_yield(fuel_per_yield);
+    Ok(())
+}
+
+// Returns the amount of fuel consumed between yields of a configuration.
+//
+// A value of 0 indicates the default of 100k instructions.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_fuel_per_yield<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let fuel_per_yield = caller
         .data()
         .config_resources()
         .get(config_id)
-        .or_trap("lunatic::process::config_can_compile_modules: Config ID doesn't exist")?
-        .can_compile_modules();
-    Ok(can as u32)
+        .or_trap("lunatic::process::config_get_fuel_per_yield: Config ID doesn't exist")?
+        .get_fuel_per_yield();
+    Ok(fuel_per_yield.unwrap_or(0))
 }
 
-// If set to a value >0 (true), processes spawned from this configuration will be able to compile
-// Wasm modules.
+// Sets the maximum wall-clock lifetime of processes spawned from this configuration, in
+// milliseconds. Processes still running after the deadline are killed.
+//
+// A value of 0 indicates no lifetime limit.
 //
 // Traps:
 // * If the config ID doesn't exist.
-fn config_set_can_compile_modules<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
-where
-    T: ProcessState + ProcessCtx<T>,
-    T::Config: ProcessConfigCtx,
-{
+fn config_set_max_lifetime_ms<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_lifetime_ms: u64,
+) -> Result<()> {
+    let max_lifetime_ms = match max_lifetime_ms {
+        0 => None,
+        max_lifetime_ms => Some(max_lifetime_ms),
+    };
+
     caller
         .data_mut()
         .config_resources_mut()
         .get_mut(config_id)
-        .or_trap("lunatic::process::config_set_can_compile_modules: Config ID doesn't exist")?
-        .set_can_compile_modules(can != 0);
+        .or_trap("lunatic::process::config_set_max_lifetime_ms: Config ID doesn't exist")?
+        .set_max_lifetime_ms(max_lifetime_ms);
     Ok(())
 }
 
-// Returns 1 if processes spawned from this configuration can create other configurations,
-// otherwise 0.
+// Returns the maximum lifetime of a configuration, in milliseconds.
+//
+// A value of 0 indicates no lifetime limit.
 //
 // Traps:
 // * If the config ID doesn't exist.
-fn config_can_create_configs<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
-where
-    T: ProcessState + ProcessCtx<T>,
-    T::Config: ProcessConfigCtx,
-{
-    let can = caller
+fn config_get_max_lifetime_ms<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let max_lifetime_ms = caller
         .data()
         .config_resources()
         .get(config_id)
-        .or_trap("lunatic::process::config_can_create_configs: Config ID doesn't exist")?
-        .can_create_configs();
-    Ok(can as u32)
+        .or_trap("lunatic::process::config_get_max_lifetime_ms: Config ID doesn't exist")?
+        .get_max_lifetime_ms();
+    Ok(max_lifetime_ms.unwrap_or(0))
 }
 
-// If set to a value >0 (true), processes spawned from this configuration will be able to create
-// other configuration.
+// Sets the maximum number of running processes in the subtree spawned from this configuration.
+//
+// All processes spawned from the configuration, and all processes spawned by them (no matter
+// which configuration they use), are counted against the limit. Spawning a process beyond the
+// limit fails with an error. Changing the limit of a configuration keeps counting the processes
+// that are already running.
+//
+// The count is kept by the node of the calling process. Processes spawned on other nodes with
+// `lunatic::distributed::spawn` are not counted and not limited, the limit is not carried over
+// to the remote node.
+//
+// A value of 0 indicates no process limit.
 //
 // Traps:
 // * If the config ID doesn't exist.
-fn config_set_can_create_configs<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+fn config_set_max_processes<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_processes: u64,
+) -> Result<()>
 where
-    T: ProcessState + ProcessCtx<T>,
     T::Config: ProcessConfigCtx,
 {
-    caller
+    let state = caller.data();
+    let counter_id = state
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_set_max_processes: Config ID doesn't exist")?
+        .process_counter_id();
+    let counter = counter_id.and_then(|counter_id| {
+        state
+            .environment()
+            .get_process_counter(counter_id)
+            .map(|counter| (counter_id, counter))
+    });
+    let counter_id = match (counter, max_processes) {
+        (Some((counter_id, counter)), 0) => {
+            counter.set_max_processes(u64::MAX);
+            Some(counter_id)
+        }
+        (Some((counter_id, counter)), max_processes) => {
+            counter.set_max_processes(max_processes);
+            Some(counter_id)
+        }
+        (None, 0) => None,
+        (None, max_processes) => Some(
+            state
+                .environment()
+                .create_process_counter(state.id(), max_processes),
+        ),
+    };
+
+    let config = caller
         .data_mut()
         .config_resources_mut()
         .get_mut(config_id)
-        .or_trap("lunatic::process::config_set_can_create_configs: Config ID doesn't exist")?
-        .set_can_create_configs(can != 0);
+        .or_trap("lunatic::process::config_set_max_processes: Config ID doesn't exist")?;
+    config.set_max_processes(match max_processes {
+        0 => None,
+        max_processes => Some(max_processes),
+    });
+    config.set_process_counter_id(counter_id);
     Ok(())
 }
 
-// Returns 1 if processes spawned from this configuration can spawn sub-processes, otherwise 0.
+// Returns the process limit of a configuration.
+//
+// A value of 0 indicates no process limit.
 //
 // Traps:
 // * If the config ID doesn't exist.
-fn config_can_spawn_processes<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
-where
-    T: ProcessState + ProcessCtx<T>,
-    T::Config: ProcessConfigCtx,
-{
-    let can = caller
+fn config_get_max_processes<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let max_processes = caller
         .data()
         .config_resources()
         .get(config_id)
-        .or_trap("lunatic::process::config_can_spawn_processes: Config ID doesn't exist")?
-        .can_spawn_processes();
-    Ok(can as u32)
+        .or_trap("lunatic::process::config_get_max_processes: Config ID doesn't exist")?
+        .get_max_processes();
+    Ok(max_processes.unwrap_or(0))
 }
 
-// If set to a value >0 (true), processes spawned from this configuration will be able to spawn
-// sub-processes.
+// Sets the maximum number of messages waiting in the mailbox of processes spawned from this
+// configuration.
+//
+// A value of 0 indicates no limit.
 //
 // Traps:
+// * If max_mailbox_size is bigger than the platform maximum.
 // * If the config ID doesn't exist.
-fn config_set_can_spawn_processes<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
-where
-    T: ProcessState + ProcessCtx<T>,
-    T::Config: ProcessConfigCtx,
-{
+fn config_set_max_mailbox_size<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_mailbox_size: u64,
+) -> Result<()> {
+    let max_mailbox_size = match max_mailbox_size {
+        0 => None,
+        max_mailbox_size => Some(usize::try_from(max_mailbox_size).or_trap(
+            "lunatic::process::config_set_max_mailbox_size: max_mailbox_size exceeds platform max",
+        )?),
+    };
+
     caller
         .data_mut()
         .config_resources_mut()
         .get_mut(config_id)
-        .or_trap("lunatic::process::config_set_can_spawn_processes: Config ID doesn't exist")?
-        .set_can_spawn_processes(can != 0);
+        .or_trap("lunatic::process::config_set_max_mailbox_size: Config ID doesn't exist")?
+        .set_max_mailbox_size(max_mailbox_size);
     Ok(())
 }
 
-// Spawns a new process using the passed in function inside a module as the entry point.
-//
-// If **link** is not 0, it will link the child and parent processes. The value of the **link**
-// argument will be used as the link-tag for the child. This means, if the child traps the parent
-// is going to get a signal back with the value used as the tag.
+// Returns the maximum mailbox size of a configuration.
 //
-// If *config_id* or *module_id* have the value -1, the same module/config is used as in the
-// process calling this function.
+// A value of 0 indicates no limit.
 //
-// The function arguments are passed as an array with the following structure:
-// [0 byte = type ID; 1..17 bytes = value as u128, ...]
-// The type ID follows the WebAssembly binary convention:
-//  - 0x7F => i32
-//  - 0x7E => i64
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_max_mailbox_size<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let max_mailbox_size = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_max_mailbox_size: Config ID doesn't exist")?
+        .get_max_mailbox_size();
+    Ok(max_mailbox_size.unwrap_or(0) as u64)
+}
+
+// Sets what happens if a message arrives to a full mailbox.
+//
+// Policies:
+// * 0 - Drop the arriving message.
+// * 1 - Drop the oldest message in the mailbox.
+// * 2 - Kill the process.
+//
+// Traps:
+// * If the policy is not one of the above.
+// * If the config ID doesn't exist.
+fn config_set_mailbox_overflow_policy<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    policy: u32,
+) -> Result<()> {
+    let policy = match policy {
+        0 => MailboxOverflowPolicy::DropNewest,
+        1 => MailboxOverflowPolicy::DropOldest,
+        2 => MailboxOverflowPolicy::KillProcess,
+        _ => {
+            return Err(anyhow!(
+                "lunatic::process::config_set_mailbox_overflow_policy: Unknown policy {policy}"
+            ))
+        }
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_mailbox_overflow_policy: Config ID doesn't exist")?
+        .set_mailbox_overflow_policy(policy);
+    Ok(())
+}
+
+// Returns the mailbox overflow policy of a configuration.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_mailbox_overflow_policy<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u32> {
+    let policy = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_mailbox_overflow_policy: Config ID doesn't exist")?
+        .get_mailbox_overflow_policy();
+    Ok(match policy {
+        MailboxOverflowPolicy::DropNewest => 0,
+        MailboxOverflowPolicy::DropOldest => 1,
+        MailboxOverflowPolicy::KillProcess => 2,
+    })
+}
+
+// Sets the number of waiting messages at which the mailbox of processes spawned from this
+// configuration is considered to be under pressure. Senders using
+// `lunatic::message::send_with_backpressure` are notified once it's reached.
+//
+// A value of 0 indicates no watermark.
+//
+// Traps:
+// * If mailbox_watermark is bigger than the platform maximum.
+// * If the config ID doesn't exist.
+fn config_set_mailbox_watermark<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    mailbox_watermark: u64,
+) -> Result<()> {
+    let mailbox_watermark = match mailbox_watermark {
+        0 => None,
+        mailbox_watermark => Some(usize::try_from(mailbox_watermark).or_trap(
+            "lunatic::process::config_set_mailbox_watermark: mailbox_watermark exceeds platform max",
+        )?),
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_mailbox_watermark: Config ID doesn't exist")?
+        .set_mailbox_watermark(mailbox_watermark);
+    Ok(())
+}
+
+// Returns the mailbox watermark of a configuration.
+//
+// A value of 0 indicates no watermark.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_mailbox_watermark<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let mailbox_watermark = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_mailbox_watermark: Config ID doesn't exist")?
+        .get_mailbox_watermark();
+    Ok(mailbox_watermark.unwrap_or(0) as u64)
+}
+
+// Sets the most verbose level of records that processes spawned from this configuration can log
+// with the `lunatic::log` functions. Less important records are dropped, the host logger can
+// filter the remaining ones further.
+//
+// Levels:
+// * 0 - Off
+// * 1 - Error
+// * 2 - Warn
+// * 3 - Info
+// * 4 - Debug
+// * 5 - Trace (default)
+//
+// Traps:
+// * If the level is not one of the above.
+// * If the config ID doesn't exist.
+fn config_set_max_log_level<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    level: u32,
+) -> Result<()> {
+    let level = match level {
+        0 => LogLevel::Off,
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        4 => LogLevel::Debug,
+        5 => LogLevel::Trace,
+        _ => {
+            return Err(anyhow!(
+                "lunatic::process::config_set_max_log_level: Unknown level {level}"
+            ))
+        }
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_max_log_level: Config ID doesn't exist")?
+        .set_max_log_level(level);
+    Ok(())
+}
+
+// Returns the most verbose log level of a configuration.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_max_log_level<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u32> {
+    let level = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_max_log_level: Config ID doesn't exist")?
+        .get_max_log_level();
+    Ok(level as u32)
+}
+
+// Enables or disables crash reports for processes spawned from this configuration.
+//
+// If a process with crash reports enabled fails, a JSON report with the wasm backtrace, the first
+// messages waiting in its mailbox and its links is written to the crash dump directory of the
+// node. Nothing is written if the node was started without a crash dump directory.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_crash_dumps<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    enabled: u32,
+) -> Result<()> {
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_crash_dumps: Config ID doesn't exist")?
+        .set_crash_dumps(enabled != 0);
+    Ok(())
+}
+
+// Returns 1 if crash reports are enabled for the configuration, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_crash_dumps<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u32> {
+    let enabled = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_crash_dumps: Config ID doesn't exist")?
+        .get_crash_dumps();
+    Ok(enabled as u32)
+}
+
+// Sets the maximum size of data messages in bytes for processes spawned from this configuration.
+//
+// Writing past the limit to a message traps. Sending a message exceeding it to such a process
+// returns an error code, messages that still arrive (e.g. through groups or from other nodes) are
+// dropped.
+//
+// A value of 0 indicates no limit.
+//
+// Traps:
+// * If max_message_size is bigger than the platform maximum.
+// * If the config ID doesn't exist.
+fn config_set_max_message_size<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_message_size: u64,
+) -> Result<()> {
+    let max_message_size = match max_message_size {
+        0 => None,
+        max_message_size => Some(usize::try_from(max_message_size).or_trap(
+            "lunatic::process::config_set_max_message_size: max_message_size exceeds platform max",
+        )?),
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_max_message_size: Config ID doesn't exist")?
+        .set_max_message_size(max_message_size);
+    Ok(())
+}
+
+// Returns the maximum message size of a configuration.
+//
+// A value of 0 indicates no limit.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_max_message_size<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64> {
+    let max_message_size = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_max_message_size: Config ID doesn't exist")?
+        .get_max_message_size();
+    Ok(max_message_size.unwrap_or(0) as u64)
+}
+
+// Returns 1 if processes spawned from this configuration can compile Wasm modules, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_compile_modules<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_compile_modules: Config ID doesn't exist")?
+        .can_compile_modules();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to compile
+// Wasm modules.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_compile_modules<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_compile_modules: Config ID doesn't exist")?
+        .set_can_compile_modules(can != 0);
+    Ok(())
+}
+
+// Returns 1 if processes spawned from this configuration can create other configurations,
+// otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_create_configs<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_create_configs: Config ID doesn't exist")?
+        .can_create_configs();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to create
+// other configuration.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_create_configs<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_create_configs: Config ID doesn't exist")?
+        .set_can_create_configs(can != 0);
+    Ok(())
+}
+
+// Returns 1 if processes spawned from this configuration can spawn sub-processes, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_spawn_processes<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_spawn_processes: Config ID doesn't exist")?
+        .can_spawn_processes();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to spawn
+// sub-processes.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_spawn_processes<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_spawn_processes: Config ID doesn't exist")?
+        .set_can_spawn_processes(can != 0);
+    Ok(())
+}
+
+// Returns 1 if processes spawned from this configuration can run inference with wasi-nn,
+// otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_use_nn<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_use_nn: Config ID doesn't exist")?
+        .can_use_nn();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to run
+// inference with the host functions of wasi-nn.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_use_nn<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_use_nn: Config ID doesn't exist")?
+        .set_can_use_nn(can != 0);
+    Ok(())
+}
+
+// Returns 1 if processes spawned from this configuration can change the tags of the node they are
+// running on, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_set_node_tags<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_set_node_tags: Config ID doesn't exist")?
+        .can_set_node_tags();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to change
+// the tags of the node they are running on with `lunatic::distributed::set_node_tag`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_set_node_tags<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_set_node_tags: Config ID doesn't exist")?
+        .set_can_set_node_tags(can != 0);
+    Ok(())
+}
+
+// Allows processes spawned from this configuration to connect to a host or network on ports
+// between **port_start** and **port_end** (inclusive).
+//
+// The target is a host name (`*.example.com` also matches all subdomains), an IP address or a
+// network in CIDR notation (e.g. `10.0.0.0/8`). Once the first rule is added, only matching
+// targets can be accessed. The rules are enforced when connecting, sending UDP datagrams and
+// resolving host names.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the target is not a valid host name, IP address or network.
+// * If the port range is empty or any port is bigger than 65535.
+// * If any memory outside the guest heap space is referenced.
+fn config_allow_network<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    port_start: u32,
+    port_end: u32,
+) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let (target, ports) = network_rule(
+        &mut caller,
+        target_str_ptr,
+        target_str_len,
+        port_start,
+        port_end,
+    )
+    .or_trap("lunatic::process::config_allow_network")?;
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_allow_network: Config ID doesn't exist")?
+        .allow_network(&target, ports)
+        .or_trap("lunatic::process::config_allow_network")?;
+    Ok(())
+}
+
+// Denies processes spawned from this configuration to connect to a host or network on ports
+// between **port_start** and **port_end** (inclusive). Deny rules take precedence over allow
+// rules.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the target is not a valid host name, IP address or network.
+// * If the port range is empty or any port is bigger than 65535.
+// * If any memory outside the guest heap space is referenced.
+fn config_deny_network<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    port_start: u32,
+    port_end: u32,
+) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let (target, ports) = network_rule(
+        &mut caller,
+        target_str_ptr,
+        target_str_len,
+        port_start,
+        port_end,
+    )
+    .or_trap("lunatic::process::config_deny_network")?;
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_deny_network: Config ID doesn't exist")?
+        .deny_network(&target, ports)
+        .or_trap("lunatic::process::config_deny_network")?;
+    Ok(())
+}
+
+// Allows processes spawned from this configuration to open sqlite databases with a path matching
+// the glob at **path_glob_str_ptr**, where `*` stands for any sequence of characters. Relative
+// globs are resolved against the working directory of the runtime.
+//
+// Once the first rule is added, only matching databases can be opened, and the directories still
+// need to be preopened. Databases can't grow beyond **max_db_size** bytes, a value of 0 indicates
+// no limit. If several rules match, the first one added applies. Without any rules, every database
+// inside a preopened directory can be opened and grow without a limit.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the glob is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn config_allow_sqlite<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    path_glob_str_ptr: u32,
+    path_glob_str_len: u32,
+    max_db_size: u64,
+) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let path_glob = memory
+        .data(&caller)
+        .get(path_glob_str_ptr as usize..(path_glob_str_ptr + path_glob_str_len) as usize)
+        .or_trap("lunatic::process::config_allow_sqlite")?;
+    let path_glob = std::str::from_utf8(path_glob)
+        .or_trap("lunatic::process::config_allow_sqlite")?
+        .to_string();
+    let max_db_size = match max_db_size {
+        0 => None,
+        max_db_size => Some(max_db_size),
+    };
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_allow_sqlite: Config ID doesn't exist")?
+        .allow_sqlite(&path_glob, max_db_size);
+    Ok(())
+}
+
+fn network_rule<T>(
+    caller: &mut Caller<T>,
+    target_str_ptr: u32,
+    target_str_len: u32,
+    port_start: u32,
+    port_end: u32,
+) -> Result<(String, RangeInclusive<u16>)> {
+    let memory = get_memory(caller)?;
+    let target = memory
+        .data(&caller)
+        .get(target_str_ptr as usize..(target_str_ptr + target_str_len) as usize)
+        .ok_or_else(|| anyhow!("target is outside the guest memory"))?;
+    let target = std::str::from_utf8(target)?.to_string();
+    let ports = u16::try_from(port_start)?..=u16::try_from(port_end)?;
+    Ok((target, ports))
+}
+
+// Spawns a new process using the passed in function inside a module as the entry point.
+//
+// If **link** is not 0, it will link the child and parent processes. The value of the **link**
+// argument will be used as the link-tag for the child. This means, if the child traps the parent
+// is going to get a signal back with the value used as the tag.
+//
+// If *config_id* or *module_id* have the value -1, the same module/config is used as in the
+// process calling this function.
+//
+// The function arguments are passed as an array with the following structure:
+// [0 byte = type ID; 1..17 bytes = value as u128, ...]
+// The type ID follows the WebAssembly binary convention:
+//  - 0x7F => i32
+//  - 0x7E => i64
 //  - 0x7B => v128
 // If any other value is used as type ID, this function will trap.
 //
 // Returns:
 // * 0 on success - The ID of the newly created process is written to **id_ptr**
-// * 1 on error   - The error ID is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, e.g. if the process limit of the
+//                  configuration is reached.
 //
 // Traps:
 // * If the module ID doesn't exist.
@@ -537,7 +1582,119 @@ where
 // * If any memory outside the guest heap space is referenced.
 #[allow(clippy::too_many_arguments)]
 fn spawn<T>(
-    mut caller: Caller<T>,
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState
+        + ProcessCtx<T>
+        + ErrorCtx
+        + LunaticWasiCtx
+        + ResourceLimiter
+        + Send
+        + Sync
+        + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        link,
+        config_id,
+        module_id,
+        SpawnEntry::Export {
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        },
+        id_ptr,
+        false,
+        false,
+    )
+}
+
+// Same as `spawn`, but the message currently in the scratch area is delivered as the first
+// message of the newly spawned process.
+//
+// The message is put into the child's mailbox before the child starts running, so it's
+// guaranteed to be there once the child calls `receive`. This removes the race between spawning
+// and sending the first message, e.g. when doing startup handshakes in supervisors.
+//
+// If spawning fails the message is dropped.
+//
+// Returns:
+// * 0 on success - The ID of the newly created process is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**
+//
+// Traps:
+// * If it's called without a message being inside of the scratch area.
+// * Same cases as `spawn`.
+#[allow(clippy::too_many_arguments)]
+fn spawn_with_message<T>(
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState
+        + ProcessCtx<T>
+        + ErrorCtx
+        + LunaticWasiCtx
+        + ResourceLimiter
+        + Send
+        + Sync
+        + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        link,
+        config_id,
+        module_id,
+        SpawnEntry::Export {
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        },
+        id_ptr,
+        true,
+        false,
+    )
+}
+
+// Same as `spawn`, but once the child's entry function returns an i64, the value is sent back to
+// the parent as a data message tagged with **link**. The message contains the value as 8 little
+// endian bytes.
+//
+// Together with the link this gives the parent a completion notification in both cases: a
+// message with the return value if the child succeeds, and a `LinkDied` message (or signal) with
+// the same tag if it fails. If the entry function doesn't return a single i64, no message is sent.
+//
+// Returns:
+// * 0 on success - The ID of the newly created process is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**
+//
+// Traps:
+// * Same cases as `spawn`.
+#[allow(clippy::too_many_arguments)]
+fn spawn_link_await<T>(
+    caller: Caller<T>,
     link: i64,
     config_id: i64,
     module_id: i64,
@@ -547,6 +1704,173 @@ fn spawn<T>(
     params_len: u32,
     id_ptr: u32,
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState
+        + ProcessCtx<T>
+        + ErrorCtx
+        + LunaticWasiCtx
+        + ResourceLimiter
+        + Send
+        + Sync
+        + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        link,
+        config_id,
+        module_id,
+        SpawnEntry::Export {
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        },
+        id_ptr,
+        false,
+        true,
+    )
+}
+
+// Spawns a new process running the module's WASI `_start` function, instead of an exported
+// function. This allows unmodified WASI command modules to be spawned and supervised.
+//
+// The command line arguments and environment variables of the process are taken from the
+// configuration. If the process exits with `proc_exit(0)` it's considered to have finished
+// normally, any other exit code is treated as a failure.
+//
+// The **link**, **config_id** and **module_id** arguments have the same meaning as in `spawn`.
+//
+// Returns:
+// * 0 on success - The ID of the newly created process is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, e.g. if the module doesn't export a
+//                  `_start` function.
+//
+// Traps:
+// * If the module ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn spawn_start<T>(
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState
+        + ProcessCtx<T>
+        + ErrorCtx
+        + LunaticWasiCtx
+        + ResourceLimiter
+        + Send
+        + Sync
+        + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        link,
+        config_id,
+        module_id,
+        SpawnEntry::Start,
+        id_ptr,
+        false,
+        false,
+    )
+}
+
+// Spawns a new process from the checkpoint at **path_str_ptr**, written by `checkpoint`.
+//
+// The process starts with the linear memory and the messages of the checkpoint and continues by
+// calling the function that was passed to `checkpoint`. The checkpoint can only be restored into
+// the module it was captured from, but the node and configuration can be different.
+//
+// The **link**, **config_id** and **module_id** arguments have the same meaning as in `spawn`.
+//
+// Returns:
+// * 0 on success - The ID of the newly created process is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, e.g. if the process doesn't have access
+//                  to the path or the checkpoint was captured from a different module.
+//
+// Traps:
+// * If the module ID doesn't exist.
+// * If the path is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn restore<T>(
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    path_str_ptr: u32,
+    path_str_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState
+        + ProcessCtx<T>
+        + ErrorCtx
+        + LunaticWasiCtx
+        + ResourceLimiter
+        + Send
+        + Sync
+        + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        link,
+        config_id,
+        module_id,
+        SpawnEntry::Checkpoint {
+            path_str_ptr,
+            path_str_len,
+        },
+        id_ptr,
+        false,
+        false,
+    )
+}
+
+// The entry point of a spawned process.
+enum SpawnEntry {
+    // An exported function, with the name and arguments read from guest memory.
+    Export {
+        func_str_ptr: u32,
+        func_str_len: u32,
+        params_ptr: u32,
+        params_len: u32,
+    },
+    // The WASI `_start` function of a command module.
+    Start,
+    // The function of a checkpoint, with the path of the checkpoint read from guest memory.
+    Checkpoint {
+        path_str_ptr: u32,
+        path_str_len: u32,
+    },
+}
+
+// Shared implementation of `spawn`, `spawn_with_message`, `spawn_link_await`, `spawn_start` and
+// `restore`.
+//
+// If **with_message** is true, the message from the scratch area is moved into the mailbox of the
+// new process before it's started.
+//
+// If **await_return** is true, the value returned by the new process' entry function is sent back
+// to the parent.
+#[allow(clippy::too_many_arguments)]
+fn spawn_process<T>(
+    mut caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    entry: SpawnEntry,
+    id_ptr: u32,
+    with_message: bool,
+    await_return: bool,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
 where
     T: ProcessState
         + ProcessCtx<T>
@@ -599,37 +1923,134 @@ where
                 .clone(),
         };
 
+        let process_counters = match acquire_process_counters(state, &config) {
+            Some(process_counters) => process_counters,
+            None => {
+                // The message is dropped, like with any other spawn error.
+                if with_message {
+                    caller.data_mut().message_scratch_area().take();
+                }
+                let error = anyhow!("Process limit of the config reached");
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                let memory = get_memory(&mut caller)?;
+                memory
+                    .write(caller, id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::process::spawn")?;
+                return Ok(1);
+            }
+        };
+        let state = caller.data();
         let mut new_state = state.new_state(module.clone(), config)?;
+        new_state.set_process_counters(process_counters);
+
+        // Deliver the initial message before the process is started.
+        if with_message {
+            let message = caller
+                .data_mut()
+                .message_scratch_area()
+                .take()
+                .or_trap("lunatic::process::spawn_with_message: no message in scratch area")?;
+            new_state.message_mailbox().push(message);
+        }
+
+        // Request the return value before the process is started, so that it's the first signal
+        // the child handles.
+        if await_return {
+            let id = caller.data().id();
+            let signal_mailbox = caller.data().signal_mailbox().clone();
+            let parent = WasmProcess::new(id, signal_mailbox.0);
+            let tag = match link {
+                0 => None,
+                tag => Some(tag),
+            };
+            new_state
+                .signal_mailbox()
+                .0
+                .send(Signal::AwaitReturn(tag, Arc::new(parent)))
+                .expect("receiver must exist at this point");
+        }
 
         let memory = get_memory(&mut caller)?;
-        let func_str = memory
-            .data(&caller)
-            .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
-            .or_trap("lunatic::process::spawn")?;
-        let function = std::str::from_utf8(func_str).or_trap("lunatic::process::spawn")?;
-        let params = memory
-            .data(&caller)
-            .get(params_ptr as usize..(params_ptr + params_len) as usize)
-            .or_trap("lunatic::process::spawn")?;
-        let params_chunks = &mut params.chunks_exact(17);
-        let params = params_chunks
-            .map(|chunk| {
-                let value = u128::from_le_bytes(chunk[1..].try_into()?);
-                let result = match chunk[0] {
-                    0x7F => Val::I32(value as i32),
-                    0x7E => Val::I64(value as i64),
-                    0x7B => Val::V128(value),
-                    _ => return Err(anyhow!("Unsupported type ID")),
+        let (function, params, checkpoint) = match entry {
+            SpawnEntry::Export {
+                func_str_ptr,
+                func_str_len,
+                params_ptr,
+                params_len,
+            } => {
+                let func_str = memory
+                    .data(&caller)
+                    .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
+                    .or_trap("lunatic::process::spawn")?;
+                let function = std::str::from_utf8(func_str).or_trap("lunatic::process::spawn")?;
+                let params = memory
+                    .data(&caller)
+                    .get(params_ptr as usize..(params_ptr + params_len) as usize)
+                    .or_trap("lunatic::process::spawn")?;
+                let params_chunks = &mut params.chunks_exact(17);
+                let params = params_chunks
+                    .map(|chunk| {
+                        let value = u128::from_le_bytes(chunk[1..].try_into()?);
+                        let result = match chunk[0] {
+                            0x7F => Val::I32(value as i32),
+                            0x7E => Val::I64(value as i64),
+                            0x7B => Val::V128(value),
+                            _ => return Err(anyhow!("Unsupported type ID")),
+                        };
+                        Ok(result)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if !params_chunks.remainder().is_empty() {
+                    return Err(anyhow!(
+                        "Params array must be in chunks of 17 bytes, but {} bytes remained",
+                        params_chunks.remainder().len()
+                    ));
+                }
+                (function.to_string(), params, None)
+            }
+            SpawnEntry::Start => {
+                // Fail early if the module is not a WASI command module.
+                let has_start = module.exports().any(|export| {
+                    export.name() == "_start" && matches!(export.ty(), ExternType::Func(_))
+                });
+                if !has_start {
+                    let error = anyhow!("Module doesn't export a `_start` function");
+                    let error_id = caller.data_mut().error_resources_mut().add(error);
+                    memory
+                        .write(caller, id_ptr as usize, &error_id.to_le_bytes())
+                        .or_trap("lunatic::process::spawn_start")?;
+                    return Ok(1);
+                }
+                ("_start".to_string(), Vec::new(), None)
+            }
+            SpawnEntry::Checkpoint {
+                path_str_ptr,
+                path_str_len,
+            } => {
+                let path = memory
+                    .data(&caller)
+                    .get(path_str_ptr as usize..(path_str_ptr + path_str_len) as usize)
+                    .or_trap("lunatic::process::restore")?;
+                let path = std::str::from_utf8(path).or_trap("lunatic::process::restore")?;
+                let path = Path::new(path).to_path_buf();
+                let checkpoint = match caller.data().config().can_access_fs_location(&path) {
+                    Ok(()) => tokio::task::spawn_blocking(move || Checkpoint::read(&path))
+                        .await
+                        .or_trap("lunatic::process::restore")?,
+                    Err(error_message) => Err(anyhow!(error_message)),
                 };
-                Ok(result)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        if !params_chunks.remainder().is_empty() {
-            return Err(anyhow!(
-                "Params array must be in chunks of 17 bytes, but {} bytes remained",
-                params_chunks.remainder().len()
-            ));
-        }
+                match checkpoint {
+                    Ok(checkpoint) => (String::new(), Vec::new(), Some(checkpoint)),
+                    Err(error) => {
+                        let error_id = caller.data_mut().error_resources_mut().add(error);
+                        memory
+                            .write(caller, id_ptr as usize, &error_id.to_le_bytes())
+                            .or_trap("lunatic::process::restore")?;
+                        return Ok(1);
+                    }
+                }
+            }
+        };
         // Should processes be linked together?
         let link: Option<(Option<i64>, Arc<dyn Process>)> = match link {
             0 => None,
@@ -666,11 +2087,21 @@ where
 
         // set state instead of config TODO
         let env = caller.data().environment();
-        let (proc_or_error_id, result) = match lunatic_process::wasm::spawn_wasm(
-            env, runtime, &module, new_state, function, params, link,
-        )
-        .await
-        {
+        let spawned = match checkpoint {
+            Some(checkpoint) => {
+                lunatic_process::wasm::restore_wasm(
+                    env, runtime, &module, new_state, checkpoint, link,
+                )
+                .await
+            }
+            None => {
+                lunatic_process::wasm::spawn_wasm(
+                    env, runtime, &module, new_state, &function, params, link,
+                )
+                .await
+            }
+        };
+        let (proc_or_error_id, result) = match spawned {
             Ok((_, process)) => (process.id(), 0),
             Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
         };
@@ -682,6 +2113,28 @@ where
     })
 }
 
+// Takes a slot in the process limits of all subtrees a new process spawned from `config` belongs
+// to. These are the subtrees of the parent and the subtree of `config`, if it has a process limit.
+fn acquire_process_counters<T>(state: &T, config: &T::Config) -> Option<ProcessCounterGuard>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let mut counters = state.process_counters().counters().to_vec();
+    let config_counter = config
+        .process_counter_id()
+        .and_then(|counter_id| state.environment().get_process_counter(counter_id));
+    if let Some(config_counter) = config_counter {
+        if !counters
+            .iter()
+            .any(|counter| Arc::ptr_eq(counter, &config_counter))
+        {
+            counters.push(config_counter);
+        }
+    }
+    ProcessCounterGuard::acquire(counters)
+}
+
 // Looks up or spawns a new process.
 //
 // This function has a similar signature as `spawn`, but it first tries to look up a process in the registry
@@ -692,6 +2145,12 @@ where
 // Different than spawn, the lookup can result in a process running on a different node. This means that the
 // node_id also needs to be returned through a pointer.
 //
+// If the registered process is running on this node, but doesn't exist anymore, a new process is spawned
+// and registered in its place. If spawning fails, nothing is registered under `name`.
+//
+// This function is also exposed as `lunatic::process::spawn_named`. Guests should use it instead of
+// holding the registry lock across multiple host calls.
+//
 // Returns:
 // * 0 on success        - The ID of the newly created process is written to **id_ptr**
 // * 1 on error          - The error ID is written to **id_ptr**
@@ -743,7 +2202,15 @@ where
         // Lock the registry for every other process before lookup.
         let registry = state.registry().clone();
         let mut registry = registry.write().await;
-        let process = registry.get(name).copied();
+        let this_node_id = state
+            .distributed()
+            .as_ref()
+            .map(|d| d.node_id())
+            .unwrap_or(0);
+        // Ignore local processes that don't exist anymore.
+        let process = registry.get(name).copied().filter(|(node_id, process_id)| {
+            *node_id != this_node_id || state.environment().get_process(*process_id).is_some()
+        });
 
         if let Some((node_id, process_id)) = process {
             // Return the process from the registry.
@@ -800,7 +2267,21 @@ where
                     .clone(),
             };
 
+            let process_counters = match acquire_process_counters(state, &config) {
+                Some(process_counters) => process_counters,
+                None => {
+                    let error = anyhow!("Process limit of the config reached");
+                    let error_id = state.error_resources_mut().add(error);
+                    memory_slice
+                        .get_mut(id_ptr as usize..(id_ptr + 8) as usize)
+                        .or_trap("lunatic::process::get_or_spawn")?
+                        .write(&error_id.to_le_bytes())
+                        .or_trap("lunatic::process::get_or_spawn")?;
+                    return Ok(1);
+                }
+            };
             let mut new_state = state.new_state(module.clone(), config)?;
+            new_state.set_process_counters(process_counters);
 
             let func_str = memory_slice
                 .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
@@ -874,15 +2355,10 @@ where
                 Err(error) => (state.error_resources_mut().add(error), 1),
             };
 
-            let node_id = state
-                .distributed()
-                .as_ref()
-                .map(|d| d.node_id())
-                .unwrap_or(0);
             memory_slice
                 .get_mut(node_id_ptr as usize..(node_id_ptr + 8) as usize)
                 .or_trap("lunatic::process::get_or_spawn")?
-                .write(&node_id.to_le_bytes())
+                .write(&this_node_id.to_le_bytes())
                 .or_trap("lunatic::process::get_or_spawn")?;
 
             memory_slice
@@ -892,7 +2368,9 @@ where
                 .or_trap("lunatic::process::get_or_spawn")?;
 
             // Register newly spawned process under correct name
-            registry.insert(name, (node_id, proc_or_error_id));
+            if result == 0 {
+                registry.insert(name, (this_node_id, proc_or_error_id));
+            }
 
             Ok(result)
         }
@@ -917,13 +2395,30 @@ fn sleep_ms<T: ProcessState + ProcessCtx<T>>(
 // 1. `trap == 0` the received signal will be turned into a signal message and put into the mailbox.
 // 2. `trap != 0` the process will die and notify all linked processes of its death.
 //
-// The default behaviour for a newly spawned process is 2.
-fn die_when_link_dies<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, trap: u32) {
+// The default behaviour for a newly spawned process is 2.
+fn die_when_link_dies<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, trap: u32) {
+    caller
+        .data_mut()
+        .signal_mailbox()
+        .0
+        .send(Signal::DieWhenLinkDies(trap != 0))
+        .expect("The signal is sent to itself and the receiver must exist at this point");
+}
+
+// Defines what happens to this process if it receives a shutdown request.
+//
+// There are 2 options:
+// 1. `trap == 0` the process will die right away, same as if it was killed.
+// 2. `trap != 0` the shutdown request will be turned into a message and put into the mailbox.
+//    The process is killed if it doesn't finish before the grace period expires.
+//
+// The default behaviour for a newly spawned process is 1.
+fn trap_shutdown<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, trap: u32) {
     caller
         .data_mut()
         .signal_mailbox()
         .0
-        .send(Signal::DieWhenLinkDies(trap != 0))
+        .send(Signal::TrapShutdown(trap != 0))
         .expect("The signal is sent to itself and the receiver must exist at this point");
 }
 
@@ -937,6 +2432,50 @@ fn environment_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().environment().id()
 }
 
+// Links the current process to **process_id** and sends the message from the scratch area to it
+// in one step.
+//
+// Different from calling `link` and `send` separately, it's guaranteed that the current process is
+// notified with **tag** if **process_id** dies or doesn't exist anymore, even if it dies before
+// handling the link request. This allows waiting on a reply without the risk of waiting forever.
+//
+// Traps:
+// * If it's called before creating the next message.
+fn link_and_send<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    tag: i64,
+) -> Result<()> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::process::link_and_send::no_message")?;
+    let tag = match tag {
+        0 => None,
+        tag => Some(tag),
+    };
+    // Create handle to itself
+    let id = caller.data().id();
+    let signal_mailbox = caller.data().signal_mailbox().clone();
+    let this_process = WasmProcess::new(id, signal_mailbox.0.clone());
+
+    let process = caller.data().environment().get_process(process_id);
+    let signal = match process {
+        Some(process) if process.try_send(Signal::Link(tag, Arc::new(this_process))) => {
+            // Signals are processed in order, the link is established before the message arrives.
+            process.send(Signal::Message(message));
+            Signal::Link(tag, process)
+        }
+        _ => Signal::LinkDied(None, process_id, tag, DeathReason::NoProcess, None),
+    };
+    signal_mailbox
+        .0
+        .send(signal)
+        .expect("The signal is sent to itself and the receiver must exist at this point");
+    Ok(())
+}
+
 // Link current process to **process_id**. This is not an atomic operation, any of the 2 processes
 // could fail before processing the `Link` signal and may not notify the other.
 //
@@ -974,7 +2513,13 @@ fn link<T: ProcessState + ProcessCtx<T>>(
             .data_mut()
             .signal_mailbox()
             .0
-            .send(Signal::LinkDied(process_id, tag, DeathReason::NoProcess))
+            .send(Signal::LinkDied(
+                None,
+                process_id,
+                tag,
+                DeathReason::NoProcess,
+                None,
+            ))
             .expect(
                 "The LinkDied signal is sent to itself and the receiver must exist at this point",
             );
@@ -995,6 +2540,7 @@ fn unlink<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u6
 
     if let Some(process) = process {
         process.send(Signal::UnLink {
+            node_id: None,
             process_id: this_process_id,
         });
     }
@@ -1004,7 +2550,10 @@ fn unlink<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u6
         .data_mut()
         .signal_mailbox()
         .0
-        .send(Signal::UnLink { process_id })
+        .send(Signal::UnLink {
+            node_id: None,
+            process_id,
+        })
         .expect("The signal is sent to itself and the receiver must exist at this point");
 
     Ok(())
@@ -1012,10 +2561,14 @@ fn unlink<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u6
 
 // Start monitoring **process_id**. This is not an atomic operation.
 //
-// Traps:
-// * If the process ID doesn't exist.
-fn monitor<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> Result<()> {
-    // Send link signal to other process
+// Different from links, monitors are unidirectional. Once the monitored process dies, a
+// `ProcessDied` message containing the process ID and reason of death is put into the mailbox
+// of the current process. The death of a monitored process will never kill the current process.
+//
+// If the process doesn't exist, the `ProcessDied` message is delivered right away with the
+// "no process" reason.
+fn monitor<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u64) -> Result<()> {
+    // Send monitor signal to other process
     let process = caller.data().environment().get_process(process_id);
 
     if let Some(process) = process {
@@ -1023,27 +2576,31 @@ fn monitor<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64)
         let signal_mailbox = caller.data().signal_mailbox().clone();
         let this_process = WasmProcess::new(id, signal_mailbox.0);
         process.send(Signal::Monitor(Arc::new(this_process)));
+    } else {
+        caller
+            .data_mut()
+            .signal_mailbox()
+            .0
+            .send(Signal::ProcessDied(process_id, DeathReason::NoProcess))
+            .expect(
+                "The ProcessDied signal is sent to itself and the receiver must exist at this point",
+            );
     }
 
     Ok(())
 }
 
-// Stop monitoring **process_id**. This is not an atomic operation.
-//
-// Traps:
-// * If the process ID doesn't exist.
-fn stop_monitoring<T: ProcessState + ProcessCtx<T>>(
-    caller: Caller<T>,
-    process_id: u64,
-) -> Result<()> {
-    // Create handle to itself
+// Stop monitoring **process_id**. This is not an atomic operation, a `ProcessDied` message
+// could already be in the mailbox when this function is called.
+fn demonitor<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> Result<()> {
     let this_process_id = caller.data().id();
 
-    // Send unlink signal to other process
+    // Send stop monitoring signal to other process
     let process = caller.data().environment().get_process(process_id);
 
     if let Some(process) = process {
         process.send(Signal::StopMonitoring {
+            node_id: None,
             process_id: this_process_id,
         });
     }
@@ -1058,7 +2615,207 @@ fn stop_monitoring<T: ProcessState + ProcessCtx<T>>(
 fn kill<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> Result<()> {
     // Send kill signal to process
     if let Some(process) = caller.data().environment().get_process(process_id) {
-        process.send(Signal::Kill);
+        process.send(Signal::Kill(None));
+    }
+    Ok(())
+}
+
+// Send a Kill signal carrying a **reason** to **process_id**.
+//
+// Linked processes that don't die together with the killed process will receive the reason
+// inside the `LinkDied` message. This allows supervisors to distinguish between different kinds of
+// terminations.
+//
+// Traps:
+// * If the reason is bigger than 1 KiB.
+// * If any memory outside the guest heap space is referenced.
+fn kill_with_reason<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    reason_ptr: u32,
+    reason_len: u32,
+) -> Result<()> {
+    if reason_len as usize > MAX_KILL_REASON_SIZE {
+        return Err(anyhow!(
+            "lunatic::process::kill_with_reason: Reason is bigger than {MAX_KILL_REASON_SIZE} bytes"
+        ));
+    }
+    let memory = get_memory(&mut caller)?;
+    let reason = memory
+        .data(&caller)
+        .get(reason_ptr as usize..(reason_ptr as usize + reason_len as usize))
+        .or_trap("lunatic::process::kill_with_reason")?
+        .to_vec();
+    if let Some(process) = caller.data().environment().get_process(process_id) {
+        process.send(Signal::Kill(Some(reason)));
+    }
+    Ok(())
+}
+
+// Send a Shutdown signal to **process_id**.
+//
+// If the process traps shutdowns, it will receive a `Shutdown` message and has **timeout_ms**
+// milliseconds to finish before it's killed. Otherwise, it's killed right away.
+fn shutdown<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    process_id: u64,
+    timeout_ms: u64,
+) -> Result<()> {
+    if let Some(process) = caller.data().environment().get_process(process_id) {
+        process.send(Signal::Shutdown(timeout_ms));
+    }
+    Ok(())
+}
+
+// Send a Suspend signal to **process_id**.
+//
+// A suspended process is not executed anymore (and doesn't consume fuel) until it receives a
+// Resume signal. It will still process other signals, like Kill, while suspended. If the process
+// suspends itself, it will stop at the next point it yields back to the runtime.
+fn suspend<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> Result<()> {
+    if let Some(process) = caller.data().environment().get_process(process_id) {
+        process.send(Signal::Suspend);
+    }
+    Ok(())
+}
+
+// Hibernates the current process to release its memory while it waits for the next message.
+//
+// The Wasm instance is dropped together with its stack, this function never returns. Everything
+// else belonging to the process is kept, e.g. the mailbox, links, resources and the process-local
+// storage. Of the linear memory only the part up to the last non-zero byte is kept. Once a message
+// arrives, the module is instantiated again, the linear memory is restored and the exported
+// function with the name at **func_str_ptr** is called without arguments. Like with `checkpoint`,
+// globals are not restored, so the function can only depend on state kept in linear memory.
+//
+// Hibernating inside of `lunatic::trap::catch` is not supported, the catch will return as if the
+// process trapped.
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the module doesn't export a function with this name.
+// * If any memory outside the guest heap space is referenced.
+fn hibernate<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    func_str_ptr: u32,
+    func_str_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let func_str = memory
+        .data(&caller)
+        .get(func_str_ptr as usize..(func_str_ptr as usize + func_str_len as usize))
+        .or_trap("lunatic::process::hibernate")?;
+    let function = std::str::from_utf8(func_str)
+        .or_trap("lunatic::process::hibernate")?
+        .to_string();
+    caller
+        .get_export(&function)
+        .and_then(|export| export.into_func())
+        .or_trap("lunatic::process::hibernate: function not exported")?;
+    let data = memory.data(&caller);
+    // Zeroed pages at the end of the memory don't need to be kept
+    let used = data
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    Err(anyhow::Error::new(Hibernate {
+        function,
+        memory: data[..used].to_vec(),
+        memory_size: data.len() as u64,
+    }))
+}
+
+// Writes a checkpoint of the current process to the file at **path_str_ptr**.
+//
+// The checkpoint contains the linear memory and the messages waiting in the mailbox. It can be
+// restored with `restore` (or `lunatic run --restore`) as a new process of the same module, which
+// continues by calling the exported function with the name at **func_str_ptr** without arguments.
+// The wasm stack and globals are not part of the checkpoint, so the function can only depend on
+// state kept in linear memory. The current process keeps running after the checkpoint is written.
+//
+// Messages with resources and link, monitor or shutdown messages in the mailbox can't be part of a
+// checkpoint.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**, e.g. if the process doesn't have
+//                  access to the path or the mailbox contains messages that can't be checkpointed.
+//
+// Traps:
+// * If the path or function string is not a valid utf8 string.
+// * If the module doesn't export a function with this name.
+// * If any memory outside the guest heap space is referenced.
+fn checkpoint<T: ProcessState + ProcessCtx<T> + ErrorCtx + Send + Sync>(
+    mut caller: Caller<T>,
+    path_str_ptr: u32,
+    path_str_len: u32,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T::Config: ProcessConfigCtx,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let path = memory
+            .data(&caller)
+            .get(path_str_ptr as usize..(path_str_ptr as usize + path_str_len as usize))
+            .or_trap("lunatic::process::checkpoint")?;
+        let path = std::str::from_utf8(path)
+            .or_trap("lunatic::process::checkpoint")?
+            .to_string();
+        let func_str = memory
+            .data(&caller)
+            .get(func_str_ptr as usize..(func_str_ptr as usize + func_str_len as usize))
+            .or_trap("lunatic::process::checkpoint")?;
+        let function = std::str::from_utf8(func_str)
+            .or_trap("lunatic::process::checkpoint")?
+            .to_string();
+        caller
+            .get_export(&function)
+            .and_then(|export| export.into_func())
+            .or_trap("lunatic::process::checkpoint: function not exported")?;
+
+        let path = Path::new(&path).to_path_buf();
+        let result = match caller.data().config().can_access_fs_location(&path) {
+            Ok(()) => {
+                let state = caller.data();
+                Checkpoint::capture(
+                    state.module().source(),
+                    &function,
+                    memory.data(&caller),
+                    state.message_mailbox(),
+                )
+            }
+            Err(error_message) => Err(anyhow!(error_message)),
+        };
+        // Linear memory can be large, write it without blocking the executor
+        let result = match result {
+            Ok(checkpoint) => tokio::task::spawn_blocking(move || checkpoint.write(&path))
+                .await
+                .or_trap("lunatic::process::checkpoint")?,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::process::checkpoint")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Send a Resume signal to **process_id**, continuing the execution of a suspended process.
+//
+// Resuming a process that is not suspended has no effect.
+fn resume<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> Result<()> {
+    if let Some(process) = caller.data().environment().get_process(process_id) {
+        process.send(Signal::Resume);
     }
     Ok(())
 }
@@ -1071,3 +2828,356 @@ fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -
         .get_process(process_id)
         .is_some() as i32
 }
+
+// Writes the resource usage of **process_id** to **stats_ptr**.
+//
+// The stats are written as 4 little endian u64 values:
+// [consumed fuel | linear memory size in bytes | mailbox length | number of links]
+//
+// Consumed fuel is tracked in increments of 100k instructions (one unit of compute).
+//
+// Returns:
+// * 0 on success
+// * 1 if the process doesn't exist in the current environment or its usage is not tracked.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn stats<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    stats_ptr: u32,
+) -> Result<u32> {
+    let stats = caller
+        .data()
+        .environment()
+        .get_process(process_id)
+        .and_then(|process| process.stats());
+    match stats {
+        Some(stats) => {
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(&mut caller, stats_ptr as usize, &stats.to_le_bytes())
+                .or_trap("lunatic::process::stats")?;
+            Ok(0)
+        }
+        None => Ok(1),
+    }
+}
+
+// Copies the IDs of processes running in the environment **env_id** to **ids_ptr**.
+//
+// If **prefix_len** is not 0, only processes registered under a name starting with the prefix at
+// **prefix_ptr** are listed. The IDs are written in ascending order as little endian u64 values.
+// At most **ids_len** IDs are copied, calling it with an **ids_len** of 0 can be used to query the
+// number of processes first.
+//
+// Processes can only list the environment they are running in.
+//
+// Returns:
+// * The number of matching processes, can be bigger than **ids_len**.
+// * -1 if **env_id** is not the environment of the calling process.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn list<T, E>(
+    mut caller: Caller<T>,
+    env_id: u64,
+    prefix_ptr: u32,
+    prefix_len: u32,
+    ids_ptr: u32,
+    ids_len: u32,
+) -> Box<dyn Future<Output = Result<i64>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + DistributedCtx<E> + Send + Sync,
+    E: Environment,
+{
+    Box::new(async move {
+        let env = caller.data().environment();
+        if env.id() != env_id {
+            return Ok(-1);
+        }
+
+        let mut ids: Vec<u64> = if prefix_len == 0 {
+            env.iter_processes().map(|process| process.id()).collect()
+        } else {
+            let memory = get_memory(&mut caller)?;
+            let prefix = memory
+                .data(&caller)
+                .get(prefix_ptr as usize..(prefix_ptr as usize + prefix_len as usize))
+                .or_trap("lunatic::process::list")?
+                .to_vec();
+            let this_node_id = caller
+                .data()
+                .distributed()
+                .as_ref()
+                .map(|d| d.node_id())
+                .unwrap_or(0);
+            let registry = caller.data().registry().read().await;
+            registry
+                .iter()
+                .filter(|(name, (node_id, process_id))| {
+                    name.as_bytes().starts_with(&prefix)
+                        && *node_id == this_node_id
+                        && env.get_process(*process_id).is_some()
+                })
+                .map(|(_, (_, process_id))| *process_id)
+                .collect()
+        };
+        ids.sort_unstable();
+        ids.dedup();
+
+        let copy_len = ids.len().min(ids_len as usize);
+        let bytes: Vec<u8> = ids[..copy_len]
+            .iter()
+            .flat_map(|id| id.to_le_bytes())
+            .collect();
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, ids_ptr as usize, &bytes)
+            .or_trap("lunatic::process::list")?;
+        Ok(ids.len() as i64)
+    })
+}
+
+// Creates a new process group in the current environment and returns its ID.
+//
+// The group only takes up space while it has members. It's removed once its last member leaves
+// or dies and can be joined again afterwards. Until a process joins it, the group counts as not
+// existing for `group_leave`, `group_kill` and `group_send`.
+fn group_create<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().environment().create_group()
+}
+
+// Adds **process_id** to the group **group_id**.
+//
+// Returns:
+// * 0 on success
+// * 1 if the group or process doesn't exist in the current environment.
+fn group_join<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    group_id: u64,
+    process_id: u64,
+) -> u32 {
+    let joined = caller.data().environment().join_group(group_id, process_id);
+    !joined as u32
+}
+
+// Removes **process_id** from the group **group_id**.
+//
+// Returns:
+// * 0 on success
+// * 1 if the group doesn't exist.
+fn group_leave<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    group_id: u64,
+    process_id: u64,
+) -> u32 {
+    let left = caller
+        .data()
+        .environment()
+        .leave_group(group_id, process_id);
+    !left as u32
+}
+
+// Sends a Kill signal to all members of the group **group_id**.
+//
+// This is an atomic operation, no process can join or leave the group while the signals are being
+// sent.
+//
+// Returns:
+// * 0 on success
+// * 1 if the group doesn't exist.
+fn group_kill<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, group_id: u64) -> u32 {
+    let killed = caller.data().environment().kill_group(group_id);
+    !killed as u32
+}
+
+// Sends the message from the scratch area to all members of the group **group_id**.
+//
+// Each member receives a copy of the message buffer. Messages with resources attached can't be
+// copied and stay in the scratch area.
+//
+// Returns:
+// * 0 on success
+// * 1 if the group doesn't exist.
+// * 2 if resources are attached to the message.
+//
+// Traps:
+// * If it's called before creating the next message.
+fn group_send<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    group_id: u64,
+) -> Result<u32> {
+    let has_resources = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::process::group_send::no_message")?
+        .has_resources();
+    if has_resources {
+        return Ok(2);
+    }
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::process::group_send::no_message")?;
+    let sent = caller.data().environment().send_group(group_id, message);
+    Ok(!sent as u32)
+}
+
+// Stores **value** under **key** in the process-local storage, replacing any previous value.
+//
+// The storage lives in the host-side state of the process and is not affected by the guest
+// unwinding its stack, e.g. when a trap is caught with `lunatic::trap::catch`. It's not inherited
+// by spawned processes.
+//
+// Keys can be up to 1 KiB and values up to 64 KiB big. The storage holds at most 1024 entries
+// with a combined size of 1 MiB.
+//
+// Returns:
+// * 0 if the value was stored.
+// * 1 if the key or value is too big, or the storage is full. Nothing is changed in this case.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn local_set<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::process::local_set")?;
+    let value = memory_slice
+        .get(value_ptr as usize..(value_ptr as usize + value_len as usize))
+        .or_trap("lunatic::process::local_set")?;
+    let stored = state.local_storage_mut().insert(key, value);
+    Ok(!stored as u32)
+}
+
+// Copies the value stored under **key** in the process-local storage to **value_ptr**.
+//
+// At most **value_len** bytes are copied. Calling it with a **value_len** of 0 can be used to
+// query the size of the value first.
+//
+// Returns:
+// * The size of the stored value in bytes.
+// * -1 if nothing is stored under **key**.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn local_get<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<i64> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::process::local_get")?;
+    let value = match state.local_storage().get(key) {
+        Some(value) => value,
+        None => return Ok(-1),
+    };
+    let copy_len = value.len().min(value_len as usize);
+    memory_slice
+        .get_mut(value_ptr as usize..(value_ptr as usize + copy_len))
+        .or_trap("lunatic::process::local_get")?
+        .copy_from_slice(&value[..copy_len]);
+    Ok(value.len() as i64)
+}
+
+// Removes the value stored under **key** from the process-local storage.
+//
+// Returns:
+// * 0 if the value was removed.
+// * 1 if nothing is stored under **key**.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn local_delete<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::process::local_delete")?;
+    let removed = state.local_storage_mut().remove(key);
+    Ok(!removed as u32)
+}
+
+// Sets the trace context of the process, e.g. the W3C `traceparent` of the current span. A
+// **context_len** of 0 clears it.
+//
+// The trace context is attached as the `traceparent` header to all data messages created
+// afterwards, also ones sent to other nodes, unless the header is overwritten. Receiving a data
+// message replaces the trace context of the process with the one of the message, so that the
+// span handling the message can use it as parent. Processes spawned on the same node start with the trace
+// context of the parent.
+//
+// Traps:
+// * If the context is bigger than 4 KiB.
+// * If any memory outside the guest heap space is referenced.
+fn set_trace_context<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    context_ptr: u32,
+    context_len: u32,
+) -> Result<()> {
+    if context_len as usize > MAX_HEADERS_SIZE {
+        return Err(anyhow!(
+            "lunatic::process::set_trace_context: Context is bigger than {MAX_HEADERS_SIZE} bytes"
+        ));
+    }
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let context = memory_slice
+        .get(context_ptr as usize..(context_ptr as usize + context_len as usize))
+        .or_trap("lunatic::process::set_trace_context")?;
+    let context = match context.len() {
+        0 => None,
+        _ => Some(context.to_vec()),
+    };
+    state.set_trace_context(context);
+    Ok(())
+}
+
+// Copies the trace context of the process to **context_ptr**.
+//
+// At most **context_len** bytes are copied. Calling it with a **context_len** of 0 can be used to
+// query the size of the context first.
+//
+// Returns:
+// * The size of the trace context in bytes.
+// * -1 if the process has no trace context.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn get_trace_context<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    context_ptr: u32,
+    context_len: u32,
+) -> Result<i64> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let context = match state.trace_context() {
+        Some(context) => context,
+        None => return Ok(-1),
+    };
+    let copy_len = context.len().min(context_len as usize);
+    memory_slice
+        .get_mut(context_ptr as usize..(context_ptr as usize + copy_len))
+        .or_trap("lunatic::process::get_trace_context")?
+        .copy_from_slice(&context[..copy_len]);
+    Ok(context.len() as i64)
+}
@@ -0,0 +1,163 @@
+//! Checkpoints of a process' linear memory and mailbox.
+//!
+//! A checkpoint can be restored as a new process of the same module, on the same or on another
+//! node. The restored instance starts with the linear memory of the checkpoint and continues by
+//! calling an exported function, the wasm stack and globals are not part of the checkpoint. This
+//! works the same way as waking up from hibernation.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mailbox::MessageMailbox,
+    message::{DataMessage, Message, MessageHeaders},
+    runtimes::{artifact_cache::artifact_key, RawWasm},
+};
+
+// Changes each time the format of the checkpoint file changes
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    // Hash of the module source, a checkpoint can only be restored into the same module
+    module: String,
+    function: String,
+    memory: Vec<u8>,
+    messages: Vec<CheckpointMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointMessage {
+    tag: Option<i64>,
+    buffer: Vec<u8>,
+    headers: MessageHeaders,
+}
+
+impl Checkpoint {
+    /// Captures the linear memory and the messages waiting in the mailbox.
+    ///
+    /// Only data messages without resources can be part of a checkpoint, because resources and
+    /// the processes referenced by link and monitor messages don't outlive the node.
+    pub fn capture(
+        module: &RawWasm,
+        function: &str,
+        memory: &[u8],
+        mailbox: &MessageMailbox,
+    ) -> Result<Self> {
+        let messages = mailbox
+            .snapshot(usize::MAX)
+            .into_iter()
+            .map(|message| match message {
+                Message::Data(message) if message.resources.is_empty() => Ok(CheckpointMessage {
+                    tag: message.tag,
+                    buffer: message.buffer,
+                    headers: message.headers,
+                }),
+                Message::Data(_) => Err(anyhow!(
+                    "Messages with resources can't be part of a checkpoint"
+                )),
+                _ => Err(anyhow!(
+                    "Link, monitor and shutdown messages can't be part of a checkpoint"
+                )),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            version: VERSION,
+            module: artifact_key(&[module.as_slice()]),
+            function: function.to_string(),
+            memory: memory.to_vec(),
+            messages,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let checkpoint = bincode::serialize(self)?;
+        std::fs::write(path, checkpoint)
+            .with_context(|| format!("Failed to write checkpoint '{}'", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let checkpoint = std::fs::read(path)
+            .with_context(|| format!("Failed to read checkpoint '{}'", path.display()))?;
+        let checkpoint: Self = bincode::deserialize(&checkpoint)
+            .with_context(|| format!("'{}' is not a valid checkpoint", path.display()))?;
+        if checkpoint.version != VERSION {
+            return Err(anyhow!(
+                "Checkpoint '{}' was written by an incompatible version of lunatic",
+                path.display()
+            ));
+        }
+        Ok(checkpoint)
+    }
+
+    /// Fails if the checkpoint was captured from another module.
+    pub fn check_module(&self, module: &RawWasm) -> Result<()> {
+        if self.module != artifact_key(&[module.as_slice()]) {
+            return Err(anyhow!("Checkpoint was captured from a different module"));
+        }
+        Ok(())
+    }
+
+    /// The exported function the restored process continues with.
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// Splits the checkpoint into the linear memory and the messages to put into the mailbox.
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Vec<Message>) {
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|message| {
+                let mut data = DataMessage::new_from_vec(message.tag, message.buffer);
+                data.headers = message.headers;
+                Message::Data(data)
+            })
+            .collect();
+        (self.memory, messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let module = RawWasm::from(b"module".to_vec());
+        let mailbox = MessageMailbox::default();
+        mailbox.push(Message::Data(DataMessage::new_from_vec(
+            Some(7),
+            vec![1, 2],
+        )));
+        let checkpoint = Checkpoint::capture(&module, "resume", &[0, 1, 2, 3], &mailbox).unwrap();
+
+        let path = std::env::temp_dir().join(format!("lunatic-checkpoint-{}", std::process::id()));
+        checkpoint.write(&path).unwrap();
+        let checkpoint = Checkpoint::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        checkpoint.check_module(&module).unwrap();
+        assert!(checkpoint
+            .check_module(&RawWasm::from(b"other".to_vec()))
+            .is_err());
+        assert_eq!(checkpoint.function(), "resume");
+        let (memory, messages) = checkpoint.into_parts();
+        assert_eq!(memory, vec![0, 1, 2, 3]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].tag(), Some(7));
+    }
+
+    #[test]
+    fn messages_with_resources_are_rejected() {
+        let mailbox = MessageMailbox::default();
+        let mut message = DataMessage::new_from_vec(None, Vec::new());
+        message.add_resource(std::sync::Arc::new(()));
+        mailbox.push(Message::Data(message));
+        let module = RawWasm::from(Vec::new());
+        assert!(Checkpoint::capture(&module, "resume", &[], &mailbox).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+/*!
+A monotonic clock shared by all processes running on the same node.
+
+Guests can't use `Instant`s directly, so points in time are represented as milliseconds elapsed
+since a fixed origin. The origin is set the first time the clock is used.
+*/
+
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+static ORIGIN: OnceLock<Instant> = OnceLock::new();
+
+fn origin() -> Instant {
+    *ORIGIN.get_or_init(Instant::now)
+}
+
+/// Returns the milliseconds elapsed since the origin of the clock.
+pub fn now_monotonic_ms() -> u64 {
+    origin().elapsed().as_millis() as u64
+}
+
+/// Converts milliseconds since the origin of the clock into an `Instant`.
+///
+/// Returns `None` if the point in time can't be represented.
+pub fn instant_from_monotonic_ms(ms: u64) -> Option<Instant> {
+    origin().checked_add(Duration::from_millis(ms))
+}
@@ -1,4 +1,4 @@
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 // One unit of fuel represents around 100k instructions.
 pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
@@ -12,13 +12,74 @@ pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 /// the process. This host functions are the ones that consider specific configuration while
 /// performing operations.
 ///
-/// However, two properties of a process are enforced by the runtime (maximum memory and maximum
-/// fuel usage). This two properties need to be part of every configuration.
+/// However, some properties of a process are enforced by the runtime (maximum memory, maximum fuel
+/// usage, mailbox and message size limits and maximum lifetime). This properties need to be part of every
+/// configuration.
 ///
 /// `ProcessConfig` must be serializable in case it is used to spawn processes on other nodes.
 pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
     fn set_max_fuel(&mut self, max_fuel: Option<u64>);
     fn get_max_fuel(&self) -> Option<u64>;
+    fn set_fuel_per_yield(&mut self, fuel_per_yield: Option<u64>);
+    fn get_fuel_per_yield(&self) -> Option<u64>;
     fn set_max_memory(&mut self, max_memory: usize);
     fn get_max_memory(&self) -> usize;
+    fn set_max_mailbox_size(&mut self, max_mailbox_size: Option<usize>);
+    fn get_max_mailbox_size(&self) -> Option<usize>;
+    fn set_mailbox_overflow_policy(&mut self, policy: MailboxOverflowPolicy);
+    fn get_mailbox_overflow_policy(&self) -> MailboxOverflowPolicy;
+    fn set_mailbox_watermark(&mut self, mailbox_watermark: Option<usize>);
+    fn get_mailbox_watermark(&self) -> Option<usize>;
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>);
+    fn get_max_message_size(&self) -> Option<usize>;
+    fn set_max_lifetime_ms(&mut self, max_lifetime_ms: Option<u64>);
+    fn get_max_lifetime_ms(&self) -> Option<u64>;
+    fn set_max_processes(&mut self, max_processes: Option<u64>);
+    fn get_max_processes(&self) -> Option<u64>;
+    fn set_max_log_level(&mut self, max_log_level: LogLevel);
+    fn get_max_log_level(&self) -> LogLevel;
+    fn set_crash_dumps(&mut self, crash_dumps: bool);
+    fn get_crash_dumps(&self) -> bool;
+}
+
+/// Defines what happens if a message arrives to a full mailbox.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailboxOverflowPolicy {
+    /// The arriving message is dropped.
+    #[default]
+    DropNewest,
+    /// The oldest message in the mailbox is dropped to make space for the arriving one.
+    DropOldest,
+    /// The process is killed.
+    KillProcess,
+}
+
+/// Most verbose level of the log records that a process can emit, less important records are
+/// dropped before reaching the host logger.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    #[default]
+    Trace,
+}
+
+impl LogLevel {
+    pub fn allows(self, level: log::Level) -> bool {
+        self.to_level_filter() >= level
+    }
+
+    pub fn to_level_filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
 }
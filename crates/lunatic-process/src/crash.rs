@@ -0,0 +1,145 @@
+//! Crash reports of failed processes, for post-mortem debugging.
+//!
+//! Reports are only written for processes that have crash dumps enabled in their configuration,
+//! and only once a directory was set with [`set_directory`].
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use wasmtime::WasmBacktrace;
+
+use crate::{message::Message, DeathReason};
+
+/// Number of messages waiting in the mailbox that are included in a report.
+pub const MAILBOX_MESSAGES: usize = 16;
+// Number of bytes of a data message that are included in a report
+const DATA_PREVIEW: usize = 64;
+
+static DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory crash reports are written to, creating it if it doesn't exist.
+///
+/// Can only be called once.
+pub fn set_directory(dir: impl Into<PathBuf>) -> Result<()> {
+    let dir = dir.into();
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create crash dump directory '{}'", dir.display()))?;
+    DIRECTORY
+        .set(dir)
+        .map_err(|_| anyhow!("Crash dump directory is already set"))
+}
+
+/// Directory crash reports are written to, if set.
+pub fn directory() -> Option<&'static Path> {
+    DIRECTORY.get().map(PathBuf::as_path)
+}
+
+/// A frame of the wasm backtrace, the innermost frame comes first.
+#[derive(Clone, Debug, Serialize)]
+pub struct Frame {
+    pub module: Option<String>,
+    pub function_index: u32,
+    pub function: Option<String>,
+    pub module_offset: Option<usize>,
+}
+
+impl Frame {
+    pub(crate) fn from_backtrace(backtrace: &WasmBacktrace) -> Vec<Frame> {
+        backtrace
+            .frames()
+            .iter()
+            .map(|frame| Frame {
+                module: frame.module_name().map(str::to_owned),
+                function_index: frame.func_index(),
+                function: frame.func_name().map(str::to_owned),
+                module_offset: frame.module_offset(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrashReport {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub environment_id: u64,
+    pub process_id: u64,
+    /// Names the process is registered under.
+    pub names: Vec<String>,
+    /// ID of the module assigned by the control server, if the process runs in a cluster.
+    pub module_id: Option<u64>,
+    pub reason: DeathReason,
+    pub error: String,
+    pub backtrace: Vec<Frame>,
+    pub mailbox_len: usize,
+    /// The first [`MAILBOX_MESSAGES`] messages waiting in the mailbox.
+    pub mailbox: Vec<MessageSummary>,
+    /// IDs of the linked processes.
+    pub links: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageSummary {
+    pub kind: &'static str,
+    pub tag: Option<i64>,
+    /// Size of the data in bytes, 0 for signals.
+    pub size: usize,
+    /// The first bytes of the data, hex encoded.
+    pub data: String,
+}
+
+impl From<&Message> for MessageSummary {
+    fn from(message: &Message) -> Self {
+        let (kind, buffer): (_, &[u8]) = match message {
+            Message::Data(data) => ("data", &data.buffer),
+            Message::LinkDied(..) => ("link_died", &[]),
+            Message::ProcessDied(..) => ("process_died", &[]),
+            Message::Shutdown => ("shutdown", &[]),
+        };
+        let data = buffer[..buffer.len().min(DATA_PREVIEW)].iter().fold(
+            String::with_capacity(DATA_PREVIEW * 2),
+            |mut data, byte| {
+                let _ = write!(data, "{byte:02x}");
+                data
+            },
+        );
+        Self {
+            kind,
+            tag: message.tag(),
+            size: buffer.len(),
+            data,
+        }
+    }
+}
+
+impl CrashReport {
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Writes the report as JSON to the crash dump directory and returns the path of the file.
+    ///
+    /// Returns `None` if no directory is set.
+    pub async fn write(&self) -> Result<Option<PathBuf>> {
+        let dir = match directory() {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        let path = dir.join(format!(
+            "{}-{}-{}.json",
+            self.environment_id, self.process_id, self.time
+        ));
+        let report = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(&path, report).await?;
+        Ok(Some(path))
+    }
+}
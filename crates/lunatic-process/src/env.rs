@@ -1,12 +1,15 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use dashmap::DashMap;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
-use crate::{Process, Signal};
+use crate::{message::Message, Process, Signal};
 
 #[async_trait]
 pub trait Environment: Send + Sync {
@@ -16,8 +19,25 @@ pub trait Environment: Send + Sync {
     fn add_process(&self, id: u64, proc: Arc<dyn Process>);
     fn remove_process(&self, id: u64);
     fn process_count(&self) -> usize;
+    fn iter_processes(&self) -> Box<dyn Iterator<Item = Arc<dyn Process>> + '_>;
     async fn can_spawn_next_process(&self) -> Result<Option<()>>;
     fn send(&self, id: u64, signal: Signal);
+
+    // Process groups
+    fn create_group(&self) -> u64;
+    fn join_group(&self, group_id: u64, process_id: u64) -> bool;
+    fn leave_group(&self, group_id: u64, process_id: u64) -> bool;
+    fn kill_group(&self, group_id: u64) -> bool;
+    fn send_group(&self, group_id: u64, message: Message) -> bool;
+
+    // Pub/sub topics
+    fn subscribe(&self, topic: &str, process_id: u64) -> bool;
+    fn unsubscribe(&self, topic: &str, process_id: u64) -> bool;
+    fn publish(&self, topic: &str, message: Message) -> usize;
+
+    // Process counters
+    fn create_process_counter(&self, process_id: u64, max_processes: u64) -> u64;
+    fn get_process_counter(&self, counter_id: u64) -> Option<Arc<ProcessCounter>>;
 }
 
 #[async_trait]
@@ -33,6 +53,16 @@ pub struct LunaticEnvironment {
     environment_id: u64,
     next_process_id: Arc<AtomicU64>,
     processes: Arc<DashMap<u64, Arc<dyn Process>>>,
+    next_group_id: Arc<AtomicU64>,
+    // Members of the groups that have any, groups are only added once the first process joins
+    groups: Arc<DashMap<u64, HashSet<u64>>>,
+    topics: Arc<DashMap<String, HashSet<u64>>>,
+    // The groups and topics of each process, so that they can be left once the process dies
+    memberships: Arc<DashMap<u64, Memberships>>,
+    next_process_counter_id: Arc<AtomicU64>,
+    process_counters: Arc<DashMap<u64, Arc<ProcessCounter>>>,
+    // The counters created by each process, they are removed together with the process
+    owned_process_counters: Arc<DashMap<u64, Vec<u64>>>,
 }
 
 impl LunaticEnvironment {
@@ -41,6 +71,13 @@ impl LunaticEnvironment {
             environment_id: id,
             processes: Arc::new(DashMap::new()),
             next_process_id: Arc::new(AtomicU64::new(1)),
+            next_group_id: Arc::new(AtomicU64::new(1)),
+            groups: Arc::new(DashMap::new()),
+            topics: Arc::new(DashMap::new()),
+            memberships: Arc::new(DashMap::new()),
+            next_process_counter_id: Arc::new(AtomicU64::new(1)),
+            process_counters: Arc::new(DashMap::new()),
+            owned_process_counters: Arc::new(DashMap::new()),
         }
     }
 }
@@ -53,10 +90,8 @@ impl Environment for LunaticEnvironment {
 
     fn add_process(&self, id: u64, proc: Arc<dyn Process>) {
         self.processes.insert(id, proc);
-        #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
-        let labels: [(String, String); 0] = [];
-        #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
-        let labels = [("environment_id", self.id().to_string())];
+        #[cfg(feature = "metrics")]
+        let labels = crate::metrics_labels::labels(Some(self.id()), None);
         #[cfg(feature = "metrics")]
         metrics::gauge!(
             "lunatic.process.environment.process.count",
@@ -67,10 +102,29 @@ impl Environment for LunaticEnvironment {
 
     fn remove_process(&self, id: u64) {
         self.processes.remove(&id);
-        #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
-        let labels: [(String, String); 0] = [];
-        #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
-        let labels = [("environment_id", self.id().to_string())];
+        // Dead processes leave all their groups and unsubscribe from all their topics. Groups and
+        // topics that are left empty by it are removed.
+        if let Some((_, memberships)) = self.memberships.remove(&id) {
+            for group_id in memberships.groups {
+                self.groups.remove_if_mut(&group_id, |_, members| {
+                    members.remove(&id) && members.is_empty()
+                });
+            }
+            for topic in memberships.topics {
+                self.topics.remove_if_mut(&topic, |_, subscribers| {
+                    subscribers.remove(&id) && subscribers.is_empty()
+                });
+            }
+        }
+        // Only the process that created a counter can spawn new processes from its configuration.
+        // Processes that are still counted against it hold on to the counter itself.
+        if let Some((_, counter_ids)) = self.owned_process_counters.remove(&id) {
+            for counter_id in counter_ids {
+                self.process_counters.remove(&counter_id);
+            }
+        }
+        #[cfg(feature = "metrics")]
+        let labels = crate::metrics_labels::labels(Some(self.id()), None);
         #[cfg(feature = "metrics")]
         metrics::gauge!(
             "lunatic.process.environment.process.count",
@@ -83,6 +137,10 @@ impl Environment for LunaticEnvironment {
         self.processes.len()
     }
 
+    fn iter_processes(&self) -> Box<dyn Iterator<Item = Arc<dyn Process>> + '_> {
+        Box::new(self.processes.iter().map(|entry| entry.value().clone()))
+    }
+
     fn send(&self, id: u64, signal: Signal) {
         if let Some(proc) = self.processes.get(&id) {
             proc.send(signal);
@@ -101,6 +159,210 @@ impl Environment for LunaticEnvironment {
         // Don't impose any limits to process spawning
         Ok(Some(()))
     }
+
+    fn create_group(&self) -> u64 {
+        self.next_group_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn join_group(&self, group_id: u64, process_id: u64) -> bool {
+        // Holding the memberships of the process while joining guarantees that `remove_process`
+        // either sees the new membership or the process can't join anymore.
+        let mut memberships = self.memberships.entry(process_id).or_default();
+        if !self.processes.contains_key(&process_id) {
+            drop(memberships);
+            self.memberships
+                .remove_if(&process_id, |_, memberships| memberships.is_empty());
+            return false;
+        }
+        // Only groups that were created can be joined.
+        if group_id == 0 || group_id >= self.next_group_id.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.groups.entry(group_id).or_default().insert(process_id);
+        memberships.groups.insert(group_id);
+        true
+    }
+
+    fn leave_group(&self, group_id: u64, process_id: u64) -> bool {
+        if let Some(mut memberships) = self.memberships.get_mut(&process_id) {
+            memberships.groups.remove(&group_id);
+        }
+        let mut exists = false;
+        // Remove the group once the last member left.
+        self.groups.remove_if_mut(&group_id, |_, members| {
+            exists = true;
+            members.remove(&process_id) && members.is_empty()
+        });
+        exists
+    }
+
+    fn kill_group(&self, group_id: u64) -> bool {
+        // Holding the group lock while sending the signals guarantees that no process can join
+        // or leave the group before all members received the `Kill` signal.
+        match self.groups.get(&group_id) {
+            Some(members) => {
+                for process_id in members.iter() {
+                    self.send(*process_id, Signal::Kill(None));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn send_group(&self, group_id: u64, message: Message) -> bool {
+        match self.groups.get(&group_id) {
+            Some(members) => {
+                for process_id in members.iter() {
+                    self.send(*process_id, Signal::Message(message.clone()));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn subscribe(&self, topic: &str, process_id: u64) -> bool {
+        // Same as for groups, the memberships are held until the process subscribed
+        let mut memberships = self.memberships.entry(process_id).or_default();
+        if !self.processes.contains_key(&process_id) {
+            drop(memberships);
+            self.memberships
+                .remove_if(&process_id, |_, memberships| memberships.is_empty());
+            return false;
+        }
+        self.topics
+            .entry(topic.to_string())
+            .or_default()
+            .insert(process_id);
+        memberships.topics.insert(topic.to_string());
+        true
+    }
+
+    fn unsubscribe(&self, topic: &str, process_id: u64) -> bool {
+        if let Some(mut memberships) = self.memberships.get_mut(&process_id) {
+            memberships.topics.remove(topic);
+        }
+        let mut subscribed = false;
+        // Remove the topic once the last subscriber left.
+        self.topics.remove_if_mut(topic, |_, subscribers| {
+            subscribed = subscribers.remove(&process_id);
+            subscribed && subscribers.is_empty()
+        });
+        subscribed
+    }
+
+    fn publish(&self, topic: &str, message: Message) -> usize {
+        match self.topics.get(topic) {
+            Some(subscribers) => {
+                for process_id in subscribers.iter() {
+                    self.send(*process_id, Signal::Message(message.clone()));
+                }
+                subscribers.len()
+            }
+            None => 0,
+        }
+    }
+
+    fn create_process_counter(&self, process_id: u64, max_processes: u64) -> u64 {
+        let counter_id = self.next_process_counter_id.fetch_add(1, Ordering::Relaxed);
+        self.process_counters
+            .insert(counter_id, Arc::new(ProcessCounter::new(max_processes)));
+        self.owned_process_counters
+            .entry(process_id)
+            .or_default()
+            .push(counter_id);
+        counter_id
+    }
+
+    fn get_process_counter(&self, counter_id: u64) -> Option<Arc<ProcessCounter>> {
+        self.process_counters.get(&counter_id).map(|x| x.clone())
+    }
+}
+
+// Groups and topics a process is a member of.
+#[derive(Default)]
+struct Memberships {
+    groups: HashSet<u64>,
+    topics: HashSet<String>,
+}
+
+impl Memberships {
+    fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.topics.is_empty()
+    }
+}
+
+/// Counts the running processes of a subtree, spawned from a configuration with a process limit.
+#[derive(Debug)]
+pub struct ProcessCounter {
+    max_processes: AtomicU64,
+    count: AtomicU64,
+}
+
+impl ProcessCounter {
+    pub fn new(max_processes: u64) -> Self {
+        Self {
+            max_processes: AtomicU64::new(max_processes),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes the limit, processes that are already running keep being counted.
+    ///
+    /// If the limit is lowered below the count, no new processes can join until enough of them
+    /// finished.
+    pub fn set_max_processes(&self, max_processes: u64) {
+        self.max_processes.store(max_processes, Ordering::Release);
+    }
+
+    // Increments the counter, returns false if the limit is already reached.
+    fn try_increment(&self) -> bool {
+        let max_processes = self.max_processes.load(Ordering::Acquire);
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max_processes).then_some(count + 1)
+            })
+            .is_ok()
+    }
+
+    fn decrement(&self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Holds a slot in each [`ProcessCounter`] a process is counted against.
+///
+/// The slots are released once the guard is dropped, together with the state of the process.
+#[derive(Debug, Default)]
+pub struct ProcessCounterGuard {
+    counters: Vec<Arc<ProcessCounter>>,
+}
+
+impl ProcessCounterGuard {
+    /// Takes a slot in each of the `counters`.
+    ///
+    /// Returns `None` without taking any slot if one of the counters reached its limit.
+    pub fn acquire(counters: Vec<Arc<ProcessCounter>>) -> Option<Self> {
+        for (i, counter) in counters.iter().enumerate() {
+            if !counter.try_increment() {
+                counters[..i].iter().for_each(|counter| counter.decrement());
+                return None;
+            }
+        }
+        Some(Self { counters })
+    }
+
+    /// Counters this process is counted against.
+    pub fn counters(&self) -> &[Arc<ProcessCounter>] {
+        &self.counters
+    }
+}
+
+impl Drop for ProcessCounterGuard {
+    fn drop(&mut self) {
+        self.counters.iter().for_each(|counter| counter.decrement());
+    }
 }
 
 #[derive(Clone, Default)]
@@ -108,6 +370,20 @@ pub struct LunaticEnvironments {
     envs: Arc<DashMap<u64, Arc<LunaticEnvironment>>>,
 }
 
+impl LunaticEnvironments {
+    /// Number of processes running in all environments.
+    pub fn process_count(&self) -> usize {
+        self.envs.iter().map(|env| env.process_count()).sum()
+    }
+
+    /// All environments, ordered by ID.
+    pub fn environments(&self) -> Vec<Arc<LunaticEnvironment>> {
+        let mut envs: Vec<_> = self.envs.iter().map(|env| env.clone()).collect();
+        envs.sort_by_key(|env| env.id());
+        envs
+    }
+}
+
 #[async_trait]
 impl Environments for LunaticEnvironments {
     type Env = LunaticEnvironment;
@@ -123,3 +399,85 @@ impl Environments for LunaticEnvironments {
         self.envs.get(&id).map(|e| e.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WasmProcess;
+
+    fn add_process(env: &LunaticEnvironment) -> u64 {
+        let id = env.get_next_process_id();
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        env.add_process(id, Arc::new(WasmProcess::new(id, sender)));
+        id
+    }
+
+    #[test]
+    fn removed_processes_only_leave_their_groups() {
+        let env = LunaticEnvironment::new(1);
+        let process = add_process(&env);
+        let joined = env.create_group();
+        let other = env.create_group();
+        assert!(env.join_group(joined, process));
+        assert!(env.join_group(other, add_process(&env)));
+        assert!(env.subscribe("topic", process));
+
+        env.remove_process(process);
+        // The group and topic were emptied by removing the process
+        assert!(!env.groups.contains_key(&joined));
+        assert!(!env.topics.contains_key("topic"));
+        assert!(env.groups.contains_key(&other));
+        assert!(!env.memberships.contains_key(&process));
+    }
+
+    #[test]
+    fn groups_are_only_kept_with_members() {
+        let env = LunaticEnvironment::new(1);
+        let process = add_process(&env);
+        let group = env.create_group();
+        // Groups that were never joined don't take up any space
+        assert!(!env.groups.contains_key(&group));
+        assert!(!env.send_group(group, Message::Shutdown));
+
+        assert!(env.join_group(group, process));
+        assert!(env.send_group(group, Message::Shutdown));
+        assert!(env.leave_group(group, process));
+        assert!(!env.groups.contains_key(&group));
+        // Emptied groups can be joined again, but only created ones
+        assert!(env.join_group(group, process));
+        assert!(!env.join_group(0, process));
+        assert!(!env.join_group(group + 1, process));
+    }
+
+    #[test]
+    fn removed_processes_drop_their_counters() {
+        let env = LunaticEnvironment::new(1);
+        let process = add_process(&env);
+        let other = add_process(&env);
+        let counter_id = env.create_process_counter(process, 1);
+        let other_counter_id = env.create_process_counter(other, 1);
+        let counter = env.get_process_counter(counter_id).unwrap();
+
+        env.remove_process(process);
+        assert!(env.get_process_counter(counter_id).is_none());
+        assert!(env.get_process_counter(other_counter_id).is_some());
+        // Processes counted against the removed counter still release their slots
+        let guard = ProcessCounterGuard::acquire(vec![counter.clone()]).unwrap();
+        assert!(ProcessCounterGuard::acquire(vec![counter.clone()]).is_none());
+        drop(guard);
+        assert!(ProcessCounterGuard::acquire(vec![counter]).is_some());
+    }
+
+    #[test]
+    fn changing_the_limit_keeps_the_count() {
+        let counter = Arc::new(ProcessCounter::new(2));
+        let first = ProcessCounterGuard::acquire(vec![counter.clone()]).unwrap();
+        let _second = ProcessCounterGuard::acquire(vec![counter.clone()]).unwrap();
+
+        counter.set_max_processes(1);
+        drop(first);
+        assert!(ProcessCounterGuard::acquire(vec![counter.clone()]).is_none());
+        counter.set_max_processes(3);
+        assert!(ProcessCounterGuard::acquire(vec![counter]).is_some());
+    }
+}
@@ -0,0 +1,307 @@
+//! Append-only log of lifecycle events, for security reviews and debugging supervision trees.
+//!
+//! Events are only recorded once the log was configured with [`configure`], and only for the
+//! selected environments. Events of the node itself, like connections to other nodes, don't
+//! belong to an environment and are always recorded.
+
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::DeathReason;
+
+/// Format the events are written in, one event per line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    /// One JSON object per event.
+    #[default]
+    Jsonl,
+    /// One OTLP/JSON `ExportLogsServiceRequest` per event, as written by the OpenTelemetry
+    /// collector's file exporter.
+    Otlp,
+}
+
+impl FromStr for EventFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(EventFormat::Jsonl),
+            "otlp" => Ok(EventFormat::Otlp),
+            _ => Err(anyhow!(
+                "Unknown event log format '{s}', expected jsonl or otlp"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Spawn {
+        process_id: u64,
+    },
+    Exit {
+        process_id: u64,
+        reason: DeathReason,
+    },
+    Link {
+        process_id: u64,
+        linked_process_id: u64,
+    },
+    Kill {
+        process_id: u64,
+    },
+    RegistryPut {
+        name: String,
+        node_id: u64,
+        process_id: u64,
+    },
+    RegistryRemove {
+        name: String,
+    },
+    NodeConnected {
+        node_id: u64,
+    },
+    NodeDisconnected {
+        node_id: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Milliseconds since the unix epoch.
+    time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment_id: Option<u64>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+struct EventLog {
+    // Records are written to the file by a dedicated thread, so that recording an event never
+    // waits on the disk
+    records: Sender<String>,
+    format: EventFormat,
+    // `None` if events of all environments are recorded
+    environments: Option<HashSet<u64>>,
+}
+
+static LOG: OnceLock<EventLog> = OnceLock::new();
+
+/// Opens the file events are appended to, creating it if it doesn't exist, and starts the thread
+/// writing to it.
+///
+/// Only events of the `environments` are recorded, or of all environments if it's empty. Can only
+/// be called once.
+pub fn configure(
+    path: impl Into<PathBuf>,
+    format: EventFormat,
+    environments: &[u64],
+) -> Result<()> {
+    let path = path.into();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open event log '{}'", path.display()))?;
+    let environments = if environments.is_empty() {
+        None
+    } else {
+        Some(environments.iter().copied().collect())
+    };
+    if LOG.get().is_some() {
+        return Err(anyhow!("Event log is already configured"));
+    }
+    let (records, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("lunatic-event-log".into())
+        .spawn(move || write_records(file, receiver))
+        .context("Failed to start the event log writer")?;
+    LOG.set(EventLog {
+        records,
+        format,
+        environments,
+    })
+    .map_err(|_| anyhow!("Event log is already configured"))
+}
+
+// Appends the records to the file until all senders are dropped. Records are buffered and only
+// flushed once no more records are waiting.
+fn write_records(file: File, receiver: Receiver<String>) {
+    let mut file = BufWriter::new(file);
+    loop {
+        let record = match receiver.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = file.flush() {
+                    log::warn!("Failed to write to the event log: {e}");
+                }
+                match receiver.recv() {
+                    Ok(record) => record,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        if let Err(e) = writeln!(file, "{record}") {
+            log::warn!("Failed to write to the event log: {e}");
+        }
+    }
+    if let Err(e) = file.flush() {
+        log::warn!("Failed to write to the event log: {e}");
+    }
+}
+
+/// Returns true if events of the environment are recorded.
+pub fn enabled(environment_id: u64) -> bool {
+    match LOG.get() {
+        Some(log) => log
+            .environments
+            .as_ref()
+            .is_none_or(|environments| environments.contains(&environment_id)),
+        None => false,
+    }
+}
+
+/// Appends the event to the log, if events of the environment are recorded.
+///
+/// The event is written in the background. Failing to write it is logged, but doesn't affect the
+/// caller.
+pub fn record(environment_id: Option<u64>, event: Event) {
+    let event_log = match LOG.get() {
+        Some(event_log) => event_log,
+        None => return,
+    };
+    if let Some(environment_id) = environment_id {
+        if !enabled(environment_id) {
+            return;
+        }
+    }
+    let record = Record {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default(),
+        environment_id,
+        event: &event,
+    };
+    let line = match event_log.format {
+        EventFormat::Jsonl => serde_json::to_string(&record),
+        EventFormat::Otlp => serde_json::to_value(&record).and_then(|record| {
+            let record = otlp_log_record(record);
+            serde_json::to_string(&record)
+        }),
+    };
+    match line {
+        // Only fails if the writer thread is gone
+        Ok(line) => {
+            let _ = event_log.records.send(line);
+        }
+        Err(e) => log::warn!("Failed to write to the event log: {e}"),
+    }
+}
+
+// Wraps the fields of the record as attributes of an OTLP log record. The event name is the body.
+fn otlp_log_record(record: Value) -> Value {
+    let mut time = 0;
+    let mut body = Value::Null;
+    let mut attributes = Vec::new();
+    if let Value::Object(fields) = record {
+        for (key, value) in fields {
+            match key.as_str() {
+                "time" => time = value.as_u64().unwrap_or_default(),
+                "event" => body = json!({ "stringValue": value }),
+                _ => attributes.push(json!({ "key": key, "value": otlp_value(value) })),
+            }
+        }
+    }
+    json!({
+        "resourceLogs": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "lunatic" } }]
+            },
+            "scopeLogs": [{
+                "scope": { "name": "lunatic::events" },
+                "logRecords": [{
+                    // OTLP/JSON encodes 64 bit integers as strings
+                    "timeUnixNano": (time as u128 * 1_000_000).to_string(),
+                    "severityText": "INFO",
+                    "severityNumber": 9,
+                    "body": body,
+                    "attributes": attributes,
+                }]
+            }]
+        }]
+    })
+}
+
+fn otlp_value(value: Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(number) => json!({ "intValue": number.to_string() }),
+            None => json!({ "stringValue": number.to_string() }),
+        },
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::String(value) => json!({ "stringValue": value }),
+        value => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_written_in_order() {
+        let path = std::env::temp_dir().join(format!("lunatic-events-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let (records, receiver) = mpsc::channel();
+        let writer = std::thread::spawn(move || write_records(file, receiver));
+        for i in 0..3 {
+            records.send(format!("record {i}")).unwrap();
+        }
+        drop(records);
+        writer.join().unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(content, "record 0\nrecord 1\nrecord 2\n");
+    }
+
+    #[test]
+    fn otlp_record_has_event_as_body() {
+        let event = Event::Link {
+            process_id: 1,
+            linked_process_id: 2,
+        };
+        let record = Record {
+            time: 3,
+            environment_id: Some(4),
+            event: &event,
+        };
+        let record = otlp_log_record(serde_json::to_value(&record).unwrap());
+        let log_record = &record["resourceLogs"][0]["scopeLogs"][0]["logRecords"][0];
+        assert_eq!(log_record["timeUnixNano"], "3000000");
+        assert_eq!(log_record["body"]["stringValue"], "link");
+        let attributes = log_record["attributes"].as_array().unwrap();
+        assert_eq!(attributes.len(), 3);
+        assert!(
+            attributes.contains(&json!({ "key": "environment_id", "value": { "intValue": "4" } }))
+        );
+        assert!(attributes
+            .contains(&json!({ "key": "linked_process_id", "value": { "intValue": "2" } })));
+    }
+}
@@ -1,17 +1,33 @@
+pub mod checkpoint;
+pub mod clock;
 pub mod config;
+pub mod crash;
 pub mod env;
+pub mod events;
 pub mod mailbox;
 pub mod message;
+#[cfg(feature = "metrics")]
+pub mod metrics_labels;
+pub mod plugin;
 pub mod runtimes;
 pub mod state;
+pub mod stats;
 pub mod wasm;
 
-use std::{collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::{pending, Future},
+    hash::Hash,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use env::Environment;
 use log::{debug, log_enabled, trace, warn, Level};
 
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use state::ProcessState;
 use tokio::{
@@ -20,9 +36,17 @@ use tokio::{
         Mutex,
     },
     task::JoinHandle,
+    time::{sleep_until, Instant},
 };
 
-use crate::{mailbox::MessageMailbox, message::Message};
+use crate::{
+    config::ProcessConfig,
+    crash::{CrashReport, MessageSummary},
+    events::Event,
+    mailbox::MessageMailbox,
+    message::{DataMessage, Message},
+    stats::{ProcessStats, ProcessStatsSnapshot},
+};
 
 #[cfg(feature = "metrics")]
 pub fn describe_metrics() {
@@ -105,7 +129,36 @@ pub fn describe_metrics() {
 /// a [`Message`] are opaque and left to the receiver for interpretation.
 pub trait Process: Send + Sync {
     fn id(&self) -> u64;
+    /// Returns the ID of the node the process runs on, or `None` if it runs on the current node.
+    ///
+    /// Process IDs are only unique per node, links and monitors are kept by node and process ID.
+    fn node_id(&self) -> Option<u64> {
+        None
+    }
     fn send(&self, signal: Signal);
+    /// Same as `send`, but returns `false` if the process already finished and the signal was
+    /// dropped.
+    ///
+    /// If it returns `true`, the process is guaranteed to handle `Link` and `Monitor` signals,
+    /// even if it finishes before getting to them.
+    fn try_send(&self, signal: Signal) -> bool {
+        self.send(signal);
+        true
+    }
+    /// Returns the current resource usage of the process, if it's tracked.
+    fn stats(&self) -> Option<ProcessStatsSnapshot> {
+        None
+    }
+    /// Returns the number of messages waiting in the mailbox and if it reached the configured
+    /// watermark, if it's tracked.
+    fn mailbox_pressure(&self) -> Option<(usize, bool)> {
+        None
+    }
+    /// Returns the maximum size of data messages in bytes, if the process limits it. Bigger
+    /// messages are dropped on arrival.
+    fn max_message_size(&self) -> Option<usize> {
+        None
+    }
 }
 
 impl Debug for dyn Process {
@@ -124,48 +177,105 @@ impl Hash for dyn Process {
 pub enum Signal {
     // Messages can contain opaque data.
     Message(Message),
-    // When received, the process should stop immediately.
-    Kill,
+    // When received, the process should stop immediately. The optional reason is forwarded to
+    // linked processes inside of the `LinkDied` signal.
+    Kill(Option<Vec<u8>>),
     // Change behaviour of what happens if a linked process dies.
     DieWhenLinkDies(bool),
+    // Request the process to shut down. If the process traps shutdowns, it will receive a
+    // `Shutdown` message and is killed if it doesn't finish in the grace period (milliseconds).
+    // Otherwise, it has the same effect as `Kill`.
+    Shutdown(u64),
+    // Change behaviour of what happens if a `Shutdown` signal is received.
+    TrapShutdown(bool),
     // Sent from a process that wants to be linked. In case of a death the tag will be returned
     // to the sender in form of a `LinkDied` signal.
     Link(Option<i64>, Arc<dyn Process>),
-    // Request from a process to be unlinked
-    UnLink { process_id: u64 },
-    // Sent to linked processes when the link dies. Contains the tag used when the link was
-    // established. Depending on the value of `die_when_link_dies` (default is `true`) and
-    // the death reason, the receiving process will turn this signal into a message or the
-    // process will immediately die as well.
-    LinkDied(u64, Option<i64>, DeathReason),
+    // Request from a process to be unlinked. The node ID is `None` for processes on the current
+    // node.
+    UnLink {
+        node_id: Option<u64>,
+        process_id: u64,
+    },
+    // Sent to linked processes when the link dies. Contains the node ID (`None` for the current
+    // node) and ID of the dead process and the tag used when the link was established. Depending
+    // on the value of `die_when_link_dies` (default is `true`) and the death reason, the
+    // receiving process will turn this signal into a message or the process will immediately die
+    // as well. If the process was killed with a reason, it's included too.
+    LinkDied(Option<u64>, u64, Option<i64>, DeathReason, Option<Vec<u8>>),
+    // Sent from a process that wants to be notified about this process' death. Different from
+    // links, monitors are unidirectional and will never cause the monitoring process to die.
     Monitor(Arc<dyn Process>),
-    StopMonitoring { process_id: u64 },
-    ProcessDied(u64),
+    // Request from a process to stop being notified about this process' death. The node ID is
+    // `None` for processes on the current node.
+    StopMonitoring {
+        node_id: Option<u64>,
+        process_id: u64,
+    },
+    // When received, the process will stop being polled until a `Resume` signal is received.
+    // Signals are still processed while the process is suspended.
+    Suspend,
+    // Continue executing a suspended process.
+    Resume,
+    // Sent to monitoring processes when the monitored process dies. Contains the ID of the dead
+    // process and the reason of death. It's always turned into a `ProcessDied` message.
+    ProcessDied(u64, DeathReason),
+    // Sent from a process that wants to receive the value returned by the entry function. If the
+    // function finishes successfully and returns an `i64`, it's sent back to the process as a
+    // data message with the given tag.
+    AwaitReturn(Option<i64>, Arc<dyn Process>),
 }
 
 impl Debug for Signal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Message(_) => write!(f, "Message"),
-            Self::Kill => write!(f, "Kill"),
+            Self::Kill(_) => write!(f, "Kill"),
+            Self::Suspend => write!(f, "Suspend"),
+            Self::Resume => write!(f, "Resume"),
             Self::DieWhenLinkDies(_) => write!(f, "DieWhenLinkDies"),
+            Self::Shutdown(timeout) => write!(f, "Shutdown {timeout}"),
+            Self::TrapShutdown(_) => write!(f, "TrapShutdown"),
             Self::Link(_, p) => write!(f, "Link {}", p.id()),
-            Self::UnLink { process_id } => write!(f, "UnLink {process_id}"),
-            Self::LinkDied(_, _, reason) => write!(f, "LinkDied {reason:?}"),
+            Self::UnLink { process_id, .. } => write!(f, "UnLink {process_id}"),
+            Self::LinkDied(_, _, _, reason, _) => write!(f, "LinkDied {reason:?}"),
             Self::Monitor(p) => write!(f, "Monitor {}", p.id()),
-            Self::StopMonitoring { process_id } => write!(f, "UnMonitor {process_id}"),
-            Self::ProcessDied(_) => write!(f, "ProcessDied"),
+            Self::StopMonitoring { process_id, .. } => write!(f, "UnMonitor {process_id}"),
+            Self::ProcessDied(_, reason) => write!(f, "ProcessDied {reason:?}"),
+            Self::AwaitReturn(_, p) => write!(f, "AwaitReturn {}", p.id()),
         }
     }
 }
 
 // The reason of a process' death
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeathReason {
     // Process finished normaly.
     Normal,
     Failure,
     NoProcess,
+    // Process called `proc_exit` with a non-zero exit code.
+    Exit(i32),
+}
+
+impl DeathReason {
+    /// Returns the numeric representation of the reason, as exposed to guest code.
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            DeathReason::Normal => 0,
+            DeathReason::Failure => 1,
+            DeathReason::NoProcess => 2,
+            DeathReason::Exit(_) => 3,
+        }
+    }
+
+    /// Returns the exit code if the process called `proc_exit` with a non-zero code.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            DeathReason::Exit(code) => Some(*code),
+            _ => None,
+        }
+    }
 }
 
 /// The reason of a process finishing
@@ -182,16 +292,40 @@ pub enum Finished<T> {
 ///
 /// They can be created with [`spawn_wasm`](crate::wasm::spawn_wasm), and once spawned they will be
 /// running in the background and can't be observed directly.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct WasmProcess {
     id: u64,
     signal_mailbox: UnboundedSender<Signal>,
+    stats: Option<ProcessStats>,
 }
 
 impl WasmProcess {
     /// Create a new WasmProcess
     pub fn new(id: u64, signal_mailbox: UnboundedSender<Signal>) -> Self {
-        Self { id, signal_mailbox }
+        Self {
+            id,
+            signal_mailbox,
+            stats: None,
+        }
+    }
+
+    /// Create a new WasmProcess that exposes the resource usage of the process
+    pub fn with_stats(
+        id: u64,
+        signal_mailbox: UnboundedSender<Signal>,
+        stats: ProcessStats,
+    ) -> Self {
+        Self {
+            id,
+            signal_mailbox,
+            stats: Some(stats),
+        }
+    }
+}
+
+impl Debug for WasmProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmProcess").field("id", &self.id).finish()
     }
 }
 
@@ -201,21 +335,36 @@ impl Process for WasmProcess {
     }
 
     fn send(&self, signal: Signal) {
-        #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
-        let labels = [("process_kind", "wasm")];
-        #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
-        let labels = [
-            ("process_kind", "wasm"),
-            ("process_id", self.id().to_string()),
-        ];
-        #[cfg(feature = "metrics")]
-        metrics::increment_counter!("lunatic.process.signals.send", &labels);
-
         // If the receiver doesn't exist or is closed, just ignore it and drop the `signal`.
         // lunatic can't guarantee that a message was successfully seen by the receiving side even
         // if this call succeeds. We deliberately don't expose this API, as it would not make sense
         // to relay on it and could signal wrong guarantees to users.
-        let _ = self.signal_mailbox.send(signal);
+        let _ = self.try_send(signal);
+    }
+
+    fn try_send(&self, signal: Signal) -> bool {
+        #[cfg(feature = "metrics")]
+        let mut labels = metrics_labels::labels(None, Some(self.id()));
+        #[cfg(feature = "metrics")]
+        labels.push(("process_kind", "wasm".to_string()));
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("lunatic.process.signals.send", &labels);
+
+        self.signal_mailbox.send(signal).is_ok()
+    }
+
+    fn stats(&self) -> Option<ProcessStatsSnapshot> {
+        self.stats.as_ref().map(|stats| stats.snapshot())
+    }
+
+    fn mailbox_pressure(&self) -> Option<(usize, bool)> {
+        self.stats.as_ref().map(|stats| stats.mailbox_pressure())
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.stats
+            .as_ref()
+            .and_then(|stats| stats.max_message_size())
     }
 }
 
@@ -281,6 +430,7 @@ pub(crate) async fn new<F, S, R>(
     env: Arc<dyn Environment>,
     signal_mailbox: Arc<Mutex<UnboundedReceiver<Signal>>>,
     message_mailbox: MessageMailbox,
+    stats: ProcessStats,
 ) -> Result<S>
 where
     S: ProcessState,
@@ -288,25 +438,35 @@ where
     F: Future<Output = R> + Send + 'static,
 {
     trace!("Process {} spawned", id);
+    events::record(Some(env.id()), Event::Spawn { process_id: id });
+    plugin::on_process_spawn(env.id(), id);
     tokio::pin!(fut);
 
     // Defines what happens if one of the linked processes dies.
     // If the value is set to false, instead of dying too the process will receive a message about
     // the linked process' death.
     let mut die_when_link_dies = true;
-    // Process linked to this one
+    // Process linked to this one, by node and process ID
     let mut links = HashMap::new();
-    // Processes monitoring this one
+    // Processes monitoring this one, by node and process ID
     let mut monitors = HashMap::new();
+    // Processes waiting for the value returned by the entry function
+    let mut awaiting = Vec::new();
     // TODO: Maybe wrapping this in some kind of `std::panic::catch_unwind` wold be a good idea,
     //       to protect against panics in host function calls that unwind through Wasm code.
     //       Currently a panic would just kill the task, but not notify linked processes.
     let mut signal_mailbox = signal_mailbox.lock().await;
     let mut has_sender = true;
-    #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
-    let labels: [(String, String); 0] = [];
-    #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
-    let labels = [("process_id", id.to_string())];
+    // If the process is suspended, the future is not polled anymore until it gets resumed.
+    let mut suspended = false;
+    // If set to true, a `Shutdown` signal is turned into a message instead of killing the process.
+    let mut trap_shutdown = false;
+    // The process is killed at this point in time if a shutdown was requested.
+    let mut shutdown_deadline: Option<Instant> = None;
+    // Reason passed along with the `Kill` signal that terminated the process.
+    let mut kill_reason = None;
+    #[cfg(feature = "metrics")]
+    let labels = metrics_labels::labels(Some(env.id()), Some(id));
     let result = loop {
         tokio::select! {
             biased;
@@ -321,7 +481,19 @@ where
                         #[cfg(feature = "metrics")]
                         message.write_metrics();
 
-                        message_mailbox.push(message);
+                        // Plugins can drop messages, e.g. to test how processes cope with loss
+                        if !plugin::on_message_send(env.id(), id, &message) {
+                            continue;
+                        }
+
+                        if let Message::Data(data) = &message {
+                            stats.add_bytes_received(data.size());
+                        }
+
+                        // Kill the process if the mailbox overflows and the policy requires it
+                        if !message_mailbox.push(message) {
+                            break Finished::KillSignal;
+                        }
 
                         // process metrics
                         #[cfg(feature = "metrics")]
@@ -331,44 +503,79 @@ where
                         metrics::gauge!("lunatic.process.messages.outstanding", message_mailbox.len() as f64, &labels);
                     },
                     Ok(Signal::DieWhenLinkDies(value)) => die_when_link_dies = value,
+                    Ok(Signal::TrapShutdown(value)) => trap_shutdown = value,
+                    Ok(Signal::AwaitReturn(tag, proc)) => awaiting.push((tag, proc)),
+                    // Give the process a chance to finish before killing it.
+                    Ok(Signal::Shutdown(timeout)) => {
+                        if !trap_shutdown {
+                            break Finished::KillSignal;
+                        }
+                        // A grace period too large to be represented never expires.
+                        let deadline = Instant::now().checked_add(Duration::from_millis(timeout));
+                        // Multiple shutdown requests can only shorten the grace period.
+                        if let Some(deadline) = deadline {
+                            if shutdown_deadline.is_none_or(|current| deadline < current) {
+                                shutdown_deadline = Some(deadline);
+                            }
+                        }
+                        if !message_mailbox.push(Message::Shutdown) {
+                            break Finished::KillSignal;
+                        }
+                    }
                     // Put process into list of linked processes
                     Ok(Signal::Link(tag, proc)) => {
-                        links.insert(proc.id(), (proc, tag));
+                        events::record(
+                            Some(env.id()),
+                            Event::Link { process_id: id, linked_process_id: proc.id() },
+                        );
+                        links.insert((proc.node_id(), proc.id()), (proc, tag));
+                        stats.set_links(links.len());
 
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
                     },
                     // Remove process from list
-                    Ok(Signal::UnLink { process_id }) => {
-                        links.remove(&process_id);
+                    Ok(Signal::UnLink { node_id, process_id }) => {
+                        links.remove(&(node_id, process_id));
+                        stats.set_links(links.len());
 
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
                     }
                     // Exit loop and don't poll anymore the future if Signal::Kill received.
-                    Ok(Signal::Kill) => break Finished::KillSignal,
+                    Ok(Signal::Kill(reason)) => {
+                        events::record(Some(env.id()), Event::Kill { process_id: id });
+                        kill_reason = reason;
+                        break Finished::KillSignal
+                    },
+                    // Stop polling the future until resumed.
+                    Ok(Signal::Suspend) => suspended = true,
+                    Ok(Signal::Resume) => suspended = false,
                     // Depending if `die_when_link_dies` is set, process will die or turn the
                     // signal into a message
-                    Ok(Signal::LinkDied(id, tag, reason)) => {
-                        links.remove(&id);
+                    Ok(Signal::LinkDied(node_id, id, tag, reason, kill_reason)) => {
+                        links.remove(&(node_id, id));
+                        stats.set_links(links.len());
 
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
                         match reason {
-                            DeathReason::Failure | DeathReason::NoProcess => {
+                            DeathReason::Failure | DeathReason::NoProcess | DeathReason::Exit(_) => {
                                 if die_when_link_dies {
                                     // Even this was not a **kill** signal it has the same effect on
                                     // this process and should be propagated as such.
                                     break Finished::KillSignal
                                 } else {
-                                    let message = Message::LinkDied(tag);
+                                    let message = Message::LinkDied(tag, id, reason, kill_reason);
 
                                     #[cfg(feature = "metrics")]
                                     metrics::increment_counter!("lunatic.process.messages.send", &labels);
 
                                     #[cfg(feature = "metrics")]
                                     metrics::gauge!("lunatic.process.messages.outstanding", message_mailbox.len() as f64, &labels);
-                                    message_mailbox.push(message);
+                                    if !message_mailbox.push(message) {
+                                        break Finished::KillSignal;
+                                    }
                                 }
                             },
                             // In case a linked process finishes normally, don't do anything.
@@ -377,15 +584,17 @@ where
                     },
                     // Put process into list of monitor processes
                     Ok(Signal::Monitor(proc)) => {
-                        monitors.insert(proc.id(), proc);
+                        monitors.insert((proc.node_id(), proc.id()), proc);
                     }
                     // Remove process from monitor list
-                    Ok(Signal::StopMonitoring { process_id }) => {
-                        monitors.remove(&process_id);
+                    Ok(Signal::StopMonitoring { node_id, process_id }) => {
+                        monitors.remove(&(node_id, process_id));
                     }
                     // Notify process that a monitored process died
-                    Ok(Signal::ProcessDied(id)) => {
-                        message_mailbox.push(Message::ProcessDied(id));
+                    Ok(Signal::ProcessDied(id, reason)) => {
+                        if !message_mailbox.push(Message::ProcessDied(id, reason)) {
+                            break Finished::KillSignal;
+                        }
                     }
                     Err(_) => {
                         debug_assert!(has_sender);
@@ -394,22 +603,77 @@ where
                 }
             }
             // Run process
-            output = &mut fut => { break Finished::Normal(output); }
+            output = &mut fut, if !suspended => { break Finished::Normal(output); }
+            // Kill process if the shutdown grace period expired
+            _ = async {
+                match shutdown_deadline {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => pending().await,
+                }
+            }, if shutdown_deadline.is_some() => { break Finished::KillSignal; }
+            // A suspended process without signal senders can never be resumed.
+            else => { break Finished::KillSignal; }
         }
     };
 
     env.remove_process(id);
 
+    // Stop receiving signals. Links and monitors that were requested before this point, but not
+    // handled yet, still need to be notified about the process' death.
+    signal_mailbox.close();
+    while let Ok(signal) = signal_mailbox.try_recv() {
+        match signal {
+            Signal::Link(tag, proc) => {
+                links.insert((proc.node_id(), proc.id()), (proc, tag));
+            }
+            Signal::UnLink {
+                node_id,
+                process_id,
+            } => {
+                links.remove(&(node_id, process_id));
+            }
+            Signal::Monitor(proc) => {
+                monitors.insert((proc.node_id(), proc.id()), proc);
+            }
+            Signal::StopMonitoring {
+                node_id,
+                process_id,
+            } => {
+                monitors.remove(&(node_id, process_id));
+            }
+            _ => {}
+        }
+    }
+
+    let mut exit_code = None;
     let result = match result {
         Finished::Normal(result) => {
             let result: ExecutionResult<_> = result.into();
 
-            if let Some(failure) = result.failure() {
-                let registry = result.state().registry().read().await;
-                let name = registry
+            if let Some(code) = result.exit_code() {
+                warn!(
+                    "Process {} exited with code {}, notifying: {} links",
+                    id,
+                    code,
+                    links.len()
+                );
+                exit_code = Some(code);
+                Err(anyhow!("Process exited with code {code}"))
+            } else if let Some(failure) = result.failure() {
+                // Copy the names so that the registry isn't locked while the crash report is written
+                let mut names: Vec<String> = result
+                    .state()
+                    .registry()
+                    .read()
+                    .await
                     .iter()
                     .filter(|(_, (_, process_id))| process_id == &id)
-                    .map(|(name, _)| name.splitn(4, '/').last().unwrap_or(name.as_str()))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                names.sort();
+                let name = names
+                    .iter()
+                    .map(|name| name.splitn(4, '/').last().unwrap_or(name.as_str()))
                     .collect::<NameOrID>()
                     .or_id(id);
                 warn!(
@@ -425,8 +689,47 @@ where
                 );
                 debug!("{}", failure);
 
+                if result.state().config().get_crash_dumps() {
+                    let mut link_ids: Vec<u64> = links.keys().map(|(_, id)| *id).collect();
+                    link_ids.sort_unstable();
+                    let report = CrashReport {
+                        time: CrashReport::now(),
+                        environment_id: env.id(),
+                        process_id: id,
+                        names: names.clone(),
+                        module_id: result.state().module().source().id,
+                        reason: DeathReason::Failure,
+                        error: failure.to_string(),
+                        backtrace: result.backtrace().to_vec(),
+                        mailbox_len: message_mailbox.len(),
+                        mailbox: message_mailbox
+                            .snapshot(crash::MAILBOX_MESSAGES)
+                            .iter()
+                            .map(MessageSummary::from)
+                            .collect(),
+                        links: link_ids,
+                    };
+                    match report.write().await {
+                        Ok(Some(path)) => {
+                            warn!(
+                                "Crash report of process {} written to {}",
+                                id,
+                                path.display()
+                            )
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to write crash report of process {}: {}", id, e),
+                    }
+                }
+
                 Err(anyhow!(failure.to_string()))
             } else {
+                if let Some(value) = result.return_value() {
+                    for (tag, proc) in awaiting.iter() {
+                        let message = DataMessage::new_from_vec(*tag, value.to_le_bytes().to_vec());
+                        proc.send(Signal::Message(Message::Data(message)));
+                    }
+                }
                 Ok(result.into_state())
             }
         }
@@ -441,19 +744,34 @@ where
         }
     };
 
-    let reason = match result {
-        Ok(_) => DeathReason::Normal,
-        Err(_) => DeathReason::Failure,
+    let reason = match (&result, exit_code) {
+        (Ok(_), _) => DeathReason::Normal,
+        (Err(_), Some(code)) => DeathReason::Exit(code),
+        (Err(_), None) => DeathReason::Failure,
     };
+    events::record(
+        Some(env.id()),
+        Event::Exit {
+            process_id: id,
+            reason,
+        },
+    );
+    plugin::on_process_exit(env.id(), id, reason);
 
     // Notify all links that we finished
     for (proc, tag) in links.values() {
-        proc.send(Signal::LinkDied(id, *tag, reason));
+        proc.send(Signal::LinkDied(
+            None,
+            id,
+            *tag,
+            reason,
+            kill_reason.clone(),
+        ));
     }
 
     // Notify all monitoring processes we died
     for proc in monitors.values() {
-        proc.send(Signal::ProcessDied(id));
+        proc.send(Signal::ProcessDied(id, reason));
     }
 
     result
@@ -486,7 +804,15 @@ where
     };
     let fut = func(process.clone(), message_mailbox.clone());
     let signal_mailbox = Arc::new(Mutex::new(signal_mailbox));
-    let join = tokio::task::spawn(new(fut, id, env.clone(), signal_mailbox, message_mailbox));
+    let stats = ProcessStats::new(message_mailbox.clone());
+    let join = tokio::task::spawn(new(
+        fut,
+        id,
+        env.clone(),
+        signal_mailbox,
+        message_mailbox,
+        stats,
+    ));
     (join, process)
 }
 
@@ -496,21 +822,22 @@ impl Process for NativeProcess {
     }
 
     fn send(&self, signal: Signal) {
-        #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
-        let labels = [("process_kind", "native")];
-        #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
-        let labels = [
-            ("process_kind", "native"),
-            ("process_id", self.id().to_string()),
-        ];
-        #[cfg(feature = "metrics")]
-        metrics::increment_counter!("lunatic.process.signals.send", &labels);
-
         // If the receiver doesn't exist or is closed, just ignore it and drop the `signal`.
         // lunatic can't guarantee that a message was successfully seen by the receiving side even
         // if this call succeeds. We deliberately don't expose this API, as it would not make sense
         // to relay on it and could signal wrong guarantees to users.
-        let _ = self.signal_mailbox.send(signal);
+        let _ = self.try_send(signal);
+    }
+
+    fn try_send(&self, signal: Signal) -> bool {
+        #[cfg(feature = "metrics")]
+        let mut labels = metrics_labels::labels(None, Some(self.id()));
+        #[cfg(feature = "metrics")]
+        labels.push(("process_kind", "native".to_string()));
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("lunatic.process.signals.send", &labels);
+
+        self.signal_mailbox.send(signal).is_ok()
     }
 }
 
@@ -520,6 +847,9 @@ impl Process for NativeProcess {
 pub struct ExecutionResult<T> {
     state: T,
     result: ResultValue,
+    return_value: Option<i64>,
+    // Wasm backtrace of the failure, innermost frame first
+    backtrace: Vec<crash::Frame>,
 }
 
 impl<T> ExecutionResult<T> {
@@ -532,6 +862,32 @@ impl<T> ExecutionResult<T> {
         }
     }
 
+    // Returns the exit code if the process called `proc_exit` with a non-zero code.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.result {
+            ResultValue::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    // Splits off the hibernated process, if the process hibernated.
+    pub fn into_hibernated(self) -> std::result::Result<(T, Hibernate), Self> {
+        match self.result {
+            ResultValue::Hibernated(hibernate) => Ok((self.state, hibernate)),
+            result => Err(ExecutionResult { result, ..self }),
+        }
+    }
+
+    // Returns the wasm backtrace if the process trapped.
+    pub fn backtrace(&self) -> &[crash::Frame] {
+        &self.backtrace
+    }
+
+    // Returns the value returned by the entry function, if it returned a single `i64`.
+    pub fn return_value(&self) -> Option<i64> {
+        self.return_value
+    }
+
     // Returns the process state reference
     pub fn state(&self) -> &T {
         &self.state
@@ -553,10 +909,14 @@ where
             Ok(t) => ExecutionResult {
                 state: t,
                 result: ResultValue::Ok,
+                return_value: None,
+                backtrace: Vec::new(),
             },
             Err(e) => ExecutionResult {
                 state: T::default(),
                 result: ResultValue::Failed(e.to_string()),
+                return_value: None,
+                backtrace: Vec::new(),
             },
         }
     }
@@ -566,4 +926,33 @@ pub enum ResultValue {
     Ok,
     Failed(String),
     SpawnError(String),
+    // The process called `proc_exit` with a non-zero exit code.
+    Exited(i32),
+    // The instance was dropped to save memory, contains what's needed to continue once a new
+    // message arrives.
+    Hibernated(Hibernate),
 }
+
+/// Returned from a host function to request the hibernation of a Wasm process.
+///
+/// The instance is dropped, while the rest of the process (state, mailbox, links, ...) is kept
+/// around. Only the used part of the linear memory is kept, without the zeroed pages at its end.
+/// Once a new message arrives the process is re-instantiated, the memory is restored and the
+/// function is called.
+#[derive(Debug)]
+pub struct Hibernate {
+    /// The exported function to call after waking up.
+    pub function: String,
+    /// The linear memory up to the last non-zero byte.
+    pub memory: Vec<u8>,
+    /// The size of the linear memory in bytes.
+    pub memory_size: u64,
+}
+
+impl std::fmt::Display for Hibernate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process hibernated, continuing with `{}`", self.function)
+    }
+}
+
+impl std::error::Error for Hibernate {}
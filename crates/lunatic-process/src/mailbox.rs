@@ -4,6 +4,9 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 
+use log::warn;
+
+use crate::config::MailboxOverflowPolicy;
 use crate::message::Message;
 
 /// The `MessageMailbox` is a data structure holding all messages of a process.
@@ -12,6 +15,9 @@ use crate::message::Message;
 /// this structure. The order of messages is preserved. This struct also implements the [`Future`]
 /// trait and `pop()` operations can be awaited on if the queue is empty.
 ///
+/// The mailbox can be limited to a maximum number of messages, in which case the
+/// [`MailboxOverflowPolicy`] defines what happens to new messages once the limit is reached.
+///
 /// ## Safety
 ///
 /// This should be cancellation safe and can be used inside `tokio::select!` statements:
@@ -24,12 +30,112 @@ pub struct MessageMailbox {
 #[derive(Default)]
 struct InnerMessageMailbox {
     waker: Option<Waker>,
-    tags: Option<Vec<i64>>,
+    tags: Option<TagFilter>,
     found: Option<Message>,
     messages: VecDeque<Message>,
+    // Number of data messages in `messages`, kept up to date by the queue operations below.
+    data_len: usize,
+    max_size: Option<usize>,
+    overflow_policy: MailboxOverflowPolicy,
+    watermark: Option<usize>,
+    max_message_size: Option<usize>,
+    // Number of data messages dropped for exceeding the maximum message size.
+    oversized_dropped: u64,
+}
+
+// Selects the messages a `pop` is interested in.
+enum TagFilter {
+    // Matches messages with any of the tags.
+    Tags(Vec<i64>),
+    // Matches messages with a tag inside the inclusive range.
+    Range(i64, i64),
+}
+
+impl InnerMessageMailbox {
+    // All changes to the queue go through these methods, so that the count of data messages is
+    // kept up to date.
+
+    fn push_back(&mut self, message: Message) {
+        self.data_len += message.is_data() as usize;
+        self.messages.push_back(message);
+    }
+
+    fn push_front(&mut self, message: Message) {
+        self.data_len += message.is_data() as usize;
+        self.messages.push_front(message);
+    }
+
+    fn remove(&mut self, index: usize) -> Option<Message> {
+        let message = self.messages.remove(index)?;
+        self.data_len -= message.is_data() as usize;
+        Some(message)
+    }
+
+    fn pop_front(&mut self) -> Option<Message> {
+        self.remove(0)
+    }
+}
+
+impl TagFilter {
+    fn matches(&self, message: &Message) -> bool {
+        // Only consider messages that also have a tag.
+        match (self, message.tag()) {
+            (TagFilter::Tags(tags), Some(tag)) => tags.contains(&tag),
+            (TagFilter::Range(min, max), Some(tag)) => *min <= tag && tag <= *max,
+            (_, None) => false,
+        }
+    }
 }
 
 impl MessageMailbox {
+    /// Create a new mailbox holding at most `max_size` data messages, or an unbounded one if
+    /// `None`.
+    ///
+    /// Messages created from signals, like `LinkDied`, don't count against the limit and are
+    /// always delivered.
+    pub fn new(max_size: Option<usize>, overflow_policy: MailboxOverflowPolicy) -> Self {
+        let inner = InnerMessageMailbox {
+            max_size,
+            overflow_policy,
+            ..Default::default()
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Sets the number of messages at which the mailbox is considered to be under pressure.
+    pub fn set_watermark(&self, watermark: Option<usize>) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.watermark = watermark;
+    }
+
+    /// Sets the maximum size of data messages in bytes, bigger messages are dropped on arrival.
+    pub fn set_max_message_size(&self, max_message_size: Option<usize>) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.max_message_size = max_message_size;
+    }
+
+    /// Returns the maximum size of data messages in bytes.
+    pub fn max_message_size(&self) -> Option<usize> {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.max_message_size
+    }
+
+    /// Returns the number of data messages dropped for exceeding the maximum message size.
+    pub fn oversized_dropped(&self) -> u64 {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.oversized_dropped
+    }
+
+    /// Returns the number of messages currently available and if it reached the watermark.
+    pub fn pressure(&self) -> (usize, bool) {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        let len = mailbox.messages.len();
+        let reached = matches!(mailbox.watermark, Some(watermark) if len >= watermark);
+        (len, reached)
+    }
+
     /// Return message in FIFO order from mailbox.
     ///
     /// If function is called with a `tags` value different from None, it will only return the first
@@ -37,6 +143,21 @@ impl MessageMailbox {
     ///
     /// If no message exist, blocks until a message is received.
     pub async fn pop(&self, tags: Option<&[i64]>) -> Message {
+        self.pop_filtered(tags.map(|tags| TagFilter::Tags(tags.into())))
+            .await
+    }
+
+    /// Return the first message in FIFO order with a tag between `min_tag` and `max_tag`
+    /// (inclusive).
+    ///
+    /// If no such message exist, blocks until one is received. Nothing matches if `min_tag` is
+    /// greater than `max_tag`, callers need to check the range.
+    pub async fn pop_range(&self, min_tag: i64, max_tag: i64) -> Message {
+        self.pop_filtered(Some(TagFilter::Range(min_tag, max_tag)))
+            .await
+    }
+
+    async fn pop_filtered(&self, filter: Option<TagFilter>) -> Message {
         // Mailbox lock must be released before .await
         {
             let mut mailbox = self.inner.lock().expect("only accessed by one process");
@@ -44,31 +165,24 @@ impl MessageMailbox {
             // If a found message exists here, it means that the previous `.await` was canceled
             // after a `wake()` call. To not lose this message it should be put into the queue.
             if let Some(found) = mailbox.found.take() {
-                mailbox.messages.push_back(found);
+                mailbox.push_back(found);
             }
 
             // When looking for specific tags, loop through all messages to check for it
-            if let Some(tags) = tags {
-                let index = mailbox.messages.iter().position(|x| {
-                    // Only consider messages that also have a tag.
-                    if let Some(tag) = x.tag() {
-                        tags.contains(&tag)
-                    } else {
-                        false
-                    }
-                });
+            if let Some(filter) = filter.as_ref() {
+                let index = mailbox.messages.iter().position(|x| filter.matches(x));
                 // If message matching tags is found, remove it.
                 if let Some(index) = index {
-                    return mailbox.messages.remove(index).expect("must exist");
+                    return mailbox.remove(index).expect("must exist");
                 }
             } else {
                 // If not looking for a specific tags try to pop the first message available.
-                if let Some(message) = mailbox.messages.pop_front() {
+                if let Some(message) = mailbox.pop_front() {
                     return message;
                 }
             }
             // Mark the tags to wait on.
-            mailbox.tags = tags.map(|tags| tags.into());
+            mailbox.tags = filter;
         }
         self.await
     }
@@ -100,11 +214,11 @@ impl MessageMailbox {
             // If a found message exists here, it means that the previous `.await` was canceled
             // after a `wake()` call. To not lose this message it should be put into the queue.
             if let Some(found) = mailbox.found.take() {
-                mailbox.messages.push_back(found);
+                mailbox.push_back(found);
             }
 
             // Mark the tags to wait on.
-            mailbox.tags = tags.map(|tags| tags.into());
+            mailbox.tags = tags.map(|tags| TagFilter::Tags(tags.into()));
         }
         self.await
     }
@@ -113,30 +227,130 @@ impl MessageMailbox {
     ///
     /// If the message is being .awaited on, this call will immediately notify the waker that it's
     /// ready, otherwise it will push it at the end of the queue.
-    pub fn push(&self, message: Message) {
+    ///
+    /// Returns `false` if the mailbox is full and the overflow policy requires the process to be
+    /// killed. In this case the message is dropped.
+    ///
+    /// Data messages exceeding the maximum message size are dropped.
+    pub fn push(&self, message: Message) -> bool {
         let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        if let (Message::Data(data), Some(max_message_size)) = (&message, mailbox.max_message_size)
+        {
+            if data.size() > max_message_size {
+                // Any sender can trigger this, only the first drop is logged.
+                if mailbox.oversized_dropped == 0 {
+                    warn!(
+                        "Dropped message of {} bytes, exceeding the message size limit of {} bytes. \
+                        Further drops are only counted",
+                        data.size(),
+                        max_message_size
+                    );
+                }
+                mailbox.oversized_dropped += 1;
+                return true;
+            }
+        }
         // If waiting on a new message notify executor that it arrived.
         if let Some(waker) = mailbox.waker.take() {
             // If waiting on specific tags only notify if tags are matched, otherwise forward every message.
-            // Note that because of the short-circuit rule in Rust it's safe to use `unwrap()` here.
-            if mailbox.tags.is_none()
-                || (message.tag().is_some()
-                    && mailbox
-                        .tags
-                        .as_ref()
-                        .unwrap()
-                        .contains(&message.tag().unwrap()))
-            {
+            let matches = match mailbox.tags.as_ref() {
+                Some(filter) => filter.matches(&message),
+                None => true,
+            };
+            if matches {
                 mailbox.found = Some(message);
                 waker.wake();
-                return;
+                return true;
             } else {
                 // Put the waker back if this is not the message we are looking for.
                 mailbox.waker = Some(waker);
             }
         }
-        // Otherwise put message into queue
-        mailbox.messages.push_back(message);
+        // Otherwise put message into queue, if there is space left for data messages
+        if let (Message::Data(_), Some(max_size)) = (&message, mailbox.max_size) {
+            if mailbox.data_len >= max_size {
+                match mailbox.overflow_policy {
+                    MailboxOverflowPolicy::DropNewest => return true,
+                    MailboxOverflowPolicy::DropOldest => {
+                        let oldest = mailbox.messages.iter().position(Message::is_data);
+                        if let Some(oldest) = oldest {
+                            mailbox.remove(oldest);
+                        }
+                    }
+                    MailboxOverflowPolicy::KillProcess => return false,
+                }
+            }
+        }
+        mailbox.push_back(message);
+        true
+    }
+
+    /// Waits until a message is available, without removing it from the mailbox.
+    pub async fn wait(&self) {
+        self.move_to_front(None, |_| ()).await;
+    }
+
+    /// Similar to `pop`, but leaves the message in the mailbox.
+    ///
+    /// The matching message is moved to the front of the queue and a copy of it is returned. Only
+    /// the data of the message is copied, the resources stay with the message in the queue.
+    pub async fn peek(&self, tags: Option<&[i64]>) -> Message {
+        self.move_to_front(tags, Message::copy_data).await
+    }
+
+    // Moves the first message matching the tags to the front of the queue, waiting for it if
+    // there is none yet, and returns the result of `f` called with the message.
+    async fn move_to_front<R>(&self, tags: Option<&[i64]>, f: impl FnOnce(&Message) -> R) -> R {
+        // Mailbox lock must be released before .await
+        {
+            let mut mailbox = self.inner.lock().expect("only accessed by one process");
+
+            // A found message from a canceled `.await` goes into the queue, same as in `pop`.
+            if let Some(found) = mailbox.found.take() {
+                mailbox.push_back(found);
+            }
+
+            let filter = tags.map(|tags| TagFilter::Tags(tags.into()));
+            let index = match filter.as_ref() {
+                Some(filter) => mailbox.messages.iter().position(|x| filter.matches(x)),
+                None => (!mailbox.messages.is_empty()).then_some(0),
+            };
+            if let Some(index) = index {
+                if index > 0 {
+                    let message = mailbox.remove(index).expect("must exist");
+                    mailbox.push_front(message);
+                }
+                return f(mailbox.messages.front().expect("must exist"));
+            }
+            // Mark the tags to wait on.
+            mailbox.tags = filter;
+        }
+        let message = self.await;
+        let result = f(&message);
+        self.push_front(message);
+        result
+    }
+
+    /// Puts a message back at the front of the queue, e.g. after it was popped.
+    ///
+    /// The size limit of the mailbox is not enforced for messages put back.
+    pub fn push_front(&self, message: Message) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.push_front(message);
+    }
+
+    /// Drops the message at the front of the queue.
+    ///
+    /// Returns `false` if the mailbox is empty.
+    pub fn discard(&self) -> bool {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.pop_front().is_some()
+    }
+
+    /// Returns copies of the first `n` messages in the queue.
+    pub fn snapshot(&self, n: usize) -> Vec<Message> {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.messages.iter().take(n).cloned().collect()
     }
 
     /// Returns the number of messages currently available
@@ -176,16 +390,25 @@ mod tests {
         task::{Context, Poll, Wake},
     };
 
-    use super::{Message, MessageMailbox};
+    use super::{MailboxOverflowPolicy, Message, MessageMailbox};
+    use crate::{message::DataMessage, DeathReason};
+
+    fn link_died(tag: Option<i64>) -> Message {
+        Message::LinkDied(tag, 1, DeathReason::Failure, None)
+    }
+
+    fn data(tag: Option<i64>) -> Message {
+        Message::Data(DataMessage::new_from_vec(tag, Vec::new()))
+    }
 
     #[tokio::test]
     async fn no_tags_signal_message() {
         let mailbox = MessageMailbox::default();
-        let message = Message::LinkDied(None);
+        let message = link_died(None);
         mailbox.push(message);
         let result = mailbox.pop(None).await;
         match result {
-            Message::LinkDied(None) => (),
+            Message::LinkDied(None, 1, DeathReason::Failure, None) => (),
             _ => panic!("Wrong message received"),
         }
     }
@@ -194,7 +417,7 @@ mod tests {
     async fn tag_signal_message() {
         let mailbox = MessageMailbox::default();
         let tag = 1337;
-        let message = Message::LinkDied(Some(tag));
+        let message = link_died(Some(tag));
         mailbox.push(message);
         let message = mailbox.pop(None).await;
         assert_eq!(message.tag(), Some(tag));
@@ -208,11 +431,11 @@ mod tests {
         let tag3 = 3;
         let tag4 = 4;
         let tag5 = 5;
-        mailbox.push(Message::LinkDied(Some(tag1)));
-        mailbox.push(Message::LinkDied(Some(tag2)));
-        mailbox.push(Message::LinkDied(Some(tag3)));
-        mailbox.push(Message::LinkDied(Some(tag4)));
-        mailbox.push(Message::LinkDied(Some(tag5)));
+        mailbox.push(link_died(Some(tag1)));
+        mailbox.push(link_died(Some(tag2)));
+        mailbox.push(link_died(Some(tag3)));
+        mailbox.push(link_died(Some(tag4)));
+        mailbox.push(link_died(Some(tag5)));
         let message = mailbox.pop(Some(&[tag2])).await;
         assert_eq!(message.tag(), Some(tag2));
         let message = mailbox.pop(Some(&[tag1])).await;
@@ -234,11 +457,11 @@ mod tests {
         let tag3 = 3;
         let tag4 = 4;
         let tag5 = 5;
-        mailbox.push(Message::LinkDied(Some(tag1)));
-        mailbox.push(Message::LinkDied(Some(tag2)));
-        mailbox.push(Message::LinkDied(Some(tag3)));
-        mailbox.push(Message::LinkDied(Some(tag4)));
-        mailbox.push(Message::LinkDied(Some(tag5)));
+        mailbox.push(link_died(Some(tag1)));
+        mailbox.push(link_died(Some(tag2)));
+        mailbox.push(link_died(Some(tag3)));
+        mailbox.push(link_died(Some(tag4)));
+        mailbox.push(link_died(Some(tag5)));
         let message = mailbox.pop(Some(&[tag2, tag1, tag3])).await;
         assert_eq!(message.tag(), Some(tag1));
         let message = mailbox.pop(Some(&[tag2, tag1, tag3])).await;
@@ -252,6 +475,162 @@ mod tests {
         assert_eq!(message.tag(), Some(tag5));
     }
 
+    #[tokio::test]
+    async fn range_receive_tags_signal_message() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(link_died(None));
+        mailbox.push(link_died(Some(1)));
+        mailbox.push(link_died(Some(0x10_0001)));
+        mailbox.push(link_died(Some(0x10_0002)));
+        let message = mailbox.pop_range(0x10_0000, 0x10_ffff).await;
+        assert_eq!(message.tag(), Some(0x10_0001));
+        let message = mailbox.pop_range(0x10_0000, 0x10_ffff).await;
+        assert_eq!(message.tag(), Some(0x10_0002));
+        // Untagged messages never match a range
+        let message = mailbox.pop_range(i64::MIN, i64::MAX).await;
+        assert_eq!(message.tag(), Some(1));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn peek_and_discard_signal_message() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(link_died(Some(1)));
+        mailbox.push(link_died(Some(2)));
+        let message = mailbox.peek(Some(&[2])).await;
+        assert_eq!(message.tag(), Some(2));
+        // The peeked message is moved to the front
+        let message = mailbox.peek(None).await;
+        assert_eq!(message.tag(), Some(2));
+        assert_eq!(mailbox.len(), 2);
+        assert!(mailbox.discard());
+        let message = mailbox.pop(None).await;
+        assert_eq!(message.tag(), Some(1));
+        assert!(!mailbox.discard());
+    }
+
+    #[test]
+    fn watermark_pressure() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(link_died(Some(1)));
+        assert_eq!(mailbox.pressure(), (1, false));
+        mailbox.set_watermark(Some(2));
+        assert_eq!(mailbox.pressure(), (1, false));
+        mailbox.push(link_died(Some(2)));
+        assert_eq!(mailbox.pressure(), (2, true));
+    }
+
+    #[test]
+    fn snapshot_keeps_messages() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(link_died(Some(1)));
+        mailbox.push(link_died(Some(2)));
+        mailbox.push(link_died(Some(3)));
+        let tags: Vec<_> = mailbox.snapshot(2).iter().map(|m| m.tag()).collect();
+        assert_eq!(tags, [Some(1), Some(2)]);
+        assert_eq!(mailbox.len(), 3);
+    }
+
+    #[test]
+    fn max_message_size_drops_big_messages() {
+        let mailbox = MessageMailbox::default();
+        mailbox.set_max_message_size(Some(4));
+        assert!(mailbox.push(Message::Data(DataMessage::new_from_vec(None, vec![0; 4]))));
+        assert!(mailbox.push(Message::Data(DataMessage::new_from_vec(None, vec![0; 5]))));
+        // Signals are never dropped
+        assert!(mailbox.push(link_died(None)));
+        assert!(mailbox.push(Message::Data(DataMessage::new_from_vec(None, vec![0; 6]))));
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.oversized_dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn peek_copies_only_data() {
+        let mailbox = MessageMailbox::default();
+        let mut message = DataMessage::new_from_vec(Some(1), vec![1, 2, 3]);
+        message.add_resource(Arc::new(()));
+        mailbox.push(Message::Data(message));
+        match mailbox.peek(None).await {
+            Message::Data(copy) => {
+                assert_eq!(copy.buffer, vec![1, 2, 3]);
+                assert!(copy.resources.is_empty());
+            }
+            _ => panic!("Expected data message"),
+        }
+        // The resources stay with the message in the queue
+        assert!(mailbox.pop(None).await.has_resources());
+    }
+
+    #[tokio::test]
+    async fn overflow_drop_newest() {
+        let mailbox = MessageMailbox::new(Some(2), MailboxOverflowPolicy::DropNewest);
+        assert!(mailbox.push(data(Some(1))));
+        assert!(mailbox.push(data(Some(2))));
+        assert!(mailbox.push(data(Some(3))));
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.pop(None).await.tag(), Some(1));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn overflow_drop_oldest() {
+        let mailbox = MessageMailbox::new(Some(2), MailboxOverflowPolicy::DropOldest);
+        assert!(mailbox.push(data(Some(1))));
+        assert!(mailbox.push(data(Some(2))));
+        assert!(mailbox.push(data(Some(3))));
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.pop(None).await.tag(), Some(2));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(3));
+    }
+
+    #[test]
+    fn overflow_kill_process() {
+        let mailbox = MessageMailbox::new(Some(1), MailboxOverflowPolicy::KillProcess);
+        assert!(mailbox.push(data(None)));
+        assert!(!mailbox.push(data(None)));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn receiving_frees_space() {
+        let mailbox = MessageMailbox::new(Some(1), MailboxOverflowPolicy::DropNewest);
+        mailbox.push(data(Some(1)));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(1));
+        mailbox.push(data(Some(2)));
+        assert_eq!(mailbox.peek(None).await.tag(), Some(2));
+        // Peeked messages still count
+        mailbox.push(data(Some(3)));
+        assert_eq!(mailbox.len(), 1);
+        assert!(mailbox.discard());
+        mailbox.push(data(Some(4)));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn overflow_only_applies_to_data() {
+        let mailbox = MessageMailbox::new(Some(1), MailboxOverflowPolicy::DropOldest);
+        assert!(mailbox.push(link_died(Some(1))));
+        assert!(mailbox.push(data(Some(2))));
+        assert!(mailbox.push(Message::Shutdown));
+        // Only the oldest data message is dropped
+        assert!(mailbox.push(data(Some(3))));
+        assert!(mailbox.push(Message::ProcessDied(4, DeathReason::Normal)));
+        assert_eq!(mailbox.len(), 4);
+        assert_eq!(mailbox.pop(None).await.tag(), Some(1));
+        assert!(matches!(mailbox.pop(None).await, Message::Shutdown));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(3));
+        assert!(matches!(
+            mailbox.pop(None).await,
+            Message::ProcessDied(4, _)
+        ));
+
+        // Signals are delivered even if the process would be killed for a full mailbox
+        let mailbox = MessageMailbox::new(Some(1), MailboxOverflowPolicy::KillProcess);
+        assert!(mailbox.push(data(None)));
+        assert!(mailbox.push(link_died(None)));
+        assert!(!mailbox.push(data(None)));
+    }
+
     #[derive(Clone)]
     struct FlagWaker(Arc<Mutex<bool>>);
     impl Wake for FlagWaker {
@@ -279,7 +658,7 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message to the mailbox will call the waker
-        mailbox.push(Message::LinkDied(tags));
+        mailbox.push(link_died(tags));
         assert!(*waker_ref.0.lock().unwrap());
         // Next poll will return the value
         let result = fut.as_mut().poll(&mut context);
@@ -302,15 +681,15 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message with the `None` tags should not trigger the waker
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(link_died(None));
         assert!(!*waker_ref.0.lock().unwrap());
         // Next poll will still not have the value with the tags 1337
         let result = fut.as_mut().poll(&mut context);
         assert!(result.is_pending());
         // Pushing another None in the meantime should not remove the waker
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(link_died(None));
         // Pushing a message with tags 1337 should trigger the waker
-        mailbox.push(Message::LinkDied(Some(1337)));
+        mailbox.push(link_died(Some(1337)));
         assert!(*waker_ref.0.lock().unwrap());
         // Next poll will have the message ready
         let result = fut.as_mut().poll(&mut context);
@@ -332,7 +711,7 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message with the `None` tags should call the waker()
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(link_died(None));
         assert!(*waker_ref.0.lock().unwrap());
         // Dropping the future will cancel it
         drop(fut);
@@ -346,7 +725,7 @@ mod tests {
         tokio::pin!(fut);
         let result = fut.poll(&mut context);
         match result {
-            Poll::Ready(Message::LinkDied(tags)) => assert_eq!(tags, None),
+            Poll::Ready(Message::LinkDied(tags, ..)) => assert_eq!(tags, None),
             _ => panic!("Unexpected message"),
         }
     }
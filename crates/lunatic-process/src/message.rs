@@ -6,46 +6,114 @@ kinds of messages, like the [`Message::LinkDied`], that is received if a linked
 
 use std::{
     any::Any,
+    collections::HashMap,
     fmt::Debug,
     io::{Read, Write},
     sync::Arc,
 };
 
-use lunatic_networking_api::{TcpConnection, TlsConnection};
-use tokio::net::UdpSocket;
+use lunatic_networking_api::{TcpConnection, TlsConnection, TlsListener};
+use lunatic_quic_api::{QuicConnection, QuicRecvStream, QuicSendStream};
+use lunatic_stdout_capture::StdinPipeWriter;
+use lunatic_websocket_api::WebSocketConnection;
+use tokio::net::{TcpListener, UdpSocket};
 
-use crate::runtimes::wasmtime::WasmtimeCompiledModule;
+use crate::{runtimes::wasmtime::WasmtimeCompiledModule, DeathReason};
 
 pub type Resource = dyn Any + Send + Sync;
 
+/// Key/value metadata attached to a [`DataMessage`], e.g. tracing context or content type.
+pub type MessageHeaders = HashMap<Vec<u8>, Vec<u8>>;
+
+/// Maximum combined size of all header keys and values of a message in bytes.
+pub const MAX_HEADERS_SIZE: usize = 4 * 1024;
+
+/// Header carrying the trace context of the sending process, e.g. in the W3C `traceparent`
+/// format. The host doesn't interpret the value, it only moves it between processes.
+pub const TRACE_CONTEXT_HEADER: &[u8] = b"traceparent";
+
 /// Can be sent between processes by being embedded into a  [`Signal::Message`][0]
 ///
-/// A [`Message`] has 2 variants:
+/// A [`Message`] has 4 variants:
 /// * Data - Regular message containing a tag, buffer and resources.
-/// * LinkDied - A `LinkDied` signal that was turned into a message.
+/// * LinkDied - A `LinkDied` signal that was turned into a message. It contains the ID of the
+///   dead process and the reason of death.
+/// * ProcessDied - A `ProcessDied` signal received from a monitored process.
+/// * Shutdown - A `Shutdown` signal that was turned into a message.
 ///
 /// [0]: crate::Signal
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Message {
     Data(DataMessage),
-    LinkDied(Option<i64>),
-    ProcessDied(u64),
+    LinkDied(Option<i64>, u64, DeathReason, Option<Vec<u8>>),
+    ProcessDied(u64, DeathReason),
+    Shutdown,
 }
 
 impl Message {
     pub fn tag(&self) -> Option<i64> {
         match self {
             Message::Data(message) => message.tag,
-            Message::LinkDied(tag) => *tag,
-            Message::ProcessDied(_) => None,
+            Message::LinkDied(tag, ..) => *tag,
+            Message::ProcessDied(..) => None,
+            Message::Shutdown => None,
         }
     }
 
+    /// Returns true for data messages, false for signals turned into messages.
+    pub fn is_data(&self) -> bool {
+        matches!(self, Message::Data(_))
+    }
+
     pub fn process_id(&self) -> Option<u64> {
         match self {
             Message::Data(_) => None,
-            Message::LinkDied(_) => None,
-            Message::ProcessDied(process_id) => Some(*process_id),
+            Message::LinkDied(_, process_id, ..) => Some(*process_id),
+            Message::ProcessDied(process_id, _) => Some(*process_id),
+            Message::Shutdown => None,
+        }
+    }
+
+    pub fn kill_reason(&self) -> Option<&[u8]> {
+        match self {
+            Message::Data(_) => None,
+            Message::LinkDied(.., reason) => reason.as_deref(),
+            Message::ProcessDied(..) => None,
+            Message::Shutdown => None,
+        }
+    }
+
+    pub fn death_reason(&self) -> Option<DeathReason> {
+        match self {
+            Message::Data(_) => None,
+            Message::LinkDied(_, _, reason, _) => Some(*reason),
+            Message::ProcessDied(_, reason) => Some(*reason),
+            Message::Shutdown => None,
+        }
+    }
+
+    /// Returns a copy of the message without its resources, only the data is copied.
+    pub fn copy_data(&self) -> Message {
+        match self {
+            Message::Data(message) => Message::Data(DataMessage {
+                tag: message.tag,
+                read_ptr: 0,
+                buffer: message.buffer.clone(),
+                resources: Vec::new(),
+                headers: message.headers.clone(),
+            }),
+            message => message.clone(),
+        }
+    }
+
+    /// Returns true if resources are attached to the message.
+    ///
+    /// Copies of a message share its resources, so such messages can only be sent to a single
+    /// process.
+    pub fn has_resources(&self) -> bool {
+        match self {
+            Message::Data(message) => message.resources.iter().any(Option::is_some),
+            _ => false,
         }
     }
 
@@ -53,10 +121,11 @@ impl Message {
     pub fn write_metrics(&self) {
         match self {
             Message::Data(message) => message.write_metrics(),
-            Message::LinkDied(_) => {
+            Message::LinkDied(..) => {
                 metrics::increment_counter!("lunatic.process.messages.link_died.count");
             }
-            Message::ProcessDied(_) => {}
+            Message::ProcessDied(..) => {}
+            Message::Shutdown => {}
         }
     }
 }
@@ -64,13 +133,16 @@ impl Message {
 /// A variant of a [`Message`] that has a buffer of data and resources attached to it.
 ///
 /// It implements the [`Read`](std::io::Read) and [`Write`](std::io::Write) traits.
-#[derive(Debug, Default)]
+///
+/// Cloning a `DataMessage` copies the buffer, but resources are shared between the clones.
+#[derive(Debug, Default, Clone)]
 pub struct DataMessage {
     // TODO: Only the Node implementation depends on these fields being public.
     pub tag: Option<i64>,
     pub read_ptr: usize,
     pub buffer: Vec<u8>,
     pub resources: Vec<Option<Arc<Resource>>>,
+    pub headers: MessageHeaders,
 }
 
 impl DataMessage {
@@ -81,6 +153,7 @@ impl DataMessage {
             read_ptr: 0,
             buffer: Vec::with_capacity(buffer_capacity),
             resources: Vec::new(),
+            headers: MessageHeaders::new(),
         }
     }
 
@@ -91,9 +164,38 @@ impl DataMessage {
             read_ptr: 0,
             buffer,
             resources: Vec::new(),
+            headers: MessageHeaders::new(),
         }
     }
 
+    /// Sets the header `key` to `value`, replacing any previous value.
+    ///
+    /// Returns `false` without changing the headers if the combined size of all headers would
+    /// exceed [`MAX_HEADERS_SIZE`].
+    pub fn set_header(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let size: usize = self
+            .headers
+            .iter()
+            .filter(|(k, _)| **k != key)
+            .map(|(k, v)| k.len() + v.len())
+            .sum();
+        if size + key.len() + value.len() > MAX_HEADERS_SIZE {
+            return false;
+        }
+        self.headers.insert(key, value);
+        true
+    }
+
+    /// Returns the value of the header `key`.
+    pub fn header(&self, key: &[u8]) -> Option<&[u8]> {
+        self.headers.get(key).map(|value| value.as_slice())
+    }
+
+    /// Returns the trace context of the sending process, see [`TRACE_CONTEXT_HEADER`].
+    pub fn trace_context(&self) -> Option<&[u8]> {
+        self.header(TRACE_CONTEXT_HEADER)
+    }
+
     /// Adds a resource to the message and returns the index of it inside of the message.
     ///
     /// The resource is `Any` and is downcasted when accessing later.
@@ -137,6 +239,74 @@ impl DataMessage {
         self.take_downcast(index)
     }
 
+    /// Takes a TCP listener from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a tcp listener the function will
+    /// return None.
+    pub fn take_tcp_listener(&mut self, index: usize) -> Option<Arc<TcpListener>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a TLS listener from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a tls listener the function will
+    /// return None.
+    pub fn take_tls_listener(&mut self, index: usize) -> Option<Arc<TlsListener>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a Unix stream from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a unix stream the function will
+    /// return None.
+    #[cfg(unix)]
+    pub fn take_unix_stream(
+        &mut self,
+        index: usize,
+    ) -> Option<Arc<lunatic_networking_api::UnixConnection>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a WebSocket from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a WebSocket the function will return
+    /// None.
+    pub fn take_websocket(&mut self, index: usize) -> Option<Arc<WebSocketConnection>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a QUIC connection from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a QUIC connection the function will
+    /// return None.
+    pub fn take_quic_connection(&mut self, index: usize) -> Option<Arc<QuicConnection>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a QUIC send stream from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a QUIC send stream the function will
+    /// return None.
+    pub fn take_quic_send_stream(&mut self, index: usize) -> Option<Arc<QuicSendStream>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a QUIC receive stream from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a QUIC receive stream the function
+    /// will return None.
+    pub fn take_quic_recv_stream(&mut self, index: usize) -> Option<Arc<QuicRecvStream>> {
+        self.take_downcast(index)
+    }
+
+    /// Takes a stdin pipe from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a stdin pipe the function will return
+    /// None.
+    pub fn take_stdin_pipe(&mut self, index: usize) -> Option<Arc<StdinPipeWriter>> {
+        self.take_downcast(index)
+    }
+
     /// Moves read pointer to index.
     pub fn seek(&mut self, index: usize) {
         self.read_ptr = index;
@@ -0,0 +1,98 @@
+//! Labels attached to the metrics of processes and environments.
+//!
+//! Labelling metrics with process IDs creates a new time series for every process, which
+//! overwhelms most metrics backends once many short-lived processes are spawned. How detailed the
+//! labels are is therefore configured once for the whole runtime.
+
+use std::{str::FromStr, sync::OnceLock};
+
+use anyhow::{anyhow, Result};
+
+/// How finely metrics of processes are split up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsGranularity {
+    /// Metrics are aggregated for the whole node.
+    Node,
+    /// Metrics are labelled with the environment ID.
+    Environment,
+    /// Metrics are labelled with the environment and process ID.
+    Process,
+}
+
+impl Default for MetricsGranularity {
+    fn default() -> Self {
+        if cfg!(feature = "detailed_metrics") {
+            MetricsGranularity::Process
+        } else {
+            MetricsGranularity::Node
+        }
+    }
+}
+
+impl FromStr for MetricsGranularity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "node" => Ok(MetricsGranularity::Node),
+            "environment" => Ok(MetricsGranularity::Environment),
+            "process" => Ok(MetricsGranularity::Process),
+            _ => Err(anyhow!(
+                "Unknown metrics granularity '{s}', expected node, environment or process"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MetricsLabels {
+    pub granularity: MetricsGranularity,
+    /// With [`MetricsGranularity::Process`] only every n-th process gets labelled with its ID,
+    /// the metrics of the other processes are only labelled with the environment ID.
+    pub process_sampling: u64,
+}
+
+impl Default for MetricsLabels {
+    fn default() -> Self {
+        Self {
+            granularity: MetricsGranularity::default(),
+            process_sampling: 1,
+        }
+    }
+}
+
+static LABELS: OnceLock<MetricsLabels> = OnceLock::new();
+
+/// Sets the labels of process metrics, can only be called once and before the first process is
+/// spawned.
+pub fn configure(labels: MetricsLabels) -> Result<()> {
+    if labels.process_sampling == 0 {
+        return Err(anyhow!("Process sampling must be greater than 0"));
+    }
+    LABELS
+        .set(labels)
+        .map_err(|_| anyhow!("Metrics labels are already configured"))
+}
+
+/// Returns the labels for a metric of the environment or process, as far as they are known.
+pub(crate) fn labels(
+    environment_id: Option<u64>,
+    process_id: Option<u64>,
+) -> Vec<(&'static str, String)> {
+    let config = LABELS.get_or_init(MetricsLabels::default);
+    let mut labels = Vec::new();
+    if config.granularity == MetricsGranularity::Node {
+        return labels;
+    }
+    if let Some(environment_id) = environment_id {
+        labels.push(("environment_id", environment_id.to_string()));
+    }
+    if config.granularity == MetricsGranularity::Process {
+        if let Some(process_id) = process_id {
+            if process_id % config.process_sampling == 0 {
+                labels.push(("process_id", process_id.to_string()));
+            }
+        }
+    }
+    labels
+}
@@ -0,0 +1,429 @@
+//! Plugins are WebAssembly modules that transform other modules before they are compiled, e.g. to
+//! add instrumentation.
+//!
+//! A plugin exports its `memory`, a `lunatic_alloc(size: u32) -> u32` function and the hook
+//! `lunatic_create_module_hook(module_ptr: u32, module_len: u32)`. The runtime copies each module
+//! into a fresh instance of the plugin and calls the hook, which can hand back a transformed module
+//! with the `lunatic::plugin::set_module` host function. If it doesn't, the module stays unchanged.
+//!
+//! Plugins run in a separate engine without access to any other host functions, and with a limit
+//! on the memory and fuel they can use per module.
+//!
+//! Plugins are applied in the order they are loaded, each one receiving the module produced by
+//! the previous one. Only the final module is validated, when it's compiled. Runtimes with an
+//! [`ArtifactCache`](crate::runtimes::artifact_cache::ArtifactCache) store the compiled result
+//! keyed by the original module and [`Plugins::id`], so the plugins only run once per module.
+//!
+//! Plugins can also export callbacks for lifecycle events of processes, once the hooks are
+//! installed with [`Plugins::install_lifecycle_hooks`]. The callbacks of a plugin share one
+//! instance for the lifetime of the runtime, so they can keep state between events:
+//! * `on_process_spawn(environment_id: u64, process_id: u64)`
+//! * `on_process_exit(environment_id: u64, process_id: u64, reason: u32)` with the reason as
+//!   exposed to guest code.
+//! * `on_message_send(environment_id: u64, process_id: u64, tag: i64, size: u32) -> u32` is called
+//!   before a message reaches the mailbox of the receiving process. Returning anything other than
+//!   0 drops the message. The tag is 0 for untagged messages and the size is the size of the data.
+//!   It's only called if message callbacks are enabled when installing the hooks.
+//!
+//! Callbacks are called while the runtime waits on them and should return quickly. A callback that
+//! traps is logged and otherwise ignored.
+//!
+//! Each plugin instance runs one callback at a time, so events of all processes wait for each
+//! other while a plugin handles them. This matters most for `on_message_send`, which turns every
+//! message delivery of the runtime into a call of the plugin, and is why it needs to be enabled
+//! explicitly. Events without a callback don't wait on the plugins.
+//!
+//! # ABI versions
+//!
+//! Every plugin exports `lunatic_plugin_abi_version() -> u32`, returning the version of the plugin
+//! ABI it was built against. Plugins with a version outside of [`MIN_ABI_VERSION`] and
+//! [`ABI_VERSION`] fail to load. The version is bumped when an existing export or host function
+//! changes or is removed, and the minimum version is raised if the runtime can't support the older
+//! ABI anymore. Adding optional callbacks or host functions keeps the version.
+
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use wasmtime::{
+    Caller, Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc, WasmParams, WasmResults,
+};
+
+use crate::{
+    config::UNIT_OF_COMPUTE_IN_INSTRUCTIONS, message::Message,
+    runtimes::artifact_cache::artifact_key, DeathReason,
+};
+
+/// Version of the plugin ABI implemented by this runtime.
+pub const ABI_VERSION: u32 = 1;
+/// Oldest version of the plugin ABI that plugins can be built against.
+pub const MIN_ABI_VERSION: u32 = 1;
+
+const ABI_VERSION_FUNCTION_NAME: &str = "lunatic_plugin_abi_version";
+const ALLOCATOR_FUNCTION_NAME: &str = "lunatic_alloc";
+const HOOK_FUNCTION_NAME: &str = "lunatic_create_module_hook";
+// Fuel a plugin can consume while transforming one module
+const MAX_FUEL: u64 = 100_000 * UNIT_OF_COMPUTE_IN_INSTRUCTIONS;
+// Memory a plugin can use while transforming one module
+const MAX_MEMORY: usize = 1024 * 1024 * 1024; // 1 GB
+
+// Fuel a lifecycle callback can consume per call
+const MAX_CALLBACK_FUEL: u64 = 100 * UNIT_OF_COMPUTE_IN_INSTRUCTIONS;
+
+static LIFECYCLE_HOOKS: OnceLock<Vec<LifecycleHooks>> = OnceLock::new();
+
+/// The plugins loaded by the runtime, in the order they are applied.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    plugins: Arc<Vec<Plugin>>,
+    // Hash of all plugin modules in order
+    id: String,
+}
+
+struct Plugin {
+    name: String,
+    engine: Engine,
+    instance_pre: InstancePre<PluginState>,
+}
+
+struct PluginState {
+    limits: StoreLimits,
+    module: Option<Vec<u8>>,
+}
+
+impl PluginState {
+    fn new() -> Self {
+        Self {
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            module: None,
+        }
+    }
+}
+
+// The lifecycle callbacks of one plugin instance. Which callbacks exist is known without locking
+// the instance.
+struct LifecycleHooks {
+    plugin: String,
+    store: Mutex<Store<PluginState>>,
+    on_process_spawn: Option<TypedFunc<(u64, u64), ()>>,
+    on_process_exit: Option<TypedFunc<(u64, u64, u32), ()>>,
+    on_message_send: Option<TypedFunc<(u64, u64, i64, u32), u32>>,
+}
+
+impl Plugins {
+    /// Loads the plugins from `.wasm` files.
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        let plugins = paths
+            .iter()
+            .map(|path| {
+                let module = std::fs::read(path)
+                    .with_context(|| format!("Failed to read plugin '{}'", path.display()))?;
+                Ok((path.display().to_string(), module))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(plugins)
+    }
+
+    /// Compiles the plugins, given as pairs of a name used in error messages and the module.
+    pub fn new(plugins: Vec<(String, Vec<u8>)>) -> Result<Self> {
+        if plugins.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap("lunatic::plugin", "set_module", set_module)?;
+        let modules: Vec<&[u8]> = plugins
+            .iter()
+            .map(|(_, module)| module.as_slice())
+            .collect();
+        let id = artifact_key(&modules);
+        let plugins = plugins
+            .into_iter()
+            .map(|(name, module)| {
+                let instance_pre = Module::new(&engine, module)
+                    .and_then(|module| linker.instantiate_pre(&module))
+                    .with_context(|| format!("Failed to load plugin '{name}'"))?;
+                let plugin = Plugin {
+                    name,
+                    engine: engine.clone(),
+                    instance_pre,
+                };
+                plugin
+                    .check_abi_version()
+                    .with_context(|| format!("Failed to load plugin '{}'", plugin.name))?;
+                Ok(plugin)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            plugins: Arc::new(plugins),
+            id,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Identifies the set of plugins and the order they are applied in. Changes if any plugin
+    /// changes.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Passes the module through the hook of each plugin.
+    pub fn transform<'a>(&self, module: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let mut module = Cow::Borrowed(module);
+        for plugin in self.plugins.iter() {
+            if let Some(transformed) = plugin
+                .transform(&module)
+                .with_context(|| format!("Plugin '{}' failed", plugin.name))?
+            {
+                module = Cow::Owned(transformed);
+            }
+        }
+        Ok(module)
+    }
+
+    /// Instantiates the plugins exporting lifecycle callbacks and calls them on process events
+    /// from now on. `on_message_send` is only called if `message_callbacks` is set.
+    ///
+    /// Can only be called once.
+    pub fn install_lifecycle_hooks(&self, message_callbacks: bool) -> Result<()> {
+        let mut hooks = Vec::new();
+        for plugin in self.plugins.iter() {
+            if let Some(plugin_hooks) = plugin
+                .lifecycle_hooks(message_callbacks)
+                .with_context(|| format!("Failed to instantiate plugin '{}'", plugin.name))?
+            {
+                hooks.push(plugin_hooks);
+            }
+        }
+        LIFECYCLE_HOOKS
+            .set(hooks)
+            .map_err(|_| anyhow!("Plugin lifecycle hooks are already installed"))
+    }
+}
+
+impl Plugin {
+    fn check_abi_version(&self) -> Result<()> {
+        if self
+            .instance_pre
+            .module()
+            .get_export(ABI_VERSION_FUNCTION_NAME)
+            .is_none()
+        {
+            return Err(anyhow!(
+                "No export `{ABI_VERSION_FUNCTION_NAME}` found, the plugin was built for an older \
+                 version of lunatic"
+            ));
+        }
+        let mut store = Store::new(&self.engine, PluginState::new());
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(MAX_CALLBACK_FUEL)?;
+        let instance = self.instance_pre.instantiate(&mut store)?;
+        let version = instance
+            .get_typed_func::<(), u32>(&mut store, ABI_VERSION_FUNCTION_NAME)?
+            .call(&mut store, ())?;
+        if (MIN_ABI_VERSION..=ABI_VERSION).contains(&version) {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "The plugin was built for ABI version {version}, but this version of lunatic \
+                 supports versions {MIN_ABI_VERSION} to {ABI_VERSION}"
+            ))
+        }
+    }
+
+    // Returns the transformed module, or `None` if the plugin left it unchanged.
+    fn transform(&self, module: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut store = Store::new(&self.engine, PluginState::new());
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(MAX_FUEL)?;
+        let instance = self.instance_pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("No export `memory` found")?;
+        let alloc = instance.get_typed_func::<u32, u32>(&mut store, ALLOCATOR_FUNCTION_NAME)?;
+        let hook = instance.get_typed_func::<(u32, u32), ()>(&mut store, HOOK_FUNCTION_NAME)?;
+
+        let module_len = u32::try_from(module.len()).context("Module is too big")?;
+        let module_ptr = alloc.call(&mut store, module_len)?;
+        memory.write(&mut store, module_ptr as usize, module)?;
+        hook.call(&mut store, (module_ptr, module_len))?;
+        Ok(store.into_data().module)
+    }
+
+    // Returns `None` if the plugin doesn't export any of the lifecycle callbacks.
+    fn lifecycle_hooks(&self, message_callbacks: bool) -> Result<Option<LifecycleHooks>> {
+        let module = self.instance_pre.module();
+        if !message_callbacks && module.get_export("on_message_send").is_some() {
+            warn!(
+                "Plugin '{}' exports `on_message_send`, but message callbacks are disabled",
+                self.name
+            );
+        }
+        let mut callbacks = vec!["on_process_spawn", "on_process_exit"];
+        if message_callbacks {
+            callbacks.push("on_message_send");
+        }
+        if !callbacks
+            .iter()
+            .any(|name| module.get_export(name).is_some())
+        {
+            return Ok(None);
+        }
+        let mut store = Store::new(&self.engine, PluginState::new());
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(MAX_CALLBACK_FUEL)?;
+        let instance = self.instance_pre.instantiate(&mut store)?;
+        let on_process_spawn = match instance.get_func(&mut store, "on_process_spawn") {
+            Some(func) => Some(func.typed(&store)?),
+            None => None,
+        };
+        let on_process_exit = match instance.get_func(&mut store, "on_process_exit") {
+            Some(func) => Some(func.typed(&store)?),
+            None => None,
+        };
+        let on_message_send = match instance.get_func(&mut store, "on_message_send") {
+            Some(func) if message_callbacks => Some(func.typed(&store)?),
+            _ => None,
+        };
+        Ok(Some(LifecycleHooks {
+            plugin: self.name.clone(),
+            store: Mutex::new(store),
+            on_process_spawn,
+            on_process_exit,
+            on_message_send,
+        }))
+    }
+}
+
+// Calls the callback of each plugin that exports it, with a refilled fuel tank. Plugins without
+// the callback are skipped without locking their instance.
+fn call_hooks<Params, Results>(
+    callback: &str,
+    func: fn(&LifecycleHooks) -> Option<TypedFunc<Params, Results>>,
+    params: Params,
+    mut on_results: impl FnMut(Results),
+) where
+    Params: WasmParams + Copy,
+    Results: WasmResults,
+{
+    for hooks in LIFECYCLE_HOOKS.get().into_iter().flatten() {
+        let func = match func(hooks) {
+            Some(func) => func,
+            None => continue,
+        };
+        let mut store = hooks.store.lock().unwrap();
+        match refuel(&mut store).and_then(|_| func.call(&mut *store, params)) {
+            Ok(results) => on_results(results),
+            Err(e) => warn!("Plugin '{}' failed in `{callback}`: {e}", hooks.plugin),
+        }
+    }
+}
+
+// Refills the fuel of the instance before a callback is called.
+fn refuel(store: &mut Store<PluginState>) -> Result<()> {
+    let remaining = store.consume_fuel(0)?;
+    store.add_fuel(MAX_CALLBACK_FUEL.saturating_sub(remaining))
+}
+
+pub(crate) fn on_process_spawn(environment_id: u64, process_id: u64) {
+    call_hooks(
+        "on_process_spawn",
+        |hooks| hooks.on_process_spawn,
+        (environment_id, process_id),
+        |_| {},
+    );
+}
+
+pub(crate) fn on_process_exit(environment_id: u64, process_id: u64, reason: DeathReason) {
+    call_hooks(
+        "on_process_exit",
+        |hooks| hooks.on_process_exit,
+        (environment_id, process_id, reason.as_u32()),
+        |_| {},
+    );
+}
+
+pub(crate) fn on_message_send(environment_id: u64, process_id: u64, message: &Message) -> bool {
+    let tag = message.tag().unwrap_or(0);
+    let size = match message {
+        Message::Data(data) => data.size() as u32,
+        _ => 0,
+    };
+    let mut deliver = true;
+    call_hooks(
+        "on_message_send",
+        |hooks| hooks.on_message_send,
+        (environment_id, process_id, tag, size),
+        |drop| {
+            if drop != 0 {
+                deliver = false;
+            }
+        },
+    );
+    deliver
+}
+
+// Replaces the module passed to the hook with the one at **module_ptr**.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn set_module(mut caller: Caller<PluginState>, module_ptr: u32, module_len: u32) -> Result<()> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .context("No export `memory` found")?;
+    let module = memory
+        .data(&caller)
+        .get(module_ptr as usize..(module_ptr as usize + module_len as usize))
+        .context("lunatic::plugin::set_module")?
+        .to_vec();
+    caller.data_mut().module = Some(module);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plugins;
+
+    // Replaces every module with an empty one
+    const EMPTY_MODULE_PLUGIN: &str = r#"
+        (module
+            (import "lunatic::plugin" "set_module" (func $set_module (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\00asm\01\00\00\00")
+            (func (export "lunatic_plugin_abi_version") (result i32) i32.const 1)
+            (func (export "lunatic_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "lunatic_create_module_hook") (param i32 i32)
+                (call $set_module (i32.const 0) (i32.const 8))))
+    "#;
+
+    const FUTURE_ABI_PLUGIN: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "lunatic_plugin_abi_version") (result i32) i32.const 1000)
+            (func (export "lunatic_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "lunatic_create_module_hook") (param i32 i32)))
+    "#;
+
+    #[test]
+    fn plugin_replaces_module() {
+        let plugins = Plugins::new(vec![("empty".into(), EMPTY_MODULE_PLUGIN.into())]).unwrap();
+        let module = plugins.transform(b"\0asm\x01\0\0\0\x00\x00").unwrap();
+        assert_eq!(&*module, b"\0asm\x01\0\0\0");
+    }
+
+    #[test]
+    fn plugins_with_unsupported_abi_fail_to_load() {
+        assert!(Plugins::new(vec![("future".into(), FUTURE_ABI_PLUGIN.into())]).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    cmp::Reverse,
+    fmt::Write,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the hex encoded SHA-256 hash of all parts, used as the key of an artifact.
+pub fn artifact_key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Prefix each part with its length, so that moving bytes between parts changes the key
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .fold(String::with_capacity(64), |mut key, byte| {
+            let _ = write!(key, "{byte:02x}");
+            key
+        })
+}
+
+/// Returns a key identifying the wasmtime version, target and configuration of the engine.
+///
+/// Artifacts can only be loaded by engines with the same key. It's derived from a serialized empty
+/// module, because wasmtime stores exactly this information in the header of each artifact.
+pub fn engine_key(engine: &wasmtime::Engine) -> Result<String> {
+    let artifact = wasmtime::Module::new(engine, "(module)")?.serialize()?;
+    Ok(artifact_key(&[&artifact]))
+}
+
+/// Compiled modules serialized by wasmtime, kept on disk so that modules don't need to be
+/// transformed and compiled again on the next start.
+///
+/// Multiple runtimes can share the same directory. Artifacts are written atomically, if two
+/// runtimes compile the same module at the same time both store the same artifact.
+///
+/// Without a size limit the cache grows with every module compiled. With a limit, the least
+/// recently used artifacts are removed after storing a new one, until the artifacts fit again.
+///
+/// The artifacts are loaded as machine code without any validation, so the directory must only be
+/// writable by the users running lunatic.
+#[derive(Clone, Debug)]
+pub struct ArtifactCache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+}
+
+impl ArtifactCache {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create artifact cache '{}'", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_size: None,
+        })
+    }
+
+    /// Limits the total size of the artifacts in the directory to `max_size` bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.cwasm"))
+    }
+
+    /// Returns the artifact stored under `key`.
+    ///
+    /// Artifacts that were compiled by another version of wasmtime or with a different engine
+    /// configuration are ignored.
+    pub fn get(&self, engine: &wasmtime::Engine, key: &str) -> Option<wasmtime::Module> {
+        let path = self.path(key);
+        if !path.exists() {
+            return None;
+        }
+        // Safety: the artifacts in the cache directory were written by `put` and the directory is
+        // trusted. Wasmtime checks that the artifact is compatible with the engine.
+        match unsafe { wasmtime::Module::deserialize_file(engine, &path) } {
+            Ok(module) => {
+                // The modification time orders the artifacts by their last use for the eviction
+                if self.max_size.is_some() {
+                    let _ = File::options()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+                }
+                Some(module)
+            }
+            Err(e) => {
+                log::warn!("Ignoring artifact {key} in the artifact cache: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, module: &wasmtime::Module) -> Result<()> {
+        let artifact = module.serialize()?;
+        // Write to a temporary file first, so that other runtimes sharing the cache never read a
+        // partially written artifact
+        let tmp = self.dir.join(format!(
+            "{key}.{}-{}.tmp",
+            std::process::id(),
+            TMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&tmp, artifact)?;
+        std::fs::rename(&tmp, self.path(key))?;
+        if let Some(max_size) = self.max_size {
+            self.evict(max_size)?;
+        }
+        Ok(())
+    }
+
+    // Removes the least recently used artifacts until the rest fits into `max_size` bytes.
+    fn evict(&self, max_size: u64) -> Result<()> {
+        let mut artifacts = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension() != Some("cwasm".as_ref()) {
+                continue;
+            }
+            // Another runtime sharing the directory could have removed the artifact already
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            artifacts.push((used, metadata.len(), path));
+        }
+        // Newest first
+        artifacts.sort_by_key(|(used, _, _)| Reverse(*used));
+        let mut size = 0;
+        for (_, len, path) in artifacts {
+            size += len;
+            if size > max_size {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_depends_on_part_boundaries() {
+        assert_ne!(artifact_key(&[b"ab", b"c"]), artifact_key(&[b"a", b"bc"]));
+        assert_eq!(artifact_key(&[b"ab", b"c"]), artifact_key(&[b"ab", b"c"]));
+    }
+
+    #[test]
+    fn engine_key_depends_on_config() {
+        let engine = wasmtime::Engine::default();
+        let key = engine_key(&engine).unwrap();
+        assert_eq!(key, engine_key(&wasmtime::Engine::default()).unwrap());
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let fuel_engine = wasmtime::Engine::new(&config).unwrap();
+        assert_ne!(key, engine_key(&fuel_engine).unwrap());
+    }
+
+    #[test]
+    fn cached_artifacts_are_loaded() {
+        let dir = std::env::temp_dir().join(format!("lunatic-artifacts-{}", std::process::id()));
+        let cache = ArtifactCache::new(&dir).unwrap();
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, "(module (func (export \"f\")))").unwrap();
+        let key = artifact_key(&[b"module"]);
+        assert!(cache.get(&engine, &key).is_none());
+        cache.put(&key, &module).unwrap();
+        let cached = cache.get(&engine, &key).unwrap();
+        assert!(cached.get_export("f").is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_artifacts_are_evicted() {
+        let dir = std::env::temp_dir().join(format!("lunatic-evict-{}", std::process::id()));
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, "(module)").unwrap();
+        let size = module.serialize().unwrap().len() as u64;
+        let cache = ArtifactCache::new(&dir).unwrap().with_max_size(2 * size);
+        let (first, second, third) = (
+            artifact_key(&[b"first"]),
+            artifact_key(&[b"second"]),
+            artifact_key(&[b"third"]),
+        );
+        cache.put(&first, &module).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.put(&second, &module).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        // Using the first artifact makes the second one the least recently used
+        assert!(cache.get(&engine, &first).is_some());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.put(&third, &module).unwrap();
+        assert!(cache.get(&engine, &first).is_some());
+        assert!(cache.get(&engine, &second).is_none());
+        assert!(cache.get(&engine, &third).is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
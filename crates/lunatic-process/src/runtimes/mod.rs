@@ -16,6 +16,7 @@ use crate::state::ProcessState;
 
 use self::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 
+pub mod artifact_cache;
 pub mod wasmtime;
 
 pub struct RawWasm {
@@ -98,6 +99,11 @@ impl<T: ProcessState + 'static> Modules<T> {
         self.modules.get(&module_id).map(|m| m.clone())
     }
 
+    /// IDs of all compiled modules.
+    pub fn ids(&self) -> Vec<u64> {
+        self.modules.iter().map(|m| *m.key()).collect()
+    }
+
     pub fn compile(
         &self,
         runtime: WasmtimeRuntime,
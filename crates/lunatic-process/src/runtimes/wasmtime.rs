@@ -1,68 +1,187 @@
 use std::sync::Arc;
 
-use anyhow::Result;
-use wasmtime::ResourceLimiter;
+use anyhow::{Context, Result};
+use wasmtime::{ExternType, ResourceLimiter, Val, ValType};
 
 use crate::{
     config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
+    crash::Frame,
+    plugin::Plugins,
     state::ProcessState,
-    ExecutionResult, ResultValue,
+    ExecutionResult, Hibernate, ResultValue,
 };
 
-use super::RawWasm;
+use super::{
+    artifact_cache::{artifact_key, engine_key, ArtifactCache},
+    RawWasm,
+};
 
 #[derive(Clone)]
 pub struct WasmtimeRuntime {
     engine: wasmtime::Engine,
+    plugins: Plugins,
+    // The cache and the key of the engine's configuration
+    cache: Option<(ArtifactCache, String)>,
+}
+
+// Size of a page of linear memory in bytes.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// WASI errno returned by functions that are not implemented.
+const ENOSYS: i32 = 52;
+
+// Language runtimes often import WASI functions that they only call in rare cases, or that this
+// runtime doesn't implement. Instead of failing to link such modules, the missing functions
+// return `ENOSYS` when called, so that the guest can handle it like any other error.
+//
+// Only functions that follow the WASI convention of returning an errno are defined.
+fn define_missing_wasi_imports<T>(linker: &mut wasmtime::Linker<T>, module: &wasmtime::Module) {
+    for import in module.imports() {
+        if !import.module().starts_with("wasi_") {
+            continue;
+        }
+        let ExternType::Func(ty) = import.ty() else {
+            continue;
+        };
+        if !ty.results().eq([ValType::I32]) {
+            continue;
+        }
+        // Existing functions can't be redefined, so this only adds the missing ones.
+        let _ = linker.func_new(import.module(), import.name(), ty, |_, _, results| {
+            results[0] = Val::I32(ENOSYS);
+            Ok(())
+        });
+    }
 }
 
 impl WasmtimeRuntime {
     pub fn new(config: &wasmtime::Config) -> Result<Self> {
         let engine = wasmtime::Engine::new(config)?;
-        Ok(Self { engine })
+        Ok(Self {
+            engine,
+            plugins: Plugins::default(),
+            cache: None,
+        })
+    }
+
+    /// Transforms all modules with the plugins before compiling them.
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// Stores modules in the cache once they are compiled, so that the same module doesn't need
+    /// to be compiled again by this or another runtime with the same configuration and plugins.
+    pub fn with_cache(mut self, cache: ArtifactCache) -> Result<Self> {
+        let engine_key = engine_key(&self.engine)?;
+        self.cache = Some((cache, engine_key));
+        Ok(self)
     }
 
     /// Compiles a wasm module to machine code and performs type-checking on host functions.
+    ///
+    /// The source of the compiled module is the one before the plugins transformed it.
     pub fn compile_module<T>(&self, data: RawWasm) -> Result<WasmtimeCompiledModule<T>>
     where
         T: ProcessState,
     {
-        let module = wasmtime::Module::new(&self.engine, data.as_slice())?;
+        let module = self.compile(data.as_slice())?;
         let mut linker = wasmtime::Linker::new(&self.engine);
         // Register host functions to linker.
         <T as ProcessState>::register(&mut linker)?;
+        define_missing_wasi_imports(&mut linker, &module);
         let instance_pre = linker.instantiate_pre(&module)?;
         let compiled_module = WasmtimeCompiledModule::new(data, module, instance_pre);
         Ok(compiled_module)
     }
 
+    fn compile(&self, data: &[u8]) -> Result<wasmtime::Module> {
+        let cached = self.cache.as_ref().map(|(cache, engine_key)| {
+            let key = artifact_key(&[data, self.plugins.id().as_bytes(), engine_key.as_bytes()]);
+            (cache, key)
+        });
+        if let Some((cache, key)) = &cached {
+            if let Some(module) = cache.get(&self.engine, key) {
+                return Ok(module);
+            }
+        }
+        let module = if self.plugins.is_empty() {
+            wasmtime::Module::new(&self.engine, data)?
+        } else {
+            let transformed = self.plugins.transform(data)?;
+            // Plugins can produce invalid modules, compiling validates the final module once.
+            wasmtime::Module::new(&self.engine, transformed)
+                .context("Module transformed by plugins failed to compile")?
+        };
+        if let Some((cache, key)) = &cached {
+            if let Err(e) = cache.put(key, &module) {
+                log::warn!("Failed to store module in the artifact cache: {e}");
+            }
+        }
+        Ok(module)
+    }
+
     pub async fn instantiate<T>(
         &self,
         compiled_module: &WasmtimeCompiledModule<T>,
         state: T,
     ) -> Result<WasmtimeInstance<T>>
+    where
+        T: ProcessState + Send + ResourceLimiter,
+    {
+        self.try_instantiate(compiled_module, state)
+            .await
+            .map_err(|(error, _)| error)
+    }
+
+    /// Same as [`instantiate`](Self::instantiate), but gives the state back if the instantiation
+    /// fails.
+    pub async fn try_instantiate<T>(
+        &self,
+        compiled_module: &WasmtimeCompiledModule<T>,
+        mut state: T,
+    ) -> std::result::Result<WasmtimeInstance<T>, (anyhow::Error, T)>
     where
         T: ProcessState + Send + ResourceLimiter,
     {
         let max_fuel = state.config().get_max_fuel();
+        // Fuel consumed by previous instances of the same process (e.g. before hibernating).
+        let fuel_consumed = state.stats().snapshot().fuel_consumed;
+        let fuel_per_yield = state
+            .config()
+            .get_fuel_per_yield()
+            .unwrap_or(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+        state.reset_memory_size();
         let mut store = wasmtime::Store::new(&self.engine, state);
         // Set limits of the store
         store.limiter(|state| state);
+        // Fuel yields are only counted while the instance executes Wasm code
+        store.call_hook(|state, hook| {
+            state.stats().set_in_host_call(hook.entering_host());
+            Ok(())
+        });
         // Trap if out of fuel
         store.out_of_fuel_trap();
-        // Define maximum fuel
-        match max_fuel {
-            Some(max_fuel) => {
-                store.out_of_fuel_async_yield(max_fuel, UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
-            }
+        // Each time the process exhausts `fuel_per_yield` it yields back to the scheduler and
+        // continues. It only traps once the hard cap is reached.
+        let injection_count = match max_fuel {
+            Some(max_fuel) => max_fuel
+                .saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+                .saturating_sub(fuel_consumed)
+                .div_ceil(fuel_per_yield),
             // If no limit is specified use maximum
-            None => store.out_of_fuel_async_yield(u64::MAX, UNIT_OF_COMPUTE_IN_INSTRUCTIONS),
+            None => u64::MAX,
         };
+        store.out_of_fuel_async_yield(injection_count, fuel_per_yield);
         // Create instance
-        let instance = compiled_module
+        let instance = match compiled_module
             .instantiator()
             .instantiate_async(&mut store)
-            .await?;
+            .await
+        {
+            Ok(instance) => instance,
+            Err(error) => return Err((error, store.into_data())),
+        };
         // Mark state as initialized
         store.data_mut().initialize();
         Ok(WasmtimeInstance { store, instance })
@@ -124,34 +243,80 @@ where
 
 impl<T> WasmtimeInstance<T>
 where
-    T: Send,
+    T: ProcessState + Send,
 {
+    /// Overwrites the start of the exported linear memory with `data`, growing the memory if it's
+    /// smaller than `size` bytes.
+    pub fn restore_memory(&mut self, data: &[u8], size: u64) -> Result<()> {
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .context("Module doesn't export a linear memory")?;
+        let pages = size.max(data.len() as u64).div_ceil(WASM_PAGE_SIZE);
+        let current = memory.size(&self.store);
+        if pages > current {
+            memory
+                .grow(&mut self.store, pages - current)
+                .context("Linear memory can't grow to the size of the restored memory")?;
+        }
+        memory.write(&mut self.store, 0, data)?;
+        Ok(())
+    }
+
+    /// Drops the instance and returns the state of the process.
+    pub fn into_state(self) -> T {
+        let fuel_consumed = self.store.fuel_consumed().unwrap_or_default();
+        let state = self.store.into_data();
+        state.stats().finish_instance(fuel_consumed);
+        state
+    }
+
     pub async fn call(mut self, function: &str, params: Vec<wasmtime::Val>) -> ExecutionResult<T> {
         let entry = self.instance.get_func(&mut self.store, function);
 
         if entry.is_none() {
             return ExecutionResult {
-                state: self.store.into_data(),
+                state: self.into_state(),
                 result: ResultValue::SpawnError(format!("Function '{function}' not found")),
+                return_value: None,
+                backtrace: Vec::new(),
             };
         }
 
+        let entry = entry.unwrap();
+        let mut results = vec![wasmtime::Val::I32(0); entry.ty(&self.store).results().len()];
         let result = entry
-            .unwrap()
-            .call_async(&mut self.store, &params, &mut [])
+            .call_async(&mut self.store, &params, &mut results)
             .await;
+        let return_value = match results[..] {
+            [wasmtime::Val::I64(value)] => Some(value),
+            _ => None,
+        };
+        let backtrace = result
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<wasmtime::WasmBacktrace>())
+            .map(Frame::from_backtrace)
+            .unwrap_or_default();
 
         ExecutionResult {
-            state: self.store.into_data(),
+            state: self.into_state(),
+            return_value,
+            backtrace,
             result: match result {
                 Ok(()) => ResultValue::Ok,
-                Err(err) => {
-                    // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
-                    match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
-                        Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
-                        _ => ResultValue::Failed(err.to_string()),
+                Err(err) => match err.downcast::<Hibernate>() {
+                    Ok(hibernate) => ResultValue::Hibernated(hibernate),
+                    Err(err) => {
+                        // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
+                        // Other exit codes are not failures of the runtime, only of the process.
+                        match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                            Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
+                            Some(wasmtime_wasi::I32Exit(code)) => ResultValue::Exited(*code),
+                            _ => ResultValue::Failed(err.to_string()),
+                        }
                     }
-                }
+                },
             },
         }
     }
@@ -175,3 +340,22 @@ pub fn default_config() -> wasmtime::Config {
         .static_memory_forced(true);
     config
 }
+
+/// Same as [`default_config`], but instances are allocated from a pool of `instances` slots that
+/// are reserved up front, instead of on demand.
+///
+/// Slots keep the memory mappings of the last module instantiated in them, and new instances of a
+/// module prefer slots of the same module. Up to `warm_slots` unused slots stay warm this way, so
+/// that hot modules spawning a process per request don't pay for setting up a new instance each
+/// time. Spawning fails once all slots are taken and the linear memory of each process is limited
+/// to `max_memory` bytes, independent of the process configuration.
+pub fn pooling_config(instances: u32, max_memory: u64, warm_slots: u32) -> wasmtime::Config {
+    let mut pooling = wasmtime::PoolingAllocationConfig::default();
+    pooling
+        .instance_count(instances)
+        .instance_memory_pages(max_memory.div_ceil(WASM_PAGE_SIZE))
+        .max_unused_warm_slots(warm_slots);
+    let mut config = default_config();
+    config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(pooling));
+    config
+}
@@ -12,6 +12,7 @@ use crate::{
     config::ProcessConfig,
     mailbox::MessageMailbox,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
+    stats::ProcessStats,
     Signal,
 };
 
@@ -42,6 +43,9 @@ pub trait ProcessState: Sized {
     fn initialize(&mut self);
     /// Returns true if the instance was initialized
     fn is_initialized(&self) -> bool;
+    /// Called before a new Wasm instance is created for the state, e.g. when waking up from
+    /// hibernation. The memories of a previous instance don't count towards the limit anymore.
+    fn reset_memory_size(&mut self);
 
     /// Returns the WebAssembly runtime
     fn runtime(&self) -> &WasmtimeRuntime;
@@ -56,6 +60,8 @@ pub trait ProcessState: Sized {
     fn signal_mailbox(&self) -> &(SignalSender, SignalReceiver);
     // Returns message mailbox
     fn message_mailbox(&self) -> &MessageMailbox;
+    // Returns resource usage counters
+    fn stats(&self) -> &ProcessStats;
 
     // Config resources
     fn config_resources(&self) -> &ConfigResources<Self::Config>;
@@ -0,0 +1,270 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::mailbox::MessageMailbox;
+
+/// Resource usage counters of a process.
+///
+/// The counters are shared between the process state, the execution loop and the process handle
+/// registered in the environment, so that any process can inspect the usage of other processes.
+/// Cloning `ProcessStats` returns a handle to the same counters.
+#[derive(Clone, Default)]
+pub struct ProcessStats {
+    inner: Arc<InnerProcessStats>,
+}
+
+#[derive(Default)]
+struct InnerProcessStats {
+    // Fuel consumed by finished instances, read from their store.
+    fuel_consumed: AtomicU64,
+    // Fuel consumed by the running instance, counted in fuel yields.
+    instance_fuel_consumed: AtomicU64,
+    // Set while the running instance is inside of a host function.
+    in_host_call: AtomicBool,
+    memory_size: AtomicU64,
+    links: AtomicU64,
+    bytes_received: AtomicU64,
+    mailbox: MessageMailbox,
+}
+
+/// A point in time copy of [`ProcessStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessStatsSnapshot {
+    /// Fuel consumed. While an instance is running, it increases by the fuel per yield each time
+    /// the instance yields.
+    pub fuel_consumed: u64,
+    /// Current linear memory size in bytes.
+    pub memory_size: u64,
+    /// Number of messages waiting in the mailbox.
+    pub mailbox_len: u64,
+    /// Number of processes linked to this one.
+    pub links: u64,
+}
+
+impl ProcessStatsSnapshot {
+    /// Returns the snapshot encoded as 4 little endian `u64` values, in field declaration order.
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        bytes[0..8].copy_from_slice(&self.fuel_consumed.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.memory_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.mailbox_len.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.links.to_le_bytes());
+        bytes
+    }
+}
+
+impl ProcessStats {
+    /// Create new counters for a process owning the `mailbox`.
+    pub fn new(mailbox: MessageMailbox) -> Self {
+        Self {
+            inner: Arc::new(InnerProcessStats {
+                mailbox,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Adds the fuel consumed by an instance that finished, read from its store. It replaces the
+    /// fuel counted while the instance was running.
+    pub fn finish_instance(&self, fuel: u64) {
+        self.inner.fuel_consumed.fetch_add(fuel, Ordering::Relaxed);
+        self.inner
+            .instance_fuel_consumed
+            .store(0, Ordering::Relaxed);
+    }
+
+    /// Marks the running instance as being inside of a host function or executing Wasm code.
+    pub fn set_in_host_call(&self, in_host_call: bool) {
+        self.inner
+            .in_host_call
+            .store(in_host_call, Ordering::Relaxed);
+    }
+
+    pub fn set_memory_size(&self, size: usize) {
+        self.inner.memory_size.store(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_links(&self, links: usize) {
+        self.inner.links.store(links as u64, Ordering::Relaxed);
+    }
+
+    /// Counts the data of a message delivered to the mailbox.
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.inner
+            .bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Total size of the data messages delivered to the mailbox, in bytes.
+    pub fn bytes_received(&self) -> u64 {
+        self.inner.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages waiting in the mailbox and if it reached the watermark.
+    pub fn mailbox_pressure(&self) -> (usize, bool) {
+        self.inner.mailbox.pressure()
+    }
+
+    /// Returns the maximum size of data messages accepted by the mailbox.
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.inner.mailbox.max_message_size()
+    }
+
+    /// Returns the number of data messages dropped for exceeding the maximum message size.
+    pub fn oversized_messages_dropped(&self) -> u64 {
+        self.inner.mailbox.oversized_dropped()
+    }
+
+    pub fn snapshot(&self) -> ProcessStatsSnapshot {
+        ProcessStatsSnapshot {
+            fuel_consumed: self.inner.fuel_consumed.load(Ordering::Relaxed)
+                + self.inner.instance_fuel_consumed.load(Ordering::Relaxed),
+            memory_size: self.inner.memory_size.load(Ordering::Relaxed),
+            mailbox_len: self.inner.mailbox.len() as u64,
+            links: self.inner.links.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wraps the future executing the Wasm instance to keep track of consumed fuel.
+    ///
+    /// `fuel_per_yield` needs to match the amount of fuel the instance consumes between yields.
+    pub fn track_fuel<F: Future>(&self, fut: F, fuel_per_yield: u64) -> FuelTracker<F> {
+        FuelTracker {
+            fut: Box::pin(fut),
+            stats: self.clone(),
+            fuel_per_yield,
+        }
+    }
+}
+
+/// A future counting the fuel consumed by a Wasm instance while it's running.
+///
+/// Wasmtime doesn't give access to the store while the instance is running, but it's configured to
+/// yield back each time a fixed amount of fuel is consumed. A fuel yield wakes the task right away,
+/// before returning `Poll::Pending`. Host functions can do the same (e.g. tokio's cooperative
+/// yields), so wakes are only counted if the instance isn't inside of a host function. Once the
+/// instance finishes, the exact value is read from its store with [`ProcessStats::finish_instance`].
+pub struct FuelTracker<F: Future> {
+    fut: Pin<Box<F>>,
+    stats: ProcessStats,
+    fuel_per_yield: u64,
+}
+
+impl<F: Future> Future for FuelTracker<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let flag = Arc::new(WakeFlag {
+            woken: AtomicBool::new(false),
+            waker: cx.waker().clone(),
+        });
+        let waker = Waker::from(flag.clone());
+        let mut context = Context::from_waker(&waker);
+        let result = self.fut.as_mut().poll(&mut context);
+        if result.is_pending()
+            && flag.woken.load(Ordering::Relaxed)
+            && !self.stats.inner.in_host_call.load(Ordering::Relaxed)
+        {
+            self.stats
+                .inner
+                .instance_fuel_consumed
+                .fetch_add(self.fuel_per_yield, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+// Remembers if it was woken and forwards the wake to the original waker.
+struct WakeFlag {
+    woken: AtomicBool,
+    waker: Waker,
+}
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Relaxed);
+        self.waker.wake_by_ref();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::ProcessStats;
+    use crate::config::UNIT_OF_COMPUTE_IN_INSTRUCTIONS;
+
+    // Yields `n` times the same way Wasmtime does when it runs out of fuel.
+    struct FuelYield(u32);
+    impl Future for FuelYield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                Poll::Ready(())
+            } else {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fuel_yields_are_counted() {
+        let stats = ProcessStats::default();
+        stats
+            .track_fuel(FuelYield(3), UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+            .await;
+        assert_eq!(
+            stats.snapshot().fuel_consumed,
+            3 * UNIT_OF_COMPUTE_IN_INSTRUCTIONS
+        );
+    }
+
+    #[tokio::test]
+    async fn yields_inside_host_calls_are_not_counted() {
+        let stats = ProcessStats::default();
+        stats.set_in_host_call(true);
+        stats
+            .track_fuel(FuelYield(3), UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+            .await;
+        assert_eq!(stats.snapshot().fuel_consumed, 0);
+    }
+
+    #[tokio::test]
+    async fn finished_instances_report_exact_fuel() {
+        let stats = ProcessStats::default();
+        stats
+            .track_fuel(FuelYield(3), UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+            .await;
+        stats.finish_instance(3 * UNIT_OF_COMPUTE_IN_INSTRUCTIONS + 7);
+        assert_eq!(
+            stats.snapshot().fuel_consumed,
+            3 * UNIT_OF_COMPUTE_IN_INSTRUCTIONS + 7
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_is_not_counted() {
+        let stats = ProcessStats::default();
+        stats
+            .track_fuel(
+                tokio::time::sleep(std::time::Duration::from_millis(1)),
+                UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            )
+            .await;
+        assert_eq!(stats.snapshot().fuel_consumed, 0);
+    }
+}
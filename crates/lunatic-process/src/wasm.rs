@@ -1,14 +1,16 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use log::trace;
 use tokio::task::JoinHandle;
 use wasmtime::{ResourceLimiter, Val};
 
+use crate::checkpoint::Checkpoint;
+use crate::config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS};
 use crate::env::Environment;
 use crate::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 use crate::state::ProcessState;
-use crate::{Process, Signal, WasmProcess};
+use crate::{ExecutionResult, Process, ResultValue, Signal, WasmProcess};
 
 /// Spawns a new wasm process from a compiled module.
 ///
@@ -28,6 +30,66 @@ pub async fn spawn_wasm<S>(
     params: Vec<Val>,
     link: Option<(Option<i64>, Arc<dyn Process>)>,
 ) -> Result<(JoinHandle<Result<S>>, Arc<dyn Process>)>
+where
+    S: ProcessState + Send + Sync + ResourceLimiter + 'static,
+{
+    spawn(env, runtime, module, state, function, params, link, None).await
+}
+
+/// Spawns a new wasm process from a checkpoint of a process running the same module.
+///
+/// The messages of the checkpoint are put into the mailbox and the linear memory is restored
+/// before the process continues with the function of the checkpoint.
+pub async fn restore_wasm<S>(
+    env: Arc<dyn Environment>,
+    runtime: WasmtimeRuntime,
+    module: &WasmtimeCompiledModule<S>,
+    state: S,
+    checkpoint: Checkpoint,
+    link: Option<(Option<i64>, Arc<dyn Process>)>,
+) -> Result<(JoinHandle<Result<S>>, Arc<dyn Process>)>
+where
+    S: ProcessState + Send + Sync + ResourceLimiter + 'static,
+{
+    checkpoint.check_module(module.source())?;
+    let function = checkpoint.function().to_string();
+    let (memory, messages) = checkpoint.into_parts();
+    for message in messages {
+        state.message_mailbox().push(message);
+    }
+    spawn(
+        env,
+        runtime,
+        module,
+        state,
+        &function,
+        Vec::new(),
+        link,
+        Some(memory),
+    )
+    .await
+}
+
+fn spawn_error<S>(state: S, error: anyhow::Error) -> ExecutionResult<S> {
+    ExecutionResult {
+        state,
+        result: ResultValue::SpawnError(error.to_string()),
+        return_value: None,
+        backtrace: Vec::new(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn spawn<S>(
+    env: Arc<dyn Environment>,
+    runtime: WasmtimeRuntime,
+    module: &WasmtimeCompiledModule<S>,
+    state: S,
+    function: &str,
+    params: Vec<Val>,
+    link: Option<(Option<i64>, Arc<dyn Process>)>,
+    memory: Option<Vec<u8>>,
+) -> Result<(JoinHandle<Result<S>>, Arc<dyn Process>)>
 where
     S: ProcessState + Send + Sync + ResourceLimiter + 'static,
 {
@@ -35,12 +97,57 @@ where
     trace!("Spawning process: {}", id);
     let signal_mailbox = state.signal_mailbox().clone();
     let message_mailbox = state.message_mailbox().clone();
+    let stats = state.stats().clone();
+    let fuel_per_yield = state
+        .config()
+        .get_fuel_per_yield()
+        .unwrap_or(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+    let max_lifetime = state
+        .config()
+        .get_max_lifetime_ms()
+        .map(Duration::from_millis);
 
-    let instance = runtime.instantiate(module, state).await?;
+    let mut instance = runtime.instantiate(module, state).await?;
+    if let Some(memory) = memory {
+        instance.restore_memory(&memory, memory.len() as u64)?;
+    }
+    let module = module.clone();
     let function = function.to_string();
-    let fut = async move { instance.call(&function, params).await };
-    let child_process = crate::new(fut, id, env.clone(), signal_mailbox.1, message_mailbox);
-    let child_process_handle = Arc::new(WasmProcess::new(id, signal_mailbox.0.clone()));
+    let fut = stats.track_fuel(
+        async move {
+            let mut result = instance.call(&function, params).await;
+            // A hibernated process is re-instantiated once the next message arrives. The linear
+            // memory of the new instance is restored before it calls the requested function.
+            loop {
+                let (state, hibernate) = match result.into_hibernated() {
+                    Ok(hibernated) => hibernated,
+                    Err(result) => break result,
+                };
+                state.message_mailbox().wait().await;
+                let instance = runtime.try_instantiate(&module, state).await;
+                result = match instance {
+                    Ok(mut instance) => {
+                        match instance.restore_memory(&hibernate.memory, hibernate.memory_size) {
+                            Ok(()) => instance.call(&hibernate.function, Vec::new()).await,
+                            Err(error) => spawn_error(instance.into_state(), error),
+                        }
+                    }
+                    Err((error, state)) => spawn_error(state, error),
+                };
+            }
+        },
+        fuel_per_yield,
+    );
+    let child_process = crate::new(
+        fut,
+        id,
+        env.clone(),
+        signal_mailbox.1,
+        message_mailbox,
+        stats.clone(),
+    );
+    let child_process_handle =
+        Arc::new(WasmProcess::with_stats(id, signal_mailbox.0.clone(), stats));
 
     env.add_process(id, child_process_handle.clone());
 
@@ -81,6 +188,21 @@ where
 
     // Spawn a background process
     trace!("Process size: {}", std::mem::size_of_val(&child_process));
-    let join = tokio::task::spawn(child_process);
+    // If the process has a maximum lifetime, arm a timer that kills it once the deadline passes.
+    // The timer is cancelled as soon as the process finishes on its own.
+    let deadline = max_lifetime.map(|max_lifetime| {
+        let process = child_process_handle.clone();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(max_lifetime).await;
+            process.send(Signal::Kill(None));
+        })
+    });
+    let join = tokio::task::spawn(async move {
+        let result = child_process.await;
+        if let Some(deadline) = deadline {
+            deadline.abort();
+        }
+        result
+    });
     Ok((join, child_process_handle))
 }
@@ -0,0 +1,810 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hash_map_id::HashMapId;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_error_api::ErrorCtx;
+use lunatic_networking_api::{
+    check_network_access, load_cert_chain, load_private_key, socket_address, NetworkingCtx,
+};
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+use rustls::{OwnedTrustAnchor, RootCertStore};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use wasmtime::{Caller, Linker};
+
+pub struct QuicConnection {
+    connection: Connection,
+    // Datagram that didn't fit into the guest buffer
+    datagram: Mutex<Option<Bytes>>,
+}
+
+impl QuicConnection {
+    fn new(connection: Connection) -> Self {
+        QuicConnection {
+            connection,
+            datagram: Mutex::new(None),
+        }
+    }
+}
+
+pub struct QuicSendStream {
+    stream: Mutex<SendStream>,
+}
+
+pub struct QuicRecvStream {
+    stream: Mutex<RecvStream>,
+}
+
+pub type QuicEndpointResources = HashMapId<Endpoint>;
+pub type QuicConnectionResources = HashMapId<Arc<QuicConnection>>;
+pub type QuicSendStreamResources = HashMapId<Arc<QuicSendStream>>;
+pub type QuicRecvStreamResources = HashMapId<Arc<QuicRecvStream>>;
+
+pub trait QuicCtx {
+    fn quic_endpoint_resources(&self) -> &QuicEndpointResources;
+    fn quic_endpoint_resources_mut(&mut self) -> &mut QuicEndpointResources;
+    fn quic_connection_resources(&self) -> &QuicConnectionResources;
+    fn quic_connection_resources_mut(&mut self) -> &mut QuicConnectionResources;
+    fn quic_send_stream_resources(&self) -> &QuicSendStreamResources;
+    fn quic_send_stream_resources_mut(&mut self) -> &mut QuicSendStreamResources;
+    fn quic_recv_stream_resources(&self) -> &QuicRecvStreamResources;
+    fn quic_recv_stream_resources_mut(&mut self) -> &mut QuicRecvStreamResources;
+}
+
+// Register the QUIC APIs to the linker
+pub fn register<T: QuicCtx + NetworkingCtx + ErrorCtx + Send + 'static>(
+    linker: &mut Linker<T>,
+) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap("lunatic::quic", "bind", bind)?;
+    linker.func_wrap("lunatic::quic", "drop_endpoint", drop_endpoint)?;
+    linker.func_wrap10_async("lunatic::quic", "connect", connect)?;
+    linker.func_wrap2_async("lunatic::quic", "accept", accept)?;
+    linker.func_wrap("lunatic::quic", "close", close)?;
+    linker.func_wrap("lunatic::quic", "drop_connection", drop_connection)?;
+    linker.func_wrap3_async("lunatic::quic", "open_bi", open_bi)?;
+    linker.func_wrap2_async("lunatic::quic", "open_uni", open_uni)?;
+    linker.func_wrap3_async("lunatic::quic", "accept_bi", accept_bi)?;
+    linker.func_wrap2_async("lunatic::quic", "accept_uni", accept_uni)?;
+    linker.func_wrap4_async("lunatic::quic", "write", write)?;
+    linker.func_wrap2_async("lunatic::quic", "finish", finish)?;
+    linker.func_wrap4_async("lunatic::quic", "read", read)?;
+    linker.func_wrap("lunatic::quic", "drop_send_stream", drop_send_stream)?;
+    linker.func_wrap("lunatic::quic", "drop_recv_stream", drop_recv_stream)?;
+    linker.func_wrap("lunatic::quic", "send_datagram", send_datagram)?;
+    linker.func_wrap4_async("lunatic::quic", "read_datagram", read_datagram)?;
+    Ok(())
+}
+
+// Outgoing connections trust the default root certificates.
+fn client_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    ClientConfig::new(Arc::new(crypto))
+}
+
+// Creates a new QUIC endpoint bound to the address.
+//
+// If **certs_array_len** is 0 the endpoint can only be used to connect to other endpoints.
+// Otherwise it also accepts incoming connections, using the PEM encoded certificate chain and
+// private key.
+//
+// Returns:
+// * 0 on success - The ID of the endpoint is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If **addr_type** is neither 4 or 6.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn bind<T: QuicCtx + NetworkingCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    addr_type: u32,
+    addr_u8_ptr: u32,
+    port: u32,
+    flow_info: u32,
+    scope_id: u32,
+    certs_array_ptr: u32,
+    certs_array_len: u32,
+    keys_array_ptr: u32,
+    keys_array_len: u32,
+    id_u64_ptr: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let socket_addr = socket_address(
+        &caller,
+        &memory,
+        addr_type,
+        addr_u8_ptr,
+        port,
+        flow_info,
+        scope_id,
+    )?;
+    let certs = memory
+        .data(&caller)
+        .get(certs_array_ptr as usize..(certs_array_ptr + certs_array_len) as usize)
+        .or_trap("lunatic::quic::bind")?;
+    let keys = memory
+        .data(&caller)
+        .get(keys_array_ptr as usize..(keys_array_ptr + keys_array_len) as usize)
+        .or_trap("lunatic::quic::bind")?;
+
+    let endpoint = if certs.is_empty() {
+        Endpoint::client(socket_addr).map_err(anyhow::Error::from)
+    } else {
+        load_cert_chain(certs)
+            .and_then(|certs| Ok((certs, load_private_key(keys)?)))
+            .map_err(anyhow::Error::from)
+            .and_then(|(certs, key)| Ok(ServerConfig::with_single_cert(certs, key)?))
+            .and_then(|config| Ok(Endpoint::server(config, socket_addr)?))
+    }
+    .map(|mut endpoint| {
+        endpoint.set_default_client_config(client_config());
+        endpoint
+    });
+
+    let (endpoint_or_error_id, result) = match endpoint {
+        Ok(endpoint) => (
+            caller
+                .data_mut()
+                .quic_endpoint_resources_mut()
+                .add(endpoint),
+            0,
+        ),
+        Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+    };
+    memory
+        .write(
+            &mut caller,
+            id_u64_ptr as usize,
+            &endpoint_or_error_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::quic::bind")?;
+    Ok(result)
+}
+
+// Drops the endpoint resource. Connections of the endpoint stay open.
+//
+// Traps:
+// * If the endpoint ID doesn't exist.
+fn drop_endpoint<T: QuicCtx>(mut caller: Caller<T>, endpoint_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .quic_endpoint_resources_mut()
+        .remove(endpoint_id)
+        .or_trap("lunatic::quic::drop_endpoint")?;
+    Ok(())
+}
+
+// Connects to a QUIC server. **server_name_str_ptr** points to the name used to verify the
+// certificate of the server.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0 on success - The ID of the connection is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the endpoint ID doesn't exist.
+// * If **addr_type** is neither 4 or 6.
+// * If the server name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn connect<T: QuicCtx + NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    endpoint_id: u64,
+    addr_type: u32,
+    addr_u8_ptr: u32,
+    port: u32,
+    flow_info: u32,
+    scope_id: u32,
+    server_name_str_ptr: u32,
+    server_name_str_len: u32,
+    timeout_duration: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let socket_addr = socket_address(
+            &caller,
+            &memory,
+            addr_type,
+            addr_u8_ptr,
+            port,
+            flow_info,
+            scope_id,
+        )?;
+        let server_name = memory
+            .data(&caller)
+            .get(server_name_str_ptr as usize..(server_name_str_ptr + server_name_str_len) as usize)
+            .or_trap("lunatic::quic::connect")?;
+        let server_name = std::str::from_utf8(server_name)
+            .or_trap("lunatic::quic::connect")?
+            .to_string();
+        let endpoint = caller
+            .data()
+            .quic_endpoint_resources()
+            .get(endpoint_id)
+            .or_trap("lunatic::quic::connect")?
+            .clone();
+        let ip = socket_addr.ip().to_string();
+        let port = Some(socket_addr.port());
+        if !check_network_access(&mut caller, &memory, &ip, port, id_u64_ptr)? {
+            return Ok(1);
+        }
+
+        let connect = async {
+            let connection = endpoint.connect(socket_addr, &server_name)?.await?;
+            Ok::<_, anyhow::Error>(connection)
+        };
+        let result = match timeout_duration {
+            // Without timeout
+            u64::MAX => connect.await,
+            // With timeout
+            t => match timeout(Duration::from_millis(t), connect).await {
+                Ok(result) => result,
+                // Call timed out
+                Err(_) => return Ok(9027),
+            },
+        };
+
+        let (connection_or_error_id, result) = match result {
+            Ok(connection) => (
+                caller
+                    .data_mut()
+                    .quic_connection_resources_mut()
+                    .add(Arc::new(QuicConnection::new(connection))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &connection_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::quic::connect")?;
+        Ok(result)
+    })
+}
+
+// Waits for the next incoming connection to the endpoint.
+//
+// Returns:
+// * 0 on success - The ID of the connection is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the endpoint ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn accept<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    endpoint_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let endpoint = caller
+            .data()
+            .quic_endpoint_resources()
+            .get(endpoint_id)
+            .or_trap("lunatic::quic::accept")?
+            .clone();
+
+        let result = match endpoint.accept().await {
+            Some(connecting) => connecting.await.map_err(anyhow::Error::from),
+            None => Err(anyhow!("The endpoint is closed")),
+        };
+        let (connection_or_error_id, result) = match result {
+            Ok(connection) => (
+                caller
+                    .data_mut()
+                    .quic_connection_resources_mut()
+                    .add(Arc::new(QuicConnection::new(connection))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &connection_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::quic::accept")?;
+        Ok(result)
+    })
+}
+
+// Closes the connection immediately with the error code and reason. Streams of the connection
+// fail afterwards.
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If the code is bigger than 2^62 - 1.
+// * If any memory outside the guest heap space is referenced.
+fn close<T: QuicCtx>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    code: u64,
+    reason_ptr: u32,
+    reason_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let reason = memory
+        .data(&caller)
+        .get(reason_ptr as usize..(reason_ptr + reason_len) as usize)
+        .or_trap("lunatic::quic::close")?;
+    let code = VarInt::from_u64(code).or_trap("lunatic::quic::close")?;
+    caller
+        .data()
+        .quic_connection_resources()
+        .get(connection_id)
+        .or_trap("lunatic::quic::close")?
+        .connection
+        .close(code, reason);
+    Ok(())
+}
+
+// Drops the connection resource. The connection is closed once all streams are dropped.
+//
+// Traps:
+// * If the connection ID doesn't exist.
+fn drop_connection<T: QuicCtx>(mut caller: Caller<T>, connection_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .quic_connection_resources_mut()
+        .remove(connection_id)
+        .or_trap("lunatic::quic::drop_connection")?;
+    Ok(())
+}
+
+fn connection<T: QuicCtx>(
+    caller: &Caller<T>,
+    connection_id: u64,
+    trap: &str,
+) -> Result<Arc<QuicConnection>> {
+    Ok(caller
+        .data()
+        .quic_connection_resources()
+        .get(connection_id)
+        .or_trap(trap)?
+        .clone())
+}
+
+// Adds the bidirectional stream to the resources and writes the IDs, or the error ID to
+// **send_id_u64_ptr**.
+fn write_bi_stream<T: QuicCtx + ErrorCtx>(
+    caller: &mut Caller<T>,
+    stream: Result<(SendStream, RecvStream), quinn::ConnectionError>,
+    send_id_u64_ptr: u32,
+    recv_id_u64_ptr: u32,
+    trap: &str,
+) -> Result<u32> {
+    let memory = get_memory(caller)?;
+    match stream {
+        Ok((send, recv)) => {
+            let send_id = caller
+                .data_mut()
+                .quic_send_stream_resources_mut()
+                .add(Arc::new(QuicSendStream {
+                    stream: Mutex::new(send),
+                }));
+            let recv_id = caller
+                .data_mut()
+                .quic_recv_stream_resources_mut()
+                .add(Arc::new(QuicRecvStream {
+                    stream: Mutex::new(recv),
+                }));
+            memory
+                .write(
+                    &mut *caller,
+                    send_id_u64_ptr as usize,
+                    &send_id.to_le_bytes(),
+                )
+                .or_trap(trap)?;
+            memory
+                .write(caller, recv_id_u64_ptr as usize, &recv_id.to_le_bytes())
+                .or_trap(trap)?;
+            Ok(0)
+        }
+        Err(error) => {
+            let error_id = caller.data_mut().error_resources_mut().add(error.into());
+            memory
+                .write(caller, send_id_u64_ptr as usize, &error_id.to_le_bytes())
+                .or_trap(trap)?;
+            Ok(1)
+        }
+    }
+}
+
+// Opens a new bidirectional stream. The peer only learns about the stream once data is written
+// to it.
+//
+// Returns:
+// * 0 on success - The ID of the send stream is written to **send_id_u64_ptr** and the ID of
+//                  the receive stream to **recv_id_u64_ptr**
+// * 1 on error   - The error ID is written to **send_id_u64_ptr**
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn open_bi<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    send_id_u64_ptr: u32,
+    recv_id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connection = connection(&caller, connection_id, "lunatic::quic::open_bi")?;
+        let stream = connection.connection.open_bi().await;
+        write_bi_stream(
+            &mut caller,
+            stream,
+            send_id_u64_ptr,
+            recv_id_u64_ptr,
+            "lunatic::quic::open_bi",
+        )
+    })
+}
+
+// Waits for the peer to open a bidirectional stream.
+//
+// Returns:
+// * 0 on success - The ID of the send stream is written to **send_id_u64_ptr** and the ID of
+//                  the receive stream to **recv_id_u64_ptr**
+// * 1 on error   - The error ID is written to **send_id_u64_ptr**
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn accept_bi<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    send_id_u64_ptr: u32,
+    recv_id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connection = connection(&caller, connection_id, "lunatic::quic::accept_bi")?;
+        let stream = connection.connection.accept_bi().await;
+        write_bi_stream(
+            &mut caller,
+            stream,
+            send_id_u64_ptr,
+            recv_id_u64_ptr,
+            "lunatic::quic::accept_bi",
+        )
+    })
+}
+
+// Opens a new unidirectional stream.
+//
+// Returns:
+// * 0 on success - The ID of the send stream is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn open_uni<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connection = connection(&caller, connection_id, "lunatic::quic::open_uni")?;
+        let (stream_or_error_id, result) = match connection.connection.open_uni().await {
+            Ok(stream) => (
+                caller
+                    .data_mut()
+                    .quic_send_stream_resources_mut()
+                    .add(Arc::new(QuicSendStream {
+                        stream: Mutex::new(stream),
+                    })),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::quic::open_uni")?;
+        Ok(result)
+    })
+}
+
+// Waits for the peer to open a unidirectional stream.
+//
+// Returns:
+// * 0 on success - The ID of the receive stream is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn accept_uni<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connection = connection(&caller, connection_id, "lunatic::quic::accept_uni")?;
+        let (stream_or_error_id, result) = match connection.connection.accept_uni().await {
+            Ok(stream) => (
+                caller
+                    .data_mut()
+                    .quic_recv_stream_resources_mut()
+                    .add(Arc::new(QuicRecvStream {
+                        stream: Mutex::new(stream),
+                    })),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &stream_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::quic::accept_uni")?;
+        Ok(result)
+    })
+}
+
+// Writes the whole buffer to the stream.
+//
+// Returns:
+// * 0 on success - The number of bytes written is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+//
+// Traps:
+// * If the send stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn write<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let buffer = memory
+            .data(&caller)
+            .get(buffer_ptr as usize..(buffer_ptr + buffer_len) as usize)
+            .or_trap("lunatic::quic::write")?
+            .to_vec();
+        let stream = caller
+            .data()
+            .quic_send_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::quic::write")?
+            .clone();
+
+        let result = stream.stream.lock().await.write_all(&buffer).await;
+        let (opaque, return_) = match result {
+            Ok(()) => (buffer.len() as u64, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::quic::write")?;
+        Ok(return_)
+    })
+}
+
+// Finishes the stream, so that the peer reads the end of the stream after all written data.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the send stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn finish<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .quic_send_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::quic::finish")?
+            .clone();
+
+        let result = stream.stream.lock().await.finish().await;
+        match result {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error.into());
+                let memory = get_memory(&mut caller)?;
+                memory
+                    .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::quic::finish")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Reads data from the stream into the buffer. Once the peer finished the stream, 0 bytes are
+// returned.
+//
+// Returns:
+// * 0 on success - The number of bytes read is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+//
+// Traps:
+// * If the receive stream ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn read<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_id: u64,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = caller
+            .data()
+            .quic_recv_stream_resources()
+            .get(stream_id)
+            .or_trap("lunatic::quic::read")?
+            .clone();
+
+        let mut buffer = vec![0; buffer_len as usize];
+        let result = stream.stream.lock().await.read(&mut buffer).await;
+        let memory = get_memory(&mut caller)?;
+        let (opaque, return_) = match result {
+            Ok(read) => {
+                let read = read.unwrap_or(0);
+                memory
+                    .write(&mut caller, buffer_ptr as usize, &buffer[..read])
+                    .or_trap("lunatic::quic::read")?;
+                (read as u64, 0)
+            }
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::quic::read")?;
+        Ok(return_)
+    })
+}
+
+// Drops the send stream resource. Data that was already written is still delivered.
+//
+// Traps:
+// * If the send stream ID doesn't exist.
+fn drop_send_stream<T: QuicCtx>(mut caller: Caller<T>, stream_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .quic_send_stream_resources_mut()
+        .remove(stream_id)
+        .or_trap("lunatic::quic::drop_send_stream")?;
+    Ok(())
+}
+
+// Drops the receive stream resource.
+//
+// Traps:
+// * If the receive stream ID doesn't exist.
+fn drop_recv_stream<T: QuicCtx>(mut caller: Caller<T>, stream_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .quic_recv_stream_resources_mut()
+        .remove(stream_id)
+        .or_trap("lunatic::quic::drop_recv_stream")?;
+    Ok(())
+}
+
+// Sends an unreliable datagram. Datagrams can be lost or arrive out of order and need to fit into
+// a single packet.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn send_datagram<T: QuicCtx + ErrorCtx>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    data_ptr: u32,
+    data_len: u32,
+    error_id_ptr: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let data = memory
+        .data(&caller)
+        .get(data_ptr as usize..(data_ptr + data_len) as usize)
+        .or_trap("lunatic::quic::send_datagram")?;
+    let data = Bytes::copy_from_slice(data);
+    let connection = connection(&caller, connection_id, "lunatic::quic::send_datagram")?;
+    match connection.connection.send_datagram(data) {
+        Ok(()) => Ok(0),
+        Err(error) => {
+            let error_id = caller.data_mut().error_resources_mut().add(error.into());
+            memory
+                .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+                .or_trap("lunatic::quic::send_datagram")?;
+            Ok(1)
+        }
+    }
+}
+
+// Waits for the next datagram and writes it into the buffer.
+//
+// Returns:
+// * 0 on success - The size of the datagram is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+// * 2 if the buffer is too small - The size of the datagram is written to **opaque_ptr** and the
+//                                  datagram is kept, so that it can be read with a bigger buffer.
+//
+// Traps:
+// * If the connection ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn read_datagram<T: QuicCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    connection_id: u64,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let connection = connection(&caller, connection_id, "lunatic::quic::read_datagram")?;
+        let mut pending = connection.datagram.lock().await;
+        let datagram = match pending.take() {
+            Some(datagram) => Ok(datagram),
+            None => connection.connection.read_datagram().await,
+        };
+
+        let memory = get_memory(&mut caller)?;
+        let (opaque, return_) = match datagram {
+            Ok(datagram) if datagram.len() > buffer_len as usize => {
+                let len = datagram.len() as u64;
+                *pending = Some(datagram);
+                (len, 2)
+            }
+            Ok(datagram) => {
+                memory
+                    .write(&mut caller, buffer_ptr as usize, &datagram)
+                    .or_trap("lunatic::quic::read_datagram")?;
+                (datagram.len() as u64, 0)
+            }
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::quic::read_datagram")?;
+        Ok(return_)
+    })
+}
@@ -1,15 +1,26 @@
 use std::future::Future;
 
 use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
-use lunatic_process::state::ProcessState;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_distributed::{control, DistributedCtx};
+use lunatic_process::{
+    env::Environment,
+    events::{self, Event},
+};
 use lunatic_process_api::ProcessCtx;
 use wasmtime::{Caller, Linker};
 
+/// Names starting with this prefix are registered in the whole cluster through the control
+/// server. Without a distributed node they behave like any other name.
+pub const GLOBAL_PREFIX: &str = "global::";
+
 // Register the registry APIs to the linker
-pub fn register<T: ProcessState + ProcessCtx<T> + Send + Sync + 'static>(
-    linker: &mut Linker<T>,
-) -> Result<()> {
+pub fn register<T, E>(linker: &mut Linker<T>) -> Result<()>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + Sync + 'static,
+    E: Environment + 'static,
+{
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap4_async("lunatic::registry", "put", put)?;
     linker.func_wrap4_async("lunatic::registry", "get", get)?;
     linker.func_wrap2_async("lunatic::registry", "remove", remove)?;
@@ -42,12 +53,44 @@ pub fn register<T: ProcessState + ProcessCtx<T> + Send + Sync + 'static>(
     Ok(())
 }
 
+// Returns the control client if `name` is a global one and the node is part of a cluster.
+fn global_registry<T: DistributedCtx<E>, E: Environment>(
+    state: &T,
+    name: &str,
+) -> Option<control::Client> {
+    if name.starts_with(GLOBAL_PREFIX) {
+        state
+            .distributed()
+            .ok()
+            .map(|distributed| distributed.control.clone())
+    } else {
+        None
+    }
+}
+
+/// Looks up the node and process ID registered under `name`.
+///
+/// Global names are looked up in the whole cluster.
+pub async fn lookup<T, E>(state: &T, name: &str) -> Result<Option<(u64, u64)>>
+where
+    T: DistributedCtx<E> + Sync,
+    E: Environment,
+{
+    match global_registry(state, name) {
+        Some(control) => control.registry_get(name).await,
+        None => Ok(state.registry().read().await.get(name).copied()),
+    }
+}
+
 // Registers process with ID under `name`.
 //
+// Names starting with `global::` are visible on all nodes of the cluster.
+//
 // Traps:
 // * If the process ID doesn't exist.
+// * If `name` is global and the control server doesn't support global names.
 // * If any memory outside the guest heap space is referenced.
-fn put<T: ProcessState + ProcessCtx<T> + Send + Sync>(
+fn put<T: DistributedCtx<E> + ProcessCtx<T> + Send + Sync, E: Environment>(
     mut caller: Caller<T>,
     name_str_ptr: u32,
     name_str_len: u32,
@@ -62,11 +105,24 @@ fn put<T: ProcessState + ProcessCtx<T> + Send + Sync>(
             .or_trap("lunatic::registry::put")?;
         let name = std::str::from_utf8(name).or_trap("lunatic::registry::put")?;
 
-        state
-            .registry()
-            .write()
-            .await
-            .insert(name.to_owned(), (node_id, process_id));
+        if let Some(control) = global_registry(state, name) {
+            let name = name.to_owned();
+            control.registry_put(&name, node_id, process_id).await?;
+        } else {
+            state
+                .registry()
+                .write()
+                .await
+                .insert(name.to_owned(), (node_id, process_id));
+        }
+        events::record(
+            Some(state.environment_id()),
+            Event::RegistryPut {
+                name: name.to_owned(),
+                node_id,
+                process_id,
+            },
+        );
 
         #[cfg(feature = "metrics")]
         metrics::increment_counter!("lunatic.registry.write");
@@ -81,8 +137,9 @@ fn put<T: ProcessState + ProcessCtx<T> + Send + Sync>(
 // Looks up process under `name` and returns 0 if it was found or 1 if not found.
 //
 // Traps:
+// * If `name` is global and the control server doesn't support global names.
 // * If any memory outside the guest heap space is referenced.
-fn get<T: ProcessState + ProcessCtx<T> + Send + Sync>(
+fn get<T: DistributedCtx<E> + ProcessCtx<T> + Send + Sync, E: Environment>(
     mut caller: Caller<T>,
     name_str_ptr: u32,
     name_str_len: u32,
@@ -100,8 +157,9 @@ fn get<T: ProcessState + ProcessCtx<T> + Send + Sync>(
         #[cfg(feature = "metrics")]
         metrics::increment_counter!("lunatic.registry.read");
 
-        let (node_id, process_id) = if let Some(process) = state.registry().read().await.get(name) {
-            *process
+        let process = lookup(state, name).await?;
+        let (node_id, process_id) = if let Some(process) = process {
+            process
         } else {
             return Ok(1);
         };
@@ -124,8 +182,9 @@ fn get<T: ProcessState + ProcessCtx<T> + Send + Sync>(
 // Removes process under `name` if it exists.
 //
 // Traps:
+// * If `name` is global and the control server doesn't support global names.
 // * If any memory outside the guest heap space is referenced.
-fn remove<T: ProcessState + ProcessCtx<T> + Send + Sync>(
+fn remove<T: DistributedCtx<E> + ProcessCtx<T> + Send + Sync, E: Environment>(
     mut caller: Caller<T>,
     name_str_ptr: u32,
     name_str_len: u32,
@@ -138,7 +197,18 @@ fn remove<T: ProcessState + ProcessCtx<T> + Send + Sync>(
             .or_trap("lunatic::registry::get")?;
         let name = std::str::from_utf8(name).or_trap("lunatic::registry::get")?;
 
-        state.registry().write().await.remove(name);
+        if let Some(control) = global_registry(state, name) {
+            let name = name.to_owned();
+            control.registry_remove(&name).await?;
+        } else {
+            state.registry().write().await.remove(name);
+        }
+        events::record(
+            Some(state.environment_id()),
+            Event::RegistryRemove {
+                name: name.to_owned(),
+            },
+        );
 
         #[cfg(feature = "metrics")]
         metrics::increment_counter!("lunatic.registry.deletion");
@@ -16,9 +16,44 @@ pub mod sqlite_guest_bindings {
         /// to the guest so that values can be bound to the statement at a later point
         pub fn query_prepare(connection_id: u64, query_str: *const u8, query_str_len: u32) -> u64;
 
+        /// Same as `query_prepare`, but reuses a statement that was prepared from the same query
+        /// on the connection and finalized since, instead of parsing the query again
+        ///
+        /// Reused statements are reset, but keep the values bound to them
+        pub fn query_prepare_cached(
+            connection_id: u64,
+            query_str: *const u8,
+            query_str_len: u32,
+        ) -> u64;
+
         /// Executes the passed query and returns the SQLite response code
         pub fn execute(connection_id: u64, exec_str: *const u8, exec_str_len: u32) -> u32;
 
+        /// Starts a transaction and returns the SQLite response code
+        ///
+        /// Transactions that are still open when the process dies are rolled back
+        pub fn begin(connection_id: u64) -> u32;
+
+        /// Commits the open transaction and returns the SQLite response code
+        pub fn commit(connection_id: u64) -> u32;
+
+        /// Rolls back the open transaction and returns the SQLite response code
+        pub fn rollback(connection_id: u64) -> u32;
+
+        /// Creates a savepoint with the given name and returns the SQLite response code
+        pub fn savepoint(connection_id: u64, name_str: *const u8, name_str_len: u32) -> u32;
+
+        /// Releases the savepoint with the given name and returns the SQLite response code
+        pub fn release_savepoint(connection_id: u64, name_str: *const u8, name_str_len: u32)
+            -> u32;
+
+        /// Rolls back to the savepoint with the given name and returns the SQLite response code
+        pub fn rollback_to_savepoint(
+            connection_id: u64,
+            name_str: *const u8,
+            name_str_len: u32,
+        ) -> u32;
+
         /// Binds one or more values to the statement identified by `statement_id`.
         /// The function expects to receive a `BindList` encoded via `bincode` as demonstrated by this example:
         ///
@@ -45,6 +80,9 @@ pub mod sqlite_guest_bindings {
         /// Anything other than a `BindList` will be rejected and a Trap will be returned
         pub fn bind_value(statement_id: u64, bind_data_ptr: u32, bind_data_len: u32);
 
+        /// Resets the statement and binds the `BindList` to it, in the same format as `bind_value`
+        pub fn rebind(statement_id: u64, bind_data_ptr: u32, bind_data_len: u32);
+
         /// returns count of changes/rows that the last call to SQLite triggered
         pub fn sqlite3_changes(connection_id: u64) -> u32;
 
@@ -113,5 +151,42 @@ pub mod sqlite_guest_bindings {
         /// looks up the value of the last error, encodes a `Vec<String>` via bincode
         /// and writes it into a guest allocated Vec<u8>
         pub fn column_names(statement_id: u64, opaque_ptr: *mut u32) -> u32;
+
+        /// steps through the next `max_rows` rows of the statement, encodes them as a
+        /// `Vec<SqliteRow>` via bincode and writes it into a guest allocated Vec<u8>
+        ///
+        /// fewer rows than requested are returned once there are no rows left
+        pub fn query_stream_next(statement_id: u64, max_rows: u32, opaque_ptr: *mut u32) -> u32;
+
+        /// opens the blob in `column` of the row with `row_id` in `table` for incremental I/O
+        /// and writes the id of the blob to `blob_id`
+        ///
+        /// returns the SQLite response code
+        pub fn blob_open(
+            connection_id: u64,
+            table: *const u8,
+            table_len: u32,
+            column: *const u8,
+            column_len: u32,
+            row_id: i64,
+            writable: u32,
+            blob_id: *mut u64,
+        ) -> u32;
+
+        /// reads `buf_len` bytes of the blob starting at `offset` into `buf`
+        ///
+        /// returns the SQLite response code
+        pub fn blob_read(blob_id: u64, offset: u32, buf: *mut u8, buf_len: u32) -> u32;
+
+        /// writes `data_len` bytes into the blob starting at `offset`
+        ///
+        /// returns the SQLite response code
+        pub fn blob_write(blob_id: u64, offset: u32, data: *const u8, data_len: u32) -> u32;
+
+        /// returns the size of the blob in bytes
+        pub fn blob_bytes(blob_id: u64) -> u32;
+
+        /// closes the blob
+        pub fn blob_close(blob_id: u64);
     }
 }
@@ -1,12 +1,13 @@
 use anyhow::Result;
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, write_to_guest_vec, IntoTrap};
+use lunatic_common_api::{get_memory, write_to_guest_vec, InstrumentedLinker, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::state::ProcessState;
 use lunatic_process_api::ProcessConfigCtx;
 use sqlite::{Connection, State, Statement};
 use std::{
     collections::HashMap,
+    ffi::{c_char, c_int, c_void, CStr, CString},
     future::Future,
     path::Path,
     sync::{Arc, Mutex},
@@ -21,9 +22,84 @@ pub const SQLITE_DONE: u32 = 101;
 pub type SQLiteConnections = HashMapId<Arc<Mutex<Connection>>>;
 pub type SQLiteResults = HashMapId<Vec<u8>>;
 // sometimes we need to lookup the connection_id for the statement
-pub type SQLiteStatements = HashMapId<(u64, Statement)>;
+pub type SQLiteStatements = HashMapId<(u64, Arc<Mutex<Statement>>)>;
 // maps connection_id to name of allocation function
 pub type SQLiteGuestAllocators = HashMap<u64, String>;
+
+/// Handle for incremental I/O on a blob, opened with `blob_open`.
+pub struct SQLiteBlob {
+    raw: *mut sqlite3_sys::sqlite3_blob,
+    // Keeps the connection open until the blob is closed
+    conn: Arc<Mutex<Connection>>,
+}
+
+// Safety: the blob is only used while holding the lock of its connection
+unsafe impl Send for SQLiteBlob {}
+
+impl Drop for SQLiteBlob {
+    fn drop(&mut self) {
+        unsafe { sqlite3_sys::sqlite3_blob_close(self.raw) };
+    }
+}
+
+pub type SQLiteBlobs = HashMapId<Arc<Mutex<SQLiteBlob>>>;
+
+// Upper limit of finalized statements kept around by a process
+const MAX_CACHED_STATEMENTS: usize = 128;
+
+/// Prepared statements that were finalized by the guest, but are kept around so that preparing the
+/// same SQL on the connection again skips parsing it.
+#[derive(Default)]
+pub struct SQLiteStatementCache {
+    // idle statements by connection and SQL
+    idle: HashMap<(u64, String), Vec<Statement>>,
+    idle_count: usize,
+    // connection and SQL of the statements in use that were prepared through the cache
+    in_use: HashMap<u64, (u64, String)>,
+}
+
+impl SQLiteStatementCache {
+    fn take(&mut self, conn_id: u64, sql: &str) -> Option<Statement> {
+        let statements = self.idle.get_mut(&(conn_id, sql.to_owned()))?;
+        let statement = statements.pop()?;
+        self.idle_count -= 1;
+        Some(statement)
+    }
+
+    fn track(&mut self, statement_id: u64, conn_id: u64, sql: String) {
+        self.in_use.insert(statement_id, (conn_id, sql));
+    }
+
+    // Keeps the statement if it was prepared through the cache and there is room left
+    fn release(&mut self, statement_id: u64, statement: Arc<Mutex<Statement>>) {
+        let Some(key) = self.in_use.remove(&statement_id) else {
+            return;
+        };
+        if self.idle_count >= MAX_CACHED_STATEMENTS {
+            return;
+        }
+        let Some(mut statement) = Arc::try_unwrap(statement)
+            .ok()
+            .and_then(|statement| statement.into_inner().ok())
+        else {
+            return;
+        };
+        if statement.reset().is_ok() {
+            self.idle.entry(key).or_default().push(statement);
+            self.idle_count += 1;
+        }
+    }
+}
+
+impl std::fmt::Debug for SQLiteStatementCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SQLiteStatementCache")
+            .field("idle_count", &self.idle_count)
+            .field("in_use", &self.in_use.len())
+            .finish()
+    }
+}
+
 pub trait SQLiteCtx {
     fn sqlite_connections(&self) -> &SQLiteConnections;
     fn sqlite_connections_mut(&mut self) -> &mut SQLiteConnections;
@@ -33,6 +109,11 @@ pub trait SQLiteCtx {
 
     fn sqlite_statements(&self) -> &SQLiteStatements;
     fn sqlite_statements_mut(&mut self) -> &mut SQLiteStatements;
+
+    fn sqlite_statement_cache_mut(&mut self) -> &mut SQLiteStatementCache;
+
+    fn sqlite_blobs(&self) -> &SQLiteBlobs;
+    fn sqlite_blobs_mut(&mut self) -> &mut SQLiteBlobs;
 }
 
 // Register the SqlLite apis
@@ -42,112 +123,370 @@ pub fn register<T: SQLiteCtx + ProcessState + Send + ErrorCtx + ResourceLimiter
 where
     T::Config: lunatic_process_api::ProcessConfigCtx,
 {
-    linker.func_wrap("lunatic::sqlite", "open", open)?;
-    linker.func_wrap("lunatic::sqlite", "query_prepare", query_prepare)?;
-    linker.func_wrap("lunatic::sqlite", "execute", execute)?;
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap3_async("lunatic::sqlite", "open", open)?;
+    linker.func_wrap3_async("lunatic::sqlite", "query_prepare", query_prepare)?;
+    linker.func_wrap3_async(
+        "lunatic::sqlite",
+        "query_prepare_cached",
+        query_prepare_cached,
+    )?;
+    linker.func_wrap3_async("lunatic::sqlite", "execute", execute)?;
+    linker.func_wrap1_async("lunatic::sqlite", "begin", begin)?;
+    linker.func_wrap1_async("lunatic::sqlite", "commit", commit)?;
+    linker.func_wrap1_async("lunatic::sqlite", "rollback", rollback)?;
+    linker.func_wrap3_async("lunatic::sqlite", "savepoint", savepoint)?;
+    linker.func_wrap3_async("lunatic::sqlite", "release_savepoint", release_savepoint)?;
+    linker.func_wrap3_async(
+        "lunatic::sqlite",
+        "rollback_to_savepoint",
+        rollback_to_savepoint,
+    )?;
     linker.func_wrap("lunatic::sqlite", "bind_value", bind_value)?;
+    linker.func_wrap("lunatic::sqlite", "rebind", rebind)?;
     linker.func_wrap("lunatic::sqlite", "sqlite3_changes", sqlite3_changes)?;
     linker.func_wrap("lunatic::sqlite", "statement_reset", statement_reset)?;
     linker.func_wrap2_async("lunatic::sqlite", "last_error", last_error)?;
     linker.func_wrap("lunatic::sqlite", "sqlite3_finalize", sqlite3_finalize)?;
-    linker.func_wrap("lunatic::sqlite", "sqlite3_step", sqlite3_step)?;
+    linker.func_wrap1_async("lunatic::sqlite", "sqlite3_step", sqlite3_step)?;
     linker.func_wrap3_async("lunatic::sqlite", "read_column", read_column)?;
     linker.func_wrap2_async("lunatic::sqlite", "column_names", column_names)?;
     linker.func_wrap2_async("lunatic::sqlite", "read_row", read_row)?;
     linker.func_wrap("lunatic::sqlite", "column_count", column_count)?;
     linker.func_wrap3_async("lunatic::sqlite", "column_name", column_name)?;
+    linker.func_wrap3_async("lunatic::sqlite", "query_stream_next", query_stream_next)?;
+    linker.func_wrap8_async("lunatic::sqlite", "blob_open", blob_open)?;
+    linker.func_wrap4_async("lunatic::sqlite", "blob_read", blob_read)?;
+    linker.func_wrap4_async("lunatic::sqlite", "blob_write", blob_write)?;
+    linker.func_wrap("lunatic::sqlite", "blob_bytes", blob_bytes)?;
+    linker.func_wrap("lunatic::sqlite", "blob_close", blob_close)?;
     Ok(())
 }
 
+// Runs a call into sqlite on the blocking thread pool, so that other processes keep running while
+// it waits on the disk or on locks held by other connections. Processes wait for each call to
+// finish before issuing the next one, so the calls on a connection keep their order.
+async fn blocking<R, F>(f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .or_trap("lunatic::sqlite::blocking")?
+}
+
 fn open<T>(
     mut caller: Caller<T>,
     path_str_ptr: u32,
     path_str_len: u32,
     connection_id_ptr: u32,
-) -> Result<u64>
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_>
 where
-    T: ProcessState + ErrorCtx + SQLiteCtx,
+    T: ProcessState + ErrorCtx + SQLiteCtx + Send,
     T::Config: lunatic_process_api::ProcessConfigCtx,
 {
-    // obtain the memory and the state
-    let memory = get_memory(&mut caller)?;
-    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    Box::new(async move {
+        // obtain the memory and the state
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+
+        // obtain the path as a byte slice reference
+        let path = memory_slice
+            .get(path_str_ptr as usize..(path_str_ptr + path_str_len) as usize)
+            .or_trap("lunatic::sqlite::open")?;
+        let path = std::str::from_utf8(path)
+            .or_trap("lunatic::sqlite::open")?
+            .to_owned();
+        let access = {
+            let config = state.config();
+            config
+                .can_access_fs_location(Path::new(&path))
+                .and_then(|()| config.can_open_sqlite(Path::new(&path)))
+        };
+        let max_db_size = match access {
+            Ok(max_db_size) => max_db_size,
+            Err(error_message) => {
+                let error_id = state.error_resources_mut().add(
+                    anyhow::Error::msg(error_message).context(format!("Failed to access '{path}'")),
+                );
+                memory
+                    .write(
+                        &mut caller,
+                        connection_id_ptr as usize,
+                        &error_id.to_le_bytes(),
+                    )
+                    .or_trap("lunatic::sqlite::open")?;
+                return Ok(1);
+            }
+        };
+
+        // call the open function, and define the return code
+        let opened = blocking(move || Ok(open_restricted(&path, max_db_size))).await?;
+        let (conn_or_err_id, return_code) = match opened {
+            Ok(conn) => (
+                caller
+                    .data_mut()
+                    .sqlite_connections_mut()
+                    .add(Arc::new(Mutex::new(conn))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
 
-    // obtain the path as a byte slice reference
-    let path = memory_slice
-        .get(path_str_ptr as usize..(path_str_ptr + path_str_len) as usize)
-        .or_trap("lunatic::sqlite::open")?;
-    let path = std::str::from_utf8(path).or_trap("lunatic::sqlite::open")?;
-    if let Err(error_message) = state.config().can_access_fs_location(Path::new(path)) {
-        let error_id = state
-            .error_resources_mut()
-            .add(anyhow::Error::msg(error_message).context(format!("Failed to access '{path}'")));
+        // write the result into memory and return the return code
         memory
             .write(
                 &mut caller,
                 connection_id_ptr as usize,
-                &error_id.to_le_bytes(),
+                &conn_or_err_id.to_le_bytes(),
             )
             .or_trap("lunatic::sqlite::open")?;
-        return Ok(1);
-    }
+        Ok(return_code)
+    })
+}
 
-    // call the open function, and define the return code
-    let (conn_or_err_id, return_code) = match sqlite::open(path) {
-        Ok(conn) => (
-            caller
-                .data_mut()
-                .sqlite_connections_mut()
-                .add(Arc::new(Mutex::new(conn))),
-            0,
-        ),
-        Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
-    };
+// Opens the database and limits its size to the quota. Writes that would grow the database beyond
+// the quota fail with `SQLITE_FULL`, databases that already exceed it can't grow anymore.
+fn open_restricted(path: &str, max_db_size: Option<u64>) -> sqlite::Result<Connection> {
+    let conn = sqlite::open(path)?;
+    if let Some(max_db_size) = max_db_size {
+        let page_size = {
+            let mut statement = conn.prepare("PRAGMA page_size")?;
+            statement.next()?;
+            statement.read::<i64, usize>(0)?.max(1) as u64
+        };
+        let max_page_count = (max_db_size / page_size).max(1);
+        conn.execute(format!("PRAGMA max_page_count = {max_page_count}"))?;
+    }
+    // Safety: the connection is open and the authorizer doesn't outlive it. The user data is only
+    // used as a flag and never dereferenced.
+    unsafe {
+        let has_quota = max_db_size.is_some() as usize as *mut c_void;
+        sqlite3_sys::sqlite3_set_authorizer(conn.as_raw(), Some(authorizer), has_quota);
+    }
+    Ok(conn)
+}
 
-    // write the result into memory and return the return code
-    memory
-        .write(
-            &mut caller,
-            connection_id_ptr as usize,
-            &conn_or_err_id.to_le_bytes(),
-        )
-        .or_trap("lunatic::sqlite::open")?;
-    Ok(return_code)
+// Denies statements that would get around the restrictions of `open`. Attaching databases opens
+// files without checking the permissions, and changing the page limit or size lifts the quota.
+extern "C" fn authorizer(
+    has_quota: *mut c_void,
+    action: c_int,
+    arg1: *const c_char,
+    _: *const c_char,
+    _: *const c_char,
+    _: *const c_char,
+) -> c_int {
+    match action {
+        sqlite3_sys::SQLITE_ATTACH => sqlite3_sys::SQLITE_DENY,
+        sqlite3_sys::SQLITE_PRAGMA if !has_quota.is_null() && !arg1.is_null() => {
+            // Safety: sqlite passes the name of the pragma as a nul-terminated string
+            let pragma = unsafe { CStr::from_ptr(arg1) }.to_bytes();
+            if pragma.eq_ignore_ascii_case(b"max_page_count")
+                || pragma.eq_ignore_ascii_case(b"page_size")
+            {
+                sqlite3_sys::SQLITE_DENY
+            } else {
+                sqlite3_sys::SQLITE_OK
+            }
+        }
+        _ => sqlite3_sys::SQLITE_OK,
+    }
 }
 
-fn execute<T: ProcessState + ErrorCtx + SQLiteCtx>(
+fn execute<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
     mut caller: Caller<T>,
     conn_id: u64,
     exec_str_ptr: u32,
     exec_str_len: u32,
-) -> Result<u32> {
-    let memory = get_memory(&mut caller)?;
-    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
-    let exec = memory_slice
-        .get(exec_str_ptr as usize..(exec_str_ptr + exec_str_len) as usize)
-        .or_trap("lunatic::sqlite::execute")?;
-    let exec = std::str::from_utf8(exec).or_trap("lunatic::sqlite::execute")?;
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        let exec = memory_slice
+            .get(exec_str_ptr as usize..(exec_str_ptr + exec_str_len) as usize)
+            .or_trap("lunatic::sqlite::execute")?;
+        let exec = std::str::from_utf8(exec)
+            .or_trap("lunatic::sqlite::execute")?
+            .to_owned();
+        let conn = state
+            .sqlite_connections()
+            .get(conn_id)
+            .or_trap("lunatic::sqlite::execute")?
+            .clone();
 
-    // execute a single sqlite query
-    match state
-        .sqlite_connections()
-        .get(conn_id)
-        .or_trap("lunatic::sqlite::execute")?
-        .lock()
-        .or_trap("lunatic::sqlite::execute")?
-        .execute(exec)
-    {
+        // execute a single sqlite query
+        execute_on(conn, exec).await
+    })
+}
+
+// Executes the statements on the connection and returns the SQLite response code
+async fn execute_on(conn: Arc<Mutex<Connection>>, statements: String) -> Result<u32> {
+    let result = blocking(move || {
+        let conn = conn.lock().or_trap("lunatic::sqlite::execute")?;
+        Ok(conn.execute(statements))
+    })
+    .await?;
+    match result {
         // 1 is equal to SQLITE_ERROR, which is a generic error code
         Err(e) => Ok(e.code.unwrap_or(1) as u32),
         Ok(_) => Ok(0),
     }
 }
 
-fn query_prepare<T: ProcessState + ErrorCtx + SQLiteCtx>(
-    mut caller: Caller<T>,
+// Transactions are started, committed and rolled back with the following functions. If the process
+// dies with an open transaction, the transaction is rolled back when its connections are closed.
+//
+// Returns the SQLite response code, 0 on success.
+//
+// Traps:
+// * If the connection doesn't exist.
+fn begin<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(transaction_control(caller, conn_id, "BEGIN"))
+}
+
+fn commit<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(transaction_control(caller, conn_id, "COMMIT"))
+}
+
+fn rollback<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(transaction_control(caller, conn_id, "ROLLBACK"))
+}
+
+async fn transaction_control<T: SQLiteCtx>(
+    caller: Caller<'_, T>,
+    conn_id: u64,
+    statement: &'static str,
+) -> Result<u32> {
+    let conn = caller
+        .data()
+        .sqlite_connections()
+        .get(conn_id)
+        .or_trap("lunatic::sqlite::transaction::obtain_conn")?
+        .clone();
+    execute_on(conn, statement.to_owned()).await
+}
+
+// Savepoints are created, released and rolled back to by the name at **name_str_ptr**. Savepoints
+// can be nested and also be used outside of transactions.
+//
+// Returns the SQLite response code, 0 on success.
+//
+// Traps:
+// * If the connection doesn't exist.
+// * If the name is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn savepoint<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(savepoint_control(
+        caller,
+        conn_id,
+        name_str_ptr,
+        name_str_len,
+        "SAVEPOINT",
+    ))
+}
+
+fn release_savepoint<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(savepoint_control(
+        caller,
+        conn_id,
+        name_str_ptr,
+        name_str_len,
+        "RELEASE SAVEPOINT",
+    ))
+}
+
+fn rollback_to_savepoint<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(savepoint_control(
+        caller,
+        conn_id,
+        name_str_ptr,
+        name_str_len,
+        "ROLLBACK TO SAVEPOINT",
+    ))
+}
+
+async fn savepoint_control<T: SQLiteCtx>(
+    mut caller: Caller<'_, T>,
+    conn_id: u64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    statement: &'static str,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let name = memory_slice
+        .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+        .or_trap("lunatic::sqlite::savepoint")?;
+    let name = std::str::from_utf8(name).or_trap("lunatic::sqlite::savepoint")?;
+    let conn = state
+        .sqlite_connections()
+        .get(conn_id)
+        .or_trap("lunatic::sqlite::savepoint::obtain_conn")?
+        .clone();
+    // quote the name as an identifier, so that it can't inject other statements
+    let statement = format!("{statement} \"{}\"", name.replace('"', "\"\""));
+    execute_on(conn, statement).await
+}
+
+fn query_prepare<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
     conn_id: u64,
     query_str_ptr: u32,
     query_str_len: u32,
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_> {
+    Box::new(prepare(
+        caller,
+        conn_id,
+        query_str_ptr,
+        query_str_len,
+        false,
+    ))
+}
+
+// Same as `query_prepare`, but reuses a statement of the connection that was prepared from the
+// same SQL and finalized since. Finalizing the returned statement keeps it around for the next
+// call. Reused statements are reset, but keep the values bound to them.
+fn query_prepare_cached<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    caller: Caller<T>,
+    conn_id: u64,
+    query_str_ptr: u32,
+    query_str_len: u32,
+) -> Box<dyn Future<Output = Result<u64>> + Send + '_> {
+    Box::new(prepare(caller, conn_id, query_str_ptr, query_str_len, true))
+}
+
+async fn prepare<T: SQLiteCtx>(
+    mut caller: Caller<'_, T>,
+    conn_id: u64,
+    query_str_ptr: u32,
+    query_str_len: u32,
+    cached: bool,
 ) -> Result<u64> {
     // get the memory
     let memory = get_memory(&mut caller)?;
@@ -157,24 +496,47 @@ fn query_prepare<T: ProcessState + ErrorCtx + SQLiteCtx>(
     let query = memory_slice
         .get(query_str_ptr as usize..(query_str_ptr + query_str_len) as usize)
         .or_trap("lunatic::sqlite::query_prepare::get_query")?;
-    let query = std::str::from_utf8(query).or_trap("lunatic::sqlite::query_prepare::from_utf8")?;
+    let query = std::str::from_utf8(query)
+        .or_trap("lunatic::sqlite::query_prepare::from_utf8")?
+        .to_owned();
 
-    let statement = {
-        // obtain the sqlite connection
-        let conn = state
-            .sqlite_connections()
-            .get(conn_id)
-            .take()
-            .or_trap("lunatic::sqlite::query_prepare::obtain_conn")?
-            .lock()
-            .or_trap("lunatic::sqlite::query_prepare::obtain_conn")?;
+    let idle = if cached {
+        state.sqlite_statement_cache_mut().take(conn_id, &query)
+    } else {
+        None
+    };
+    let statement = match idle {
+        Some(statement) => statement,
+        None => {
+            // obtain the sqlite connection
+            let conn = state
+                .sqlite_connections()
+                .get(conn_id)
+                .or_trap("lunatic::sqlite::query_prepare::obtain_conn")?
+                .clone();
 
-        // prepare the statement
-        conn.prepare(query)
-            .or_trap("lunatic::sqlite::query_prepare::prepare_statement")?
+            // prepare the statement
+            let query = query.clone();
+            blocking(move || {
+                let conn = conn
+                    .lock()
+                    .or_trap("lunatic::sqlite::query_prepare::obtain_conn")?;
+                conn.prepare(query)
+                    .or_trap("lunatic::sqlite::query_prepare::prepare_statement")
+            })
+            .await?
+        }
     };
 
-    let statement_id = state.sqlite_statements_mut().add((conn_id, statement));
+    let state = caller.data_mut();
+    let statement_id = state
+        .sqlite_statements_mut()
+        .add((conn_id, Arc::new(Mutex::new(statement))));
+    if cached {
+        state
+            .sqlite_statement_cache_mut()
+            .track(statement_id, conn_id, query);
+    }
 
     Ok(statement_id)
 }
@@ -182,9 +544,9 @@ fn query_prepare<T: ProcessState + ErrorCtx + SQLiteCtx>(
 macro_rules! get_statement {
     ($state:ident, $statement_id:ident) => {
         $state
-            .sqlite_statements_mut()
-            .get_mut($statement_id)
-            .map(|(connection_id, statement)| (*connection_id, statement))
+            .sqlite_statements()
+            .get($statement_id)
+            .map(|(connection_id, statement)| (*connection_id, statement.clone()))
             .or_trap("lunatic::sqlite::get_statement_by_id")?
     };
 }
@@ -213,13 +575,46 @@ fn bind_value<T: ProcessState + ErrorCtx + SQLiteCtx>(
     let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
 
     let (_, statement) = get_statement!(state, statement_id);
+    let mut statement = statement.lock().or_trap("lunatic::sqlite::bind_value")?;
 
     // get the query
     let bind_data = memory_slice
         .get(bind_data_ptr as usize..(bind_data_ptr + bind_data_len) as usize)
         .or_trap("lunatic::sqlite::bind_value::load_bind_data")?;
 
-    let values: BindList = bincode::deserialize(bind_data).unwrap();
+    bind(&mut statement, bind_data)
+}
+
+// Resets the statement and binds the `BindList` at **bind_data_ptr** to it, replacing the values
+// bound before. Parameters that are not part of the list keep their value.
+//
+// Traps:
+// * If the statement doesn't exist.
+// * If the data is not a `BindList` or can't be bound to the statement.
+// * If any memory outside the guest heap space is referenced.
+fn rebind<T: ProcessState + ErrorCtx + SQLiteCtx>(
+    mut caller: Caller<T>,
+    statement_id: u64,
+    bind_data_ptr: u32,
+    bind_data_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+
+    let (_, statement) = get_statement!(state, statement_id);
+    let mut statement = statement.lock().or_trap("lunatic::sqlite::rebind")?;
+    statement.reset().or_trap("lunatic::sqlite::rebind")?;
+
+    let bind_data = memory_slice
+        .get(bind_data_ptr as usize..(bind_data_ptr + bind_data_len) as usize)
+        .or_trap("lunatic::sqlite::rebind::load_bind_data")?;
+
+    bind(&mut statement, bind_data)
+}
+
+fn bind(statement: &mut Statement, bind_data: &[u8]) -> Result<()> {
+    let values: BindList =
+        bincode::deserialize(bind_data).or_trap("lunatic::sqlite::bind_value::decode")?;
 
     for pair in values.iter() {
         pair.bind(statement)
@@ -250,7 +645,10 @@ fn statement_reset<T: ProcessState + ErrorCtx + SQLiteCtx>(
     let (_, state) = memory.data_and_store_mut(&mut caller);
     let (_, stmt) = get_statement!(state, statement_id);
 
-    stmt.reset().or_trap("lunatic::sqlite::statement_reset")?;
+    stmt.lock()
+        .or_trap("lunatic::sqlite::statement_reset")?
+        .reset()
+        .or_trap("lunatic::sqlite::statement_reset")?;
 
     Ok(())
 }
@@ -267,8 +665,11 @@ fn read_column<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
         let (_, state) = memory.data_and_store_mut(&mut caller);
         let (_, stmt) = get_statement!(state, statement_id);
 
-        let column = bincode::serialize(&SqliteValue::read_column(stmt, col_idx as usize)?)
-            .or_trap("lunatic::sqlite::read_column")?;
+        let column = {
+            let stmt = stmt.lock().or_trap("lunatic::sqlite::read_column")?;
+            SqliteValue::read_column(&stmt, col_idx as usize)?
+        };
+        let column = bincode::serialize(&column).or_trap("lunatic::sqlite::read_column")?;
 
         write_to_guest_vec(&mut caller, &memory, &column, opaque_ptr).await
     })
@@ -285,7 +686,11 @@ fn column_names<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
         let (_, state) = memory.data_and_store_mut(&mut caller);
         let (_, stmt) = get_statement!(state, statement_id);
 
-        let column_names = stmt.column_names().to_vec();
+        let column_names = stmt
+            .lock()
+            .or_trap("lunatic::sqlite::column_names")?
+            .column_names()
+            .to_vec();
 
         let column_names =
             bincode::serialize(&column_names).or_trap("lunatic::sqlite::column_names")?;
@@ -307,7 +712,10 @@ fn read_row<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
         let (_, state) = memory.data_and_store_mut(&mut caller);
         let (_, stmt) = get_statement!(state, statement_id);
 
-        let read_row = SqliteRow::read_row(stmt)?;
+        let read_row = {
+            let mut stmt = stmt.lock().or_trap("lunatic::sqlite::read_row")?;
+            SqliteRow::read_row(&mut stmt)?
+        };
 
         let row = bincode::serialize(&read_row).or_trap("lunatic::sqlite::read_row")?;
 
@@ -315,6 +723,280 @@ fn read_row<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
     })
 }
 
+// Steps through the next **max_rows** rows of the statement, encodes them as a `Vec<SqliteRow>` via
+// bincode and writes it into a guest allocated Vec<u8>. Fewer rows than requested are returned once
+// the end of the results is reached, so large results can be read in chunks of bounded size.
+//
+// Returns the pointer to the data, the length is written to **opaque_ptr**.
+//
+// Traps:
+// * If the statement doesn't exist.
+// * If stepping through the statement fails.
+// * If any memory outside the guest heap space is referenced.
+fn query_stream_next<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    mut caller: Caller<T>,
+    statement_id: u64,
+    max_rows: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (_, state) = memory.data_and_store_mut(&mut caller);
+        let (conn_id, statement) = get_statement!(state, statement_id);
+        let conn = state
+            .sqlite_connections()
+            .get(conn_id)
+            .or_trap("lunatic::sqlite::query_stream_next::obtain_conn")?
+            .clone();
+
+        let rows = blocking(move || {
+            let _conn = conn
+                .lock()
+                .or_trap("lunatic::sqlite::query_stream_next::obtain_conn")?;
+            let mut statement = statement
+                .lock()
+                .or_trap("lunatic::sqlite::query_stream_next")?;
+            let mut rows = Vec::new();
+            while rows.len() < max_rows as usize {
+                match statement
+                    .next()
+                    .or_trap("lunatic::sqlite::query_stream_next")?
+                {
+                    State::Row => rows.push(SqliteRow::read_row(&mut statement)?),
+                    State::Done => break,
+                }
+            }
+            Ok(rows)
+        })
+        .await?;
+
+        let rows = bincode::serialize(&rows).or_trap("lunatic::sqlite::query_stream_next")?;
+        write_to_guest_vec(&mut caller, &memory, &rows, opaque_ptr).await
+    })
+}
+
+// Opens the blob in **column** of the row with **row_id** in **table** for incremental I/O, and
+// writes the ID of the blob to **blob_id_ptr**. The blob can only be written if **writable** is
+// not 0. Blobs can't change their size, and once the row changes, reading or writing the blob
+// fails with `SQLITE_ABORT`.
+//
+// Returns the SQLite response code, 0 on success.
+//
+// Traps:
+// * If the connection doesn't exist.
+// * If the table or column name is not a valid UTF-8 string or contains a nul byte.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn blob_open<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    mut caller: Caller<T>,
+    conn_id: u64,
+    table_str_ptr: u32,
+    table_str_len: u32,
+    column_str_ptr: u32,
+    column_str_len: u32,
+    row_id: i64,
+    writable: u32,
+    blob_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        let name = |ptr: u32, len: u32| {
+            let name = memory_slice
+                .get(ptr as usize..(ptr + len) as usize)
+                .or_trap("lunatic::sqlite::blob_open")?;
+            let name = std::str::from_utf8(name).or_trap("lunatic::sqlite::blob_open")?;
+            CString::new(name).or_trap("lunatic::sqlite::blob_open")
+        };
+        let table = name(table_str_ptr, table_str_len)?;
+        let column = name(column_str_ptr, column_str_len)?;
+        let conn = state
+            .sqlite_connections()
+            .get(conn_id)
+            .or_trap("lunatic::sqlite::blob_open::obtain_conn")?
+            .clone();
+
+        let opened = blocking(move || {
+            let mut raw = std::ptr::null_mut();
+            let code = {
+                let conn = conn.lock().or_trap("lunatic::sqlite::blob_open")?;
+                // Safety: the connection is open and the names are nul-terminated
+                unsafe {
+                    sqlite3_sys::sqlite3_blob_open(
+                        conn.as_raw(),
+                        c"main".as_ptr(),
+                        table.as_ptr(),
+                        column.as_ptr(),
+                        row_id,
+                        (writable != 0) as c_int,
+                        &mut raw,
+                    )
+                }
+            };
+            match code {
+                sqlite3_sys::SQLITE_OK => Ok(Ok(SQLiteBlob { raw, conn })),
+                code => Ok(Err(code)),
+            }
+        })
+        .await?;
+
+        match opened {
+            Ok(blob) => {
+                let blob_id = caller
+                    .data_mut()
+                    .sqlite_blobs_mut()
+                    .add(Arc::new(Mutex::new(blob)));
+                memory
+                    .write(&mut caller, blob_id_ptr as usize, &blob_id.to_le_bytes())
+                    .or_trap("lunatic::sqlite::blob_open")?;
+                Ok(0)
+            }
+            Err(code) => Ok(code as u32),
+        }
+    })
+}
+
+// Reads **buf_len** bytes of the blob starting at **offset** into **buf_ptr**.
+//
+// Returns the SQLite response code, 0 on success. Reading past the end of the blob fails with
+// `SQLITE_ERROR`.
+//
+// Traps:
+// * If the blob doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn blob_read<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    mut caller: Caller<T>,
+    blob_id: u64,
+    offset: u32,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        memory_slice
+            .get(buf_ptr as usize..(buf_ptr as usize + buf_len as usize))
+            .or_trap("lunatic::sqlite::blob_read")?;
+        let blob = state
+            .sqlite_blobs()
+            .get(blob_id)
+            .or_trap("lunatic::sqlite::blob_read")?
+            .clone();
+        let (offset, len) = blob_range(offset, buf_len).or_trap("lunatic::sqlite::blob_read")?;
+
+        let (code, buffer) = blocking(move || {
+            let blob = blob.lock().or_trap("lunatic::sqlite::blob_read")?;
+            let _conn = blob.conn.lock().or_trap("lunatic::sqlite::blob_read")?;
+            let mut buffer = vec![0u8; len as usize];
+            // Safety: the buffer is big enough for `len` bytes
+            let code = unsafe {
+                sqlite3_sys::sqlite3_blob_read(
+                    blob.raw,
+                    buffer.as_mut_ptr() as *mut c_void,
+                    len,
+                    offset,
+                )
+            };
+            Ok((code, buffer))
+        })
+        .await?;
+
+        if code == sqlite3_sys::SQLITE_OK {
+            memory
+                .write(&mut caller, buf_ptr as usize, &buffer)
+                .or_trap("lunatic::sqlite::blob_read")?;
+        }
+        Ok(code as u32)
+    })
+}
+
+// Writes **data_len** bytes from **data_ptr** into the blob starting at **offset**.
+//
+// Returns the SQLite response code, 0 on success. Writing past the end of the blob fails with
+// `SQLITE_ERROR` and writing a blob that wasn't opened as writable with `SQLITE_READONLY`.
+//
+// Traps:
+// * If the blob doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn blob_write<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
+    mut caller: Caller<T>,
+    blob_id: u64,
+    offset: u32,
+    data_ptr: u32,
+    data_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        let data = memory_slice
+            .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
+            .or_trap("lunatic::sqlite::blob_write")?
+            .to_vec();
+        let blob = state
+            .sqlite_blobs()
+            .get(blob_id)
+            .or_trap("lunatic::sqlite::blob_write")?
+            .clone();
+        let (offset, len) = blob_range(offset, data_len).or_trap("lunatic::sqlite::blob_write")?;
+
+        blocking(move || {
+            let blob = blob.lock().or_trap("lunatic::sqlite::blob_write")?;
+            let _conn = blob.conn.lock().or_trap("lunatic::sqlite::blob_write")?;
+            // Safety: the data is `len` bytes long
+            let code = unsafe {
+                sqlite3_sys::sqlite3_blob_write(
+                    blob.raw,
+                    data.as_ptr() as *const c_void,
+                    len,
+                    offset,
+                )
+            };
+            Ok(code as u32)
+        })
+        .await
+    })
+}
+
+fn blob_range(offset: u32, len: u32) -> Result<(c_int, c_int), std::num::TryFromIntError> {
+    Ok((c_int::try_from(offset)?, c_int::try_from(len)?))
+}
+
+// Returns the size of the blob in bytes.
+//
+// Traps:
+// * If the blob doesn't exist.
+fn blob_bytes<T: ProcessState + ErrorCtx + SQLiteCtx>(
+    caller: Caller<T>,
+    blob_id: u64,
+) -> Result<u32> {
+    let blob = caller
+        .data()
+        .sqlite_blobs()
+        .get(blob_id)
+        .or_trap("lunatic::sqlite::blob_bytes")?
+        .lock()
+        .or_trap("lunatic::sqlite::blob_bytes")?;
+    // Safety: the blob is open until it's dropped
+    let bytes = unsafe { sqlite3_sys::sqlite3_blob_bytes(blob.raw) };
+    Ok(bytes as u32)
+}
+
+// Closes the blob.
+//
+// Traps:
+// * If the blob doesn't exist.
+fn blob_close<T: ProcessState + ErrorCtx + SQLiteCtx>(
+    mut caller: Caller<T>,
+    blob_id: u64,
+) -> Result<()> {
+    caller
+        .data_mut()
+        .sqlite_blobs_mut()
+        .remove(blob_id)
+        .or_trap("lunatic::sqlite::blob_close")?;
+    Ok(())
+}
+
 fn last_error<T: ProcessState + ErrorCtx + SQLiteCtx + ResourceLimiter + Send + Sync>(
     mut caller: Caller<T>,
     conn_id: u64,
@@ -343,29 +1025,49 @@ fn sqlite3_finalize<T: ProcessState + ErrorCtx + SQLiteCtx>(
     // get state
     let memory = get_memory(&mut caller)?;
     let (_, state) = memory.data_and_store_mut(&mut caller);
-    // dropping the statement should invoke the C function `sqlite3_finalize`
-    state
+    // dropping the statement should invoke the C function `sqlite3_finalize`, unless it's
+    // kept in the cache
+    let (_, statement) = state
         .sqlite_statements_mut()
         .remove(statement_id)
         .or_trap("lunatic::sqlite::sqlite3_finalize")?;
+    state
+        .sqlite_statement_cache_mut()
+        .release(statement_id, statement);
 
     Ok(())
 }
 
 // sends back SQLITE_DONE or SQLITE_ROW depending on whether there's more data available or not
-fn sqlite3_step<T: ProcessState + ErrorCtx + SQLiteCtx>(
+fn sqlite3_step<T: ProcessState + ErrorCtx + SQLiteCtx + Send>(
     mut caller: Caller<T>,
     statement_id: u64,
-) -> Result<u32> {
-    // get state
-    let memory = get_memory(&mut caller)?;
-    let (_, state) = memory.data_and_store_mut(&mut caller);
-    let (_, statement) = get_statement!(state, statement_id);
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        // get state
+        let memory = get_memory(&mut caller)?;
+        let (_, state) = memory.data_and_store_mut(&mut caller);
+        let (conn_id, statement) = get_statement!(state, statement_id);
+        let conn = state
+            .sqlite_connections()
+            .get(conn_id)
+            .or_trap("lunatic::sqlite::sqlite3_step::obtain_conn")?
+            .clone();
 
-    match statement.next().or_trap("lunatic::sqlite::sqlite3_step")? {
-        State::Done => Ok(SQLITE_DONE),
-        State::Row => Ok(SQLITE_ROW),
-    }
+        let next = blocking(move || {
+            // hold the connection while stepping, so that no other call runs on it in between
+            let _conn = conn
+                .lock()
+                .or_trap("lunatic::sqlite::sqlite3_step::obtain_conn")?;
+            let mut statement = statement.lock().or_trap("lunatic::sqlite::sqlite3_step")?;
+            statement.next().or_trap("lunatic::sqlite::sqlite3_step")
+        })
+        .await?;
+        match next {
+            State::Done => Ok(SQLITE_DONE),
+            State::Row => Ok(SQLITE_ROW),
+        }
+    })
 }
 
 fn column_count<T: ProcessState + ErrorCtx + SQLiteCtx>(
@@ -376,8 +1078,12 @@ fn column_count<T: ProcessState + ErrorCtx + SQLiteCtx>(
     let memory = get_memory(&mut caller)?;
     let (_, state) = memory.data_and_store_mut(&mut caller);
     let (_, statement) = get_statement!(state, statement_id);
+    let column_count = statement
+        .lock()
+        .or_trap("lunatic::sqlite::column_count")?
+        .column_count();
 
-    Ok(statement.column_count() as u32)
+    Ok(column_count as u32)
 }
 
 fn column_name<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
@@ -389,17 +1095,16 @@ fn column_name<T: ProcessState + ErrorCtx + SQLiteCtx + Send + Sync>(
     Box::new(async move {
         // get state
         let memory = get_memory(&mut caller)?;
-        let (_, column_name) = {
+        let column_name = {
             let (_, state) = memory.data_and_store_mut(&mut caller);
-            let (connection_id, statement) = get_statement!(state, statement_id);
-
-            (
-                connection_id,
-                statement
-                    .column_name(column_idx as usize)
-                    .or_trap("lunatic::sqlite::column_name")?
-                    .to_owned(),
-            )
+            let (_, statement) = get_statement!(state, statement_id);
+            let name = statement
+                .lock()
+                .or_trap("lunatic::sqlite::column_name")?
+                .column_name(column_idx as usize)
+                .or_trap("lunatic::sqlite::column_name")?
+                .to_owned();
+            name
         };
 
         write_to_guest_vec(&mut caller, &memory, column_name.as_bytes(), opaque_ptr).await
@@ -1,7 +1,11 @@
+mod sink;
+mod stdin;
+
 use std::{
     any::Any,
+    collections::VecDeque,
     fmt::{Display, Formatter},
-    io::{stdout, Cursor, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write},
+    io::{stdout, IoSlice, IoSliceMut, SeekFrom, Write},
     sync::{Arc, Mutex, RwLock},
 };
 
@@ -10,10 +14,62 @@ use wasi_common::{
     Error, ErrorExt, SystemTimeSpec, WasiFile,
 };
 
+pub use sink::RotatingFile;
+#[cfg(unix)]
+pub use sink::Syslog;
+pub use stdin::{StdinPipe, StdinPipeWriter};
+
 // This signature looks scary, but it just means that the vector holding all output streams
 // is rarely extended and often accessed (`RwLock`). The `Mutex` is necessary to allow
 // parallel writes for independent processes, it doesn't have any contention.
-type StdOutVec = Arc<RwLock<Vec<Mutex<Cursor<Vec<u8>>>>>>;
+type StdOutVec = Arc<RwLock<Vec<Mutex<Stream>>>>;
+
+// Output of one process.
+//
+// If the size is limited, the first half of the limit is kept in `head` and the last half in
+// `tail`. Everything in between is dropped and only counted.
+#[derive(Debug, Default)]
+struct Stream {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    truncated: u64,
+}
+
+impl Stream {
+    fn write(&mut self, data: &[u8], limit: Option<usize>) {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return self.head.extend_from_slice(data),
+        };
+        let tail_limit = limit / 2;
+        let head_limit = limit - tail_limit;
+        let to_head = head_limit.saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..to_head]);
+        self.tail.extend(&data[to_head..]);
+        if self.tail.len() > tail_limit {
+            let dropped = self.tail.len() - tail_limit;
+            self.tail.drain(..dropped);
+            self.truncated += dropped as u64;
+        }
+    }
+
+    fn content(&self) -> String {
+        let mut content = String::from_utf8_lossy(&self.head).to_string();
+        if self.truncated > 0 {
+            if !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&format!("... {} bytes truncated ...\n", self.truncated));
+        }
+        let (front, back) = self.tail.as_slices();
+        content.push_str(&String::from_utf8_lossy(&[front, back].concat()));
+        content
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_empty() && self.tail.is_empty()
+    }
+}
 
 /// `StdoutCapture` holds the standard output from multiple processes.
 ///
@@ -24,6 +80,8 @@ pub struct StdoutCapture {
     // If true, all captured writes are echoed to stdout. This is used in testing scenarios with
     // the flag `--nocapture` set, because we still need to capture the output to inspect panics.
     echo: bool,
+    // Maximum number of bytes kept per stream, the rest is replaced by a truncation marker
+    limit: Option<usize>,
     writers: StdOutVec,
     // Index of the stdout currently in use by a process
     index: usize,
@@ -45,8 +103,7 @@ impl Display for StdoutCapture {
         } else {
             for (i, stream) in streams.iter().enumerate() {
                 writeln!(f, " --- process {i} stdout ---").unwrap();
-                let stream = stream.lock().unwrap();
-                let content = String::from_utf8_lossy(stream.get_ref()).to_string();
+                let content = stream.lock().unwrap().content();
                 write!(f, "{content}").unwrap();
             }
         }
@@ -57,9 +114,18 @@ impl Display for StdoutCapture {
 impl StdoutCapture {
     // Create a new `StdoutCapture` with one stream inside.
     pub fn new(echo: bool) -> Self {
+        Self::with_limit(echo, None)
+    }
+
+    /// Create a new `StdoutCapture` that keeps at most `limit` bytes of each stream.
+    ///
+    /// The beginning and the end of the output are retained, with a `... N bytes truncated ...`
+    /// marker in between.
+    pub fn with_limit(echo: bool, limit: Option<usize>) -> Self {
         Self {
             echo,
-            writers: Arc::new(RwLock::new(vec![Mutex::new(Cursor::new(Vec::new()))])),
+            limit,
+            writers: Arc::new(RwLock::new(vec![Mutex::new(Stream::default())])),
             index: 0,
         }
     }
@@ -74,11 +140,12 @@ impl StdoutCapture {
         let index = {
             let mut writers = RwLock::write(&self.writers).unwrap();
             // If the stream already exists don't add a new one, e.g. stdout & stderr share the same stream.
-            writers.push(Mutex::new(Cursor::new(Vec::new())));
+            writers.push(Mutex::new(Stream::default()));
             writers.len() - 1
         };
         Self {
             echo: self.echo,
+            limit: self.limit,
             writers: self.writers.clone(),
             index,
         }
@@ -87,24 +154,39 @@ impl StdoutCapture {
     /// Returns true if all streams are empty
     pub fn is_empty(&self) -> bool {
         let streams = RwLock::read(&self.writers).unwrap();
-        streams.iter().all(|stream| {
-            let stream = stream.lock().unwrap();
-            stream.get_ref().is_empty()
-        })
+        streams
+            .iter()
+            .all(|stream| stream.lock().unwrap().is_empty())
     }
 
     /// Returns stream's content
     pub fn content(&self) -> String {
         let streams = RwLock::read(&self.writers).unwrap();
         let stream = streams[self.index].lock().unwrap();
-        String::from_utf8_lossy(stream.get_ref()).to_string()
+        stream.content()
+    }
+
+    /// Returns the number of bytes dropped from the stream because it was over the limit.
+    pub fn truncated_bytes(&self) -> u64 {
+        let streams = RwLock::read(&self.writers).unwrap();
+        let stream = streams[self.index].lock().unwrap();
+        stream.truncated
+    }
+
+    /// Returns the number of bytes dropped from all streams.
+    pub fn total_truncated_bytes(&self) -> u64 {
+        let streams = RwLock::read(&self.writers).unwrap();
+        streams
+            .iter()
+            .map(|stream| stream.lock().unwrap().truncated)
+            .sum()
     }
 
     /// Add string to end of the stream
     pub fn push_str(&self, content: &str) {
         let streams = RwLock::read(&self.writers).unwrap();
         let mut stream = streams[self.index].lock().unwrap();
-        write!(stream, "{content}").unwrap();
+        stream.write(content.as_bytes(), self.limit);
     }
 }
 
@@ -162,13 +244,14 @@ impl WasiFile for StdoutCapture {
     async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
         let streams = RwLock::read(&self.writers).unwrap();
         let mut stream = streams[self.index].lock().unwrap();
-        let n = stream.write_vectored(bufs)?;
-        // Echo the captured part to stdout
-        if self.echo {
-            stream.seek(SeekFrom::End(-(n as i64)))?;
-            let mut echo = vec![0; n];
-            stream.read_exact(&mut echo)?;
-            stdout().write_all(&echo)?;
+        let mut n = 0;
+        for buf in bufs {
+            stream.write(buf, self.limit);
+            // Echo the whole write to stdout, even if it's not kept
+            if self.echo {
+                stdout().write_all(buf)?;
+            }
+            n += buf.len();
         }
         Ok(n.try_into()?)
     }
@@ -209,3 +292,19 @@ impl WasiFile for StdoutCapture {
         Err(Error::badf())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::StdoutCapture;
+
+    #[test]
+    fn truncate_over_limit() {
+        let stdout = StdoutCapture::with_limit(false, Some(8));
+        stdout.push_str("head");
+        stdout.push_str("middle");
+        stdout.push_str("tail");
+
+        assert_eq!(stdout.content(), "head\n... 6 bytes truncated ...\ntail");
+        assert_eq!(stdout.truncated_bytes(), 6);
+    }
+}
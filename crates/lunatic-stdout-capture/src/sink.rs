@@ -0,0 +1,176 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// `RotatingFile` appends the output of a process to a file.
+///
+/// Once the file would grow over `max_size` bytes, it's renamed to `<path>.1` and a new file is
+/// started. Older files are shifted to `<path>.2`, `<path>.3`, ... and only `max_files` of them
+/// are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new(path: impl AsRef<Path>, max_size: Option<u64>, max_files: u32) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `Syslog` sends each line of the output as a message to the local syslog daemon.
+///
+/// journald listens on the same socket, so the messages also end up in the journal.
+#[cfg(unix)]
+pub struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    // `<priority>identifier: ` prepended to every line
+    prefix: String,
+    // Output that doesn't end with a newline yet
+    line: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    /// Severity of standard output lines (`info`).
+    pub const INFO: u8 = 6;
+    /// Severity of standard error lines (`err`).
+    pub const ERR: u8 = 3;
+
+    /// Connects to `/dev/log`. Messages are logged with the `user` facility and the `severity`.
+    pub fn new(identifier: &str, severity: u8) -> io::Result<Self> {
+        const USER_FACILITY: u8 = 1;
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        let priority = USER_FACILITY * 8 + severity;
+        Ok(Self {
+            socket,
+            prefix: format!("<{priority}>{identifier}: "),
+            line: Vec::new(),
+        })
+    }
+
+    fn send_line(&mut self) -> io::Result<()> {
+        let mut message = self.prefix.clone().into_bytes();
+        message.append(&mut self.line);
+        self.socket.send(&message)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Write for Syslog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|byte| *byte == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(line) => {
+                    self.line.extend_from_slice(line);
+                    self.send_line()?;
+                }
+                None => self.line.extend_from_slice(line),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Syslog {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.send_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::RotatingFile;
+
+    #[test]
+    fn rotate_when_full() {
+        let dir = std::env::temp_dir().join(format!("lunatic-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.log");
+
+        let mut file = RotatingFile::new(&path, Some(8), 2).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.log.1")).unwrap(),
+            "three\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("out.log.2")).unwrap(),
+            "one\ntwo\n"
+        );
+        assert!(!dir.join("out.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    io::{IoSliceMut, Read},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+use wasi_common::{
+    file::{FdFlags, FileType},
+    Error, WasiFile,
+};
+
+#[derive(Default)]
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl PipeBuffer {
+    // Reads return either data or the end of file.
+    fn is_readable(&self) -> bool {
+        !self.data.is_empty() || self.closed
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    buffer: Mutex<PipeBuffer>,
+    // Wakes up readers waiting for data
+    available: Notify,
+}
+
+/// `StdinPipe` is the reading end of a pipe that can be used as the standard input of processes.
+///
+/// Reads wait until data is written to the pipe with a [`StdinPipeWriter`]. Once all writers
+/// are dropped, reads return the end of file.
+#[derive(Clone)]
+pub struct StdinPipe {
+    inner: Arc<Pipe>,
+}
+
+// A pipe without any writers is already closed.
+impl Default for StdinPipe {
+    fn default() -> Self {
+        let buffer = PipeBuffer {
+            data: VecDeque::new(),
+            closed: true,
+        };
+        Self {
+            inner: Arc::new(Pipe {
+                buffer: Mutex::new(buffer),
+                available: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Debug for StdinPipe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StdinPipe").finish_non_exhaustive()
+    }
+}
+
+impl StdinPipe {
+    // Waits until the pipe has data or is closed, without blocking the thread.
+    async fn readable(&self) {
+        loop {
+            // Writers notify all waiting readers, so the notification can't be missed between
+            // checking the buffer and waiting.
+            let notified = self.inner.available.notified();
+            if self.inner.buffer.lock().unwrap().is_readable() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    async fn read(&self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        loop {
+            self.readable().await;
+            let mut buffer = self.inner.buffer.lock().unwrap();
+            // Another reader could have taken the data in the meantime
+            if buffer.is_readable() {
+                return buffer.data.read_vectored(bufs);
+            }
+        }
+    }
+}
+
+/// `StdinPipeWriter` feeds data into a [`StdinPipe`]. The pipe is closed when it's dropped.
+pub struct StdinPipeWriter {
+    pipe: StdinPipe,
+}
+
+impl StdinPipeWriter {
+    pub fn new() -> Self {
+        Self {
+            pipe: StdinPipe {
+                inner: Arc::default(),
+            },
+        }
+    }
+
+    /// Returns the reading end of the pipe.
+    pub fn pipe(&self) -> StdinPipe {
+        self.pipe.clone()
+    }
+
+    /// Appends data to the pipe and wakes up waiting readers.
+    pub fn write(&self, data: &[u8]) {
+        let pipe = &self.pipe.inner;
+        pipe.buffer.lock().unwrap().data.extend(data);
+        pipe.available.notify_waiters();
+    }
+}
+
+impl Default for StdinPipeWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for StdinPipeWriter {
+    fn drop(&mut self) {
+        let pipe = &self.pipe.inner;
+        pipe.buffer.lock().unwrap().closed = true;
+        pipe.available.notify_waiters();
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiFile for StdinPipe {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::Pipe)
+    }
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::empty())
+    }
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let n = self.read(bufs).await?;
+        Ok(n.try_into()?)
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.inner.buffer.lock().unwrap().data.len() as u64)
+    }
+    fn isatty(&self) -> bool {
+        false
+    }
+    async fn readable(&self) -> Result<(), Error> {
+        StdinPipe::readable(self).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSliceMut;
+
+    use super::StdinPipeWriter;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn read_until_closed() {
+        let writer = StdinPipeWriter::new();
+        let pipe = writer.pipe();
+        writer.write(b"hello");
+
+        let runtime = runtime();
+        let mut buf = [0; 16];
+        let n = runtime
+            .block_on(pipe.read(&mut [IoSliceMut::new(&mut buf)]))
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        drop(writer);
+        let n = runtime
+            .block_on(pipe.read(&mut [IoSliceMut::new(&mut buf)]))
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    // Waiting readers let other tasks on the same thread write to the pipe
+    #[test]
+    fn read_waits_for_writer_on_same_thread() {
+        let writer = StdinPipeWriter::new();
+        let pipe = writer.pipe();
+        // Only runs once the reader waits
+        let runtime = runtime();
+        runtime.spawn(async move { writer.write(b"hello") });
+
+        let mut buf = [0; 16];
+        let n = runtime
+            .block_on(pipe.read(&mut [IoSliceMut::new(&mut buf)]))
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+    }
+}
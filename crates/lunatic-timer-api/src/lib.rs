@@ -7,8 +7,8 @@ use std::{
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
-use lunatic_common_api::IntoTrap;
-use lunatic_process::{state::ProcessState, Signal};
+use lunatic_common_api::{InstrumentedLinker, IntoTrap};
+use lunatic_process::{clock, state::ProcessState, Signal};
 use lunatic_process_api::ProcessCtx;
 use tokio::task::JoinHandle;
 use wasmtime::{Caller, Linker};
@@ -87,8 +87,10 @@ pub trait TimerCtx {
 pub fn register<T: ProcessState + ProcessCtx<T> + TimerCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::timer", "send_after", send_after)?;
     linker.func_wrap1_async("lunatic::timer", "cancel_timer", cancel_timer)?;
+    linker.func_wrap("lunatic::timer", "now_monotonic", now_monotonic)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -191,3 +193,11 @@ fn cancel_timer<T: ProcessState + TimerCtx + Send>(
         }
     })
 }
+
+// Returns the current time of a monotonic clock in milliseconds.
+//
+// The value is only meaningful compared to other values of the same clock, e.g. to compute
+// deadlines for `lunatic::message::receive_until`. All processes on the same node share the clock.
+fn now_monotonic<T>(_caller: Caller<T>) -> u64 {
+    clock::now_monotonic_ms()
+}
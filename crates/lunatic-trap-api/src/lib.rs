@@ -1,11 +1,12 @@
 use std::future::Future;
 
 use anyhow::Result;
-use lunatic_common_api::IntoTrap;
+use lunatic_common_api::{InstrumentedLinker, IntoTrap};
 use wasmtime::{Caller, Linker, Val};
 
 // Register the trap APIs to the linker
 pub fn register<T: Send + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap2_async("lunatic::trap", "catch", catch_trap::<T>)?;
     Ok(())
 }
@@ -44,7 +45,7 @@ fn catch_trap<T: Send>(
             .call_async(caller, &params, &mut result)
             .await;
         match execution_result {
-            Ok(()) => Ok(result.get(0).unwrap().i32().unwrap()),
+            Ok(()) => Ok(result.first().unwrap().i32().unwrap()),
             Err(_) => Ok(0),
         }
     })
@@ -1,7 +1,9 @@
+use lunatic_common_api::InstrumentedLinker;
 use wasmtime::Linker;
 
 /// Links the `version` APIs.
 pub fn register<T>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
     linker.func_wrap("lunatic::version", "major", major)?;
     linker.func_wrap("lunatic::version", "minor", minor)?;
     linker.func_wrap("lunatic::version", "patch", patch)?;
@@ -0,0 +1,42 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use cap_std::time;
+use wasi_common::clocks::{WasiClocks, WasiMonotonicClock, WasiSystemClock};
+
+/// A clock that never advances. All processes spawned from the same configuration observe the
+/// same time, which makes them reproducible.
+pub struct FixedClock {
+    system: SystemTime,
+    monotonic: Instant,
+}
+
+impl FixedClock {
+    /// Returns clocks that are fixed at `epoch_ms` milliseconds after the unix epoch.
+    pub fn clocks(epoch_ms: u64) -> WasiClocks {
+        let system = SystemTime::UNIX_EPOCH + Duration::from_millis(epoch_ms);
+        let monotonic = Instant::now();
+        WasiClocks::new()
+            .with_system(FixedClock { system, monotonic })
+            .with_monotonic(FixedClock { system, monotonic })
+    }
+}
+
+impl WasiSystemClock for FixedClock {
+    fn resolution(&self) -> time::Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: time::Duration) -> time::SystemTime {
+        time::SystemTime::from_std(self.system)
+    }
+}
+
+impl WasiMonotonicClock for FixedClock {
+    fn resolution(&self) -> time::Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: time::Duration) -> time::Instant {
+        time::Instant::from_std(self.monotonic)
+    }
+}
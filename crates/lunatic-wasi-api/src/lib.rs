@@ -1,34 +1,214 @@
-use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
-use lunatic_process::state::ProcessState;
-use lunatic_stdout_capture::StdoutCapture;
+mod fixed_clock;
+mod output;
+mod restricted;
+mod sched;
+mod secrets;
+mod snapshots;
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use hash_map_id::HashMapId;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_process::{env::Environment, state::ProcessState};
+use lunatic_stdout_capture::{StdinPipe, StdinPipeWriter, StdoutCapture};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use serde::{Deserialize, Serialize};
+use wasi_common::{pipe::ReadPipe, table::Table, RngCore};
 use wasmtime::{Caller, Linker};
-use wasmtime_wasi::{ambient_authority, Dir, WasiCtx, WasiCtxBuilder};
+use wasmtime_wasi::{ambient_authority, clocks_ctx, random_ctx, stdio, Dir, WasiCtx};
+
+pub use fixed_clock::FixedClock;
+pub use output::OutputSink;
+pub use restricted::{OpenFiles, Quota, RestrictedDir, RestrictedFile};
+pub use sched::LunaticSched;
+pub use secrets::{set_secrets_provider, EnvSecretsProvider, SecretsProvider, SECRET_ENV_PREFIX};
+
+/// Opens the preopened directory as read-only.
+pub const PREOPEN_READ_ONLY: u32 = 1;
+
+/// A directory that processes get access to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PreopenedDir {
+    /// Path under which the guest sees the directory
+    pub path: String,
+    /// Path of the directory on the host
+    pub resolved_path: String,
+    /// Forbid any changes inside the directory
+    pub read_only: bool,
+    /// Maximum number of bytes each process can add to the directory
+    pub quota: Option<u64>,
+}
+
+impl PreopenedDir {
+    /// Returns true if processes can modify the directory without any limits.
+    pub fn is_unrestricted(&self) -> bool {
+        !self.read_only && self.quota.is_none()
+    }
+}
+
+/// Filesystem limits of a process, shared by all of its preopened directories.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct FsLimits {
+    /// Maximum number of files and directories the process can have open at the same time
+    pub max_open_files: Option<u64>,
+    /// Maximum number of bytes the process can write to all preopened directories
+    pub write_quota: Option<u64>,
+}
+
+impl FsLimits {
+    fn is_unlimited(&self) -> bool {
+        self.max_open_files.is_none() && self.write_quota.is_none()
+    }
+}
+
+/// Where processes read their standard input from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum StdinSource {
+    /// Read from the stdin of the runtime, usually the terminal.
+    #[default]
+    Inherit,
+    /// Reads always return the end of file.
+    Closed,
+    /// Read the data written to a pipe by other processes. Processes spawned on other nodes
+    /// can't reach the pipe and see it as closed.
+    Pipe(#[serde(skip)] StdinPipe),
+}
+
+/// Selects the standard output stream in `config_set_output_*` host functions.
+pub const OUTPUT_STDOUT: u32 = 1;
+/// Selects the standard error stream in `config_set_output_*` host functions.
+pub const OUTPUT_STDERR: u32 = 2;
+
+pub type StdinPipeResources = HashMapId<Arc<StdinPipeWriter>>;
 
 /// Create a `WasiCtx` from configuration settings.
+///
+/// If `fixed_clock` is set, all clocks are fixed to the given milliseconds after the unix epoch.
+/// If `random_seed` is set, random numbers are generated from the seed. Processes created with
+/// the same settings observe the same time and random numbers.
+///
+/// `secrets` are `(variable name, secret key)` pairs that are resolved with the
+/// [`SecretsProvider`] and added to the environment variables.
+///
+/// The `fs_limits` apply to all preopened directories together.
+///
+/// Collector processes of the `stdout` and `stderr` sinks are looked up in the `environment`.
+#[allow(clippy::too_many_arguments)]
 pub fn build_wasi(
     args: Option<&Vec<String>>,
     envs: Option<&Vec<(String, String)>>,
-    dirs: &[(String, String)],
+    secrets: &[(String, String)],
+    dirs: &[PreopenedDir],
+    fs_limits: FsLimits,
+    stdin: &StdinSource,
+    stdout: &OutputSink,
+    stderr: &OutputSink,
+    fixed_clock: Option<u64>,
+    random_seed: Option<u64>,
+    environment: &dyn Environment,
 ) -> Result<WasiCtx> {
-    let mut wasi = WasiCtxBuilder::new().inherit_stdio();
+    let random: Box<dyn RngCore + Send + Sync> = match random_seed {
+        Some(seed) => Box::new(ChaCha20Rng::seed_from_u64(seed)),
+        None => random_ctx(),
+    };
+    let clocks = match fixed_clock {
+        Some(epoch_ms) => FixedClock::clocks(epoch_ms),
+        None => clocks_ctx(),
+    };
+    let mut wasi = WasiCtx::new(random, clocks, Box::new(LunaticSched), Table::new());
+    match stdout.open(false, environment)? {
+        Some(sink) => wasi.set_stdout(sink),
+        None => wasi.set_stdout(Box::new(stdio::stdout())),
+    }
+    match stderr.open(true, environment)? {
+        Some(sink) => wasi.set_stderr(sink),
+        None => wasi.set_stderr(Box::new(stdio::stderr())),
+    }
+    match stdin {
+        StdinSource::Inherit => wasi.set_stdin(Box::new(stdio::stdin())),
+        StdinSource::Closed => wasi.set_stdin(Box::new(ReadPipe::new(std::io::empty()))),
+        StdinSource::Pipe(pipe) => wasi.set_stdin(Box::new(pipe.clone())),
+    }
     if let Some(envs) = envs {
-        wasi = wasi.envs(envs)?;
+        for (key, value) in envs {
+            wasi.push_env(key, value)?;
+        }
+    }
+    for (key, value) in secrets::resolve_secrets(secrets)? {
+        wasi.push_env(&key, &value)?;
     }
     if let Some(args) = args {
-        wasi = wasi.args(args)?;
+        for arg in args {
+            wasi.push_arg(arg)?;
+        }
     }
-    for (preopen_dir_path, resolved_path) in dirs {
-        let preopen_dir = Dir::open_ambient_dir(resolved_path, ambient_authority())?;
-        wasi = wasi.preopened_dir(preopen_dir, preopen_dir_path)?;
+    // Files that already exist in writable directories with a quota are counted, so that
+    // removing them frees up the quota
+    let existing: Vec<u64> = dirs
+        .iter()
+        .map(|dir| {
+            let has_quota = dir.quota.is_some() || fs_limits.write_quota.is_some();
+            if has_quota && !dir.read_only {
+                restricted::dir_size(Path::new(&dir.resolved_path))
+            } else {
+                0
+            }
+        })
+        .collect();
+    let write_quota = fs_limits
+        .write_quota
+        .map(|limit| Arc::new(Quota::new(limit).with_existing(existing.iter().sum())));
+    let open_files = fs_limits
+        .max_open_files
+        .map(|limit| Arc::new(OpenFiles::new(limit)));
+    for (dir, existing) in dirs.iter().zip(existing) {
+        let preopen_dir = Dir::open_ambient_dir(&dir.resolved_path, ambient_authority())?;
+        let preopen_dir = Box::new(wasmtime_wasi::dir::Dir::from_cap_std(preopen_dir));
+        if dir.is_unrestricted() && fs_limits.is_unlimited() {
+            wasi.push_preopened_dir(preopen_dir, &dir.path)?;
+        } else {
+            let quota = match (dir.quota, &write_quota) {
+                (Some(limit), Some(parent)) => Some(Arc::new(
+                    Quota::with_parent(limit, parent.clone()).with_existing(existing),
+                )),
+                (Some(limit), None) => Some(Arc::new(Quota::new(limit).with_existing(existing))),
+                (None, write_quota) => write_quota.clone(),
+            };
+            let restricted =
+                RestrictedDir::new(preopen_dir, dir.read_only, quota, open_files.clone());
+            wasi.push_preopened_dir(Box::new(restricted), &dir.path)?;
+        }
     }
-    Ok(wasi.build())
+    Ok(wasi)
 }
 
 pub trait LunaticWasiConfigCtx {
     fn add_environment_variable(&mut self, key: String, value: String);
+    /// Adds the runtime's environment variables matching the filter, without checking if they
+    /// are exposed.
+    fn inherit_environment(&mut self, filter: &str);
+    fn add_secret(&mut self, name: String, key: String);
+    /// Allows processes spawned with the configuration to pass the runtime's environment variables
+    /// matching the filter on to the processes they spawn.
+    fn expose_environment(&mut self, filter: String);
+    /// Allows processes spawned with the configuration to pass the secret on to the processes
+    /// they spawn.
+    fn expose_secret(&mut self, key: String);
+    /// Returns the runtime's environment variables matching the filter that processes with this
+    /// configuration can pass on, or an error if they can't pass on any.
+    fn exposed_environment(&self, filter: &str) -> Result<Vec<(String, String)>, String>;
+    /// Returns an error if processes with this configuration can't pass on the secret.
+    fn can_expose_secret(&self, key: &str) -> Result<(), String>;
     fn add_command_line_argument(&mut self, argument: String);
-    fn preopen_dir(&mut self, dir: String);
+    fn preopen_dir(&mut self, dir: String, read_only: bool, quota: Option<u64>);
+    fn set_stdin(&mut self, stdin: StdinSource);
+    fn set_stdout_sink(&mut self, sink: OutputSink);
+    fn set_stderr_sink(&mut self, sink: OutputSink);
+    fn set_fixed_clock(&mut self, epoch_ms: u64);
+    fn set_random_seed(&mut self, seed: u64);
+    fn set_max_open_files(&mut self, limit: u64);
+    fn set_fs_write_quota(&mut self, bytes: u64);
 }
 
 pub trait LunaticWasiCtx {
@@ -38,6 +218,11 @@ pub trait LunaticWasiCtx {
     fn get_stdout(&self) -> Option<&StdoutCapture>;
     fn set_stderr(&mut self, stderr: StdoutCapture);
     fn get_stderr(&self) -> Option<&StdoutCapture>;
+    fn stdin_pipe_resources(&self) -> &StdinPipeResources;
+    fn stdin_pipe_resources_mut(&mut self) -> &mut StdinPipeResources;
+    // Output files are opened by the host and need to pass the same filesystem permission check
+    // as other file accesses of the process.
+    fn can_access_fs_location(&self, path: &Path) -> Result<(), String>;
 }
 
 // Register WASI APIs to the linker
@@ -47,10 +232,11 @@ where
     T::Config: LunaticWasiConfigCtx,
 {
     // Register all wasi host functions
-    wasmtime_wasi::sync::snapshots::preview_1::add_wasi_snapshot_preview1_to_linker(
-        linker,
-        |ctx| ctx.wasi_mut(),
-    )?;
+    snapshots::preview_1::add_wasi_snapshot_preview1_to_linker(linker, |ctx| ctx.wasi_mut())?;
+    // Older toolchains still target the `wasi_unstable` snapshot
+    snapshots::preview_0::add_wasi_unstable_to_linker(linker, |ctx| ctx.wasi_mut())?;
+
+    let mut linker = InstrumentedLinker::new(linker);
 
     // Register host functions to configure wasi
     linker.func_wrap(
@@ -58,12 +244,53 @@ where
         "config_add_environment_variable",
         add_environment_variable,
     )?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_inherit_environment",
+        inherit_environment,
+    )?;
+    linker.func_wrap("lunatic::wasi", "config_add_secret", add_secret)?;
     linker.func_wrap(
         "lunatic::wasi",
         "config_add_command_line_argument",
         add_command_line_argument,
     )?;
     linker.func_wrap("lunatic::wasi", "config_preopen_dir", preopen_dir)?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_preopen_dir_with_options",
+        preopen_dir_with_options,
+    )?;
+    linker.func_wrap("lunatic::wasi", "config_set_stdin", set_stdin)?;
+    linker.func_wrap("lunatic::wasi", "config_set_stdin_pipe", set_stdin_pipe)?;
+    linker.func_wrap("lunatic::wasi", "config_set_output_file", set_output_file)?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_set_output_syslog",
+        set_output_syslog,
+    )?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_set_output_process",
+        set_output_process,
+    )?;
+    linker.func_wrap("lunatic::wasi", "config_set_fixed_clock", set_fixed_clock)?;
+    linker.func_wrap("lunatic::wasi", "config_set_random_seed", set_random_seed)?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_set_max_open_files",
+        set_max_open_files,
+    )?;
+    linker.func_wrap(
+        "lunatic::wasi",
+        "config_set_fs_write_quota",
+        set_fs_write_quota,
+    )?;
+
+    // Register host functions to feed stdin pipes
+    linker.func_wrap("lunatic::wasi", "create_stdin_pipe", create_stdin_pipe)?;
+    linker.func_wrap("lunatic::wasi", "stdin_pipe_write", stdin_pipe_write)?;
+    linker.func_wrap("lunatic::wasi", "drop_stdin_pipe", drop_stdin_pipe)?;
 
     Ok(())
 }
@@ -111,6 +338,101 @@ where
     Ok(())
 }
 
+// Adds all environment variables of the runtime with a name matching the **filter** to the
+// configuration. The filter can contain `*` wildcards, e.g. `APP_*`. Only variables the runtime
+// exposed to the calling process are inherited, variables holding secrets (`LUNATIC_SECRET_*`)
+// never are.
+//
+// Traps:
+// * If the runtime didn't expose any environment variables to the calling process.
+// * If the config ID doesn't exist.
+// * If the filter string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn inherit_environment<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    filter_ptr: u32,
+    filter_len: u32,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let filter_str = memory
+        .data(&caller)
+        .get(filter_ptr as usize..(filter_ptr + filter_len) as usize)
+        .or_trap("lunatic::wasi::config_inherit_environment")?;
+    let filter =
+        std::str::from_utf8(filter_str).or_trap("lunatic::wasi::config_inherit_environment")?;
+    let variables = caller
+        .data()
+        .config()
+        .exposed_environment(filter)
+        .or_trap("lunatic::wasi::config_inherit_environment")?;
+
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::config_inherit_environment: Config ID doesn't exist")?;
+    for (key, value) in variables {
+        config.add_environment_variable(key, value);
+    }
+    Ok(())
+}
+
+// Adds the environment variable **name** to the configuration, with the value of the secret
+// **key**. The value is looked up by the runtime when a process is spawned and is never visible
+// to the process creating the configuration. Spawning fails if the secret doesn't exist.
+//
+// Traps:
+// * If the runtime didn't expose the secret to the calling process.
+// * If the config ID doesn't exist.
+// * If the name or key string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn add_secret<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    name_ptr: u32,
+    name_len: u32,
+    key_ptr: u32,
+    key_len: u32,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let name_str = memory
+        .data(&caller)
+        .get(name_ptr as usize..(name_ptr + name_len) as usize)
+        .or_trap("lunatic::wasi::config_add_secret")?;
+    let name = std::str::from_utf8(name_str)
+        .or_trap("lunatic::wasi::config_add_secret")?
+        .to_string();
+    let key_str = memory
+        .data(&caller)
+        .get(key_ptr as usize..(key_ptr + key_len) as usize)
+        .or_trap("lunatic::wasi::config_add_secret")?;
+    let key = std::str::from_utf8(key_str)
+        .or_trap("lunatic::wasi::config_add_secret")?
+        .to_string();
+    caller
+        .data()
+        .config()
+        .can_expose_secret(&key)
+        .or_trap("lunatic::wasi::config_add_secret")?;
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::config_add_secret: Config ID doesn't exist")?
+        .add_secret(name, key);
+    Ok(())
+}
+
 // Adds command line argument to a configuration.
 //
 // Traps:
@@ -170,6 +492,398 @@ where
         .config_resources_mut()
         .get_mut(config_id)
         .or_trap("lunatic::wasi::preopen_dir: Config ID doesn't exist")?
-        .preopen_dir(dir);
+        .preopen_dir(dir, false, None);
+    Ok(())
+}
+
+// Mark a directory as preopened in the configuration, with restricted access.
+//
+// If **flags** contains `PREOPEN_READ_ONLY` (0x1), processes can't modify anything inside the
+// directory. If **quota** is different from `u64::MAX`, each process can only add that many bytes
+// to the directory. Writes over the quota fail with `EDQUOT`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the directory string is not a valid utf8 string.
+// * If **flags** contains unknown bits.
+// * If any of the memory slices falls outside the memory.
+fn preopen_dir_with_options<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    dir_ptr: u32,
+    dir_len: u32,
+    flags: u32,
+    quota: u64,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    if flags & !PREOPEN_READ_ONLY != 0 {
+        return Err(anyhow!(
+            "lunatic::wasi::preopen_dir_with_options: Unknown flags {flags:#x}"
+        ));
+    }
+    let memory = get_memory(&mut caller)?;
+    let dir_str = memory
+        .data(&caller)
+        .get(dir_ptr as usize..(dir_ptr + dir_len) as usize)
+        .or_trap("lunatic::wasi::preopen_dir_with_options")?;
+    let dir = std::str::from_utf8(dir_str)
+        .or_trap("lunatic::wasi::preopen_dir_with_options")?
+        .to_string();
+    let read_only = flags & PREOPEN_READ_ONLY != 0;
+    let quota = match quota {
+        u64::MAX => None,
+        quota => Some(quota),
+    };
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::preopen_dir_with_options: Config ID doesn't exist")?
+        .preopen_dir(dir, read_only, quota);
+    Ok(())
+}
+
+// Sets the source of the standard input for processes spawned from the configuration.
+//
+// * 0 - Inherit the stdin of the runtime (default).
+// * 1 - Closed, reads return the end of file.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the source is unknown.
+fn set_stdin<T>(mut caller: Caller<T>, config_id: u64, source: u32) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let source = match source {
+        0 => StdinSource::Inherit,
+        1 => StdinSource::Closed,
+        source => return Err(anyhow!("lunatic::wasi::set_stdin: Unknown source {source}")),
+    };
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_stdin: Config ID doesn't exist")?
+        .set_stdin(source);
+    Ok(())
+}
+
+// Processes spawned from the configuration will read their standard input from the pipe.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the stdin pipe ID doesn't exist.
+fn set_stdin_pipe<T>(mut caller: Caller<T>, config_id: u64, pipe_id: u64) -> Result<()>
+where
+    T: ProcessState + LunaticWasiCtx,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let pipe = caller
+        .data()
+        .stdin_pipe_resources()
+        .get(pipe_id)
+        .or_trap("lunatic::wasi::set_stdin_pipe")?
+        .pipe();
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_stdin_pipe: Config ID doesn't exist")?
+        .set_stdin(StdinSource::Pipe(pipe));
+    Ok(())
+}
+
+// Sets the sink of the standard output (**stream** = 1) or error (**stream** = 2) stream.
+fn set_output_sink<T>(
+    caller: &mut Caller<T>,
+    config_id: u64,
+    stream: u32,
+    sink: OutputSink,
+    function: &str,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap(format!(
+            "lunatic::wasi::{function}: Config ID doesn't exist"
+        ))?;
+    match stream {
+        OUTPUT_STDOUT => config.set_stdout_sink(sink),
+        OUTPUT_STDERR => config.set_stderr_sink(sink),
+        stream => {
+            return Err(anyhow!(
+                "lunatic::wasi::{function}: Unknown stream {stream}"
+            ))
+        }
+    }
+    Ok(())
+}
+
+// Processes spawned from the configuration will append the **stream** (1 = stdout, 2 = stderr)
+// to the file at **path** on the host.
+//
+// If **max_size** is different from `u64::MAX`, the file is rotated once it would grow over
+// **max_size** bytes. Rotated files get the suffixes `.1`, `.2`, ... and only **max_files** of
+// them are kept.
+//
+// The calling process needs to have access to the file, because it's written, rotated and
+// removed by the host.
+//
+// Returns:
+// * 0 on success.
+// * 1 if the calling process doesn't have access to the file.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the stream is not known.
+// * If the path string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn set_output_file<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    stream: u32,
+    path_ptr: u32,
+    path_len: u32,
+    max_size: u64,
+    max_files: u32,
+) -> Result<u32>
+where
+    T: ProcessState + LunaticWasiCtx,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let path_str = memory
+        .data(&caller)
+        .get(path_ptr as usize..(path_ptr + path_len) as usize)
+        .or_trap("lunatic::wasi::config_set_output_file")?;
+    let path = std::str::from_utf8(path_str)
+        .or_trap("lunatic::wasi::config_set_output_file")?
+        .to_string();
+    if caller
+        .data()
+        .can_access_fs_location(Path::new(&path))
+        .is_err()
+    {
+        return Ok(1);
+    }
+    let sink = OutputSink::File {
+        path,
+        max_size: (max_size != u64::MAX).then_some(max_size),
+        max_files,
+    };
+    set_output_sink(
+        &mut caller,
+        config_id,
+        stream,
+        sink,
+        "config_set_output_file",
+    )?;
+    Ok(0)
+}
+
+// Processes spawned from the configuration will send each line of the **stream** (1 = stdout,
+// 2 = stderr) to the local syslog daemon, tagged with the **identifier**. Standard error lines
+// are logged with a higher severity.
+//
+// Spawning fails on platforms without syslog.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the stream is not known.
+// * If the identifier string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn set_output_syslog<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    stream: u32,
+    identifier_ptr: u32,
+    identifier_len: u32,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let identifier_str = memory
+        .data(&caller)
+        .get(identifier_ptr as usize..(identifier_ptr + identifier_len) as usize)
+        .or_trap("lunatic::wasi::config_set_output_syslog")?;
+    let identifier = std::str::from_utf8(identifier_str)
+        .or_trap("lunatic::wasi::config_set_output_syslog")?
+        .to_string();
+    let sink = OutputSink::Syslog { identifier };
+    set_output_sink(
+        &mut caller,
+        config_id,
+        stream,
+        sink,
+        "config_set_output_syslog",
+    )
+}
+
+// Processes spawned from the configuration will forward every write to the **stream**
+// (1 = stdout, 2 = stderr) as a message with the **tag** to the process **process_id**. A tag of
+// 0 means no tag.
+//
+// Spawning fails if the collector process doesn't exist on the node.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the stream is not known.
+fn set_output_process<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    stream: u32,
+    process_id: u64,
+    tag: i64,
+) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let sink = OutputSink::Process {
+        process_id,
+        tag: (tag != 0).then_some(tag),
+    };
+    set_output_sink(
+        &mut caller,
+        config_id,
+        stream,
+        sink,
+        "config_set_output_process",
+    )
+}
+
+// Fixes the WASI clocks of processes spawned from the configuration to **epoch_ms** milliseconds
+// after the unix epoch. The time doesn't advance while the processes are running.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn set_fixed_clock<T>(mut caller: Caller<T>, config_id: u64, epoch_ms: u64) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_fixed_clock: Config ID doesn't exist")?
+        .set_fixed_clock(epoch_ms);
+    Ok(())
+}
+
+// Processes spawned from the configuration generate random numbers from the **seed**, so that
+// all of them receive the same sequence.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn set_random_seed<T>(mut caller: Caller<T>, config_id: u64, seed: u64) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_random_seed: Config ID doesn't exist")?
+        .set_random_seed(seed);
+    Ok(())
+}
+
+// Limits how many files and directories processes spawned from the configuration can have open
+// at the same time. Opening more files fails with `EMFILE`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn set_max_open_files<T>(mut caller: Caller<T>, config_id: u64, limit: u64) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_max_open_files: Config ID doesn't exist")?
+        .set_max_open_files(limit);
+    Ok(())
+}
+
+// Limits how many bytes each process spawned from the configuration can write to all of its
+// preopened directories together. Writes that exceed the quota fail with `EDQUOT`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn set_fs_write_quota<T>(mut caller: Caller<T>, config_id: u64, bytes: u64) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::set_fs_write_quota: Config ID doesn't exist")?
+        .set_fs_write_quota(bytes);
+    Ok(())
+}
+
+// Creates a new stdin pipe and returns its ID. Processes reading from the pipe receive the end of
+// file once all references to it are dropped.
+fn create_stdin_pipe<T: LunaticWasiCtx>(mut caller: Caller<T>) -> u64 {
+    caller
+        .data_mut()
+        .stdin_pipe_resources_mut()
+        .add(Arc::new(StdinPipeWriter::new()))
+}
+
+// Writes data to the stdin pipe. The data is buffered until the reading processes consume it.
+//
+// Traps:
+// * If the stdin pipe ID doesn't exist.
+// * If any of the memory slices falls outside the memory.
+fn stdin_pipe_write<T: LunaticWasiCtx>(
+    mut caller: Caller<T>,
+    pipe_id: u64,
+    data_ptr: u32,
+    data_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let data = memory
+        .data(&caller)
+        .get(data_ptr as usize..(data_ptr + data_len) as usize)
+        .or_trap("lunatic::wasi::stdin_pipe_write")?;
+    caller
+        .data()
+        .stdin_pipe_resources()
+        .get(pipe_id)
+        .or_trap("lunatic::wasi::stdin_pipe_write")?
+        .write(data);
+    Ok(())
+}
+
+// Drops the stdin pipe resource.
+//
+// Traps:
+// * If the stdin pipe ID doesn't exist.
+fn drop_stdin_pipe<T: LunaticWasiCtx>(mut caller: Caller<T>, pipe_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .stdin_pipe_resources_mut()
+        .remove(pipe_id)
+        .or_trap("lunatic::wasi::drop_stdin_pipe")?;
     Ok(())
 }
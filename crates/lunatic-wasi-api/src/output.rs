@@ -0,0 +1,92 @@
+use std::{io::Write, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use lunatic_process::{
+    env::Environment,
+    message::{DataMessage, Message},
+    Process, Signal,
+};
+use lunatic_stdout_capture::RotatingFile;
+use serde::{Deserialize, Serialize};
+use wasi_common::{pipe::WritePipe, WasiFile};
+
+/// Where processes write their standard output or error to.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum OutputSink {
+    /// Write to the stdout or stderr of the runtime, or to the stream captured by the parent.
+    #[default]
+    Inherit,
+    /// Append to a file on the host, rotating it once it grows over `max_size` bytes.
+    File {
+        path: String,
+        max_size: Option<u64>,
+        max_files: u32,
+    },
+    /// Send each line to the local syslog daemon or journald.
+    Syslog { identifier: String },
+    /// Forward each write as a message to a collector process. The process must be running on
+    /// the same node as the processes spawned from the configuration.
+    Process { process_id: u64, tag: Option<i64> },
+}
+
+impl OutputSink {
+    /// Opens the sink, or returns `None` if the output is inherited.
+    ///
+    /// `is_stderr` selects the severity of syslog messages.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn open(
+        &self,
+        is_stderr: bool,
+        environment: &dyn Environment,
+    ) -> Result<Option<Box<dyn WasiFile>>> {
+        let file: Box<dyn WasiFile> = match self {
+            OutputSink::Inherit => return Ok(None),
+            OutputSink::File {
+                path,
+                max_size,
+                max_files,
+            } => {
+                let file = RotatingFile::new(path, *max_size, *max_files)
+                    .map_err(|error| anyhow!("Failed to open output file '{path}': {error}"))?;
+                Box::new(WritePipe::new(file))
+            }
+            #[cfg(unix)]
+            OutputSink::Syslog { identifier } => {
+                use lunatic_stdout_capture::Syslog;
+                let severity = if is_stderr { Syslog::ERR } else { Syslog::INFO };
+                let syslog = Syslog::new(identifier, severity)
+                    .map_err(|error| anyhow!("Failed to connect to syslog: {error}"))?;
+                Box::new(WritePipe::new(syslog))
+            }
+            #[cfg(not(unix))]
+            OutputSink::Syslog { .. } => {
+                return Err(anyhow!("Syslog output is only supported on unix"));
+            }
+            OutputSink::Process { process_id, tag } => {
+                let process = environment.get_process(*process_id).ok_or_else(|| {
+                    anyhow!("Output collector process {process_id} doesn't exist")
+                })?;
+                Box::new(WritePipe::new(ProcessOutput { process, tag: *tag }))
+            }
+        };
+        Ok(Some(file))
+    }
+}
+
+// Sends every write as a data message to the collector process.
+struct ProcessOutput {
+    process: Arc<dyn Process>,
+    tag: Option<i64>,
+}
+
+impl Write for ProcessOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let message = DataMessage::new_from_vec(self.tag, buf.to_vec());
+        self.process.send(Signal::Message(Message::Data(message)));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
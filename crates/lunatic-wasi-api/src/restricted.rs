@@ -0,0 +1,544 @@
+use std::{
+    any::Any,
+    io::{IoSlice, IoSliceMut, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use wasi_common::{
+    dir::{OpenResult, ReaddirCursor, ReaddirEntity},
+    file::{Advice, FdFlags, FileType, Filestat, OFlags},
+    snapshots::preview_1::types::Errno,
+    Error, ErrorExt, SystemTimeSpec, WasiDir, WasiFile,
+};
+
+/// Limits how many bytes a process can add to a preopened directory.
+///
+/// Only the growth of files opened through the directory is counted, removing or truncating
+/// files frees up the quota again. Files that existed before are counted with
+/// [`with_existing`](Quota::with_existing), so that removing them frees up the quota the same
+/// way. A quota can be nested inside a parent quota, e.g. the quota of a directory inside the
+/// quota of the whole process.
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+    parent: Option<Arc<Quota>>,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Quota {
+            limit,
+            used: AtomicU64::new(0),
+            parent: None,
+        }
+    }
+
+    /// Creates a quota that also counts against the `parent` quota.
+    pub fn with_parent(limit: u64, parent: Arc<Quota>) -> Self {
+        Quota {
+            limit,
+            used: AtomicU64::new(0),
+            parent: Some(parent),
+        }
+    }
+
+    /// Counts `bytes` of existing files as used, in addition to the `limit` that can be added.
+    pub fn with_existing(mut self, bytes: u64) -> Self {
+        self.limit = self.limit.saturating_add(bytes);
+        self.used = AtomicU64::new(bytes);
+        self
+    }
+
+    // Reserves `bytes` of the quota or fails with `EDQUOT` if there is not enough left.
+    fn reserve(&self, bytes: u64) -> Result<(), Error> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|used| *used <= self.limit)
+            })
+            .map_err(|_| Error::from(Errno::Dquot))?;
+        if let Some(parent) = &self.parent {
+            if let Err(error) = parent.reserve(bytes) {
+                self.release_own(bytes);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        self.release_own(bytes);
+        if let Some(parent) = &self.parent {
+            parent.release(bytes);
+        }
+    }
+
+    fn release_own(&self, bytes: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+}
+
+/// Returns the size of all files in the directory and its sub-directories, without following
+/// symlinks. Files with multiple hard links are counted once per link.
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Limits how many files and directories a process can have open at the same time.
+pub struct OpenFiles {
+    limit: u64,
+    open: AtomicU64,
+}
+
+impl OpenFiles {
+    pub fn new(limit: u64) -> Self {
+        OpenFiles {
+            limit,
+            open: AtomicU64::new(0),
+        }
+    }
+
+    // Counts a newly opened file or fails with `EMFILE` if the limit is reached.
+    fn acquire(self: &Arc<Self>) -> Result<OpenFile, Error> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                Some(open + 1).filter(|open| *open <= self.limit)
+            })
+            .map_err(|_| Error::from(Errno::Mfile))?;
+        Ok(OpenFile(self.clone()))
+    }
+}
+
+// Releases its slot in the open files limit once the file is closed.
+struct OpenFile(Arc<OpenFiles>);
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A preopened directory that can be read-only, limited by a [`Quota`] or by the number of
+/// [`OpenFiles`].
+///
+/// All files and sub-directories opened through it are restricted in the same way.
+pub struct RestrictedDir {
+    inner: Box<dyn WasiDir>,
+    read_only: bool,
+    quota: Option<Arc<Quota>>,
+    open_files: Option<Arc<OpenFiles>>,
+    _open_file: Option<OpenFile>,
+}
+
+impl RestrictedDir {
+    pub fn new(
+        inner: Box<dyn WasiDir>,
+        read_only: bool,
+        quota: Option<Arc<Quota>>,
+        open_files: Option<Arc<OpenFiles>>,
+    ) -> Self {
+        RestrictedDir {
+            inner,
+            read_only,
+            quota,
+            open_files,
+            _open_file: None,
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::perm())
+        } else {
+            Ok(())
+        }
+    }
+
+    // The inner directories only work with their own type, so other directories need to be
+    // unwrapped before they are passed on.
+    fn unwrap_other<'a>(&self, other: &'a dyn WasiDir) -> Result<&'a dyn WasiDir, Error> {
+        let other = other
+            .as_any()
+            .downcast_ref::<RestrictedDir>()
+            .ok_or_else(Error::perm)?;
+        other.check_writable()?;
+        // Moving files between directories with different quotas would escape the limit.
+        match (&self.quota, &other.quota) {
+            (None, None) => Ok(other.inner.as_ref()),
+            (Some(a), Some(b)) if Arc::ptr_eq(a, b) => Ok(other.inner.as_ref()),
+            _ => Err(Error::perm()),
+        }
+    }
+
+    // Returns the size the file occupies in the quota. Files with other hard links keep their
+    // data when a link is removed, so they count as empty.
+    async fn unlinked_size(&self, path: &str) -> u64 {
+        self.inner
+            .get_path_filestat(path, false)
+            .await
+            .map(|stat| if stat.nlink > 1 { 0 } else { stat.size })
+            .unwrap_or(0)
+    }
+
+    async fn file_size(&self, path: &str) -> u64 {
+        self.inner
+            .get_path_filestat(path, false)
+            .await
+            .map(|stat| stat.size)
+            .unwrap_or(0)
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiDir for RestrictedDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            self.check_writable()?;
+        }
+        // Truncating a file frees up the quota
+        let truncated = match (&self.quota, oflags.contains(OFlags::TRUNCATE)) {
+            (Some(_), true) => self.file_size(path).await,
+            _ => 0,
+        };
+        let open_file = match &self.open_files {
+            Some(open_files) => Some(open_files.acquire()?),
+            None => None,
+        };
+        let result = self
+            .inner
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .await?;
+        if let Some(quota) = &self.quota {
+            quota.release(truncated);
+        }
+        Ok(match result {
+            OpenResult::File(file) => OpenResult::File(Box::new(RestrictedFile {
+                inner: file,
+                read_only: self.read_only,
+                quota: self.quota.clone(),
+                _open_file: open_file,
+            })),
+            OpenResult::Dir(dir) => OpenResult::Dir(Box::new(RestrictedDir {
+                inner: dir,
+                read_only: self.read_only,
+                quota: self.quota.clone(),
+                open_files: self.open_files.clone(),
+                _open_file: open_file,
+            })),
+        })
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner.create_dir(path).await
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.inner.readdir(cursor).await
+    }
+
+    async fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner.symlink(old_path, new_path).await
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner.remove_dir(path).await
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        let size = match &self.quota {
+            Some(_) => self.unlinked_size(path).await,
+            None => 0,
+        };
+        self.inner.unlink_file(path).await?;
+        if let Some(quota) = &self.quota {
+            quota.release(size);
+        }
+        Ok(())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.inner.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.inner.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.inner.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        let dest_dir = self.unwrap_other(dest_dir)?;
+        self.inner.rename(path, dest_dir, dest_path).await
+    }
+
+    async fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        // Removing the original file would free up the quota while the data stays on disk.
+        if self.quota.is_some() {
+            return Err(Error::perm());
+        }
+        let target_dir = self.unwrap_other(target_dir)?;
+        self.inner.hard_link(path, target_dir, target_path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner
+            .set_times(path, atime, mtime, follow_symlinks)
+            .await
+    }
+}
+
+/// A file opened through a [`RestrictedDir`].
+///
+/// Regular files are not pollable, so `poll_oneoff` is not supported on them.
+pub struct RestrictedFile {
+    inner: Box<dyn WasiFile>,
+    read_only: bool,
+    quota: Option<Arc<Quota>>,
+    _open_file: Option<OpenFile>,
+}
+
+impl RestrictedFile {
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::perm())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn size(&self) -> Result<u64, Error> {
+        Ok(self.inner.get_filestat().await?.size)
+    }
+
+    // Runs a write operation that can grow the file up to `max_size` and only charges the quota
+    // for the actual growth.
+    async fn grow<F>(&self, max_size: u64, write: F) -> Result<u64, Error>
+    where
+        F: std::future::Future<Output = Result<u64, Error>>,
+    {
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => return write.await,
+        };
+        let size = self.size().await?;
+        let reserved = max_size.saturating_sub(size);
+        quota.reserve(reserved)?;
+        let result = write.await;
+        let new_size = self.size().await.unwrap_or(size + reserved);
+        quota.release(reserved.saturating_sub(new_size.saturating_sub(size)));
+        result
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiFile for RestrictedFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        self.inner.get_filetype().await
+    }
+    fn isatty(&self) -> bool {
+        self.inner.isatty()
+    }
+    async fn datasync(&self) -> Result<(), Error> {
+        self.inner.datasync().await
+    }
+    async fn sync(&self) -> Result<(), Error> {
+        self.inner.sync().await
+    }
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        self.inner.get_fdflags().await
+    }
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.inner.set_fdflags(flags).await
+    }
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.inner.get_filestat().await
+    }
+    async fn set_filestat_size(&self, size: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        let old_size = match &self.quota {
+            Some(_) => self.size().await?,
+            None => 0,
+        };
+        self.grow(size, async {
+            self.inner.set_filestat_size(size).await?;
+            Ok(0)
+        })
+        .await?;
+        if let Some(quota) = &self.quota {
+            quota.release(old_size.saturating_sub(size));
+        }
+        Ok(())
+    }
+    async fn advise(&self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.inner.advise(offset, len, advice).await
+    }
+    async fn allocate(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        self.grow(offset.saturating_add(len), async {
+            self.inner.allocate(offset, len).await?;
+            Ok(0)
+        })
+        .await?;
+        Ok(())
+    }
+    async fn set_times(
+        &self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner.set_times(atime, mtime).await
+    }
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.inner.read_vectored(bufs).await
+    }
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.inner.read_vectored_at(bufs, offset).await
+    }
+    async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        self.check_writable()?;
+        let len: u64 = bufs.iter().map(|buf| buf.len() as u64).sum();
+        // In append mode the data is always written to the end of the file
+        let position = match self.inner.get_fdflags().await?.contains(FdFlags::APPEND) {
+            true => self.size().await?,
+            false => self.inner.seek(SeekFrom::Current(0)).await?,
+        };
+        self.grow(
+            position.saturating_add(len),
+            self.inner.write_vectored(bufs),
+        )
+        .await
+    }
+    async fn write_vectored_at<'a>(&self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        self.check_writable()?;
+        let len: u64 = bufs.iter().map(|buf| buf.len() as u64).sum();
+        self.grow(
+            offset.saturating_add(len),
+            self.inner.write_vectored_at(bufs, offset),
+        )
+        .await
+    }
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        self.inner.seek(pos).await
+    }
+    async fn peek(&self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.inner.peek(buf).await
+    }
+    fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes()
+    }
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+    async fn writable(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.inner.writable().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{OpenFiles, Quota};
+
+    #[test]
+    fn nested_quota() {
+        let process = Arc::new(Quota::new(100));
+        let dir = Quota::with_parent(80, process.clone());
+        dir.reserve(60).unwrap();
+        process.reserve(30).unwrap();
+        // The directory has space left, but the process quota is exhausted.
+        assert!(dir.reserve(20).is_err());
+        dir.release(60);
+        dir.reserve(20).unwrap();
+    }
+
+    #[test]
+    fn existing_files_count_against_quota() {
+        let quota = Quota::new(10).with_existing(50);
+        quota.reserve(10).unwrap();
+        assert!(quota.reserve(1).is_err());
+        // Removing an existing file frees up its size
+        quota.release(50);
+        quota.reserve(50).unwrap();
+    }
+
+    #[test]
+    fn open_files_limit() {
+        let open_files = Arc::new(OpenFiles::new(1));
+        let file = open_files.acquire().unwrap();
+        assert!(open_files.acquire().is_err());
+        drop(file);
+        open_files.acquire().unwrap();
+    }
+}
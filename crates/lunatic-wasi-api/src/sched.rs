@@ -0,0 +1,131 @@
+use std::{future::Future, pin::Pin, task::Poll as TaskPoll, time::Duration};
+
+use tokio::runtime::{Handle, RuntimeFlavor};
+use wasi_common::{
+    sched::{
+        subscription::{RwEventFlags, Subscription},
+        Poll, WasiSched,
+    },
+    Error, ErrorExt, WasiFile,
+};
+
+type Readiness<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// A WASI scheduler that runs on top of the lunatic async runtime.
+///
+/// Sleeping and waiting on files that implement `readable`/`writable` suspends the calling
+/// process and lets other processes run on the same thread.
+pub struct LunaticSched;
+
+#[wiggle::async_trait]
+impl WasiSched for LunaticSched {
+    async fn poll_oneoff<'a>(&self, poll: &mut Poll<'a>) -> Result<(), Error> {
+        if poll.is_empty() {
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        for sub in poll.rw_subscriptions() {
+            match sub {
+                Subscription::Read(sub) => files.push((true, sub.file)),
+                Subscription::Write(sub) => files.push((false, sub.file)),
+                Subscription::MonotonicClock(_) => unreachable!(),
+            }
+        }
+        let host_files = files
+            .iter()
+            .filter(|(_, file)| file.pollable().is_some())
+            .count();
+        if host_files == 0 {
+            poll_async(poll, files).await
+        } else if host_files == files.len() {
+            // Files backed by host file descriptors can only be polled with a blocking call.
+            blocking(|| wiggle::run_in_dummy_executor(wasmtime_wasi::sched::poll_oneoff(poll)))
+                .map_err(Error::trap)?
+        } else {
+            Err(Error::not_supported()
+                .context("can't poll host files together with lunatic resources"))
+        }
+    }
+
+    async fn sched_yield(&self) -> Result<(), Error> {
+        tokio::task::yield_now().await;
+        Ok(())
+    }
+
+    async fn sleep(&self, duration: Duration) -> Result<(), Error> {
+        tokio::time::sleep(duration).await;
+        Ok(())
+    }
+}
+
+// Blocking the thread requires the runtime to move other tasks away from it.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// Waits until at least one file is ready or the earliest clock deadline is reached.
+async fn poll_async<'a>(
+    poll: &mut Poll<'a>,
+    files: Vec<(bool, &'a dyn WasiFile)>,
+) -> Result<(), Error> {
+    let timeout = poll
+        .earliest_clock_deadline()
+        .map(|clock| clock.duration_until().unwrap_or_default());
+    let mut sleep = Box::pin(async move {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    });
+    let mut waiting: Vec<Option<Readiness<'a>>> = files
+        .iter()
+        .map(|(read, file)| match read {
+            true => Some(file.readable()),
+            false => Some(file.writable()),
+        })
+        .collect();
+    let mut results: Vec<Option<Result<(), Error>>> = files.iter().map(|_| None).collect();
+
+    std::future::poll_fn(|cx| {
+        let mut ready = false;
+        for (future, result) in waiting.iter_mut().zip(results.iter_mut()) {
+            if let Some(fut) = future {
+                if let TaskPoll::Ready(value) = fut.as_mut().poll(cx) {
+                    *result = Some(value);
+                    *future = None;
+                    ready = true;
+                }
+            }
+        }
+        if ready || sleep.as_mut().poll(cx).is_ready() {
+            TaskPoll::Ready(())
+        } else {
+            TaskPoll::Pending
+        }
+    })
+    .await;
+    drop(waiting);
+
+    for (sub, result) in poll.rw_subscriptions().zip(results) {
+        let (sub, read) = match sub {
+            Subscription::Read(sub) => (sub, true),
+            Subscription::Write(sub) => (sub, false),
+            Subscription::MonotonicClock(_) => unreachable!(),
+        };
+        match result {
+            Some(Ok(())) if read => {
+                let ready = sub.file.num_ready_bytes()?;
+                sub.complete(ready.max(1), RwEventFlags::empty());
+            }
+            Some(Ok(())) => sub.complete(0, RwEventFlags::empty()),
+            Some(Err(error)) => sub.error(error),
+            None => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+
+/// Prefix of the runtime's environment variables that [`EnvSecretsProvider`] reads secrets from.
+///
+/// Variables with this prefix are never inherited by processes.
+pub const SECRET_ENV_PREFIX: &str = "LUNATIC_SECRET_";
+
+/// Provides secrets to processes on the host side, so that the processes configuring them never
+/// see the values.
+pub trait SecretsProvider: Send + Sync {
+    /// Returns the value of the secret, or `None` if it doesn't exist.
+    fn secret(&self, key: &str) -> Option<String>;
+}
+
+/// Reads the secret `KEY` from the `LUNATIC_SECRET_KEY` environment variable of the runtime.
+pub struct EnvSecretsProvider;
+
+impl SecretsProvider for EnvSecretsProvider {
+    fn secret(&self, key: &str) -> Option<String> {
+        std::env::var(format!("{SECRET_ENV_PREFIX}{key}")).ok()
+    }
+}
+
+static PROVIDER: OnceLock<Box<dyn SecretsProvider>> = OnceLock::new();
+
+fn provider() -> &'static dyn SecretsProvider {
+    PROVIDER
+        .get_or_init(|| Box::new(EnvSecretsProvider))
+        .as_ref()
+}
+
+/// Replaces the default [`EnvSecretsProvider`].
+///
+/// Fails if the provider was already set or used to spawn a process.
+pub fn set_secrets_provider(provider: Box<dyn SecretsProvider>) -> Result<()> {
+    PROVIDER
+        .set(provider)
+        .map_err(|_| anyhow!("The secrets provider is already in use"))
+}
+
+// Turns `(variable name, secret key)` pairs into environment variables.
+pub(crate) fn resolve_secrets(secrets: &[(String, String)]) -> Result<Vec<(String, String)>> {
+    secrets
+        .iter()
+        .map(|(name, key)| match provider().secret(key) {
+            Some(value) => Ok((name.clone(), value)),
+            None => Err(anyhow!("Secret '{key}' doesn't exist")),
+        })
+        .collect()
+}
@@ -0,0 +1,23 @@
+// Async bindings to the WASI snapshots.
+//
+// The bindings exported by `wasmtime_wasi::sync` run each call to completion on the current
+// thread, which means that a process sleeping inside `poll_oneoff` would block the executor.
+// These bindings await the host functions on the lunatic runtime instead.
+
+pub mod preview_1 {
+    wiggle::wasmtime_integration!({
+        target: wasi_common::snapshots::preview_1,
+        witx: ["$WASI_ROOT/phases/snapshot/witx/wasi_snapshot_preview1.witx"],
+        errors: { errno => trappable Error },
+        async: *
+    });
+}
+
+pub mod preview_0 {
+    wiggle::wasmtime_integration!({
+        target: wasi_common::snapshots::preview_0,
+        witx: ["$WASI_ROOT/phases/old/snapshot_0/witx/wasi_unstable.witx"],
+        errors: { errno => trappable Error },
+        async: *
+    });
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::Result;
+
+/// Model formats defined by the wasi-nn proposal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GraphEncoding {
+    Openvino,
+    Onnx,
+    Tensorflow,
+    Pytorch,
+    TensorflowLite,
+}
+
+impl GraphEncoding {
+    pub fn from_u32(encoding: u32) -> Option<Self> {
+        match encoding {
+            0 => Some(GraphEncoding::Openvino),
+            1 => Some(GraphEncoding::Onnx),
+            2 => Some(GraphEncoding::Tensorflow),
+            3 => Some(GraphEncoding::Pytorch),
+            4 => Some(GraphEncoding::TensorflowLite),
+            _ => None,
+        }
+    }
+}
+
+/// Devices that a graph can be executed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionTarget {
+    Cpu,
+    Gpu,
+    Tpu,
+}
+
+impl ExecutionTarget {
+    pub fn from_u32(target: u32) -> Option<Self> {
+        match target {
+            0 => Some(ExecutionTarget::Cpu),
+            1 => Some(ExecutionTarget::Gpu),
+            2 => Some(ExecutionTarget::Tpu),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TensorType {
+    F16,
+    F32,
+    U8,
+    I32,
+}
+
+impl TensorType {
+    pub fn from_u8(ty: u8) -> Option<Self> {
+        match ty {
+            0 => Some(TensorType::F16),
+            1 => Some(TensorType::F32),
+            2 => Some(TensorType::U8),
+            3 => Some(TensorType::I32),
+            _ => None,
+        }
+    }
+}
+
+/// An input tensor, the data is borrowed from the guest memory.
+pub struct Tensor<'a> {
+    pub dimensions: Vec<u32>,
+    pub ty: TensorType,
+    pub data: &'a [u8],
+}
+
+/// Loads models of one [`GraphEncoding`] on the host.
+pub trait Backend: Send + Sync {
+    /// Loads a graph from the builders passed by the guest, e.g. the model and its weights.
+    fn load(&self, builders: &[&[u8]], target: ExecutionTarget) -> Result<Arc<dyn Graph>>;
+}
+
+/// A loaded model, can be shared by many execution contexts.
+pub trait Graph: Send + Sync {
+    fn init_execution_context(&self) -> Result<Box<dyn ExecutionContext>>;
+}
+
+/// Holds the inputs and outputs of one inference.
+pub trait ExecutionContext: Send {
+    fn set_input(&mut self, index: u32, tensor: Tensor) -> Result<()>;
+    /// Runs the inference. It's called on a blocking thread, so it can take a while.
+    fn compute(&mut self) -> Result<()>;
+    /// Copies the output tensor to `output` and returns its size in bytes.
+    fn get_output(&mut self, index: u32, output: &mut [u8]) -> Result<u32>;
+}
+
+static BACKENDS: OnceLock<RwLock<HashMap<GraphEncoding, Arc<dyn Backend>>>> = OnceLock::new();
+
+/// Registers the backend that loads graphs of the given encoding.
+///
+/// Graphs with an encoding that doesn't have a backend fail to load with `invalid_encoding`.
+pub fn register_backend(encoding: GraphEncoding, backend: Arc<dyn Backend>) {
+    BACKENDS
+        .get_or_init(Default::default)
+        .write()
+        .unwrap()
+        .insert(encoding, backend);
+}
+
+pub(crate) fn backend(encoding: GraphEncoding) -> Option<Arc<dyn Backend>> {
+    BACKENDS.get()?.read().unwrap().get(&encoding).cloned()
+}
@@ -0,0 +1,319 @@
+mod backend;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use hash_map_id::HashMapId;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_process::state::ProcessState;
+use lunatic_process_api::ProcessConfigCtx;
+use wasmtime::{Caller, Linker, Memory};
+
+pub use backend::{
+    register_backend, Backend, ExecutionContext, ExecutionTarget, Graph, GraphEncoding, Tensor,
+    TensorType,
+};
+
+// Error codes of the wasi-nn proposal
+const SUCCESS: u32 = 0;
+const INVALID_ARGUMENT: u32 = 1;
+const INVALID_ENCODING: u32 = 2;
+const RUNTIME_ERROR: u32 = 5;
+
+// Size of a tensor in guest memory
+const TENSOR_SIZE: usize = 20;
+
+pub type NnGraphResources = HashMapId<Arc<dyn Graph>>;
+pub type NnContextResources = HashMapId<Arc<Mutex<Box<dyn ExecutionContext>>>>;
+
+pub trait WasiNnCtx {
+    fn nn_graph_resources(&self) -> &NnGraphResources;
+    fn nn_graph_resources_mut(&mut self) -> &mut NnGraphResources;
+    fn nn_context_resources(&self) -> &NnContextResources;
+    fn nn_context_resources_mut(&mut self) -> &mut NnContextResources;
+}
+
+// Register the wasi-nn APIs to the linker
+pub fn register<T>(linker: &mut Linker<T>) -> Result<()>
+where
+    T: ProcessState + WasiNnCtx + Send + 'static,
+    T::Config: ProcessConfigCtx,
+{
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap("wasi_ephemeral_nn", "load", load)?;
+    linker.func_wrap(
+        "wasi_ephemeral_nn",
+        "init_execution_context",
+        init_execution_context,
+    )?;
+    linker.func_wrap("wasi_ephemeral_nn", "set_input", set_input)?;
+    linker.func_wrap1_async("wasi_ephemeral_nn", "compute", compute)?;
+    linker.func_wrap("wasi_ephemeral_nn", "get_output", get_output)?;
+    Ok(())
+}
+
+fn check_permission<T>(caller: &Caller<T>, function: &str) -> Result<()>
+where
+    T: ProcessState,
+    T::Config: ProcessConfigCtx,
+{
+    match caller.data().config().can_use_nn() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "wasi_ephemeral_nn::{function}: process is not allowed to use wasi-nn"
+        )),
+    }
+}
+
+fn read_u32(memory: &[u8], ptr: usize) -> Option<u32> {
+    let bytes = memory.get(ptr..ptr + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+// Reads a (pointer, length) pair of an array with elements of `size` bytes and returns the
+// referenced slice.
+fn read_slice(memory: &[u8], ptr: usize, size: usize) -> Option<&[u8]> {
+    let data_ptr = read_u32(memory, ptr)? as usize;
+    let data_len = (read_u32(memory, ptr + 4)? as usize).checked_mul(size)?;
+    memory.get(data_ptr..data_ptr.checked_add(data_len)?)
+}
+
+fn write_handle<T>(memory: Memory, caller: &mut Caller<T>, ptr: u32, id: u64) -> Result<()> {
+    let handle = u32::try_from(id).or_trap("wasi_ephemeral_nn: out of handles")?;
+    memory
+        .write(caller, ptr as usize, &handle.to_le_bytes())
+        .or_trap("wasi_ephemeral_nn")
+}
+
+// Loads a graph with the backend registered for the **encoding**.
+//
+// **builders_ptr** points to an array of **builders_len** (pointer, length) pairs, referencing
+// the data of the graph, like the model and the weights. The graph handle is written to
+// **graph_ptr**.
+//
+// Returns:
+// * 0 on success
+// * 1 if the execution target is not known
+// * 2 if no backend is registered for the encoding
+// * 5 if the backend failed to load the graph
+//
+// Traps:
+// * If the process is not allowed to use wasi-nn.
+// * If any memory outside the guest heap space is referenced.
+fn load<T>(
+    mut caller: Caller<T>,
+    builders_ptr: u32,
+    builders_len: u32,
+    encoding: u32,
+    target: u32,
+    graph_ptr: u32,
+) -> Result<u32>
+where
+    T: ProcessState + WasiNnCtx,
+    T::Config: ProcessConfigCtx,
+{
+    check_permission(&caller, "load")?;
+    let backend = match GraphEncoding::from_u32(encoding).and_then(backend::backend) {
+        Some(backend) => backend,
+        None => return Ok(INVALID_ENCODING),
+    };
+    let target = match ExecutionTarget::from_u32(target) {
+        Some(target) => target,
+        None => return Ok(INVALID_ARGUMENT),
+    };
+    let memory = get_memory(&mut caller)?;
+    let memory_slice = memory.data(&caller);
+    let builders = (0..builders_len as usize)
+        .map(|i| read_slice(memory_slice, builders_ptr as usize + i * 8, 1))
+        .collect::<Option<Vec<_>>>()
+        .or_trap("wasi_ephemeral_nn::load")?;
+    let graph = match backend.load(&builders, target) {
+        Ok(graph) => graph,
+        Err(error) => {
+            log::debug!("wasi_ephemeral_nn::load failed: {error}");
+            return Ok(RUNTIME_ERROR);
+        }
+    };
+    let id = caller.data_mut().nn_graph_resources_mut().add(graph);
+    write_handle(memory, &mut caller, graph_ptr, id)?;
+    Ok(SUCCESS)
+}
+
+// Creates an execution context for the **graph** and writes its handle to **context_ptr**.
+//
+// Returns:
+// * 0 on success
+// * 1 if the graph doesn't exist
+// * 5 if the backend failed to create the context
+//
+// Traps:
+// * If the process is not allowed to use wasi-nn.
+// * If any memory outside the guest heap space is referenced.
+fn init_execution_context<T>(mut caller: Caller<T>, graph: u32, context_ptr: u32) -> Result<u32>
+where
+    T: ProcessState + WasiNnCtx,
+    T::Config: ProcessConfigCtx,
+{
+    check_permission(&caller, "init_execution_context")?;
+    let graph = match caller.data().nn_graph_resources().get(graph as u64) {
+        Some(graph) => graph.clone(),
+        None => return Ok(INVALID_ARGUMENT),
+    };
+    let context = match graph.init_execution_context() {
+        Ok(context) => context,
+        Err(error) => {
+            log::debug!("wasi_ephemeral_nn::init_execution_context failed: {error}");
+            return Ok(RUNTIME_ERROR);
+        }
+    };
+    let id = caller
+        .data_mut()
+        .nn_context_resources_mut()
+        .add(Arc::new(Mutex::new(context)));
+    let memory = get_memory(&mut caller)?;
+    write_handle(memory, &mut caller, context_ptr, id)?;
+    Ok(SUCCESS)
+}
+
+// Sets the input tensor at **index** of the execution **context**.
+//
+// **tensor_ptr** points to a tensor with the following structure:
+// [0..8 bytes = dimensions as (pointer, length) of u32 values; 8..9 bytes = tensor type;
+//  12..20 bytes = data as (pointer, length)]
+//
+// Returns:
+// * 0 on success
+// * 1 if the context doesn't exist or the tensor type is not known
+// * 5 if the backend rejected the input
+//
+// Traps:
+// * If the process is not allowed to use wasi-nn.
+// * If any memory outside the guest heap space is referenced.
+fn set_input<T>(mut caller: Caller<T>, context: u32, index: u32, tensor_ptr: u32) -> Result<u32>
+where
+    T: ProcessState + WasiNnCtx,
+    T::Config: ProcessConfigCtx,
+{
+    check_permission(&caller, "set_input")?;
+    let context = match caller.data().nn_context_resources().get(context as u64) {
+        Some(context) => context.clone(),
+        None => return Ok(INVALID_ARGUMENT),
+    };
+    let memory = get_memory(&mut caller)?;
+    let memory_slice = memory.data(&caller);
+    let tensor = memory_slice
+        .get(tensor_ptr as usize..tensor_ptr as usize + TENSOR_SIZE)
+        .or_trap("wasi_ephemeral_nn::set_input")?;
+    let ty = match TensorType::from_u8(tensor[8]) {
+        Some(ty) => ty,
+        None => return Ok(INVALID_ARGUMENT),
+    };
+    let dimensions = read_slice(memory_slice, tensor_ptr as usize, 4)
+        .or_trap("wasi_ephemeral_nn::set_input")?
+        .chunks_exact(4)
+        .map(|dimension| u32::from_le_bytes(dimension.try_into().expect("exactly 4 bytes")))
+        .collect();
+    let data = read_slice(memory_slice, tensor_ptr as usize + 12, 1)
+        .or_trap("wasi_ephemeral_nn::set_input")?;
+    let tensor = Tensor {
+        dimensions,
+        ty,
+        data,
+    };
+    let mut context = context.lock().unwrap();
+    match context.set_input(index, tensor) {
+        Ok(()) => Ok(SUCCESS),
+        Err(error) => {
+            log::debug!("wasi_ephemeral_nn::set_input failed: {error}");
+            Ok(RUNTIME_ERROR)
+        }
+    }
+}
+
+// Runs the inference of the execution **context**.
+//
+// The inference runs on a separate thread, other processes keep running in the meantime.
+//
+// Returns:
+// * 0 on success
+// * 1 if the context doesn't exist
+// * 5 if the inference failed
+//
+// Traps:
+// * If the process is not allowed to use wasi-nn.
+fn compute<T>(caller: Caller<T>, context: u32) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: ProcessState + WasiNnCtx + Send,
+    T::Config: ProcessConfigCtx,
+{
+    Box::new(async move {
+        check_permission(&caller, "compute")?;
+        let context = match caller.data().nn_context_resources().get(context as u64) {
+            Some(context) => context.clone(),
+            None => return Ok(INVALID_ARGUMENT),
+        };
+        let result = tokio::task::spawn_blocking(move || context.lock().unwrap().compute())
+            .await
+            .or_trap("wasi_ephemeral_nn::compute")?;
+        match result {
+            Ok(()) => Ok(SUCCESS),
+            Err(error) => {
+                log::debug!("wasi_ephemeral_nn::compute failed: {error}");
+                Ok(RUNTIME_ERROR)
+            }
+        }
+    })
+}
+
+// Copies the output tensor at **index** of the execution **context** to **out_ptr**.
+//
+// At most **out_len** bytes are copied, the number of bytes written is stored at
+// **bytes_written_ptr**.
+//
+// Returns:
+// * 0 on success
+// * 1 if the context doesn't exist
+// * 5 if the output is not available or doesn't fit into the buffer
+//
+// Traps:
+// * If the process is not allowed to use wasi-nn.
+// * If any memory outside the guest heap space is referenced.
+fn get_output<T>(
+    mut caller: Caller<T>,
+    context: u32,
+    index: u32,
+    out_ptr: u32,
+    out_len: u32,
+    bytes_written_ptr: u32,
+) -> Result<u32>
+where
+    T: ProcessState + WasiNnCtx,
+    T::Config: ProcessConfigCtx,
+{
+    check_permission(&caller, "get_output")?;
+    let context = match caller.data().nn_context_resources().get(context as u64) {
+        Some(context) => context.clone(),
+        None => return Ok(INVALID_ARGUMENT),
+    };
+    let memory = get_memory(&mut caller)?;
+    let output = memory
+        .data_mut(&mut caller)
+        .get_mut(out_ptr as usize..(out_ptr as usize).saturating_add(out_len as usize))
+        .or_trap("wasi_ephemeral_nn::get_output")?;
+    let bytes_written = match context.lock().unwrap().get_output(index, output) {
+        Ok(bytes_written) => bytes_written,
+        Err(error) => {
+            log::debug!("wasi_ephemeral_nn::get_output failed: {error}");
+            return Ok(RUNTIME_ERROR);
+        }
+    };
+    memory
+        .write(
+            &mut caller,
+            bytes_written_ptr as usize,
+            &bytes_written.to_le_bytes(),
+        )
+        .or_trap("wasi_ephemeral_nn::get_output")?;
+    Ok(SUCCESS)
+}
@@ -0,0 +1,442 @@
+use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use hash_map_id::HashMapId;
+use lunatic_common_api::{get_memory, InstrumentedLinker, IntoTrap};
+use lunatic_error_api::ErrorCtx;
+use lunatic_networking_api::NetworkingCtx;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use wasmtime::{Caller, Linker};
+
+/// A TCP or TLS stream that was upgraded to a WebSocket.
+pub trait WebSocketIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> WebSocketIo for T {}
+
+type WebSocket = WebSocketStream<Box<dyn WebSocketIo>>;
+
+pub struct WebSocketConnection {
+    // The stream and the payload of the last frame read from it.
+    reader: Mutex<(SplitStream<WebSocket>, Vec<u8>)>,
+    writer: Mutex<SplitSink<WebSocket, Message>>,
+}
+
+impl WebSocketConnection {
+    fn new(websocket: WebSocket) -> Self {
+        let (writer, reader) = websocket.split();
+        WebSocketConnection {
+            reader: Mutex::new((reader, Vec::new())),
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+pub type WebSocketResources = HashMapId<Arc<WebSocketConnection>>;
+
+pub trait WebSocketCtx {
+    fn websocket_resources(&self) -> &WebSocketResources;
+    fn websocket_resources_mut(&mut self) -> &mut WebSocketResources;
+}
+
+// Register the WebSocket APIs to the linker
+pub fn register<T: WebSocketCtx + NetworkingCtx + ErrorCtx + Send + 'static>(
+    linker: &mut Linker<T>,
+) -> Result<()> {
+    let mut linker = InstrumentedLinker::new(linker);
+    linker.func_wrap3_async("lunatic::websocket", "ws_accept", ws_accept)?;
+    linker.func_wrap5_async("lunatic::websocket", "ws_connect", ws_connect)?;
+    linker.func_wrap3_async("lunatic::websocket", "ws_read_frame", ws_read_frame)?;
+    linker.func_wrap3_async("lunatic::websocket", "ws_frame_data", ws_frame_data)?;
+    linker.func_wrap5_async("lunatic::websocket", "ws_write_frame", ws_write_frame)?;
+    linker.func_wrap("lunatic::websocket", "drop_websocket", drop_websocket)?;
+    Ok(())
+}
+
+// Frame kinds, as seen by the guest.
+const TEXT: u32 = 0;
+const BINARY: u32 = 1;
+const PING: u32 = 2;
+const PONG: u32 = 3;
+const CLOSE: u32 = 4;
+
+// Removes the TCP (**stream_type** = 0) or TLS (**stream_type** = 1) stream from the process'
+// resources, so that it can be upgraded.
+//
+// Streams with clones can't be taken over, because the clones would keep writing to the same
+// connection. This is reported as an error, the stream stays in the resources then.
+fn take_stream<T: NetworkingCtx>(
+    state: &mut T,
+    stream_type: u32,
+    stream_id: u64,
+    trap: &str,
+) -> Result<Result<Box<dyn WebSocketIo>>> {
+    let cloned_error = || anyhow!("The stream is cloned and can't be upgraded to a WebSocket");
+    match stream_type {
+        0 => {
+            let stream = state.tcp_stream_resources().get(stream_id).or_trap(trap)?;
+            if Arc::strong_count(stream) > 1 {
+                return Ok(Err(cloned_error()));
+            }
+            let stream = state
+                .tcp_stream_resources_mut()
+                .remove(stream_id)
+                .or_trap(trap)?;
+            let stream = Arc::try_unwrap(stream).map_err(|_| cloned_error())?;
+            let stream = stream
+                .reader
+                .into_inner()
+                .reunite(stream.writer.into_inner())?;
+            Ok(Ok(Box::new(stream)))
+        }
+        1 => {
+            let stream = state.tls_stream_resources().get(stream_id).or_trap(trap)?;
+            if Arc::strong_count(stream) > 1 {
+                return Ok(Err(cloned_error()));
+            }
+            let stream = state
+                .tls_stream_resources_mut()
+                .remove(stream_id)
+                .or_trap(trap)?;
+            let stream = Arc::try_unwrap(stream).map_err(|_| cloned_error())?;
+            let stream = stream
+                .reader
+                .into_inner()
+                .unsplit(stream.writer.into_inner());
+            Ok(Ok(Box::new(stream)))
+        }
+        _ => Err(anyhow!("{trap}: unsupported stream type")),
+    }
+}
+
+// Performs the server side of the WebSocket handshake on the TCP (**stream_type** = 0) or TLS
+// (**stream_type** = 1) stream. The stream is removed from the process' resources and can only
+// be used through the WebSocket afterwards, even if the handshake fails.
+//
+// Returns:
+// * 0 on success - The ID of the WebSocket is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If the stream type is neither 0 nor 1.
+// * If any memory outside the guest heap space is referenced.
+fn ws_accept<T: WebSocketCtx + NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_type: u32,
+    stream_id: u64,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let stream = take_stream(
+            caller.data_mut(),
+            stream_type,
+            stream_id,
+            "lunatic::websocket::ws_accept",
+        )?;
+        let websocket = match stream {
+            Ok(stream) => tokio_tungstenite::accept_async(stream)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error),
+        };
+        let (websocket_or_error_id, result) = match websocket {
+            Ok(websocket) => (
+                caller
+                    .data_mut()
+                    .websocket_resources_mut()
+                    .add(Arc::new(WebSocketConnection::new(websocket))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &websocket_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::websocket::ws_accept")?;
+        Ok(result)
+    })
+}
+
+// Performs the client side of the WebSocket handshake for the **url** on the TCP
+// (**stream_type** = 0) or TLS (**stream_type** = 1) stream. The stream is removed from the
+// process' resources and can only be used through the WebSocket afterwards, even if the
+// handshake fails.
+//
+// Returns:
+// * 0 on success - The ID of the WebSocket is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**
+//
+// Traps:
+// * If the stream ID doesn't exist.
+// * If the stream type is neither 0 nor 1.
+// * If the URL is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn ws_connect<T: WebSocketCtx + NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    stream_type: u32,
+    stream_id: u64,
+    url_str_ptr: u32,
+    url_str_len: u32,
+    id_u64_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let url = memory
+            .data(&caller)
+            .get(url_str_ptr as usize..(url_str_ptr + url_str_len) as usize)
+            .or_trap("lunatic::websocket::ws_connect")?;
+        let url = std::str::from_utf8(url)
+            .or_trap("lunatic::websocket::ws_connect")?
+            .to_string();
+
+        let stream = take_stream(
+            caller.data_mut(),
+            stream_type,
+            stream_id,
+            "lunatic::websocket::ws_connect",
+        )?;
+        let websocket = match stream {
+            Ok(stream) => tokio_tungstenite::client_async(url, stream)
+                .await
+                .map(|(websocket, _)| websocket)
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error),
+        };
+        let (websocket_or_error_id, result) = match websocket {
+            Ok(websocket) => (
+                caller
+                    .data_mut()
+                    .websocket_resources_mut()
+                    .add(Arc::new(WebSocketConnection::new(websocket))),
+                0,
+            ),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+
+        memory
+            .write(
+                &mut caller,
+                id_u64_ptr as usize,
+                &websocket_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::websocket::ws_connect")?;
+        Ok(result)
+    })
+}
+
+// Waits for the next frame on the WebSocket. The kind of the frame is written to **kind_ptr**
+// (0 = text, 1 = binary, 2 = ping, 3 = pong, 4 = close) and the payload can be copied into the
+// guest memory with `ws_frame_data`. The payload of a close frame consists of the 2 byte status
+// code in big endian followed by the reason, or is empty if no status code was sent.
+//
+// Pings are answered automatically.
+//
+// Returns:
+// * 0 on success - The size of the payload is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**
+// * 2 if the connection is closed
+//
+// Traps:
+// * If the WebSocket ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn ws_read_frame<T: WebSocketCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    websocket_id: u64,
+    kind_ptr: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let websocket = caller
+            .data()
+            .websocket_resources()
+            .get(websocket_id)
+            .or_trap("lunatic::websocket::ws_read_frame")?
+            .clone();
+        let mut reader = websocket.reader.lock().await;
+        let (stream, frame) = &mut *reader;
+
+        let (kind, opaque, result) = match stream.next().await {
+            Some(Ok(message)) => {
+                let (kind, payload) = frame_parts(message);
+                *frame = payload;
+                (kind, frame.len() as u64, 0)
+            }
+            Some(Err(error)) => (
+                0,
+                caller.data_mut().error_resources_mut().add(error.into()),
+                1,
+            ),
+            None => (0, 0, 2),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, kind_ptr as usize, &kind.to_le_bytes())
+            .or_trap("lunatic::websocket::ws_read_frame")?;
+        memory
+            .write(&mut caller, opaque_ptr as usize, &opaque.to_le_bytes())
+            .or_trap("lunatic::websocket::ws_read_frame")?;
+        Ok(result)
+    })
+}
+
+fn frame_parts(message: Message) -> (u32, Vec<u8>) {
+    match message {
+        Message::Text(text) => (TEXT, text.into_bytes()),
+        Message::Binary(data) => (BINARY, data),
+        Message::Ping(data) => (PING, data),
+        Message::Pong(data) => (PONG, data),
+        Message::Close(Some(close_frame)) => {
+            let mut payload = u16::from(close_frame.code).to_be_bytes().to_vec();
+            payload.extend_from_slice(close_frame.reason.as_bytes());
+            (CLOSE, payload)
+        }
+        Message::Close(None) => (CLOSE, Vec::new()),
+        // Raw frames are never returned when reading
+        Message::Frame(frame) => (BINARY, frame.into_data()),
+    }
+}
+
+// Copies the payload of the last frame read with `ws_read_frame` into the buffer. If the buffer
+// is too small, only **buf_len** bytes are copied.
+//
+// Returns the number of copied bytes.
+//
+// Traps:
+// * If the WebSocket ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn ws_frame_data<T: WebSocketCtx + Send>(
+    mut caller: Caller<T>,
+    websocket_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let websocket = caller
+            .data()
+            .websocket_resources()
+            .get(websocket_id)
+            .or_trap("lunatic::websocket::ws_frame_data")?
+            .clone();
+        let reader = websocket.reader.lock().await;
+        let (_, frame) = &*reader;
+        let len = frame.len().min(buf_len as usize);
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, buf_ptr as usize, &frame[..len])
+            .or_trap("lunatic::websocket::ws_frame_data")?;
+        Ok(len as u32)
+    })
+}
+
+// Sends a frame of the given **kind** (0 = text, 1 = binary, 2 = ping, 3 = pong, 4 = close) over
+// the WebSocket. The payload of a close frame needs to be empty or start with a 2 byte status
+// code in big endian, followed by the reason.
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**
+//
+// Traps:
+// * If the WebSocket ID doesn't exist.
+// * If the kind is not one of the listed values.
+// * If any memory outside the guest heap space is referenced.
+fn ws_write_frame<T: WebSocketCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    websocket_id: u64,
+    kind: u32,
+    data_ptr: u32,
+    data_len: u32,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let data = memory
+            .data(&caller)
+            .get(data_ptr as usize..(data_ptr + data_len) as usize)
+            .or_trap("lunatic::websocket::ws_write_frame")?
+            .to_vec();
+        let message = match kind {
+            TEXT => String::from_utf8(data)
+                .map(Message::Text)
+                .map_err(anyhow::Error::from),
+            BINARY => Ok(Message::Binary(data)),
+            PING => Ok(Message::Ping(data)),
+            PONG => Ok(Message::Pong(data)),
+            CLOSE => close_message(data),
+            _ => {
+                return Err(anyhow!(
+                    "lunatic::websocket::ws_write_frame: unknown frame kind"
+                ))
+            }
+        };
+
+        let websocket = caller
+            .data()
+            .websocket_resources()
+            .get(websocket_id)
+            .or_trap("lunatic::websocket::ws_write_frame")?
+            .clone();
+        let sent = match message {
+            Ok(message) => websocket
+                .writer
+                .lock()
+                .await
+                .send(message)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error),
+        };
+
+        let (error_id, result) = match sent {
+            Ok(()) => (0, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        memory
+            .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+            .or_trap("lunatic::websocket::ws_write_frame")?;
+        Ok(result)
+    })
+}
+
+fn close_message(payload: Vec<u8>) -> Result<Message> {
+    if payload.is_empty() {
+        return Ok(Message::Close(None));
+    }
+    if payload.len() < 2 {
+        return Err(anyhow!("Close frame payload is missing the status code"));
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    let reason = String::from_utf8(payload[2..].to_vec())?;
+    Ok(Message::Close(Some(CloseFrame {
+        code: code.into(),
+        reason: Cow::Owned(reason),
+    })))
+}
+
+// Drops the WebSocket resource, closing the underlying connection.
+//
+// Traps:
+// * If the WebSocket ID doesn't exist.
+fn drop_websocket<T: WebSocketCtx>(mut caller: Caller<T>, websocket_id: u64) -> Result<()> {
+    caller
+        .data_mut()
+        .websocket_resources_mut()
+        .remove(websocket_id)
+        .or_trap("lunatic::websocket::drop_websocket")?;
+    Ok(())
+}
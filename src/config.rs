@@ -1,12 +1,16 @@
 use std::{
     fmt::Debug,
     fs,
+    net::IpAddr,
+    ops::RangeInclusive,
     path::{Component, Path, PathBuf},
 };
 
-use lunatic_process::config::ProcessConfig;
+use lunatic_process::config::{LogLevel, MailboxOverflowPolicy, ProcessConfig};
 use lunatic_process_api::ProcessConfigCtx;
-use lunatic_wasi_api::LunaticWasiConfigCtx;
+use lunatic_wasi_api::{
+    FsLimits, LunaticWasiConfigCtx, OutputSink, PreopenedDir, StdinSource, SECRET_ENV_PREFIX,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -15,16 +19,64 @@ pub struct DefaultProcessConfig {
     max_memory: usize,
     // Maximum amount of compute expressed in units of 100k instructions.
     max_fuel: Option<u64>,
+    // Amount of instructions after which the process yields back to the scheduler
+    fuel_per_yield: Option<u64>,
+    // Maximum wall-clock time in milliseconds before the process is killed
+    max_lifetime_ms: Option<u64>,
+    // Maximum number of running processes in the subtree spawned from this config
+    max_processes: Option<u64>,
+    // Environment counter enforcing `max_processes`, only valid on the node that created it. It's
+    // not sent to other nodes, so processes spawned there are not limited.
+    #[serde(skip)]
+    process_counter_id: Option<u64>,
+    // Maximum number of messages waiting in the mailbox
+    max_mailbox_size: Option<usize>,
+    // What happens if a message arrives to a full mailbox
+    mailbox_overflow_policy: MailboxOverflowPolicy,
+    // Number of waiting messages at which senders are told to slow down
+    mailbox_watermark: Option<usize>,
+    // Maximum size of data messages in bytes
+    max_message_size: Option<usize>,
+    // Most verbose level of log records passed on to the host logger
+    max_log_level: LogLevel,
+    // Write a crash report if the process fails
+    crash_dumps: bool,
     // Can this process compile new WebAssembly modules
     can_compile_modules: bool,
     // Can this process create new configurations
     can_create_configs: bool,
     // Can this process spawn sub-processes
     can_spawn_processes: bool,
+    // Can this process run inference with wasi-nn
+    can_use_nn: bool,
+    // Can this process change the tags of the node it's running on
+    can_set_node_tags: bool,
+    // Hosts and networks that processes can connect to, everything is allowed if empty
+    allowed_network: Vec<NetworkRule>,
+    // Hosts and networks that processes can't connect to, even if they are allowed
+    denied_network: Vec<NetworkRule>,
+    // Sqlite databases that processes can open, every accessible path if empty
+    allowed_sqlite: Vec<SqliteRule>,
     // WASI configs
-    preopened_dirs: Vec<(String, String)>,
+    preopened_dirs: Vec<PreopenedDir>,
+    fs_limits: FsLimits,
     command_line_arguments: Vec<String>,
     environment_variables: Vec<(String, String)>,
+    // Environment variables with values provided by the secrets provider, as (name, key) pairs
+    secrets: Vec<(String, String)>,
+    // Filters of the runtime's environment variables that processes can pass on to the processes
+    // they spawn, nothing is exposed if empty. Only set by the host.
+    exposed_environment: Vec<String>,
+    // Keys of the secrets that processes can pass on to the processes they spawn. Only set by the
+    // host.
+    exposed_secrets: Vec<String>,
+    stdin: StdinSource,
+    stdout: OutputSink,
+    stderr: OutputSink,
+    // Milliseconds after the unix epoch that the WASI clocks are fixed to
+    fixed_clock: Option<u64>,
+    // Seed of the WASI random number generator
+    random_seed: Option<u64>,
 }
 
 impl Debug for DefaultProcessConfig {
@@ -32,9 +84,30 @@ impl Debug for DefaultProcessConfig {
         f.debug_struct("EnvConfig")
             .field("max_memory", &self.max_memory)
             .field("max_fuel", &self.max_fuel)
+            .field("fuel_per_yield", &self.fuel_per_yield)
+            .field("max_lifetime_ms", &self.max_lifetime_ms)
+            .field("max_processes", &self.max_processes)
+            .field("max_mailbox_size", &self.max_mailbox_size)
+            .field("mailbox_overflow_policy", &self.mailbox_overflow_policy)
+            .field("mailbox_watermark", &self.mailbox_watermark)
+            .field("max_message_size", &self.max_message_size)
+            .field("max_log_level", &self.max_log_level)
+            .field("crash_dumps", &self.crash_dumps)
+            .field("allowed_network", &self.allowed_network)
+            .field("denied_network", &self.denied_network)
+            .field("allowed_sqlite", &self.allowed_sqlite)
             .field("preopened_dirs", &self.preopened_dirs)
+            .field("fs_limits", &self.fs_limits)
             .field("args", &self.command_line_arguments)
             .field("envs", &self.environment_variables)
+            .field("secrets", &self.secrets)
+            .field("exposed_environment", &self.exposed_environment)
+            .field("exposed_secrets", &self.exposed_secrets)
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .field("fixed_clock", &self.fixed_clock)
+            .field("random_seed", &self.random_seed)
             .finish()
     }
 }
@@ -48,6 +121,14 @@ impl ProcessConfig for DefaultProcessConfig {
         self.max_fuel
     }
 
+    fn set_fuel_per_yield(&mut self, fuel_per_yield: Option<u64>) {
+        self.fuel_per_yield = fuel_per_yield;
+    }
+
+    fn get_fuel_per_yield(&self) -> Option<u64> {
+        self.fuel_per_yield
+    }
+
     fn set_max_memory(&mut self, max_memory: usize) {
         self.max_memory = max_memory
     }
@@ -55,6 +136,70 @@ impl ProcessConfig for DefaultProcessConfig {
     fn get_max_memory(&self) -> usize {
         self.max_memory
     }
+
+    fn set_max_mailbox_size(&mut self, max_mailbox_size: Option<usize>) {
+        self.max_mailbox_size = max_mailbox_size
+    }
+
+    fn get_max_mailbox_size(&self) -> Option<usize> {
+        self.max_mailbox_size
+    }
+
+    fn set_mailbox_overflow_policy(&mut self, policy: MailboxOverflowPolicy) {
+        self.mailbox_overflow_policy = policy
+    }
+
+    fn get_mailbox_overflow_policy(&self) -> MailboxOverflowPolicy {
+        self.mailbox_overflow_policy
+    }
+
+    fn set_mailbox_watermark(&mut self, mailbox_watermark: Option<usize>) {
+        self.mailbox_watermark = mailbox_watermark
+    }
+
+    fn get_mailbox_watermark(&self) -> Option<usize> {
+        self.mailbox_watermark
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.max_message_size = max_message_size
+    }
+
+    fn get_max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    fn set_max_lifetime_ms(&mut self, max_lifetime_ms: Option<u64>) {
+        self.max_lifetime_ms = max_lifetime_ms
+    }
+
+    fn get_max_lifetime_ms(&self) -> Option<u64> {
+        self.max_lifetime_ms
+    }
+
+    fn set_max_log_level(&mut self, max_log_level: LogLevel) {
+        self.max_log_level = max_log_level
+    }
+
+    fn get_max_log_level(&self) -> LogLevel {
+        self.max_log_level
+    }
+
+    fn set_crash_dumps(&mut self, crash_dumps: bool) {
+        self.crash_dumps = crash_dumps
+    }
+
+    fn get_crash_dumps(&self) -> bool {
+        self.crash_dumps
+    }
+
+    fn set_max_processes(&mut self, max_processes: Option<u64>) {
+        self.max_processes = max_processes
+    }
+
+    fn get_max_processes(&self) -> Option<u64> {
+        self.max_processes
+    }
 }
 
 impl LunaticWasiConfigCtx for DefaultProcessConfig {
@@ -62,22 +207,98 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
         self.environment_variables.push((key, value));
     }
 
+    fn inherit_environment(&mut self, filter: &str) {
+        let inherited = std::env::vars()
+            .filter(|(key, _)| !key.starts_with(SECRET_ENV_PREFIX) && glob_matches(filter, key));
+        self.environment_variables.extend(inherited);
+    }
+
+    fn add_secret(&mut self, name: String, key: String) {
+        self.secrets.push((name, key));
+    }
+
+    fn expose_environment(&mut self, filter: String) {
+        self.exposed_environment.push(filter);
+    }
+
+    fn expose_secret(&mut self, key: String) {
+        self.exposed_secrets.push(key);
+    }
+
+    fn exposed_environment(&self, filter: &str) -> Result<Vec<(String, String)>, String> {
+        if self.exposed_environment.is_empty() {
+            return Err("Process doesn't have permissions to inherit environment variables".into());
+        }
+        let exposed = std::env::vars().filter(|(key, _)| {
+            !key.starts_with(SECRET_ENV_PREFIX)
+                && glob_matches(filter, key)
+                && self
+                    .exposed_environment
+                    .iter()
+                    .any(|exposed| glob_matches(exposed, key))
+        });
+        Ok(exposed.collect())
+    }
+
+    fn can_expose_secret(&self, key: &str) -> Result<(), String> {
+        if self.exposed_secrets.iter().any(|exposed| exposed == key) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Process doesn't have permissions to use secret '{key}'"
+            ))
+        }
+    }
+
     fn add_command_line_argument(&mut self, argument: String) {
         self.command_line_arguments.push(argument);
     }
 
-    fn preopen_dir(&mut self, dir: String) {
+    fn preopen_dir(&mut self, dir: String, read_only: bool, quota: Option<u64>) {
         let resolved_path = if &dir == "~" {
             dirs::home_dir().unwrap().to_str().unwrap().to_string()
         } else {
             dir.clone()
         };
-        self.preopened_dirs.push((dir, resolved_path));
+        self.preopened_dirs.push(PreopenedDir {
+            path: dir,
+            resolved_path,
+            read_only,
+            quota,
+        });
+    }
+
+    fn set_stdin(&mut self, stdin: StdinSource) {
+        self.stdin = stdin;
+    }
+
+    fn set_stdout_sink(&mut self, sink: OutputSink) {
+        self.stdout = sink;
+    }
+
+    fn set_stderr_sink(&mut self, sink: OutputSink) {
+        self.stderr = sink;
+    }
+
+    fn set_fixed_clock(&mut self, epoch_ms: u64) {
+        self.fixed_clock = Some(epoch_ms);
+    }
+
+    fn set_random_seed(&mut self, seed: u64) {
+        self.random_seed = Some(seed);
+    }
+
+    fn set_max_open_files(&mut self, limit: u64) {
+        self.fs_limits.max_open_files = Some(limit);
+    }
+
+    fn set_fs_write_quota(&mut self, bytes: u64) {
+        self.fs_limits.write_quota = Some(bytes);
     }
 }
 
 impl DefaultProcessConfig {
-    pub fn preopened_dirs(&self) -> &[(String, String)] {
+    pub fn preopened_dirs(&self) -> &[PreopenedDir] {
         &self.preopened_dirs
     }
 
@@ -91,13 +312,22 @@ impl DefaultProcessConfig {
         } else {
             dir.clone()
         };
-        self.preopened_dirs.push((dir, resolved_path))
+        self.preopened_dirs.push(PreopenedDir {
+            path: dir,
+            resolved_path,
+            read_only: false,
+            quota: None,
+        })
     }
 
     pub fn set_command_line_arguments(&mut self, args: Vec<String>) {
         self.command_line_arguments = args;
     }
 
+    pub fn fs_limits(&self) -> FsLimits {
+        self.fs_limits
+    }
+
     pub fn command_line_arguments(&self) -> &Vec<String> {
         &self.command_line_arguments
     }
@@ -109,6 +339,30 @@ impl DefaultProcessConfig {
     pub fn environment_variables(&self) -> &Vec<(String, String)> {
         &self.environment_variables
     }
+
+    pub fn secrets(&self) -> &[(String, String)] {
+        &self.secrets
+    }
+
+    pub fn stdin(&self) -> &StdinSource {
+        &self.stdin
+    }
+
+    pub fn stdout(&self) -> &OutputSink {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &OutputSink {
+        &self.stderr
+    }
+
+    pub fn fixed_clock(&self) -> Option<u64> {
+        self.fixed_clock
+    }
+
+    pub fn random_seed(&self) -> Option<u64> {
+        self.random_seed
+    }
 }
 
 impl ProcessConfigCtx for DefaultProcessConfig {
@@ -136,6 +390,30 @@ impl ProcessConfigCtx for DefaultProcessConfig {
         self.can_spawn_processes = can
     }
 
+    fn can_use_nn(&self) -> bool {
+        self.can_use_nn
+    }
+
+    fn set_can_use_nn(&mut self, can: bool) {
+        self.can_use_nn = can
+    }
+
+    fn can_set_node_tags(&self) -> bool {
+        self.can_set_node_tags
+    }
+
+    fn set_can_set_node_tags(&mut self, can: bool) {
+        self.can_set_node_tags = can
+    }
+
+    fn process_counter_id(&self) -> Option<u64> {
+        self.process_counter_id
+    }
+
+    fn set_process_counter_id(&mut self, counter_id: Option<u64>) {
+        self.process_counter_id = counter_id
+    }
+
     fn can_access_fs_location(&self, path: &std::path::Path) -> Result<(), String> {
         let (file_path, parent_dir) = match strip_file(path) {
             Ok(p) => p,
@@ -146,10 +424,9 @@ impl ProcessConfigCtx for DefaultProcessConfig {
         let has_access = self
             .preopened_dirs()
             .iter()
-            .filter_map(|(_, dir)| match get_absolute_path(Path::new(dir)) {
-                Ok(d) => Some(d),
-                _ => None,
-            })
+            // Host functions write to files directly, bypassing the restrictions
+            .filter(|dir| dir.is_unrestricted())
+            .filter_map(|dir| get_absolute_path(Path::new(&dir.resolved_path)).ok())
             .any(|dir| dir.exists() && path_is_ancestor(&dir, &parent_dir));
 
         match has_access {
@@ -157,6 +434,179 @@ impl ProcessConfigCtx for DefaultProcessConfig {
             false => Err(format!("Permission to '{file_path:?}' denied")),
         }
     }
+
+    fn allow_network(&mut self, target: &str, ports: RangeInclusive<u16>) -> Result<(), String> {
+        self.allowed_network
+            .push(NetworkRule::parse(target, ports)?);
+        Ok(())
+    }
+
+    fn deny_network(&mut self, target: &str, ports: RangeInclusive<u16>) -> Result<(), String> {
+        self.denied_network.push(NetworkRule::parse(target, ports)?);
+        Ok(())
+    }
+
+    fn can_access_network(
+        &self,
+        host: Option<&str>,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+    ) -> Result<(), String> {
+        // Without a port (e.g. DNS lookups) allow rules match any of their ports, but deny rules
+        // only match if they cover all of them.
+        let allowed = self.allowed_network.is_empty()
+            || self.allowed_network.iter().any(|rule| {
+                rule.matches(host, ip) && port.is_none_or(|port| rule.ports.contains(&port))
+            });
+        let denied = self.denied_network.iter().any(|rule| {
+            rule.matches(host, ip)
+                && port.map_or(rule.ports == (0..=u16::MAX), |port| {
+                    rule.ports.contains(&port)
+                })
+        });
+
+        match allowed && !denied {
+            true => Ok(()),
+            false => {
+                let target = match (host, ip) {
+                    (Some(host), _) => host.to_string(),
+                    (None, Some(ip)) => ip.to_string(),
+                    (None, None) => String::new(),
+                };
+                match port {
+                    Some(port) => Err(format!("Network access to '{target}:{port}' denied")),
+                    None => Err(format!("Network access to '{target}' denied")),
+                }
+            }
+        }
+    }
+
+    fn allow_sqlite(&mut self, path_glob: &str, max_db_size: Option<u64>) {
+        let path_glob = get_absolute_path(Path::new(path_glob))
+            .map(|path_glob| path_glob.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| path_glob.to_string());
+        self.allowed_sqlite.push(SqliteRule {
+            path_glob,
+            max_db_size,
+        });
+    }
+
+    fn can_open_sqlite(&self, path: &Path) -> Result<Option<u64>, String> {
+        // Without any rules the sqlite API isn't restricted on top of the filesystem access, which
+        // keeps configurations that only preopen directories working as before
+        if self.allowed_sqlite.is_empty() {
+            return Ok(None);
+        }
+        let path = get_absolute_path(path).map_err(|e| e.to_string())?;
+        let path_str = path.to_string_lossy();
+        self.allowed_sqlite
+            .iter()
+            .find(|rule| glob_matches(&rule.path_glob, &path_str))
+            .map(|rule| rule.max_db_size)
+            .ok_or_else(|| format!("Permission to open database {path:?} denied"))
+    }
+}
+
+// Databases matching the glob, together with their size quota.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SqliteRule {
+    // Absolute path glob
+    path_glob: String,
+    max_db_size: Option<u64>,
+}
+
+// A host or network together with the range of ports that the rule applies to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct NetworkRule {
+    target: NetworkTarget,
+    ports: RangeInclusive<u16>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum NetworkTarget {
+    // Host name, `*.example.com` also matches all subdomains
+    Host(String),
+    // IP network in CIDR notation
+    Network(IpAddr, u8),
+}
+
+impl NetworkRule {
+    fn parse(target: &str, ports: RangeInclusive<u16>) -> Result<Self, String> {
+        if ports.is_empty() {
+            return Err(format!("Invalid port range {ports:?}"));
+        }
+        let (address, prefix) = match target.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (target, None),
+        };
+        let target = match (address.parse::<IpAddr>(), prefix) {
+            (Ok(ip), prefix) => {
+                let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|prefix| *prefix <= max_prefix)
+                        .ok_or_else(|| format!("Invalid network '{target}'"))?,
+                    None => max_prefix,
+                };
+                NetworkTarget::Network(ip, prefix)
+            }
+            (Err(_), None) => {
+                let name = target.strip_prefix("*.").unwrap_or(target);
+                let valid = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+                if !valid {
+                    return Err(format!("Invalid host '{target}'"));
+                }
+                NetworkTarget::Host(target.to_ascii_lowercase())
+            }
+            (Err(_), Some(_)) => return Err(format!("Invalid network '{target}'")),
+        };
+        Ok(NetworkRule { target, ports })
+    }
+
+    fn matches(&self, host: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match (&self.target, host, ip) {
+            (NetworkTarget::Host(rule), Some(host), _) => {
+                let host = host.trim_end_matches('.');
+                match rule.strip_prefix('*') {
+                    Some(suffix) => {
+                        host.len() > suffix.len()
+                            && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                    }
+                    None => host.eq_ignore_ascii_case(rule),
+                }
+            }
+            (NetworkTarget::Network(network, prefix), _, Some(ip)) => match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+// Matches the name against a pattern where `*` stands for any sequence of characters.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => match name.strip_prefix(prefix) {
+            None => false,
+            Some(name) => (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| glob_matches(rest, &name[i..])),
+        },
+    }
 }
 
 fn path_is_ancestor(ancestor: &Path, descendant: &Path) -> bool {
@@ -231,14 +681,84 @@ fn normalize_path(path: &Path) -> PathBuf {
     ret
 }
 
+impl Default for DefaultProcessConfig {
+    fn default() -> Self {
+        Self {
+            max_memory: u32::MAX as usize, // = 4 GB
+            max_fuel: None,
+            fuel_per_yield: None,
+            max_lifetime_ms: None,
+            max_processes: None,
+            process_counter_id: None,
+            max_mailbox_size: None,
+            mailbox_overflow_policy: MailboxOverflowPolicy::default(),
+            mailbox_watermark: None,
+            max_message_size: None,
+            max_log_level: LogLevel::default(),
+            crash_dumps: false,
+            can_compile_modules: false,
+            can_create_configs: false,
+            can_spawn_processes: false,
+            can_use_nn: false,
+            can_set_node_tags: false,
+            allowed_network: vec![],
+            denied_network: vec![],
+            allowed_sqlite: vec![],
+            preopened_dirs: vec![],
+            fs_limits: FsLimits::default(),
+            command_line_arguments: vec![],
+            environment_variables: vec![],
+            secrets: vec![],
+            exposed_environment: vec![],
+            exposed_secrets: vec![],
+            stdin: StdinSource::default(),
+            stdout: OutputSink::default(),
+            stderr: OutputSink::default(),
+            fixed_clock: None,
+            random_seed: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
-    use crate::config::{get_absolute_path, path_is_ancestor};
+    use lunatic_process_api::ProcessConfigCtx;
+    use lunatic_wasi_api::LunaticWasiConfigCtx;
+
+    use crate::config::{get_absolute_path, glob_matches, path_is_ancestor, DefaultProcessConfig};
 
     use super::normalize_path;
 
+    #[test]
+    fn environment_filter() {
+        assert!(glob_matches("*", "PATH"));
+        assert!(glob_matches("APP_*", "APP_PORT"));
+        assert!(glob_matches("*_URL", "DATABASE_URL"));
+        assert!(glob_matches("A*B*C", "AxxBxxC"));
+        assert!(!glob_matches("APP_*", "PATH"));
+        assert!(!glob_matches("HOME", "HOMEDIR"));
+    }
+
+    #[test]
+    fn exposed_environment_and_secrets() {
+        std::env::set_var("LUNATIC_TEST_EXPOSED", "1");
+        std::env::set_var("LUNATIC_TEST_HIDDEN", "2");
+        let mut config = DefaultProcessConfig::default();
+        assert!(config.exposed_environment("*").is_err());
+        assert!(config.can_expose_secret("TOKEN").is_err());
+
+        config.expose_environment("LUNATIC_TEST_EXP*".to_string());
+        config.expose_secret("TOKEN".to_string());
+        assert_eq!(
+            config.exposed_environment("LUNATIC_TEST_*").unwrap(),
+            vec![("LUNATIC_TEST_EXPOSED".to_string(), "1".to_string())]
+        );
+        assert!(config.can_expose_secret("TOKEN").is_ok());
+        assert!(config.can_expose_secret("OTHER").is_err());
+    }
+
     #[test]
     fn test_accessible_paths() {
         let crates = get_absolute_path(Path::new("crates")).unwrap();
@@ -274,6 +794,64 @@ mod tests {
         assert!(!path_is_ancestor(&src, Path::new("/etc/passwd")));
     }
 
+    #[test]
+    fn sqlite_rules() {
+        let mut config = DefaultProcessConfig::default();
+        assert_eq!(config.can_open_sqlite(Path::new("/etc/app.db")), Ok(None));
+
+        config.allow_sqlite("/data/*.db", Some(1024));
+        config.allow_sqlite("/data/*", None);
+        assert_eq!(
+            config.can_open_sqlite(Path::new("/data/app.db")),
+            Ok(Some(1024))
+        );
+        assert_eq!(
+            config.can_open_sqlite(Path::new("/data/app.sqlite")),
+            Ok(None)
+        );
+        assert!(config.can_open_sqlite(Path::new("/etc/app.db")).is_err());
+        assert!(config
+            .can_open_sqlite(Path::new("/data/../etc/app.db"))
+            .is_err());
+    }
+
+    #[test]
+    fn network_rules() {
+        let mut config = DefaultProcessConfig::default();
+        assert!(config
+            .can_access_network(Some("example.com"), None, Some(443))
+            .is_ok());
+
+        config.allow_network("*.example.com", 443..=443).unwrap();
+        config.allow_network("10.0.0.0/8", 0..=u16::MAX).unwrap();
+        config.deny_network("10.0.0.1", 0..=u16::MAX).unwrap();
+        assert!(config.allow_network("10.0.0.0/33", 0..=1).is_err());
+        assert!(config.allow_network("exa mple.com", 0..=1).is_err());
+
+        assert!(config
+            .can_access_network(Some("api.example.com"), None, Some(443))
+            .is_ok());
+        assert!(config
+            .can_access_network(Some("API.Example.com"), None, None)
+            .is_ok());
+        assert!(config
+            .can_access_network(Some("api.example.com"), None, Some(80))
+            .is_err());
+        assert!(config
+            .can_access_network(Some("example.com"), None, Some(443))
+            .is_err());
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+        assert!(config
+            .can_access_network(None, ip("10.1.2.3"), Some(22))
+            .is_ok());
+        assert!(config
+            .can_access_network(None, ip("10.0.0.1"), Some(22))
+            .is_err());
+        assert!(config
+            .can_access_network(None, ip("11.0.0.1"), Some(22))
+            .is_err());
+    }
+
     #[test]
     fn normalized_paths() {
         let crates = get_absolute_path(Path::new("crates")).unwrap();
@@ -286,18 +864,3 @@ mod tests {
         assert_eq!(crates, normalize_path(&sneaky_path));
     }
 }
-
-impl Default for DefaultProcessConfig {
-    fn default() -> Self {
-        Self {
-            max_memory: u32::MAX as usize, // = 4 GB
-            max_fuel: None,
-            can_compile_modules: false,
-            can_create_configs: false,
-            can_spawn_processes: false,
-            preopened_dirs: vec![],
-            command_line_arguments: vec![],
-            environment_variables: vec![],
-        }
-    }
-}
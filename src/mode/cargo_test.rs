@@ -1,4 +1,4 @@
-use std::{collections::HashMap, env, fs, path::Path, sync::Arc, time::Instant};
+use std::{collections::HashMap, fs, path::Path, sync::Arc, time::Instant};
 
 use anyhow::{Context, Result};
 use clap::Parser;
@@ -10,6 +10,7 @@ use lunatic_process::{
 use lunatic_process_api::ProcessConfigCtx;
 use lunatic_runtime::{DefaultProcessConfig, DefaultProcessState};
 use lunatic_stdout_capture::StdoutCapture;
+use lunatic_wasi_api::LunaticWasiConfigCtx;
 use lunatic_wasi_api::LunaticWasiCtx;
 use tokio::sync::RwLock;
 
@@ -45,6 +46,10 @@ struct Args {
     #[arg(long)]
     show_output: bool,
 
+    /// Keep at most this many bytes of each captured output, dropping the middle part
+    #[arg(long, value_name = "BYTES")]
+    capture_limit: Option<usize>,
+
     /// List all tests
     #[arg(long, requires = "format")]
     list: bool,
@@ -75,16 +80,19 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
     };
 
     let mut config = DefaultProcessConfig::default();
-    // Allow initial process to compile modules, create configurations and spawn sub-processes
+    // Allow initial process to compile modules, create configurations, spawn sub-processes, run
+    // inference and change the tags of the node
     config.set_can_compile_modules(true);
     config.set_can_create_configs(true);
     config.set_can_spawn_processes(true);
+    config.set_can_use_nn(true);
+    config.set_can_set_node_tags(true);
 
     // Set correct command line arguments for the guest
     config.set_command_line_arguments(args.wasm_args);
 
     // Inherit environment variables
-    config.set_environment_variables(env::vars().collect());
+    config.inherit_environment("*");
 
     // Always preopen the current dir
     config.preopen_dir(".");
@@ -209,6 +217,12 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
 
     let config = Arc::new(config);
 
+    let panic_regex =
+        // Modes:
+        // * m: ^ and $ match begin/end of line (not string)
+        // * s: allow . to match \n
+        regex::Regex::new("(?ms)^thread '.*' panicked at '(.*)', ").unwrap();
+
     for test_function in test_functions {
         // Skip over filtered out functions
         if test_function.filtered {
@@ -241,7 +255,7 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
 
         // If --nocapture is not set, use in-memory stdout & stderr to hide output in case of
         // success
-        let stdout = StdoutCapture::new(args.nocapture);
+        let stdout = StdoutCapture::with_limit(args.nocapture, args.capture_limit);
         state.set_stdout(stdout.clone());
         state.set_stderr(stdout.clone());
 
@@ -264,6 +278,7 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
 
         let sender = sender.clone();
         let nocapture = args.nocapture;
+        let panic_regex = panic_regex.clone();
 
         tokio::task::spawn(async move {
             let result = match task.await.unwrap() {
@@ -287,32 +302,25 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
                 }
                 Err(_err) => {
                     // Find panic output
-                    let panic_regex =
-                    // Modes:
-                    // * m: ^ and $ match begin/end of line (not string)
-                    // * s: allow . to match \n
-                    regex::Regex::new("(?ms)^thread '.*' panicked at '(.*)', ").unwrap();
-
                     let content = stdout.content();
                     let panic_detected = panic_regex.captures(&content);
 
                     // If we didn't expect a panic, but got one or were killed by a signal
-                    if test_function.panic.is_none() {
-                        // In case of --nocapture the regex will never match (content is empty).
-                        // At this point we can't be certain if there was a panic.
-                        if panic_detected.is_none() && !nocapture {
-                            stdout.push_str("note: Process trapped or received kill signal\n");
-                        }
-                        TestResult {
-                            name: test_function.function_name,
-                            status: TestStatus::Failed,
-                            stdout,
+                    match test_function.panic {
+                        None => {
+                            // In case of --nocapture the regex will never match (content is empty).
+                            // At this point we can't be certain if there was a panic.
+                            if panic_detected.is_none() && !nocapture {
+                                stdout.push_str("note: Process trapped or received kill signal\n");
+                            }
+                            TestResult {
+                                name: test_function.function_name,
+                                status: TestStatus::Failed,
+                                stdout,
+                            }
                         }
-                    } else {
-                        match panic_detected {
+                        Some(expected_panic) => match panic_detected {
                             Some(panic) => {
-                                // `test_function.panic` is always `Some` in this branch.
-                                let expected_panic = test_function.panic.unwrap();
                                 let panic_message = panic.get(1).map_or("", |m| m.as_str());
                                 if panic_message.contains(&expected_panic) {
                                     TestResult {
@@ -338,20 +346,20 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
                                 name: test_function.function_name,
                                 // This is only considered a success if the `expected` panic string
                                 // didn't contain anything.
-                                status: if test_function.panic.as_ref().unwrap() == "" {
+                                status: if expected_panic.is_empty() {
                                     TestStatus::PanicOk
                                 } else {
                                     stdout.push_str(
                                         &format!(
                                             "note: Process received kill signal, but expected a panic that contains `{}`\n",
-                                            test_function.panic.unwrap()
+                                            expected_panic
                                         )
                                     );
                                     TestStatus::PanicFailed
                                 },
                                 stdout,
                             },
-                        }
+                        },
                     }
                 }
             };
@@ -1,37 +1,270 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, Context, Result};
 use clap::Args;
 
 use lunatic_distributed::DistributedProcessState;
 use lunatic_process::{
-    env::{Environment, LunaticEnvironment, LunaticEnvironments},
-    runtimes::{wasmtime::WasmtimeRuntime, RawWasm},
-    wasm::spawn_wasm,
+    checkpoint::Checkpoint,
+    config::ProcessConfig,
+    env::{Environment, LunaticEnvironment},
+    runtimes::{artifact_cache::ArtifactCache, wasmtime::WasmtimeRuntime, RawWasm},
+    wasm::{restore_wasm, spawn_wasm},
 };
 use lunatic_process_api::ProcessConfigCtx;
 use lunatic_runtime::{DefaultProcessConfig, DefaultProcessState};
+use lunatic_wasi_api::LunaticWasiConfigCtx;
+use tokio::sync::RwLock;
 
 #[derive(Args, Debug)]
-pub struct WasmArgs {}
+pub struct WasmArgs {
+    /// Enable 64-bit and multiple linear memories (experimental). Host functions still take
+    /// 32-bit pointers into the memory exported as `memory`, which needs to stay a 32-bit memory.
+    /// Large data can be kept in additional 64-bit memories of the module. The maximum memory of
+    /// a process applies to each of its memories.
+    #[arg(long, conflicts_with = "pool_instances")]
+    pub wasm_memory64: bool,
+}
+
+impl WasmArgs {
+    pub fn apply(&self, config: &mut wasmtime::Config) {
+        if self.wasm_memory64 {
+            config
+                .wasm_memory64(true)
+                .wasm_multi_memory(true)
+                // Static memories are reserved with a size of 4 GiB, 64-bit memories need to be
+                // able to grow past it
+                .static_memory_forced(false);
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CrashDumpArgs {
+    /// Write a JSON crash report to the directory when a process with crash dumps enabled
+    /// fails. Crash dumps are enabled for the main process.
+    #[arg(long, value_name = "DIR")]
+    pub crash_dumps: Option<PathBuf>,
+}
+
+impl CrashDumpArgs {
+    pub fn apply(&self) -> Result<()> {
+        match &self.crash_dumps {
+            Some(dir) => lunatic_process::crash::set_directory(dir),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct EventLogArgs {
+    /// Append lifecycle events (spawns, exits, links, kills, registry changes and node
+    /// connections) to the file
+    #[arg(long, value_name = "FILE")]
+    pub event_log: Option<PathBuf>,
+
+    /// Format of the event log, `jsonl` (default) or `otlp`
+    #[arg(long, value_name = "FORMAT", requires = "event_log")]
+    pub event_log_format: Option<lunatic_process::events::EventFormat>,
+
+    /// Only record events of the environments with these IDs, instead of all environments
+    #[arg(
+        long,
+        value_name = "ENVIRONMENT_ID",
+        value_delimiter = ',',
+        requires = "event_log"
+    )]
+    pub event_log_environments: Vec<u64>,
+}
+
+impl EventLogArgs {
+    pub fn apply(&self) -> Result<()> {
+        match &self.event_log {
+            Some(path) => lunatic_process::events::configure(
+                path,
+                self.event_log_format.unwrap_or_default(),
+                &self.event_log_environments,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ExposeArgs {
+    /// Allow the main process to pass the runtime's environment variables matching the filter on
+    /// to the processes it spawns. The filter can contain `*` wildcards, e.g. `APP_*`.
+    #[arg(long = "expose-env", value_name = "FILTER")]
+    pub environment: Vec<String>,
+
+    /// Allow the main process to pass the secret on to the processes it spawns
+    #[arg(long = "expose-secret", value_name = "KEY")]
+    pub secrets: Vec<String>,
+}
+
+impl ExposeArgs {
+    pub fn apply(self, config: &mut DefaultProcessConfig) {
+        for filter in self.environment {
+            config.expose_environment(filter);
+        }
+        for key in self.secrets {
+            config.expose_secret(key);
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct KvArgs {
+    /// Persist the key-value stores of environments in the directory, one sqlite database per
+    /// environment. Without it the stores only live in memory.
+    #[arg(long, value_name = "DIR")]
+    pub kv_dir: Option<PathBuf>,
+
+    /// Maximum number of entries in the key-value store of each environment
+    #[arg(long, value_name = "ENTRIES")]
+    pub kv_max_entries: Option<usize>,
+
+    /// Maximum size of all keys and values in the key-value store of each environment, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub kv_max_size: Option<u64>,
+}
+
+impl KvArgs {
+    pub fn apply(&self) -> Result<()> {
+        lunatic_kv_api::store::set_quota(lunatic_kv_api::store::Quota {
+            max_entries: self.kv_max_entries,
+            max_bytes: self.kv_max_size,
+        })?;
+        match &self.kv_dir {
+            Some(dir) => lunatic_kv_api::store::configure(dir),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PluginArgs {
+    /// Transform every module with the WebAssembly plugin before compiling it. Can be passed
+    /// multiple times, the plugins are applied in order.
+    #[arg(long = "plugin", value_name = "PLUGIN_WASM")]
+    pub plugins: Vec<PathBuf>,
+
+    /// Call the `on_message_send` callback of the plugins for every message delivered to a
+    /// process. Each plugin handles one callback at a time, so this slows down the delivery of
+    /// all messages.
+    #[arg(long, requires = "plugins")]
+    pub plugin_message_callbacks: bool,
+}
+
+impl PluginArgs {
+    /// Loads the plugins into the runtime and installs their lifecycle callbacks.
+    pub fn apply(&self, runtime: WasmtimeRuntime) -> Result<WasmtimeRuntime> {
+        let plugins = lunatic_process::plugin::Plugins::load(&self.plugins)?;
+        plugins.install_lifecycle_hooks(self.plugin_message_callbacks)?;
+        Ok(runtime.with_plugins(plugins))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PoolingArgs {
+    /// Allocate processes from a pool of pre-allocated instance slots to reduce spawn latency.
+    /// Spawning fails once all slots are taken.
+    #[arg(long, value_name = "INSTANCES")]
+    pub pool_instances: Option<u32>,
+
+    /// Maximum linear memory of a process allocated from the pool, in bytes
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 64 * 1024 * 1024,
+        requires = "pool_instances"
+    )]
+    pub pool_max_memory: u64,
+
+    /// Number of unused slots that keep the memory of the module last instantiated in them, so
+    /// that spawning the same module again is faster
+    #[arg(
+        long,
+        value_name = "SLOTS",
+        default_value_t = 100,
+        requires = "pool_instances"
+    )]
+    pub pool_warm_slots: u32,
+}
+
+impl PoolingArgs {
+    /// Returns the wasmtime configuration, using the pooling allocator if enabled.
+    pub fn wasmtime_config(&self) -> wasmtime::Config {
+        match self.pool_instances {
+            Some(instances) => lunatic_process::runtimes::wasmtime::pooling_config(
+                instances,
+                self.pool_max_memory,
+                self.pool_warm_slots,
+            ),
+            None => lunatic_process::runtimes::wasmtime::default_config(),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ModuleCacheArgs {
+    /// Cache compiled modules in the directory, so that repeated runs and node restarts don't
+    /// need to compile (or transform with plugins) the same module again. The directory can be
+    /// shared by multiple runtimes.
+    #[arg(
+        long,
+        alias = "plugin-cache",
+        env = "LUNATIC_COMPILE_CACHE",
+        value_name = "DIR"
+    )]
+    pub compile_cache: Option<PathBuf>,
+
+    /// Maximum size of the compiled modules in the cache directory, the least recently used ones
+    /// are removed first
+    #[arg(
+        long,
+        value_name = "BYTES",
+        requires = "compile_cache",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub compile_cache_max_size: u64,
+}
+
+impl ModuleCacheArgs {
+    pub fn apply(&self, runtime: WasmtimeRuntime) -> Result<WasmtimeRuntime> {
+        match &self.compile_cache {
+            Some(dir) => runtime
+                .with_cache(ArtifactCache::new(dir)?.with_max_size(self.compile_cache_max_size)),
+            None => Ok(runtime),
+        }
+    }
+}
 
 pub struct RunWasm {
     pub path: PathBuf,
     pub wasm_args: Vec<String>,
     pub dir: Vec<PathBuf>,
+    // Checkpoint the main process is restored from, instead of calling `_start`
+    pub restore: Option<PathBuf>,
+    // Environment variables and secrets the main process can pass on
+    pub expose: ExposeArgs,
 
     pub runtime: WasmtimeRuntime,
-    pub envs: Arc<LunaticEnvironments>,
     pub env: Arc<LunaticEnvironment>,
     pub distributed: Option<DistributedProcessState>,
+    pub registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
 }
 
 pub async fn run_wasm(args: RunWasm) -> Result<()> {
     let mut config = DefaultProcessConfig::default();
-    // Allow initial process to compile modules, create configurations and spawn sub-processes
+    // Allow initial process to compile modules, create configurations, spawn sub-processes, run
+    // inference and change the tags of the node
     config.set_can_compile_modules(true);
     config.set_can_create_configs(true);
     config.set_can_spawn_processes(true);
+    config.set_can_use_nn(true);
+    config.set_can_set_node_tags(true);
+    config.set_crash_dumps(lunatic_process::crash::directory().is_some());
 
     // Path to wasm file
     let path = args.path;
@@ -43,7 +276,8 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
     config.set_command_line_arguments(wasi_args);
 
     // Inherit environment variables
-    config.set_environment_variables(std::env::vars().collect());
+    config.inherit_environment("*");
+    args.expose.apply(&mut config);
 
     // Always preopen the current dir
     config.preopen_dir(".");
@@ -71,30 +305,66 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
         args.runtime.clone(),
         module.clone(),
         Arc::new(config),
-        Default::default(),
+        args.registry,
     )
     .unwrap();
 
     args.env.can_spawn_next_process().await?;
-    let (task, _) = spawn_wasm(
-        args.env,
-        args.runtime,
-        &module,
-        state,
-        "_start",
-        Vec::new(),
-        None,
-    )
-    .await
-    .context(format!(
-        "Failed to spawn process from {}::_start()",
-        path.to_string_lossy()
-    ))?;
+    let (task, _) = match args.restore {
+        Some(checkpoint_path) => {
+            let checkpoint = Checkpoint::read(&checkpoint_path)?;
+            restore_wasm(args.env, args.runtime, &module, state, checkpoint, None)
+                .await
+                .context(format!(
+                    "Failed to restore process from checkpoint {}",
+                    checkpoint_path.to_string_lossy()
+                ))?
+        }
+        None => spawn_wasm(
+            args.env,
+            args.runtime,
+            &module,
+            state,
+            "_start",
+            Vec::new(),
+            None,
+        )
+        .await
+        .context(format!(
+            "Failed to spawn process from {}::_start()",
+            path.to_string_lossy()
+        ))?,
+    };
 
     // Wait on the main process to finish
     task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()))
 }
 
+#[cfg(feature = "metrics")]
+#[derive(Args, Debug)]
+pub struct MetricsArgs {
+    /// Split up the metrics of processes by node, environment or process. Every labelled process
+    /// creates new time series, which can overwhelm the metrics backend.
+    #[arg(long, value_name = "GRANULARITY")]
+    pub metrics_granularity: Option<lunatic_process::metrics_labels::MetricsGranularity>,
+
+    /// Only label every n-th process with its ID if the granularity is `process`
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub metrics_process_sampling: u64,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsArgs {
+    pub fn apply(&self) -> Result<()> {
+        use lunatic_process::metrics_labels::{configure, MetricsLabels};
+
+        configure(MetricsLabels {
+            granularity: self.metrics_granularity.unwrap_or_default(),
+            process_sampling: self.metrics_process_sampling,
+        })
+    }
+}
+
 #[cfg(feature = "prometheus")]
 #[derive(Args, Debug)]
 pub struct PrometheusArgs {
@@ -1,4 +1,7 @@
-use std::net::{SocketAddr, TcpListener};
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+};
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
@@ -7,15 +10,21 @@ use clap::Parser;
 pub(crate) struct Args {
     #[arg(long, value_name = "CONTROL_SERVER_SOCKET")]
     bind_socket: Option<SocketAddr>,
+
+    /// Keep the state in a SQLite database, so that it survives restarts. Control servers using
+    /// the same database can run as replicas behind one URL.
+    #[arg(long, value_name = "PATH")]
+    database: Option<PathBuf>,
 }
 
 pub(crate) async fn start(args: Args) -> Result<()> {
     if let Some(socket) = args.bind_socket {
         log::info!("Register URL: http://{}/", socket);
-        lunatic_control_axum::server::control_server(socket).await?;
+        lunatic_control_axum::server::control_server(socket, args.database.as_deref()).await?;
     } else if let Some(listener) = get_available_localhost() {
         log::info!("Register URL: http://{}/", listener.local_addr().unwrap());
-        lunatic_control_axum::server::control_server_from_tcp(listener).await?;
+        lunatic_control_axum::server::control_server_from_tcp(listener, args.database.as_deref())
+            .await?;
     }
 
     Err(anyhow!("No available port on 127.0.0.1. Aborting"))
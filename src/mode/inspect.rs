@@ -0,0 +1,174 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use axum::{extract::State, routing::get, Json, Router};
+use clap::Args;
+use lunatic_distributed::{
+    congestion::NodeStatus,
+    control,
+    distributed::{self, client::NodeId},
+};
+use lunatic_process::{
+    env::{Environment, LunaticEnvironments},
+    runtimes::Modules,
+};
+use lunatic_runtime::DefaultProcessState;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Serve a JSON snapshot of the processes, registered names, modules and node connections
+    /// of this runtime on the address. Anyone reaching the address can read it, so only bind it
+    /// to a local interface.
+    #[arg(long, value_name = "INSPECT_ADDRESS")]
+    pub inspect: Option<SocketAddr>,
+}
+
+/// The parts of a running node that can be inspected.
+#[derive(Clone)]
+pub struct Inspector {
+    pub envs: Arc<LunaticEnvironments>,
+    pub registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+    pub modules: Option<Modules<DefaultProcessState>>,
+    pub distributed: Option<(control::Client, distributed::Client)>,
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    node_id: Option<u64>,
+    environments: Vec<EnvironmentInfo>,
+    registry: Vec<RegisteredName>,
+    modules: Vec<u64>,
+    nodes: Vec<NodeConnection>,
+}
+
+#[derive(Serialize)]
+struct EnvironmentInfo {
+    id: u64,
+    processes: Vec<ProcessInfo>,
+}
+
+#[derive(Serialize)]
+struct ProcessInfo {
+    id: u64,
+    // Resource usage is only tracked for WebAssembly processes
+    mailbox_len: Option<u64>,
+    links: Option<u64>,
+    memory_size: Option<u64>,
+    fuel_consumed: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct RegisteredName {
+    name: String,
+    node_id: u64,
+    process_id: u64,
+}
+
+#[derive(Serialize)]
+struct NodeConnection {
+    id: u64,
+    name: String,
+    address: SocketAddr,
+    status: Option<&'static str>,
+}
+
+/// Starts serving the snapshot on `GET /` of the address.
+pub async fn serve(addr: SocketAddr, inspector: Inspector) -> Result<()> {
+    let app = Router::new()
+        .route("/", get(snapshot))
+        .with_state(inspector);
+    let server = axum::Server::try_bind(&addr)?.serve(app.into_make_service());
+    log::info!("Serving the runtime inspection API on http://{addr}");
+    tokio::task::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Inspection API failed: {e}");
+        }
+    });
+    Ok(())
+}
+
+async fn snapshot(State(inspector): State<Inspector>) -> Json<Snapshot> {
+    let environments = inspector
+        .envs
+        .environments()
+        .into_iter()
+        .map(|env| {
+            let mut processes: Vec<ProcessInfo> = env
+                .iter_processes()
+                .map(|process| {
+                    let stats = process.stats();
+                    ProcessInfo {
+                        id: process.id(),
+                        mailbox_len: stats.map(|stats| stats.mailbox_len),
+                        links: stats.map(|stats| stats.links),
+                        memory_size: stats.map(|stats| stats.memory_size),
+                        fuel_consumed: stats.map(|stats| stats.fuel_consumed),
+                    }
+                })
+                .collect();
+            processes.sort_by_key(|process| process.id);
+            EnvironmentInfo {
+                id: env.id(),
+                processes,
+            }
+        })
+        .collect();
+
+    let mut registry: Vec<RegisteredName> = inspector
+        .registry
+        .read()
+        .await
+        .iter()
+        .map(|(name, (node_id, process_id))| RegisteredName {
+            name: name.clone(),
+            node_id: *node_id,
+            process_id: *process_id,
+        })
+        .collect();
+    registry.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut modules = inspector
+        .modules
+        .as_ref()
+        .map(|modules| modules.ids())
+        .unwrap_or_default();
+    modules.sort_unstable();
+
+    let (node_id, nodes) = match &inspector.distributed {
+        Some((control, client)) => {
+            let mut node_ids = control.node_ids();
+            node_ids.sort_unstable();
+            let nodes = node_ids
+                .into_iter()
+                .filter(|id| *id != control.node_id())
+                .filter_map(|id| control.node_info(id))
+                .map(|node| NodeConnection {
+                    id: node.id,
+                    name: node.name,
+                    address: node.address,
+                    status: client.node_status(NodeId(node.id)).map(status_name),
+                })
+                .collect();
+            (Some(control.node_id()), nodes)
+        }
+        None => (None, Vec::new()),
+    };
+
+    Json(Snapshot {
+        node_id,
+        environments,
+        registry,
+        modules,
+        nodes,
+    })
+}
+
+fn status_name(status: NodeStatus) -> &'static str {
+    match status {
+        NodeStatus::Connected => "connected",
+        NodeStatus::Connecting => "connecting",
+        NodeStatus::Unreachable => "unreachable",
+    }
+}
@@ -12,6 +12,7 @@ mod config;
 mod control;
 mod deploy;
 mod init;
+mod inspect;
 mod login;
 mod node;
 mod run;
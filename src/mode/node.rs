@@ -2,16 +2,19 @@ use std::{
     collections::HashSet,
     net::{SocketAddr, UdpSocket},
     path::PathBuf,
+    time::{Duration, SystemTime},
 };
 
 use clap::Parser;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, Context, Result};
+use lunatic_control::{api::NodeStats, NodeInfo};
 use lunatic_distributed::{
-    control::{self},
-    distributed::{self, server::ServerCtx},
+    congestion::{self, Codec, CongestionConfig},
+    control::{self, peers::StaticPeers},
+    distributed::{self, server::ServerCtx, ModuleCache},
     quic,
 };
 use lunatic_process::{
@@ -21,7 +24,15 @@ use lunatic_process::{
 use lunatic_runtime::DefaultProcessState;
 use uuid::Uuid;
 
-use crate::mode::common::{run_wasm, RunWasm};
+use crate::mode::{
+    common::{run_wasm, RunWasm},
+    inspect::{InspectArgs, Inspector},
+};
+
+// How often the number of running processes is reported to the other nodes
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+// How long to wait before trying again if renewing the node certificate failed
+const RENEW_RETRY_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
@@ -33,80 +44,164 @@ pub(crate) struct Args {
     )]
     control: String,
 
+    /// How the other nodes are found: `control` asks the control server, `static:<PATH>` reads
+    /// them from a TOML peers file and `mdns` discovers them on the local network. Without a
+    /// control server the nodes trust each other through the built-in test certificate, so only
+    /// use them in trusted networks.
+    #[arg(long, value_name = "DISCOVERY", default_value = "control")]
+    discovery: Discovery,
+
     #[arg(long, value_name = "NODE_SOCKET")]
     bind_socket: Option<SocketAddr>,
 
     #[arg(long, value_name = "WASM_MODULE")]
     wasm: Option<PathBuf>,
 
+    /// Directory modules downloaded from the control server are cached in, defaults to
+    /// `lunatic/modules` in the user's cache directory
+    #[arg(long, value_name = "DIR")]
+    module_cache: Option<PathBuf>,
+
+    /// Download modules on every spawn instead of caching them on disk
+    #[arg(long, conflicts_with = "module_cache")]
+    no_module_cache: bool,
+
     /// Define key=value variable to store as node information
     #[arg(long, value_parser = parse_key_val, action = clap::ArgAction::Append)]
     tag: Vec<(String, String)>,
 
+    /// Maximum size of the chunks that messages to other nodes are split into
+    #[arg(long, value_name = "BYTES", default_value_t = congestion::DEFAULT_CHUNK_SIZE)]
+    chunk_size: usize,
+
+    /// Number of QUIC streams opened to each node
+    #[arg(long, value_name = "STREAMS", default_value_t = congestion::DEFAULT_STREAMS)]
+    streams: usize,
+
+    /// Number of message chunks queued for each node before waiting for the connection
+    #[arg(long, value_name = "CHUNKS", default_value_t = congestion::DEFAULT_NODE_QUEUE_DEPTH)]
+    node_queue_depth: usize,
+
+    /// Number of messages each process can queue for other nodes before sending waits
+    #[arg(long, value_name = "MESSAGES", default_value_t = congestion::DEFAULT_PROCESS_QUEUE_DEPTH)]
+    process_queue_depth: usize,
+
+    /// Compress messages to other nodes that support the codec (none, zstd)
+    #[arg(long, value_name = "CODEC", default_value_t = Codec::None)]
+    compression: Codec,
+
+    /// Only compress messages of at least this size
+    #[arg(long, value_name = "BYTES", default_value_t = congestion::DEFAULT_COMPRESSION_THRESHOLD)]
+    compression_threshold: usize,
+
+    #[command(flatten)]
+    expose: super::common::ExposeArgs,
+
+    #[command(flatten)]
+    inspect: InspectArgs,
+
+    #[command(flatten)]
+    crash_dumps: super::common::CrashDumpArgs,
+
+    #[command(flatten)]
+    event_log: super::common::EventLogArgs,
+
+    #[command(flatten)]
+    kv: super::common::KvArgs,
+
+    #[command(flatten)]
+    plugins: super::common::PluginArgs,
+
+    #[command(flatten)]
+    compile_cache: super::common::ModuleCacheArgs,
+
+    #[command(flatten)]
+    pooling: super::common::PoolingArgs,
+
+    #[command(flatten)]
+    wasm_features: super::common::WasmArgs,
+
+    #[cfg(feature = "metrics")]
+    #[command(flatten)]
+    metrics: super::common::MetricsArgs,
+
     #[cfg(feature = "prometheus")]
     #[command(flatten)]
     prometheus: super::common::PrometheusArgs,
 }
 
 pub(crate) async fn start(args: Args) -> Result<()> {
+    if args.chunk_size == 0
+        || args.streams == 0
+        || args.node_queue_depth == 0
+        || args.process_queue_depth == 0
+    {
+        return Err(anyhow!(
+            "--chunk-size, --streams and the queue depths must be greater than 0"
+        ));
+    }
+    if args.chunk_size > congestion::MAX_CHUNK_SIZE {
+        return Err(anyhow!(
+            "--chunk-size can't be bigger than {}",
+            congestion::MAX_CHUNK_SIZE
+        ));
+    }
+
+    #[cfg(feature = "metrics")]
+    args.metrics.apply()?;
+
     #[cfg(feature = "prometheus")]
     if args.prometheus.prometheus {
         super::common::prometheus(args.prometheus.prometheus_http, None)?;
     }
 
+    args.crash_dumps.apply()?;
+    args.event_log.apply()?;
+    args.kv.apply()?;
+
     let socket = args
         .bind_socket
         .or_else(get_available_localhost)
         .ok_or_else(|| anyhow!("No available localhost UDP port"))?;
-    let http_client = reqwest::Client::new();
-
-    // TODO unwrap, better message
-    let node_name = Uuid::new_v4();
-    let node_name_str = node_name.as_hyphenated().to_string();
     let node_attributes: HashMap<String, String> = args.tag.clone().into_iter().collect();
-    let node_cert = lunatic_distributed::distributed::server::gen_node_cert(&node_name_str)
-        .with_context(|| "Failed to generate node CSR and PK")?;
-    log::info!("Generate CSR for node name {node_name_str}");
-
-    let reg = control::Client::register(
-        &http_client,
-        args.control
-            .parse()
-            .with_context(|| "Parsing control URL")?,
+    let Membership {
+        control: control_client,
         node_name,
-        node_cert.serialize_request_pem()?,
-    )
-    .await?;
-
-    let allowed_envs = if reg.is_privileged {
-        None
-    } else {
-        Some(
-            reg.envs
-                .iter()
-                .map(|env_id| *env_id as u64)
-                .collect::<HashSet<u64>>(),
-        )
+        root_cert,
+        cert_pem_chain,
+        private_key,
+        allowed_envs,
+    } = match args.discovery {
+        Discovery::Control => register(&args.control, socket, node_attributes).await?,
+        Discovery::Static(ref path) => join_static(path.clone(), socket).await?,
+        Discovery::Mdns => join_mdns(socket, node_attributes).await?,
     };
 
-    let control_client =
-        control::Client::new(http_client.clone(), reg.clone(), socket, node_attributes).await?;
-
     let node_id = control_client.node_id();
 
-    log::info!("Registration successful, node id {}", node_id);
-
     let quic_client = quic::new_quic_client(
-        &reg.root_cert,
-        reg.cert_pem_chain
-            .get(0)
+        &root_cert,
+        cert_pem_chain
+            .first()
             .ok_or_else(|| anyhow!("No certificate available for QUIC client"))?,
-        &node_cert.serialize_private_key_pem(),
+        &private_key,
     )
     .with_context(|| "Failed to create mTLS QUIC client")?;
 
-    let distributed_client =
-        distributed::Client::new(node_id, control_client.clone(), quic_client.clone());
+    let congestion_config = CongestionConfig {
+        chunk_size: args.chunk_size,
+        streams: args.streams,
+        node_queue_depth: args.node_queue_depth,
+        process_queue_depth: args.process_queue_depth,
+        compression: args.compression,
+        compression_threshold: args.compression_threshold,
+    };
+    let distributed_client = distributed::Client::new(
+        node_id,
+        control_client.clone(),
+        quic_client.clone(),
+        congestion_config,
+    );
 
     let dist = lunatic_distributed::DistributedProcessState::new(
         node_id,
@@ -115,25 +210,82 @@ pub(crate) async fn start(args: Args) -> Result<()> {
     )
     .await?;
 
-    let wasmtime_config = runtimes::wasmtime::default_config();
+    let module_cache = if args.no_module_cache {
+        None
+    } else {
+        args.module_cache
+            .clone()
+            .or_else(|| dirs::cache_dir().map(|dir| dir.join("lunatic").join("modules")))
+            .map(ModuleCache::new)
+            .transpose()?
+    };
+
+    let mut wasmtime_config = args.pooling.wasmtime_config();
+    args.wasm_features.apply(&mut wasmtime_config);
     let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
+    let runtime = args.compile_cache.apply(args.plugins.apply(runtime)?)?;
     let envs = Arc::new(LunaticEnvironments::default());
+    let modules = Modules::<DefaultProcessState>::default();
+    let registry = Arc::default();
+
+    if let Some(addr) = args.inspect.inspect {
+        let inspector = Inspector {
+            envs: envs.clone(),
+            registry: Arc::clone(&registry),
+            modules: Some(modules.clone()),
+            distributed: Some((control_client.clone(), distributed_client.clone())),
+        };
+        crate::mode::inspect::serve(addr, inspector).await?;
+    }
 
+    let quic_server =
+        quic::new_quic_server(socket, cert_pem_chain.clone(), &private_key, &root_cert)
+            .with_context(|| "Failed to create mTLS QUIC server")?;
     let node = tokio::task::spawn(lunatic_distributed::distributed::server::node_server(
         ServerCtx {
             envs: envs.clone(),
-            modules: Modules::<DefaultProcessState>::default(),
+            modules,
             distributed: dist.clone(),
             runtime: runtime.clone(),
             node_client: distributed_client.clone(),
             allowed_envs,
+            module_cache,
         },
-        socket,
-        reg.root_cert,
-        reg.cert_pem_chain,
-        node_cert.serialize_private_key_pem(),
+        quic_server.clone(),
     ));
 
+    // Only certificates signed by a control server expire
+    let renews_certificate = control_client
+        .reg()
+        .is_some_and(|reg| reg.urls.renew_certificate.is_some());
+    if renews_certificate {
+        tokio::task::spawn(rotate_certificate(
+            control_client.clone(),
+            Credentials {
+                node_name,
+                root_cert,
+                cert_pem_chain,
+            },
+            quic_client.clone(),
+            quic_server,
+        ));
+    }
+
+    // Other nodes take the number of processes into account when placing new processes
+    let (ctrl, stats_envs) = (control_client.clone(), envs.clone());
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let stats = NodeStats {
+                processes: stats_envs.process_count() as u64,
+            };
+            if let Err(e) = ctrl.report_stats(stats).await {
+                log::debug!("Failed to report node stats: {e}");
+            }
+        }
+    });
+
     if args.wasm.is_some() {
         let env = envs.create(1).await?;
         tokio::task::spawn(async {
@@ -141,10 +293,12 @@ pub(crate) async fn start(args: Args) -> Result<()> {
                 path: args.wasm.unwrap(),
                 wasm_args: vec![],
                 dir: vec![],
+                restore: None,
+                expose: args.expose,
                 runtime,
-                envs,
                 env,
                 distributed: Some(dist),
+                registry,
             })
             .await
             {
@@ -168,6 +322,209 @@ pub(crate) async fn start(args: Args) -> Result<()> {
     Ok(())
 }
 
+// The control client and the credentials this node uses to talk to the other nodes
+struct Membership {
+    control: control::Client,
+    node_name: String,
+    root_cert: String,
+    cert_pem_chain: Vec<String>,
+    private_key: String,
+    allowed_envs: Option<HashSet<u64>>,
+}
+
+struct Credentials {
+    node_name: String,
+    root_cert: String,
+    cert_pem_chain: Vec<String>,
+}
+
+// Renews the certificate of the node before it expires. Open connections to other nodes move to
+// the new certificate after writing the messages already queued for them.
+async fn rotate_certificate(
+    control: control::Client,
+    mut credentials: Credentials,
+    quic_client: quic::Client,
+    quic_server: quic::Endpoint,
+) {
+    loop {
+        let renew_at = match credentials
+            .cert_pem_chain
+            .first()
+            .ok_or_else(|| anyhow!("No certificate to renew"))
+            .and_then(|cert| quic::certificate_validity(cert))
+        {
+            // Renew when a third of the validity is left
+            Ok((not_before, not_after)) => {
+                let validity = not_after.duration_since(not_before).unwrap_or_default();
+                not_before + validity * 2 / 3
+            }
+            Err(e) => {
+                log::error!("Failed to read the node certificate, it won't be renewed: {e}");
+                return;
+            }
+        };
+        let wait = renew_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        match renew_certificate(&control, &credentials, &quic_client, &quic_server).await {
+            Ok(cert_pem_chain) => {
+                log::info!("Node certificate renewed");
+                credentials.cert_pem_chain = cert_pem_chain;
+            }
+            Err(e) => {
+                log::error!("Failed to renew the node certificate, retrying: {e:?}");
+                tokio::time::sleep(RENEW_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn renew_certificate(
+    control: &control::Client,
+    credentials: &Credentials,
+    quic_client: &quic::Client,
+    quic_server: &quic::Endpoint,
+) -> Result<Vec<String>> {
+    let node_cert = distributed::server::gen_node_cert(&credentials.node_name)?;
+    let cert_pem_chain = control
+        .renew_certificate(node_cert.serialize_request_pem()?)
+        .await?
+        .ok_or_else(|| anyhow!("The control server doesn't renew certificates"))?;
+    let private_key = node_cert.serialize_private_key_pem();
+    quic::set_server_certificate(
+        quic_server,
+        cert_pem_chain.clone(),
+        &private_key,
+        &credentials.root_cert,
+    )?;
+    quic_client.set_certificate(
+        &credentials.root_cert,
+        cert_pem_chain
+            .first()
+            .ok_or_else(|| anyhow!("No certificate in the renewed chain"))?,
+        &private_key,
+    )?;
+    Ok(cert_pem_chain)
+}
+
+#[derive(Clone, Debug)]
+enum Discovery {
+    Control,
+    Static(PathBuf),
+    Mdns,
+}
+
+impl FromStr for Discovery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "control" => Ok(Discovery::Control),
+            "mdns" => Ok(Discovery::Mdns),
+            _ => match s.strip_prefix("static:") {
+                Some(path) if !path.is_empty() => Ok(Discovery::Static(path.into())),
+                _ => Err(anyhow!(
+                    "Unknown discovery '{s}', expected 'control', 'static:<PATH>' or 'mdns'"
+                )),
+            },
+        }
+    }
+}
+
+async fn register(
+    control_url: &str,
+    socket: SocketAddr,
+    node_attributes: HashMap<String, String>,
+) -> Result<Membership> {
+    let http_client = reqwest::Client::new();
+
+    // TODO unwrap, better message
+    let node_name = Uuid::new_v4();
+    let node_name_str = node_name.as_hyphenated().to_string();
+    let node_cert = lunatic_distributed::distributed::server::gen_node_cert(&node_name_str)
+        .with_context(|| "Failed to generate node CSR and PK")?;
+    log::info!("Generate CSR for node name {node_name_str}");
+
+    let reg = control::Client::register(
+        &http_client,
+        control_url.parse().with_context(|| "Parsing control URL")?,
+        node_name,
+        node_cert.serialize_request_pem()?,
+    )
+    .await?;
+
+    let allowed_envs = if reg.is_privileged {
+        None
+    } else {
+        Some(
+            reg.envs
+                .iter()
+                .map(|env_id| *env_id as u64)
+                .collect::<HashSet<u64>>(),
+        )
+    };
+
+    let control =
+        control::Client::new(http_client.clone(), reg.clone(), socket, node_attributes).await?;
+
+    log::info!("Registration successful, node id {}", control.node_id());
+
+    Ok(Membership {
+        control,
+        node_name: node_name_str,
+        root_cert: reg.root_cert,
+        cert_pem_chain: reg.cert_pem_chain,
+        private_key: node_cert.serialize_private_key_pem(),
+        allowed_envs,
+    })
+}
+
+async fn join_static(peers_file: PathBuf, socket: SocketAddr) -> Result<Membership> {
+    let node_name = StaticPeers::read(&peers_file)?.find(socket)?.name();
+    let control = control::Client::new_static(peers_file, socket).await?;
+    log::info!("Static peers loaded, node id {}", control.node_id());
+    local_membership(control, &node_name)
+}
+
+async fn join_mdns(
+    socket: SocketAddr,
+    node_attributes: HashMap<String, String>,
+) -> Result<Membership> {
+    let node_name = Uuid::new_v4();
+    let node = NodeInfo {
+        id: node_name.as_u64_pair().0,
+        address: socket,
+        name: node_name.as_hyphenated().to_string(),
+        codecs: Codec::SUPPORTED
+            .iter()
+            .map(|codec| codec.name().to_owned())
+            .collect(),
+        processes: None,
+        attributes: node_attributes.clone(),
+    };
+    let node_name = node.name.clone();
+    let control = control::Client::new_mdns(node, node_attributes).await?;
+    log::info!("mDNS discovery started, node id {}", control.node_id());
+    local_membership(control, &node_name)
+}
+
+// Nodes without a control server sign their own certificates with the test root certificate
+fn local_membership(control: control::Client, node_name: &str) -> Result<Membership> {
+    let root_cert = control::cert::test_root_cert()?;
+    let (cert_pem, private_key) = control::cert::local_node_certificate(node_name, &root_cert)
+        .with_context(|| "Failed to generate node certificate")?;
+    Ok(Membership {
+        control,
+        node_name: node_name.to_owned(),
+        root_cert: distributed::server::test_root_cert(),
+        cert_pem_chain: vec![cert_pem],
+        private_key,
+        allowed_envs: None,
+    })
+}
+
 fn get_available_localhost() -> Option<SocketAddr> {
     for port in 1025..65535u16 {
         let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
@@ -7,7 +7,10 @@ use lunatic_process::{
     runtimes::{self},
 };
 
-use super::common::{run_wasm, RunWasm};
+use super::{
+    common::{run_wasm, RunWasm},
+    inspect::{InspectArgs, Inspector},
+};
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -20,6 +23,11 @@ pub struct Args {
     #[arg(long)]
     pub bench: bool,
 
+    /// Restore the main process from a checkpoint written by `lunatic::process::checkpoint`
+    /// instead of calling `_start`. The checkpoint must come from the same .wasm file.
+    #[arg(long, value_name = "CHECKPOINT")]
+    pub restore: Option<PathBuf>,
+
     /// Entry .wasm file
     #[arg(index = 1)]
     pub path: PathBuf,
@@ -28,21 +36,72 @@ pub struct Args {
     #[arg(index = 2)]
     pub wasm_args: Vec<String>,
 
+    #[command(flatten)]
+    expose: super::common::ExposeArgs,
+
+    #[command(flatten)]
+    inspect: InspectArgs,
+
+    #[command(flatten)]
+    crash_dumps: super::common::CrashDumpArgs,
+
+    #[command(flatten)]
+    event_log: super::common::EventLogArgs,
+
+    #[command(flatten)]
+    kv: super::common::KvArgs,
+
+    #[command(flatten)]
+    plugins: super::common::PluginArgs,
+
+    #[command(flatten)]
+    compile_cache: super::common::ModuleCacheArgs,
+
+    #[command(flatten)]
+    pooling: super::common::PoolingArgs,
+
+    #[command(flatten)]
+    wasm_features: super::common::WasmArgs,
+
+    #[cfg(feature = "metrics")]
+    #[command(flatten)]
+    metrics: super::common::MetricsArgs,
+
     #[cfg(feature = "prometheus")]
     #[command(flatten)]
     prometheus: super::common::PrometheusArgs,
 }
 
 pub(crate) async fn start(mut args: Args) -> Result<()> {
+    args.crash_dumps.apply()?;
+    args.event_log.apply()?;
+    args.kv.apply()?;
+
+    #[cfg(feature = "metrics")]
+    args.metrics.apply()?;
+
     #[cfg(feature = "prometheus")]
     if args.prometheus.prometheus {
         super::common::prometheus(args.prometheus.prometheus_http, None)?;
     }
 
     // Create wasmtime runtime
-    let wasmtime_config = runtimes::wasmtime::default_config();
+    let mut wasmtime_config = args.pooling.wasmtime_config();
+    args.wasm_features.apply(&mut wasmtime_config);
     let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
+    let runtime = args.compile_cache.apply(args.plugins.apply(runtime)?)?;
     let envs = Arc::new(LunaticEnvironments::default());
+    let registry = Arc::default();
+
+    if let Some(addr) = args.inspect.inspect {
+        let inspector = Inspector {
+            envs: envs.clone(),
+            registry: Arc::clone(&registry),
+            modules: None,
+            distributed: None,
+        };
+        super::inspect::serve(addr, inspector).await?;
+    }
 
     let env = envs.create(1).await?;
     if args.bench {
@@ -52,10 +111,12 @@ pub(crate) async fn start(mut args: Args) -> Result<()> {
         path: args.path,
         wasm_args: args.wasm_args,
         dir: args.dir,
+        restore: args.restore,
+        expose: args.expose,
         runtime,
-        envs,
         env,
         distributed: None,
+        registry,
     })
     .await
 }
@@ -1,29 +1,45 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
 use std::sync::Arc;
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
 use lunatic_distributed::{DistributedCtx, DistributedProcessState};
 use lunatic_error_api::{ErrorCtx, ErrorResource};
+use lunatic_http_api::{HttpCtx, HttpResponseResources};
 use lunatic_networking_api::{DnsIterator, TlsConnection, TlsListener};
 use lunatic_networking_api::{NetworkingCtx, TcpConnection};
-use lunatic_process::env::{Environment, LunaticEnvironment};
+use lunatic_process::env::{Environment, LunaticEnvironment, ProcessCounterGuard};
 use lunatic_process::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 use lunatic_process::state::{ConfigResources, ProcessState};
 use lunatic_process::{
     config::ProcessConfig,
     state::{SignalReceiver, SignalSender},
 };
-use lunatic_process::{mailbox::MessageMailbox, message::Message};
-use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
-use lunatic_sqlite_api::{SQLiteConnections, SQLiteCtx, SQLiteGuestAllocators, SQLiteStatements};
+use lunatic_process::{mailbox::MessageMailbox, message::Message, stats::ProcessStats};
+use lunatic_process_api::{
+    LocalStorage, MessageSlots, ModuleVersions, ProcessConfigCtx, ProcessCtx,
+};
+use lunatic_quic_api::{
+    QuicConnectionResources, QuicCtx, QuicEndpointResources, QuicRecvStreamResources,
+    QuicSendStreamResources,
+};
+use lunatic_sqlite_api::{
+    SQLiteBlobs, SQLiteConnections, SQLiteCtx, SQLiteGuestAllocators, SQLiteStatementCache,
+    SQLiteStatements,
+};
 use lunatic_stdout_capture::StdoutCapture;
 use lunatic_timer_api::{TimerCtx, TimerResources};
-use lunatic_wasi_api::{build_wasi, LunaticWasiCtx};
+use lunatic_wasi_api::{build_wasi, LunaticWasiCtx, OutputSink, StdinPipeResources};
+use lunatic_wasi_nn_api::{NnContextResources, NnGraphResources, WasiNnCtx};
+use lunatic_websocket_api::{WebSocketCtx, WebSocketResources};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::{Mutex, RwLock};
+use wasi_common::{file::FileCaps, WasiFile};
 use wasmtime::{Linker, ResourceLimiter};
 use wasmtime_wasi::WasiCtx;
 
@@ -31,9 +47,12 @@ use crate::DefaultProcessConfig;
 
 #[derive(Debug, Default)]
 pub struct DbResources {
-    // sqlite data
-    sqlite_connections: SQLiteConnections,
+    // sqlite data, statements are dropped before the connections because sqlite can't close a
+    // connection with unfinalized statements and would keep its open transactions around
     sqlite_statements: SQLiteStatements,
+    sqlite_statement_cache: SQLiteStatementCache,
+    sqlite_blobs: SQLiteBlobs,
+    sqlite_connections: SQLiteConnections,
     sqlite_guest_allocator: SQLiteGuestAllocators,
 }
 
@@ -54,10 +73,22 @@ pub struct DefaultProcessState {
     // guest to reserve enough space, and then it's received. Both of those actions use
     // `message` as a temp space to store messages across host calls.
     message: Option<Message>,
+    // Additional spaces for messages, so that multiple messages can be built at the same time
+    message_slots: MessageSlots,
     // Signals sent to the mailbox
     signal_mailbox: (SignalSender, SignalReceiver),
     // Messages sent to the process
     message_mailbox: MessageMailbox,
+    // Resource usage counters
+    stats: ProcessStats,
+    // Combined size of all linear memories, in bytes
+    memory_size: usize,
+    // Slots taken in the process limits of all subtrees this process belongs to
+    process_counters: ProcessCounterGuard,
+    // Process-local key/value storage
+    local_storage: LocalStorage,
+    // Trace context attached to created messages and taken over from received ones
+    trace_context: Option<Vec<u8>>,
     // Resources
     resources: Resources,
     // WASI
@@ -71,6 +102,8 @@ pub struct DefaultProcessState {
     // database resources
     db_resources: DbResources,
     registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+    // Named module versions, shared by all processes spawned from the same root process
+    module_versions: ModuleVersions<Self>,
 }
 
 impl DefaultProcessState {
@@ -84,7 +117,26 @@ impl DefaultProcessState {
     ) -> Result<Self> {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
-        let message_mailbox = MessageMailbox::default();
+        let message_mailbox = MessageMailbox::new(
+            config.get_max_mailbox_size(),
+            config.get_mailbox_overflow_policy(),
+        );
+        message_mailbox.set_watermark(config.get_mailbox_watermark());
+        message_mailbox.set_max_message_size(config.get_max_message_size());
+        let stats = ProcessStats::new(message_mailbox.clone());
+        let wasi = build_wasi(
+            Some(config.command_line_arguments()),
+            Some(config.environment_variables()),
+            config.secrets(),
+            config.preopened_dirs(),
+            config.fs_limits(),
+            config.stdin(),
+            config.stdout(),
+            config.stderr(),
+            config.fixed_clock(),
+            config.random_seed(),
+            environment.as_ref(),
+        )?;
         let state = Self {
             id: environment.get_next_process_id(),
             environment,
@@ -93,18 +145,21 @@ impl DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            message_slots: MessageSlots::default(),
             signal_mailbox,
             message_mailbox,
+            stats,
+            memory_size: 0,
+            process_counters: ProcessCounterGuard::default(),
+            local_storage: LocalStorage::new(),
+            trace_context: None,
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-            )?,
+            wasi,
             wasi_stdout: None,
             wasi_stderr: None,
             initialized: false,
             registry,
+            module_versions: ModuleVersions::default(),
             db_resources: DbResources::default(),
         };
         Ok(state)
@@ -121,7 +176,26 @@ impl ProcessState for DefaultProcessState {
     ) -> Result<Self> {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
-        let message_mailbox = MessageMailbox::default();
+        let message_mailbox = MessageMailbox::new(
+            config.get_max_mailbox_size(),
+            config.get_mailbox_overflow_policy(),
+        );
+        message_mailbox.set_watermark(config.get_mailbox_watermark());
+        message_mailbox.set_max_message_size(config.get_max_message_size());
+        let stats = ProcessStats::new(message_mailbox.clone());
+        let wasi = build_wasi(
+            Some(config.command_line_arguments()),
+            Some(config.environment_variables()),
+            config.secrets(),
+            config.preopened_dirs(),
+            config.fs_limits(),
+            config.stdin(),
+            config.stdout(),
+            config.stderr(),
+            config.fixed_clock(),
+            config.random_seed(),
+            self.environment.as_ref(),
+        )?;
         let state = Self {
             id: self.environment.get_next_process_id(),
             environment: self.environment.clone(),
@@ -130,18 +204,21 @@ impl ProcessState for DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            message_slots: MessageSlots::default(),
             signal_mailbox,
             message_mailbox,
+            stats,
+            memory_size: 0,
+            process_counters: ProcessCounterGuard::default(),
+            local_storage: LocalStorage::new(),
+            trace_context: self.trace_context.clone(),
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-            )?,
+            wasi,
             wasi_stdout: None,
             wasi_stderr: None,
             initialized: false,
             registry: self.registry.clone(),
+            module_versions: self.module_versions.clone(),
             db_resources: DbResources::default(),
         };
         Ok(state)
@@ -153,11 +230,17 @@ impl ProcessState for DefaultProcessState {
         lunatic_messaging_api::register(linker)?;
         lunatic_timer_api::register(linker)?;
         lunatic_networking_api::register(linker)?;
+        lunatic_http_api::register(linker)?;
+        lunatic_log_api::register(linker)?;
+        lunatic_websocket_api::register(linker)?;
+        lunatic_quic_api::register(linker)?;
         lunatic_version_api::register(linker)?;
         lunatic_wasi_api::register(linker)?;
+        lunatic_wasi_nn_api::register(linker)?;
         lunatic_registry_api::register(linker)?;
         lunatic_distributed_api::register(linker)?;
         lunatic_sqlite_api::register(linker)?;
+        lunatic_kv_api::register(linker)?;
         #[cfg(feature = "metrics")]
         lunatic_metrics_api::register(linker)?;
         lunatic_trap_api::register(linker)?;
@@ -172,6 +255,11 @@ impl ProcessState for DefaultProcessState {
         self.initialized
     }
 
+    fn reset_memory_size(&mut self) {
+        self.memory_size = 0;
+        self.stats.set_memory_size(0);
+    }
+
     fn runtime(&self) -> &WasmtimeRuntime {
         self.runtime.as_ref().unwrap()
     }
@@ -196,6 +284,10 @@ impl ProcessState for DefaultProcessState {
         &self.message_mailbox
     }
 
+    fn stats(&self) -> &ProcessStats {
+        &self.stats
+    }
+
     fn config_resources(&self) -> &ConfigResources<<DefaultProcessState as ProcessState>::Config> {
         &self.resources.configs
     }
@@ -219,10 +311,21 @@ impl Debug for DefaultProcessState {
     }
 }
 
-// Limit the maximum memory of the process depending on the environment it was spawned in.
+// Modules can only use multiple memories if multi-memory is enabled, e.g. to keep large data in a
+// 64-bit memory next to the 32-bit memory that host functions use.
+const MAX_MEMORIES: usize = 8;
+
+// Limit the maximum memory of the process depending on the environment it was spawned in. With
+// multiple memories the limit applies to their combined size.
 impl ResourceLimiter for DefaultProcessState {
-    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
-        desired <= self.config().get_max_memory()
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        let total = self.memory_size - current + desired;
+        let allowed = total <= self.config().get_max_memory();
+        if allowed {
+            self.memory_size = total;
+            self.stats.set_memory_size(total);
+        }
+        allowed
     }
 
     fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
@@ -239,9 +342,8 @@ impl ResourceLimiter for DefaultProcessState {
         1
     }
 
-    // Allow one memory per store
     fn memories(&self) -> usize {
-        1
+        MAX_MEMORIES
     }
 }
 
@@ -264,6 +366,10 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
         &mut self.message
     }
 
+    fn message_slots(&mut self) -> &mut MessageSlots {
+        &mut self.message_slots
+    }
+
     fn module_resources(&self) -> &lunatic_process_api::ModuleResources<DefaultProcessState> {
         &self.resources.modules
     }
@@ -274,9 +380,37 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
         &mut self.resources.modules
     }
 
+    fn module_versions(&self) -> &ModuleVersions<DefaultProcessState> {
+        &self.module_versions
+    }
+
     fn environment(&self) -> Arc<dyn Environment> {
         self.environment.clone()
     }
+
+    fn process_counters(&self) -> &ProcessCounterGuard {
+        &self.process_counters
+    }
+
+    fn set_process_counters(&mut self, counters: ProcessCounterGuard) {
+        self.process_counters = counters;
+    }
+
+    fn local_storage(&self) -> &LocalStorage {
+        &self.local_storage
+    }
+
+    fn local_storage_mut(&mut self) -> &mut LocalStorage {
+        &mut self.local_storage
+    }
+
+    fn trace_context(&self) -> Option<&[u8]> {
+        self.trace_context.as_deref()
+    }
+
+    fn set_trace_context(&mut self, context: Option<Vec<u8>>) {
+        self.trace_context = context;
+    }
 }
 
 impl NetworkingCtx for DefaultProcessState {
@@ -296,6 +430,16 @@ impl NetworkingCtx for DefaultProcessState {
         &mut self.resources.tcp_streams
     }
 
+    fn tcp_connecting_resources(&self) -> &lunatic_networking_api::TcpConnectingResources {
+        &self.resources.tcp_connecting
+    }
+
+    fn tcp_connecting_resources_mut(
+        &mut self,
+    ) -> &mut lunatic_networking_api::TcpConnectingResources {
+        &mut self.resources.tcp_connecting
+    }
+
     fn tls_listener_resources(&self) -> &lunatic_networking_api::TlsListenerResources {
         &self.resources.tls_listeners
     }
@@ -312,6 +456,28 @@ impl NetworkingCtx for DefaultProcessState {
         &mut self.resources.tls_streams
     }
 
+    #[cfg(unix)]
+    fn unix_listener_resources(&self) -> &lunatic_networking_api::UnixListenerResources {
+        &self.resources.unix_listeners
+    }
+
+    #[cfg(unix)]
+    fn unix_listener_resources_mut(
+        &mut self,
+    ) -> &mut lunatic_networking_api::UnixListenerResources {
+        &mut self.resources.unix_listeners
+    }
+
+    #[cfg(unix)]
+    fn unix_stream_resources(&self) -> &lunatic_networking_api::UnixStreamResources {
+        &self.resources.unix_streams
+    }
+
+    #[cfg(unix)]
+    fn unix_stream_resources_mut(&mut self) -> &mut lunatic_networking_api::UnixStreamResources {
+        &mut self.resources.unix_streams
+    }
+
     fn udp_resources(&self) -> &lunatic_networking_api::UdpResources {
         &self.resources.udp_sockets
     }
@@ -327,6 +493,137 @@ impl NetworkingCtx for DefaultProcessState {
     fn dns_resources_mut(&mut self) -> &mut lunatic_networking_api::DnsResources {
         &mut self.resources.dns_iterators
     }
+
+    fn dns_resolver_resources(&self) -> &lunatic_networking_api::DnsResolverResources {
+        &self.resources.dns_resolvers
+    }
+
+    fn dns_resolver_resources_mut(&mut self) -> &mut lunatic_networking_api::DnsResolverResources {
+        &mut self.resources.dns_resolvers
+    }
+
+    fn dns_record_resources(&self) -> &lunatic_networking_api::DnsRecordResources {
+        &self.resources.dns_records
+    }
+
+    fn dns_record_resources_mut(&mut self) -> &mut lunatic_networking_api::DnsRecordResources {
+        &mut self.resources.dns_records
+    }
+
+    fn can_access_fs_location(&self, path: &std::path::Path) -> Result<(), String> {
+        self.config.can_access_fs_location(path)
+    }
+
+    fn can_access_network(&self, host: &str, port: Option<u16>) -> Result<(), String> {
+        match host.parse::<IpAddr>() {
+            // Addresses resolved from a host name are also checked against the rules for the name
+            Ok(ip) => {
+                let host = self.resources.resolved_hosts.get(&ip);
+                self.config.can_access_network(host, Some(ip), port)
+            }
+            Err(_) => self.config.can_access_network(Some(host), None, port),
+        }
+    }
+
+    fn add_resolved_host(&mut self, addr: IpAddr, host: &str) {
+        self.resources
+            .resolved_hosts
+            .insert(addr, host.to_ascii_lowercase());
+    }
+
+    fn wait_for_message(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mailbox = self.message_mailbox.clone();
+        Box::pin(async move { mailbox.wait().await })
+    }
+
+    fn push_wasi_file(&mut self, file: Box<dyn WasiFile>) -> Result<u32> {
+        Ok(self.wasi.push_file(file, FileCaps::POLL_READWRITE)?)
+    }
+
+    fn wasi_ctx(&self) -> &WasiCtx {
+        &self.wasi
+    }
+}
+
+impl HttpCtx for DefaultProcessState {
+    fn http_response_resources(&self) -> &HttpResponseResources {
+        &self.resources.http_responses
+    }
+
+    fn http_response_resources_mut(&mut self) -> &mut HttpResponseResources {
+        &mut self.resources.http_responses
+    }
+
+    fn can_access_network(&self, host: &str, port: Option<u16>) -> Result<(), String> {
+        NetworkingCtx::can_access_network(self, host, port)
+    }
+
+    fn can_access_address(&self, host: &str, ip: IpAddr, port: u16) -> Result<(), String> {
+        self.config
+            .can_access_network(Some(host), Some(ip), Some(port))
+    }
+}
+
+impl WebSocketCtx for DefaultProcessState {
+    fn websocket_resources(&self) -> &WebSocketResources {
+        &self.resources.websockets
+    }
+
+    fn websocket_resources_mut(&mut self) -> &mut WebSocketResources {
+        &mut self.resources.websockets
+    }
+}
+
+impl QuicCtx for DefaultProcessState {
+    fn quic_endpoint_resources(&self) -> &QuicEndpointResources {
+        &self.resources.quic_endpoints
+    }
+
+    fn quic_endpoint_resources_mut(&mut self) -> &mut QuicEndpointResources {
+        &mut self.resources.quic_endpoints
+    }
+
+    fn quic_connection_resources(&self) -> &QuicConnectionResources {
+        &self.resources.quic_connections
+    }
+
+    fn quic_connection_resources_mut(&mut self) -> &mut QuicConnectionResources {
+        &mut self.resources.quic_connections
+    }
+
+    fn quic_send_stream_resources(&self) -> &QuicSendStreamResources {
+        &self.resources.quic_send_streams
+    }
+
+    fn quic_send_stream_resources_mut(&mut self) -> &mut QuicSendStreamResources {
+        &mut self.resources.quic_send_streams
+    }
+
+    fn quic_recv_stream_resources(&self) -> &QuicRecvStreamResources {
+        &self.resources.quic_recv_streams
+    }
+
+    fn quic_recv_stream_resources_mut(&mut self) -> &mut QuicRecvStreamResources {
+        &mut self.resources.quic_recv_streams
+    }
+}
+
+impl WasiNnCtx for DefaultProcessState {
+    fn nn_graph_resources(&self) -> &NnGraphResources {
+        &self.resources.nn_graphs
+    }
+
+    fn nn_graph_resources_mut(&mut self) -> &mut NnGraphResources {
+        &mut self.resources.nn_graphs
+    }
+
+    fn nn_context_resources(&self) -> &NnContextResources {
+        &self.resources.nn_contexts
+    }
+
+    fn nn_context_resources_mut(&mut self) -> &mut NnContextResources {
+        &mut self.resources.nn_contexts
+    }
 }
 
 impl TimerCtx for DefaultProcessState {
@@ -348,16 +645,20 @@ impl LunaticWasiCtx for DefaultProcessState {
         &mut self.wasi
     }
 
-    // Redirect the stdout stream
+    // Redirect the stdout stream, unless the configuration sends it to a sink
     fn set_stdout(&mut self, stdout: StdoutCapture) {
-        self.wasi_stdout = Some(stdout.clone());
-        self.wasi.set_stdout(Box::new(stdout));
+        if matches!(self.config.stdout(), OutputSink::Inherit) {
+            self.wasi_stdout = Some(stdout.clone());
+            self.wasi.set_stdout(Box::new(stdout));
+        }
     }
 
-    // Redirect the stderr stream
+    // Redirect the stderr stream, unless the configuration sends it to a sink
     fn set_stderr(&mut self, stderr: StdoutCapture) {
-        self.wasi_stderr = Some(stderr.clone());
-        self.wasi.set_stderr(Box::new(stderr));
+        if matches!(self.config.stderr(), OutputSink::Inherit) {
+            self.wasi_stderr = Some(stderr.clone());
+            self.wasi.set_stderr(Box::new(stderr));
+        }
     }
 
     fn get_stdout(&self) -> Option<&StdoutCapture> {
@@ -367,6 +668,18 @@ impl LunaticWasiCtx for DefaultProcessState {
     fn get_stderr(&self) -> Option<&StdoutCapture> {
         self.wasi_stderr.as_ref()
     }
+
+    fn stdin_pipe_resources(&self) -> &StdinPipeResources {
+        &self.resources.stdin_pipes
+    }
+
+    fn stdin_pipe_resources_mut(&mut self) -> &mut StdinPipeResources {
+        &mut self.resources.stdin_pipes
+    }
+
+    fn can_access_fs_location(&self, path: &std::path::Path) -> Result<(), String> {
+        self.config.can_access_fs_location(path)
+    }
 }
 
 impl SQLiteCtx for DefaultProcessState {
@@ -392,6 +705,18 @@ impl SQLiteCtx for DefaultProcessState {
     fn sqlite_guest_allocator_mut(&mut self) -> &mut SQLiteGuestAllocators {
         &mut self.db_resources.sqlite_guest_allocator
     }
+
+    fn sqlite_statement_cache_mut(&mut self) -> &mut SQLiteStatementCache {
+        &mut self.db_resources.sqlite_statement_cache
+    }
+
+    fn sqlite_blobs(&self) -> &SQLiteBlobs {
+        &self.db_resources.sqlite_blobs
+    }
+
+    fn sqlite_blobs_mut(&mut self) -> &mut SQLiteBlobs {
+        &mut self.db_resources.sqlite_blobs
+    }
 }
 
 #[derive(Default, Debug)]
@@ -400,14 +725,62 @@ pub(crate) struct Resources {
     pub(crate) modules: HashMapId<Arc<WasmtimeCompiledModule<DefaultProcessState>>>,
     pub(crate) timers: TimerResources,
     pub(crate) dns_iterators: HashMapId<DnsIterator>,
-    pub(crate) tcp_listeners: HashMapId<TcpListener>,
+    pub(crate) dns_resolvers: lunatic_networking_api::DnsResolverResources,
+    pub(crate) dns_records: lunatic_networking_api::DnsRecordResources,
+    // Host names that addresses were resolved from, used by the network policy
+    pub(crate) resolved_hosts: ResolvedHosts,
+    pub(crate) tcp_listeners: HashMapId<Arc<TcpListener>>,
     pub(crate) tcp_streams: HashMapId<Arc<TcpConnection>>,
-    pub(crate) tls_listeners: HashMapId<TlsListener>,
+    pub(crate) tcp_connecting: lunatic_networking_api::TcpConnectingResources,
+    pub(crate) tls_listeners: HashMapId<Arc<TlsListener>>,
     pub(crate) tls_streams: HashMapId<Arc<TlsConnection>>,
     pub(crate) udp_sockets: HashMapId<Arc<UdpSocket>>,
+    pub(crate) http_responses: HttpResponseResources,
+    pub(crate) websockets: WebSocketResources,
+    pub(crate) quic_endpoints: QuicEndpointResources,
+    pub(crate) quic_connections: QuicConnectionResources,
+    pub(crate) quic_send_streams: QuicSendStreamResources,
+    pub(crate) quic_recv_streams: QuicRecvStreamResources,
+    pub(crate) stdin_pipes: StdinPipeResources,
+    pub(crate) nn_graphs: NnGraphResources,
+    pub(crate) nn_contexts: NnContextResources,
+    #[cfg(unix)]
+    pub(crate) unix_listeners: lunatic_networking_api::UnixListenerResources,
+    #[cfg(unix)]
+    pub(crate) unix_streams: lunatic_networking_api::UnixStreamResources,
     pub(crate) errors: HashMapId<anyhow::Error>,
 }
 
+// Number of resolved addresses a process remembers the host names of
+const MAX_RESOLVED_HOSTS: usize = 1024;
+
+// Host names of the most recently resolved addresses. Once the limit is reached the oldest
+// addresses are forgotten, connecting to them is then only checked against the rules for the IP.
+#[derive(Default, Debug)]
+pub(crate) struct ResolvedHosts {
+    hosts: HashMap<IpAddr, String>,
+    // Oldest first
+    order: VecDeque<IpAddr>,
+}
+
+impl ResolvedHosts {
+    pub(crate) fn get(&self, addr: &IpAddr) -> Option<&str> {
+        self.hosts.get(addr).map(String::as_str)
+    }
+
+    pub(crate) fn insert(&mut self, addr: IpAddr, host: String) {
+        if self.hosts.insert(addr, host).is_some() {
+            self.order.retain(|resolved| resolved != &addr);
+        }
+        self.order.push_back(addr);
+        if self.order.len() > MAX_RESOLVED_HOSTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hosts.remove(&oldest);
+            }
+        }
+    }
+}
+
 impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
     fn distributed_mut(&mut self) -> Result<&mut DistributedProcessState> {
         match self.distributed.as_mut() {
@@ -438,6 +811,10 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
         self.config().can_spawn_processes()
     }
 
+    fn can_set_node_tags(&self) -> bool {
+        self.config().can_set_node_tags()
+    }
+
     fn new_dist_state(
         environment: Arc<LunaticEnvironment>,
         distributed: DistributedProcessState,
@@ -447,7 +824,26 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
     ) -> Result<Self> {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
-        let message_mailbox = MessageMailbox::default();
+        let message_mailbox = MessageMailbox::new(
+            config.get_max_mailbox_size(),
+            config.get_mailbox_overflow_policy(),
+        );
+        message_mailbox.set_watermark(config.get_mailbox_watermark());
+        message_mailbox.set_max_message_size(config.get_max_message_size());
+        let stats = ProcessStats::new(message_mailbox.clone());
+        let wasi = build_wasi(
+            Some(config.command_line_arguments()),
+            Some(config.environment_variables()),
+            config.secrets(),
+            config.preopened_dirs(),
+            config.fs_limits(),
+            config.stdin(),
+            config.stdout(),
+            config.stderr(),
+            config.fixed_clock(),
+            config.random_seed(),
+            environment.as_ref(),
+        )?;
         let state = Self {
             id: environment.get_next_process_id(),
             environment,
@@ -456,18 +852,21 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            message_slots: MessageSlots::default(),
             signal_mailbox,
             message_mailbox,
+            stats,
+            memory_size: 0,
+            process_counters: ProcessCounterGuard::default(),
+            local_storage: LocalStorage::new(),
+            trace_context: None,
             resources: Resources::default(),
-            wasi: build_wasi(
-                Some(config.command_line_arguments()),
-                Some(config.environment_variables()),
-                config.preopened_dirs(),
-            )?,
+            wasi,
             wasi_stdout: None,
             wasi_stderr: None,
             initialized: false,
             registry: Default::default(), // TODO move registry into env?
+            module_versions: ModuleVersions::default(), // TODO share through the control server
             db_resources: DbResources::default(),
         };
         Ok(state)
@@ -516,4 +915,877 @@ mod tests {
             .await
             .unwrap();
     }
+
+    // Spawns the exported function `test` of the module as a process with the configuration
+    #[cfg(test)]
+    async fn spawn_test_module(
+        runtime: lunatic_process::runtimes::wasmtime::WasmtimeRuntime,
+        raw_module: Vec<u8>,
+        config: crate::DefaultProcessConfig,
+    ) -> anyhow::Result<(
+        tokio::task::JoinHandle<anyhow::Result<crate::state::DefaultProcessState>>,
+        std::sync::Arc<dyn lunatic_process::Process>,
+    )> {
+        use std::collections::HashMap;
+        use tokio::sync::RwLock;
+
+        use crate::state::DefaultProcessState;
+        use lunatic_process::env::Environment;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let module = Arc::new(runtime.compile_module(raw_module.into())?);
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(RwLock::new(HashMap::new()));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            registry,
+        )?;
+        env.can_spawn_next_process().await?;
+        spawn_wasm(env, runtime, &module, state, "test", Vec::new(), None).await
+    }
+
+    // Runs the exported function `test` of the module as a process with the configuration
+    #[cfg(test)]
+    async fn run_test_module(
+        runtime: lunatic_process::runtimes::wasmtime::WasmtimeRuntime,
+        raw_module: Vec<u8>,
+        config: crate::DefaultProcessConfig,
+    ) -> anyhow::Result<crate::state::DefaultProcessState> {
+        let (task, _) = spawn_test_module(runtime, raw_module, config).await?;
+        task.await?
+    }
+
+    #[cfg(test)]
+    fn test_runtime() -> lunatic_process::runtimes::wasmtime::WasmtimeRuntime {
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        lunatic_process::runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sqlite_column_name() {
+        use crate::DefaultProcessConfig;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        let path = dir.join("test.db");
+        let path = path.to_str().unwrap();
+
+        // Traps if any of the calls fails or the column name isn't "name"
+        let create = "CREATE TABLE t(name); INSERT INTO t VALUES ('x');";
+        let select = "SELECT name FROM t";
+        let raw_module = wat::parse_str(format!(
+            r#"(module
+                (import "lunatic::sqlite" "open" (func $open (param i32 i32 i32) (result i64)))
+                (import "lunatic::sqlite" "execute" (func $execute (param i64 i32 i32) (result i32)))
+                (import "lunatic::sqlite" "query_prepare" (func $prepare (param i64 i32 i32) (result i64)))
+                (import "lunatic::sqlite" "sqlite3_step" (func $step (param i64) (result i32)))
+                (import "lunatic::sqlite" "column_name" (func $column_name (param i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 4096))
+                (data (i32.const 1024) "{path}")
+                (data (i32.const 2048) "{create}")
+                (data (i32.const 3072) "{select}")
+                (func (export "lunatic_alloc") (param i32) (result i32)
+                    global.get $heap
+                    global.get $heap
+                    local.get 0
+                    i32.add
+                    global.set $heap)
+                (func (export "test") (local $conn i64) (local $statement i64) (local $name i32)
+                    (if (i64.ne (call $open (i32.const 1024) (i32.const {}) (i32.const 0)) (i64.const 0))
+                        (then unreachable))
+                    (local.set $conn (i64.load (i32.const 0)))
+                    (if (i32.ne (call $execute (local.get $conn) (i32.const 2048) (i32.const {})) (i32.const 0))
+                        (then unreachable))
+                    (local.set $statement (call $prepare (local.get $conn) (i32.const 3072) (i32.const {})))
+                    ;; SQLITE_ROW
+                    (if (i32.ne (call $step (local.get $statement)) (i32.const 100))
+                        (then unreachable))
+                    (local.set $name (call $column_name (local.get $statement) (i32.const 0) (i32.const 8)))
+                    (if (i64.ne (i64.load (i32.const 8)) (i64.const 4))
+                        (then unreachable))
+                    ;; "name" in little endian
+                    (if (i32.ne (i32.load (local.get $name)) (i32.const 0x656d616e))
+                        (then unreachable)))
+            )"#,
+            path.len(),
+            create.len(),
+            select.len(),
+        ))
+        .unwrap();
+        let result = run_test_module(test_runtime(), raw_module, config).await;
+        std::fs::remove_dir_all(dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn secrets_need_to_be_exposed() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+        use lunatic_wasi_api::LunaticWasiConfigCtx;
+
+        // Passes the secret "TOKEN" on to a new configuration
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::wasi" "config_add_secret" (func $add_secret (param i64 i32 i32 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "TOKEN")
+                (func (export "test")
+                    (call $add_secret (call $create_config) (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 5)))
+            )"#,
+        )
+        .unwrap();
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        assert!(
+            run_test_module(test_runtime(), raw_module.clone(), config.clone())
+                .await
+                .is_err()
+        );
+        config.expose_secret("TOKEN".to_string());
+        run_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn receive_range_rejects_inverted_ranges() {
+        use crate::DefaultProcessConfig;
+
+        // Times out with an empty mailbox, or traps before waiting if the range is inverted
+        let module = |min_tag: i64, max_tag: i64| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "lunatic::message" "receive_range" (func $receive_range (param i64 i64 i64) (result i32)))
+                    (memory (export "memory") 1)
+                    (func (export "test")
+                        (if (i32.ne (call $receive_range (i64.const {min_tag}) (i64.const {max_tag}) (i64.const 0)) (i32.const 9027))
+                            (then unreachable)))
+                )"#
+            ))
+            .unwrap()
+        };
+
+        run_test_module(
+            test_runtime(),
+            module(1, 1),
+            DefaultProcessConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert!(run_test_module(
+            test_runtime(),
+            module(2, 1),
+            DefaultProcessConfig::default()
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn memory64_modules_export_a_32_bit_memory() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config
+            .async_support(true)
+            .consume_fuel(true)
+            .wasm_memory64(true)
+            .wasm_multi_memory(true)
+            .static_memory_forced(false);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Looks up an empty key, the host function reads it from the exported memory
+        let module = |memory: &str| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "lunatic::kv" "get" (func $get (param i32 i32 i32 i32) (result i64)))
+                    {memory}
+                    (func (export "test")
+                        (if (i64.ne (call $get (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)) (i64.const -1))
+                            (then unreachable)))
+                )"#
+            ))
+            .unwrap()
+        };
+
+        // Large data lives in a second, 64-bit memory
+        let raw_module = module(r#"(memory (export "memory") 1) (memory $heap i64 1)"#);
+        run_test_module(runtime.clone(), raw_module, DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        // Host functions can't access a 64-bit `memory`
+        let raw_module = module(r#"(memory (export "memory") i64 1)"#);
+        assert!(
+            run_test_module(runtime, raw_module, DefaultProcessConfig::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_limit_applies_to_all_memories() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process::{config::ProcessConfig, runtimes::wasmtime::WasmtimeRuntime};
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config
+            .async_support(true)
+            .consume_fuel(true)
+            .wasm_multi_memory(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Two memories of one page each, the second one grows by `pages`
+        let module = |pages: u32| {
+            wat::parse_str(format!(
+                r#"(module
+                    (memory (export "memory") 1)
+                    (memory $heap 1)
+                    (func (export "test")
+                        (if (i32.eq (memory.grow $heap (i32.const {pages})) (i32.const -1))
+                            (then unreachable)))
+                )"#
+            ))
+            .unwrap()
+        };
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_max_memory(3 * 65536);
+        let state = run_test_module(runtime.clone(), module(1), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(state.memory_size, 3 * 65536);
+        assert_eq!(state.stats.snapshot().memory_size, 3 * 65536);
+        assert!(run_test_module(runtime, module(2), config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn finished_processes_report_consumed_fuel() {
+        use crate::DefaultProcessConfig;
+
+        // Finishes before consuming enough fuel to yield even once
+        let raw_module = wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "test") (local $i i32)
+                    (loop $count
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $count (i32.lt_u (local.get $i) (i32.const 100)))))
+            )"#,
+        )
+        .unwrap();
+        let state = run_test_module(test_runtime(), raw_module, DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        assert!(state.stats.snapshot().fuel_consumed >= 100);
+    }
+
+    #[tokio::test]
+    async fn spawning_past_the_process_limit_fails() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        config.set_can_spawn_processes(true);
+
+        // Children wait for messages, so they keep their slot. Traps if a spawn doesn't return the
+        // expected result.
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::process" "config_set_max_processes" (func $set_max_processes (param i64 i64)))
+                (import "lunatic::process" "spawn" (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "wait")
+                (global $config (mut i64) (i64.const 0))
+                (func (export "wait")
+                    (loop $wait
+                        (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                        (br $wait)))
+                (func $spawn_wait (param $expected i32)
+                    (if (i32.ne (call $spawn (i64.const 0) (global.get $config) (i64.const -1)
+                                    (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 16))
+                                (local.get $expected))
+                        (then unreachable)))
+                (func (export "test")
+                    (global.set $config (call $create_config))
+                    (call $set_max_processes (global.get $config) (i64.const 1))
+                    (call $spawn_wait (i32.const 0))
+                    (call $spawn_wait (i32.const 1))
+                    ;; Raising the limit keeps counting the running child
+                    (call $set_max_processes (global.get $config) (i64.const 2))
+                    (call $spawn_wait (i32.const 0))
+                    (call $spawn_wait (i32.const 1)))
+            )"#,
+        )
+        .unwrap();
+        run_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_storage_is_limited() {
+        use crate::DefaultProcessConfig;
+
+        // Traps if storing a value doesn't return the expected result
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "local_set" (func $set (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 2)
+                (func $expect (param $result i32) (param $expected i32)
+                    (if (i32.ne (local.get $result) (local.get $expected))
+                        (then unreachable)))
+                (func (export "test") (local $i i32)
+                    ;; Too big keys and values
+                    (call $expect (call $set (i32.const 0) (i32.const 1025) (i32.const 0) (i32.const 0)) (i32.const 1))
+                    (call $expect (call $set (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 65537)) (i32.const 1))
+                    ;; Fill the storage with 4 byte keys
+                    (loop $fill
+                        (i32.store (i32.const 0) (local.get $i))
+                        (call $expect (call $set (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 0)) (i32.const 0))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $fill (i32.lt_u (local.get $i) (i32.const 1024))))
+                    (i32.store (i32.const 0) (local.get $i))
+                    (call $expect (call $set (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 0)) (i32.const 1))
+                    ;; Existing entries can still be replaced
+                    (i32.store (i32.const 0) (i32.const 0))
+                    (call $expect (call $set (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 4)) (i32.const 0)))
+            )"#,
+        )
+        .unwrap();
+        let state = run_test_module(test_runtime(), raw_module, DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            state.local_storage.get(&[0, 0, 0, 0]),
+            Some(&[0, 0, 0, 0][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_need_filesystem_access() {
+        use crate::DefaultProcessConfig;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        // Traps if connecting doesn't return the expected result
+        let module = |expected: u32| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "lunatic::networking" "unix_connect" (func $connect (param i32 i32 i64 i32) (result i32)))
+                    (memory (export "memory") 1)
+                    (data (i32.const 1024) "{path}")
+                    (func (export "test")
+                        (if (i32.ne (call $connect (i32.const 1024) (i32.const {}) (i64.const -1) (i32.const 0)) (i32.const {expected}))
+                            (then unreachable)))
+                )"#,
+                path.len()
+            ))
+            .unwrap()
+        };
+
+        let denied =
+            run_test_module(test_runtime(), module(1), DefaultProcessConfig::default()).await;
+        let mut config = DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        let allowed = run_test_module(test_runtime(), module(0), config).await;
+        std::fs::remove_dir_all(dir).unwrap();
+        denied.unwrap();
+        allowed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_send_file_reads_wasi_descriptors() {
+        use std::io::Read;
+
+        use crate::DefaultProcessConfig;
+        use lunatic_wasi_api::LunaticWasiConfigCtx;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-send-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("data"), "hello file").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = std::thread::spawn(move || {
+            let mut received = String::new();
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        // Opens the file in the read-only preopened directory (fd 3) with the rights to read and
+        // seek, then sends everything after the first 6 bytes. Traps if any of the calls fails or
+        // the wrong number of bytes is sent.
+        let raw_module = wat::parse_str(format!(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open" (func $open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_connect" (func $connect (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+                (import "lunatic::networking" "tcp_send_file" (func $send_file (param i64 i32 i64 i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "\7f\00\00\01")
+                (data (i32.const 2048) "data")
+                (func (export "test")
+                    (if (call $open (i32.const 3) (i32.const 0) (i32.const 2048) (i32.const 4) (i32.const 0) (i64.const 6) (i64.const 0) (i32.const 0) (i32.const 8))
+                        (then unreachable))
+                    (if (call $connect (i32.const 4) (i32.const 1024) (i32.const {port}) (i32.const 0) (i32.const 0) (i64.const -1) (i32.const 0))
+                        (then unreachable))
+                    (if (call $send_file (i64.load (i32.const 0)) (i32.load (i32.const 8)) (i64.const 6) (i64.const -1) (i32.const 16))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 16)) (i64.const 4))
+                        (then unreachable)))
+            )"#
+        ))
+        .unwrap();
+        let mut config = DefaultProcessConfig::default();
+        LunaticWasiConfigCtx::preopen_dir(
+            &mut config,
+            dir.to_str().unwrap().to_string(),
+            true,
+            None,
+        );
+        let result = run_test_module(test_runtime(), raw_module, config).await;
+        std::fs::remove_dir_all(dir).unwrap();
+        result.unwrap();
+        assert_eq!(received.join().unwrap(), "file");
+    }
+
+    #[tokio::test]
+    async fn cloned_tcp_listeners_accept_in_multiple_processes() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+
+        // The parent sends a clone of its listener to a child and both accept one connection.
+        // The child replies once it accepted, traps if any of the calls fails.
+        let raw_module = wat::parse_str(format!(
+            r#"(module
+                (import "lunatic::networking" "tcp_bind" (func $bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "clone_tcp_listener" (func $clone (param i64) (result i64)))
+                (import "lunatic::networking" "tcp_accept" (func $accept (param i64 i32 i32) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "spawn" (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "push_tcp_listener" (func $push_listener (param i64) (result i64)))
+                (import "lunatic::message" "take_tcp_listener" (func $take_listener (param i64) (result i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "child")
+                (data (i32.const 1100) "\7f\00\00\01")
+                (func (export "child") (local $parent i64)
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                    (drop (call $read_data (i32.const 16) (i32.const 8)))
+                    (local.set $parent (i64.load (i32.const 16)))
+                    (if (i32.ne (call $accept (call $take_listener (i64.const 0)) (i32.const 24) (i32.const 32)) (i32.const 0))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (local.get $parent))))
+                (func (export "test") (local $listener i64)
+                    (if (i32.ne (call $bind (i32.const 4) (i32.const 1100) (i32.const {port}) (i32.const 0) (i32.const 0) (i32.const 0)) (i32.const 0))
+                        (then unreachable))
+                    (local.set $listener (i64.load (i32.const 0)))
+                    (if (i32.ne (call $spawn (i64.const 0) (i64.const -1) (i64.const -1) (i32.const 1024) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 8)) (i32.const 0))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (i64.store (i32.const 16) (call $process_id))
+                    (drop (call $write_data (i32.const 16) (i32.const 8)))
+                    (drop (call $push_listener (call $clone (local.get $listener))))
+                    (drop (call $send (i64.load (i32.const 8))))
+
+                    (if (i32.ne (call $accept (local.get $listener) (i32.const 24) (i32.const 32)) (i32.const 0))
+                        (then unreachable))
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1))))
+            )"#
+        ))
+        .unwrap();
+        let (task, _) = spawn_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+
+        // Connecting succeeds once the listener is bound, the connections stay open until both
+        // are accepted
+        let mut connections = Vec::new();
+        while connections.len() < 2 {
+            match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(connection) => connections.push(connection),
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn multi_destination_sends_reject_resources() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_compile_modules(true);
+
+        // Traps if sending a message with a module attached to multiple processes isn't rejected,
+        // or if sending a message without resources fails
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "compile_module" (func $compile (param i32 i32 i32) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "group_create" (func $group_create (result i64)))
+                (import "lunatic::process" "group_join" (func $group_join (param i64 i64) (result i32)))
+                (import "lunatic::process" "group_send" (func $group_send (param i64) (result i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "push_module" (func $push_module (param i64) (result i64)))
+                (import "lunatic::message" "send_many" (func $send_many (param i32 i32) (result i32)))
+                (import "lunatic::pubsub" "publish" (func $publish (param i32 i32) (result i64)))
+                (import "lunatic::message" "send_receive_many" (func $send_receive_many (param i32 i32 i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "\00asm\01\00\00\00")
+                (func (export "test") (local $module i64) (local $group i64)
+                    (if (i32.ne (call $compile (i32.const 1024) (i32.const 8) (i32.const 0)) (i32.const 0))
+                        (then unreachable))
+                    (local.set $module (i64.load (i32.const 0)))
+                    (local.set $group (call $group_create))
+                    (if (i32.ne (call $group_join (local.get $group) (call $process_id)) (i32.const 0))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $push_module (local.get $module)))
+                    (if (i32.ne (call $group_send (local.get $group)) (i32.const 2))
+                        (then unreachable))
+                    (i64.store (i32.const 8) (call $process_id))
+                    (if (i32.ne (call $send_many (i32.const 8) (i32.const 1)) (i32.const 1))
+                        (then unreachable))
+                    (if (i64.ne (call $publish (i32.const 1024) (i32.const 1)) (i64.const -1))
+                        (then unreachable))
+                    (if (i32.ne (call $send_receive_many (i32.const 8) (i32.const 16) (i32.const 1) (i32.const 0) (i64.const 0)) (i32.const -1))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (if (i32.ne (call $group_send (local.get $group)) (i32.const 0))
+                        (then unreachable)))
+            )"#,
+        )
+        .unwrap();
+        run_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_rejected() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        config.set_can_spawn_processes(true);
+
+        // Spawns a child accepting messages of up to 2 bytes and traps if sending a bigger
+        // message to it isn't rejected by any of the send functions, or if sending a smaller one
+        // fails
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::process" "config_set_max_message_size" (func $set_max_message_size (param i64 i64)))
+                (import "lunatic::process" "spawn" (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "send_many" (func $send_many (param i32 i32) (result i32)))
+                (import "lunatic::message" "send_receive_many" (func $send_receive_many (param i32 i32 i32 i32 i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "child")
+                (func (export "child")
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1))))
+                (func (export "test") (local $config i64) (local $child i64)
+                    (local.set $config (call $create_config))
+                    (call $set_max_message_size (local.get $config) (i64.const 2))
+                    (if (i32.ne (call $spawn (i64.const 0) (local.get $config) (i64.const -1) (i32.const 1024) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 0)) (i32.const 0))
+                        (then unreachable))
+                    (local.set $child (i64.load (i32.const 0)))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 1024) (i32.const 3)))
+                    (if (i32.ne (call $send (local.get $child)) (i32.const 1))
+                        (then unreachable))
+
+                    ;; Multi-destination sends keep the rejected message in the scratch area
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 1024) (i32.const 3)))
+                    (if (i32.ne (call $send_many (i32.const 0) (i32.const 1)) (i32.const 2))
+                        (then unreachable))
+                    (if (i32.ne (call $send_receive_many (i32.const 0) (i32.const 16) (i32.const 1) (i32.const 0) (i64.const 0)) (i32.const -2))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 1024) (i32.const 2)))
+                    (if (i32.ne (call $send (local.get $child)) (i32.const 0))
+                        (then unreachable)))
+            )"#,
+        )
+        .unwrap();
+        run_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn receive_until_writes_remaining_time() {
+        use crate::DefaultProcessConfig;
+
+        // Traps if the remaining time isn't 0 once the deadline is reached, or isn't within the
+        // budget if a message arrives in time
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::timer" "now_monotonic" (func $now (result i64)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive_until" (func $receive_until (param i32 i32 i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "test")
+                    (if (i32.ne (call $receive_until (i32.const 0) (i32.const 0) (i64.add (call $now) (i64.const 10)) (i32.const 8)) (i32.const 9027))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 8)) (i64.const 0))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (call $process_id)))
+                    (if (i32.ne (call $receive_until (i32.const 0) (i32.const 0) (i64.add (call $now) (i64.const 60000)) (i32.const 8)) (i32.const 0))
+                        (then unreachable))
+                    (if (i64.gt_u (i64.load (i32.const 8)) (i64.const 60000))
+                        (then unreachable))
+                    (if (i64.lt_u (i64.load (i32.const 8)) (i64.const 50000))
+                        (then unreachable))
+
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (call $process_id)))
+                    (drop (call $receive_until (i32.const 0) (i32.const 0) (i64.const -1) (i32.const 8)))
+                    (if (i64.ne (i64.load (i32.const 8)) (i64.const -1))
+                        (then unreachable)))
+            )"#,
+        )
+        .unwrap();
+        run_test_module(test_runtime(), raw_module, DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn hibernation_keeps_memory() {
+        use crate::DefaultProcessConfig;
+
+        // Sends itself a message to wake up right after hibernating and traps if the linear
+        // memory isn't restored
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "hibernate" (func $hibernate (param i32 i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "wake")
+                (func (export "test")
+                    (drop (memory.grow (i32.const 2)))
+                    (i32.store (i32.const 2048) (i32.const 42))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (call $process_id)))
+                    (call $hibernate (i32.const 1024) (i32.const 4)))
+                (func (export "wake")
+                    (if (i32.ne (i32.load (i32.const 2048)) (i32.const 42))
+                        (then unreachable))
+                    (if (i32.ne (memory.size) (i32.const 3))
+                        (then unreachable)))
+            )"#,
+        )
+        .unwrap();
+        run_test_module(test_runtime(), raw_module, DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn hibernated_process_close_to_memory_limit_wakes_up() {
+        use crate::DefaultProcessConfig;
+        use lunatic_process::config::ProcessConfig;
+
+        // Uses 3 of 4 allowed pages when hibernating. Only the memory of the new instance counts
+        // once it wakes up.
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "hibernate" (func $hibernate (param i32 i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "wake")
+                (func (export "test")
+                    (if (i32.eq (memory.grow (i32.const 2)) (i32.const -1))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (call $process_id)))
+                    (call $hibernate (i32.const 1024) (i32.const 4)))
+                (func (export "wake"))
+            )"#,
+        )
+        .unwrap();
+        let mut config = DefaultProcessConfig::default();
+        config.set_max_memory(4 * 65536);
+        let state = run_test_module(test_runtime(), raw_module, config)
+            .await
+            .unwrap();
+        assert_eq!(state.memory_size, 3 * 65536);
+        assert_eq!(state.stats.snapshot().memory_size, 3 * 65536);
+    }
+
+    #[tokio::test]
+    async fn links_are_kept_by_node_and_process_id() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::DefaultProcessConfig;
+        use lunatic_process::{Process, Signal};
+
+        // Records the IDs of the processes it was notified about
+        struct LinkedProcess {
+            node_id: Option<u64>,
+            died: Mutex<Vec<u64>>,
+        }
+
+        impl Process for LinkedProcess {
+            fn id(&self) -> u64 {
+                1
+            }
+
+            fn node_id(&self) -> Option<u64> {
+                self.node_id
+            }
+
+            fn send(&self, signal: Signal) {
+                if let Signal::LinkDied(_, id, ..) = signal {
+                    self.died.lock().unwrap().push(id);
+                }
+            }
+        }
+
+        // Waits for messages until it's killed
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "test")
+                    (loop $wait
+                        (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                        (br $wait)))
+            )"#,
+        )
+        .unwrap();
+        let (task, process) =
+            spawn_test_module(test_runtime(), raw_module, DefaultProcessConfig::default())
+                .await
+                .unwrap();
+
+        // A local and a remote process with the same ID, unlinking the remote one keeps the
+        // local link
+        let local = Arc::new(LinkedProcess {
+            node_id: None,
+            died: Mutex::default(),
+        });
+        let remote = Arc::new(LinkedProcess {
+            node_id: Some(2),
+            died: Mutex::default(),
+        });
+        process.send(Signal::Link(None, local.clone()));
+        process.send(Signal::Link(None, remote.clone()));
+        process.send(Signal::UnLink {
+            node_id: Some(2),
+            process_id: 1,
+        });
+        process.send(Signal::Kill(None));
+        assert!(task.await.unwrap().is_err());
+
+        assert_eq!(*local.died.lock().unwrap(), vec![process.id()]);
+        assert!(remote.died.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resolved_hosts_are_bounded() {
+        use super::{ResolvedHosts, MAX_RESOLVED_HOSTS};
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let addr = |i: usize| IpAddr::V4(Ipv4Addr::from(i as u32));
+        let mut hosts = ResolvedHosts::default();
+        for i in 0..MAX_RESOLVED_HOSTS {
+            hosts.insert(addr(i), format!("host{i}"));
+        }
+        // Resolving the first address again makes the second one the oldest
+        hosts.insert(addr(0), "renamed".to_string());
+        hosts.insert(addr(MAX_RESOLVED_HOSTS), "new".to_string());
+        assert_eq!(hosts.get(&addr(0)), Some("renamed"));
+        assert_eq!(hosts.get(&addr(1)), None);
+        assert_eq!(hosts.get(&addr(MAX_RESOLVED_HOSTS)), Some("new"));
+        assert_eq!(hosts.hosts.len(), MAX_RESOLVED_HOSTS);
+    }
+
+    #[test]
+    fn missing_wasi_imports_link() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "wasi_unstable" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "not_implemented" (func (param i32) (result i32)))
+            )"#,
+        )
+        .unwrap();
+        runtime
+            .compile_module::<DefaultProcessState>(raw_module.into())
+            .unwrap();
+
+        // Missing functions in other namespaces still fail to link
+        let raw_module = wat::parse_str(
+            r#"(module
+                (import "lunatic::process" "not_implemented" (func (result i32)))
+            )"#,
+        )
+        .unwrap();
+        assert!(runtime
+            .compile_module::<DefaultProcessState>(raw_module.into())
+            .is_err());
+    }
 }
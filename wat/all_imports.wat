@@ -10,26 +10,112 @@
     (import "lunatic::message" "read_data" (func (param i32 i32) (result i32)))
     (import "lunatic::message" "seek_data" (func (param i64)))
     (import "lunatic::message" "get_tag" (func (result i64)))
+    (import "lunatic::message" "set_header" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::message" "get_header" (func (param i32 i32 i32 i32) (result i64)))
+    (import "lunatic::message" "get_process_id" (func (result i64)))
+    (import "lunatic::message" "get_link_died_pid" (func (result i64)))
+    (import "lunatic::message" "get_death_reason" (func (result i32)))
+    (import "lunatic::message" "get_exit_code" (func (result i32)))
+    (import "lunatic::message" "get_kill_reason" (func (param i32 i32) (result i64)))
     (import "lunatic::message" "data_size" (func (result i64)))
     (import "lunatic::message" "push_tcp_stream" (func (param i64) (result i64)))
     (import "lunatic::message" "take_tcp_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_tcp_listener" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_tcp_listener" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_tls_listener" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_tls_listener" (func (param i64) (result i64)))
     (import "lunatic::message" "push_udp_socket" (func (param i64) (result i64)))
     (import "lunatic::message" "take_udp_socket" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_websocket" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_websocket" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_quic_connection" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_quic_connection" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_quic_send_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_quic_send_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_quic_recv_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_quic_recv_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_stdin_pipe" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_stdin_pipe" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_unix_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_unix_stream" (func (param i64) (result i64)))
+    (import "lunatic::pubsub" "subscribe" (func (param i32 i32)))
+    (import "lunatic::pubsub" "unsubscribe" (func (param i32 i32) (result i32)))
+    (import "lunatic::pubsub" "publish" (func (param i32 i32) (result i64)))
     (import "lunatic::message" "send" (func (param i64) (result i32)))
+    (import "lunatic::message" "send_many" (func (param i32 i32) (result i32)))
+    (import "lunatic::message" "send_to" (func (param i64 i64) (result i32)))
+    (import "lunatic::message" "send_to_name" (func (param i32 i32) (result i32)))
+    (import "lunatic::message" "send_with_backpressure" (func (param i64 i32) (result i32)))
+    (import "lunatic::message" "create_data_slot" (func (result i64)))
+    (import "lunatic::message" "drop_data_slot" (func (param i64) (result i32)))
+    (import "lunatic::message" "slot_create_data" (func (param i64 i64 i64)))
+    (import "lunatic::message" "slot_write_data" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::message" "slot_send" (func (param i64 i64) (result i32)))
     (import "lunatic::message" "send_receive_skip_search" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::message" "send_receive_many" (func (param i32 i32 i32 i32 i64) (result i32)))
     (import "lunatic::message" "receive" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "receive_until" (func (param i32 i32 i64 i32) (result i32)))
+    (import "lunatic::message" "receive_range" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::message" "peek" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "discard" (func (result i32)))
 
     (import "lunatic::timer" "send_after" (func (param i64 i64) (result i64)))
     (import "lunatic::timer" "cancel_timer" (func (param i64) (result i32)))
+    (import "lunatic::timer" "now_monotonic" (func (result i64)))
+
+    (import "lunatic::http" "request" (func (param i32 i32 i32 i32 i32 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::http" "response_status" (func (param i64) (result i32)))
+    (import "lunatic::http" "response_headers" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::http" "response_read" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::http" "drop_response" (func (param i64)))
+
+    (import "lunatic::websocket" "ws_accept" (func (param i32 i64 i32) (result i32)))
+    (import "lunatic::websocket" "ws_connect" (func (param i32 i64 i32 i32 i32) (result i32)))
+    (import "lunatic::websocket" "ws_read_frame" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::websocket" "ws_frame_data" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::websocket" "ws_write_frame" (func (param i64 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::websocket" "drop_websocket" (func (param i64)))
+    (import "lunatic::quic" "bind" (func (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::quic" "drop_endpoint" (func (param i64)))
+    (import "lunatic::quic" "connect" (func (param i64 i32 i32 i32 i32 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::quic" "accept" (func (param i64 i32) (result i32)))
+    (import "lunatic::quic" "close" (func (param i64 i64 i32 i32)))
+    (import "lunatic::quic" "drop_connection" (func (param i64)))
+    (import "lunatic::quic" "open_bi" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::quic" "open_uni" (func (param i64 i32) (result i32)))
+    (import "lunatic::quic" "accept_bi" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::quic" "accept_uni" (func (param i64 i32) (result i32)))
+    (import "lunatic::quic" "write" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::quic" "finish" (func (param i64 i32) (result i32)))
+    (import "lunatic::quic" "read" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::quic" "drop_send_stream" (func (param i64)))
+    (import "lunatic::quic" "drop_recv_stream" (func (param i64)))
+    (import "lunatic::quic" "send_datagram" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::quic" "read_datagram" (func (param i64 i32 i32 i32) (result i32)))
 
     (import "lunatic::networking" "resolve" (func (param i32 i32 i64 i32) (result i32)))
     (import "lunatic::networking" "drop_dns_iterator" (func (param i64)))
     (import "lunatic::networking" "resolve_next" (func (param i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "create_dns_resolver" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "drop_dns_resolver" (func (param i64)))
+    (import "lunatic::networking" "resolve_with" (func (param i64 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::networking" "dns_record_next" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "drop_dns_records" (func (param i64)))
+    (import "lunatic::networking" "poll" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::networking" "wasi_poll_fd" (func (param i32 i64) (result i32)))
+    (import "lunatic::networking" "tcp_connect_start" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_connect_finish" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "drop_tcp_connecting" (func (param i64)))
+    (import "lunatic::networking" "tcp_connect_via_proxy" (func (param i32 i32 i32 i32 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::networking" "tls_connect_via_proxy" (func (param i32 i32 i32 i32 i32 i32 i32 i64 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_bind_with_options" (func (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_accept_with_timeout" (func (param i64 i64 i32 i32) (result i32)))
     (import "lunatic::networking" "tcp_bind" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::networking" "drop_tcp_listener" (func (param i64)))
     (import "lunatic::networking" "tcp_local_addr" (func (param i64 i32) (result i32)))
     (import "lunatic::networking" "tcp_accept" (func (param i64 i32 i32) (result i32)))
     (import "lunatic::networking" "tcp_connect" (func (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::networking" "tcp_stream_local_addr" (func (param i64 i32) (result i32)))
     (import "lunatic::networking" "drop_tcp_stream" (func (param i64)))
     (import "lunatic::networking" "clone_tcp_stream" (func (param i64) (result i64)))
     (import "lunatic::networking" "tcp_write_vectored" (func (param i64 i32 i32 i32) (result i32)))
@@ -41,6 +127,31 @@
     (import "lunatic::networking" "set_peek_timeout" (func (param i64 i64)))
     (import "lunatic::networking" "get_peek_timeout" (func (param i64) (result i64)))
     (import "lunatic::networking" "tcp_flush" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tcp_send_file" (func (param i64 i32 i64 i64 i32) (result i32)))
+    (import "lunatic::networking" "tcp_shutdown" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_set_nodelay" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_set_keepalive" (func (param i64 i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_set_linger" (func (param i64 i64 i32) (result i32)))
+    (import "lunatic::networking" "tcp_set_send_buffer_size" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "tcp_set_recv_buffer_size" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "unix_bind" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "drop_unix_listener" (func (param i64)))
+    (import "lunatic::networking" "unix_accept" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "unix_connect" (func (param i32 i32 i64 i32) (result i32)))
+    (import "lunatic::networking" "drop_unix_stream" (func (param i64)))
+    (import "lunatic::networking" "clone_unix_stream" (func (param i64) (result i64)))
+    (import "lunatic::networking" "unix_write_vectored" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "unix_read" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "set_unix_read_timeout" (func (param i64 i64)))
+    (import "lunatic::networking" "get_unix_read_timeout" (func (param i64) (result i64)))
+    (import "lunatic::networking" "set_unix_write_timeout" (func (param i64 i64)))
+    (import "lunatic::networking" "get_unix_write_timeout" (func (param i64) (result i64)))
+    (import "lunatic::networking" "unix_flush" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tls_peer_addr" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tls_stream_local_addr" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tls_shutdown" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tls_listener_reload_certs" (func (param i64 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::networking" "tls_listener_remove_sni_cert" (func (param i64 i32 i32) (result i32)))
     (import "lunatic::networking" "udp_bind" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::networking" "drop_udp_socket" (func (param i64)))
     (import "lunatic::networking" "udp_local_addr" (func (param i64 i32) (result i32)))
@@ -58,8 +169,16 @@
     (import "lunatic::networking" "udp_peer_addr" (func (param i64 i32) (result i32)))
 
     (import "lunatic::sqlite" "open" (func (param i32 i32 i32) (result i64)))
+    (import "lunatic::sqlite" "query_prepare_cached" (func (param i64 i32 i32) (result i64)))
     (import "lunatic::sqlite" "execute" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "begin" (func (param i64) (result i32)))
+    (import "lunatic::sqlite" "commit" (func (param i64) (result i32)))
+    (import "lunatic::sqlite" "rollback" (func (param i64) (result i32)))
+    (import "lunatic::sqlite" "savepoint" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "release_savepoint" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "rollback_to_savepoint" (func (param i64 i32 i32) (result i32)))
     (import "lunatic::sqlite" "bind_value" (func (param i64 i32 i32)))
+    (import "lunatic::sqlite" "rebind" (func (param i64 i32 i32)))
     (import "lunatic::sqlite" "sqlite3_changes" (func (param i64)(result i32)))
     (import "lunatic::sqlite" "statement_reset" (func (param i64)))
     (import "lunatic::sqlite" "sqlite3_step" (func (param i64) (result i32)))
@@ -70,37 +189,124 @@
     (import "lunatic::sqlite" "read_row" (func (param i64 i32) (result i32)))
     (import "lunatic::sqlite" "column_name" (func (param i64 i32 i32) (result i32)))
     (import "lunatic::sqlite" "column_names" (func (param i64 i32) (result i32)))
+    (import "lunatic::sqlite" "query_stream_next" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "blob_open" (func (param i64 i32 i32 i32 i32 i64 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "blob_read" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "blob_write" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::sqlite" "blob_bytes" (func (param i64) (result i32)))
+    (import "lunatic::sqlite" "blob_close" (func (param i64)))
+
+    (import "lunatic::kv" "get" (func (param i32 i32 i32 i32) (result i64)))
+    (import "lunatic::kv" "put" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::kv" "delete" (func (param i32 i32) (result i32)))
+    (import "lunatic::kv" "cas" (func (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::kv" "scan" (func (param i32 i32 i32 i32 i32 i32 i32) (result i64)))
 
     (import "lunatic::process" "compile_module" (func (param i32 i32 i32) (result i32)))
     (import "lunatic::process" "drop_module" (func (param i64)))
+    (import "lunatic::process" "set_module_version" (func (param i32 i32 i64) (result i64)))
+    (import "lunatic::process" "get_module_version" (func (param i32 i32 i32) (result i64)))
     (import "lunatic::process" "create_config" (func (result i64)))
     (import "lunatic::process" "drop_config" (func (param i64)))
     (import "lunatic::process" "config_set_max_memory" (func (param i64 i64)))
     (import "lunatic::process" "config_get_max_memory" (func (param i64) (result i64)))
     (import "lunatic::process" "config_set_max_fuel" (func (param i64 i64)))
     (import "lunatic::process" "config_get_max_fuel" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_fuel_per_yield" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_fuel_per_yield" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_max_lifetime_ms" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_max_lifetime_ms" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_max_processes" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_max_processes" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_max_mailbox_size" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_max_mailbox_size" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_mailbox_overflow_policy" (func (param i64 i32)))
+    (import "lunatic::process" "config_get_mailbox_overflow_policy" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_mailbox_watermark" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_mailbox_watermark" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_max_log_level" (func (param i64 i32)))
+    (import "lunatic::process" "config_get_max_log_level" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_crash_dumps" (func (param i64 i32)))
+    (import "lunatic::process" "config_get_crash_dumps" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_max_message_size" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_max_message_size" (func (param i64) (result i64)))
     (import "lunatic::process" "config_can_compile_modules" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_compile_modules" (func (param i64 i32)))
     (import "lunatic::process" "config_can_create_configs" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_create_configs" (func (param i64 i32)))
     (import "lunatic::process" "config_can_spawn_processes" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_spawn_processes" (func (param i64 i32)))
+    (import "lunatic::process" "config_can_use_nn" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_can_use_nn" (func (param i64 i32)))
+    (import "lunatic::process" "config_can_set_node_tags" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_can_set_node_tags" (func (param i64 i32)))
+    (import "lunatic::process" "config_allow_network" (func (param i64 i32 i32 i32 i32)))
+    (import "lunatic::process" "config_deny_network" (func (param i64 i32 i32 i32 i32)))
+    (import "lunatic::process" "config_allow_sqlite" (func (param i64 i32 i32 i64)))
     (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_named" (func (param i32 i32 i64 i64 i64 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_with_message" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_link_await" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_start" (func (param i64 i64 i64 i32) (result i32)))
+    (import "lunatic::process" "restore" (func (param i64 i64 i64 i32 i32 i32) (result i32)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
+    (import "lunatic::process" "trap_shutdown" (func (param i32)))
     (import "lunatic::process" "process_id" (func (result i64)))
     (import "lunatic::process" "link" (func (param i64 i64)))
+    (import "lunatic::process" "link_and_send" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
+    (import "lunatic::process" "monitor" (func (param i64)))
+    (import "lunatic::process" "demonitor" (func (param i64)))
     (import "lunatic::process" "kill" (func (param i64)))
+    (import "lunatic::process" "kill_with_reason" (func (param i64 i32 i32)))
+    (import "lunatic::process" "shutdown" (func (param i64 i64)))
+    (import "lunatic::process" "suspend" (func (param i64)))
+    (import "lunatic::process" "hibernate" (func (param i32 i32)))
+    (import "lunatic::process" "checkpoint" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "resume" (func (param i64)))
     (import "lunatic::process" "exists" (func (param i64) (result i32)))
+    (import "lunatic::process" "stats" (func (param i64 i32) (result i32)))
+    (import "lunatic::process" "list" (func (param i64 i32 i32 i32 i32) (result i64)))
+    (import "lunatic::process" "group_create" (func (result i64)))
+    (import "lunatic::process" "group_join" (func (param i64 i64) (result i32)))
+    (import "lunatic::process" "group_leave" (func (param i64 i64) (result i32)))
+    (import "lunatic::process" "group_kill" (func (param i64) (result i32)))
+    (import "lunatic::process" "group_send" (func (param i64) (result i32)))
+    (import "lunatic::process" "local_set" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "local_get" (func (param i32 i32 i32 i32) (result i64)))
+    (import "lunatic::process" "local_delete" (func (param i32 i32) (result i32)))
+    (import "lunatic::process" "set_trace_context" (func (param i32 i32)))
+    (import "lunatic::process" "get_trace_context" (func (param i32 i32) (result i64)))
 
     (import "lunatic::version" "major" (func (result i32)))
     (import "lunatic::version" "minor" (func (result i32)))
     (import "lunatic::version" "patch" (func (result i32)))
 
     (import "lunatic::wasi" "config_add_environment_variable" (func (param i64 i32 i32 i32 i32)))
+    (import "lunatic::wasi" "config_inherit_environment" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "config_add_secret" (func (param i64 i32 i32 i32 i32)))
     (import "lunatic::wasi" "config_add_command_line_argument" (func (param i64 i32 i32)))
     (import "lunatic::wasi" "config_preopen_dir" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "config_preopen_dir_with_options" (func (param i64 i32 i32 i32 i64)))
+    (import "lunatic::wasi" "config_set_stdin" (func (param i64 i32)))
+    (import "lunatic::wasi" "config_set_stdin_pipe" (func (param i64 i64)))
+    (import "lunatic::wasi" "config_set_output_file" (func (param i64 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::wasi" "config_set_output_syslog" (func (param i64 i32 i32 i32)))
+    (import "lunatic::wasi" "config_set_output_process" (func (param i64 i32 i64 i64)))
+    (import "lunatic::wasi" "config_set_fixed_clock" (func (param i64 i64)))
+    (import "lunatic::wasi" "config_set_random_seed" (func (param i64 i64)))
+    (import "lunatic::wasi" "config_set_max_open_files" (func (param i64 i64)))
+    (import "lunatic::wasi" "config_set_fs_write_quota" (func (param i64 i64)))
+    (import "lunatic::wasi" "create_stdin_pipe" (func (result i64)))
+    (import "lunatic::wasi" "stdin_pipe_write" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "drop_stdin_pipe" (func (param i64)))
+
+    (import "wasi_ephemeral_nn" "load" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_ephemeral_nn" "init_execution_context" (func (param i32 i32) (result i32)))
+    (import "wasi_ephemeral_nn" "set_input" (func (param i32 i32 i32) (result i32)))
+    (import "wasi_ephemeral_nn" "compute" (func (param i32) (result i32)))
+    (import "wasi_ephemeral_nn" "get_output" (func (param i32 i32 i32 i32 i32) (result i32)))
 
     (import "lunatic::registry" "put" (func (param i32 i32 i64 i64)))
     (import "lunatic::registry" "get" (func (param i32 i32 i32 i32) (result i32)))
@@ -108,10 +314,27 @@
 
     (import "lunatic::distributed" "nodes_count" (func (result i32)))
     (import "lunatic::distributed" "get_nodes" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "set_node_tag" (func (param i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::distributed" "node_id" (func (result i64)))
     (import "lunatic::distributed" "module_id" (func (result i64)))
+    (import "lunatic::distributed" "node_status" (func (param i64) (result i32)))
+    (import "lunatic::distributed" "node_info" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::distributed" "subscribe_node_changes" (func (param i64)))
+    (import "lunatic::distributed" "unsubscribe_node_changes" (func))
     (import "lunatic::distributed" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_auto" (func (param i32 i32 i32 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "push_module" (func (param i32 i32 i32) (result i32)))
     (import "lunatic::distributed" "send" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "send_confirmed" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::distributed" "link" (func (param i64 i64 i64)))
+    (import "lunatic::distributed" "unlink" (func (param i64 i64)))
+    (import "lunatic::distributed" "monitor" (func (param i64 i64)))
+    (import "lunatic::distributed" "demonitor" (func (param i64 i64)))
+    (import "lunatic::distributed" "kill" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "exists" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "stream_open" (func (param i64 i64 i64) (result i64)))
+    (import "lunatic::distributed" "stream_write" (func (param i64 i32 i32 i64) (result i32)))
+    (import "lunatic::distributed" "stream_close" (func (param i64 i64) (result i32)))
     (import "lunatic::distributed" "send_receive_skip_search" (func (param i64 i64 i64 i64) (result i32)))
 
     (import "lunatic::metrics" "counter" (func (param i32 i32 i64)))
@@ -120,6 +343,13 @@
     (import "lunatic::metrics" "increment_gauge" (func (param i32 i32 f64)))
     (import "lunatic::metrics" "decrement_gauge" (func (param i32 i32 f64)))
     (import "lunatic::metrics" "histogram" (func (param i32 i32 f64)))
+    (import "lunatic::metrics" "self_stats" (func (param i32)))
+
+    (import "lunatic::log" "trace" (func (param i32 i32 i32 i32)))
+    (import "lunatic::log" "debug" (func (param i32 i32 i32 i32)))
+    (import "lunatic::log" "info" (func (param i32 i32 i32 i32)))
+    (import "lunatic::log" "warn" (func (param i32 i32 i32 i32)))
+    (import "lunatic::log" "error" (func (param i32 i32 i32 i32)))
 
     (func (export "hello") nop)
 )